@@ -0,0 +1,177 @@
+//! <https://www.abuseipdb.com> reputation enrichment
+//!
+//! The API is heavily rate-limited, so answers are kept in a dedicated cache
+//! with its own (long) TTL.
+
+use crate::{anonymity::Signals, cache::TtlCache, config::AbuseIpDbConfig};
+use serde::{Deserialize, Serialize};
+use std::{net::IpAddr, time::Duration};
+use tracing::warn;
+use utoipa::ToSchema;
+
+const ENDPOINT: &str = "https://api.abuseipdb.com/api/v2/check";
+
+/// Abuse reputation of an address.
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone, PartialEq)]
+pub struct AbuseReport {
+    /// Confidence that the address is abusive, 0-100.
+    pub confidence_score: u8,
+    /// Number of reports in the configured time window.
+    pub total_reports: u32,
+    /// Number of distinct users that reported the address.
+    pub distinct_reporters: u32,
+    pub last_reported_at: Option<String>,
+    /// Country as registered by AbuseIPDB, used to spot geolocation mismatches.
+    pub country_code: Option<String>,
+    pub usage_type: Option<String>,
+    pub is_tor: bool,
+    pub is_whitelisted: bool,
+}
+
+/// <https://docs.abuseipdb.com/#check-endpoint>
+#[derive(Deserialize, Debug)]
+struct CheckResponse {
+    data: CheckData,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct CheckData {
+    abuse_confidence_score: u8,
+    total_reports: u32,
+    num_distinct_users: u32,
+    last_reported_at: Option<String>,
+    country_code: Option<String>,
+    usage_type: Option<String>,
+    is_tor: Option<bool>,
+    is_whitelisted: Option<bool>,
+}
+
+impl From<CheckData> for AbuseReport {
+    fn from(data: CheckData) -> Self {
+        AbuseReport {
+            confidence_score: data.abuse_confidence_score,
+            total_reports: data.total_reports,
+            distinct_reporters: data.num_distinct_users,
+            last_reported_at: data.last_reported_at,
+            country_code: data.country_code,
+            usage_type: data.usage_type,
+            is_tor: data.is_tor.unwrap_or(false),
+            is_whitelisted: data.is_whitelisted.unwrap_or(false),
+        }
+    }
+}
+
+impl AbuseReport {
+    /// Anonymity signals that can be derived from the report.
+    pub fn signals(&self) -> Signals {
+        let hosting = self
+            .usage_type
+            .as_deref()
+            .map(|usage| usage.contains("Data Center") || usage.contains("Hosting"));
+        Signals {
+            hosting,
+            relay: Some(self.is_tor),
+            ..Default::default()
+        }
+    }
+}
+
+pub struct AbuseIpDb {
+    http: reqwest::Client,
+    config: AbuseIpDbConfig,
+    cache: TtlCache<AbuseReport>,
+}
+
+impl AbuseIpDb {
+    pub fn new(http: reqwest::Client, config: AbuseIpDbConfig) -> Self {
+        let cache = TtlCache::new(
+            Duration::from_secs(config.cache_ttl_secs),
+            config.cache_capacity,
+        );
+        AbuseIpDb {
+            http,
+            config,
+            cache,
+        }
+    }
+
+    /// Cached answer for `ip`, without a request.
+    pub fn cached(&self, ip: IpAddr) -> Option<AbuseReport> {
+        self.cache.get(&ip)
+    }
+
+    /// Returns the abuse report for `ip`, `None` if the API could not be reached.
+    ///
+    /// Enrichment is best effort and never fails the lookup itself.
+    pub async fn check(&self, ip: IpAddr) -> Option<AbuseReport> {
+        if let Some(report) = self.cache.get(&ip) {
+            return Some(report);
+        }
+        match self.request(ip).await {
+            Ok(report) => {
+                self.cache.insert(ip, report.clone());
+                Some(report)
+            }
+            Err(e) => {
+                warn!("abuseipdb check failed ip={} error={}", ip, e);
+                None
+            }
+        }
+    }
+
+    async fn request(&self, ip: IpAddr) -> reqwest::Result<AbuseReport> {
+        let response: CheckResponse = self
+            .http
+            .get(ENDPOINT)
+            .query(&[
+                ("ipAddress", ip.to_string()),
+                ("maxAgeInDays", self.config.max_age_days.to_string()),
+            ])
+            .header("Key", &self.config.api_key)
+            .header("Accept", "application/json")
+            .timeout(Duration::from_millis(self.config.timeout_ms))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        Ok(response.data.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEST_INPUT: &str = r#"
+{
+  "data": {
+    "ipAddress": "118.25.6.39",
+    "isPublic": true,
+    "ipVersion": 4,
+    "isWhitelisted": false,
+    "abuseConfidenceScore": 100,
+    "countryCode": "CN",
+    "usageType": "Data Center/Web Hosting/Transit",
+    "isp": "Tencent Cloud Computing (Beijing) Co. Ltd",
+    "domain": "tencent.com",
+    "hostnames": [],
+    "isTor": false,
+    "totalReports": 1,
+    "numDistinctUsers": 1,
+    "lastReportedAt": "2018-12-20T20:55:14+00:00"
+  }
+}
+"#;
+
+    #[test]
+    fn test_parse() {
+        let response: CheckResponse = serde_json::from_str(TEST_INPUT).unwrap();
+        let report = AbuseReport::from(response.data);
+        assert_eq!(report.confidence_score, 100);
+        assert_eq!(report.total_reports, 1);
+        assert_eq!(report.signals().hosting, Some(true));
+        assert_eq!(report.signals().relay, Some(false));
+    }
+}
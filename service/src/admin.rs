@@ -0,0 +1,110 @@
+//! Source restriction of the admin routes
+//!
+//! On top of the admin token, `/admin` requests are only served to callers in
+//! the `admin_sources` prefixes, e.g. the operator network. Everyone else gets
+//! a 403 before the token is even looked at, and the attempt is logged under
+//! the `audit` target so it can be routed apart from the request logs.
+
+use crate::{error::Error, extract::ClientIp};
+use axum::{
+    extract::{OriginalUri, Request, State},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use ipnet::IpNet;
+use std::{net::IpAddr, sync::Arc};
+use tracing::warn;
+
+/// Prefixes allowed to call the admin routes, any source when empty.
+#[derive(Debug, Clone, Default)]
+pub struct AdminSources {
+    nets: Vec<IpNet>,
+}
+
+impl AdminSources {
+    /// Prefixes or single addresses.
+    pub fn new(sources: &[String]) -> Result<Self, String> {
+        let nets = sources
+            .iter()
+            .map(|source| {
+                source
+                    .parse::<IpNet>()
+                    .or_else(|_| source.parse::<IpAddr>().map(IpNet::from))
+                    .map_err(|_| format!("admin_sources: invalid prefix {source}"))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(AdminSources { nets })
+    }
+
+    pub fn allows(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        self.nets.is_empty() || self.nets.iter().any(|net| net.contains(&ip))
+    }
+}
+
+/// Route layer of the admin routes, refuses callers outside the sources.
+pub async fn restrict(
+    State(sources): State<Arc<AdminSources>>,
+    ClientIp(ip): ClientIp,
+    request: Request,
+    next: Next,
+) -> Response {
+    if sources.allows(ip) {
+        return next.run(request).await;
+    }
+    // nested routers see the path without their prefix
+    let path = match request.extensions().get::<OriginalUri>() {
+        Some(OriginalUri(uri)) => uri.path(),
+        None => request.uri().path(),
+    };
+    warn!(
+        target: "audit",
+        "admin request refused ip={} method={} path={}",
+        ip,
+        request.method(),
+        path
+    );
+    Error::Blocked(format!("admin routes are not served to {ip}")).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{
+        body::Body, extract::ConnectInfo, http::StatusCode, middleware, routing::get, Router,
+    };
+    use std::net::SocketAddr;
+    use tower::ServiceExt;
+
+    #[test]
+    fn test_allows() {
+        let sources = AdminSources::new(&["10.0.0.0/8".into(), "2001:db8::1".into()]).unwrap();
+        assert!(sources.allows("10.1.2.3".parse().unwrap()));
+        assert!(sources.allows("::ffff:10.1.2.3".parse().unwrap()));
+        assert!(sources.allows("2001:db8::1".parse().unwrap()));
+        assert!(!sources.allows("2001:db8::2".parse().unwrap()));
+        assert!(!sources.allows("192.0.2.1".parse().unwrap()));
+        assert!(AdminSources::default().allows("192.0.2.1".parse().unwrap()));
+        assert!(AdminSources::new(&["10.0.0.0/33".into()]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_restrict() {
+        let sources = Arc::new(AdminSources::new(&["10.0.0.0/8".into()]).unwrap());
+        let app = Router::new()
+            .route("/admin/flags", get(|| async { "ok" }))
+            .route_layer(middleware::from_fn_with_state(sources, restrict));
+        let status = |peer: &str| {
+            let peer: SocketAddr = peer.parse().unwrap();
+            let request = Request::builder()
+                .uri("/admin/flags")
+                .extension(ConnectInfo(peer))
+                .body(Body::empty())
+                .unwrap();
+            let app = app.clone();
+            async move { app.oneshot(request).await.unwrap().status() }
+        };
+        assert_eq!(status("10.0.0.5:4000").await, StatusCode::OK);
+        assert_eq!(status("192.0.2.1:4000").await, StatusCode::FORBIDDEN);
+    }
+}
@@ -0,0 +1,174 @@
+//! VPN / proxy / hosting detection
+//!
+//! Combines the flags reported by the lookup provider (e.g. ip-api `proxy` and `hosting`),
+//! heuristics on the ASN and organization name and any optional commercial feeds
+//! into a single `Anonymity` block.
+
+use crate::geo::Geo;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+/// ASNs that belong to cloud, hosting and colocation providers.
+const HOSTING_ASNS: &[u32] = &[
+    14061,  // DigitalOcean
+    14618,  // Amazon AES
+    15169,  // Google
+    16276,  // OVH
+    16509,  // Amazon
+    20473,  // Vultr (Choopa)
+    24940,  // Hetzner
+    396982, // Google Cloud
+    45102,  // Alibaba
+    63949,  // Akamai Connected Cloud (Linode)
+    8075,   // Microsoft
+    8100,   // QuadraNet
+];
+
+/// ASNs that mostly carry commercial VPN exit traffic.
+const VPN_ASNS: &[u32] = &[
+    9009,   // M247
+    60068,  // Datacamp / CDN77
+    212238, // Datacamp
+    136787, // TEFINCOM (NordVPN)
+];
+
+/// ASNs used as egress for relay services (e.g. iCloud Private Relay).
+const RELAY_ASNS: &[u32] = &[
+    36183, // Akamai Private Relay
+];
+
+const HOSTING_KEYWORDS: &[&str] = &[
+    "hosting",
+    "datacenter",
+    "data center",
+    "colocation",
+    "cloud",
+    "server",
+    "vps",
+];
+const VPN_KEYWORDS: &[&str] = &["vpn"];
+const PROXY_KEYWORDS: &[&str] = &["proxy"];
+const RELAY_KEYWORDS: &[&str] = &["private relay", "tor exit"];
+
+/// Anonymity flags attached to a lookup response.
+#[derive(Serialize, Deserialize, ToSchema, Debug, Default, Clone, PartialEq)]
+pub struct Anonymity {
+    /// The address is a commercial VPN exit.
+    pub vpn: bool,
+    /// The address is an open or anonymizing proxy.
+    pub proxy: bool,
+    /// The address belongs to a hosting or cloud provider.
+    pub hosting: bool,
+    /// The address is a relay egress (Tor, iCloud Private Relay...).
+    pub relay: bool,
+}
+
+/// Partial anonymity signals reported by a single source.
+///
+/// `None` means the source has no opinion on the flag.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Signals {
+    pub vpn: Option<bool>,
+    pub proxy: Option<bool>,
+    pub hosting: Option<bool>,
+    pub relay: Option<bool>,
+}
+
+impl Signals {
+    /// Applies ASN and organization name heuristics to a lookup result.
+    pub fn from_asn(geo: &Geo) -> Self {
+        let org = [&geo.as_name, &geo.org, &geo.isp]
+            .iter()
+            .filter_map(|name| name.as_deref())
+            .collect::<Vec<_>>()
+            .join(" ")
+            .to_lowercase();
+        let matches = |keywords: &[&str]| keywords.iter().any(|k| org.contains(k));
+        let listed = |asns: &[u32]| geo.asn.is_some_and(|asn| asns.contains(&asn));
+
+        let hint = |value: bool| value.then_some(true);
+        Signals {
+            vpn: hint(listed(VPN_ASNS) || matches(VPN_KEYWORDS)),
+            proxy: hint(matches(PROXY_KEYWORDS)),
+            hosting: hint(listed(HOSTING_ASNS) || matches(HOSTING_KEYWORDS)),
+            relay: hint(listed(RELAY_ASNS) || matches(RELAY_KEYWORDS)),
+        }
+    }
+}
+
+impl Anonymity {
+    /// Combines the signals of every source; a flag is set if any source reports it.
+    pub fn from_signals(signals: &[Signals]) -> Self {
+        let any = |get: fn(&Signals) -> Option<bool>| signals.iter().any(|s| get(s) == Some(true));
+        Anonymity {
+            vpn: any(|s| s.vpn),
+            proxy: any(|s| s.proxy),
+            hosting: any(|s| s.hosting),
+            relay: any(|s| s.relay),
+        }
+    }
+
+    /// Runs the ASN heuristics on a lookup result and combines them with the
+    /// flags of the provider and any enabled commercial feeds.
+    pub fn detect(geo: &Geo, feeds: &[Signals]) -> Self {
+        let mut signals = vec![Signals::from_asn(geo)];
+        signals.extend_from_slice(feeds);
+        Self::from_signals(&signals)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn geo(asn: u32, as_name: &str) -> Geo {
+        let mut geo = Geo::new("1.2.3.4".parse().unwrap(), "test");
+        geo.asn = Some(asn);
+        geo.as_name = Some(as_name.to_string());
+        geo
+    }
+
+    #[test]
+    fn test_provider_flags() {
+        let provider = Signals {
+            proxy: Some(true),
+            hosting: Some(false),
+            ..Default::default()
+        };
+        let anonymity = Anonymity::detect(&geo(3320, "Deutsche Telekom AG"), &[provider]);
+        assert!(anonymity.proxy);
+        assert!(!anonymity.hosting);
+        assert!(!anonymity.vpn);
+    }
+
+    #[test]
+    fn test_asn_heuristics() {
+        let anonymity = Anonymity::detect(&geo(9009, "M247 Europe SRL"), &[]);
+        assert!(anonymity.vpn, "M247 should be flagged as VPN");
+
+        let anonymity = Anonymity::detect(&geo(24940, "Hetzner Online GmbH"), &[]);
+        assert!(anonymity.hosting, "Hetzner should be hosting");
+
+        let anonymity = Anonymity::detect(&geo(36183, "Akamai Technologies, Inc."), &[]);
+        assert!(anonymity.relay, "Private Relay ASN should be relay");
+
+        let anonymity = Anonymity::detect(&geo(64512, "Example VPN Services"), &[]);
+        assert!(anonymity.vpn, "Name keyword should be flagged");
+    }
+
+    #[test]
+    fn test_merge_signals() {
+        let feed = Signals {
+            vpn: Some(true),
+            ..Default::default()
+        };
+        let anonymity = Anonymity::from_signals(&[Signals::default(), feed]);
+        assert_eq!(
+            anonymity,
+            Anonymity {
+                vpn: true,
+                ..Default::default()
+            }
+        );
+    }
+}
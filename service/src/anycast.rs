@@ -0,0 +1,211 @@
+//! Anycast detection
+//!
+//! An anycast prefix is announced from many sites, the provider location is
+//! only one of them. Addresses are matched against a built-in list of well
+//! known anycast services and the configured prefixes. The optional latency
+//! check measures the round trip time from several vantages: when two of them
+//! are both closer to the address than light in fiber allows for the distance
+//! between them, the address answers from more than one place.
+
+use crate::{
+    cache::TtlCache,
+    config::{AnycastConfig, VantageConfig},
+    geo::distance_km,
+};
+use futures::future::join_all;
+use ipnet::IpNet;
+use serde::Deserialize;
+use std::{net::IpAddr, time::Duration};
+use tracing::{debug, warn};
+
+/// Attached to the lookups of anycast addresses.
+pub const WARNING: &str =
+    "anycast address: it is served from many locations, the geolocation is not where clients reach it";
+
+/// Distance covered per millisecond of round trip, light in fiber goes about
+/// 200 km/ms one way.
+pub(crate) const KM_PER_RTT_MS: f64 = 100.0;
+
+const BUILTIN_PREFIXES: &[&str] = &[
+    // Cloudflare DNS
+    "1.1.1.0/24",
+    "1.0.0.0/24",
+    "2606:4700:4700::/48",
+    // Google Public DNS
+    "8.8.8.0/24",
+    "8.8.4.0/24",
+    "2001:4860:4860::/48",
+    // Quad9
+    "9.9.9.0/24",
+    "149.112.112.0/24",
+    "2620:fe::/48",
+    // OpenDNS
+    "208.67.222.0/24",
+    "208.67.220.0/24",
+    // AdGuard DNS
+    "94.140.14.0/24",
+    "94.140.15.0/24",
+    // root servers a to m
+    "198.41.0.0/24",
+    "170.247.170.0/24",
+    "192.33.4.0/24",
+    "199.7.91.0/24",
+    "192.203.230.0/24",
+    "192.5.5.0/24",
+    "192.112.36.0/24",
+    "198.97.190.0/24",
+    "192.36.148.0/24",
+    "192.58.128.0/24",
+    "193.0.14.0/24",
+    "199.7.83.0/24",
+    "202.12.27.0/24",
+];
+
+#[derive(Deserialize, Debug)]
+struct ProbeReply {
+    rtt_ms: f64,
+}
+
+pub struct Anycast {
+    http: reqwest::Client,
+    prefixes: Vec<IpNet>,
+    vantages: Vec<VantageConfig>,
+    timeout: Duration,
+    /// Verdicts of the latency check.
+    cache: TtlCache<bool>,
+}
+
+impl Anycast {
+    pub fn new(http: reqwest::Client, config: AnycastConfig) -> Self {
+        let configured = config.prefixes.iter().map(String::as_str);
+        let prefixes = BUILTIN_PREFIXES
+            .iter()
+            .copied()
+            .chain(configured)
+            .filter_map(|prefix| match prefix.parse() {
+                Ok(net) => Some(net),
+                Err(_) => {
+                    warn!("anycast: ignoring invalid prefix {}", prefix);
+                    None
+                }
+            })
+            .collect();
+        Anycast {
+            http,
+            prefixes,
+            vantages: config.vantages,
+            timeout: Duration::from_millis(config.timeout_ms),
+            cache: TtlCache::new(
+                Duration::from_secs(config.cache_ttl_secs),
+                config.cache_capacity,
+            ),
+        }
+    }
+
+    /// Whether `ip` is anycast, the latency check only runs when `probe` is set.
+    pub async fn detect(&self, ip: IpAddr, probe: bool) -> bool {
+        self.listed(ip) || (probe && self.probe(ip).await)
+    }
+
+    /// Whether `ip` is in a known anycast prefix.
+    pub fn listed(&self, ip: IpAddr) -> bool {
+        self.prefixes.iter().any(|net| net.contains(&ip))
+    }
+
+    /// Latency check, `false` when less than two vantages answer.
+    pub async fn probe(&self, ip: IpAddr) -> bool {
+        if self.vantages.len() < 2 {
+            return false;
+        }
+        if let Some(anycast) = self.cache.get(&ip) {
+            return anycast;
+        }
+        let rtts = join_all(self.vantages.iter().map(|vantage| self.rtt(vantage, ip))).await;
+        let answered: Vec<(&VantageConfig, f64)> = self
+            .vantages
+            .iter()
+            .zip(rtts)
+            .filter_map(|(vantage, rtt)| Some((vantage, rtt?)))
+            .collect();
+        if answered.len() < 2 {
+            return false;
+        }
+        let anycast = impossible_rtts(&answered);
+        self.cache.insert(ip, anycast);
+        anycast
+    }
+
+    async fn rtt(&self, vantage: &VantageConfig, ip: IpAddr) -> Option<f64> {
+        let reply = async {
+            let response = self
+                .http
+                .get(&vantage.url)
+                .query(&[("ip", ip.to_string())])
+                .timeout(self.timeout)
+                .send()
+                .await?;
+            response.error_for_status()?.json::<ProbeReply>().await
+        };
+        match reply.await {
+            Ok(reply) => Some(reply.rtt_ms),
+            Err(e) => {
+                debug!("anycast probe {} failed for {}: {}", vantage.name, ip, e);
+                None
+            }
+        }
+    }
+}
+
+/// Some pair of vantages is too far apart for one location to answer both
+/// within the measured round trips.
+fn impossible_rtts(rtts: &[(&VantageConfig, f64)]) -> bool {
+    rtts.iter().enumerate().any(|(i, (a, rtt_a))| {
+        rtts[i + 1..].iter().any(|(b, rtt_b)| {
+            let apart = distance_km((a.latitude, a.longitude), (b.latitude, b.longitude));
+            (rtt_a + rtt_b) * KM_PER_RTT_MS < apart
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vantage(name: &str, latitude: f64, longitude: f64) -> VantageConfig {
+        VantageConfig {
+            name: name.into(),
+            url: format!("https://probe-{name}.example.com/rtt"),
+            latitude,
+            longitude,
+        }
+    }
+
+    #[test]
+    fn test_listed() {
+        let config = AnycastConfig {
+            prefixes: vec!["192.0.2.0/24".into()],
+            ..Default::default()
+        };
+        let anycast = Anycast::new(reqwest::Client::new(), config);
+        assert!(anycast.listed("1.1.1.1".parse().unwrap()));
+        assert!(anycast.listed("2001:4860:4860::8888".parse().unwrap()));
+        assert!(anycast.listed("192.0.2.10".parse().unwrap()));
+        assert!(!anycast.listed("198.51.100.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_impossible_rtts() {
+        let frankfurt = vantage("fra", 50.11, 8.68);
+        let new_york = vantage("nyc", 40.71, -74.01);
+        // about 6200 km apart, a single site is at least 31 ms round trip away
+        // from one of them
+        assert!(impossible_rtts(&[(&frankfurt, 3.0), (&new_york, 4.0)]));
+        assert!(!impossible_rtts(&[(&frankfurt, 3.0), (&new_york, 80.0)]));
+    }
+
+    #[tokio::test]
+    async fn test_probe_without_vantages() {
+        let anycast = Anycast::new(reqwest::Client::new(), AnycastConfig::default());
+        assert!(!anycast.probe("198.51.100.1".parse().unwrap()).await);
+    }
+}
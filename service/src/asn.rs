@@ -0,0 +1,377 @@
+//! Autonomous system details
+//!
+//! Built from two datasets downloaded on a schedule: the iptoasn.com table of
+//! announced ranges, which gives the name, country and prefixes of every
+//! routed AS, and the IANA AS number blocks, which give the registry that
+//! assigned the number.
+
+use crate::{
+    bulk,
+    config::AsnConfig,
+    dataset::{self, load_snapshot, save_snapshot, Dataset},
+    error::Error,
+};
+use ipnet::{IpNet, Ipv4Subnets, Ipv6Subnets};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    io::Read,
+    net::IpAddr,
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+use utoipa::ToSchema;
+
+pub const IP2ASN_URL: &str = "https://iptoasn.com/data/ip2asn-combined.tsv.gz";
+pub const REGISTRY_URLS: &[&str] = &[
+    "https://www.iana.org/assignments/as-numbers/as-numbers-1.csv",
+    "https://www.iana.org/assignments/as-numbers/as-numbers-2.csv",
+];
+/// Prefixes listed in a detail, the count covers all of them.
+const PREFIX_SAMPLE: usize = 20;
+
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone, PartialEq)]
+pub struct AsnDetail {
+    pub asn: u32,
+    pub name: Option<String>,
+    /// ISO 3166-1 alpha-2 country code of the registrant.
+    pub country: Option<String>,
+    /// Regional registry that assigned the number, e.g. `RIPE NCC`.
+    pub registry: Option<String>,
+    /// Announced prefixes, ranges are split into CIDR blocks.
+    pub prefix_count: usize,
+    /// The first announced prefixes, in address order.
+    pub prefixes: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct Network {
+    name: Option<String>,
+    country: Option<String>,
+    prefixes: Vec<IpNet>,
+}
+
+/// AS numbers `first..=last` assigned by `registry`.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct RegistryBlock {
+    first: u32,
+    last: u32,
+    registry: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct Snapshot {
+    networks: HashMap<u32, Network>,
+    /// Sorted by `first`.
+    registries: Vec<RegistryBlock>,
+}
+
+pub struct AsnDb {
+    http: reqwest::Client,
+    config: AsnConfig,
+    data: RwLock<Arc<Snapshot>>,
+    updated: RwLock<Option<SystemTime>>,
+}
+
+impl AsnDb {
+    /// Dataset with the snapshot loaded, empty until the first download without one.
+    pub fn new(http: reqwest::Client, config: AsnConfig) -> Self {
+        let db = AsnDb {
+            http,
+            config,
+            data: RwLock::default(),
+            updated: RwLock::default(),
+        };
+        if let Some((snapshot, modified)) = db.config.snapshot.as_deref().and_then(load_snapshot) {
+            db.replace(snapshot, modified);
+        }
+        db
+    }
+
+    /// Refreshes the dataset every `refresh_secs` until it is dropped.
+    pub fn spawn_refresh(self: &Arc<Self>) {
+        dataset::spawn_refresh(self);
+    }
+
+    pub fn detail(&self, asn: u32) -> Result<AsnDetail, Error> {
+        if self.updated().is_none() {
+            return Err(Error::ProviderUnavailable(
+                "the ASN dataset is not downloaded yet".into(),
+            ));
+        }
+        let data = self.data.read().unwrap().clone();
+        let index = data.registries.partition_point(|block| block.first <= asn);
+        let registry = index
+            .checked_sub(1)
+            .map(|i| &data.registries[i])
+            .filter(|block| asn <= block.last)
+            .map(|block| block.registry.clone());
+        let network = data.networks.get(&asn);
+        if network.is_none() && registry.is_none() {
+            return Err(Error::NotFound(format!("AS{asn}")));
+        }
+        let default = Network::default();
+        let network = network.unwrap_or(&default);
+        Ok(AsnDetail {
+            asn,
+            name: network.name.clone(),
+            country: network.country.clone(),
+            registry,
+            prefix_count: network.prefixes.len(),
+            prefixes: network
+                .prefixes
+                .iter()
+                .take(PREFIX_SAMPLE)
+                .map(ToString::to_string)
+                .collect(),
+        })
+    }
+
+    /// Prefixes announced by the ASes registered in `country`, merged into
+    /// the fewest blocks, IPv4 first.
+    pub fn country_prefixes(&self, country: &str) -> Result<Vec<IpNet>, Error> {
+        if self.updated().is_none() {
+            return Err(Error::ProviderUnavailable(
+                "the ASN dataset is not downloaded yet".into(),
+            ));
+        }
+        let data = self.data.read().unwrap().clone();
+        let prefixes: Vec<IpNet> = data
+            .networks
+            .values()
+            .filter(|network| network.country.as_deref() == Some(country))
+            .flat_map(|network| network.prefixes.iter().copied())
+            .collect();
+        Ok(IpNet::aggregate(&prefixes))
+    }
+
+    fn replace(&self, snapshot: Snapshot, updated: SystemTime) {
+        *self.data.write().unwrap() = Arc::new(snapshot);
+        *self.updated.write().unwrap() = Some(updated);
+    }
+
+    async fn download(&self, url: &str) -> Result<String, Error> {
+        let unavailable = |e: String| Error::ProviderUnavailable(format!("{url}: {e}"));
+        let response = self
+            .http
+            .get(url)
+            .timeout(Duration::from_millis(self.config.timeout_ms))
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+            .map_err(|e| unavailable(e.to_string()))?;
+        let bytes = response
+            .bytes()
+            .await
+            .map_err(|e| unavailable(e.to_string()))?;
+        // the published table is gzipped, mirrors may serve it plain
+        if bytes.starts_with(&[0x1f, 0x8b]) {
+            let mut text = String::new();
+            flate2::read::GzDecoder::new(&bytes[..])
+                .read_to_string(&mut text)
+                .map_err(|e| unavailable(e.to_string()))?;
+            Ok(text)
+        } else {
+            String::from_utf8(bytes.to_vec()).map_err(|e| unavailable(e.to_string()))
+        }
+    }
+}
+
+impl Dataset for AsnDb {
+    const NAME: &'static str = "asn";
+
+    fn updated(&self) -> Option<SystemTime> {
+        *self.updated.read().unwrap()
+    }
+
+    fn refresh_interval(&self) -> Duration {
+        Duration::from_secs(self.config.refresh_secs)
+    }
+
+    /// Downloads both datasets and saves the snapshot.
+    async fn refresh(&self) -> Result<usize, Error> {
+        let ranges = self.download(&self.config.ip2asn_url).await?;
+        let mut registries = String::new();
+        for url in &self.config.registry_urls {
+            registries.push_str(&self.download(url).await?);
+            registries.push('\n');
+        }
+        let snapshot = tokio::task::spawn_blocking(move || Snapshot {
+            networks: parse_ranges(&ranges),
+            registries: parse_registries(&registries),
+        })
+        .await
+        .map_err(|e| Error::Internal(e.to_string()))?;
+        if let Some(path) = &self.config.snapshot {
+            save_snapshot(path, &snapshot).await?;
+        }
+        let count = snapshot.networks.len();
+        self.replace(snapshot, SystemTime::now());
+        Ok(count)
+    }
+}
+
+/// `range_start range_end AS_number country_code AS_description` lines,
+/// tab separated. Unrouted ranges have the AS number 0.
+fn parse_ranges(tsv: &str) -> HashMap<u32, Network> {
+    let mut networks: HashMap<u32, Network> = HashMap::new();
+    for line in tsv.lines() {
+        let cells: Vec<&str> = line.split('\t').collect();
+        let [first, last, asn, country, name] = cells[..] else {
+            continue;
+        };
+        let (Ok(first), Ok(last), Ok(asn)) = (
+            first.parse::<IpAddr>(),
+            last.parse::<IpAddr>(),
+            asn.parse::<u32>(),
+        ) else {
+            continue;
+        };
+        if asn == 0 {
+            continue;
+        }
+        let network = networks.entry(asn).or_insert_with(|| Network {
+            name: Some(name.trim()).filter(|n| !n.is_empty()).map(Into::into),
+            country: Some(country)
+                .filter(|c| c.len() == 2)
+                .map(|c| c.to_ascii_uppercase()),
+            prefixes: Vec::new(),
+        });
+        network.prefixes.extend(range_prefixes(first, last));
+    }
+    networks
+}
+
+/// Smallest set of CIDR blocks covering `first..=last`.
+pub(crate) fn range_prefixes(first: IpAddr, last: IpAddr) -> Vec<IpNet> {
+    match (first, last) {
+        (IpAddr::V4(first), IpAddr::V4(last)) => {
+            Ipv4Subnets::new(first, last, 0).map(IpNet::V4).collect()
+        }
+        (IpAddr::V6(first), IpAddr::V6(last)) => {
+            Ipv6Subnets::new(first, last, 0).map(IpNet::V6).collect()
+        }
+        _ => Vec::new(),
+    }
+}
+
+/// IANA `Number,Description,...` rows, `Number` is `64512` or `1-6`. Only the
+/// `Assigned by <registry>` blocks are kept.
+fn parse_registries(csv: &str) -> Vec<RegistryBlock> {
+    let mut blocks: Vec<RegistryBlock> = csv
+        .lines()
+        .filter_map(|line| {
+            let cells = bulk::split(line, ',');
+            let registry = cells.get(1)?.strip_prefix("Assigned by ")?.to_string();
+            let (first, last) = match cells[0].split_once('-') {
+                Some((first, last)) => (first.parse().ok()?, last.parse().ok()?),
+                None => {
+                    let number = cells[0].parse().ok()?;
+                    (number, number)
+                }
+            };
+            Some(RegistryBlock {
+                first,
+                last,
+                registry,
+            })
+        })
+        .collect();
+    blocks.sort_by_key(|block| block.first);
+    blocks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RANGES: &str = "1.0.0.0\t1.0.0.255\t13335\tUS\tCLOUDFLARENET\n\
+        1.0.1.0\t1.0.3.255\t0\tNone\tNot routed\n\
+        8.8.4.0\t8.8.4.255\t15169\tUS\tGOOGLE\n\
+        8.8.8.0\t8.8.8.255\t15169\tUS\tGOOGLE\n\
+        10.0.0.0\t10.0.0.5\t64500\tNone\t\n\
+        2001:4860::\t2001:4860:ffff:ffff:ffff:ffff:ffff:ffff\t15169\tUS\tGOOGLE\n";
+
+    const REGISTRIES: &str = "Number,Description,WHOIS,Reference,Registration Date\n\
+        0,Reserved,,\"[RFC1930][RFC7607]\",\n\
+        1-6,Assigned by ARIN,whois.arin.net,,\n\
+        13312-15359,Assigned by ARIN,whois.arin.net,,\n\
+        64496-64511,\"Reserved for use in documentation and sample code\",,[RFC5398],\n\
+        3154-3353,Assigned by RIPE NCC,whois.ripe.net,,\n";
+
+    fn db() -> AsnDb {
+        let db = AsnDb::new(reqwest::Client::new(), AsnConfig::default());
+        let snapshot = Snapshot {
+            networks: parse_ranges(RANGES),
+            registries: parse_registries(REGISTRIES),
+        };
+        db.replace(snapshot, SystemTime::now());
+        db
+    }
+
+    #[test]
+    fn test_range_prefixes() {
+        let prefixes = range_prefixes("10.0.0.0".parse().unwrap(), "10.0.0.5".parse().unwrap());
+        let prefixes: Vec<String> = prefixes.iter().map(ToString::to_string).collect();
+        assert_eq!(prefixes, ["10.0.0.0/30", "10.0.0.4/31"]);
+    }
+
+    #[test]
+    fn test_parse_registries() {
+        let blocks = parse_registries(REGISTRIES);
+        assert_eq!(blocks.len(), 3);
+        assert_eq!(
+            blocks[1],
+            RegistryBlock {
+                first: 3154,
+                last: 3353,
+                registry: "RIPE NCC".into()
+            }
+        );
+    }
+
+    #[test]
+    fn test_detail() {
+        let db = db();
+        let google = db.detail(15169).unwrap();
+        assert_eq!(google.name.as_deref(), Some("GOOGLE"));
+        assert_eq!(google.country.as_deref(), Some("US"));
+        assert_eq!(google.registry.as_deref(), Some("ARIN"));
+        assert_eq!(google.prefix_count, 3);
+        assert_eq!(google.prefixes[2], "2001:4860::/32");
+
+        let unnamed = db.detail(64500).unwrap();
+        assert_eq!(
+            (unnamed.name, unnamed.country, unnamed.registry),
+            (None, None, None)
+        );
+
+        // assigned but not announced
+        let assigned = db.detail(3320).unwrap();
+        assert_eq!(assigned.registry.as_deref(), Some("RIPE NCC"));
+        assert_eq!(assigned.prefix_count, 0);
+
+        assert!(matches!(db.detail(0), Err(Error::NotFound(_))));
+        assert!(matches!(db.detail(7), Err(Error::NotFound(_))));
+    }
+
+    #[test]
+    fn test_country_prefixes() {
+        let prefixes = db().country_prefixes("US").unwrap();
+        let prefixes: Vec<String> = prefixes.iter().map(ToString::to_string).collect();
+        assert_eq!(
+            prefixes,
+            ["1.0.0.0/24", "8.8.4.0/24", "8.8.8.0/24", "2001:4860::/32"]
+        );
+        assert!(db().country_prefixes("DE").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_not_downloaded() {
+        let db = AsnDb::new(reqwest::Client::new(), AsnConfig::default());
+        assert!(matches!(
+            db.detail(15169),
+            Err(Error::ProviderUnavailable(_))
+        ));
+    }
+}
@@ -0,0 +1,105 @@
+//! Firewall rule sets of a country
+//!
+//! Built from the prefixes the ASN dataset lists for the ASes registered in
+//! the country, so a list follows the dataset refreshes. The registrant
+//! country of an AS is not always where its addresses are used: a global
+//! network is listed under its home country only.
+
+use ipnet::IpNet;
+use serde::Deserialize;
+use std::fmt::Write;
+use utoipa::ToSchema;
+
+#[derive(Deserialize, ToSchema, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum BlocklistFormat {
+    /// One CIDR block per line.
+    #[default]
+    Cidr,
+    /// An `nft -f` table dropping the input from the blocks.
+    Nftables,
+    /// `iptables` and `ip6tables` commands appending drop rules to `INPUT`.
+    Iptables,
+}
+
+/// The rule set dropping the traffic from `prefixes`, listed for `country`.
+pub fn render(country: &str, prefixes: &[IpNet], format: BlocklistFormat) -> String {
+    let mut out = String::new();
+    let (v4, v6): (Vec<IpNet>, Vec<IpNet>) = prefixes
+        .iter()
+        .partition(|prefix| matches!(prefix, IpNet::V4(_)));
+    match format {
+        BlocklistFormat::Cidr => {
+            for prefix in prefixes {
+                writeln!(out, "{prefix}").unwrap();
+            }
+        }
+        BlocklistFormat::Nftables => {
+            let table = format!("blocklist_{}", country.to_ascii_lowercase());
+            writeln!(out, "# prefixes registered in {country}").unwrap();
+            writeln!(out, "table inet {table}").unwrap();
+            writeln!(out, "delete table inet {table}").unwrap();
+            writeln!(out, "table inet {table} {{").unwrap();
+            for (name, kind, set) in [("v4", "ipv4_addr", &v4), ("v6", "ipv6_addr", &v6)] {
+                writeln!(out, "    set {name} {{").unwrap();
+                writeln!(out, "        type {kind}").unwrap();
+                writeln!(out, "        flags interval").unwrap();
+                // nft rejects an empty element list
+                if !set.is_empty() {
+                    let elements: Vec<String> = set.iter().map(ToString::to_string).collect();
+                    writeln!(out, "        elements = {{ {} }}", elements.join(", ")).unwrap();
+                }
+                writeln!(out, "    }}").unwrap();
+            }
+            writeln!(out, "    chain input {{").unwrap();
+            writeln!(
+                out,
+                "        type filter hook input priority 0; policy accept;"
+            )
+            .unwrap();
+            writeln!(out, "        ip saddr @v4 drop").unwrap();
+            writeln!(out, "        ip6 saddr @v6 drop").unwrap();
+            writeln!(out, "    }}").unwrap();
+            writeln!(out, "}}").unwrap();
+        }
+        BlocklistFormat::Iptables => {
+            writeln!(out, "# prefixes registered in {country}").unwrap();
+            for (command, set) in [("iptables", &v4), ("ip6tables", &v6)] {
+                for prefix in set {
+                    writeln!(out, "{command} -A INPUT -s {prefix} -j DROP").unwrap();
+                }
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn prefixes() -> Vec<IpNet> {
+        ["8.8.8.0/24", "2001:4860::/32"]
+            .iter()
+            .map(|p| p.parse().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_render() {
+        let cidr = render("US", &prefixes(), BlocklistFormat::Cidr);
+        assert_eq!(cidr, "8.8.8.0/24\n2001:4860::/32\n");
+
+        let iptables = render("US", &prefixes(), BlocklistFormat::Iptables);
+        assert!(iptables.contains("\niptables -A INPUT -s 8.8.8.0/24 -j DROP\n"));
+        assert!(iptables.ends_with("\nip6tables -A INPUT -s 2001:4860::/32 -j DROP\n"));
+
+        let nft = render("US", &prefixes(), BlocklistFormat::Nftables);
+        assert!(nft.contains("table inet blocklist_us {\n"));
+        assert!(nft.contains("elements = { 8.8.8.0/24 }"));
+        assert!(nft.contains("elements = { 2001:4860::/32 }"));
+
+        let empty = render("US", &[], BlocklistFormat::Nftables);
+        assert!(!empty.contains("elements"), "nft rejects empty elements");
+    }
+}
@@ -0,0 +1,529 @@
+//! Enrichment of address lists read line by line
+//!
+//! Feeds the `batch` command: the input is one address per line, or a CSV
+//! column, and the results come out in input order as soon as they are
+//! available, so log files can be piped through without loading them first.
+//!
+//! [`read_csv`] parses a CSV uploaded for a job the same way, chunk by chunk
+//! as the body arrives, inflating it on the go when it is gzipped.
+
+use crate::{
+    error::Error,
+    service::{dedup_key, BatchRequest},
+    BatchItem, LookupService,
+};
+use flate2::write::GzDecoder;
+use futures::{
+    future::{BoxFuture, Shared},
+    stream, FutureExt, Stream, StreamExt, TryStreamExt,
+};
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    io::Write,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncBufRead, AsyncBufReadExt},
+    time::Instant,
+};
+
+/// CSV column holding the address.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Column {
+    /// 1-based position, the input has no header.
+    Index(usize),
+    /// Header name, the first line is the header.
+    Name(String),
+}
+
+impl FromStr for Column {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.parse::<usize>() {
+            Ok(0) => Err("columns are numbered from 1".into()),
+            Ok(index) => Ok(Column::Index(index)),
+            Err(_) if s.is_empty() => Err("empty column name".into()),
+            Err(_) => Ok(Column::Name(s.to_string())),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct BulkOptions {
+    /// Whole lines are addresses when absent.
+    pub column: Option<Column>,
+    pub delimiter: char,
+    /// Lookups in flight at once.
+    pub concurrency: usize,
+    /// Most lookups started per second, unlimited when absent.
+    pub rate: Option<u32>,
+    /// Provider and fields of every lookup, `ips` is ignored.
+    pub lookup: BatchRequest,
+}
+
+impl Default for BulkOptions {
+    fn default() -> Self {
+        BulkOptions {
+            column: None,
+            delimiter: ',',
+            concurrency: 8,
+            rate: None,
+            lookup: BatchRequest::default(),
+        }
+    }
+}
+
+/// Distinct addresses whose item is kept for their repetitions.
+const DEDUP_CAPACITY: usize = 100_000;
+
+/// Looks up every address of `input`, in input order.
+///
+/// Blank lines and `#` comments are skipped. Lookup failures are reported in
+/// the items, the stream only fails when the input cannot be read. An address
+/// repeated in the input is looked up once, the last [`DEDUP_CAPACITY`]
+/// distinct ones are remembered.
+pub fn enrich<'a, R>(
+    service: &'a LookupService,
+    input: R,
+    options: &'a BulkOptions,
+) -> impl Stream<Item = Result<BatchItem, Error>> + 'a
+where
+    R: AsyncBufRead + Unpin + 'a,
+{
+    let lines = stream::unfold(input.lines(), |mut lines| async move {
+        match lines.next_line().await {
+            Ok(Some(line)) => Some((Ok(line), lines)),
+            Ok(None) => None,
+            Err(e) => Some((Err(Error::InvalidInput(format!("read failed: {e}"))), lines)),
+        }
+    });
+    let mut selector = Selector::new(options.column.clone(), options.delimiter);
+    let pacer = options.rate.map(|rate| Arc::new(Pacer::new(rate)));
+    let mut seen: HashMap<String, Shared<BoxFuture<'a, BatchItem>>> = HashMap::new();
+    lines
+        .try_filter_map(move |line| futures::future::ready(selector.select(&line)))
+        .map_ok(move |ip| {
+            let key = dedup_key(&ip);
+            if !seen.contains_key(&key) && seen.len() >= DEDUP_CAPACITY {
+                seen.clear();
+            }
+            let pacer = pacer.clone();
+            let lookup = seen
+                .entry(key)
+                .or_insert_with(|| {
+                    let ip = ip.clone();
+                    async move {
+                        if let Some(pacer) = pacer {
+                            pacer.wait().await;
+                        }
+                        service.batch_item(&options.lookup, &ip).await
+                    }
+                    .boxed()
+                    .shared()
+                })
+                .clone();
+            async move { Ok(BatchItem { ip, ..lookup.await }) }
+        })
+        .try_buffered(options.concurrency.max(1))
+}
+
+/// Addresses of the CSV body `chunks`, at most `limit` of them. Blank lines
+/// and `#` comments are skipped as in [`enrich`].
+pub async fn read_csv<S, B, E>(
+    mut chunks: S,
+    column: Option<Column>,
+    delimiter: char,
+    limit: usize,
+) -> Result<Vec<String>, Error>
+where
+    S: Stream<Item = Result<B, E>> + Unpin,
+    B: AsRef<[u8]>,
+    E: Display,
+{
+    let mut upload = CsvUpload {
+        selector: Selector::new(column, delimiter),
+        gzip: None,
+        started: false,
+        pending: Vec::new(),
+        ips: Vec::new(),
+        limit,
+    };
+    while let Some(chunk) = chunks.next().await {
+        let chunk = chunk.map_err(|e| Error::InvalidInput(format!("upload failed: {e}")))?;
+        upload.feed(chunk.as_ref())?;
+    }
+    upload.finish()
+}
+
+struct CsvUpload {
+    selector: Selector,
+    /// Inflates the upload once it starts with the gzip magic bytes.
+    gzip: Option<GzDecoder<Vec<u8>>>,
+    /// Whether the first bytes told gzip from plain text.
+    started: bool,
+    /// The first bytes until then, the unterminated last line after.
+    pending: Vec<u8>,
+    ips: Vec<String>,
+    limit: usize,
+}
+
+impl CsvUpload {
+    fn feed(&mut self, chunk: &[u8]) -> Result<(), Error> {
+        if self.started {
+            return self.inflate(chunk);
+        }
+        self.pending.extend_from_slice(chunk);
+        match self.pending.len() {
+            0 | 1 => Ok(()),
+            _ => self.start(),
+        }
+    }
+
+    fn start(&mut self) -> Result<(), Error> {
+        self.started = true;
+        let head = std::mem::take(&mut self.pending);
+        if head.starts_with(&[0x1f, 0x8b]) {
+            self.gzip = Some(GzDecoder::new(Vec::new()));
+        }
+        self.inflate(&head)
+    }
+
+    fn inflate(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        let Some(gzip) = &mut self.gzip else {
+            return self.lines(bytes);
+        };
+        gzip.write_all(bytes).map_err(inflate_error)?;
+        let text = std::mem::take(gzip.get_mut());
+        self.lines(&text)
+    }
+
+    /// Parses the lines `bytes` completes, keeps the rest for the next chunk.
+    fn lines(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        self.pending.extend_from_slice(bytes);
+        let Some(end) = self.pending.iter().rposition(|b| *b == b'\n') else {
+            return Ok(());
+        };
+        let rest = self.pending.split_off(end + 1);
+        let complete = std::mem::replace(&mut self.pending, rest);
+        complete
+            .split(|b| *b == b'\n')
+            .try_for_each(|line| self.line(line))
+    }
+
+    fn line(&mut self, line: &[u8]) -> Result<(), Error> {
+        let line = std::str::from_utf8(line)
+            .map_err(|_| Error::InvalidInput("the upload is not UTF-8 text".into()))?;
+        let Some(ip) = self.selector.select(line)? else {
+            return Ok(());
+        };
+        if self.ips.len() >= self.limit {
+            return Err(Error::InvalidInput(format!(
+                "more than {} addresses in the upload",
+                self.limit
+            )));
+        }
+        self.ips.push(ip);
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<Vec<String>, Error> {
+        if !self.started {
+            self.start()?;
+        }
+        if let Some(gzip) = self.gzip.take() {
+            let rest = gzip.finish().map_err(inflate_error)?;
+            self.lines(&rest)?;
+        }
+        let last = std::mem::take(&mut self.pending);
+        self.line(&last)?;
+        Ok(self.ips)
+    }
+}
+
+fn inflate_error(e: std::io::Error) -> Error {
+    Error::InvalidInput(format!("invalid gzip upload: {e}"))
+}
+
+/// Picks the address out of an input line.
+struct Selector {
+    column: Option<Column>,
+    delimiter: char,
+    /// 0-based position, resolved from the header for named columns.
+    index: Option<usize>,
+}
+
+impl Selector {
+    fn new(column: Option<Column>, delimiter: char) -> Self {
+        let index = match &column {
+            Some(Column::Index(index)) => Some(index - 1),
+            _ => None,
+        };
+        Selector {
+            column,
+            delimiter,
+            index,
+        }
+    }
+
+    fn select(&mut self, line: &str) -> Result<Option<String>, Error> {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            return Ok(None);
+        }
+        let Some(column) = &self.column else {
+            return Ok(Some(line.to_string()));
+        };
+        let cells = split(line, self.delimiter);
+        let Some(index) = self.index else {
+            let Column::Name(name) = column else {
+                unreachable!("indexed columns are resolved up front");
+            };
+            let index = cells
+                .iter()
+                .position(|cell| cell == name)
+                .ok_or_else(|| Error::InvalidInput(format!("no column {name} in the header")))?;
+            self.index = Some(index);
+            return Ok(None);
+        };
+        Ok(Some(cells.into_iter().nth(index).unwrap_or_default()))
+    }
+}
+
+/// Splits a CSV line, `"` quotes a cell and `""` is a quote inside one.
+pub(crate) fn split(line: &str, delimiter: char) -> Vec<String> {
+    let mut cells = Vec::new();
+    let mut cell = String::new();
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                cell.push('"');
+                chars.next();
+            }
+            '"' => quoted = !quoted,
+            c if c == delimiter && !quoted => cells.push(std::mem::take(&mut cell).trim().into()),
+            c => cell.push(c),
+        }
+    }
+    cells.push(cell.trim().into());
+    cells
+}
+
+/// Spaces the lookups evenly, concurrent callers queue for the next slot.
+struct Pacer {
+    period: Duration,
+    next: Mutex<Instant>,
+}
+
+impl Pacer {
+    fn new(rate: u32) -> Self {
+        Pacer {
+            period: Duration::from_secs(1) / rate.max(1),
+            next: Mutex::new(Instant::now()),
+        }
+    }
+
+    async fn wait(&self) {
+        let slot = {
+            let mut next = self.next.lock().unwrap();
+            let slot = (*next).max(Instant::now());
+            *next = slot + self.period;
+            slot
+        };
+        tokio::time::sleep_until(slot).await;
+    }
+}
+
+/// Columns of [`csv_record`].
+pub const CSV_HEADER: &str =
+    "ip,country_code,country,region,city,latitude,longitude,asn,org,vpn,proxy,relay,hosting,provider,error";
+
+/// One CSV line per item, without the line break.
+pub fn csv_record(item: &BatchItem) -> String {
+    let text = |value: &Option<String>| value.clone().unwrap_or_default();
+    let number = |value: Option<f64>| value.map(|v| v.to_string()).unwrap_or_default();
+    let error = item.error.as_ref().map(|e| e.message.clone());
+    let mut cells = vec![item.ip.clone()];
+    match &item.lookup {
+        Some(lookup) => {
+            let geo = &lookup.geo;
+            let anonymity = &lookup.anonymity;
+            cells.extend([
+                text(&geo.country_code),
+                text(&geo.country),
+                text(&geo.region),
+                text(&geo.city),
+                number(geo.latitude),
+                number(geo.longitude),
+                geo.asn.map(|asn| asn.to_string()).unwrap_or_default(),
+                text(&geo.org.clone().or(geo.as_name.clone())),
+                anonymity.vpn.to_string(),
+                anonymity.proxy.to_string(),
+                anonymity.relay.to_string(),
+                anonymity.hosting.to_string(),
+                geo.provider.clone(),
+                String::new(),
+            ]);
+        }
+        None => {
+            cells.resize(14, String::new());
+            cells.push(error.unwrap_or_default());
+        }
+    }
+    let cells: Vec<String> = cells.iter().map(|cell| escape(cell)).collect();
+    cells.join(",")
+}
+
+fn escape(cell: &str) -> String {
+    if cell.contains([',', '"', '\n']) {
+        format!("\"{}\"", cell.replace('"', "\"\""))
+    } else {
+        cell.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{ProviderConfig, ProviderKind};
+
+    fn service() -> LookupService {
+        LookupService::builder()
+            .provider(ProviderConfig::new(ProviderKind::Mock))
+            .build()
+    }
+
+    async fn run(input: &str, options: BulkOptions) -> Result<Vec<BatchItem>, Error> {
+        let service = service();
+        enrich(&service, input.as_bytes(), &options)
+            .try_collect()
+            .await
+    }
+
+    #[tokio::test]
+    async fn test_lines() {
+        let items = run(
+            "8.8.8.8\n\n# comment\nnope\n1.1.1.1\n",
+            BulkOptions::default(),
+        )
+        .await
+        .unwrap();
+        let ips: Vec<&str> = items.iter().map(|item| item.ip.as_str()).collect();
+        assert_eq!(ips, ["8.8.8.8", "nope", "1.1.1.1"]);
+        assert!(items[1].error.is_some());
+        assert_eq!(
+            items[2]
+                .lookup
+                .as_ref()
+                .unwrap()
+                .geo
+                .country_code
+                .as_deref(),
+            Some("AU")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_column() {
+        let input = "time,\"client, ip\",path\n1,1.1.1.1,/a\n2,8.8.8.8,/b\n";
+        let options = BulkOptions {
+            column: Some("client, ip".parse().unwrap()),
+            ..Default::default()
+        };
+        let items = run(input, options).await.unwrap();
+        let ips: Vec<&str> = items.iter().map(|item| item.ip.as_str()).collect();
+        assert_eq!(ips, ["1.1.1.1", "8.8.8.8"]);
+
+        let options = BulkOptions {
+            column: Some(Column::Index(2)),
+            delimiter: ' ',
+            ..Default::default()
+        };
+        let items = run("GET 8.8.8.8 200\n", options).await.unwrap();
+        assert_eq!(items[0].ip, "8.8.8.8");
+
+        let options = BulkOptions {
+            column: Some(Column::Name("ip".into())),
+            ..Default::default()
+        };
+        assert!(run(input, options).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_dedup() {
+        let service = service();
+        let input = "8.8.8.8\n1.1.1.1\n8.8.8.8\n2001:4860:4860::8888\n2001:4860:4860:0::8888\n";
+        let options = BulkOptions::default();
+        let items: Vec<BatchItem> = enrich(&service, input.as_bytes(), &options)
+            .try_collect()
+            .await
+            .unwrap();
+        let ips: Vec<&str> = items.iter().map(|item| item.ip.as_str()).collect();
+        assert_eq!(ips, input.lines().collect::<Vec<_>>());
+        assert_eq!(
+            items[2]
+                .lookup
+                .as_ref()
+                .unwrap()
+                .geo
+                .country_code
+                .as_deref(),
+            Some("US")
+        );
+        assert_eq!(service.usage()[0].keys[0].requests, 3);
+    }
+
+    #[test]
+    fn test_split() {
+        assert_eq!(split(r#"a, "b,c" ,"d""e""#, ','), ["a", "b,c", "d\"e"]);
+        assert_eq!(split("", ','), [""]);
+    }
+
+    #[tokio::test]
+    async fn test_csv_record() {
+        let items = run("8.8.8.8\nnope\n", BulkOptions::default())
+            .await
+            .unwrap();
+        let columns = CSV_HEADER.split(',').count();
+        assert!(csv_record(&items[0]).starts_with("8.8.8.8,US,United States,"));
+        assert_eq!(split(&csv_record(&items[1]), ',').len(), columns);
+        assert!(csv_record(&items[1]).ends_with("invalid IP address nope"));
+    }
+
+    #[tokio::test]
+    async fn test_read_csv() {
+        let chunks = |parts: Vec<Vec<u8>>| stream::iter(parts.into_iter().map(Ok::<_, Error>));
+        let csv = b"time,ip\n# comment\n1,8.8.8.8\r\n\n2,1.1.1.1";
+        let parts = csv.chunks(5).map(<[u8]>::to_vec).collect();
+        let ips = read_csv(chunks(parts), Some("ip".parse().unwrap()), ',', 10)
+            .await
+            .unwrap();
+        assert_eq!(ips, ["8.8.8.8", "1.1.1.1"], "Split across chunks");
+
+        let mut gzip = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+        gzip.write_all(b"8.8.8.8\n9.9.9.9\n").unwrap();
+        let gzip = gzip.finish().unwrap();
+        let parts = gzip.chunks(1).map(<[u8]>::to_vec).collect();
+        let ips = read_csv(chunks(parts), None, ',', 10).await.unwrap();
+        assert_eq!(ips, ["8.8.8.8", "9.9.9.9"], "Gzipped");
+
+        let parts = vec![b"8.8.8.8\n1.1.1.1\n9.9.9.9\n".to_vec()];
+        let result = read_csv(chunks(parts), None, ',', 2).await;
+        assert!(
+            matches!(result, Err(Error::InvalidInput(_))),
+            "Over the limit"
+        );
+        let result = read_csv(chunks(vec![vec![0x1f, 0x8b, 0]]), None, ',', 2).await;
+        assert!(
+            matches!(result, Err(Error::InvalidInput(_))),
+            "Truncated gzip"
+        );
+        let ips = read_csv(chunks(vec![]), None, ',', 2).await.unwrap();
+        assert!(ips.is_empty());
+    }
+}
@@ -0,0 +1,167 @@
+//! In-memory cache with a fixed TTL, keyed by IP address
+//!
+//! Reads are counted per entry since it was stored, [`TtlCache::hot`] lists
+//! the most read entries close to their expiry for a refresh ahead of time.
+
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+pub struct TtlCache<V> {
+    ttl: Duration,
+    capacity: usize,
+    entries: Mutex<HashMap<IpAddr, Entry<V>>>,
+}
+
+struct Entry<V> {
+    stored: Instant,
+    /// Reads since the entry was stored.
+    hits: u64,
+    value: V,
+}
+
+impl<V: Clone> TtlCache<V> {
+    pub fn new(ttl: Duration, capacity: usize) -> Self {
+        TtlCache {
+            ttl,
+            capacity,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns the cached value if it has not expired yet.
+    pub fn get(&self, ip: &IpAddr) -> Option<V> {
+        self.get_within(ip, self.ttl)
+    }
+
+    /// Returns the cached value if it is younger than `max_age` and has not expired.
+    pub fn get_within(&self, ip: &IpAddr, max_age: Duration) -> Option<V> {
+        let max_age = max_age.min(self.ttl);
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries
+            .get_mut(ip)
+            .filter(|entry| entry.stored.elapsed() < max_age)?;
+        entry.hits += 1;
+        Some(entry.value.clone())
+    }
+
+    /// Time left until the entry of `ip` expires.
+    pub fn remaining(&self, ip: &IpAddr) -> Option<Duration> {
+        let entries = self.entries.lock().unwrap();
+        entries
+            .get(ip)
+            .and_then(|entry| self.ttl.checked_sub(entry.stored.elapsed()))
+            .filter(|left| !left.is_zero())
+    }
+
+    /// Time since the entry of `ip` was stored, expired or not.
+    pub fn age(&self, ip: &IpAddr) -> Option<Duration> {
+        let entries = self.entries.lock().unwrap();
+        entries.get(ip).map(|entry| entry.stored.elapsed())
+    }
+
+    /// At most `top` of the most read entries expiring within `within`,
+    /// entries never read since they were stored are left out.
+    pub fn hot(&self, top: usize, within: Duration) -> Vec<IpAddr> {
+        let entries = self.entries.lock().unwrap();
+        let mut hot: Vec<(u64, IpAddr)> = entries
+            .iter()
+            .filter(|(_, entry)| entry.hits > 0)
+            .filter(|(_, entry)| {
+                self.ttl
+                    .checked_sub(entry.stored.elapsed())
+                    .is_some_and(|left| !left.is_zero() && left <= within)
+            })
+            .map(|(ip, entry)| (entry.hits, *ip))
+            .collect();
+        hot.sort_unstable_by(|a, b| b.cmp(a));
+        hot.into_iter().take(top).map(|(_, ip)| ip).collect()
+    }
+
+    pub fn insert(&self, ip: IpAddr, value: V) {
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= self.capacity && !entries.contains_key(&ip) {
+            entries.retain(|_, entry| entry.stored.elapsed() < self.ttl);
+        }
+        if entries.len() >= self.capacity && !entries.contains_key(&ip) {
+            // still full, drop the oldest entry
+            let oldest = entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.stored)
+                .map(|(ip, _)| *ip);
+            if let Some(oldest) = oldest {
+                entries.remove(&oldest);
+            }
+        }
+        let entry = Entry {
+            stored: Instant::now(),
+            hits: 0,
+            value,
+        };
+        entries.insert(ip, entry);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expire() {
+        let cache = TtlCache::new(Duration::ZERO, 10);
+        let ip = "1.1.1.1".parse().unwrap();
+        cache.insert(ip, 1);
+        assert_eq!(cache.get(&ip), None, "Entry should be expired");
+        assert_eq!(cache.remaining(&ip), None);
+
+        let cache = TtlCache::new(Duration::from_secs(60), 10);
+        cache.insert(ip, 1);
+        assert_eq!(cache.get(&ip), Some(1));
+        assert!(cache.remaining(&ip).unwrap() > Duration::from_secs(59));
+        assert_eq!(cache.get_within(&ip, Duration::ZERO), None, "Too old");
+        assert_eq!(cache.get_within(&ip, Duration::MAX), Some(1));
+        assert!(cache.age(&ip).unwrap() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_capacity() {
+        let cache = TtlCache::new(Duration::from_secs(60), 2);
+        let ips: Vec<IpAddr> = ["1.1.1.1", "2.2.2.2", "3.3.3.3"]
+            .iter()
+            .map(|ip| ip.parse().unwrap())
+            .collect();
+        for (i, ip) in ips.iter().enumerate() {
+            cache.insert(*ip, i);
+        }
+        assert_eq!(cache.get(&ips[0]), None, "Oldest entry should be evicted");
+        assert_eq!(cache.get(&ips[2]), Some(2));
+    }
+
+    #[test]
+    fn test_hot() {
+        let cache = TtlCache::new(Duration::from_secs(60), 10);
+        let ips: Vec<IpAddr> = ["1.1.1.1", "2.2.2.2", "3.3.3.3"]
+            .iter()
+            .map(|ip| ip.parse().unwrap())
+            .collect();
+        for (i, ip) in ips.iter().enumerate() {
+            cache.insert(*ip, i);
+            for _ in 0..i {
+                cache.get(ip);
+            }
+        }
+        assert!(
+            cache.hot(10, Duration::from_secs(30)).is_empty(),
+            "Not expiring yet"
+        );
+        let within = Duration::from_secs(60);
+        assert_eq!(cache.hot(10, within), vec![ips[2], ips[1]]);
+        assert_eq!(cache.hot(1, within), vec![ips[2]]);
+
+        cache.insert(ips[2], 2);
+        assert_eq!(cache.hot(10, within), vec![ips[1]], "Stored again");
+    }
+}
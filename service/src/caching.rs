@@ -0,0 +1,162 @@
+//! HTTP caching of lookup responses
+//!
+//! A response is fresh for as long as the provider answer behind it stays in
+//! the service cache, so intermediaries and clients expire it at the same
+//! time. The `ETag` is a digest of the body, a client sending it back in
+//! `If-None-Match` gets a 304 without the body while nothing changed. Whether
+//! the answer came from the cache is the `X-Cache` header, not part of it.
+
+use axum::{
+    http::{
+        header::{CACHE_CONTROL, CONTENT_TYPE, ETAG, IF_NONE_MATCH},
+        HeaderMap, HeaderName, HeaderValue, StatusCode,
+    },
+    response::{IntoResponse, Response},
+};
+use serde::Serialize;
+use std::time::Duration;
+
+/// Who may store a response.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scope {
+    /// Shared caches too, the response only depends on the URL.
+    Public,
+    /// Only the client, e.g. the lookup of the caller.
+    Private,
+}
+
+/// JSON response of `value` with `Cache-Control` and `ETag`, or a 304 when
+/// the request already holds it. Without a `max_age` caches have to
+/// revalidate before every reuse.
+pub fn json<T: Serialize>(
+    request: &HeaderMap,
+    value: &T,
+    max_age: Option<Duration>,
+    scope: Scope,
+) -> Response {
+    let body = match serde_json::to_vec(value) {
+        Ok(body) => body,
+        Err(e) => return crate::Error::Internal(e.to_string()).into_response(),
+    };
+    let tag = etag(&body);
+    let scope = match scope {
+        Scope::Public => "public",
+        Scope::Private => "private",
+    };
+    let cache_control = match max_age {
+        Some(max_age) => format!("{scope}, max-age={}", max_age.as_secs()),
+        None => format!("{scope}, no-cache"),
+    };
+    let mut headers = HeaderMap::new();
+    headers.insert(ETAG, HeaderValue::from_str(&tag).expect("hex digest"));
+    headers.insert(
+        CACHE_CONTROL,
+        HeaderValue::from_str(&cache_control).expect("ascii directives"),
+    );
+    if matches(request, &tag) {
+        return (StatusCode::NOT_MODIFIED, headers).into_response();
+    }
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    (headers, body).into_response()
+}
+
+/// `hit` when the provider answer came from the service cache, else `miss`.
+pub const X_CACHE: HeaderName = HeaderName::from_static("x-cache");
+
+/// `response` with the `X-Cache` of a lookup.
+pub fn cache_status(mut response: Response, cached: bool) -> Response {
+    let status = match cached {
+        true => "hit",
+        false => "miss",
+    };
+    response
+        .headers_mut()
+        .insert(X_CACHE, HeaderValue::from_static(status));
+    response
+}
+
+/// Strong tag of the first 128 bits of the SHA-256 of the body.
+fn etag(body: &[u8]) -> String {
+    let digest = ring::digest::digest(&ring::digest::SHA256, body);
+    format!("\"{}\"", hex::encode(&digest.as_ref()[..16]))
+}
+
+/// `If-None-Match` uses the weak comparison, `W/` prefixes are ignored.
+fn matches(request: &HeaderMap, tag: &str) -> bool {
+    request
+        .get_all(IF_NONE_MATCH)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|candidate| candidate.trim())
+        .any(|candidate| candidate == "*" || candidate.trim_start_matches("W/") == tag)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_headers() {
+        let value = serde_json::json!({ "ip": "8.8.8.8" });
+        let response = json(
+            &HeaderMap::new(),
+            &value,
+            Some(Duration::from_secs(90)),
+            Scope::Public,
+        );
+        assert_eq!(response.status(), StatusCode::OK);
+        let headers = response.headers();
+        assert_eq!(headers[CACHE_CONTROL], "public, max-age=90");
+        assert_eq!(headers[CONTENT_TYPE], "application/json");
+        let tag = headers[ETAG].to_str().unwrap();
+        assert_eq!(tag.len(), 34);
+
+        let response = json(&HeaderMap::new(), &value, None, Scope::Private);
+        assert_eq!(response.headers()[CACHE_CONTROL], "private, no-cache");
+        assert_eq!(response.headers()[ETAG], tag, "same body, same tag");
+    }
+
+    #[test]
+    fn test_not_modified() {
+        let value = serde_json::json!({ "ip": "8.8.8.8" });
+        let tag = etag(&serde_json::to_vec(&value).unwrap());
+        for header in [tag.clone(), format!("\"other\", W/{tag}"), "*".into()] {
+            let mut request = HeaderMap::new();
+            request.insert(IF_NONE_MATCH, HeaderValue::from_str(&header).unwrap());
+            let response = json(&request, &value, None, Scope::Public);
+            assert_eq!(response.status(), StatusCode::NOT_MODIFIED, "{header}");
+            assert_eq!(response.headers()[ETAG], tag.as_str());
+        }
+
+        let mut request = HeaderMap::new();
+        request.insert(IF_NONE_MATCH, HeaderValue::from_static("\"other\""));
+        let response = json(&request, &value, None, Scope::Public);
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_cache_status() {
+        use crate::{
+            config::{CacheConfig, ProviderConfig, ProviderKind},
+            LookupRequest, LookupService,
+        };
+
+        let service = LookupService::builder()
+            .provider(ProviderConfig::new(ProviderKind::Mock))
+            .cache(CacheConfig::default())
+            .build();
+        let req = LookupRequest::ip("8.8.8.8");
+        let miss = service.lookup(&req).await.unwrap();
+        let hit = service.lookup(&req).await.unwrap();
+        assert!(!miss.cached && hit.cached);
+        let response = |lookup: &crate::Lookup| {
+            let response = json(&HeaderMap::new(), lookup, None, Scope::Public);
+            cache_status(response, lookup.cached)
+        };
+        let (miss, hit) = (response(&miss), response(&hit));
+        assert_eq!(miss.headers()[ETAG], hit.headers()[ETAG]);
+        assert_eq!(miss.headers()[X_CACHE], "miss");
+        assert_eq!(hit.headers()[X_CACHE], "hit");
+    }
+}
@@ -0,0 +1,218 @@
+//! The most active callers of the API
+//!
+//! The API requests of the last hour in one-minute buckets, counted per client
+//! address: requests, failed ones and distinct request paths, which grow with
+//! every address a scraper walks through `/v1/lookup/{ip}`. Held in memory
+//! only and reported by `/v1/reports/top-callers`.
+
+use crate::extract::ClientIp;
+use axum::{
+    extract::{Request, State},
+    middleware::Next,
+    response::Response,
+};
+use serde::Serialize;
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use utoipa::ToSchema;
+
+/// Minutes of requests kept.
+pub const WINDOW_MINS: u64 = 60;
+/// Callers counted per minute, the further ones are left out until the next.
+const CALLERS_PER_MIN: usize = 10_000;
+/// Distinct paths counted per caller and minute.
+const PATHS_PER_MIN: usize = 1_000;
+
+#[derive(Debug, Default)]
+struct Counts {
+    requests: u64,
+    errors: u64,
+    paths: HashSet<String>,
+}
+
+#[derive(Debug, Default)]
+struct Minute {
+    minute: u64,
+    callers: HashMap<IpAddr, Counts>,
+}
+
+/// Requests of one caller over the window.
+#[derive(Serialize, ToSchema, Debug, Clone, PartialEq)]
+pub struct CallerStats {
+    #[schema(value_type = String, example = "203.0.113.7")]
+    pub ip: IpAddr,
+    pub requests: u64,
+    /// Requests answered with a 4xx or 5xx status.
+    pub errors: u64,
+    pub error_rate: f64,
+    /// Distinct request paths, up to 1000 a minute are counted.
+    pub distinct_targets: usize,
+}
+
+#[derive(Serialize, ToSchema, Debug, Clone, PartialEq)]
+pub struct TopCallers {
+    pub window_mins: u64,
+    /// Distinct callers over the window.
+    pub callers: usize,
+    /// Requests of all callers over the window.
+    pub requests: u64,
+    /// Most requests first.
+    pub top: Vec<CallerStats>,
+}
+
+#[derive(Debug, Default)]
+pub struct Callers {
+    minutes: Mutex<VecDeque<Minute>>,
+}
+
+impl Callers {
+    /// Counts a request of `ip` to `path`, answered with `status`.
+    pub fn request(&self, ip: IpAddr, path: &str, status: u16) {
+        self.request_at(now(), ip, path, status);
+    }
+
+    /// The `limit` callers with the most requests over the last `window_mins`.
+    pub fn top(&self, window_mins: u64, limit: usize) -> TopCallers {
+        self.top_at(now(), window_mins, limit)
+    }
+
+    fn request_at(&self, now: u64, ip: IpAddr, path: &str, status: u16) {
+        let minute = now / 60;
+        let mut minutes = self.minutes.lock().unwrap();
+        expire(&mut minutes, minute);
+        if minutes.back().is_none_or(|m| m.minute < minute) {
+            minutes.push_back(Minute {
+                minute,
+                ..Default::default()
+            });
+        }
+        // a clock stepping back counts into the latest minute
+        let callers = &mut minutes.back_mut().expect("pushed").callers;
+        if callers.len() >= CALLERS_PER_MIN && !callers.contains_key(&ip) {
+            return;
+        }
+        let counts = callers.entry(ip).or_default();
+        counts.requests += 1;
+        if status >= 400 {
+            counts.errors += 1;
+        }
+        if counts.paths.len() < PATHS_PER_MIN {
+            counts.paths.insert(path.to_string());
+        }
+    }
+
+    fn top_at(&self, now: u64, window_mins: u64, limit: usize) -> TopCallers {
+        let window_mins = window_mins.clamp(1, WINDOW_MINS);
+        let minute = now / 60;
+        let mut minutes = self.minutes.lock().unwrap();
+        expire(&mut minutes, minute);
+        let mut totals: HashMap<IpAddr, (u64, u64, HashSet<&str>)> = HashMap::new();
+        for m in minutes.iter().filter(|m| m.minute + window_mins > minute) {
+            for (ip, counts) in &m.callers {
+                let total = totals.entry(*ip).or_default();
+                total.0 += counts.requests;
+                total.1 += counts.errors;
+                total.2.extend(counts.paths.iter().map(String::as_str));
+            }
+        }
+        let mut top: Vec<CallerStats> = totals
+            .iter()
+            .map(|(ip, (requests, errors, paths))| CallerStats {
+                ip: *ip,
+                requests: *requests,
+                errors: *errors,
+                error_rate: *errors as f64 / *requests as f64,
+                distinct_targets: paths.len(),
+            })
+            .collect();
+        top.sort_by(|a, b| b.requests.cmp(&a.requests).then(a.ip.cmp(&b.ip)));
+        TopCallers {
+            window_mins,
+            callers: top.len(),
+            requests: top.iter().map(|caller| caller.requests).sum(),
+            top: top.into_iter().take(limit).collect(),
+        }
+    }
+}
+
+fn expire(minutes: &mut VecDeque<Minute>, minute: u64) {
+    while minutes
+        .front()
+        .is_some_and(|m| m.minute + WINDOW_MINS <= minute)
+    {
+        minutes.pop_front();
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Middleware counting the requests of the rest of the stack per caller.
+pub async fn track(
+    State(callers): State<Arc<Callers>>,
+    client: Option<ClientIp>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let path = request.uri().path().to_string();
+    let response = next.run(request).await;
+    if let Some(ClientIp(ip)) = client {
+        callers.request(ip, &path, response.status().as_u16());
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_top() {
+        let callers = Callers::default();
+        let scraper: IpAddr = "203.0.113.7".parse().unwrap();
+        let app: IpAddr = "198.51.100.1".parse().unwrap();
+        for i in 0..30 {
+            callers.request_at(600, scraper, &format!("/v1/lookup/10.0.0.{i}"), 200);
+        }
+        callers.request_at(660, scraper, "/v1/lookup/10.0.0.0", 429);
+        for _ in 0..10 {
+            callers.request_at(660, app, "/v1/whoami", 200);
+        }
+        callers.request_at(660, app, "/v1/lookup/nope", 400);
+
+        let report = callers.top_at(700, 60, 10);
+        assert_eq!((report.callers, report.requests), (2, 42));
+        let top = &report.top[0];
+        assert_eq!((top.ip, top.requests, top.errors), (scraper, 31, 1));
+        assert_eq!(top.distinct_targets, 30, "Paths repeated across minutes");
+        assert_eq!(report.top[1].distinct_targets, 2);
+        assert_eq!(report.top[1].error_rate, 1.0 / 11.0);
+
+        assert_eq!(callers.top_at(700, 60, 1).top.len(), 1);
+        let recent = callers.top_at(700, 1, 10);
+        assert_eq!(recent.top[0].ip, app, "Only the last minute");
+        assert_eq!(recent.top[1].requests, 1);
+        let later = callers.top_at(600 + WINDOW_MINS * 60, 60, 10);
+        assert_eq!(later.requests, 12, "Oldest minute out of the window");
+    }
+
+    #[test]
+    fn test_limits() {
+        let callers = Callers::default();
+        let ip: IpAddr = "203.0.113.7".parse().unwrap();
+        for i in 0..PATHS_PER_MIN + 5 {
+            callers.request_at(0, ip, &format!("/v1/lookup/{i}"), 200);
+        }
+        let report = callers.top_at(0, 60, 10);
+        assert_eq!(report.top[0].requests, PATHS_PER_MIN as u64 + 5);
+        assert_eq!(report.top[0].distinct_targets, PATHS_PER_MIN);
+    }
+}
@@ -0,0 +1,277 @@
+//! End-to-end canary lookups
+//!
+//! `/health` answering only tells that the process is alive. Every
+//! `canary.interval_secs` the canary looks up `canary.ip` through each
+//! provider, strictly and past the caches, and once through `/v1/lookup` of
+//! the service's own API, checking the answers carry `canary.expect_country`.
+//! The latest report is served by `/health`. The service can answer when the
+//! API check passed and at least one provider did, one failing provider is
+//! covered by the failover, like for readiness. The API lookups count in the
+//! traffic like any caller's.
+
+use crate::{
+    config::{CanaryConfig, FailoverPolicy},
+    providers::USER_AGENT,
+    service::{Lookup, LookupRequest, LookupService},
+};
+use chrono::{SecondsFormat, Utc};
+use futures::future::join_all;
+use reqwest::{Client, Url};
+use serde::Serialize;
+use serde_json::Value;
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use utoipa::ToSchema;
+
+#[derive(Serialize, ToSchema, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CheckKind {
+    Provider,
+    /// `/v1/lookup` of the service's API.
+    Api,
+}
+
+#[derive(Serialize, ToSchema, Debug, Clone, PartialEq)]
+pub struct CanaryCheck {
+    /// Provider name, or `api`.
+    pub name: String,
+    pub kind: CheckKind,
+    pub ok: bool,
+    pub latency_ms: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub country_code: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, ToSchema, Debug, Clone, PartialEq)]
+pub struct CanaryReport {
+    /// RFC 3339 time of the run.
+    pub at: String,
+    pub ip: String,
+    /// The API check passed and at least one provider did.
+    pub ok: bool,
+    /// Runs in a row that were not `ok`, this one included.
+    pub consecutive_failures: u32,
+    pub checks: Vec<CanaryCheck>,
+}
+
+pub struct Canary {
+    http: Client,
+    config: CanaryConfig,
+    url: Option<Url>,
+    latest: Mutex<Option<CanaryReport>>,
+}
+
+impl Canary {
+    pub fn new(config: CanaryConfig) -> Result<Self, String> {
+        let http = Client::builder()
+            .user_agent(USER_AGENT)
+            .timeout(Duration::from_millis(config.timeout_ms))
+            .build()
+            .map_err(|e| format!("canary: {e}"))?;
+        let url = match &config.url {
+            Some(url) => Some(lookup_url(url, &config.ip)?),
+            None => None,
+        };
+        Ok(Canary {
+            http,
+            config,
+            url,
+            latest: Mutex::new(None),
+        })
+    }
+
+    pub fn interval(&self) -> Duration {
+        Duration::from_secs(self.config.interval_secs)
+    }
+
+    pub fn latest(&self) -> Option<CanaryReport> {
+        self.latest.lock().unwrap().clone()
+    }
+
+    /// Runs every check at once and keeps the report as the latest.
+    pub async fn run(&self, service: &LookupService) -> CanaryReport {
+        let providers = service.providers();
+        let provider_checks = join_all(
+            providers
+                .iter()
+                .map(|provider| self.provider(service, &provider.name)),
+        );
+        let (mut checks, api) = futures::join!(provider_checks, self.api());
+        let providers_ok = checks.iter().any(|check| check.ok);
+        let api_ok = api.as_ref().is_none_or(|check| check.ok);
+        checks.extend(api);
+        let ok = providers_ok && api_ok;
+        let mut latest = self.latest.lock().unwrap();
+        let consecutive_failures = match (ok, latest.as_ref()) {
+            (true, _) => 0,
+            (false, Some(previous)) => previous.consecutive_failures + 1,
+            (false, None) => 1,
+        };
+        let report = CanaryReport {
+            at: Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true),
+            ip: self.config.ip.clone(),
+            ok,
+            consecutive_failures,
+            checks,
+        };
+        *latest = Some(report.clone());
+        report
+    }
+
+    async fn provider(&self, service: &LookupService, name: &str) -> CanaryCheck {
+        let req = LookupRequest {
+            provider: Some(name.to_string()),
+            failover: Some(FailoverPolicy::Strict),
+            max_age_secs: Some(0),
+            timeout_ms: Some(self.config.timeout_ms),
+            ..LookupRequest::ip(&self.config.ip)
+        };
+        let start = Instant::now();
+        let result = service.lookup(&req).await;
+        let latency_ms = start.elapsed().as_millis() as u64;
+        let country_code = match result {
+            Ok(Lookup { degraded: true, .. }) => Err("degraded".to_string()),
+            Ok(lookup) => Ok(lookup.geo.country_code),
+            Err(e) => Err(e.to_string()),
+        };
+        self.check(name, CheckKind::Provider, latency_ms, country_code)
+    }
+
+    async fn api(&self) -> Option<CanaryCheck> {
+        let url = self.url.clone()?;
+        let start = Instant::now();
+        let country_code = async {
+            let response = self.http.get(url).send().await.map_err(|e| e.to_string())?;
+            let status = response.status();
+            if !status.is_success() {
+                return Err(format!("answered {status}"));
+            }
+            let body: Value = response.json().await.map_err(|e| e.to_string())?;
+            Ok(body["geo"]["country_code"].as_str().map(str::to_string))
+        }
+        .await;
+        let latency_ms = start.elapsed().as_millis() as u64;
+        Some(self.check("api", CheckKind::Api, latency_ms, country_code))
+    }
+
+    /// Outcome of a lookup answering `country_code`.
+    fn check(
+        &self,
+        name: &str,
+        kind: CheckKind,
+        latency_ms: u64,
+        country_code: Result<Option<String>, String>,
+    ) -> CanaryCheck {
+        let error = match (&country_code, &self.config.expect_country) {
+            (Err(e), _) => Some(e.clone()),
+            (Ok(None), _) => Some("no country".to_string()),
+            (Ok(Some(got)), Some(expected)) if !got.eq_ignore_ascii_case(expected) => {
+                Some(format!("country {got}, expected {expected}"))
+            }
+            (Ok(Some(_)), _) => None,
+        };
+        CanaryCheck {
+            name: name.to_string(),
+            kind,
+            ok: error.is_none(),
+            latency_ms,
+            country_code: country_code.ok().flatten(),
+            error,
+        }
+    }
+}
+
+/// `/v1/lookup/{ip}` below the base `url`.
+fn lookup_url(url: &str, ip: &str) -> Result<Url, String> {
+    let mut url = Url::parse(url).map_err(|e| format!("canary: invalid url {url}: {e}"))?;
+    url.path_segments_mut()
+        .map_err(|_| "canary: invalid url".to_string())?
+        .pop_if_empty()
+        .extend(["v1", "lookup", ip]);
+    Ok(url)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{ProviderConfig, ProviderKind};
+    use axum::{extract::Path, routing::get, Json, Router};
+    use serde_json::json;
+
+    fn service() -> LookupService {
+        LookupService::builder()
+            .provider(ProviderConfig::new(ProviderKind::Mock))
+            .build()
+    }
+
+    #[test]
+    fn test_check() {
+        let canary = Canary::new(CanaryConfig::default()).unwrap();
+        let check = canary.check("mock", CheckKind::Provider, 5, Ok(Some("us".into())));
+        assert!(check.ok);
+        let check = canary.check("mock", CheckKind::Provider, 5, Ok(Some("DE".into())));
+        assert_eq!(check.error.as_deref(), Some("country DE, expected US"));
+        assert!(!canary.check("api", CheckKind::Api, 5, Ok(None)).ok);
+        assert_eq!(
+            lookup_url("http://127.0.0.1:8080/", "8.8.8.8")
+                .unwrap()
+                .as_str(),
+            "http://127.0.0.1:8080/v1/lookup/8.8.8.8"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_run() {
+        let app = Router::new().route(
+            "/v1/lookup/:ip",
+            get(|Path(ip): Path<String>| async move {
+                let country = if ip == "8.8.8.8" { "US" } else { "DE" };
+                Json(json!({ "ip": ip, "geo": { "country_code": country } }))
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        let url = Some(format!("http://127.0.0.1:{port}"));
+
+        let service = service();
+        let canary = Canary::new(CanaryConfig {
+            url: url.clone(),
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(canary.latest(), None);
+        let report = canary.run(&service).await;
+        assert!(report.ok, "{report:?}");
+        assert_eq!(report.checks.len(), 2);
+        assert_eq!(report.checks[0].name, "mock");
+        assert_eq!(report.checks[1].kind, CheckKind::Api);
+        assert_eq!(canary.latest(), Some(report));
+
+        // any country passes without expect_country
+        let canary = Canary::new(CanaryConfig {
+            ip: "192.0.2.1".into(),
+            expect_country: None,
+            url,
+            ..Default::default()
+        })
+        .unwrap();
+        assert!(canary.run(&service).await.ok);
+        let canary = Canary::new(CanaryConfig {
+            ip: "192.0.2.1".into(),
+            expect_country: Some("ZZ".into()),
+            url: Some("http://127.0.0.1:1".into()),
+            ..Default::default()
+        })
+        .unwrap();
+        canary.run(&service).await;
+        let report = canary.run(&service).await;
+        assert!(!report.ok);
+        assert_eq!(report.consecutive_failures, 2);
+        assert!(report.checks.iter().all(|check| !check.ok));
+    }
+}
@@ -0,0 +1,259 @@
+//! Mobile carriers and connection types
+//!
+//! Providers name the same operator differently (`T-Mobile USA, Inc.`,
+//! `TMOBILE`) and some only flag the address as mobile. [`normalize`] maps the
+//! carrier to an operator of the embedded table and fills in its MCC and MNC,
+//! [`classify`] sorts the address into mobile, hosting or broadband.
+
+use crate::{anonymity::Anonymity, geo::Geo};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+/// Mobile network operator reported for an address.
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone, PartialEq, Default)]
+pub struct Carrier {
+    pub name: Option<String>,
+    /// Mobile country code, e.g. `310`.
+    pub mcc: Option<String>,
+    /// Mobile network code, e.g. `260`.
+    pub mnc: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ConnectionType {
+    /// Cellular network of a mobile carrier.
+    Mobile,
+    /// Fixed line access network.
+    Broadband,
+    /// Hosting, cloud or colocation provider.
+    Hosting,
+}
+
+struct Operator {
+    country: &'static str,
+    /// Lowercase words of the names providers use.
+    keywords: &'static [&'static str],
+    name: &'static str,
+    mcc: &'static str,
+    mnc: &'static str,
+}
+
+const fn operator(
+    country: &'static str,
+    keywords: &'static [&'static str],
+    name: &'static str,
+    mcc: &'static str,
+    mnc: &'static str,
+) -> Operator {
+    Operator {
+        country,
+        keywords,
+        name,
+        mcc,
+        mnc,
+    }
+}
+
+/// Largest operators by country, with the main network code of each.
+const OPERATORS: &[Operator] = &[
+    operator("AU", &["telstra"], "Telstra", "505", "01"),
+    operator("AU", &["optus"], "Optus", "505", "02"),
+    operator("AU", &["vodafone", "tpg"], "Vodafone", "505", "03"),
+    operator("BR", &["claro"], "Claro", "724", "05"),
+    operator("BR", &["vivo", "telefonica"], "Vivo", "724", "06"),
+    operator("BR", &["tim"], "TIM", "724", "02"),
+    operator("CA", &["telus"], "Telus", "302", "220"),
+    operator("CA", &["bell"], "Bell", "302", "610"),
+    operator("CA", &["rogers"], "Rogers", "302", "720"),
+    operator("CN", &["china mobile"], "China Mobile", "460", "00"),
+    operator("CN", &["china unicom"], "China Unicom", "460", "01"),
+    operator("CN", &["china telecom"], "China Telecom", "460", "11"),
+    operator("DE", &["telekom"], "Telekom", "262", "01"),
+    operator("DE", &["vodafone"], "Vodafone", "262", "02"),
+    operator("DE", &["telefonica", "o2"], "O2", "262", "03"),
+    operator("ES", &["vodafone"], "Vodafone", "214", "01"),
+    operator("ES", &["orange"], "Orange", "214", "03"),
+    operator("ES", &["movistar", "telefonica"], "Movistar", "214", "07"),
+    operator("FR", &["orange"], "Orange", "208", "01"),
+    operator("FR", &["sfr"], "SFR", "208", "10"),
+    operator(
+        "FR",
+        &["free mobile", "free sas"],
+        "Free Mobile",
+        "208",
+        "15",
+    ),
+    operator("FR", &["bouygues"], "Bouygues Telecom", "208", "20"),
+    operator("GB", &["o2", "telefonica"], "O2", "234", "10"),
+    operator("GB", &["vodafone"], "Vodafone", "234", "15"),
+    operator("GB", &["three", "hutchison"], "Three", "234", "20"),
+    operator("GB", &["ee", "everything everywhere"], "EE", "234", "30"),
+    operator("IT", &["tim", "telecom italia"], "TIM", "222", "01"),
+    operator("IT", &["vodafone"], "Vodafone", "222", "10"),
+    operator("IT", &["iliad"], "Iliad", "222", "50"),
+    operator("IT", &["wind", "windtre"], "WINDTRE", "222", "88"),
+    operator("JP", &["docomo"], "NTT Docomo", "440", "10"),
+    operator("JP", &["rakuten"], "Rakuten Mobile", "440", "11"),
+    operator("JP", &["softbank"], "SoftBank", "440", "20"),
+    operator("JP", &["kddi"], "au (KDDI)", "440", "50"),
+    operator("KR", &["sk telecom"], "SK Telecom", "450", "05"),
+    operator("KR", &["lg u", "lg uplus"], "LG U+", "450", "06"),
+    operator("KR", &["kt", "korea telecom"], "KT", "450", "08"),
+    operator("NL", &["vodafone"], "Vodafone", "204", "04"),
+    operator("NL", &["kpn"], "KPN", "204", "08"),
+    operator("NL", &["t-mobile", "odido"], "Odido", "204", "16"),
+    operator("RU", &["mts", "mobile telesystems"], "MTS", "250", "01"),
+    operator("RU", &["megafon"], "MegaFon", "250", "02"),
+    operator("RU", &["tele2", "t2 mobile"], "Tele2", "250", "20"),
+    operator("RU", &["beeline", "vimpelcom"], "Beeline", "250", "99"),
+    operator(
+        "US",
+        &["t-mobile", "tmobile", "sprint"],
+        "T-Mobile",
+        "310",
+        "260",
+    ),
+    operator("US", &["at&t"], "AT&T", "310", "410"),
+    operator("US", &["verizon", "cellco"], "Verizon", "311", "480"),
+];
+
+/// Canonical name and codes of the carrier in `geo`, when the table knows it.
+///
+/// Codes reported by the provider win, the name is only used to find the
+/// operator when they are missing.
+pub fn normalize(geo: &mut Geo) {
+    let Some(carrier) = &mut geo.carrier else {
+        return;
+    };
+    let by_codes = |op: &&Operator| {
+        carrier.mcc.as_deref() == Some(op.mcc) && carrier.mnc.as_deref() == Some(op.mnc)
+    };
+    let known = match (&carrier.mcc, &carrier.mnc) {
+        (Some(_), Some(_)) => OPERATORS.iter().find(by_codes),
+        _ => {
+            let name = words(carrier.name.as_deref().unwrap_or_default());
+            OPERATORS.iter().find(|op| {
+                geo.country_code.as_deref() == Some(op.country)
+                    && op
+                        .keywords
+                        .iter()
+                        .any(|keyword| name.contains(&format!(" {keyword} ")))
+            })
+        }
+    };
+    if let Some(op) = known {
+        carrier.name = Some(op.name.to_string());
+        carrier.mcc = Some(op.mcc.to_string());
+        carrier.mnc = Some(op.mnc.to_string());
+    }
+}
+
+/// ` t-mobile usa inc `: lowercase words between single spaces.
+fn words(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .map(|c| match c {
+            c if c.is_alphanumeric() || c == '&' || c == '-' => c.to_ascii_lowercase(),
+            _ => ' ',
+        })
+        .collect();
+    let mut words = String::from(" ");
+    for word in name.split_whitespace() {
+        words.push_str(word);
+        words.push(' ');
+    }
+    words
+}
+
+/// Mobile when a carrier is known, then hosting, broadband otherwise.
+pub fn classify(geo: &Geo, anonymity: &Anonymity) -> ConnectionType {
+    if geo.carrier.is_some() {
+        ConnectionType::Mobile
+    } else if anonymity.hosting {
+        ConnectionType::Hosting
+    } else {
+        ConnectionType::Broadband
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn geo(country: &str, carrier: Carrier) -> Geo {
+        let mut geo = Geo::new("1.2.3.4".parse().unwrap(), "test");
+        geo.country_code = Some(country.into());
+        geo.carrier = Some(carrier);
+        geo
+    }
+
+    fn named(name: &str) -> Carrier {
+        Carrier {
+            name: Some(name.into()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_normalize_name() {
+        let mut us = geo("US", named("T-Mobile USA, Inc."));
+        normalize(&mut us);
+        let carrier = us.carrier.unwrap();
+        assert_eq!(carrier.name.as_deref(), Some("T-Mobile"));
+        assert_eq!(carrier.mcc.as_deref(), Some("310"));
+        assert_eq!(carrier.mnc.as_deref(), Some("260"));
+
+        // the same brand is another operator elsewhere
+        let mut nl = geo("NL", named("T-Mobile Netherlands"));
+        normalize(&mut nl);
+        assert_eq!(nl.carrier.unwrap().mnc.as_deref(), Some("16"));
+
+        // whole words only, `EE` is not in `Free`
+        let mut gb = geo("GB", named("Free Telecom"));
+        normalize(&mut gb);
+        assert_eq!(gb.carrier, Some(named("Free Telecom")));
+        let mut gb = geo("GB", named("EE Limited"));
+        normalize(&mut gb);
+        assert_eq!(gb.carrier.unwrap().mnc.as_deref(), Some("30"));
+    }
+
+    #[test]
+    fn test_normalize_codes() {
+        let reported = Carrier {
+            name: Some("Vodafone GmbH".into()),
+            mcc: Some("262".into()),
+            mnc: Some("02".into()),
+        };
+        let mut de = geo("DE", reported);
+        normalize(&mut de);
+        assert_eq!(de.carrier.unwrap().name.as_deref(), Some("Vodafone"));
+
+        // unknown codes are kept as reported
+        let reported = Carrier {
+            name: Some("Telekom".into()),
+            mcc: Some("262".into()),
+            mnc: Some("77".into()),
+        };
+        let mut de = geo("DE", reported.clone());
+        normalize(&mut de);
+        assert_eq!(de.carrier, Some(reported));
+    }
+
+    #[test]
+    fn test_classify() {
+        let mobile = geo("US", named("Verizon Wireless"));
+        let hosting = Anonymity {
+            hosting: true,
+            ..Default::default()
+        };
+        assert_eq!(classify(&mobile, &hosting), ConnectionType::Mobile);
+
+        let fixed = Geo::new("1.2.3.4".parse().unwrap(), "test");
+        assert_eq!(classify(&fixed, &hosting), ConnectionType::Hosting);
+        assert_eq!(
+            classify(&fixed, &Anonymity::default()),
+            ConnectionType::Broadband
+        );
+    }
+}
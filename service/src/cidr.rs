@@ -0,0 +1,191 @@
+//! Summaries of address blocks
+//!
+//! A prefix is split into at most `samples` equal sub-blocks and one address
+//! of each is looked up, small prefixes are enumerated. The summary tallies
+//! the countries and ASNs seen, with the representative result of every
+//! sub-block.
+
+use crate::{error::Error, BatchItem};
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, net::IpAddr};
+use utoipa::ToSchema;
+
+/// Sub-blocks looked up when the request does not say.
+pub const DEFAULT_SAMPLES: usize = 64;
+/// Most lookups of one summary.
+pub const MAX_SAMPLES: usize = 256;
+/// Shortest prefixes accepted, anything larger spans whole registries.
+const MIN_PREFIX_V4: u8 = 8;
+const MIN_PREFIX_V6: u8 = 16;
+
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone)]
+pub struct CidrSummary {
+    /// Normalized prefix, host bits cleared.
+    pub prefix: String,
+    /// Addresses in the prefix, saturated at the largest `u128`.
+    pub addresses: u128,
+    /// Every address was looked up, the prefix was sampled otherwise.
+    pub enumerated: bool,
+    /// Lookups that failed, they count towards no country or ASN.
+    pub failed: usize,
+    /// Most frequent first.
+    pub countries: Vec<CountryCount>,
+    /// Most frequent first.
+    pub asns: Vec<AsnCount>,
+    pub blocks: Vec<CidrBlock>,
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone, PartialEq)]
+pub struct CountryCount {
+    pub country_code: String,
+    pub country: Option<String>,
+    pub count: usize,
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone, PartialEq)]
+pub struct AsnCount {
+    pub asn: u32,
+    pub name: Option<String>,
+    pub count: usize,
+}
+
+/// Sub-block and the lookup of its representative address.
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone)]
+pub struct CidrBlock {
+    pub block: String,
+    pub result: BatchItem,
+}
+
+/// Parses `prefix`, a bare address is a single-address prefix.
+pub fn parse(prefix: &str) -> Result<IpNet, Error> {
+    let net = prefix
+        .parse::<IpNet>()
+        .or_else(|_| prefix.parse::<IpAddr>().map(IpNet::from))
+        .map_err(|_| Error::InvalidInput(format!("invalid prefix {prefix}")))?;
+    let min = match net {
+        IpNet::V4(_) => MIN_PREFIX_V4,
+        IpNet::V6(_) => MIN_PREFIX_V6,
+    };
+    if net.prefix_len() < min {
+        return Err(Error::InvalidInput(format!(
+            "prefix {net} is too large, at most /{min}"
+        )));
+    }
+    Ok(net.trunc())
+}
+
+/// Sub-blocks of `net` with the address looked up for each, at most `samples`.
+pub fn blocks(net: IpNet, samples: usize) -> Vec<(IpNet, IpAddr)> {
+    let samples = samples.clamp(1, MAX_SAMPLES);
+    let host_bits = net.max_prefix_len() - net.prefix_len();
+    // largest power of two not above the sample count
+    let split = (samples.ilog2() as u8).min(host_bits);
+    let subnets = net
+        .subnets(net.prefix_len() + split)
+        .expect("sub-prefix within the address length");
+    subnets
+        .map(|block| (block, representative(block)))
+        .collect()
+}
+
+/// First host of the block, its only address for a single-address block.
+fn representative(block: IpNet) -> IpAddr {
+    match block.hosts().next() {
+        Some(host) => host,
+        None => block.network(),
+    }
+}
+
+pub fn summarize(net: IpNet, blocks: Vec<(IpNet, BatchItem)>) -> CidrSummary {
+    let host_bits = u32::from(net.max_prefix_len() - net.prefix_len());
+    let addresses = 1u128.checked_shl(host_bits).unwrap_or(u128::MAX);
+    let mut countries: HashMap<String, CountryCount> = HashMap::new();
+    let mut asns: HashMap<u32, AsnCount> = HashMap::new();
+    let mut failed = 0;
+    for (_, item) in &blocks {
+        let Some(lookup) = &item.lookup else {
+            failed += 1;
+            continue;
+        };
+        let geo = &lookup.geo;
+        if let Some(code) = &geo.country_code {
+            let entry = countries
+                .entry(code.clone())
+                .or_insert_with(|| CountryCount {
+                    country_code: code.clone(),
+                    country: geo.country.clone(),
+                    count: 0,
+                });
+            entry.count += 1;
+        }
+        if let Some(asn) = geo.asn {
+            let entry = asns.entry(asn).or_insert_with(|| AsnCount {
+                asn,
+                name: geo.as_name.clone().or(geo.org.clone()),
+                count: 0,
+            });
+            entry.count += 1;
+        }
+    }
+    let mut countries: Vec<CountryCount> = countries.into_values().collect();
+    countries.sort_by(|a, b| {
+        b.count
+            .cmp(&a.count)
+            .then(a.country_code.cmp(&b.country_code))
+    });
+    let mut asns: Vec<AsnCount> = asns.into_values().collect();
+    asns.sort_by(|a, b| b.count.cmp(&a.count).then(a.asn.cmp(&b.asn)));
+    CidrSummary {
+        prefix: net.to_string(),
+        addresses,
+        enumerated: addresses == blocks.len() as u128,
+        failed,
+        countries,
+        asns,
+        blocks: blocks
+            .into_iter()
+            .map(|(block, result)| CidrBlock {
+                block: block.to_string(),
+                result,
+            })
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addresses(blocks: &[(IpNet, IpAddr)]) -> Vec<String> {
+        blocks.iter().map(|(_, ip)| ip.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse("10.1.2.3/24").unwrap().to_string(), "10.1.2.0/24");
+        assert_eq!(parse("2001:db8::1").unwrap().to_string(), "2001:db8::1/128");
+        assert!(parse("10.0.0.0/7").is_err());
+        assert!(parse("2001::/15").is_err());
+        assert!(parse("nope").is_err());
+    }
+
+    #[test]
+    fn test_blocks() {
+        let net = parse("192.0.2.0/30").unwrap();
+        let blocks = blocks(net, 64);
+        assert_eq!(
+            addresses(&blocks),
+            ["192.0.2.0", "192.0.2.1", "192.0.2.2", "192.0.2.3"]
+        );
+
+        let net = parse("10.0.0.0/8").unwrap();
+        let blocks = super::blocks(net, 100);
+        assert_eq!(blocks.len(), 64);
+        assert_eq!(blocks[1].0.to_string(), "10.4.0.0/14");
+        assert_eq!(blocks[1].1.to_string(), "10.4.0.1");
+
+        let net = parse("2001:db8::/32").unwrap();
+        assert_eq!(super::blocks(net, 10_000).len(), MAX_SAMPLES);
+    }
+}
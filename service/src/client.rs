@@ -0,0 +1,370 @@
+//! Async client of the HTTP API
+//!
+//! ```no_run
+//! use ip_service::{client::Client, BatchRequest, LookupRequest};
+//!
+//! # async fn run() -> Result<(), ip_service::client::ClientError> {
+//! let client = Client::builder("http://localhost:8080").retries(3).build()?;
+//! let response = client.lookup(&LookupRequest::ip("8.8.8.8")).await?;
+//! println!("{:?}", response.lookup.geo.country);
+//!
+//! let job = client.submit_job(&BatchRequest::new(["1.1.1.1", "9.9.9.9"])).await?;
+//! let job = client.wait_job(&job.id).await?;
+//! println!("{} of {} failed", job.failed, job.total);
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::{
+    error::ErrorBody,
+    jobs::{DeadLetter, Job, JobStatus},
+    BatchItem, BatchRequest, Lookup, LookupRequest, LookupResponse,
+};
+use reqwest::{Method, RequestBuilder, StatusCode};
+use serde::{de::DeserializeOwned, Serialize};
+use std::time::Duration;
+use thiserror::Error;
+use tracing::debug;
+
+/// Longest `Retry-After` waited for, a longer wait is left to the caller.
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(60);
+
+#[derive(Error, Debug)]
+pub enum ClientError {
+    /// The request could not be sent or the body not read.
+    #[error("request failed: {0}")]
+    Request(#[from] reqwest::Error),
+    /// The service answered with an error.
+    #[error("{status}: {}", body.message)]
+    Api {
+        status: StatusCode,
+        body: Box<ErrorBody>,
+    },
+}
+
+impl ClientError {
+    /// HTTP status of an error answer.
+    pub fn status(&self) -> Option<StatusCode> {
+        match self {
+            ClientError::Request(e) => e.status(),
+            ClientError::Api { status, .. } => Some(*status),
+        }
+    }
+
+    /// Wait the service asked for before another try, `retry_after_secs` of the body.
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            ClientError::Api { body, .. } => body.retry_after_secs.map(Duration::from_secs),
+            ClientError::Request(_) => None,
+        }
+    }
+
+    /// Throttling, gateway errors and connection failures are worth another try.
+    fn is_transient(&self) -> bool {
+        match self {
+            ClientError::Request(e) => e.is_connect() || e.is_timeout(),
+            ClientError::Api { status, .. } => matches!(
+                *status,
+                StatusCode::TOO_MANY_REQUESTS
+                    | StatusCode::BAD_GATEWAY
+                    | StatusCode::SERVICE_UNAVAILABLE
+                    | StatusCode::GATEWAY_TIMEOUT
+            ),
+        }
+    }
+}
+
+pub struct ClientBuilder {
+    base_url: String,
+    token: Option<String>,
+    timeout: Duration,
+    retries: u32,
+    backoff: Duration,
+    poll_interval: Duration,
+}
+
+impl ClientBuilder {
+    /// Bearer token sent with every request, required by the admin endpoints.
+    pub fn token(mut self, token: impl Into<String>) -> Self {
+        self.token = Some(token.into());
+        self
+    }
+
+    /// Timeout of a single attempt, 30 seconds by default.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Extra attempts after a transient failure, 2 by default.
+    pub fn retries(mut self, retries: u32) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    /// Delay before the first retry, doubled for every further one.
+    pub fn backoff(mut self, backoff: Duration) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    /// How often [`Client::wait_job`] polls, 1 second by default.
+    pub fn poll_interval(mut self, interval: Duration) -> Self {
+        self.poll_interval = interval;
+        self
+    }
+
+    pub fn build(self) -> Result<Client, ClientError> {
+        let http = reqwest::Client::builder().timeout(self.timeout).build()?;
+        Ok(Client {
+            http,
+            base_url: self.base_url.trim_end_matches('/').to_string(),
+            token: self.token,
+            retries: self.retries,
+            backoff: self.backoff,
+            poll_interval: self.poll_interval,
+        })
+    }
+}
+
+/// Client of an `ip-service` instance.
+#[derive(Clone)]
+pub struct Client {
+    http: reqwest::Client,
+    base_url: String,
+    token: Option<String>,
+    retries: u32,
+    backoff: Duration,
+    poll_interval: Duration,
+}
+
+impl Client {
+    pub fn builder(base_url: impl Into<String>) -> ClientBuilder {
+        ClientBuilder {
+            base_url: base_url.into(),
+            token: None,
+            timeout: Duration::from_secs(30),
+            retries: 2,
+            backoff: Duration::from_millis(200),
+            poll_interval: Duration::from_secs(1),
+        }
+    }
+
+    /// `POST /v1/lookup`
+    pub async fn lookup(&self, req: &LookupRequest) -> Result<LookupResponse, ClientError> {
+        self.send(Method::POST, "/v1/lookup", Some(req)).await
+    }
+
+    /// `POST /v1/batch`, at most [`MAX_BATCH`](crate::service::MAX_BATCH) addresses.
+    pub async fn batch(&self, req: &BatchRequest) -> Result<Vec<BatchItem>, ClientError> {
+        self.send(Method::POST, "/v1/batch", Some(req)).await
+    }
+
+    /// `GET /v1/whoami`, the lookup of the address the service sees the client from.
+    pub async fn whoami(&self) -> Result<Lookup, ClientError> {
+        self.send(Method::GET, "/v1/whoami", None::<&()>).await
+    }
+
+    /// `POST /v1/jobs`
+    pub async fn submit_job(&self, req: &BatchRequest) -> Result<Job, ClientError> {
+        self.send(Method::POST, "/v1/jobs", Some(req)).await
+    }
+
+    /// `GET /v1/jobs/{id}`
+    pub async fn job(&self, id: &str) -> Result<Job, ClientError> {
+        self.send(Method::GET, &format!("/v1/jobs/{id}"), None::<&()>)
+            .await
+    }
+
+    /// `GET /v1/jobs/{id}/failed`
+    pub async fn job_failures(&self, id: &str) -> Result<Vec<DeadLetter>, ClientError> {
+        self.send(Method::GET, &format!("/v1/jobs/{id}/failed"), None::<&()>)
+            .await
+    }
+
+    /// Polls the job until it is done.
+    pub async fn wait_job(&self, id: &str) -> Result<Job, ClientError> {
+        loop {
+            let job = self.job(id).await?;
+            if job.status == JobStatus::Done {
+                return Ok(job);
+            }
+            tokio::time::sleep(self.poll_interval).await;
+        }
+    }
+
+    async fn send<B, T>(
+        &self,
+        method: Method,
+        path: &str,
+        body: Option<&B>,
+    ) -> Result<T, ClientError>
+    where
+        B: Serialize + ?Sized,
+        T: DeserializeOwned,
+    {
+        let url = format!("{}{path}", self.base_url);
+        let mut attempt = 0;
+        loop {
+            let mut request = self.http.request(method.clone(), &url);
+            if let Some(body) = body {
+                request = request.json(body);
+            }
+            match self.attempt(request).await {
+                Err(e) if e.is_transient() && attempt < self.retries => {
+                    let delay = match e.retry_after() {
+                        Some(wait) if wait > MAX_RETRY_AFTER => return Err(e),
+                        Some(wait) => wait,
+                        None => self.backoff * 2u32.pow(attempt),
+                    };
+                    debug!(
+                        "{} {} failed ({}), retrying in {:?}",
+                        method, path, e, delay
+                    );
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    async fn attempt<T: DeserializeOwned>(
+        &self,
+        request: RequestBuilder,
+    ) -> Result<T, ClientError> {
+        let request = match &self.token {
+            Some(token) => request.bearer_auth(token),
+            None => request,
+        };
+        let response = request.send().await?;
+        let status = response.status();
+        if status.is_success() {
+            return Ok(response.json().await?);
+        }
+        // error answers of the service carry a body, proxies may not
+        let body = response.json().await.unwrap_or_else(|_| {
+            ErrorBody::new(status, "http", status.canonical_reason().unwrap_or("error"))
+        });
+        Err(ClientError::Api {
+            status,
+            body: Box::new(body),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::{ProviderConfig, ProviderKind},
+        error::Error,
+        LookupService,
+    };
+    use axum::{
+        extract::State,
+        http::HeaderMap,
+        routing::{get, post},
+        Json, Router,
+    };
+    use std::sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    };
+
+    /// Serves `router` on a free local port.
+    async fn serve(router: Router) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, router).await.unwrap() });
+        format!("http://{addr}")
+    }
+
+    fn client(base_url: &str) -> Client {
+        Client::builder(base_url)
+            .backoff(Duration::from_millis(1))
+            .token("secret")
+            .build()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_lookup() {
+        let service = LookupService::builder()
+            .provider(ProviderConfig::new(ProviderKind::Mock))
+            .build();
+        let router = Router::new()
+            .route(
+                "/v1/lookup",
+                post(
+                    |State(service): State<LookupService>,
+                     headers: HeaderMap,
+                     Json(req): Json<LookupRequest>| async move {
+                        assert_eq!(headers["authorization"], "Bearer secret");
+                        let lookup = service.lookup(&req).await?;
+                        Ok::<_, Error>(Json(LookupResponse {
+                            request_id: "test".into(),
+                            latency_ms: 0,
+                            lookup,
+                        }))
+                    },
+                ),
+            )
+            .with_state(service);
+        let client = client(&serve(router).await);
+
+        let response = client.lookup(&LookupRequest::ip("8.8.8.8")).await.unwrap();
+        assert_eq!(response.lookup.geo.country_code.as_deref(), Some("US"));
+
+        let error = client.lookup(&LookupRequest::ip("nope")).await.unwrap_err();
+        assert_eq!(error.status(), Some(StatusCode::BAD_REQUEST));
+        let ClientError::Api { body, .. } = error else {
+            panic!("expected an API error");
+        };
+        assert_eq!(body.error, "invalid_input");
+    }
+
+    #[tokio::test]
+    async fn test_retries() {
+        let calls = Arc::new(AtomicU32::new(0));
+        let router = Router::new()
+            .route(
+                "/v1/whoami",
+                get(|State(calls): State<Arc<AtomicU32>>| async move {
+                    calls.fetch_add(1, Ordering::Relaxed);
+                    Err::<Json<Lookup>, _>(Error::ProviderUnavailable("down".into()))
+                }),
+            )
+            .with_state(calls.clone());
+        let client = client(&serve(router).await);
+
+        let error = client.whoami().await.unwrap_err();
+        assert_eq!(error.status(), Some(StatusCode::BAD_GATEWAY));
+        assert_eq!(calls.load(Ordering::Relaxed), 3, "Two retries expected");
+    }
+
+    #[tokio::test]
+    async fn test_retry_after() {
+        use crate::error::Resource;
+
+        let calls = Arc::new(AtomicU32::new(0));
+        let router = Router::new()
+            .route(
+                "/v1/whoami",
+                get(|State(calls): State<Arc<AtomicU32>>| async move {
+                    calls.fetch_add(1, Ordering::Relaxed);
+                    Err::<Json<Lookup>, _>(Error::Saturated {
+                        resource: Resource::Budget,
+                        retry_after_secs: 3600,
+                    })
+                }),
+            )
+            .with_state(calls.clone());
+        let client = client(&serve(router).await);
+
+        let error = client.whoami().await.unwrap_err();
+        assert_eq!(error.status(), Some(StatusCode::TOO_MANY_REQUESTS));
+        assert_eq!(error.retry_after(), Some(Duration::from_secs(3600)));
+        assert_eq!(calls.load(Ordering::Relaxed), 1, "Not waited for an hour");
+    }
+}
@@ -0,0 +1,230 @@
+//! Confidence of the fields of an answer
+//!
+//! Every field starts from the usual accuracy of IP geolocation at its
+//! granularity: the country is right far more often than the city. The
+//! sampled [comparisons](crate::discrepancy) with the other providers scale
+//! it by the share the answering provider agreed in. Merged answers agreeing
+//! on a value corroborate it, those disagreeing count against it. An answer
+//! loses half of it every [`HALF_LIFE`] of age, the time it was cached plus
+//! the data age of its provider. A peering LAN located at its exchange has a
+//! known place, an anycast address no single one.
+
+use crate::{
+    discrepancy::Agreement,
+    geo::{Field, Geo},
+    providers::merge::Support,
+};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use utoipa::ToSchema;
+
+/// Age of an answer halving the confidence of its fields.
+pub const HALF_LIFE: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+/// Factor of the location fields of an anycast address.
+const ANYCAST: f64 = 0.3;
+
+/// Highest confidence agreeing answers raise a field to.
+const CORROBORATED: f64 = 0.99;
+
+/// Confidence between 0 and 1 of the fields present in the answer.
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone, Default, PartialEq)]
+pub struct Confidence {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(example = 0.98)]
+    pub country: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub region: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(example = 0.6)]
+    pub city: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub postal_code: Option<f64>,
+    /// `latitude` and `longitude`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub location: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timezone: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub asn: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub isp: Option<f64>,
+}
+
+/// What is known about the answer besides its fields.
+#[derive(Debug, Clone, Default)]
+pub struct Evidence {
+    /// Comparisons of the answering provider, absent without discrepancy detection.
+    pub agreement: Option<Agreement>,
+    /// Time since the provider answered, zero unless cached.
+    pub age: Duration,
+    /// How old the data of the provider can be, unknown when absent.
+    pub data_age: Option<Duration>,
+    /// Backing of the fields by the answers of a merge, empty unless merged.
+    pub support: Vec<(Field, Support)>,
+    pub is_anycast: bool,
+    /// Located at its internet exchange.
+    pub ixp: bool,
+}
+
+impl Confidence {
+    pub fn compute(geo: &Geo, evidence: &Evidence) -> Self {
+        let (country_agreed, location_agreed) = match &evidence.agreement {
+            // one agreement assumed so a single comparison does not zero it
+            Some(agreement) => {
+                let compared = agreement.compared as f64 + 1.0;
+                (
+                    (agreement.country_agreed as f64 + 1.0) / compared,
+                    (agreement.location_agreed as f64 + 1.0) / compared,
+                )
+            }
+            None => (1.0, 1.0),
+        };
+        let (country_prior, place_prior, place) = match (evidence.ixp, evidence.is_anycast) {
+            (true, _) => (0.99, 0.95, 1.0),
+            (false, true) => (0.98, 1.0, ANYCAST),
+            (false, false) => (0.98, 1.0, 1.0),
+        };
+        let score = |present: bool, field: Field, prior: f64| {
+            present.then(|| round(evidence.backed(field, prior)))
+        };
+        let country = country_prior * country_agreed;
+        let located = |prior: f64| prior * place_prior * place * location_agreed;
+        Confidence {
+            country: score(geo.country_code.is_some(), Field::Country, country),
+            region: score(geo.region.is_some(), Field::Region, located(0.85)),
+            city: score(geo.city.is_some(), Field::City, located(0.6)),
+            postal_code: score(geo.postal_code.is_some(), Field::PostalCode, located(0.5)),
+            location: score(geo.latitude.is_some(), Field::Location, located(0.6)),
+            timezone: score(geo.timezone.is_some(), Field::Timezone, 0.95 * country_agreed),
+            asn: score(geo.asn.is_some(), Field::Asn, 0.98),
+            isp: score(geo.isp.is_some(), Field::Isp, 0.9),
+        }
+    }
+}
+
+impl Evidence {
+    /// Confidence `prior` of `field` corroborated by the merged answers
+    /// agreeing on it, the independent chance that one of them is right,
+    /// scaled by their share and decayed by the age of its data.
+    fn backed(&self, field: Field, prior: f64) -> f64 {
+        let support = self
+            .support
+            .iter()
+            .find(|(merged, _)| *merged == field)
+            .map(|(_, support)| support);
+        let confidence = match support {
+            Some(support) if support.supplied > 0 => {
+                let corroborated = 1.0 - (1.0 - prior).powi(support.agreed as i32);
+                corroborated.min(prior.max(CORROBORATED)) * support.agreed as f64
+                    / support.supplied as f64
+            }
+            _ => prior,
+        };
+        let data_age = support.map_or(self.data_age, |support| support.age);
+        let age = self.age + data_age.unwrap_or_default();
+        confidence * 0.5_f64.powf(age.as_secs_f64() / HALF_LIFE.as_secs_f64())
+    }
+}
+
+fn round(value: f64) -> f64 {
+    (value.clamp(0.0, 1.0) * 100.0).round() / 100.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn geo() -> Geo {
+        let mut geo = Geo::new("8.8.8.8".parse().unwrap(), "ipapi");
+        geo.country_code = Some("US".into());
+        geo.city = Some("Mountain View".into());
+        geo.latitude = Some(37.4);
+        geo.longitude = Some(-122.1);
+        geo
+    }
+
+    #[test]
+    fn test_compute() {
+        let confidence = Confidence::compute(&geo(), &Evidence::default());
+        assert_eq!(confidence.country, Some(0.98));
+        assert_eq!(confidence.city, Some(0.6));
+        assert_eq!(confidence.region, None, "Not in the answer");
+
+        let evidence = Evidence {
+            agreement: Some(Agreement {
+                compared: 9,
+                country_agreed: 9,
+                location_agreed: 4,
+            }),
+            ..Default::default()
+        };
+        let confidence = Confidence::compute(&geo(), &evidence);
+        assert_eq!(confidence.country, Some(0.98));
+        assert_eq!(confidence.city, Some(0.3));
+
+        let anycast = Evidence {
+            is_anycast: true,
+            ..Default::default()
+        };
+        assert_eq!(Confidence::compute(&geo(), &anycast).location, Some(0.18));
+    }
+
+    #[test]
+    fn test_age() {
+        let evidence = Evidence {
+            age: HALF_LIFE,
+            ..Default::default()
+        };
+        let confidence = Confidence::compute(&geo(), &evidence);
+        assert_eq!(confidence.country, Some(0.49));
+        assert_eq!(confidence.asn, None);
+
+        let stale = Evidence {
+            age: HALF_LIFE / 2,
+            data_age: Some(HALF_LIFE / 2),
+            ..Default::default()
+        };
+        assert_eq!(Confidence::compute(&geo(), &stale).country, Some(0.49));
+    }
+
+    #[test]
+    fn test_support() {
+        let support = |agreed, supplied, age| Support {
+            agreed,
+            supplied,
+            age: Some(age),
+        };
+        let fresh = Duration::from_secs(60);
+        let unanimous = Evidence {
+            support: vec![
+                (Field::Country, support(3, 3, fresh)),
+                (Field::City, support(3, 3, fresh)),
+                (Field::Location, support(3, 3, fresh)),
+            ],
+            ..Default::default()
+        };
+        let single = Evidence {
+            data_age: Some(HALF_LIFE),
+            ..Default::default()
+        };
+        let unanimous = Confidence::compute(&geo(), &unanimous);
+        let single = Confidence::compute(&geo(), &single);
+        assert_eq!(unanimous.country, Some(0.99));
+        assert_eq!(unanimous.city, Some(0.94));
+        assert_eq!(single.city, Some(0.3));
+        for (unanimous, single) in [
+            (unanimous.country, single.country),
+            (unanimous.city, single.city),
+            (unanimous.location, single.location),
+        ] {
+            assert!(unanimous > single, "{unanimous:?} <= {single:?}");
+        }
+
+        let split = Evidence {
+            support: vec![(Field::City, support(1, 3, fresh))],
+            ..Default::default()
+        };
+        assert_eq!(Confidence::compute(&geo(), &split).city, Some(0.2));
+    }
+}
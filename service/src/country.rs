@@ -0,0 +1,437 @@
+//! Per-country reference data
+//!
+//! National currency, calling code and, for countries spanning a single time
+//! zone, the IANA zone name, keyed by ISO 3166-1 alpha-2 code, and the EU and
+//! EEA memberships. Used to fill the fields a provider leaves out.
+
+use crate::geo::Geo;
+use chrono::{Offset, TimeZone, Utc};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+/// Flag images used unless the configuration points elsewhere.
+pub const DEFAULT_FLAG_IMAGE_URL: &str = "https://flagcdn.com/{code}.svg";
+
+/// Flag of the resolved country.
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone, PartialEq)]
+pub struct CountryFlag {
+    /// Pair of regional indicator symbols, e.g. 🇩🇪.
+    pub emoji: String,
+    pub image_url: String,
+}
+
+impl CountryFlag {
+    /// Flag of an ISO 3166-1 alpha-2 code, `image_url` is a template with `{code}`.
+    pub fn new(code: &str, image_url: &str) -> Option<Self> {
+        let code = code.to_ascii_lowercase();
+        if code.len() != 2 || !code.bytes().all(|b| b.is_ascii_lowercase()) {
+            return None;
+        }
+        let emoji = code
+            .bytes()
+            .filter_map(|b| char::from_u32(0x1F1E6 + u32::from(b - b'a')))
+            .collect();
+        Some(CountryFlag {
+            emoji,
+            image_url: image_url.replace("{code}", &code),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CountryInfo {
+    pub code: &'static str,
+    /// ISO 4217 code.
+    pub currency: &'static str,
+    /// E.164 country calling code, e.g. `+49`.
+    pub calling_code: &'static str,
+    /// Zone of the whole country, `None` when it spans several.
+    pub timezone: Option<&'static str>,
+}
+
+const fn country(
+    code: &'static str,
+    currency: &'static str,
+    calling_code: &'static str,
+    timezone: Option<&'static str>,
+) -> CountryInfo {
+    CountryInfo {
+        code,
+        currency,
+        calling_code,
+        timezone,
+    }
+}
+
+/// Sorted by code.
+static COUNTRIES: &[CountryInfo] = &[
+    country("AD", "EUR", "+376", Some("Europe/Andorra")),
+    country("AE", "AED", "+971", Some("Asia/Dubai")),
+    country("AF", "AFN", "+93", Some("Asia/Kabul")),
+    country("AG", "XCD", "+1", Some("America/Antigua")),
+    country("AI", "XCD", "+1", Some("America/Anguilla")),
+    country("AL", "ALL", "+355", Some("Europe/Tirane")),
+    country("AM", "AMD", "+374", Some("Asia/Yerevan")),
+    country("AO", "AOA", "+244", Some("Africa/Luanda")),
+    country("AQ", "USD", "+672", None),
+    country("AR", "ARS", "+54", Some("America/Argentina/Buenos_Aires")),
+    country("AS", "USD", "+1", Some("Pacific/Pago_Pago")),
+    country("AT", "EUR", "+43", Some("Europe/Vienna")),
+    country("AU", "AUD", "+61", None),
+    country("AW", "AWG", "+297", Some("America/Aruba")),
+    country("AX", "EUR", "+358", Some("Europe/Mariehamn")),
+    country("AZ", "AZN", "+994", Some("Asia/Baku")),
+    country("BA", "BAM", "+387", Some("Europe/Sarajevo")),
+    country("BB", "BBD", "+1", Some("America/Barbados")),
+    country("BD", "BDT", "+880", Some("Asia/Dhaka")),
+    country("BE", "EUR", "+32", Some("Europe/Brussels")),
+    country("BF", "XOF", "+226", Some("Africa/Ouagadougou")),
+    country("BG", "BGN", "+359", Some("Europe/Sofia")),
+    country("BH", "BHD", "+973", Some("Asia/Bahrain")),
+    country("BI", "BIF", "+257", Some("Africa/Bujumbura")),
+    country("BJ", "XOF", "+229", Some("Africa/Porto-Novo")),
+    country("BL", "EUR", "+590", Some("America/St_Barthelemy")),
+    country("BM", "BMD", "+1", Some("Atlantic/Bermuda")),
+    country("BN", "BND", "+673", Some("Asia/Brunei")),
+    country("BO", "BOB", "+591", Some("America/La_Paz")),
+    country("BQ", "USD", "+599", Some("America/Kralendijk")),
+    country("BR", "BRL", "+55", None),
+    country("BS", "BSD", "+1", Some("America/Nassau")),
+    country("BT", "BTN", "+975", Some("Asia/Thimphu")),
+    country("BV", "NOK", "+47", None),
+    country("BW", "BWP", "+267", Some("Africa/Gaborone")),
+    country("BY", "BYN", "+375", Some("Europe/Minsk")),
+    country("BZ", "BZD", "+501", Some("America/Belize")),
+    country("CA", "CAD", "+1", None),
+    country("CC", "AUD", "+61", Some("Indian/Cocos")),
+    country("CD", "CDF", "+243", None),
+    country("CF", "XAF", "+236", Some("Africa/Bangui")),
+    country("CG", "XAF", "+242", Some("Africa/Brazzaville")),
+    country("CH", "CHF", "+41", Some("Europe/Zurich")),
+    country("CI", "XOF", "+225", Some("Africa/Abidjan")),
+    country("CK", "NZD", "+682", Some("Pacific/Rarotonga")),
+    country("CL", "CLP", "+56", None),
+    country("CM", "XAF", "+237", Some("Africa/Douala")),
+    country("CN", "CNY", "+86", Some("Asia/Shanghai")),
+    country("CO", "COP", "+57", Some("America/Bogota")),
+    country("CR", "CRC", "+506", Some("America/Costa_Rica")),
+    country("CU", "CUP", "+53", Some("America/Havana")),
+    country("CV", "CVE", "+238", Some("Atlantic/Cape_Verde")),
+    country("CW", "ANG", "+599", Some("America/Curacao")),
+    country("CX", "AUD", "+61", Some("Indian/Christmas")),
+    country("CY", "EUR", "+357", Some("Asia/Nicosia")),
+    country("CZ", "CZK", "+420", Some("Europe/Prague")),
+    country("DE", "EUR", "+49", Some("Europe/Berlin")),
+    country("DJ", "DJF", "+253", Some("Africa/Djibouti")),
+    country("DK", "DKK", "+45", Some("Europe/Copenhagen")),
+    country("DM", "XCD", "+1", Some("America/Dominica")),
+    country("DO", "DOP", "+1", Some("America/Santo_Domingo")),
+    country("DZ", "DZD", "+213", Some("Africa/Algiers")),
+    country("EC", "USD", "+593", None),
+    country("EE", "EUR", "+372", Some("Europe/Tallinn")),
+    country("EG", "EGP", "+20", Some("Africa/Cairo")),
+    country("EH", "MAD", "+212", Some("Africa/El_Aaiun")),
+    country("ER", "ERN", "+291", Some("Africa/Asmara")),
+    country("ES", "EUR", "+34", None),
+    country("ET", "ETB", "+251", Some("Africa/Addis_Ababa")),
+    country("FI", "EUR", "+358", Some("Europe/Helsinki")),
+    country("FJ", "FJD", "+679", Some("Pacific/Fiji")),
+    country("FK", "FKP", "+500", Some("Atlantic/Stanley")),
+    country("FM", "USD", "+691", None),
+    country("FO", "DKK", "+298", Some("Atlantic/Faroe")),
+    country("FR", "EUR", "+33", Some("Europe/Paris")),
+    country("GA", "XAF", "+241", Some("Africa/Libreville")),
+    country("GB", "GBP", "+44", Some("Europe/London")),
+    country("GD", "XCD", "+1", Some("America/Grenada")),
+    country("GE", "GEL", "+995", Some("Asia/Tbilisi")),
+    country("GF", "EUR", "+594", Some("America/Cayenne")),
+    country("GG", "GBP", "+44", Some("Europe/Guernsey")),
+    country("GH", "GHS", "+233", Some("Africa/Accra")),
+    country("GI", "GIP", "+350", Some("Europe/Gibraltar")),
+    country("GL", "DKK", "+299", None),
+    country("GM", "GMD", "+220", Some("Africa/Banjul")),
+    country("GN", "GNF", "+224", Some("Africa/Conakry")),
+    country("GP", "EUR", "+590", Some("America/Guadeloupe")),
+    country("GQ", "XAF", "+240", Some("Africa/Malabo")),
+    country("GR", "EUR", "+30", Some("Europe/Athens")),
+    country("GS", "GBP", "+500", Some("Atlantic/South_Georgia")),
+    country("GT", "GTQ", "+502", Some("America/Guatemala")),
+    country("GU", "USD", "+1", Some("Pacific/Guam")),
+    country("GW", "XOF", "+245", Some("Africa/Bissau")),
+    country("GY", "GYD", "+592", Some("America/Guyana")),
+    country("HK", "HKD", "+852", Some("Asia/Hong_Kong")),
+    country("HM", "AUD", "+672", None),
+    country("HN", "HNL", "+504", Some("America/Tegucigalpa")),
+    country("HR", "EUR", "+385", Some("Europe/Zagreb")),
+    country("HT", "HTG", "+509", Some("America/Port-au-Prince")),
+    country("HU", "HUF", "+36", Some("Europe/Budapest")),
+    country("ID", "IDR", "+62", None),
+    country("IE", "EUR", "+353", Some("Europe/Dublin")),
+    country("IL", "ILS", "+972", Some("Asia/Jerusalem")),
+    country("IM", "GBP", "+44", Some("Europe/Isle_of_Man")),
+    country("IN", "INR", "+91", Some("Asia/Kolkata")),
+    country("IO", "USD", "+246", Some("Indian/Chagos")),
+    country("IQ", "IQD", "+964", Some("Asia/Baghdad")),
+    country("IR", "IRR", "+98", Some("Asia/Tehran")),
+    country("IS", "ISK", "+354", Some("Atlantic/Reykjavik")),
+    country("IT", "EUR", "+39", Some("Europe/Rome")),
+    country("JE", "GBP", "+44", Some("Europe/Jersey")),
+    country("JM", "JMD", "+1", Some("America/Jamaica")),
+    country("JO", "JOD", "+962", Some("Asia/Amman")),
+    country("JP", "JPY", "+81", Some("Asia/Tokyo")),
+    country("KE", "KES", "+254", Some("Africa/Nairobi")),
+    country("KG", "KGS", "+996", Some("Asia/Bishkek")),
+    country("KH", "KHR", "+855", Some("Asia/Phnom_Penh")),
+    country("KI", "AUD", "+686", None),
+    country("KM", "KMF", "+269", Some("Indian/Comoro")),
+    country("KN", "XCD", "+1", Some("America/St_Kitts")),
+    country("KP", "KPW", "+850", Some("Asia/Pyongyang")),
+    country("KR", "KRW", "+82", Some("Asia/Seoul")),
+    country("KW", "KWD", "+965", Some("Asia/Kuwait")),
+    country("KY", "KYD", "+1", Some("America/Cayman")),
+    country("KZ", "KZT", "+7", None),
+    country("LA", "LAK", "+856", Some("Asia/Vientiane")),
+    country("LB", "LBP", "+961", Some("Asia/Beirut")),
+    country("LC", "XCD", "+1", Some("America/St_Lucia")),
+    country("LI", "CHF", "+423", Some("Europe/Vaduz")),
+    country("LK", "LKR", "+94", Some("Asia/Colombo")),
+    country("LR", "LRD", "+231", Some("Africa/Monrovia")),
+    country("LS", "LSL", "+266", Some("Africa/Maseru")),
+    country("LT", "EUR", "+370", Some("Europe/Vilnius")),
+    country("LU", "EUR", "+352", Some("Europe/Luxembourg")),
+    country("LV", "EUR", "+371", Some("Europe/Riga")),
+    country("LY", "LYD", "+218", Some("Africa/Tripoli")),
+    country("MA", "MAD", "+212", Some("Africa/Casablanca")),
+    country("MC", "EUR", "+377", Some("Europe/Monaco")),
+    country("MD", "MDL", "+373", Some("Europe/Chisinau")),
+    country("ME", "EUR", "+382", Some("Europe/Podgorica")),
+    country("MF", "EUR", "+590", Some("America/Marigot")),
+    country("MG", "MGA", "+261", Some("Indian/Antananarivo")),
+    country("MH", "USD", "+692", Some("Pacific/Majuro")),
+    country("MK", "MKD", "+389", Some("Europe/Skopje")),
+    country("ML", "XOF", "+223", Some("Africa/Bamako")),
+    country("MM", "MMK", "+95", Some("Asia/Yangon")),
+    country("MN", "MNT", "+976", None),
+    country("MO", "MOP", "+853", Some("Asia/Macau")),
+    country("MP", "USD", "+1", Some("Pacific/Saipan")),
+    country("MQ", "EUR", "+596", Some("America/Martinique")),
+    country("MR", "MRU", "+222", Some("Africa/Nouakchott")),
+    country("MS", "XCD", "+1", Some("America/Montserrat")),
+    country("MT", "EUR", "+356", Some("Europe/Malta")),
+    country("MU", "MUR", "+230", Some("Indian/Mauritius")),
+    country("MV", "MVR", "+960", Some("Indian/Maldives")),
+    country("MW", "MWK", "+265", Some("Africa/Blantyre")),
+    country("MX", "MXN", "+52", None),
+    country("MY", "MYR", "+60", Some("Asia/Kuala_Lumpur")),
+    country("MZ", "MZN", "+258", Some("Africa/Maputo")),
+    country("NA", "NAD", "+264", Some("Africa/Windhoek")),
+    country("NC", "XPF", "+687", Some("Pacific/Noumea")),
+    country("NE", "XOF", "+227", Some("Africa/Niamey")),
+    country("NF", "AUD", "+672", Some("Pacific/Norfolk")),
+    country("NG", "NGN", "+234", Some("Africa/Lagos")),
+    country("NI", "NIO", "+505", Some("America/Managua")),
+    country("NL", "EUR", "+31", Some("Europe/Amsterdam")),
+    country("NO", "NOK", "+47", Some("Europe/Oslo")),
+    country("NP", "NPR", "+977", Some("Asia/Kathmandu")),
+    country("NR", "AUD", "+674", Some("Pacific/Nauru")),
+    country("NU", "NZD", "+683", Some("Pacific/Niue")),
+    country("NZ", "NZD", "+64", None),
+    country("OM", "OMR", "+968", Some("Asia/Muscat")),
+    country("PA", "PAB", "+507", Some("America/Panama")),
+    country("PE", "PEN", "+51", Some("America/Lima")),
+    country("PF", "XPF", "+689", None),
+    country("PG", "PGK", "+675", None),
+    country("PH", "PHP", "+63", Some("Asia/Manila")),
+    country("PK", "PKR", "+92", Some("Asia/Karachi")),
+    country("PL", "PLN", "+48", Some("Europe/Warsaw")),
+    country("PM", "EUR", "+508", Some("America/Miquelon")),
+    country("PN", "NZD", "+64", Some("Pacific/Pitcairn")),
+    country("PR", "USD", "+1", Some("America/Puerto_Rico")),
+    country("PS", "ILS", "+970", None),
+    country("PT", "EUR", "+351", None),
+    country("PW", "USD", "+680", Some("Pacific/Palau")),
+    country("PY", "PYG", "+595", Some("America/Asuncion")),
+    country("QA", "QAR", "+974", Some("Asia/Qatar")),
+    country("RE", "EUR", "+262", Some("Indian/Reunion")),
+    country("RO", "RON", "+40", Some("Europe/Bucharest")),
+    country("RS", "RSD", "+381", Some("Europe/Belgrade")),
+    country("RU", "RUB", "+7", None),
+    country("RW", "RWF", "+250", Some("Africa/Kigali")),
+    country("SA", "SAR", "+966", Some("Asia/Riyadh")),
+    country("SB", "SBD", "+677", Some("Pacific/Guadalcanal")),
+    country("SC", "SCR", "+248", Some("Indian/Mahe")),
+    country("SD", "SDG", "+249", Some("Africa/Khartoum")),
+    country("SE", "SEK", "+46", Some("Europe/Stockholm")),
+    country("SG", "SGD", "+65", Some("Asia/Singapore")),
+    country("SH", "SHP", "+290", Some("Atlantic/St_Helena")),
+    country("SI", "EUR", "+386", Some("Europe/Ljubljana")),
+    country("SJ", "NOK", "+47", Some("Arctic/Longyearbyen")),
+    country("SK", "EUR", "+421", Some("Europe/Bratislava")),
+    country("SL", "SLE", "+232", Some("Africa/Freetown")),
+    country("SM", "EUR", "+378", Some("Europe/San_Marino")),
+    country("SN", "XOF", "+221", Some("Africa/Dakar")),
+    country("SO", "SOS", "+252", Some("Africa/Mogadishu")),
+    country("SR", "SRD", "+597", Some("America/Paramaribo")),
+    country("SS", "SSP", "+211", Some("Africa/Juba")),
+    country("ST", "STN", "+239", Some("Africa/Sao_Tome")),
+    country("SV", "USD", "+503", Some("America/El_Salvador")),
+    country("SX", "ANG", "+1", Some("America/Lower_Princes")),
+    country("SY", "SYP", "+963", Some("Asia/Damascus")),
+    country("SZ", "SZL", "+268", Some("Africa/Mbabane")),
+    country("TC", "USD", "+1", Some("America/Grand_Turk")),
+    country("TD", "XAF", "+235", Some("Africa/Ndjamena")),
+    country("TF", "EUR", "+262", Some("Indian/Kerguelen")),
+    country("TG", "XOF", "+228", Some("Africa/Lome")),
+    country("TH", "THB", "+66", Some("Asia/Bangkok")),
+    country("TJ", "TJS", "+992", Some("Asia/Dushanbe")),
+    country("TK", "NZD", "+690", Some("Pacific/Fakaofo")),
+    country("TL", "USD", "+670", Some("Asia/Dili")),
+    country("TM", "TMT", "+993", Some("Asia/Ashgabat")),
+    country("TN", "TND", "+216", Some("Africa/Tunis")),
+    country("TO", "TOP", "+676", Some("Pacific/Tongatapu")),
+    country("TR", "TRY", "+90", Some("Europe/Istanbul")),
+    country("TT", "TTD", "+1", Some("America/Port_of_Spain")),
+    country("TV", "AUD", "+688", Some("Pacific/Funafuti")),
+    country("TW", "TWD", "+886", Some("Asia/Taipei")),
+    country("TZ", "TZS", "+255", Some("Africa/Dar_es_Salaam")),
+    country("UA", "UAH", "+380", Some("Europe/Kyiv")),
+    country("UG", "UGX", "+256", Some("Africa/Kampala")),
+    country("UM", "USD", "+1", None),
+    country("US", "USD", "+1", None),
+    country("UY", "UYU", "+598", Some("America/Montevideo")),
+    country("UZ", "UZS", "+998", Some("Asia/Tashkent")),
+    country("VA", "EUR", "+39", Some("Europe/Vatican")),
+    country("VC", "XCD", "+1", Some("America/St_Vincent")),
+    country("VE", "VES", "+58", Some("America/Caracas")),
+    country("VG", "USD", "+1", Some("America/Tortola")),
+    country("VI", "USD", "+1", Some("America/St_Thomas")),
+    country("VN", "VND", "+84", Some("Asia/Ho_Chi_Minh")),
+    country("VU", "VUV", "+678", Some("Pacific/Efate")),
+    country("WF", "XPF", "+681", Some("Pacific/Wallis")),
+    country("WS", "WST", "+685", Some("Pacific/Apia")),
+    country("XK", "EUR", "+383", Some("Europe/Belgrade")),
+    country("YE", "YER", "+967", Some("Asia/Aden")),
+    country("YT", "EUR", "+262", Some("Indian/Mayotte")),
+    country("ZA", "ZAR", "+27", Some("Africa/Johannesburg")),
+    country("ZM", "ZMW", "+260", Some("Africa/Lusaka")),
+    country("ZW", "ZWG", "+263", Some("Africa/Harare")),
+];
+
+/// EU members, with the outermost regions that have codes of their own.
+static EU: &[&str] = &[
+    "AT", "AX", "BE", "BG", "CY", "CZ", "DE", "DK", "EE", "ES", "FI", "FR", "GF", "GP", "GR", "HR",
+    "HU", "IE", "IT", "LT", "LU", "LV", "MF", "MQ", "MT", "NL", "PL", "PT", "RE", "RO", "SE", "SI",
+    "SK", "YT",
+];
+/// EEA members outside the EU.
+static EFTA_EEA: &[&str] = &["IS", "LI", "NO"];
+
+/// Reference data of an ISO 3166-1 alpha-2 code, case-insensitive.
+pub fn info(code: &str) -> Option<&'static CountryInfo> {
+    let code = code.to_ascii_uppercase();
+    COUNTRIES
+        .binary_search_by(|country| country.code.cmp(code.as_str()))
+        .ok()
+        .map(|index| &COUNTRIES[index])
+}
+
+/// Current offset of an IANA zone from UTC in seconds, daylight saving included.
+pub fn utc_offset(timezone: &str) -> Option<i32> {
+    let tz: Tz = timezone.parse().ok()?;
+    let now = Utc::now().naive_utc();
+    Some(tz.offset_from_utc_datetime(&now).fix().local_minus_utc())
+}
+
+/// Fills the currency, calling code and time zone the provider left out, and
+/// computes the UTC offset of the zone at the current time.
+pub fn complete(geo: &mut Geo) {
+    if let Some(code) = geo.country_code.as_deref().map(str::to_ascii_uppercase) {
+        let is_eu = EU.contains(&code.as_str());
+        let is_eea = is_eu || EFTA_EEA.contains(&code.as_str());
+        let is_uk = code == "GB";
+        geo.is_eu = Some(is_eu);
+        geo.is_eea = Some(is_eea);
+        geo.is_uk = Some(is_uk);
+        geo.gdpr = Some(is_eea || is_uk);
+    }
+    if let Some(country) = geo.country_code.as_deref().and_then(info) {
+        geo.currency.get_or_insert_with(|| country.currency.into());
+        geo.calling_code
+            .get_or_insert_with(|| country.calling_code.into());
+        if geo.timezone.is_none() {
+            geo.timezone = country.timezone.map(Into::into);
+        }
+    }
+    if let Some(offset) = geo.timezone.as_deref().and_then(utc_offset) {
+        geo.utc_offset = Some(offset);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_table() {
+        assert!(
+            COUNTRIES.windows(2).all(|pair| pair[0].code < pair[1].code),
+            "Table must stay sorted"
+        );
+        for country in COUNTRIES {
+            if let Some(timezone) = country.timezone {
+                assert!(timezone.parse::<Tz>().is_ok(), "unknown zone {timezone}");
+            }
+        }
+        assert_eq!(info("de").unwrap().calling_code, "+49");
+        assert_eq!(info("ZZ"), None);
+        for code in EU.iter().chain(EFTA_EEA) {
+            assert!(info(code).is_some(), "unknown member {code}");
+        }
+    }
+
+    #[test]
+    fn test_flag() {
+        let flag = CountryFlag::new("DE", DEFAULT_FLAG_IMAGE_URL).unwrap();
+        assert_eq!(flag.emoji, "\u{1F1E9}\u{1F1EA}");
+        assert_eq!(flag.image_url, "https://flagcdn.com/de.svg");
+        assert_eq!(CountryFlag::new("D1", DEFAULT_FLAG_IMAGE_URL), None);
+    }
+
+    #[test]
+    fn test_memberships() {
+        let flags = |code: &str| {
+            let mut geo = Geo::new("1.2.3.4".parse().unwrap(), "test");
+            geo.country_code = Some(code.into());
+            complete(&mut geo);
+            (geo.is_eu, geo.is_eea, geo.is_uk, geo.gdpr)
+        };
+        let yes = Some(true);
+        let no = Some(false);
+        assert_eq!(flags("de"), (yes, yes, no, yes));
+        assert_eq!(flags("NO"), (no, yes, no, yes));
+        assert_eq!(flags("GB"), (no, no, yes, yes));
+        assert_eq!(flags("CH"), (no, no, no, no));
+        assert_eq!(flags("RE"), (yes, yes, no, yes));
+    }
+
+    #[test]
+    fn test_complete() {
+        let mut geo = Geo::new("1.2.3.4".parse().unwrap(), "test");
+        geo.country_code = Some("JP".into());
+        geo.currency = Some("USD".into());
+        complete(&mut geo);
+        assert_eq!(geo.currency.as_deref(), Some("USD"), "Provider value kept");
+        assert_eq!(geo.calling_code.as_deref(), Some("+81"));
+        assert_eq!(geo.timezone.as_deref(), Some("Asia/Tokyo"));
+        assert_eq!(geo.utc_offset, Some(9 * 3600));
+        assert_eq!(geo.gdpr, Some(false));
+
+        // several zones, only the provider knows which one
+        let mut geo = Geo::new("1.2.3.4".parse().unwrap(), "test");
+        geo.country_code = Some("US".into());
+        complete(&mut geo);
+        assert_eq!(geo.timezone, None);
+        assert_eq!(geo.utc_offset, None);
+    }
+}
@@ -0,0 +1,85 @@
+//! Datasets downloaded on a schedule
+//!
+//! A dataset is replaced as a whole by every download and saved to an optional
+//! snapshot file. The snapshot is loaded at startup, the next download only
+//! happens once it is older than the refresh interval.
+
+use crate::error::Error;
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    future::Future,
+    path::Path,
+    sync::{Arc, Weak},
+    time::{Duration, SystemTime},
+};
+use tracing::{info, warn};
+
+/// Wait before the next attempt when a download failed.
+const RETRY_AFTER: Duration = Duration::from_secs(10 * 60);
+
+pub trait Dataset: Send + Sync + 'static {
+    /// Used in the logs.
+    const NAME: &'static str;
+
+    /// Time of the current data, `None` before the first download.
+    fn updated(&self) -> Option<SystemTime>;
+
+    /// Configured time between two downloads.
+    fn refresh_interval(&self) -> Duration;
+
+    /// Downloads and swaps in the data, returns the number of records.
+    fn refresh(&self) -> impl Future<Output = Result<usize, Error>> + Send;
+}
+
+/// Refreshes `dataset` every refresh interval until it is dropped.
+pub fn spawn_refresh<D: Dataset>(dataset: &Arc<D>) {
+    let every = dataset.refresh_interval();
+    let dataset: Weak<D> = Arc::downgrade(dataset);
+    tokio::spawn(async move {
+        loop {
+            let Some(current) = dataset.upgrade() else {
+                return;
+            };
+            let age = current.updated().and_then(|updated| updated.elapsed().ok());
+            let wait = match age {
+                Some(age) if age < every => every - age,
+                _ => match current.refresh().await {
+                    Ok(count) => {
+                        info!("{}: {} records", D::NAME, count);
+                        every
+                    }
+                    Err(e) => {
+                        warn!("{} refresh failed: {}", D::NAME, e);
+                        RETRY_AFTER.min(every)
+                    }
+                },
+            };
+            drop(current);
+            tokio::time::sleep(wait).await;
+        }
+    });
+}
+
+/// Snapshot at `path` with its modification time, `None` if it is missing or unreadable.
+pub fn load_snapshot<T: DeserializeOwned>(path: &Path) -> Option<(T, SystemTime)> {
+    let snapshot = std::fs::read(path)
+        .map_err(|e| e.to_string())
+        .and_then(|bytes| serde_json::from_slice(&bytes).map_err(|e| e.to_string()));
+    match snapshot {
+        Ok(data) => {
+            let modified = std::fs::metadata(path).and_then(|m| m.modified()).ok();
+            Some((data, modified.unwrap_or(SystemTime::UNIX_EPOCH)))
+        }
+        Err(e) => {
+            warn!("snapshot {} not loaded: {}", path.display(), e);
+            None
+        }
+    }
+}
+
+pub async fn save_snapshot<T: Serialize>(path: &Path, data: &T) -> Result<(), Error> {
+    let json = serde_json::to_vec(data).map_err(|e| Error::StorageError(e.to_string()))?;
+    tokio::fs::write(path, json)
+        .await
+        .map_err(|e| Error::StorageError(format!("{}: {e}", path.display())))
+}
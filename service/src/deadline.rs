@@ -0,0 +1,62 @@
+//! Deadlines of the HTTP routes
+//!
+//! Every route belongs to a class with its own limit: the fast health and
+//! metrics routes, the normal lookups and the long batch, job, CIDR and
+//! measurement routes. A handler still running at the limit is dropped and
+//! answered with a 504, so a stuck upstream never holds a connection forever.
+
+use crate::error::Error;
+use axum::{
+    extract::{Request, State},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use std::time::Duration;
+use tracing::warn;
+
+/// Middleware running the rest of the stack within `limit`.
+pub async fn deadline(State(limit): State<Duration>, request: Request, next: Next) -> Response {
+    let path = request.uri().path().to_string();
+    match tokio::time::timeout(limit, next.run(request)).await {
+        Ok(response) => response,
+        Err(_) => {
+            warn!("{} did not complete within {:?}", path, limit);
+            Error::DeadlineExceeded(limit).into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, http::StatusCode, middleware, routing::get, Router};
+    use tower::ServiceExt;
+
+    fn app(limit: Duration) -> Router {
+        Router::new()
+            .route(
+                "/slow",
+                get(|| async {
+                    tokio::time::sleep(Duration::from_secs(60)).await;
+                    "done"
+                }),
+            )
+            .route("/fast", get(|| async { "done" }))
+            .layer(middleware::from_fn_with_state(limit, deadline))
+    }
+
+    async fn status(path: &str) -> StatusCode {
+        let request = Request::builder().uri(path).body(Body::empty()).unwrap();
+        let response = app(Duration::from_millis(20))
+            .oneshot(request)
+            .await
+            .unwrap();
+        response.status()
+    }
+
+    #[tokio::test]
+    async fn test_deadline() {
+        assert_eq!(status("/fast").await, StatusCode::OK);
+        assert_eq!(status("/slow").await, StatusCode::GATEWAY_TIMEOUT);
+    }
+}
@@ -0,0 +1,211 @@
+//! Cross-provider discrepancy detection
+//!
+//! A sample of the lookups is resolved a second time in the background with
+//! another provider. Country mismatches and locations further apart than
+//! `distance_km` are recorded per provider pair, served at
+//! `/stats/discrepancies`.
+
+use crate::{config::DiscrepancyConfig, geo::Geo, sampling::chance};
+use serde::Serialize;
+use std::{collections::VecDeque, net::IpAddr, sync::Mutex};
+use utoipa::ToSchema;
+
+/// Comparison counters of a provider pair.
+#[derive(Serialize, ToSchema, Debug, Clone, PartialEq)]
+pub struct PairStats {
+    /// Provider that answered the lookup.
+    pub primary: String,
+    /// Provider it was compared against.
+    pub secondary: String,
+    pub compared: u64,
+    pub country_mismatches: u64,
+    /// Comparisons with locations further apart than the threshold.
+    pub distant: u64,
+    /// Mean distance of the comparisons with coordinates on both sides.
+    pub mean_distance_km: Option<f64>,
+    #[serde(skip)]
+    distance_sum: f64,
+    #[serde(skip)]
+    distance_count: u64,
+}
+
+/// One recorded disagreement.
+#[derive(Serialize, ToSchema, Debug, Clone, PartialEq)]
+pub struct Discrepancy {
+    #[schema(value_type = String)]
+    pub ip: IpAddr,
+    pub primary: String,
+    pub secondary: String,
+    pub primary_country: Option<String>,
+    pub secondary_country: Option<String>,
+    pub distance_km: Option<f64>,
+}
+
+#[derive(Serialize, ToSchema, Debug, Clone, PartialEq)]
+pub struct DiscrepancyStats {
+    pub sample_rate: f64,
+    pub distance_km: f64,
+    pub pairs: Vec<PairStats>,
+    /// Latest disagreements, newest first.
+    pub recent: Vec<Discrepancy>,
+}
+
+/// Comparisons a provider took part in, on either side.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Agreement {
+    pub compared: u64,
+    /// Same country on both sides, or a country missing on one.
+    pub country_agreed: u64,
+    /// Locations within the threshold, or coordinates missing on one side.
+    pub location_agreed: u64,
+}
+
+#[derive(Default)]
+struct Records {
+    pairs: Vec<PairStats>,
+    recent: VecDeque<Discrepancy>,
+}
+
+pub struct Comparator {
+    config: DiscrepancyConfig,
+    records: Mutex<Records>,
+}
+
+impl Comparator {
+    pub fn new(config: DiscrepancyConfig) -> Self {
+        Comparator {
+            config,
+            records: Mutex::new(Records::default()),
+        }
+    }
+
+    /// Whether this lookup is part of the sample.
+    pub fn sample(&self) -> bool {
+        chance(self.config.sample_rate)
+    }
+
+    /// Compares two answers for the same address.
+    pub fn record(&self, primary: &Geo, secondary: &Geo) {
+        let country_mismatch = match (&primary.country_code, &secondary.country_code) {
+            (Some(a), Some(b)) => !a.eq_ignore_ascii_case(b),
+            _ => false,
+        };
+        let distance = primary.distance_km(secondary);
+        let distant = distance.is_some_and(|d| d > self.config.distance_km);
+
+        let mut records = self.records.lock().unwrap();
+        let index = records
+            .pairs
+            .iter()
+            .position(|p| p.primary == primary.provider && p.secondary == secondary.provider)
+            .unwrap_or_else(|| {
+                records.pairs.push(PairStats {
+                    primary: primary.provider.clone(),
+                    secondary: secondary.provider.clone(),
+                    compared: 0,
+                    country_mismatches: 0,
+                    distant: 0,
+                    mean_distance_km: None,
+                    distance_sum: 0.0,
+                    distance_count: 0,
+                });
+                records.pairs.len() - 1
+            });
+        let pair = &mut records.pairs[index];
+        pair.compared += 1;
+        pair.country_mismatches += u64::from(country_mismatch);
+        pair.distant += u64::from(distant);
+        if let Some(distance) = distance {
+            pair.distance_sum += distance;
+            pair.distance_count += 1;
+            pair.mean_distance_km = Some(pair.distance_sum / pair.distance_count as f64);
+        }
+
+        if country_mismatch || distant {
+            if records.recent.len() >= self.config.recent {
+                records.recent.pop_back();
+            }
+            records.recent.push_front(Discrepancy {
+                ip: primary.ip,
+                primary: primary.provider.clone(),
+                secondary: secondary.provider.clone(),
+                primary_country: primary.country_code.clone(),
+                secondary_country: secondary.country_code.clone(),
+                distance_km: distance,
+            });
+        }
+    }
+
+    /// Comparisons of `provider`, with every other one.
+    pub fn agreement(&self, provider: &str) -> Agreement {
+        let records = self.records.lock().unwrap();
+        records
+            .pairs
+            .iter()
+            .filter(|p| p.primary == provider || p.secondary == provider)
+            .fold(Agreement::default(), |sum, p| Agreement {
+                compared: sum.compared + p.compared,
+                country_agreed: sum.country_agreed + p.compared - p.country_mismatches,
+                location_agreed: sum.location_agreed + p.compared - p.distant,
+            })
+    }
+
+    pub fn stats(&self) -> DiscrepancyStats {
+        let records = self.records.lock().unwrap();
+        DiscrepancyStats {
+            sample_rate: self.config.sample_rate,
+            distance_km: self.config.distance_km,
+            pairs: records.pairs.clone(),
+            recent: records.recent.iter().cloned().collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn geo(provider: &str, country: &str, lat: f64, lon: f64) -> Geo {
+        let mut geo = Geo::new("1.2.3.4".parse().unwrap(), provider);
+        geo.country_code = Some(country.to_string());
+        geo.latitude = Some(lat);
+        geo.longitude = Some(lon);
+        geo
+    }
+
+    fn comparator() -> Comparator {
+        Comparator::new(DiscrepancyConfig {
+            sample_rate: 1.0,
+            distance_km: 100.0,
+            recent: 2,
+        })
+    }
+
+    #[test]
+    fn test_record() {
+        let comparator = comparator();
+        assert!(comparator.sample());
+        comparator.record(&geo("a", "DE", 52.52, 13.405), &geo("b", "DE", 52.4, 13.3));
+        comparator.record(&geo("a", "DE", 52.52, 13.405), &geo("b", "FR", 48.85, 2.35));
+        let stats = comparator.stats();
+        assert_eq!(stats.pairs.len(), 1);
+        assert_eq!(stats.pairs[0].compared, 2);
+        assert_eq!(stats.pairs[0].country_mismatches, 1);
+        assert_eq!(stats.pairs[0].distant, 1);
+        assert_eq!(stats.recent.len(), 1);
+        assert_eq!(stats.recent[0].secondary_country.as_deref(), Some("FR"));
+        let agreement = comparator.agreement("b");
+        assert_eq!(agreement.compared, 2);
+        assert_eq!(agreement.country_agreed, 1);
+        assert_eq!(comparator.agreement("c"), Agreement::default());
+    }
+
+    #[test]
+    fn test_recent_capped() {
+        let comparator = comparator();
+        for _ in 0..5 {
+            comparator.record(&geo("a", "DE", 0.0, 0.0), &geo("b", "US", 0.0, 0.0));
+        }
+        assert_eq!(comparator.stats().recent.len(), 2);
+    }
+}
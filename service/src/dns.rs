@@ -0,0 +1,231 @@
+//! Hostname and PTR resolution
+//!
+//! A and AAAA lookups through hickory, with the CNAME chain that led to the
+//! addresses. The resolver is the system one, or the upstream, DoH or DoT
+//! servers of [`DnsConfig`], and caches the answers for their TTL.
+
+use crate::{
+    config::{DnsConfig, IpFamily, ResolverKind},
+    error::Error,
+};
+use hickory_resolver::{
+    config::{LookupIpStrategy, NameServerConfig, NameServerConfigGroup, ResolverConfig},
+    name_server::TokioConnectionProvider,
+    proto::{
+        rr::{RData, RecordType},
+        xfer::Protocol,
+    },
+    ResolveError, ResolverBuilder, TokioResolver,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    net::IpAddr,
+    time::{Duration, Instant},
+};
+use tracing::{info, warn};
+use utoipa::ToSchema;
+
+/// Addresses a hostname resolves to.
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone, PartialEq)]
+pub struct Resolution {
+    pub host: String,
+    /// CNAME targets followed from `host`, in order.
+    pub chain: Vec<String>,
+    #[schema(value_type = Vec<String>)]
+    pub addresses: Vec<IpAddr>,
+}
+
+pub struct Resolver {
+    resolver: TokioResolver,
+}
+
+impl Resolver {
+    /// Resolver described by `config`, the system one when the servers are unusable.
+    pub fn new(config: &DnsConfig) -> Self {
+        Resolver::with_strategy(config, LookupIpStrategy::default())
+    }
+
+    /// Resolver of the outbound connections, to the addresses of `family`.
+    pub fn outbound(config: &DnsConfig, family: IpFamily) -> Self {
+        let strategy = match family {
+            IpFamily::Dual => LookupIpStrategy::Ipv4AndIpv6,
+            IpFamily::Ipv4 => LookupIpStrategy::Ipv4Only,
+            IpFamily::Ipv6 => LookupIpStrategy::Ipv6Only,
+        };
+        Resolver::with_strategy(config, strategy)
+    }
+
+    fn with_strategy(config: &DnsConfig, strategy: LookupIpStrategy) -> Self {
+        let mut builder = match upstream(config) {
+            Ok(None) => system(),
+            Ok(Some(servers)) => {
+                let names: Vec<String> = servers.iter().map(ToString::to_string).collect();
+                info!("dns servers: {}", names.join(", "));
+                let config = ResolverConfig::from_parts(None, Vec::new(), servers);
+                TokioResolver::builder_with_config(config, TokioConnectionProvider::default())
+            }
+            Err(e) => {
+                warn!("dns: {}, using the system resolver", e);
+                system()
+            }
+        };
+        let options = builder.options_mut();
+        options.cache_size = config.cache_size;
+        options.timeout = Duration::from_millis(config.timeout_ms);
+        options.ip_strategy = strategy;
+        if let Some(max_ttl) = config.max_ttl_secs.map(Duration::from_secs) {
+            options.positive_max_ttl = Some(max_ttl);
+            options.negative_max_ttl = Some(max_ttl);
+        }
+        Resolver {
+            resolver: builder.build(),
+        }
+    }
+
+    /// The system resolver with the default options.
+    pub fn system() -> Self {
+        Resolver::new(&DnsConfig::default())
+    }
+
+    /// A and AAAA records of `host`.
+    pub async fn resolve(&self, host: &str) -> Result<Resolution, Error> {
+        let lookup = self
+            .resolver
+            .lookup_ip(host)
+            .await
+            .map_err(|e| resolve_error(host, e))?;
+        let chain = lookup
+            .as_lookup()
+            .records()
+            .iter()
+            .filter(|record| record.record_type() == RecordType::CNAME)
+            .filter_map(|record| match record.data() {
+                RData::CNAME(target) => Some(fqdn(&target.0.to_string())),
+                _ => None,
+            })
+            .collect();
+        Ok(Resolution {
+            host: host.to_string(),
+            chain,
+            addresses: lookup.iter().collect(),
+        })
+    }
+
+    /// Addresses of `host`, with the end of their TTL.
+    pub async fn addresses(&self, host: &str) -> Result<(Vec<IpAddr>, Instant), Error> {
+        let lookup = self
+            .resolver
+            .lookup_ip(host)
+            .await
+            .map_err(|e| resolve_error(host, e))?;
+        Ok((lookup.iter().collect(), lookup.valid_until()))
+    }
+
+    /// PTR names of `ip`.
+    pub async fn reverse(&self, ip: IpAddr) -> Result<Vec<String>, Error> {
+        let lookup = self
+            .resolver
+            .reverse_lookup(ip)
+            .await
+            .map_err(|e| resolve_error(&ip.to_string(), e))?;
+        Ok(lookup.iter().map(|name| fqdn(&name.to_string())).collect())
+    }
+}
+
+fn system() -> ResolverBuilder<TokioConnectionProvider> {
+    TokioResolver::builder_tokio().unwrap_or_else(|e| {
+        warn!(
+            "system resolver configuration unusable, using defaults: {}",
+            e
+        );
+        TokioResolver::builder_with_config(
+            ResolverConfig::default(),
+            TokioConnectionProvider::default(),
+        )
+    })
+}
+
+/// Name servers of `config`, `None` for the system resolver.
+fn upstream(config: &DnsConfig) -> Result<Option<NameServerConfigGroup>, String> {
+    let protocols: &[Protocol] = match config.resolver {
+        ResolverKind::System => return Ok(None),
+        ResolverKind::Upstream => &[Protocol::Udp, Protocol::Tcp],
+        ResolverKind::Doh => &[Protocol::Https],
+        ResolverKind::Dot => &[Protocol::Tls],
+    };
+    let addrs = config.server_addrs()?;
+    if addrs.is_empty() {
+        return Err("no name servers".into());
+    }
+    let mut servers = NameServerConfigGroup::with_capacity(addrs.len() * protocols.len());
+    for addr in addrs {
+        for &protocol in protocols {
+            let mut server = NameServerConfig::new(addr, protocol);
+            server.tls_dns_name = config.tls_name.clone();
+            servers.push(server);
+        }
+    }
+    Ok(Some(servers))
+}
+
+fn resolve_error(name: &str, e: ResolveError) -> Error {
+    if e.is_no_records_found() {
+        Error::NotFound(format!("records of {name}"))
+    } else {
+        Error::ProviderUnavailable(format!("resolution of {name} failed: {e}"))
+    }
+}
+
+/// `example.com.` without the root label.
+fn fqdn(name: &str) -> String {
+    name.trim_end_matches('.').to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_hosts_file() {
+        // answered from /etc/hosts, no network needed
+        let resolution = Resolver::system().resolve("localhost").await.unwrap();
+        assert!(resolution.addresses.iter().any(IpAddr::is_loopback));
+        assert!(resolution.chain.is_empty());
+    }
+
+    #[test]
+    fn test_upstream() {
+        let config = DnsConfig {
+            resolver: ResolverKind::Doh,
+            servers: vec!["1.1.1.1".into(), "1.0.0.1:8443".into()],
+            tls_name: Some("cloudflare-dns.com".into()),
+            ..Default::default()
+        };
+        let servers: Vec<String> = upstream(&config)
+            .unwrap()
+            .unwrap()
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        assert_eq!(
+            servers,
+            [
+                "https:cloudflare-dns.com@1.1.1.1:443",
+                "https:cloudflare-dns.com@1.0.0.1:8443"
+            ]
+        );
+
+        let config = DnsConfig {
+            resolver: ResolverKind::Upstream,
+            servers: vec!["10.0.0.53".into()],
+            ..Default::default()
+        };
+        assert_eq!(upstream(&config).unwrap().unwrap().len(), 2, "UDP and TCP");
+        assert!(upstream(&DnsConfig::default()).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_fqdn() {
+        assert_eq!(fqdn("www.example.com."), "www.example.com");
+    }
+}
@@ -0,0 +1,158 @@
+//! DNS blocklist (DNSBL) checks
+//!
+//! Every configured zone is queried in parallel with a short timeout. An address
+//! is listed when `<reversed address>.<zone>` resolves to a `127.0.0.0/8` answer.
+
+use crate::config::DnsblConfig;
+use futures::future::join_all;
+use serde::{Deserialize, Serialize};
+use std::{
+    net::{IpAddr, Ipv4Addr},
+    time::Duration,
+};
+use tokio::{net::lookup_host, time::timeout};
+use utoipa::ToSchema;
+
+/// Result of checking an address against the configured blocklists.
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone, PartialEq)]
+pub struct DnsblReport {
+    /// Blocklists the address is listed on.
+    pub listed: Vec<DnsblMatch>,
+    /// Number of blocklists that answered.
+    pub checked: usize,
+    /// Blocklists that timed out or refused the query.
+    pub failed: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone, PartialEq)]
+pub struct DnsblMatch {
+    pub zone: String,
+    /// Return codes, their meaning is specific to each list.
+    pub codes: Vec<String>,
+}
+
+enum ZoneAnswer {
+    Listed(Vec<Ipv4Addr>),
+    NotListed,
+    Failed,
+}
+
+pub struct Dnsbl {
+    config: DnsblConfig,
+}
+
+impl Dnsbl {
+    pub fn new(config: DnsblConfig) -> Self {
+        Dnsbl { config }
+    }
+
+    /// Checks `ip` against every configured zone.
+    pub async fn check(&self, ip: IpAddr) -> DnsblReport {
+        let name = reverse_name(ip);
+        let queries = self
+            .config
+            .zones
+            .iter()
+            .map(|zone| self.query_zone(format!("{name}.{zone}")));
+        let answers = join_all(queries).await;
+
+        let mut report = DnsblReport {
+            listed: Vec::new(),
+            checked: 0,
+            failed: Vec::new(),
+        };
+        for (zone, answer) in self.config.zones.iter().zip(answers) {
+            match answer {
+                ZoneAnswer::Listed(codes) => {
+                    report.checked += 1;
+                    report.listed.push(DnsblMatch {
+                        zone: zone.clone(),
+                        codes: codes.iter().map(|c| c.to_string()).collect(),
+                    });
+                }
+                ZoneAnswer::NotListed => report.checked += 1,
+                ZoneAnswer::Failed => report.failed.push(zone.clone()),
+            }
+        }
+        report
+    }
+
+    async fn query_zone(&self, name: String) -> ZoneAnswer {
+        let query = lookup_host((name, 0));
+        match timeout(Duration::from_millis(self.config.timeout_ms), query).await {
+            Ok(Ok(addrs)) => {
+                let codes: Vec<Ipv4Addr> = addrs
+                    .filter_map(|addr| match addr.ip() {
+                        IpAddr::V4(v4) => Some(v4),
+                        IpAddr::V6(_) => None,
+                    })
+                    .collect();
+                classify(codes)
+            }
+            // NXDOMAIN, the address is not listed
+            Ok(Err(_)) => ZoneAnswer::NotListed,
+            Err(_) => ZoneAnswer::Failed,
+        }
+    }
+}
+
+fn classify(codes: Vec<Ipv4Addr>) -> ZoneAnswer {
+    // 127.255.255.0/24 are error codes, e.g. Spamhaus refusing queries from public resolvers
+    if codes.iter().any(|c| c.octets()[..3] == [127, 255, 255]) {
+        return ZoneAnswer::Failed;
+    }
+    let codes: Vec<Ipv4Addr> = codes.into_iter().filter(|c| c.octets()[0] == 127).collect();
+    if codes.is_empty() {
+        ZoneAnswer::NotListed
+    } else {
+        ZoneAnswer::Listed(codes)
+    }
+}
+
+/// Builds the DNSBL query label: reversed octets for IPv4, reversed nibbles for IPv6.
+fn reverse_name(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(v4) => {
+            let [a, b, c, d] = v4.octets();
+            format!("{d}.{c}.{b}.{a}")
+        }
+        IpAddr::V6(v6) => v6
+            .octets()
+            .iter()
+            .rev()
+            .flat_map(|byte| [byte & 0x0f, byte >> 4])
+            .map(|nibble| format!("{nibble:x}"))
+            .collect::<Vec<_>>()
+            .join("."),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reverse_name() {
+        let ip = "127.0.0.2".parse().unwrap();
+        assert_eq!(reverse_name(ip), "2.0.0.127");
+
+        let ip = "2001:db8::1".parse().unwrap();
+        assert_eq!(
+            reverse_name(ip),
+            "1.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.8.b.d.0.1.0.0.2"
+        );
+    }
+
+    #[test]
+    fn test_classify() {
+        let listed = classify(vec![Ipv4Addr::new(127, 0, 0, 2)]);
+        assert!(matches!(listed, ZoneAnswer::Listed(codes) if codes.len() == 1));
+
+        let refused = classify(vec![Ipv4Addr::new(127, 255, 255, 254)]);
+        assert!(matches!(refused, ZoneAnswer::Failed));
+
+        // wildcard answers outside of 127/8 are not listings
+        let wildcard = classify(vec![Ipv4Addr::new(1, 2, 3, 4)]);
+        assert!(matches!(wildcard, ZoneAnswer::NotListed));
+    }
+}
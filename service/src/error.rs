@@ -0,0 +1,392 @@
+//! Service errors and their HTTP representation
+
+use crate::providers::ProviderError;
+use axum::{
+    http::{
+        header::{CONTENT_TYPE, RETRY_AFTER},
+        HeaderValue, StatusCode,
+    },
+    response::{IntoResponse, Response},
+    Json,
+};
+use public_ip_address::{error::Error as CoreError, lookup::error::LookupError};
+use serde::{Deserialize, Serialize};
+use std::{net::IpAddr, time::Duration};
+use thiserror::Error;
+use tracing::warn;
+use utoipa::ToSchema;
+
+/// Media type of the error responses.
+pub const PROBLEM_JSON: &str = "application/problem+json";
+
+/// What the service ran out of, the `resource` member of its 429 and 503 answers.
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Resource {
+    /// The providers answer 429 or wait for their rate limit window to reset.
+    ProviderRateLimit,
+    /// The `rate_limit` configured for the providers.
+    RateLimit,
+    /// The daily or monthly `budget` of the providers.
+    Budget,
+    /// The outbound requests in flight, see `upstream.max_concurrency`.
+    UpstreamConcurrency,
+}
+
+impl std::fmt::Display for Resource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Resource::ProviderRateLimit => "provider rate limit",
+            Resource::RateLimit => "configured rate limit",
+            Resource::Budget => "provider budget",
+            Resource::UpstreamConcurrency => "upstream concurrency",
+        })
+    }
+}
+
+/// Error type for the service handlers
+#[allow(clippy::enum_variant_names)]
+#[derive(Error, Debug)]
+pub enum Error {
+    /// Malformed address, unknown provider or any other bad request field
+    #[error("invalid input: {0}")]
+    InvalidInput(String),
+    /// The feature behind the endpoint is not configured
+    #[error("{0} is not configured")]
+    NotConfigured(&'static str),
+    /// Every provider tried failed or the upstream request failed
+    #[error("provider unavailable: {0}")]
+    ProviderUnavailable(String),
+    /// The host of the provider could not be resolved
+    #[error("provider unreachable: {0}")]
+    ProviderUnresolved(String),
+    /// The provider answered but refused the lookup
+    #[error("provider rejected the lookup: {0}")]
+    ProviderRejected(String),
+    /// The upstream request did not complete in time
+    #[error("upstream request timed out")]
+    Timeout,
+    /// The handler did not complete within the deadline of its route
+    #[error("request did not complete within {0:?}")]
+    DeadlineExceeded(Duration),
+    /// Reading or writing the on-disk cache failed
+    #[error("cache error: {0}")]
+    CacheError(String),
+    /// Reading or writing recordings failed
+    #[error("storage error: {0}")]
+    StorageError(String),
+    /// Missing or wrong admin token
+    #[error("missing or wrong admin token")]
+    Unauthorized,
+    /// The admin endpoints are disabled without a configured token
+    #[error("no admin token configured")]
+    Forbidden,
+    /// The caller is refused by the geo policy or the admin sources
+    #[error("blocked: {0}")]
+    Blocked(String),
+    /// The target policy refuses lookups of the address
+    #[error("lookups of {target} are refused by the {policy} policy")]
+    TargetRefused { target: IpAddr, policy: String },
+    /// Lookups are suspended by the maintenance mode
+    #[error("service in maintenance: {reason}")]
+    Maintenance {
+        retry_after_secs: u64,
+        reason: String,
+    },
+    /// Too many requests to the endpoint or for the target
+    #[error("rate limited: {0}")]
+    RateLimited(String),
+    /// Every provider tried is out of quota or capacity, nothing was looked up
+    #[error("out of {resource}, retry in {retry_after_secs}s")]
+    Saturated {
+        resource: Resource,
+        retry_after_secs: u64,
+    },
+    /// The requested resource does not exist
+    #[error("{0} not found")]
+    NotFound(String),
+    /// Anything else
+    #[error("internal error: {0}")]
+    Internal(String),
+}
+
+impl Error {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Error::InvalidInput(_) => StatusCode::BAD_REQUEST,
+            Error::NotConfigured(_) | Error::Maintenance { .. } => StatusCode::SERVICE_UNAVAILABLE,
+            Error::ProviderUnavailable(_) | Error::ProviderUnresolved(_) => StatusCode::BAD_GATEWAY,
+            Error::ProviderRejected(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            Error::Timeout | Error::DeadlineExceeded(_) => StatusCode::GATEWAY_TIMEOUT,
+            Error::CacheError(_) | Error::StorageError(_) | Error::Internal(_) => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+            Error::Unauthorized => StatusCode::UNAUTHORIZED,
+            Error::Forbidden | Error::Blocked(_) | Error::TargetRefused { .. } => {
+                StatusCode::FORBIDDEN
+            }
+            Error::NotFound(_) => StatusCode::NOT_FOUND,
+            Error::RateLimited(_) => StatusCode::TOO_MANY_REQUESTS,
+            Error::Saturated {
+                resource: Resource::UpstreamConcurrency,
+                ..
+            } => StatusCode::SERVICE_UNAVAILABLE,
+            Error::Saturated { .. } => StatusCode::TOO_MANY_REQUESTS,
+        }
+    }
+
+    /// Stable machine readable code, the `error` field of the body.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Error::InvalidInput(_) => "invalid_input",
+            Error::NotConfigured(_) => "not_configured",
+            Error::ProviderUnavailable(_) => "provider_unavailable",
+            Error::ProviderUnresolved(_) => "provider_unresolved",
+            Error::ProviderRejected(_) => "provider_rejected",
+            Error::Timeout => "timeout",
+            Error::DeadlineExceeded(_) => "deadline_exceeded",
+            Error::CacheError(_) => "cache_error",
+            Error::StorageError(_) => "storage_error",
+            Error::Unauthorized => "unauthorized",
+            Error::Forbidden => "forbidden",
+            Error::Blocked(_) => "blocked",
+            Error::TargetRefused { .. } => "target_refused",
+            Error::NotFound(_) => "not_found",
+            Error::RateLimited(_) => "rate_limited",
+            Error::Saturated { .. } => "saturated",
+            Error::Maintenance { .. } => "maintenance",
+            Error::Internal(_) => "internal",
+        }
+    }
+
+    /// Response body describing the error.
+    pub fn body(&self) -> ErrorBody {
+        let mut body = ErrorBody::new(self.status(), self.code(), self.to_string());
+        match self {
+            Error::TargetRefused { policy, .. } => body.policy = Some(policy.clone()),
+            Error::Saturated {
+                resource,
+                retry_after_secs,
+            } => {
+                body.resource = Some(*resource);
+                body.retry_after_secs = Some(*retry_after_secs);
+            }
+            Error::Maintenance {
+                retry_after_secs, ..
+            } => body.retry_after_secs = Some(*retry_after_secs),
+            _ => {}
+        }
+        body
+    }
+}
+
+/// Body of every error response, an RFC 9457 problem details object served
+/// as `application/problem+json`
+///
+/// `error` and `message` are extension members, `message` repeats `detail`
+/// for the clients of the earlier body.
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone, PartialEq)]
+pub struct ErrorBody {
+    /// Problem type, `about:blank` as the status and `error` identify it
+    #[serde(rename = "type", default = "about_blank")]
+    #[schema(example = "about:blank")]
+    pub kind: String,
+    /// Reason phrase of the status
+    #[serde(default)]
+    #[schema(example = "Bad Request")]
+    pub title: String,
+    /// HTTP status of the response
+    #[serde(default)]
+    #[schema(example = 400)]
+    pub status: u16,
+    /// Human readable description
+    #[serde(default)]
+    #[schema(example = "invalid input: invalid IP address 8.8.8")]
+    pub detail: String,
+    /// Machine readable code, e.g. `provider_unavailable`
+    #[schema(example = "invalid_input")]
+    pub error: String,
+    /// Same as `detail`
+    #[schema(example = "invalid input: invalid IP address 8.8.8")]
+    pub message: String,
+    /// `x-request-id` of the request, set on the answers of panicked handlers
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+    /// Name of the target policy rule refusing the lookup
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub policy: Option<String>,
+    /// What ran out, set on the answers of a saturated service
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub resource: Option<Resource>,
+    /// Same as the `Retry-After` header
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retry_after_secs: Option<u64>,
+}
+
+impl ErrorBody {
+    pub fn new(status: StatusCode, error: impl Into<String>, message: impl Into<String>) -> Self {
+        let message = message.into();
+        ErrorBody {
+            kind: about_blank(),
+            title: status.canonical_reason().unwrap_or_default().into(),
+            status: status.as_u16(),
+            detail: message.clone(),
+            error: error.into(),
+            message,
+            request_id: None,
+            policy: None,
+            resource: None,
+            retry_after_secs: None,
+        }
+    }
+
+    /// Throttling, gateway errors and timeouts are worth another try.
+    pub fn is_transient(&self) -> bool {
+        matches!(self.status, 429 | 502 | 503 | 504)
+    }
+}
+
+fn about_blank() -> String {
+    "about:blank".into()
+}
+
+impl IntoResponse for Error {
+    fn into_response(self) -> Response {
+        let status = self.status();
+        if status.is_server_error() {
+            warn!("request failed: {}", self);
+        }
+        let mut response =
+            (status, [(CONTENT_TYPE, PROBLEM_JSON)], Json(self.body())).into_response();
+        if let Error::Maintenance {
+            retry_after_secs, ..
+        }
+        | Error::Saturated {
+            retry_after_secs, ..
+        } = self
+        {
+            response
+                .headers_mut()
+                .insert(RETRY_AFTER, HeaderValue::from(retry_after_secs));
+        }
+        response
+    }
+}
+
+impl From<ProviderError> for Error {
+    fn from(e: ProviderError) -> Self {
+        match e {
+            ProviderError::Request(e) if e.is_timeout() => Error::Timeout,
+            ProviderError::Unresolved(e) => Error::ProviderUnresolved(e.0),
+            ProviderError::Rejected(message) => Error::ProviderRejected(message),
+            ProviderError::Replay(message) => Error::StorageError(message),
+            e => match e.saturated() {
+                Some(resource) => Error::Saturated {
+                    resource,
+                    retry_after_secs: 1,
+                },
+                None => Error::ProviderUnavailable(e.to_string()),
+            },
+        }
+    }
+}
+
+impl From<CoreError> for Error {
+    fn from(e: CoreError) -> Self {
+        // the library messages are terse, the sources carry the details
+        let message = causes(&e);
+        match e {
+            CoreError::CacheError(_) => Error::CacheError(message),
+            CoreError::LookupError(LookupError::ReqwestError(e)) if e.is_timeout() => {
+                Error::Timeout
+            }
+            CoreError::LookupError(_) => Error::ProviderUnavailable(message),
+            _ => Error::Internal(message),
+        }
+    }
+}
+
+/// `error: source: source...`
+fn causes(e: &dyn std::error::Error) -> String {
+    let mut message = e.to_string();
+    let mut source = e.source();
+    while let Some(e) = source {
+        message.push_str(": ");
+        message.push_str(&e.to_string());
+        source = e.source();
+    }
+    message
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use public_ip_address::error::CacheError;
+
+    #[test]
+    fn test_provider_error() {
+        let error = Error::from(ProviderError::Rejected("private range".into()));
+        assert_eq!(error.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(
+            error.to_string(),
+            "provider rejected the lookup: private range"
+        );
+        let error = Error::from(ProviderError::CircuitOpen);
+        assert_eq!(error.status(), StatusCode::BAD_GATEWAY);
+        assert_eq!(error.to_string(), "provider unavailable: circuit open");
+        let error = Error::from(ProviderError::Overloaded);
+        assert_eq!(error.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let error = Error::from(ProviderError::BudgetExhausted);
+        assert_eq!(error.status(), StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[test]
+    fn test_saturated() {
+        let error = Error::Saturated {
+            resource: Resource::Budget,
+            retry_after_secs: 3600,
+        };
+        assert_eq!(error.to_string(), "out of provider budget, retry in 3600s");
+        let body = serde_json::to_value(error.body()).unwrap();
+        assert_eq!(body["resource"], "budget");
+        assert_eq!(body["retry_after_secs"], 3600);
+        let response = error.into_response();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()[RETRY_AFTER], "3600");
+    }
+
+    #[test]
+    fn test_problem_details() {
+        let response = Error::NotFound("flag x".into()).into_response();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(response.headers()[CONTENT_TYPE], PROBLEM_JSON);
+
+        let body = serde_json::to_value(Error::Timeout.body()).unwrap();
+        assert_eq!(
+            body,
+            serde_json::json!({
+                "type": "about:blank",
+                "title": "Gateway Timeout",
+                "status": 504,
+                "detail": "upstream request timed out",
+                "error": "timeout",
+                "message": "upstream request timed out"
+            })
+        );
+        // bodies without the problem members still parse
+        let body: ErrorBody =
+            serde_json::from_str(r#"{"error": "timeout", "message": "late"}"#).unwrap();
+        assert_eq!((body.kind.as_str(), body.status), ("about:blank", 0));
+    }
+
+    #[test]
+    fn test_core_error() {
+        let io = std::io::Error::new(std::io::ErrorKind::NotFound, "no cache file");
+        let error = Error::from(CoreError::CacheError(CacheError::IOError(io)));
+        assert_eq!(error.code(), "cache_error");
+        assert_eq!(
+            error.to_string(),
+            "cache error: Cache error: IO error: no cache file"
+        );
+    }
+}
@@ -0,0 +1,155 @@
+//! Axum extractors resolving the caller of a request
+//!
+//! ```no_run
+//! use axum::{extract::FromRef, routing::get, Json, Router};
+//! use ip_service::{extract::GeoIp, Lookup, LookupService};
+//! use std::net::SocketAddr;
+//!
+//! #[derive(Clone)]
+//! struct AppState {
+//!     geoip: LookupService,
+//! }
+//!
+//! impl FromRef<AppState> for LookupService {
+//!     fn from_ref(state: &AppState) -> Self {
+//!         state.geoip.clone()
+//!     }
+//! }
+//!
+//! async fn hello(GeoIp(lookup): GeoIp) -> Json<Lookup> {
+//!     Json(lookup)
+//! }
+//!
+//! # async fn run() {
+//! let state = AppState { geoip: LookupService::builder().build() };
+//! let app = Router::new().route("/hello", get(hello)).with_state(state);
+//! let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await.unwrap();
+//! // the peer address is only known with connect info
+//! axum::serve(
+//!     listener,
+//!     app.into_make_service_with_connect_info::<SocketAddr>(),
+//! )
+//! .await
+//! .unwrap();
+//! # }
+//! ```
+
+use crate::{error::Error, service::Lookup, LookupRequest, LookupService};
+use axum::{
+    async_trait,
+    extract::{ConnectInfo, FromRef, FromRequestParts},
+    http::request::Parts,
+};
+use std::net::{IpAddr, SocketAddr};
+
+/// Address of the caller.
+///
+/// Taken from a `ClientIp` request extension when a middleware (e.g. one
+/// trusting a reverse proxy header) has set it, the peer address otherwise.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClientIp(pub IpAddr);
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for ClientIp {
+    type Rejection = Error;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        if let Some(ip) = parts.extensions.get::<ClientIp>() {
+            return Ok(*ip);
+        }
+        let ConnectInfo(addr) = ConnectInfo::<SocketAddr>::from_request_parts(parts, state)
+            .await
+            .map_err(|_| Error::Internal("peer address unknown, serve with connect info".into()))?;
+        Ok(ClientIp(addr.ip()))
+    }
+}
+
+/// Geolocation of the caller, resolved with the [`LookupService`] of the
+/// router state and its cache.
+///
+/// A `Lookup` request extension is reused instead, so a middleware resolving
+/// every request does not cost a second lookup. Use `Option<GeoIp>` when the
+/// handler should run for callers that cannot be resolved, e.g. private
+/// addresses.
+#[derive(Debug, Clone)]
+pub struct GeoIp(pub Lookup);
+
+#[async_trait]
+impl<S> FromRequestParts<S> for GeoIp
+where
+    S: Send + Sync,
+    LookupService: FromRef<S>,
+{
+    type Rejection = Error;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        if let Some(lookup) = parts.extensions.get::<Lookup>() {
+            return Ok(GeoIp(lookup.clone()));
+        }
+        let ClientIp(ip) = ClientIp::from_request_parts(parts, state).await?;
+        let service = LookupService::from_ref(state);
+        let lookup = service.lookup(&LookupRequest::ip(ip.to_string())).await?;
+        parts.extensions.insert(lookup.clone());
+        Ok(GeoIp(lookup))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{ProviderConfig, ProviderKind};
+    use axum::{
+        body::Body,
+        extract::connect_info::MockConnectInfo,
+        http::{Request, StatusCode},
+        routing::get,
+        Router,
+    };
+    use tower::ServiceExt;
+
+    async fn country(GeoIp(lookup): GeoIp) -> String {
+        lookup.geo.country_code.unwrap_or_default()
+    }
+
+    fn app() -> Router {
+        let service = LookupService::builder()
+            .provider(ProviderConfig::new(ProviderKind::Mock))
+            .build();
+        Router::new().route("/", get(country)).with_state(service)
+    }
+
+    fn request() -> Request<Body> {
+        Request::builder().uri("/").body(Body::empty()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_peer_address() {
+        let peer: SocketAddr = "1.1.1.1:4000".parse().unwrap();
+        let app = app().layer(MockConnectInfo(peer));
+        let response = app.oneshot(request()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), 64)
+            .await
+            .unwrap();
+        assert_eq!(&body[..], b"AU");
+    }
+
+    #[tokio::test]
+    async fn test_client_ip_extension() {
+        let mut request = request();
+        request
+            .extensions_mut()
+            .insert(ClientIp("8.8.8.8".parse().unwrap()));
+        let response = app().oneshot(request).await.unwrap();
+        let body = axum::body::to_bytes(response.into_body(), 64)
+            .await
+            .unwrap();
+        assert_eq!(&body[..], b"US");
+    }
+
+    #[tokio::test]
+    async fn test_unknown_peer() {
+        let response = app().oneshot(request()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+}
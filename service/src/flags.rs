@@ -0,0 +1,136 @@
+//! Runtime feature flags
+//!
+//! A flag maps a name to a rollout percentage. Requests are bucketed by a
+//! stable hash of the flag name and a subject (the looked up address), so an
+//! address keeps the same behavior while the rollout grows. Flags start from
+//! the `[flags]` configuration and can be changed at runtime through
+//! `/admin/flags`; changes are not persisted.
+//!
+//! Known flags, all of them fully enabled when not set:
+//! - `risk_scoring`: compute the composite risk score
+//! - `provider.<name>`: route lookups to the named provider
+
+use crate::{config::Rollout, sampling::stable_hash};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::RwLock,
+};
+use utoipa::ToSchema;
+
+pub const RISK_SCORING: &str = "risk_scoring";
+
+/// Flag gating a provider.
+pub fn provider_flag(name: &str) -> String {
+    format!("provider.{name}")
+}
+
+#[derive(Serialize, ToSchema, Debug, Clone, PartialEq)]
+pub struct Flag {
+    pub name: String,
+    /// Share of the traffic the flag is enabled for, 0 to 100.
+    pub rollout: u8,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct FlagUpdate {
+    /// Share of the traffic to enable the flag for, 0 to 100.
+    pub rollout: u8,
+}
+
+pub struct Flags {
+    rollouts: RwLock<BTreeMap<String, u8>>,
+}
+
+impl Flags {
+    pub fn new(config: HashMap<String, Rollout>) -> Self {
+        let rollouts = config
+            .into_iter()
+            .map(|(name, rollout)| (name, rollout.percent()))
+            .collect();
+        Flags {
+            rollouts: RwLock::new(rollouts),
+        }
+    }
+
+    /// Whether `name` is enabled for `subject`, flags that are not set are enabled.
+    pub fn enabled(&self, name: &str, subject: &str) -> bool {
+        let Some(&rollout) = self.rollouts.read().unwrap().get(name) else {
+            return true;
+        };
+        bucket(name, subject) < rollout
+    }
+
+    pub fn list(&self) -> Vec<Flag> {
+        self.rollouts
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(name, &rollout)| Flag {
+                name: name.clone(),
+                rollout,
+            })
+            .collect()
+    }
+
+    pub fn set(&self, name: &str, rollout: u8) -> Flag {
+        let rollout = rollout.min(100);
+        self.rollouts
+            .write()
+            .unwrap()
+            .insert(name.to_string(), rollout);
+        Flag {
+            name: name.to_string(),
+            rollout,
+        }
+    }
+
+    /// Removes the flag, it is then enabled everywhere.
+    pub fn remove(&self, name: &str) -> bool {
+        self.rollouts.write().unwrap().remove(name).is_some()
+    }
+}
+
+/// Stable bucket from 0 to 99.
+fn bucket(name: &str, subject: &str) -> u8 {
+    let key = format!("{name}:{subject}");
+    (stable_hash(key.as_bytes()) % 100) as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn flags(rollout: Rollout) -> Flags {
+        Flags::new(HashMap::from([(RISK_SCORING.to_string(), rollout)]))
+    }
+
+    #[test]
+    fn test_unset_enabled() {
+        assert!(flags(Rollout::Enabled(false)).enabled("provider.ipinfo", "1.1.1.1"));
+    }
+
+    #[test]
+    fn test_rollout() {
+        assert!(!flags(Rollout::Enabled(false)).enabled(RISK_SCORING, "1.1.1.1"));
+        assert!(flags(Rollout::Percent(100)).enabled(RISK_SCORING, "1.1.1.1"));
+
+        let flags = flags(Rollout::Percent(30));
+        let enabled = (0..1000)
+            .filter(|i| flags.enabled(RISK_SCORING, &format!("10.0.{}.{}", i / 256, i % 256)))
+            .count();
+        assert!((200..400).contains(&enabled), "got {enabled}");
+        // stable for a subject
+        let first = flags.enabled(RISK_SCORING, "8.8.8.8");
+        assert!((0..10).all(|_| flags.enabled(RISK_SCORING, "8.8.8.8") == first));
+    }
+
+    #[test]
+    fn test_update() {
+        let flags = flags(Rollout::Percent(0));
+        flags.set(RISK_SCORING, 250);
+        assert_eq!(flags.list()[0].rollout, 100);
+        assert!(flags.remove(RISK_SCORING));
+        assert!(flags.list().is_empty());
+    }
+}
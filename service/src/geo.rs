@@ -0,0 +1,384 @@
+//! Normalized geolocation schema shared by every provider
+
+use crate::carrier::Carrier;
+use public_ip_address::response::LookupResponse as CoreResponse;
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+use utoipa::ToSchema;
+
+const EARTH_RADIUS_KM: f64 = 6371.0;
+
+/// Provider independent view of a lookup result.
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone, PartialEq)]
+pub struct Geo {
+    #[schema(value_type = String)]
+    pub ip: IpAddr,
+    pub continent: Option<String>,
+    pub continent_code: Option<String>,
+    pub country: Option<String>,
+    /// ISO 3166-1 alpha-2 country code.
+    pub country_code: Option<String>,
+    /// European Union member, outermost regions included.
+    pub is_eu: Option<bool>,
+    /// European Economic Area: the EU, Iceland, Liechtenstein and Norway.
+    pub is_eea: Option<bool>,
+    pub is_uk: Option<bool>,
+    /// The EU GDPR (EEA) or the UK GDPR applies.
+    pub gdpr: Option<bool>,
+    pub region: Option<String>,
+    pub region_code: Option<String>,
+    pub city: Option<String>,
+    pub postal_code: Option<String>,
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+    /// IANA time zone name.
+    pub timezone: Option<String>,
+    /// Current offset from UTC in seconds, daylight saving included.
+    pub utc_offset: Option<i32>,
+    /// ISO 4217 code of the national currency.
+    pub currency: Option<String>,
+    /// E.164 country calling code, e.g. `+49`.
+    pub calling_code: Option<String>,
+    /// Autonomous system number.
+    pub asn: Option<u32>,
+    /// Autonomous system name.
+    pub as_name: Option<String>,
+    pub isp: Option<String>,
+    pub org: Option<String>,
+    pub hostname: Option<String>,
+    /// Set for addresses of a mobile network.
+    pub carrier: Option<Carrier>,
+    /// Provider that answered the lookup.
+    pub provider: String,
+}
+
+/// Threat intelligence supplied by the lookup provider itself.
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone, PartialEq, Default)]
+pub struct Threat {
+    pub is_tor: bool,
+    pub is_datacenter: bool,
+    pub is_anonymous: bool,
+    pub is_known_attacker: bool,
+    pub is_known_abuser: bool,
+    /// Provider's overall verdict.
+    pub is_threat: bool,
+    pub is_bogon: bool,
+    /// Names of the provider blocklists the address is listed on.
+    pub blocklists: Vec<String>,
+}
+
+/// Field groups of a lookup.
+///
+/// A lookup can ask for a subset, it is then routed to the cheapest provider
+/// supplying all of them and the other `geo` fields are left out. `anonymity`
+/// and `threat` select providers reporting those blocks.
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Field {
+    /// `continent` and `continent_code`
+    Continent,
+    /// `country`, `country_code` and the EU, EEA and UK flags
+    Country,
+    /// `region` and `region_code`
+    Region,
+    City,
+    PostalCode,
+    /// `latitude` and `longitude`
+    Location,
+    Timezone,
+    UtcOffset,
+    Currency,
+    CallingCode,
+    /// `asn` and `as_name`
+    Asn,
+    Isp,
+    Org,
+    Hostname,
+    /// Mobile `carrier` with its MCC and MNC
+    Carrier,
+    /// Proxy, VPN or hosting flags reported by the provider.
+    Anonymity,
+    Threat,
+}
+
+impl Field {
+    /// Fields a provider has to supply for `fields`: the currency and calling
+    /// code come from the country, the UTC offset from the time zone.
+    pub fn sources(fields: &[Field]) -> Vec<Field> {
+        let mut sources = Vec::with_capacity(fields.len());
+        for field in fields {
+            let source = match field {
+                Field::Currency | Field::CallingCode => Field::Country,
+                Field::UtcOffset => Field::Timezone,
+                field => *field,
+            };
+            if !sources.contains(&source) {
+                sources.push(source);
+            }
+        }
+        sources
+    }
+}
+
+/// Fields a merge takes from one answer each, the derived ones follow their
+/// source: the currency and calling code the country, the UTC offset the
+/// time zone.
+pub const MERGED: &[Field] = &[
+    Field::Continent,
+    Field::Country,
+    Field::Region,
+    Field::City,
+    Field::PostalCode,
+    Field::Location,
+    Field::Timezone,
+    Field::Asn,
+    Field::Isp,
+    Field::Org,
+    Field::Hostname,
+    Field::Carrier,
+];
+
+impl Geo {
+    pub fn new(ip: IpAddr, provider: &str) -> Self {
+        Geo {
+            ip,
+            continent: None,
+            continent_code: None,
+            country: None,
+            country_code: None,
+            is_eu: None,
+            is_eea: None,
+            is_uk: None,
+            gdpr: None,
+            region: None,
+            region_code: None,
+            city: None,
+            postal_code: None,
+            latitude: None,
+            longitude: None,
+            timezone: None,
+            utc_offset: None,
+            currency: None,
+            calling_code: None,
+            asn: None,
+            as_name: None,
+            isp: None,
+            org: None,
+            hostname: None,
+            carrier: None,
+            provider: provider.to_string(),
+        }
+    }
+
+    /// Great-circle distance, `None` unless both sides have coordinates.
+    pub fn distance_km(&self, other: &Geo) -> Option<f64> {
+        Some(distance_km(
+            (self.latitude?, self.longitude?),
+            (other.latitude?, other.longitude?),
+        ))
+    }
+
+    /// Keeps only the requested fields, `ip` and `provider` are always kept.
+    pub fn retain(&mut self, fields: &[Field]) {
+        let keep = |field: Field| fields.contains(&field);
+        if !keep(Field::Continent) {
+            self.continent = None;
+            self.continent_code = None;
+        }
+        if !keep(Field::Country) {
+            self.country = None;
+            self.country_code = None;
+            self.is_eu = None;
+            self.is_eea = None;
+            self.is_uk = None;
+            self.gdpr = None;
+        }
+        if !keep(Field::Region) {
+            self.region = None;
+            self.region_code = None;
+        }
+        if !keep(Field::City) {
+            self.city = None;
+        }
+        if !keep(Field::PostalCode) {
+            self.postal_code = None;
+        }
+        if !keep(Field::Location) {
+            self.latitude = None;
+            self.longitude = None;
+        }
+        if !keep(Field::Timezone) {
+            self.timezone = None;
+        }
+        if !keep(Field::UtcOffset) {
+            self.utc_offset = None;
+        }
+        if !keep(Field::Currency) {
+            self.currency = None;
+        }
+        if !keep(Field::CallingCode) {
+            self.calling_code = None;
+        }
+        if !keep(Field::Asn) {
+            self.asn = None;
+            self.as_name = None;
+        }
+        if !keep(Field::Isp) {
+            self.isp = None;
+        }
+        if !keep(Field::Org) {
+            self.org = None;
+        }
+        if !keep(Field::Hostname) {
+            self.hostname = None;
+        }
+        if !keep(Field::Carrier) {
+            self.carrier = None;
+        }
+    }
+
+    /// Value of `field` comparable across providers, absent when missing.
+    pub fn key(&self, field: Field) -> Option<String> {
+        let lower = |value: &Option<String>| value.as_deref().map(str::to_lowercase);
+        match field {
+            Field::Continent => lower(&self.continent_code).or_else(|| lower(&self.continent)),
+            Field::Country | Field::Currency | Field::CallingCode => lower(&self.country_code),
+            Field::Region => lower(&self.region_code).or_else(|| lower(&self.region)),
+            Field::City => lower(&self.city),
+            Field::PostalCode => lower(&self.postal_code),
+            // about 11 km, closer answers agree
+            Field::Location => Some(format!("{:.1},{:.1}", self.latitude?, self.longitude?)),
+            Field::Timezone | Field::UtcOffset => lower(&self.timezone),
+            Field::Asn => self.asn.map(|asn| asn.to_string()),
+            Field::Isp => lower(&self.isp),
+            Field::Org => lower(&self.org),
+            Field::Hostname => lower(&self.hostname),
+            Field::Carrier => self.carrier.as_ref().and_then(|c| lower(&c.name)),
+            Field::Anonymity | Field::Threat => None,
+        }
+    }
+
+    /// Replaces `field` and the fields derived from it with the ones of `other`.
+    pub fn copy_field(&mut self, other: &Geo, field: Field) {
+        match field {
+            Field::Continent => {
+                self.continent = other.continent.clone();
+                self.continent_code = other.continent_code.clone();
+            }
+            Field::Country | Field::Currency | Field::CallingCode => {
+                self.country = other.country.clone();
+                self.country_code = other.country_code.clone();
+                self.is_eu = other.is_eu;
+                self.is_eea = other.is_eea;
+                self.is_uk = other.is_uk;
+                self.gdpr = other.gdpr;
+                self.currency = other.currency.clone();
+                self.calling_code = other.calling_code.clone();
+            }
+            Field::Region => {
+                self.region = other.region.clone();
+                self.region_code = other.region_code.clone();
+            }
+            Field::City => self.city = other.city.clone(),
+            Field::PostalCode => self.postal_code = other.postal_code.clone(),
+            Field::Location => {
+                self.latitude = other.latitude;
+                self.longitude = other.longitude;
+            }
+            Field::Timezone | Field::UtcOffset => {
+                self.timezone = other.timezone.clone();
+                self.utc_offset = other.utc_offset;
+            }
+            Field::Asn => {
+                self.asn = other.asn;
+                self.as_name = other.as_name.clone();
+            }
+            Field::Isp => self.isp = other.isp.clone(),
+            Field::Org => self.org = other.org.clone(),
+            Field::Hostname => self.hostname = other.hostname.clone(),
+            Field::Carrier => self.carrier = other.carrier.clone(),
+            Field::Anonymity | Field::Threat => {}
+        }
+    }
+}
+
+impl From<CoreResponse> for Geo {
+    fn from(response: CoreResponse) -> Self {
+        let mut geo = Geo::new(response.ip, &response.provider.to_string());
+        geo.continent = response.continent;
+        geo.country = response.country;
+        geo.country_code = response.country_code;
+        geo.region = response.region;
+        geo.city = response.city;
+        geo.postal_code = response.postal_code;
+        geo.latitude = response.latitude;
+        geo.longitude = response.longitude;
+        geo.timezone = response.time_zone;
+        geo.asn = response.asn.as_deref().and_then(parse_asn);
+        geo.as_name = response.asn_org;
+        geo.hostname = response.hostname;
+        geo
+    }
+}
+
+/// Great-circle distance between two `(latitude, longitude)` points.
+pub fn distance_km(from: (f64, f64), to: (f64, f64)) -> f64 {
+    let (lat1, lon1) = (from.0.to_radians(), from.1.to_radians());
+    let (lat2, lon2) = (to.0.to_radians(), to.1.to_radians());
+    let h = ((lat2 - lat1) / 2.0).sin().powi(2)
+        + lat1.cos() * lat2.cos() * ((lon2 - lon1) / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS_KM * h.sqrt().asin()
+}
+
+/// Extracts the AS number from strings like `AS15169 Google LLC` or `15169`.
+pub fn parse_asn(value: &str) -> Option<u32> {
+    let value = value.trim();
+    let value = value
+        .strip_prefix("AS")
+        .or_else(|| value.strip_prefix("as"))
+        .unwrap_or(value);
+    value.split_whitespace().next()?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_retain() {
+        let mut geo = Geo::new("1.2.3.4".parse().unwrap(), "test");
+        geo.country_code = Some("DE".into());
+        geo.city = Some("Berlin".into());
+        geo.retain(&[Field::Country]);
+        assert_eq!(geo.country_code.as_deref(), Some("DE"));
+        assert_eq!(geo.city, None);
+    }
+
+    #[test]
+    fn test_sources() {
+        let fields = [Field::CallingCode, Field::Country, Field::UtcOffset];
+        assert_eq!(Field::sources(&fields), [Field::Country, Field::Timezone]);
+    }
+
+    #[test]
+    fn test_distance() {
+        let mut berlin = Geo::new("1.2.3.4".parse().unwrap(), "test");
+        berlin.latitude = Some(52.52);
+        berlin.longitude = Some(13.405);
+        let mut paris = berlin.clone();
+        paris.latitude = Some(48.8566);
+        paris.longitude = Some(2.3522);
+        let distance = berlin.distance_km(&paris).unwrap();
+        assert!((distance - 878.0).abs() < 5.0, "got {distance}");
+        paris.latitude = None;
+        assert_eq!(berlin.distance_km(&paris), None);
+    }
+
+    #[test]
+    fn test_parse_asn() {
+        assert_eq!(parse_asn("AS15169 Google LLC"), Some(15169));
+        assert_eq!(parse_asn("24940"), Some(24940));
+        assert_eq!(parse_asn("Google LLC"), None);
+        assert_eq!(parse_asn(""), None);
+    }
+}
@@ -0,0 +1,263 @@
+//! <https://www.greynoise.io> internet background noise enrichment
+//!
+//! Tells apart mass scanners and common business services seen by GreyNoise
+//! from addresses that were never observed scanning, i.e. likely targeted traffic.
+//! Both the free community API and the enterprise context API are supported.
+
+use crate::{
+    anonymity::Signals,
+    cache::TtlCache,
+    config::{GreyNoiseConfig, GreyNoiseEdition},
+};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use std::{net::IpAddr, time::Duration};
+use tracing::warn;
+use utoipa::ToSchema;
+
+const COMMUNITY_ENDPOINT: &str = "https://api.greynoise.io/v3/community";
+const CONTEXT_ENDPOINT: &str = "https://api.greynoise.io/v2/noise/context";
+
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum NoiseVerdict {
+    /// Opportunistic internet-wide scanning.
+    BackgroundNoise,
+    /// Known business service (GreyNoise RIOT data set), e.g. CDNs or DNS resolvers.
+    CommonService,
+    /// Never seen scanning the internet, traffic from it is likely targeted.
+    Targeted,
+}
+
+/// GreyNoise classification of an address.
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone, PartialEq)]
+pub struct Noise {
+    pub verdict: NoiseVerdict,
+    /// Observed scanning the internet.
+    pub noise: bool,
+    /// Part of the RIOT (common business services) data set.
+    pub riot: bool,
+    /// `benign`, `malicious` or `unknown`.
+    pub classification: Option<String>,
+    /// Actor or service name.
+    pub name: Option<String>,
+    pub last_seen: Option<String>,
+    /// Scanner tags, enterprise edition only.
+    pub tags: Vec<String>,
+    /// VPN service name, enterprise edition only.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub vpn_service: Option<String>,
+}
+
+/// <https://docs.greynoise.io/reference/get_v3-community-ip>
+#[derive(Deserialize, Debug)]
+struct CommunityResponse {
+    #[serde(default)]
+    noise: bool,
+    #[serde(default)]
+    riot: bool,
+    classification: Option<String>,
+    name: Option<String>,
+    last_seen: Option<String>,
+}
+
+/// <https://docs.greynoise.io/reference/noisecontextip-1>
+#[derive(Deserialize, Debug)]
+struct ContextResponse {
+    #[serde(default)]
+    seen: bool,
+    classification: Option<String>,
+    actor: Option<String>,
+    last_seen: Option<String>,
+    #[serde(default)]
+    tags: Vec<String>,
+    #[serde(default)]
+    vpn: bool,
+    vpn_service: Option<String>,
+}
+
+impl From<CommunityResponse> for Noise {
+    fn from(response: CommunityResponse) -> Self {
+        Noise {
+            verdict: verdict(response.noise, response.riot),
+            noise: response.noise,
+            riot: response.riot,
+            classification: response.classification,
+            name: response.name,
+            last_seen: response.last_seen,
+            tags: Vec::new(),
+            vpn_service: None,
+        }
+    }
+}
+
+impl From<ContextResponse> for Noise {
+    fn from(response: ContextResponse) -> Self {
+        Noise {
+            verdict: verdict(response.seen, false),
+            noise: response.seen,
+            riot: false,
+            classification: response.classification,
+            name: response.actor,
+            last_seen: response.last_seen,
+            tags: response.tags,
+            vpn_service: response
+                .vpn
+                .then(|| response.vpn_service.unwrap_or_default()),
+        }
+    }
+}
+
+fn verdict(noise: bool, riot: bool) -> NoiseVerdict {
+    match (noise, riot) {
+        (true, _) => NoiseVerdict::BackgroundNoise,
+        (false, true) => NoiseVerdict::CommonService,
+        (false, false) => NoiseVerdict::Targeted,
+    }
+}
+
+impl Noise {
+    /// Anonymity signals that can be derived from the classification.
+    pub fn signals(&self) -> Signals {
+        Signals {
+            vpn: self.vpn_service.as_ref().map(|_| true),
+            relay: self
+                .tags
+                .iter()
+                .any(|tag| tag.to_lowercase().contains("tor"))
+                .then_some(true),
+            ..Default::default()
+        }
+    }
+}
+
+pub struct GreyNoise {
+    http: reqwest::Client,
+    config: GreyNoiseConfig,
+    cache: TtlCache<Noise>,
+}
+
+impl GreyNoise {
+    pub fn new(http: reqwest::Client, config: GreyNoiseConfig) -> Self {
+        let cache = TtlCache::new(
+            Duration::from_secs(config.cache_ttl_secs),
+            config.cache_capacity,
+        );
+        GreyNoise {
+            http,
+            config,
+            cache,
+        }
+    }
+
+    /// Cached answer for `ip`, without a request.
+    pub fn cached(&self, ip: IpAddr) -> Option<Noise> {
+        self.cache.get(&ip)
+    }
+
+    /// Returns the classification of `ip`, `None` if the API could not be reached.
+    pub async fn check(&self, ip: IpAddr) -> Option<Noise> {
+        if let Some(noise) = self.cache.get(&ip) {
+            return Some(noise);
+        }
+        match self.request(ip).await {
+            Ok(noise) => {
+                self.cache.insert(ip, noise.clone());
+                Some(noise)
+            }
+            Err(e) => {
+                warn!("greynoise check failed ip={} error={}", ip, e);
+                None
+            }
+        }
+    }
+
+    async fn request(&self, ip: IpAddr) -> reqwest::Result<Noise> {
+        let endpoint = match self.config.edition {
+            GreyNoiseEdition::Community => COMMUNITY_ENDPOINT,
+            GreyNoiseEdition::Enterprise => CONTEXT_ENDPOINT,
+        };
+        let mut request = self
+            .http
+            .get(format!("{endpoint}/{ip}"))
+            .header("Accept", "application/json")
+            .timeout(Duration::from_millis(self.config.timeout_ms));
+        if let Some(key) = &self.config.api_key {
+            request = request.header("key", key);
+        }
+        let response = request.send().await?;
+        // the community API answers unknown addresses with 404 and a regular body
+        let response = match response.status() {
+            StatusCode::NOT_FOUND => response,
+            _ => response.error_for_status()?,
+        };
+        Ok(match self.config.edition {
+            GreyNoiseEdition::Community => response.json::<CommunityResponse>().await?.into(),
+            GreyNoiseEdition::Enterprise => response.json::<ContextResponse>().await?.into(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const COMMUNITY_INPUT: &str = r#"
+{
+  "ip": "71.6.135.131",
+  "noise": true,
+  "riot": false,
+  "classification": "benign",
+  "name": "Shodan.io",
+  "link": "https://viz.greynoise.io/ip/71.6.135.131",
+  "last_seen": "2024-01-01",
+  "message": "Success"
+}
+"#;
+
+    const NOT_FOUND_INPUT: &str = r#"
+{
+  "ip": "1.2.3.4",
+  "noise": false,
+  "riot": false,
+  "message": "IP not observed scanning the internet or contained in RIOT data set."
+}
+"#;
+
+    const CONTEXT_INPUT: &str = r#"
+{
+  "ip": "185.220.101.1",
+  "seen": true,
+  "classification": "malicious",
+  "first_seen": "2023-01-01",
+  "last_seen": "2024-01-01",
+  "actor": "unknown",
+  "tags": ["Tor", "SSH Bruteforcer"],
+  "vpn": true,
+  "vpn_service": "EXAMPLE_VPN",
+  "metadata": {}
+}
+"#;
+
+    #[test]
+    fn test_parse_community() {
+        let response: CommunityResponse = serde_json::from_str(COMMUNITY_INPUT).unwrap();
+        let noise = Noise::from(response);
+        assert_eq!(noise.verdict, NoiseVerdict::BackgroundNoise);
+        assert_eq!(noise.name.as_deref(), Some("Shodan.io"));
+
+        let response: CommunityResponse = serde_json::from_str(NOT_FOUND_INPUT).unwrap();
+        assert_eq!(Noise::from(response).verdict, NoiseVerdict::Targeted);
+    }
+
+    #[test]
+    fn test_parse_context() {
+        let response: ContextResponse = serde_json::from_str(CONTEXT_INPUT).unwrap();
+        let noise = Noise::from(response);
+        assert_eq!(noise.verdict, NoiseVerdict::BackgroundNoise);
+        assert_eq!(noise.tags.len(), 2);
+        let signals = noise.signals();
+        assert_eq!(signals.vpn, Some(true));
+        assert_eq!(signals.relay, Some(true));
+    }
+}
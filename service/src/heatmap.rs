@@ -0,0 +1,215 @@
+//! Lookup counts by location for map widgets
+//!
+//! The successful API lookups of the last day in one-hour buckets, counted by
+//! country and by one-degree grid cell of the coordinates. Held in memory only
+//! and served by `/v1/reports/heatmap`.
+
+use crate::{cidr::CountryCount, error::Error, service::Lookup};
+use serde::Serialize;
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use utoipa::ToSchema;
+
+/// Hours of lookups kept.
+pub const WINDOW_HOURS: u64 = 24;
+
+#[derive(Debug, Default)]
+struct Hour {
+    hour: u64,
+    /// Count and name by country code.
+    countries: HashMap<String, (u64, Option<String>)>,
+    /// Keyed by the floored latitude and longitude.
+    cells: HashMap<(i16, i16), u64>,
+}
+
+/// One-degree cell of the grid.
+#[derive(Serialize, ToSchema, Debug, Clone, PartialEq)]
+pub struct CellCount {
+    /// Latitude of the center of the cell.
+    pub lat: f64,
+    /// Longitude of the center of the cell.
+    pub lon: f64,
+    pub count: u64,
+}
+
+#[derive(Serialize, ToSchema, Debug, Clone, PartialEq)]
+pub struct HeatmapData {
+    pub window_hours: u64,
+    /// Lookups with a country over the window.
+    pub total: u64,
+    /// Most lookups first.
+    pub countries: Vec<CountryCount>,
+    /// Cells with lookups, absent unless asked for.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cells: Option<Vec<CellCount>>,
+}
+
+#[derive(Debug, Default)]
+pub struct Heatmap {
+    hours: Mutex<VecDeque<Hour>>,
+}
+
+impl Heatmap {
+    /// Counts the location of a successful lookup.
+    pub fn lookup(&self, result: &Result<Lookup, Error>) {
+        if let Ok(lookup) = result {
+            let geo = &lookup.geo;
+            let cell = geo.latitude.zip(geo.longitude);
+            let country = geo
+                .country_code
+                .as_deref()
+                .map(|code| (code, geo.country.as_deref()));
+            self.lookup_at(now(), country, cell);
+        }
+    }
+
+    /// Counts over the last `window_hours`, the grid cells too with `grid`.
+    pub fn data(&self, window_hours: u64, grid: bool) -> HeatmapData {
+        self.data_at(now(), window_hours, grid)
+    }
+
+    fn lookup_at(
+        &self,
+        now: u64,
+        country: Option<(&str, Option<&str>)>,
+        coordinates: Option<(f64, f64)>,
+    ) {
+        let hour = now / 3600;
+        let mut hours = self.hours.lock().unwrap();
+        expire(&mut hours, hour);
+        if hours.back().is_none_or(|h| h.hour < hour) {
+            hours.push_back(Hour {
+                hour,
+                ..Default::default()
+            });
+        }
+        // a clock stepping back counts into the latest hour
+        let bucket = hours.back_mut().expect("pushed");
+        if let Some((code, name)) = country {
+            let entry = bucket
+                .countries
+                .entry(code.to_ascii_uppercase())
+                .or_default();
+            entry.0 += 1;
+            if let Some(name) = name {
+                entry.1 = Some(name.to_string());
+            }
+        }
+        if let Some((lat, lon)) = coordinates {
+            if (-90.0..=90.0).contains(&lat) && (-180.0..=180.0).contains(&lon) {
+                let cell = (lat.floor().min(89.0) as i16, lon.floor().min(179.0) as i16);
+                *bucket.cells.entry(cell).or_default() += 1;
+            }
+        }
+    }
+
+    fn data_at(&self, now: u64, window_hours: u64, grid: bool) -> HeatmapData {
+        let window_hours = window_hours.clamp(1, WINDOW_HOURS);
+        let hour = now / 3600;
+        let mut hours = self.hours.lock().unwrap();
+        expire(&mut hours, hour);
+        let mut countries: HashMap<&str, (u64, Option<&str>)> = HashMap::new();
+        let mut cells: HashMap<(i16, i16), u64> = HashMap::new();
+        for h in hours.iter().filter(|h| h.hour + window_hours > hour) {
+            for (code, (count, name)) in &h.countries {
+                let entry = countries.entry(code).or_default();
+                entry.0 += count;
+                entry.1 = name.as_deref().or(entry.1);
+            }
+            if grid {
+                for (cell, count) in &h.cells {
+                    *cells.entry(*cell).or_default() += count;
+                }
+            }
+        }
+        let mut countries: Vec<CountryCount> = countries
+            .into_iter()
+            .map(|(code, (count, name))| CountryCount {
+                country_code: code.to_string(),
+                country: name.map(Into::into),
+                count: count as usize,
+            })
+            .collect();
+        countries.sort_by(|a, b| {
+            b.count
+                .cmp(&a.count)
+                .then(a.country_code.cmp(&b.country_code))
+        });
+        let mut cells: Vec<CellCount> = cells
+            .into_iter()
+            .map(|((lat, lon), count)| CellCount {
+                lat: lat as f64 + 0.5,
+                lon: lon as f64 + 0.5,
+                count,
+            })
+            .collect();
+        cells.sort_by(|a, b| {
+            b.count
+                .cmp(&a.count)
+                .then(a.lat.total_cmp(&b.lat))
+                .then(a.lon.total_cmp(&b.lon))
+        });
+        HeatmapData {
+            window_hours,
+            total: countries.iter().map(|c| c.count as u64).sum(),
+            countries,
+            cells: grid.then_some(cells),
+        }
+    }
+}
+
+fn expire(hours: &mut VecDeque<Hour>, hour: u64) {
+    while hours.front().is_some_and(|h| h.hour + WINDOW_HOURS <= hour) {
+        hours.pop_front();
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_data() {
+        let heatmap = Heatmap::default();
+        let berlin = Some((52.52, 13.40));
+        heatmap.lookup_at(3600, Some(("de", None)), berlin);
+        heatmap.lookup_at(7200, Some(("DE", Some("Germany"))), berlin);
+        heatmap.lookup_at(7200, Some(("US", None)), Some((37.4, -122.1)));
+        heatmap.lookup_at(7200, None, Some((91.0, 0.0)));
+
+        let data = heatmap.data_at(7300, 24, false);
+        assert_eq!(data.total, 3);
+        assert_eq!(
+            data.countries[0],
+            CountryCount {
+                country_code: "DE".into(),
+                country: Some("Germany".into()),
+                count: 2
+            }
+        );
+        assert!(data.cells.is_none());
+
+        let data = heatmap.data_at(7300, 1, true);
+        assert_eq!(data.total, 2, "Only the last hour");
+        let cells = data.cells.unwrap();
+        assert_eq!(cells.len(), 2, "Coordinates out of range left out");
+        assert!(cells.contains(&CellCount {
+            lat: 37.5,
+            lon: -122.5,
+            count: 1
+        }));
+
+        assert_eq!(heatmap.data_at(3600 * 25, 24, true).total, 2);
+        assert_eq!(heatmap.data_at(3600 * 26, 24, true).total, 0);
+    }
+}
@@ -0,0 +1,417 @@
+//! Lookup history and its queries
+//!
+//! With a `[history]` section the successful API lookups are kept in memory,
+//! up to `max_entries` and for `retention_secs`: when, which address, which
+//! provider answered, the country and the ASN. `POST /v1/query` filters them
+//! and counts them per group, e.g. the distinct ASNs per day of the last
+//! week, without exporting anything. A query is a fixed set of filters,
+//! groupings and one distinct count rather than SQL, so no statement can
+//! reach past the history.
+
+use crate::{config::HistoryConfig, error::Error, service::Lookup};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::{
+    collections::{BTreeSet, HashMap, VecDeque},
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use utoipa::ToSchema;
+
+#[derive(Debug, Clone)]
+struct Entry {
+    /// Seconds since the epoch.
+    at: u64,
+    ip: String,
+    provider: String,
+    country_code: Option<String>,
+    asn: Option<u32>,
+    cached: bool,
+    degraded: bool,
+}
+
+/// Column a query groups by or counts the distinct values of.
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum Column {
+    /// UTC day of the lookup, e.g. `2026-10-14`.
+    Day,
+    /// UTC hour of the lookup, e.g. `2026-10-14T09:00Z`.
+    Hour,
+    Ip,
+    Provider,
+    CountryCode,
+    Asn,
+    Cached,
+    Degraded,
+}
+
+impl Column {
+    fn name(&self) -> &'static str {
+        match self {
+            Column::Day => "day",
+            Column::Hour => "hour",
+            Column::Ip => "ip",
+            Column::Provider => "provider",
+            Column::CountryCode => "country_code",
+            Column::Asn => "asn",
+            Column::Cached => "cached",
+            Column::Degraded => "degraded",
+        }
+    }
+
+    fn value(&self, entry: &Entry) -> Value {
+        let time = |format| {
+            DateTime::<Utc>::from_timestamp(entry.at as i64, 0)
+                .map(|time| time.format(format).to_string())
+                .into()
+        };
+        match self {
+            Column::Day => time("%Y-%m-%d"),
+            Column::Hour => time("%Y-%m-%dT%H:00Z"),
+            Column::Ip => entry.ip.clone().into(),
+            Column::Provider => entry.provider.clone().into(),
+            Column::CountryCode => entry.country_code.clone().into(),
+            Column::Asn => entry.asn.into(),
+            Column::Cached => entry.cached.into(),
+            Column::Degraded => entry.degraded.into(),
+        }
+    }
+}
+
+/// Lookups a query counts, every given value has to match.
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Filter {
+    #[schema(example = "US")]
+    pub country_code: Option<String>,
+    pub asn: Option<u32>,
+    pub provider: Option<String>,
+    pub cached: Option<bool>,
+    pub degraded: Option<bool>,
+}
+
+impl Filter {
+    fn matches(&self, entry: &Entry) -> bool {
+        let country = |code: &String| {
+            entry
+                .country_code
+                .as_ref()
+                .is_some_and(|other| other.eq_ignore_ascii_case(code))
+        };
+        self.country_code.as_ref().is_none_or(country)
+            && self.asn.is_none_or(|asn| entry.asn == Some(asn))
+            && self.provider.as_ref().is_none_or(|p| *p == entry.provider)
+            && self.cached.is_none_or(|cached| entry.cached == cached)
+            && self.degraded.is_none_or(|degraded| entry.degraded == degraded)
+    }
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct HistoryQuery {
+    /// RFC 3339 time of the oldest lookups counted, all the history by default.
+    #[schema(example = "2026-10-07T00:00:00Z")]
+    pub since: Option<String>,
+    /// RFC 3339 time the lookups counted are before, now by default.
+    pub until: Option<String>,
+    pub filter: Filter,
+    /// One row per combination of these columns, a single row when empty.
+    #[schema(example = json!(["day"]))]
+    pub group_by: Vec<Column>,
+    /// Counts the distinct values of this column per row too.
+    #[schema(example = "asn")]
+    pub distinct: Option<Column>,
+    /// Rows returned, the most lookups first, the configured `max_rows` at most.
+    pub limit: Option<usize>,
+}
+
+/// Format of the rows of a query.
+#[derive(Deserialize, ToSchema, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum HistoryFormat {
+    #[default]
+    Json,
+    /// The rows only, with a header.
+    Csv,
+}
+
+#[derive(Serialize, ToSchema, Debug, Clone, PartialEq)]
+pub struct HistoryResult {
+    /// Lookups matching the query, all rows together.
+    pub lookups: u64,
+    /// Rows left out over the limit.
+    pub truncated: usize,
+    /// The `group_by` columns, then `lookups` and `distinct_<column>`.
+    #[schema(value_type = Vec<Object>)]
+    pub rows: Vec<Map<String, Value>>,
+}
+
+pub struct History {
+    config: HistoryConfig,
+    entries: Mutex<VecDeque<Entry>>,
+}
+
+impl History {
+    pub fn new(config: HistoryConfig) -> Self {
+        History {
+            config,
+            entries: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// Keeps a successful lookup.
+    pub fn lookup(&self, result: &Result<Lookup, Error>) {
+        if let Ok(lookup) = result {
+            let entry = Entry {
+                at: now(),
+                ip: lookup.ip.clone(),
+                provider: lookup.geo.provider.clone(),
+                country_code: lookup.geo.country_code.clone(),
+                asn: lookup.geo.asn,
+                cached: lookup.cached,
+                degraded: lookup.degraded,
+            };
+            self.push(entry);
+        }
+    }
+
+    fn push(&self, entry: Entry) {
+        let mut entries = self.entries.lock().unwrap();
+        self.expire(&mut entries, entry.at);
+        if entries.len() >= self.config.max_entries {
+            entries.pop_front();
+        }
+        entries.push_back(entry);
+    }
+
+    fn expire(&self, entries: &mut VecDeque<Entry>, now: u64) {
+        let oldest = now.saturating_sub(self.config.retention_secs);
+        while entries.front().is_some_and(|entry| entry.at < oldest) {
+            entries.pop_front();
+        }
+    }
+
+    pub fn query(&self, query: &HistoryQuery) -> Result<HistoryResult, Error> {
+        let time = |time: &Option<String>, name: &str| {
+            time.as_deref()
+                .map(|time| {
+                    DateTime::parse_from_rfc3339(time)
+                        .map(|time| time.timestamp().max(0) as u64)
+                        .map_err(|_| Error::InvalidInput(format!("{name}: invalid time {time}")))
+                })
+                .transpose()
+        };
+        let since = time(&query.since, "since")?.unwrap_or(0);
+        let until = time(&query.until, "until")?.unwrap_or(u64::MAX);
+        if query.group_by.iter().any(|column| Some(*column) == query.distinct) {
+            return Err(Error::InvalidInput(
+                "distinct: the column is grouped by".into(),
+            ));
+        }
+        let limit = query
+            .limit
+            .unwrap_or(self.config.max_rows)
+            .min(self.config.max_rows);
+
+        let mut entries = self.entries.lock().unwrap();
+        self.expire(&mut entries, now());
+        // keyed by the values as JSON text, with the values
+        let mut groups: HashMap<Vec<String>, (Vec<Value>, u64, BTreeSet<String>)> =
+            HashMap::new();
+        let mut lookups = 0;
+        for entry in entries
+            .iter()
+            .filter(|entry| (since..until).contains(&entry.at) && query.filter.matches(entry))
+        {
+            let values: Vec<Value> = query.group_by.iter().map(|c| c.value(entry)).collect();
+            let key = values.iter().map(Value::to_string).collect();
+            let group = groups.entry(key).or_insert_with(|| (values, 0, BTreeSet::new()));
+            group.1 += 1;
+            if let Some(distinct) = query.distinct {
+                group.2.insert(distinct.value(entry).to_string());
+            }
+            lookups += 1;
+        }
+        drop(entries);
+
+        let mut groups: Vec<_> = groups.into_iter().collect();
+        // the most lookups first, then in the order of the keys
+        groups.sort_by(|(a, (_, a_count, _)), (b, (_, b_count, _))| {
+            b_count.cmp(a_count).then_with(|| a.cmp(b))
+        });
+        let truncated = groups.len().saturating_sub(limit);
+        let rows = groups
+            .into_iter()
+            .take(limit)
+            .map(|(_, (key, count, distinct))| {
+                let mut row: Map<String, Value> = query
+                    .group_by
+                    .iter()
+                    .map(|column| column.name().to_string())
+                    .zip(key)
+                    .collect();
+                row.insert("lookups".into(), count.into());
+                if let Some(column) = query.distinct {
+                    row.insert(format!("distinct_{}", column.name()), distinct.len().into());
+                }
+                row
+            })
+            .collect();
+        Ok(HistoryResult {
+            lookups,
+            truncated,
+            rows,
+        })
+    }
+}
+
+/// CSV of the rows, the header first; the values are plain numbers or
+/// strings quoted when they hold a delimiter, a quote or a line break.
+pub fn to_csv(query: &HistoryQuery, result: &HistoryResult) -> String {
+    let mut header: Vec<String> = query
+        .group_by
+        .iter()
+        .map(|column| column.name().to_string())
+        .collect();
+    header.push("lookups".into());
+    if let Some(column) = query.distinct {
+        header.push(format!("distinct_{}", column.name()));
+    }
+    let cell = |value: Option<&Value>| match value {
+        None | Some(Value::Null) => String::new(),
+        Some(Value::String(text)) if text.contains([',', '"', '\n', '\r']) => {
+            format!("\"{}\"", text.replace('"', "\"\""))
+        }
+        Some(Value::String(text)) => text.clone(),
+        Some(value) => value.to_string(),
+    };
+    let mut csv = header.join(",") + "\n";
+    for row in &result.rows {
+        let cells: Vec<String> = header.iter().map(|name| cell(row.get(name))).collect();
+        csv.push_str(&cells.join(","));
+        csv.push('\n');
+    }
+    csv
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DAY: u64 = 86_400;
+
+    fn entry(at: u64, ip: &str, country_code: &str, asn: u32) -> Entry {
+        Entry {
+            at,
+            ip: ip.into(),
+            provider: "ipapi".into(),
+            country_code: Some(country_code.into()),
+            asn: Some(asn),
+            cached: false,
+            degraded: false,
+        }
+    }
+
+    fn history() -> History {
+        let history = History::new(HistoryConfig {
+            retention_secs: u64::MAX,
+            ..Default::default()
+        });
+        // 2026-10-12 and 2026-10-13
+        let monday = 1_791_763_200;
+        history.push(entry(monday, "8.8.8.8", "US", 15169));
+        history.push(entry(monday + 60, "8.8.4.4", "US", 15169));
+        history.push(entry(monday + 120, "1.1.1.1", "AU", 13335));
+        history.push(entry(monday + DAY, "8.8.8.8", "US", 15169));
+        history
+    }
+
+    #[test]
+    fn test_query() {
+        let history = history();
+        let query = HistoryQuery {
+            group_by: vec![Column::Day],
+            distinct: Some(Column::Asn),
+            ..Default::default()
+        };
+        let result = history.query(&query).unwrap();
+        assert_eq!(result.lookups, 4);
+        let rows: Vec<Value> = result.rows.into_iter().map(Value::Object).collect();
+        assert_eq!(
+            rows,
+            [
+                serde_json::json!({"day": "2026-10-12", "lookups": 3, "distinct_asn": 2}),
+                serde_json::json!({"day": "2026-10-13", "lookups": 1, "distinct_asn": 1}),
+            ]
+        );
+
+        let query = HistoryQuery {
+            since: Some("2026-10-12T00:01:00Z".into()),
+            filter: Filter {
+                country_code: Some("us".into()),
+                ..Default::default()
+            },
+            distinct: Some(Column::Ip),
+            ..Default::default()
+        };
+        let result = history.query(&query).unwrap();
+        assert_eq!(result.lookups, 2);
+        assert_eq!(result.rows[0]["distinct_ip"], 2);
+
+        let query = HistoryQuery {
+            group_by: vec![Column::CountryCode, Column::Asn],
+            limit: Some(1),
+            ..Default::default()
+        };
+        let result = history.query(&query).unwrap();
+        assert_eq!(result.truncated, 1);
+        assert_eq!(
+            to_csv(&query, &result),
+            "country_code,asn,lookups\nUS,15169,3\n"
+        );
+    }
+
+    #[test]
+    fn test_invalid() {
+        let history = history();
+        let query = HistoryQuery {
+            since: Some("last week".into()),
+            ..Default::default()
+        };
+        assert!(matches!(history.query(&query), Err(Error::InvalidInput(_))));
+        let query = HistoryQuery {
+            group_by: vec![Column::Asn],
+            distinct: Some(Column::Asn),
+            ..Default::default()
+        };
+        assert!(matches!(history.query(&query), Err(Error::InvalidInput(_))));
+        let query: Result<HistoryQuery, _> = serde_json::from_str(r#"{"select": "*"}"#);
+        assert!(query.is_err(), "no statement to reach past the history");
+    }
+
+    #[test]
+    fn test_retention() {
+        let history = History::new(HistoryConfig {
+            max_entries: 2,
+            retention_secs: DAY,
+            ..Default::default()
+        });
+        let now = now();
+        history.push(entry(now - 2 * DAY, "8.8.8.8", "US", 15169));
+        history.push(entry(now, "8.8.4.4", "US", 15169));
+        assert_eq!(history.entries.lock().unwrap().len(), 1, "expired");
+        history.push(entry(now, "1.1.1.1", "AU", 13335));
+        history.push(entry(now, "1.0.0.1", "AU", 13335));
+        let result = history.query(&HistoryQuery::default()).unwrap();
+        assert_eq!(result.lookups, 2, "at most max_entries");
+    }
+}
@@ -0,0 +1,276 @@
+//! Internet exchange peering LANs
+//!
+//! Addresses of a peering LAN are numbered by the exchange and used by the
+//! routers of its members, the providers usually locate them at the office of
+//! the registrant. The prefixes of every exchange are downloaded from
+//! PeeringDB on a schedule, a matching lookup is located at the exchange.
+//!
+//! The table is saved to the optional snapshot file, a restart serves it
+//! right away and only downloads a new one once it is stale.
+
+use crate::{
+    config::IxpConfig,
+    dataset::{self, load_snapshot, save_snapshot, Dataset},
+    error::Error,
+    geo::Geo,
+};
+use ipnet::IpNet;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+use utoipa::ToSchema;
+
+pub const PEERINGDB_URL: &str = "https://www.peeringdb.com/api";
+
+/// Exchange whose peering LAN contains the address.
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone, PartialEq)]
+pub struct Exchange {
+    /// PeeringDB id of the exchange.
+    pub id: u32,
+    pub name: String,
+    pub name_long: Option<String>,
+    pub city: Option<String>,
+    /// ISO 3166-1 alpha-2 country code.
+    pub country: Option<String>,
+    /// Peering LAN prefix containing the address.
+    pub prefix: String,
+}
+
+impl Exchange {
+    /// Replaces the provider location with the one of the exchange.
+    pub fn locate(&self, geo: &mut Geo) {
+        geo.region = None;
+        geo.region_code = None;
+        geo.postal_code = None;
+        geo.latitude = None;
+        geo.longitude = None;
+        geo.city = self.city.clone();
+        if self.country.is_some() && geo.country_code != self.country {
+            // the derived fields are filled again from the new country
+            geo.country_code = self.country.clone();
+            geo.country = None;
+            geo.continent = None;
+            geo.continent_code = None;
+            geo.timezone = None;
+            geo.utc_offset = None;
+            geo.currency = None;
+            geo.calling_code = None;
+        }
+    }
+}
+
+/// `{"data": [...]}` envelope of the PeeringDB API.
+#[derive(Deserialize, Debug)]
+struct Envelope<T> {
+    data: Vec<T>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Ix {
+    id: u32,
+    name: String,
+    name_long: Option<String>,
+    city: Option<String>,
+    country: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct IxLan {
+    id: u32,
+    ix_id: u32,
+}
+
+#[derive(Deserialize, Debug)]
+struct IxPrefix {
+    ixlan_id: u32,
+    prefix: String,
+}
+
+type Table = Vec<(IpNet, Exchange)>;
+
+pub struct PeeringDb {
+    http: reqwest::Client,
+    config: IxpConfig,
+    table: RwLock<Arc<Table>>,
+    /// Time of the data in `table`, `None` before the first download.
+    updated: RwLock<Option<SystemTime>>,
+}
+
+impl PeeringDb {
+    /// Directory with the snapshot loaded, empty until the first download without one.
+    pub fn new(http: reqwest::Client, config: IxpConfig) -> Self {
+        let ixp = PeeringDb {
+            http,
+            config,
+            table: RwLock::default(),
+            updated: RwLock::default(),
+        };
+        let snapshot = ixp.config.snapshot.as_deref().and_then(load_snapshot);
+        if let Some((exchanges, modified)) = snapshot {
+            ixp.replace(exchanges, modified);
+        }
+        ixp
+    }
+
+    /// Exchange of the peering LAN containing `ip`.
+    pub fn find(&self, ip: IpAddr) -> Option<Exchange> {
+        let table = self.table.read().unwrap().clone();
+        table
+            .iter()
+            .find(|(net, _)| net.contains(&ip))
+            .map(|(_, exchange)| exchange.clone())
+    }
+
+    /// Refreshes the table every `refresh_secs` until the directory is dropped.
+    pub fn spawn_refresh(self: &Arc<Self>) {
+        dataset::spawn_refresh(self);
+    }
+
+    fn replace(&self, exchanges: Vec<Exchange>, updated: SystemTime) {
+        let table = exchanges
+            .into_iter()
+            .filter_map(|exchange| Some((exchange.prefix.parse().ok()?, exchange)))
+            .collect();
+        *self.table.write().unwrap() = Arc::new(table);
+        *self.updated.write().unwrap() = Some(updated);
+    }
+
+    async fn get<T: DeserializeOwned>(&self, object: &str, fields: &str) -> Result<Vec<T>, Error> {
+        let mut request = self
+            .http
+            .get(format!("{}/{object}", self.config.base_url))
+            .query(&[("fields", fields)])
+            .timeout(Duration::from_millis(self.config.timeout_ms));
+        if let Some(key) = &self.config.api_key {
+            request = request.header("Authorization", format!("Api-Key {key}"));
+        }
+        let unavailable = |e: reqwest::Error| {
+            Error::ProviderUnavailable(format!("peeringdb {object} download failed: {e}"))
+        };
+        let response = request
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+            .map_err(unavailable)?;
+        let envelope: Envelope<T> = response.json().await.map_err(unavailable)?;
+        Ok(envelope.data)
+    }
+}
+
+impl Dataset for PeeringDb {
+    const NAME: &'static str = "ixp";
+
+    fn updated(&self) -> Option<SystemTime> {
+        *self.updated.read().unwrap()
+    }
+
+    fn refresh_interval(&self) -> Duration {
+        Duration::from_secs(self.config.refresh_secs)
+    }
+
+    /// Downloads the prefixes of every exchange and saves the snapshot.
+    async fn refresh(&self) -> Result<usize, Error> {
+        let ixs: Vec<Ix> = self.get("ix", "id,name,name_long,city,country").await?;
+        let ixlans: Vec<IxLan> = self.get("ixlan", "id,ix_id").await?;
+        let prefixes: Vec<IxPrefix> = self.get("ixpfx", "ixlan_id,prefix").await?;
+        let exchanges = join(ixs, ixlans, prefixes);
+        if let Some(path) = &self.config.snapshot {
+            save_snapshot(path, &exchanges).await?;
+        }
+        let count = exchanges.len();
+        self.replace(exchanges, SystemTime::now());
+        Ok(count)
+    }
+}
+
+/// One exchange per peering LAN prefix, unparsable prefixes are left out.
+fn join(ixs: Vec<Ix>, ixlans: Vec<IxLan>, prefixes: Vec<IxPrefix>) -> Vec<Exchange> {
+    let ixs: HashMap<u32, Ix> = ixs.into_iter().map(|ix| (ix.id, ix)).collect();
+    let lans: HashMap<u32, u32> = ixlans.into_iter().map(|lan| (lan.id, lan.ix_id)).collect();
+    prefixes
+        .into_iter()
+        .filter(|prefix| prefix.prefix.parse::<IpNet>().is_ok())
+        .filter_map(|prefix| {
+            let ix = ixs.get(lans.get(&prefix.ixlan_id)?)?;
+            Some(Exchange {
+                id: ix.id,
+                name: ix.name.clone(),
+                name_long: ix.name_long.clone().filter(|name| !name.is_empty()),
+                city: ix.city.clone().filter(|city| !city.is_empty()),
+                country: ix.country.clone().filter(|code| !code.is_empty()),
+                prefix: prefix.prefix,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn exchanges() -> Vec<Exchange> {
+        let ixs = json!([
+            { "id": 31, "name": "DE-CIX Frankfurt", "name_long": "", "city": "Frankfurt", "country": "DE" },
+            { "id": 18, "name": "AMS-IX", "name_long": "Amsterdam Internet Exchange", "city": "Amsterdam", "country": "NL" }
+        ]);
+        let ixlans = json!([{ "id": 31, "ix_id": 31 }, { "id": 18, "ix_id": 18 }]);
+        let prefixes = json!([
+            { "ixlan_id": 31, "prefix": "80.81.192.0/21" },
+            { "ixlan_id": 31, "prefix": "2001:7f8::/64" },
+            { "ixlan_id": 18, "prefix": "80.249.208.0/21" },
+            { "ixlan_id": 99, "prefix": "192.0.2.0/24" },
+            { "ixlan_id": 18, "prefix": "bogus" }
+        ]);
+        join(
+            serde_json::from_value(ixs).unwrap(),
+            serde_json::from_value(ixlans).unwrap(),
+            serde_json::from_value(prefixes).unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_join() {
+        let exchanges = exchanges();
+        assert_eq!(exchanges.len(), 3, "orphan and bogus prefixes are dropped");
+        assert_eq!(exchanges[0].name_long, None);
+        assert_eq!(exchanges[2].name, "AMS-IX");
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let path = std::env::temp_dir().join(format!("ip-service-{}.json", uuid::Uuid::new_v4()));
+        std::fs::write(&path, serde_json::to_vec(&exchanges()).unwrap()).unwrap();
+        let config = IxpConfig {
+            snapshot: Some(path.clone()),
+            ..Default::default()
+        };
+        let ixp = PeeringDb::new(reqwest::Client::new(), config);
+        let exchange = ixp.find("80.81.193.17".parse().unwrap()).unwrap();
+        assert_eq!(exchange.name, "DE-CIX Frankfurt");
+        assert_eq!(exchange.prefix, "80.81.192.0/21");
+        assert!(ixp
+            .find("2001:7f8::1a27:5051:c09".parse().unwrap())
+            .is_some());
+        assert!(ixp.find("8.8.8.8".parse().unwrap()).is_none());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_locate() {
+        let mut geo = Geo::new("80.249.208.1".parse().unwrap(), "test");
+        geo.country_code = Some("US".into());
+        geo.country = Some("United States".into());
+        geo.city = Some("Dallas".into());
+        geo.latitude = Some(32.78);
+        exchanges()[2].locate(&mut geo);
+        assert_eq!(geo.city.as_deref(), Some("Amsterdam"));
+        assert_eq!(geo.country_code.as_deref(), Some("NL"));
+        assert_eq!(geo.country, None);
+        assert_eq!(geo.latitude, None);
+    }
+}
@@ -0,0 +1,452 @@
+//! Bookkeeping of the background batch jobs
+//!
+//! The jobs are kept in memory. With a [`JobsConfig::store`] each one also
+//! gets a directory `<id>` there, holding the request, the results appended
+//! to `results.jsonl` and a small progress record. A checkpoint, when queued,
+//! every `checkpoint_every` processed addresses and when done, appends the
+//! results since the previous one and then rewrites the progress, so a job
+//! costs its results once however many checkpoints it takes. On startup the
+//! log is replayed up to the progress of every job and the jobs a restart
+//! interrupted are handed to the server to resume from their last
+//! checkpoint, the one-shot `lookup` and `batch` leave them be.
+//!
+//! An address failing transiently is looked up again up to `retries` times;
+//! the ones still failing are the [`DeadLetter`]s of the job, for the caller
+//! to submit again on their own.
+
+use crate::{
+    config::JobsConfig,
+    dataset::{load_snapshot, save_snapshot},
+    service::{BatchItem, BatchRequest},
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, VecDeque},
+    io::{BufRead, BufReader},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::Duration,
+};
+use tokio::io::AsyncWriteExt;
+use tracing::{info, warn};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+/// Finished jobs kept for polling, the oldest ones are dropped first.
+const RETAINED: usize = 100;
+
+/// Files of a job directory.
+const REQUEST: &str = "request.json";
+const PROGRESS: &str = "progress.json";
+const RESULTS: &str = "results.jsonl";
+
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum JobStatus {
+    Queued,
+    Running,
+    Done,
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone)]
+pub struct Job {
+    pub id: String,
+    pub status: JobStatus,
+    /// Addresses submitted.
+    pub total: usize,
+    /// Addresses processed so far, failures included.
+    pub completed: usize,
+    pub failed: usize,
+    /// Outcomes in submission order, filled in as the job progresses.
+    pub results: Vec<BatchItem>,
+}
+
+/// An address of a job that failed for good.
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone, PartialEq)]
+pub struct DeadLetter {
+    /// Address as submitted.
+    pub ip: String,
+    /// Code of the last error, e.g. `provider_unavailable`.
+    #[schema(example = "provider_unavailable")]
+    pub reason: String,
+    pub detail: String,
+}
+
+/// Submitted jobs, oldest first.
+pub struct Jobs {
+    jobs: Mutex<VecDeque<Job>>,
+    store: Option<PathBuf>,
+    checkpoint_every: usize,
+    retries: u32,
+    retry_delay: Duration,
+    /// Unfinished jobs of the store, until the service resumes them.
+    interrupted: Mutex<Vec<(String, BatchRequest)>>,
+    /// Results in the log of every job of the store.
+    logged: Mutex<HashMap<String, usize>>,
+}
+
+impl Default for Jobs {
+    fn default() -> Self {
+        Jobs::new(&JobsConfig::default())
+    }
+}
+
+impl Jobs {
+    /// Jobs of the store of `config`, none without one.
+    pub fn new(config: &JobsConfig) -> Self {
+        let jobs = Jobs {
+            jobs: Mutex::new(VecDeque::new()),
+            store: config.store.clone(),
+            checkpoint_every: config.checkpoint_every.max(1),
+            retries: config.retries,
+            retry_delay: Duration::from_millis(config.retry_delay_ms),
+            interrupted: Mutex::new(Vec::new()),
+            logged: Mutex::new(HashMap::new()),
+        };
+        if let Some(dir) = &config.store {
+            jobs.load(dir);
+        }
+        jobs
+    }
+
+    fn load(&self, dir: &Path) {
+        let entries = match std::fs::create_dir_all(dir).and_then(|_| std::fs::read_dir(dir)) {
+            Ok(entries) => entries,
+            Err(e) => {
+                warn!("jobs: store {} not loaded: {}", dir.display(), e);
+                return;
+            }
+        };
+        let mut records: Vec<_> = entries
+            .flatten()
+            .map(|entry| entry.path())
+            .filter(|path| path.is_dir())
+            .filter_map(|path| replay(&path))
+            .collect();
+        records.sort_by_key(|(_, _, modified)| *modified);
+        let mut jobs = self.jobs.lock().unwrap();
+        let mut interrupted = self.interrupted.lock().unwrap();
+        let mut logged = self.logged.lock().unwrap();
+        for (mut job, request, _) in records {
+            if job.status != JobStatus::Done {
+                job.status = JobStatus::Queued;
+                interrupted.push((job.id.clone(), request));
+            }
+            logged.insert(job.id.clone(), job.completed);
+            jobs.push_back(job);
+        }
+        if !jobs.is_empty() {
+            info!(
+                "jobs: {} loaded, {} to resume",
+                jobs.len(),
+                interrupted.len()
+            );
+        }
+    }
+
+    pub fn create(&self, total: usize) -> Job {
+        let job = Job {
+            id: Uuid::new_v4().to_string(),
+            status: JobStatus::Queued,
+            total,
+            completed: 0,
+            failed: 0,
+            results: Vec::new(),
+        };
+        let mut jobs = self.jobs.lock().unwrap();
+        let finished = jobs.iter().filter(|j| j.status == JobStatus::Done).count();
+        if finished >= RETAINED {
+            if let Some(oldest) = jobs.iter().position(|j| j.status == JobStatus::Done) {
+                let oldest = jobs.remove(oldest).unwrap();
+                self.logged.lock().unwrap().remove(&oldest.id);
+                if let Some(path) = self.path(&oldest.id) {
+                    if let Err(e) = std::fs::remove_dir_all(&path) {
+                        warn!("jobs: {} not removed: {}", path.display(), e);
+                    }
+                }
+            }
+        }
+        jobs.push_back(job.clone());
+        job
+    }
+
+    pub fn get(&self, id: &str) -> Option<Job> {
+        let jobs = self.jobs.lock().unwrap();
+        jobs.iter().find(|j| j.id == id).cloned()
+    }
+
+    /// Failed addresses of the job so far, in submission order.
+    pub fn failed(&self, id: &str) -> Option<Vec<DeadLetter>> {
+        let jobs = self.jobs.lock().unwrap();
+        let job = jobs.iter().find(|j| j.id == id)?;
+        let failed = job.results.iter().filter_map(|item| {
+            item.error.as_ref().map(|error| DeadLetter {
+                ip: item.ip.clone(),
+                reason: error.error.clone(),
+                detail: error.detail.clone(),
+            })
+        });
+        Some(failed.collect())
+    }
+
+    /// Wait before the retry after `attempt` failed lookups of an address,
+    /// `None` once the retries are used up.
+    pub fn retry_delay(&self, attempt: u32) -> Option<Duration> {
+        (attempt <= self.retries).then(|| self.retry_delay * 2u32.saturating_pow(attempt - 1))
+    }
+
+    /// Marks the job running, returns the addresses it already processed.
+    pub fn start(&self, id: &str) -> usize {
+        let mut completed = 0;
+        self.update(id, |job| {
+            job.status = JobStatus::Running;
+            completed = job.completed;
+        });
+        completed
+    }
+
+    /// Adds the outcome of the next address, returns whether a checkpoint is due.
+    pub fn record(&self, id: &str, item: BatchItem) -> bool {
+        let mut due = false;
+        self.update(id, |job| {
+            job.completed += 1;
+            if item.error.is_some() {
+                job.failed += 1;
+            }
+            job.results.push(item);
+            due = job.completed % self.checkpoint_every == 0;
+        });
+        due && self.store.is_some()
+    }
+
+    pub fn finish(&self, id: &str) {
+        self.update(id, |job| job.status = JobStatus::Done);
+    }
+
+    /// Checkpoint of the job to the store, when there is one: the `request`
+    /// the first time, then the results since the previous checkpoint and
+    /// the progress.
+    pub async fn save(&self, id: &str, request: &BatchRequest) {
+        let (Some(dir), Some(mut job)) = (self.path(id), self.get(id)) else {
+            return;
+        };
+        let logged = self.logged.lock().unwrap().get(id).copied();
+        let results = std::mem::take(&mut job.results);
+        let saved = match append(&dir, &results, logged, request).await {
+            Ok(()) => {
+                self.logged
+                    .lock()
+                    .unwrap()
+                    .insert(id.to_string(), results.len());
+                progress(&dir, &job).await
+            }
+            Err(e) => Err(e),
+        };
+        if let Err(e) = saved {
+            warn!("jobs: {} not saved: {}", dir.display(), e);
+        }
+    }
+
+    /// The unfinished jobs of the store with their requests, once.
+    pub fn interrupted(&self) -> Vec<(String, BatchRequest)> {
+        std::mem::take(&mut self.interrupted.lock().unwrap())
+    }
+
+    fn path(&self, id: &str) -> Option<PathBuf> {
+        self.store.as_ref().map(|dir| dir.join(id))
+    }
+
+    fn update(&self, id: &str, f: impl FnOnce(&mut Job)) {
+        let mut jobs = self.jobs.lock().unwrap();
+        if let Some(job) = jobs.iter_mut().find(|j| j.id == id) {
+            f(job);
+        }
+    }
+}
+
+/// Writes `request` unless some results are `logged` already, then appends
+/// the `results` from `logged` on to the log.
+async fn append(
+    dir: &Path,
+    results: &[BatchItem],
+    logged: Option<usize>,
+    request: &BatchRequest,
+) -> Result<(), String> {
+    if logged.is_none() {
+        tokio::fs::create_dir_all(dir)
+            .await
+            .map_err(|e| e.to_string())?;
+        save_snapshot(&dir.join(REQUEST), request)
+            .await
+            .map_err(|e| e.to_string())?;
+    }
+    let mut lines = Vec::new();
+    for item in results.iter().skip(logged.unwrap_or_default()) {
+        serde_json::to_writer(&mut lines, item).map_err(|e| e.to_string())?;
+        lines.push(b'\n');
+    }
+    if logged.is_some() && lines.is_empty() {
+        return Ok(());
+    }
+    let mut log = tokio::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(dir.join(RESULTS))
+        .await
+        .map_err(|e| e.to_string())?;
+    log.write_all(&lines).await.map_err(|e| e.to_string())?;
+    log.sync_data().await.map_err(|e| e.to_string())
+}
+
+/// Rewrites the progress of `job`, its results left out.
+async fn progress(dir: &Path, job: &Job) -> Result<(), String> {
+    // a restart while writing leaves the previous progress intact
+    let path = dir.join(PROGRESS);
+    let partial = path.with_extension("json.tmp");
+    save_snapshot(&partial, job)
+        .await
+        .map_err(|e| e.to_string())?;
+    tokio::fs::rename(&partial, &path)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+/// Job of a directory of the store with its request, the results replayed
+/// from the log up to the progress; results logged after the last progress
+/// are processed again.
+fn replay(dir: &Path) -> Option<(Job, BatchRequest, std::time::SystemTime)> {
+    let (mut job, modified) = load_snapshot::<Job>(&dir.join(PROGRESS))?;
+    let (request, _) = load_snapshot::<BatchRequest>(&dir.join(REQUEST))?;
+    let log = match std::fs::File::open(dir.join(RESULTS)) {
+        Ok(log) => log,
+        Err(e) => {
+            warn!("jobs: {} not replayed: {}", dir.display(), e);
+            return None;
+        }
+    };
+    // a line cut short by a restart ends the replay
+    job.results = BufReader::new(log)
+        .lines()
+        .map_while(|line| serde_json::from_str::<BatchItem>(&line.ok()?).ok())
+        .take(job.completed)
+        .collect();
+    job.completed = job.results.len();
+    job.failed = job.results.iter().filter(|item| item.error.is_some()).count();
+    Some((job, request, modified))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_retention() {
+        let jobs = Jobs::default();
+        let first = jobs.create(0);
+        jobs.finish(&first.id);
+        let running = jobs.create(0);
+        for _ in 0..RETAINED {
+            let job = jobs.create(0);
+            jobs.finish(&job.id);
+        }
+        assert!(
+            jobs.get(&first.id).is_none(),
+            "Oldest finished job should go"
+        );
+        assert!(jobs.get(&running.id).is_some(), "Unfinished jobs are kept");
+    }
+
+    #[tokio::test]
+    async fn test_store() {
+        let dir = std::env::temp_dir().join(format!("ip-service-jobs-{}", Uuid::new_v4()));
+        let config = JobsConfig {
+            store: Some(dir.clone()),
+            checkpoint_every: 2,
+            ..Default::default()
+        };
+        let item = |ip: &str| BatchItem {
+            ip: ip.into(),
+            lookup: None,
+            error: None,
+        };
+        let jobs = Jobs::new(&config);
+        let done = BatchRequest::new(["8.8.8.8"]);
+        let finished = jobs.create(1);
+        jobs.start(&finished.id);
+        jobs.record(&finished.id, item("8.8.8.8"));
+        jobs.finish(&finished.id);
+        jobs.save(&finished.id, &done).await;
+
+        let request = BatchRequest::new(["1.1.1.1", "1.0.0.1", "9.9.9.9"]);
+        let running = jobs.create(3);
+        jobs.save(&running.id, &request).await;
+        jobs.start(&running.id);
+        assert!(!jobs.record(&running.id, item("1.1.1.1")));
+        assert!(jobs.record(&running.id, item("1.0.0.1")), "Checkpoint due");
+        jobs.save(&running.id, &request).await;
+        jobs.record(&running.id, item("9.9.9.9"));
+
+        let jobs = Jobs::new(&config);
+        assert_eq!(jobs.get(&finished.id).unwrap().status, JobStatus::Done);
+        let job = jobs.get(&running.id).unwrap();
+        assert_eq!(job.status, JobStatus::Queued);
+        assert_eq!(job.completed, 2, "From the last checkpoint");
+        let interrupted = jobs.interrupted();
+        assert_eq!(interrupted.len(), 1);
+        assert_eq!(interrupted[0].0, running.id);
+        assert_eq!(interrupted[0].1.ips, request.ips);
+        assert_eq!(jobs.start(&running.id), 2);
+        assert!(jobs.interrupted().is_empty());
+        let results: Vec<_> = job.results.iter().map(|item| item.ip.as_str()).collect();
+        assert_eq!(results, ["1.1.1.1", "1.0.0.1"], "Replayed from the log");
+
+        // the next checkpoint appends the third result only
+        jobs.record(&running.id, item("9.9.9.9"));
+        jobs.save(&running.id, &request).await;
+        let log = dir.join(&running.id).join(RESULTS);
+        assert_eq!(std::fs::read_to_string(&log).unwrap().lines().count(), 3);
+
+        // results logged past the progress, or cut short, are not replayed
+        let mut progress = job.clone();
+        progress.results.clear();
+        std::fs::write(
+            dir.join(&running.id).join(PROGRESS),
+            serde_json::to_vec(&progress).unwrap(),
+        )
+        .unwrap();
+        let jobs = Jobs::new(&config);
+        assert_eq!(jobs.get(&running.id).unwrap().results.len(), 2);
+        std::fs::write(&log, "{\"ip\": \"1.1.1.1\"}\n{\"ip\": \"1.0").unwrap();
+        let jobs = Jobs::new(&config);
+        assert_eq!(jobs.get(&running.id).unwrap().completed, 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_failed() {
+        let jobs = Jobs::default();
+        let job = jobs.create(2);
+        let error = crate::error::Error::Timeout.body();
+        let item = |ip: &str, error| BatchItem {
+            ip: ip.into(),
+            lookup: None,
+            error,
+        };
+        jobs.record(&job.id, item("8.8.8.8", None));
+        jobs.record(&job.id, item("1.1.1.1", Some(error.clone())));
+        let failed = jobs.failed(&job.id).unwrap();
+        assert_eq!(
+            failed,
+            [DeadLetter {
+                ip: "1.1.1.1".into(),
+                reason: "timeout".into(),
+                detail: error.detail,
+            }]
+        );
+        assert!(jobs.failed("unknown").is_none());
+
+        assert_eq!(jobs.retry_delay(1), Some(Duration::from_secs(1)));
+        assert_eq!(jobs.retry_delay(2), Some(Duration::from_secs(2)));
+        assert_eq!(jobs.retry_delay(3), None, "2 retries by default");
+    }
+}
@@ -0,0 +1,282 @@
+//! Tower middleware resolving the peer of every request
+//!
+//! [`GeoLayer`] looks the caller up with a [`LookupService`] and stores the
+//! [`Lookup`] as a request extension, where handlers read it directly or
+//! through the [`GeoIp`](crate::extract::GeoIp) extractor. With a
+//! [`GeoPolicy`] it also answers 403 to the callers the policy refuses.
+//!
+//! ```no_run
+//! use axum::{routing::get, Extension, Router};
+//! use ip_service::{
+//!     layer::{GeoLayer, GeoPolicy},
+//!     Lookup, LookupService,
+//! };
+//!
+//! async fn hello(lookup: Option<Extension<Lookup>>) -> String {
+//!     let country = lookup.and_then(|Extension(lookup)| lookup.geo.country_code);
+//!     format!("hello from {}", country.as_deref().unwrap_or("nowhere"))
+//! }
+//!
+//! let policy = GeoPolicy::default()
+//!     .deny_countries(["KP"])
+//!     .deny_anonymous(true);
+//! let app: Router = Router::new()
+//!     .route("/", get(hello))
+//!     .layer(GeoLayer::new(LookupService::builder().build()).policy(policy));
+//! ```
+//!
+//! The peer address comes from a [`ClientIp`] extension, or from the connect
+//! info of `into_make_service_with_connect_info::<SocketAddr>()`.
+
+use crate::{error::Error, extract::ClientIp, Lookup, LookupRequest, LookupService};
+use axum::{
+    extract::{connect_info::MockConnectInfo, ConnectInfo, Request},
+    response::{IntoResponse, Response},
+};
+use futures::future::BoxFuture;
+use serde::Deserialize;
+use std::{
+    net::SocketAddr,
+    task::{Context, Poll},
+};
+use tower::{Layer, Service};
+use tracing::debug;
+
+/// Which callers are let through, everything is allowed by default.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct GeoPolicy {
+    /// Only these ISO 3166-1 alpha-2 countries, any country when empty.
+    pub allow_countries: Vec<String>,
+    pub deny_countries: Vec<String>,
+    pub deny_asns: Vec<u32>,
+    /// Refuse VPN exits, proxies and relays.
+    pub deny_anonymous: bool,
+    /// Refuse the callers that cannot be resolved instead of letting them through.
+    pub fail_closed: bool,
+}
+
+impl GeoPolicy {
+    pub fn allow_countries<I: IntoIterator<Item = S>, S: Into<String>>(mut self, codes: I) -> Self {
+        self.allow_countries = codes.into_iter().map(Into::into).collect();
+        self
+    }
+
+    pub fn deny_countries<I: IntoIterator<Item = S>, S: Into<String>>(mut self, codes: I) -> Self {
+        self.deny_countries = codes.into_iter().map(Into::into).collect();
+        self
+    }
+
+    pub fn deny_asns(mut self, asns: impl IntoIterator<Item = u32>) -> Self {
+        self.deny_asns = asns.into_iter().collect();
+        self
+    }
+
+    pub fn deny_anonymous(mut self, deny: bool) -> Self {
+        self.deny_anonymous = deny;
+        self
+    }
+
+    pub fn fail_closed(mut self, fail_closed: bool) -> Self {
+        self.fail_closed = fail_closed;
+        self
+    }
+
+    /// Why the caller is refused, `None` when it is let through.
+    pub fn check(&self, lookup: &Lookup) -> Option<String> {
+        let country = lookup.geo.country_code.as_deref();
+        let listed = |codes: &[String]| {
+            country.is_some_and(|country| codes.iter().any(|c| c.eq_ignore_ascii_case(country)))
+        };
+        if !self.allow_countries.is_empty() && !listed(&self.allow_countries) {
+            return Some(format!(
+                "country {} not allowed",
+                country.unwrap_or("unknown")
+            ));
+        }
+        if listed(&self.deny_countries) {
+            return Some(format!("country {} denied", country.unwrap_or_default()));
+        }
+        if let Some(asn) = lookup.geo.asn.filter(|asn| self.deny_asns.contains(asn)) {
+            return Some(format!("AS{asn} denied"));
+        }
+        let anonymity = &lookup.anonymity;
+        if self.deny_anonymous && (anonymity.vpn || anonymity.proxy || anonymity.relay) {
+            return Some("anonymizing network denied".into());
+        }
+        None
+    }
+}
+
+/// Annotates the requests with the [`Lookup`] of their peer.
+#[derive(Clone)]
+pub struct GeoLayer {
+    service: LookupService,
+    policy: Option<GeoPolicy>,
+}
+
+impl GeoLayer {
+    pub fn new(service: LookupService) -> Self {
+        GeoLayer {
+            service,
+            policy: None,
+        }
+    }
+
+    /// Refuses the callers `policy` does not allow.
+    pub fn policy(mut self, policy: GeoPolicy) -> Self {
+        self.policy = Some(policy);
+        self
+    }
+}
+
+impl<S> Layer<S> for GeoLayer {
+    type Service = GeoService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        GeoService {
+            inner,
+            layer: self.clone(),
+        }
+    }
+}
+
+/// Middleware of [`GeoLayer`].
+#[derive(Clone)]
+pub struct GeoService<S> {
+    inner: S,
+    layer: GeoLayer,
+}
+
+impl<S> Service<Request> for GeoService<S>
+where
+    S: Service<Request, Response = Response> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = Response;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Response, S::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: Request) -> Self::Future {
+        // the ready service handles this request, the clone the next one
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let layer = self.layer.clone();
+        Box::pin(async move {
+            let resolved = match peer(&req) {
+                Some(ip) => {
+                    let request = LookupRequest::ip(ip.0.to_string());
+                    layer.service.lookup(&request).await
+                }
+                None => Err(Error::Internal("peer address unknown".into())),
+            };
+            let policy = layer.policy.as_ref();
+            match resolved {
+                Ok(lookup) => {
+                    if let Some(reason) = policy.and_then(|policy| policy.check(&lookup)) {
+                        debug!("request from {} refused: {}", lookup.ip, reason);
+                        return Ok(Error::Blocked(reason).into_response());
+                    }
+                    req.extensions_mut().insert(lookup);
+                }
+                Err(e) if policy.is_some_and(|policy| policy.fail_closed) => {
+                    debug!("unresolved request refused: {}", e);
+                    return Ok(Error::Blocked("caller cannot be located".into()).into_response());
+                }
+                Err(e) => debug!("request not annotated: {}", e),
+            }
+            inner.call(req).await
+        })
+    }
+}
+
+fn peer(req: &Request) -> Option<ClientIp> {
+    let extensions = req.extensions();
+    extensions
+        .get::<ClientIp>()
+        .copied()
+        .or_else(|| {
+            extensions
+                .get::<ConnectInfo<SocketAddr>>()
+                .map(|ConnectInfo(addr)| ClientIp(addr.ip()))
+        })
+        .or_else(|| {
+            extensions
+                .get::<MockConnectInfo<SocketAddr>>()
+                .map(|MockConnectInfo(addr)| ClientIp(addr.ip()))
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::{ProviderConfig, ProviderKind},
+        extract::GeoIp,
+    };
+    use axum::{body::Body, http::StatusCode, routing::get, Router};
+    use tower::ServiceExt;
+
+    async fn country(geo: Option<GeoIp>) -> String {
+        geo.and_then(|GeoIp(lookup)| lookup.geo.country_code)
+            .unwrap_or_default()
+    }
+
+    fn app(policy: GeoPolicy) -> Router {
+        let service = LookupService::builder()
+            .provider(ProviderConfig::new(ProviderKind::Mock))
+            .build();
+        Router::new()
+            .route("/", get(country))
+            .layer(GeoLayer::new(service.clone()).policy(policy))
+            .with_state(service)
+    }
+
+    async fn call(app: Router, ip: Option<&str>) -> (StatusCode, String) {
+        let mut request = Request::builder().uri("/").body(Body::empty()).unwrap();
+        if let Some(ip) = ip {
+            request
+                .extensions_mut()
+                .insert(ClientIp(ip.parse().unwrap()));
+        }
+        let response = app.oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), 1024)
+            .await
+            .unwrap();
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_annotate() {
+        let (status, body) = call(app(GeoPolicy::default()), Some("1.1.1.1")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, "AU");
+        // unresolved callers are let through without annotation
+        let (status, body) = call(app(GeoPolicy::default()), None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, "");
+    }
+
+    #[tokio::test]
+    async fn test_policy() {
+        let policy = GeoPolicy::default().allow_countries(["us", "AU"]);
+        assert_eq!(call(app(policy.clone()), Some("8.8.8.8")).await.1, "US");
+        let policy = policy.deny_asns([13335]);
+        let (status, body) = call(app(policy), Some("1.1.1.1")).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert!(body.contains("AS13335 denied"), "{body}");
+
+        let policy = GeoPolicy::default().deny_anonymous(true);
+        let (status, _) = call(app(policy), Some("198.51.100.1")).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+
+        let policy = GeoPolicy::default().fail_closed(true);
+        let (status, _) = call(app(policy), None).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+    }
+}
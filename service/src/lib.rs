@@ -0,0 +1,74 @@
+//! IP intelligence service
+//!
+//! Geolocation with provider failover, caching and reputation enrichment. The
+//! `ip-service` binary exposes [`LookupService`] over HTTP, other services can
+//! embed it directly.
+
+pub mod abuseipdb;
+pub mod admin;
+pub mod anonymity;
+pub mod anycast;
+pub mod asn;
+pub mod blocklist;
+pub mod bulk;
+pub mod cache;
+pub mod caching;
+pub mod callers;
+pub mod canary;
+pub mod carrier;
+pub mod cidr;
+pub mod client;
+pub mod confidence;
+pub mod config;
+pub mod country;
+pub mod dataset;
+pub mod deadline;
+pub mod discrepancy;
+pub mod dns;
+pub mod dnsbl;
+pub mod error;
+pub mod extract;
+pub mod flags;
+pub mod geo;
+pub mod greynoise;
+pub mod heatmap;
+pub mod history;
+pub mod ixp;
+pub mod jobs;
+pub mod layer;
+pub mod maintenance;
+pub mod netblock;
+pub mod otlp;
+pub mod paging;
+pub mod pdns;
+pub mod ping;
+pub mod policy;
+pub mod probe;
+pub mod prometheus;
+pub mod providers;
+pub mod ratelimit;
+pub mod readiness;
+pub mod recovery;
+pub mod reports;
+pub mod risk;
+pub mod sampling;
+pub mod service;
+pub mod shadow;
+pub mod shodan;
+pub mod slo;
+pub mod slow;
+pub mod smtp;
+pub mod socks;
+pub mod statsd;
+pub mod threatlist;
+pub mod tls;
+pub mod traceroute;
+pub mod traffic;
+pub mod v2;
+pub mod versioning;
+
+pub use error::Error;
+pub use service::{
+    BatchItem, BatchRequest, Distance, Lookup, LookupRequest, LookupResponse, LookupService,
+    LookupServiceBuilder,
+};
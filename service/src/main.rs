@@ -1,42 +1,129 @@
 use axum::{
-    extract::State,
-    http::{HeaderMap, StatusCode},
-    routing::{get, post},
+    extract::{FromRequest, Path, Query, Request, State},
+    http::{
+        header::{AUTHORIZATION, CONTENT_TYPE},
+        HeaderMap, StatusCode,
+    },
+    middleware,
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        Html, IntoResponse, Response,
+    },
+    routing::{get, post, put},
     Json, Router,
 };
-use public_ip_address::perform_lookup;
+use clap::{Parser, Subcommand, ValueEnum};
+use futures::{stream, Stream, TryStreamExt};
+use ip_service::{
+    abuseipdb::AbuseReport,
+    admin::{restrict, AdminSources},
+    anonymity::Anonymity,
+    asn::AsnDetail,
+    blocklist::{self, BlocklistFormat},
+    bulk::{self, BulkOptions, Column},
+    caching::{self, Scope},
+    callers::{self, CallerStats, Callers, TopCallers, WINDOW_MINS},
+    canary::{CanaryCheck, CanaryReport, CheckKind},
+    carrier::{Carrier, ConnectionType},
+    cidr::{AsnCount, CidrBlock, CidrSummary, CountryCount, DEFAULT_SAMPLES, MAX_SAMPLES},
+    confidence::Confidence,
+    config::{
+        self, FailoverPolicy, PolicyAction, PolicyRule, Priority, ReportPeriod, TargetPolicyConfig,
+    },
+    country::CountryFlag,
+    deadline::deadline,
+    discrepancy::{Discrepancy, DiscrepancyStats, PairStats},
+    dns::Resolution,
+    dnsbl::{DnsblMatch, DnsblReport},
+    error::{Error, ErrorBody, Resource},
+    extract::GeoIp,
+    flags::{Flag, FlagUpdate},
+    geo::{Field, Geo, Threat},
+    greynoise::{Noise, NoiseVerdict},
+    heatmap::{CellCount, Heatmap, HeatmapData, WINDOW_HOURS},
+    history::{self, Filter, History, HistoryFormat, HistoryQuery, HistoryResult},
+    ixp::Exchange,
+    jobs::{DeadLetter, Job, JobStatus},
+    maintenance::MaintenanceMode,
+    netblock::{AbuseContact, Netblock},
+    pdns::{PassiveDns, PassiveDomain},
+    ping::{Ping, PingMethod},
+    probe::{PortState, Probe},
+    prometheus::{self, observe},
+    providers::{
+        health::{CircuitState, HealthState},
+        registry::{KeyUsage, ProviderSpend, ProviderStatus, ProviderUsage, SpendStats},
+    },
+    readiness::{Dependency, DependencyState, Readiness},
+    recovery::{catch_panic, PanicCounter, REQUEST_ID},
+    reports::{self, ProviderSummary, Summary},
+    risk::{RiskScore, RiskSignal},
+    sampling::{LogSampler, Outcome},
+    service::{HostLookup, MAX_JOB},
+    shadow::ShadowStats,
+    shodan::ShodanHost,
+    slo::{SloStatus, Slos},
+    slow::{detect, SlowRequests},
+    tls::{Certificate, TlsInspection},
+    traceroute::{Hop, TraceMethod, Traceroute},
+    traffic::{track, RecentLookup, Traffic, TrafficSnapshot},
+    v2::{BatchItemV2, HostLookupV2, Location, LookupV2, Meta, Network, Security},
+    versioning::{self, deprecated, Deprecation, Schema},
+    BatchItem, BatchRequest, Distance, Lookup, LookupRequest, LookupResponse, LookupService,
+    LookupServiceBuilder,
+};
 use serde::{Deserialize, Serialize};
-use std::{net::SocketAddr, sync::Arc, time::Instant};
-use tokio::net::TcpListener;
+use std::{
+    io::Write,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::PathBuf,
+    process::ExitCode,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use subtle::ConstantTimeEq;
+use tokio::{
+    io::{AsyncBufRead, BufReader},
+    net::TcpListener,
+};
 use tracing::{info, warn};
-use uuid::Uuid;
-use utoipa::{OpenApi, ToSchema};
+use utoipa::{
+    openapi::security::{HttpAuthScheme, HttpBuilder, SecurityScheme},
+    IntoParams, Modify, OpenApi, ToSchema,
+};
 use utoipa_swagger_ui::SwaggerUi;
+use uuid::Uuid;
 
 // --------- models ---------
 
-#[derive(Clone)]
 struct AppState {
     started_at: std::time::SystemTime,
-}
-
-#[derive(Deserialize, ToSchema)]
-struct LookupRequest {
-    ip: Option<String>,
-}
-
-#[derive(Serialize, ToSchema)]
-struct LookupResponse {
-    ip: String,
-    raw: serde_json::Value,
-    latency_ms: u128,
-    request_id: String,
+    service: LookupService,
+    admin_token: Option<String>,
+    panics: Arc<PanicCounter>,
+    slow: Arc<SlowRequests>,
+    traffic: Arc<Traffic>,
+    callers: Arc<Callers>,
+    heatmap: Heatmap,
+    /// Absent without a `[history]` section.
+    history: Option<History>,
+    slos: Slos,
+    /// Configuration the service was built from, environment included.
+    config: config::Config,
+    log_sampler: LogSampler,
 }
 
 #[derive(Serialize, ToSchema)]
 struct HealthResponse {
+    /// `ok`, `maintenance` while lookups are suspended, or `failing` when the
+    /// latest canary run could not look up its address.
     status: String,
     uptime_sec: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    maintenance: Option<MaintenanceMode>,
+    /// Latest canary run, absent before the first one or without `[canary]`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    canary: Option<CanaryReport>,
 }
 
 #[derive(Serialize, ToSchema)]
@@ -44,128 +131,1736 @@ struct MetricsResponse {
     service: String,
     version: String,
     uptime_sec: u64,
+    /// Handler panics answered with a 500.
+    panics: u64,
+    /// Requests over `slow_requests.request_ms`, the slow provider calls are
+    /// counted per key.
+    slow_requests: u64,
+    /// Request counters per provider and API key.
+    providers: Vec<ProviderUsage>,
+}
+
+/// Event of the `/v1/stats/live` stream, sent every second.
+#[derive(Serialize, ToSchema)]
+struct LiveStats {
+    uptime_sec: u64,
+    traffic: TrafficSnapshot,
+    providers: Vec<ProviderStatus>,
 }
 
 // --------- OpenAPI ---------
 
 #[derive(OpenApi)]
 #[openapi(
-    paths(lookup_handler, health_handler, metrics_handler),
+    paths(
+        lookup_handler,
+        get_lookup_handler,
+        cidr_handler,
+        distance_handler,
+        batch_handler,
+        submit_job_handler,
+        job_handler,
+        job_failures_handler,
+        whoami_handler,
+        noise_handler,
+        dnsbl_handler,
+        asn_handler,
+        blocklist_handler,
+        netblock_handler,
+        traceroute_handler,
+        ping_handler,
+        probe_handler,
+        tls_handler,
+        discrepancies_handler,
+        shadows_handler,
+        spend_handler,
+        heatmap_handler,
+        summary_handler,
+        slo_handler,
+        live_stats_handler,
+        providers_handler,
+        list_flags_handler,
+        set_flag_handler,
+        delete_flag_handler,
+        config_handler,
+        get_maintenance_handler,
+        set_maintenance_handler,
+        get_target_policy_handler,
+        set_target_policy_handler,
+        reload_target_policy_handler,
+        top_callers_handler,
+        query_handler,
+        health_handler,
+        ready_handler,
+        metrics_handler,
+        prometheus_handler
+    ),
     components(
         schemas(
             LookupRequest,
             LookupResponse,
+            Lookup,
+            HostLookup,
+            LookupV2,
+            Location,
+            Network,
+            Security,
+            Meta,
+            Confidence,
+            HostLookupV2,
+            BatchItemV2,
+            Resolution,
+            CountryFlag,
+            Distance,
+            CidrSummary,
+            CidrBlock,
+            CountryCount,
+            AsnCount,
+            AsnDetail,
+            BlocklistFormat,
+            Netblock,
+            AbuseContact,
+            Traceroute,
+            Hop,
+            TraceMethod,
+            Ping,
+            PingMethod,
+            Probe,
+            PortState,
+            TlsInspection,
+            Certificate,
+            BatchRequest,
+            BatchItem,
+            Job,
+            JobStatus,
+            DeadLetter,
+            FailoverPolicy,
+            Priority,
+            Geo,
+            Field,
+            Threat,
+            Carrier,
+            ConnectionType,
+            Exchange,
+            Anonymity,
+            AbuseReport,
+            Noise,
+            NoiseVerdict,
+            PassiveDns,
+            PassiveDomain,
+            ShodanHost,
+            DnsblReport,
+            DnsblMatch,
+            DiscrepancyStats,
+            PairStats,
+            Discrepancy,
+            ShadowStats,
+            RiskScore,
+            RiskSignal,
             HealthResponse,
-            MetricsResponse
+            CanaryReport,
+            CanaryCheck,
+            CheckKind,
+            Readiness,
+            Dependency,
+            DependencyState,
+            MetricsResponse,
+            LiveStats,
+            TrafficSnapshot,
+            TopCallers,
+            CallerStats,
+            HistoryQuery,
+            Filter,
+            history::Column,
+            HistoryFormat,
+            HistoryResult,
+            HeatmapData,
+            CellCount,
+            SloStatus,
+            Summary,
+            ProviderSummary,
+            ReportPeriod,
+            RecentLookup,
+            ProviderUsage,
+            KeyUsage,
+            ProviderStatus,
+            SpendStats,
+            ProviderSpend,
+            HealthState,
+            CircuitState,
+            Flag,
+            FlagUpdate,
+            MaintenanceMode,
+            TargetPolicyConfig,
+            PolicyRule,
+            PolicyAction,
+            ErrorBody,
+            Resource
         )
     ),
+    modifiers(&AdminToken),
     tags(
         (name = "adatari-ip", description = "IP Intelligence Service")
     )
 )]
 struct ApiDoc;
 
+/// Bearer scheme of the `/admin` endpoints, the static `admin_token` of the configuration.
+struct AdminToken;
+
+impl Modify for AdminToken {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let scheme = HttpBuilder::new()
+            .scheme(HttpAuthScheme::Bearer)
+            .description(Some("`admin_token` of the configuration"))
+            .build();
+        openapi
+            .components
+            .get_or_insert_with(Default::default)
+            .add_security_scheme("admin_token", SecurityScheme::Http(scheme));
+    }
+}
+
 // --------- main ---------
 
+#[derive(Parser)]
+#[command(version, about = "IP intelligence service")]
+struct Cli {
+    /// `serve` when omitted.
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug, PartialEq)]
+enum Command {
+    /// Run the HTTP server.
+    Serve {
+        #[arg(long, default_value = "0.0.0.0:8080")]
+        listen: SocketAddr,
+    },
+    /// Look one address or hostname up and print the result, the public address when omitted.
+    Lookup {
+        ip: Option<String>,
+        /// Configured provider to try first.
+        #[arg(long)]
+        provider: Option<String>,
+        #[arg(long, value_enum, default_value_t = Format::Json)]
+        format: Format,
+    },
+    /// Look up every address of a file or stdin, one per line or in a CSV column.
+    Batch {
+        /// Standard input when omitted or `-`.
+        file: Option<PathBuf>,
+        /// CSV column of the address, a 1-based position or a header name.
+        #[arg(long)]
+        column: Option<Column>,
+        #[arg(long, default_value_t = ',')]
+        delimiter: char,
+        /// Lookups in flight at once.
+        #[arg(long, default_value_t = 8)]
+        concurrency: usize,
+        /// Most lookups started per second.
+        #[arg(long)]
+        rate: Option<u32>,
+        /// Configured provider to try first.
+        #[arg(long)]
+        provider: Option<String>,
+        /// `json` prints one object per line, `table` waits for the whole input.
+        #[arg(long, value_enum, default_value_t = BatchFormat::Json)]
+        format: BatchFormat,
+    },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+enum Format {
+    Json,
+    Table,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+enum BatchFormat {
+    Json,
+    Csv,
+    Table,
+}
+
 #[tokio::main]
-async fn main() {
-    tracing_subscriber::fmt::init();
+async fn main() -> ExitCode {
+    // stdout is reserved for the lookup results
+    tracing_subscriber::fmt()
+        .with_writer(std::io::stderr)
+        .init();
+
+    let cli = Cli::parse();
+    let config = match config::Config::load() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("error: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let command = cli.command.unwrap_or(Command::Serve {
+        listen: "0.0.0.0:8080".parse().unwrap(),
+    });
+    let result = match command {
+        Command::Serve { listen } => {
+            serve(config, listen).await;
+            Ok(())
+        }
+        Command::Lookup {
+            ip,
+            provider,
+            format,
+        } => {
+            let service = LookupService::from_config(config);
+            let mut stdout = std::io::stdout().lock();
+            lookup(&service, ip, provider, format, &mut stdout).await
+        }
+        Command::Batch {
+            file,
+            column,
+            delimiter,
+            concurrency,
+            rate,
+            provider,
+            format,
+        } => {
+            let options = BulkOptions {
+                column,
+                delimiter,
+                concurrency,
+                rate,
+                lookup: BatchRequest {
+                    provider,
+                    ..Default::default()
+                },
+            };
+            batch(config, file, &options, format).await
+        }
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("error: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
 
+async fn serve(mut config: config::Config, addr: SocketAddr) {
+    if let Some(canary) = &mut config.canary {
+        // through the loopback when listening on every address
+        let ip = match addr.ip() {
+            ip if ip.is_unspecified() && ip.is_ipv4() => IpAddr::V4(Ipv4Addr::LOCALHOST),
+            ip if ip.is_unspecified() => IpAddr::V6(Ipv6Addr::LOCALHOST),
+            ip => ip,
+        };
+        let url = format!("http://{}", SocketAddr::new(ip, addr.port()));
+        canary.url.get_or_insert(url);
+    }
+    let service = LookupServiceBuilder::from_config(config.clone())
+        .resume_jobs(true)
+        .build();
+    let app = app(config, service);
+
+    let listener = TcpListener::bind(addr).await.unwrap();
+
+    info!("Listening on {}", addr);
+    info!("Swagger: http://localhost:{}/swagger", addr.port());
+    info!("Dashboard: http://localhost:{}/dashboard", addr.port());
+
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .with_graceful_shutdown(shutdown_signal())
+    .await
+    .unwrap();
+}
+
+/// Routes of the server and their middleware.
+fn app(config: config::Config, service: LookupService) -> Router {
+    let admin_token = config.admin_token.clone();
+    // validated by Config::load
+    let admin_sources = Arc::new(AdminSources::new(&config.admin_sources).unwrap_or_default());
+    let panics = Arc::new(PanicCounter::default());
+    let slow = Arc::new(SlowRequests::new(Duration::from_millis(
+        config.slow_requests.request_ms,
+    )));
+    let traffic = Arc::new(Traffic::default());
+    let callers = Arc::new(Callers::default());
+    let api = config.api.clone();
+    let timeouts = config.timeouts.clone();
+    let latency_metrics = service.latency_metrics().clone();
     let state = Arc::new(AppState {
         started_at: std::time::SystemTime::now(),
+        service: service.clone(),
+        admin_token,
+        panics: panics.clone(),
+        slow: slow.clone(),
+        traffic: traffic.clone(),
+        callers: callers.clone(),
+        heatmap: Heatmap::default(),
+        history: config.history.clone().map(History::new),
+        slos: Slos::new(&config.slos),
+        log_sampler: LogSampler::new(config.log_sampling.clone()),
+        config,
     });
+    // the extractor takes the service straight from the router state
+    let whoami = Router::new()
+        .route("/whoami", get(whoami_handler))
+        .with_state(service);
 
-    let app = Router::new()
+    let limit = |ms| middleware::from_fn_with_state(Duration::from_millis(ms), deadline);
+    let long = Router::new()
+        .route("/lookup/cidr/*prefix", get(cidr_handler))
+        .route("/batch", post(batch_handler))
+        .route("/jobs", post(submit_job_handler))
+        .route("/traceroute/:ip", get(traceroute_handler))
+        .route("/ping/:ip", get(ping_handler))
+        .with_state(state.clone())
+        .layer(limit(timeouts.long_ms));
+    let admin = Router::new()
+        .route("/admin/flags", get(list_flags_handler))
+        .route(
+            "/admin/flags/:name",
+            put(set_flag_handler).delete(delete_flag_handler),
+        )
+        .route("/admin/config", get(config_handler))
+        .route(
+            "/admin/maintenance",
+            get(get_maintenance_handler).put(set_maintenance_handler),
+        )
+        .route(
+            "/admin/target-policy",
+            get(get_target_policy_handler).put(set_target_policy_handler),
+        )
+        .route(
+            "/admin/target-policy/reload",
+            post(reload_target_policy_handler),
+        )
+        .route("/reports/top-callers", get(top_callers_handler))
+        .route("/query", post(query_handler))
+        .with_state(state.clone())
+        .route_layer(middleware::from_fn_with_state(
+            admin_sources.clone(),
+            restrict,
+        ));
+    // EventSource sends no Authorization header, the token may come as a parameter;
+    // the stream outlives any request timeout
+    let dashboard = Router::new()
+        .route("/dashboard", get(dashboard_handler))
+        .route("/v1/stats/live", get(live_stats_handler))
+        .with_state(state.clone())
+        .route_layer(middleware::from_fn_with_state(state.clone(), authorize_dashboard))
+        .route_layer(middleware::from_fn_with_state(admin_sources, restrict));
+    let v1 = Router::new()
         .route("/lookup", post(lookup_handler))
+        .route("/lookup/:ip", get(get_lookup_handler))
+        .route("/distance", get(distance_handler))
+        .route("/jobs/:id", get(job_handler))
+        .route("/jobs/:id/failed", get(job_failures_handler))
+        .route("/noise/:ip", get(noise_handler))
+        .route("/dnsbl/:ip", get(dnsbl_handler))
+        .route("/asn/:number", get(asn_handler))
+        .route("/export/blocklist", get(blocklist_handler))
+        .route("/netblock/:ip", get(netblock_handler))
+        .route("/probe/:ip/:port", get(probe_handler))
+        .route("/tls/:target", get(tls_handler))
+        .route("/stats/discrepancies", get(discrepancies_handler))
+        .route("/stats/shadows", get(shadows_handler))
+        .route("/stats/spend", get(spend_handler))
+        .route("/reports/heatmap", get(heatmap_handler))
+        .route("/reports/summary", get(summary_handler))
+        .route("/slo", get(slo_handler))
+        .route("/providers", get(providers_handler))
+        .with_state(state.clone())
+        .merge(whoami)
+        .merge(admin)
+        .layer(limit(timeouts.normal_ms))
+        // after the normal deadline, before the layers every request goes through
+        .merge(long)
+        .layer(middleware::from_fn_with_state(slow, detect))
+        .layer(middleware::from_fn_with_state(traffic, track))
+        .layer(middleware::from_fn_with_state(latency_metrics, observe))
+        .layer(middleware::from_fn_with_state(callers, callers::track));
+
+    // a /v2 router nests next to /v1, the unversioned paths stay aliases of /v1
+    let mut app = Router::new()
         .route("/health", get(health_handler))
+        .route("/ready", get(ready_handler))
         .route("/metrics", get(metrics_handler))
-        .merge(SwaggerUi::new("/swagger").url("/api-doc/openapi.json", ApiDoc::openapi()))
-        .with_state(state);
+        .route("/metrics/prometheus", get(prometheus_handler))
+        .with_state(state.clone())
+        .layer(limit(timeouts.fast_ms))
+        .nest(versioning::V1, v1.clone())
+        .merge(dashboard)
+        .merge(SwaggerUi::new("/swagger").url("/api-doc/openapi.json", ApiDoc::openapi()));
+    if api.legacy_routes {
+        let deprecation = Arc::new(Deprecation::new(&api));
+        app = app.merge(v1.layer(middleware::from_fn_with_state(deprecation, deprecated)));
+    }
+    app.layer(middleware::from_fn_with_state(panics, catch_panic))
+}
 
-    let addr: SocketAddr = "0.0.0.0:8080".parse().unwrap();
-    let listener = TcpListener::bind(addr).await.unwrap();
+// --------- cli ---------
 
-    info!("Listening on {}", addr);
-    info!("Swagger: http://localhost:8080/swagger");
+async fn lookup(
+    service: &LookupService,
+    ip: Option<String>,
+    provider: Option<String>,
+    format: Format,
+    out: &mut impl Write,
+) -> Result<(), Error> {
+    // anything that is not an address is a hostname
+    let (ip, host) = match ip {
+        Some(name) if name.parse::<IpAddr>().is_err() => (None, Some(name)),
+        ip => (ip, None),
+    };
+    let req = LookupRequest {
+        ip,
+        host,
+        provider,
+        ..Default::default()
+    };
+    let lookup = service.lookup(&req).await?;
+    write_lookup(out, &lookup, format).map_err(output_error)
+}
 
-    axum::serve(listener, app)
-        .with_graceful_shutdown(shutdown_signal())
-        .await
-        .unwrap();
+async fn batch(
+    config: config::Config,
+    file: Option<PathBuf>,
+    options: &BulkOptions,
+    format: BatchFormat,
+) -> Result<(), Error> {
+    let input: Box<dyn AsyncBufRead + Unpin> = match file {
+        Some(file) if file.as_os_str() != "-" => {
+            let file = tokio::fs::File::open(&file)
+                .await
+                .map_err(|e| Error::InvalidInput(format!("{}: {e}", file.display())))?;
+            Box::new(BufReader::new(file))
+        }
+        _ => Box::new(BufReader::new(tokio::io::stdin())),
+    };
+
+    let service = LookupService::from_config(config);
+    let mut stdout = std::io::stdout().lock();
+    write_batch(&service, input, options, format, &mut stdout).await
+}
+
+/// Writes the items as they come, or the whole table at the end.
+async fn write_batch(
+    service: &LookupService,
+    input: impl AsyncBufRead + Unpin,
+    options: &BulkOptions,
+    format: BatchFormat,
+    out: &mut impl Write,
+) -> Result<(), Error> {
+    let mut items = std::pin::pin!(bulk::enrich(service, input, options));
+    let mut rows = vec![batch_header()];
+    if let BatchFormat::Csv = format {
+        writeln!(out, "{}", bulk::CSV_HEADER).map_err(output_error)?;
+    }
+    while let Some(item) = items.try_next().await? {
+        match format {
+            BatchFormat::Json => {
+                writeln!(out, "{}", serde_json::to_string(&item).unwrap()).map_err(output_error)?
+            }
+            BatchFormat::Csv => {
+                writeln!(out, "{}", bulk::csv_record(&item)).map_err(output_error)?
+            }
+            BatchFormat::Table => rows.push(batch_row(&item)),
+        }
+    }
+    if let BatchFormat::Table = format {
+        write_table(out, &rows).map_err(output_error)?;
+    }
+    Ok(())
+}
+
+/// Writing to stdout fails once the reading end of a pipe is gone.
+fn output_error(e: std::io::Error) -> Error {
+    Error::Internal(format!("cannot write the results: {e}"))
+}
+
+fn write_lookup(out: &mut impl Write, lookup: &Lookup, format: Format) -> std::io::Result<()> {
+    if let Format::Json = format {
+        return writeln!(out, "{}", serde_json::to_string_pretty(lookup).unwrap());
+    }
+    let geo = &lookup.geo;
+    let text = |value: &Option<String>| value.clone().unwrap_or_default();
+    let mut rows = vec![
+        vec!["ip".into(), lookup.ip.clone()],
+        vec!["country".into(), text(&geo.country)],
+        vec!["region".into(), text(&geo.region)],
+        vec!["city".into(), text(&geo.city)],
+        vec![
+            "asn".into(),
+            geo.asn.map(|asn| format!("AS{asn}")).unwrap_or_default(),
+        ],
+        vec!["org".into(), text(&geo.org.clone().or(geo.as_name.clone()))],
+        vec!["timezone".into(), text(&geo.timezone)],
+        vec!["anonymity".into(), anonymity(&lookup.anonymity)],
+        vec!["connection".into(), connection_type(lookup.connection_type)],
+        vec!["provider".into(), geo.provider.clone()],
+    ];
+    if let Some(ixp) = &lookup.ixp {
+        rows.insert(1, vec!["ixp".into(), ixp.name.clone()]);
+    }
+    if lookup.is_anycast {
+        rows.insert(1, vec!["anycast".into(), "yes".into()]);
+    }
+    if let Some(carrier) = &geo.carrier {
+        let codes = match (&carrier.mcc, &carrier.mnc) {
+            (Some(mcc), Some(mnc)) => format!(" ({mcc}-{mnc})"),
+            _ => String::new(),
+        };
+        rows.insert(9, vec!["carrier".into(), text(&carrier.name) + &codes]);
+    }
+    if let Some(risk) = &lookup.risk {
+        rows.push(vec!["risk".into(), risk.score.to_string()]);
+    }
+    if let Some(host) = &lookup.host {
+        let resolution = &host.resolution;
+        rows.insert(0, vec!["host".into(), resolution.host.clone()]);
+        if !resolution.chain.is_empty() {
+            rows.insert(1, vec!["cname".into(), resolution.chain.join(" -> ")]);
+        }
+        let addresses: Vec<String> = resolution.addresses.iter().map(IpAddr::to_string).collect();
+        rows.push(vec!["addresses".into(), addresses.join(", ")]);
+    }
+    write_table(out, &rows)
+}
+
+fn batch_header() -> Vec<String> {
+    ["IP", "COUNTRY", "CITY", "ASN", "ORG", "ANONYMITY", "ERROR"]
+        .map(String::from)
+        .to_vec()
+}
+
+fn batch_row(item: &BatchItem) -> Vec<String> {
+    let Some(lookup) = &item.lookup else {
+        let error = item.error.as_ref().map(|e| e.message.clone());
+        let mut row = vec![item.ip.clone()];
+        row.resize(6, String::new());
+        row.push(error.unwrap_or_default());
+        return row;
+    };
+    let geo = &lookup.geo;
+    vec![
+        item.ip.clone(),
+        geo.country_code.clone().unwrap_or_default(),
+        geo.city.clone().unwrap_or_default(),
+        geo.asn.map(|asn| format!("AS{asn}")).unwrap_or_default(),
+        geo.org.clone().or(geo.as_name.clone()).unwrap_or_default(),
+        anonymity(&lookup.anonymity),
+        String::new(),
+    ]
+}
+
+/// `vpn,hosting`, empty when no flag is set.
+fn anonymity(anonymity: &Anonymity) -> String {
+    let flags = [
+        ("vpn", anonymity.vpn),
+        ("proxy", anonymity.proxy),
+        ("relay", anonymity.relay),
+        ("hosting", anonymity.hosting),
+    ];
+    let set: Vec<&str> = flags
+        .iter()
+        .filter(|(_, on)| *on)
+        .map(|(name, _)| *name)
+        .collect();
+    set.join(",")
+}
+
+fn connection_type(connection_type: ConnectionType) -> String {
+    match connection_type {
+        ConnectionType::Mobile => "mobile",
+        ConnectionType::Broadband => "broadband",
+        ConnectionType::Hosting => "hosting",
+    }
+    .to_string()
+}
+
+/// Left-aligned columns separated by two spaces.
+fn write_table(out: &mut impl Write, rows: &[Vec<String>]) -> std::io::Result<()> {
+    let columns = rows.iter().map(Vec::len).max().unwrap_or(0);
+    let widths: Vec<usize> = (0..columns)
+        .map(|i| {
+            rows.iter()
+                .map(|row| row.get(i).map_or(0, |cell| cell.chars().count()))
+                .max()
+                .unwrap_or(0)
+        })
+        .collect();
+    for row in rows {
+        let line: Vec<String> = row
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{cell:width$}"))
+            .collect();
+        writeln!(out, "{}", line.join("  ").trim_end())?;
+    }
+    Ok(())
 }
 
 // --------- handlers ---------
 
 #[utoipa::path(
     post,
-    path = "/lookup",
+    path = "/v1/lookup",
     request_body = LookupRequest,
+    params(
+        ("Accept" = Option<String>, Header, description = "`application/vnd.adatari.v2+json` selects the v2 schema")
+    ),
     responses(
-        (status = 200, body = LookupResponse)
+        (status = 200, content(
+            ("application/json" = LookupResponse),
+            ("application/vnd.adatari.v2+json" = LookupV2)
+        ), headers(("X-Cache" = String, description = "`hit` when the provider answer came from the cache, else `miss`"))),
+        (status = 400, description = "Invalid IP address or unknown provider", body = ErrorBody, content_type = "application/problem+json"),
+        (status = 403, description = "The target policy refuses the address, `policy` names the rule", body = ErrorBody, content_type = "application/problem+json"),
+        (status = 404, description = "cache_only and neither the cache nor an offline provider knows the address", body = ErrorBody, content_type = "application/problem+json"),
+        (status = 422, description = "The provider refused the lookup", body = ErrorBody, content_type = "application/problem+json"),
+        (status = 429, description = "Every provider is out of quota, `resource` names it, retry after Retry-After seconds", body = ErrorBody, content_type = "application/problem+json"),
+        (status = 502, description = "Every provider failed", body = ErrorBody, content_type = "application/problem+json"),
+        (status = 503, description = "Maintenance mode or no upstream capacity left, retry after Retry-After seconds", body = ErrorBody, content_type = "application/problem+json"),
+        (status = 504, description = "The provider timed out", body = ErrorBody, content_type = "application/problem+json")
     )
 )]
 async fn lookup_handler(
-    State(_state): State<Arc<AppState>>,
+    State(state): State<Arc<AppState>>,
     headers: HeaderMap,
     Json(req): Json<LookupRequest>,
-) -> Result<Json<LookupResponse>, StatusCode> {
+) -> Result<Response, Error> {
     let request_id = headers
-        .get("x-request-id")
+        .get(REQUEST_ID)
         .and_then(|v| v.to_str().ok())
         .map(|s| s.to_string())
         .unwrap_or_else(|| Uuid::new_v4().to_string());
 
     let start = Instant::now();
+    let result = state.service.lookup(&req).await;
+    let latency = start.elapsed().as_millis();
+    log_lookup(&state.log_sampler, &req, &result, latency, &request_id);
+    state.traffic.lookup(&req, &result, latency);
+    state.heatmap.lookup(&result);
+    if let Some(history) = &state.history {
+        history.lookup(&result);
+    }
+    state.slos.lookup(&result, latency);
+    let lookup = result?;
+    let cached = lookup.cached;
 
-    let raw_json = if let Some(ip) = req.ip {
-        // === РЕАЛЬНЫЙ LOOKUP ПО ЧУЖОМУ IP ===
-        lookup_external_ip(&ip).await?
-    } else {
-        // fallback: мой public IP
-        let res = perform_lookup(None)
-            .await
-            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-        serde_json::to_value(res).unwrap()
+    let response = LookupResponse {
+        request_id,
+        latency_ms: latency,
+        lookup,
     };
+    let schema = Schema::negotiate(&headers);
+    let response = match schema {
+        Schema::V1 => Json(response).into_response(),
+        Schema::V2 => Json(LookupV2::from(response)).into_response(),
+    };
+    Ok(schema.label(caching::cache_status(response, cached)))
+}
+
+/// Info line of a lookup request, sampled at the rate of its outcome.
+fn log_lookup(
+    sampler: &LogSampler,
+    req: &LookupRequest,
+    result: &Result<Lookup, Error>,
+    latency: u128,
+    request_id: &str,
+) {
+    let outcome = match result {
+        Ok(lookup) if lookup.cached => Outcome::CacheHit,
+        Ok(_) => Outcome::CacheMiss,
+        Err(_) => Outcome::Error,
+    };
+    if !sampler.sample(outcome) {
+        return;
+    }
+    let rate = sampler.rate(outcome);
+    match result {
+        Ok(lookup) => info!(
+            "lookup ip={} cached={} latency={}ms request_id={} sample_rate={}",
+            lookup.ip, lookup.cached, latency, request_id, rate
+        ),
+        Err(e) => info!(
+            "lookup failed target={} error={} latency={}ms request_id={} sample_rate={}",
+            req.ip.as_deref().or(req.host.as_deref()).unwrap_or("self"),
+            e.code(),
+            latency,
+            request_id,
+            rate
+        ),
+    }
+}
+
+#[derive(Deserialize, IntoParams)]
+struct LookupParams {
+    /// Name of the configured provider to try first, bypasses the cache.
+    provider: Option<String>,
+    #[serde(default)]
+    shodan: bool,
+    #[serde(default)]
+    passive_dns: bool,
+    #[serde(default)]
+    dnsbl: bool,
+    #[serde(default)]
+    country_flag: bool,
+    #[serde(default)]
+    anycast_probe: bool,
+    /// Answer from the caches and offline providers only, 404 for unknown addresses.
+    #[serde(default)]
+    cache_only: bool,
+    /// Oldest cached answer accepted, 0 always calls a provider.
+    max_age_secs: Option<u64>,
+    /// Wait at most this long for the providers, the location is unknown otherwise.
+    timeout_ms: Option<u64>,
+}
 
+#[utoipa::path(
+    get,
+    path = "/v1/lookup/{ip}",
+    params(
+        ("ip" = String, Path, description = "IP address to resolve"),
+        LookupParams,
+        ("If-None-Match" = Option<String>, Header, description = "ETag of a previous response"),
+        ("Accept" = Option<String>, Header, description = "`application/vnd.adatari.v2+json` selects the v2 schema")
+    ),
+    responses(
+        (status = 200, content(
+            ("application/json" = Lookup),
+            ("application/vnd.adatari.v2+json" = LookupV2)
+        ), description = "Fresh for the max-age of Cache-Control, the time left of the cached provider answer",
+            headers(("Cache-Control" = String), ("ETag" = String), ("Vary" = String), ("X-Cache" = String))),
+        (status = 304, description = "The ETag of If-None-Match is still current"),
+        (status = 400, description = "Invalid IP address or unknown provider", body = ErrorBody, content_type = "application/problem+json"),
+        (status = 403, description = "The target policy refuses the address, `policy` names the rule", body = ErrorBody, content_type = "application/problem+json"),
+        (status = 404, description = "cache_only and neither the cache nor an offline provider knows the address", body = ErrorBody, content_type = "application/problem+json"),
+        (status = 422, description = "The provider refused the lookup", body = ErrorBody, content_type = "application/problem+json"),
+        (status = 429, description = "Every provider is out of quota, `resource` names it, retry after Retry-After seconds", body = ErrorBody, content_type = "application/problem+json"),
+        (status = 502, description = "Every provider failed", body = ErrorBody, content_type = "application/problem+json"),
+        (status = 503, description = "Maintenance mode or no upstream capacity left, retry after Retry-After seconds", body = ErrorBody, content_type = "application/problem+json"),
+        (status = 504, description = "The provider timed out", body = ErrorBody, content_type = "application/problem+json")
+    )
+)]
+async fn get_lookup_handler(
+    State(state): State<Arc<AppState>>,
+    Path(ip): Path<String>,
+    Query(params): Query<LookupParams>,
+    headers: HeaderMap,
+) -> Result<Response, Error> {
+    let req = LookupRequest {
+        ip: Some(ip),
+        provider: params.provider,
+        shodan: params.shodan,
+        passive_dns: params.passive_dns,
+        dnsbl: params.dnsbl,
+        country_flag: params.country_flag,
+        anycast_probe: params.anycast_probe,
+        cache_only: params.cache_only,
+        max_age_secs: params.max_age_secs,
+        timeout_ms: params.timeout_ms,
+        ..Default::default()
+    };
+    let request_id = headers
+        .get(REQUEST_ID)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default();
+    let start = Instant::now();
+    let result = state.service.lookup(&req).await;
     let latency = start.elapsed().as_millis();
+    log_lookup(&state.log_sampler, &req, &result, latency, request_id);
+    state.traffic.lookup(&req, &result, latency);
+    state.heatmap.lookup(&result);
+    if let Some(history) = &state.history {
+        history.lookup(&result);
+    }
+    state.slos.lookup(&result, latency);
+    let lookup = result?;
+    let max_age = state.service.cache_ttl(&req, &lookup);
+    let cached = lookup.cached;
+    let schema = Schema::negotiate(&headers);
+    let response = match schema {
+        Schema::V1 => caching::json(&headers, &lookup, max_age, Scope::Public),
+        Schema::V2 => caching::json(&headers, &LookupV2::from(lookup), max_age, Scope::Public),
+    };
+    Ok(schema.label(caching::cache_status(response, cached)))
+}
 
-    let ip = raw_json
-        .get("query")
-        .or_else(|| raw_json.get("ip"))
-        .and_then(|v| v.as_str())
-        .unwrap_or("unknown")
-        .to_string();
+#[derive(Deserialize, IntoParams)]
+struct CidrParams {
+    /// Sub-blocks looked up, 64 by default and at most 256.
+    samples: Option<usize>,
+}
 
-    info!("lookup ip={} latency={}ms request_id={}", ip, latency, request_id);
+#[utoipa::path(
+    get,
+    path = "/v1/lookup/cidr/{prefix}",
+    params(
+        ("prefix" = String, Path, description = "CIDR prefix, e.g. 203.0.113.0/24"),
+        CidrParams
+    ),
+    responses(
+        (status = 200, body = CidrSummary, description = "Countries and ASNs of the sampled sub-blocks"),
+        (status = 400, description = "Invalid or too large prefix", body = ErrorBody, content_type = "application/problem+json")
+    )
+)]
+async fn cidr_handler(
+    State(state): State<Arc<AppState>>,
+    Path(prefix): Path<String>,
+    Query(params): Query<CidrParams>,
+) -> Result<Json<CidrSummary>, Error> {
+    let samples = params.samples.unwrap_or(DEFAULT_SAMPLES);
+    if samples == 0 || samples > MAX_SAMPLES {
+        return Err(Error::InvalidInput(format!(
+            "samples must be between 1 and {MAX_SAMPLES}"
+        )));
+    }
+    Ok(Json(state.service.cidr(&prefix, samples).await?))
+}
 
-    Ok(Json(LookupResponse {
-        ip,
-        raw: raw_json,
-        latency_ms: latency,
-        request_id,
-    }))
+#[derive(Deserialize, IntoParams)]
+struct DistanceParams {
+    /// First IP address, e.g. the login address.
+    from: String,
+    /// Second IP address.
+    to: String,
 }
 
-// --------- external lookup ---------
+#[utoipa::path(
+    get,
+    path = "/v1/distance",
+    params(DistanceParams),
+    responses(
+        (status = 200, body = Distance, description = "Distance and whether country and ASN match"),
+        (status = 400, description = "Invalid IP address", body = ErrorBody, content_type = "application/problem+json")
+    )
+)]
+async fn distance_handler(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<DistanceParams>,
+) -> Result<Json<Distance>, Error> {
+    Ok(Json(
+        state.service.distance(&params.from, &params.to).await?,
+    ))
+}
 
-async fn lookup_external_ip(ip: &str) -> Result<serde_json::Value, StatusCode> {
-    let url = format!("http://ip-api.com/json/{}?fields=66846719", ip);
+#[utoipa::path(
+    post,
+    path = "/v1/batch",
+    request_body = BatchRequest,
+    responses(
+        (status = 200, body = Vec<BatchItem>, description = "One item per address, in submission order"),
+        (status = 400, description = "Too many addresses, submit a job instead", body = ErrorBody, content_type = "application/problem+json")
+    )
+)]
+async fn batch_handler(
+    State(state): State<Arc<AppState>>,
+    Json(req): Json<BatchRequest>,
+) -> Result<Json<Vec<BatchItem>>, Error> {
+    Ok(Json(state.service.batch(&req).await?))
+}
 
-    let resp = reqwest::get(url)
-        .await
-        .map_err(|_| StatusCode::BAD_GATEWAY)?;
+/// Options of a CSV job upload, the JSON body carries its own.
+#[derive(Deserialize, IntoParams)]
+struct UploadParams {
+    /// CSV column of the address, a 1-based position or a header name. Whole
+    /// lines are addresses when omitted.
+    column: Option<String>,
+    /// Cell delimiter of the CSV, `,` by default.
+    delimiter: Option<char>,
+    /// Name of the configured provider to try first.
+    provider: Option<String>,
+    #[serde(default)]
+    cache_only: bool,
+}
 
-    let json: serde_json::Value = resp
-        .json()
+#[utoipa::path(
+    post,
+    path = "/v1/jobs",
+    params(UploadParams),
+    request_body(
+        content = BatchRequest,
+        description = "The addresses as JSON, or a `text/csv` or `application/gzip` CSV streamed in with the options as query parameters"
+    ),
+    responses(
+        (status = 202, body = Job, description = "Queued, poll /v1/jobs/{id} for the results"),
+        (status = 400, description = "Too many addresses or an unreadable upload", body = ErrorBody, content_type = "application/problem+json")
+    )
+)]
+async fn submit_job_handler(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<UploadParams>,
+    request: Request,
+) -> Result<(StatusCode, Json<Job>), Response> {
+    let content_type = request
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+    let csv = ["text/csv", "application/gzip"]
+        .iter()
+        .any(|csv| content_type.starts_with(csv));
+    let req = match csv {
+        true => csv_job(params, request)
+            .await
+            .map_err(Error::into_response)?,
+        false => {
+            let Json(req) = Json::<BatchRequest>::from_request(request, &())
+                .await
+                .map_err(IntoResponse::into_response)?;
+            req
+        }
+    };
+    let job = state
+        .service
+        .submit(req)
         .await
-        .map_err(|_| StatusCode::BAD_GATEWAY)?;
+        .map_err(Error::into_response)?;
+    info!("job {} queued, {} addresses", job.id, job.total);
+    Ok((StatusCode::ACCEPTED, Json(job)))
+}
+
+/// The job of a CSV upload, read as it streams in.
+async fn csv_job(params: UploadParams, request: Request) -> Result<BatchRequest, Error> {
+    let column = params
+        .column
+        .map(|c| c.parse().map_err(Error::InvalidInput))
+        .transpose()?;
+    let delimiter = params.delimiter.unwrap_or(',');
+    let body = request.into_body().into_data_stream();
+    let ips = bulk::read_csv(body, column, delimiter, MAX_JOB).await?;
+    Ok(BatchRequest {
+        provider: params.provider,
+        cache_only: params.cache_only,
+        ..BatchRequest::new(ips)
+    })
+}
+
+#[utoipa::path(
+    get,
+    path = "/v1/jobs/{id}",
+    params(
+        ("id" = String, Path, description = "Job id returned on submission")
+    ),
+    responses(
+        (status = 200, body = Job),
+        (status = 404, description = "Unknown or expired job", body = ErrorBody, content_type = "application/problem+json")
+    )
+)]
+async fn job_handler(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+) -> Result<Json<Job>, Error> {
+    Ok(Json(state.service.job(&id)?))
+}
+
+#[utoipa::path(
+    get,
+    path = "/v1/jobs/{id}/failed",
+    params(
+        ("id" = String, Path, description = "Job id returned on submission")
+    ),
+    responses(
+        (status = 200, body = Vec<DeadLetter>, description = "Addresses that failed after the retries, so far while the job runs"),
+        (status = 404, description = "Unknown or expired job", body = ErrorBody, content_type = "application/problem+json")
+    )
+)]
+async fn job_failures_handler(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+) -> Result<Json<Vec<DeadLetter>>, Error> {
+    Ok(Json(state.service.job_failures(&id)?))
+}
+
+#[utoipa::path(
+    get,
+    path = "/v1/whoami",
+    responses(
+        (status = 200, body = Lookup, description = "Only the caller may cache it, for the time left of the cached provider answer",
+            headers(("Cache-Control" = String), ("ETag" = String), ("X-Cache" = String))),
+        (status = 304, description = "The ETag of If-None-Match is still current"),
+        (status = 422, description = "The caller address cannot be resolved, e.g. a private one", body = ErrorBody, content_type = "application/problem+json"),
+        (status = 429, description = "Every provider is out of quota, `resource` names it, retry after Retry-After seconds", body = ErrorBody, content_type = "application/problem+json"),
+        (status = 502, description = "Every provider failed", body = ErrorBody, content_type = "application/problem+json"),
+        (status = 503, description = "Maintenance mode or no upstream capacity left, retry after Retry-After seconds", body = ErrorBody, content_type = "application/problem+json")
+    )
+)]
+async fn whoami_handler(
+    State(service): State<LookupService>,
+    headers: HeaderMap,
+    GeoIp(lookup): GeoIp,
+) -> Response {
+    let max_age = service.cache_ttl(&LookupRequest::default(), &lookup);
+    let response = caching::json(&headers, &lookup, max_age, Scope::Private);
+    caching::cache_status(response, lookup.cached)
+}
+
+#[utoipa::path(
+    get,
+    path = "/v1/noise/{ip}",
+    params(
+        ("ip" = String, Path, description = "IP address to classify")
+    ),
+    responses(
+        (status = 200, body = Noise),
+        (status = 400, description = "Invalid IP address", body = ErrorBody, content_type = "application/problem+json"),
+        (status = 502, description = "GreyNoise request failed", body = ErrorBody, content_type = "application/problem+json"),
+        (status = 503, description = "GreyNoise is not configured", body = ErrorBody, content_type = "application/problem+json")
+    )
+)]
+async fn noise_handler(
+    State(state): State<Arc<AppState>>,
+    Path(ip): Path<String>,
+) -> Result<Json<Noise>, Error> {
+    Ok(Json(state.service.noise(&ip).await?))
+}
+
+#[utoipa::path(
+    get,
+    path = "/v1/dnsbl/{ip}",
+    params(
+        ("ip" = String, Path, description = "IP address to check")
+    ),
+    responses(
+        (status = 200, body = DnsblReport),
+        (status = 400, description = "Invalid IP address", body = ErrorBody, content_type = "application/problem+json"),
+        (status = 503, description = "DNSBL checks are not configured", body = ErrorBody, content_type = "application/problem+json")
+    )
+)]
+async fn dnsbl_handler(
+    State(state): State<Arc<AppState>>,
+    Path(ip): Path<String>,
+) -> Result<Json<DnsblReport>, Error> {
+    Ok(Json(state.service.dnsbl(&ip).await?))
+}
+
+#[derive(Deserialize, IntoParams)]
+struct BlocklistParams {
+    /// ISO 3166-1 alpha-2 code of the country.
+    #[param(example = "DE")]
+    country: String,
+    #[serde(default)]
+    format: BlocklistFormat,
+}
+
+#[utoipa::path(
+    get,
+    path = "/v1/export/blocklist",
+    params(BlocklistParams),
+    responses(
+        (status = 200, description = "Rule set dropping the prefixes announced by the ASes registered in the country", body = String, content_type = "text/plain"),
+        (status = 400, description = "Unknown country", body = ErrorBody, content_type = "application/problem+json"),
+        (status = 502, description = "The ASN dataset is not downloaded yet", body = ErrorBody, content_type = "application/problem+json"),
+        (status = 503, description = "The ASN dataset is not configured", body = ErrorBody, content_type = "application/problem+json")
+    )
+)]
+async fn blocklist_handler(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<BlocklistParams>,
+) -> Result<impl IntoResponse, Error> {
+    let prefixes = state.service.blocklist(&params.country)?;
+    let country = params.country.to_ascii_uppercase();
+    Ok((
+        [(CONTENT_TYPE, "text/plain; charset=utf-8")],
+        blocklist::render(&country, &prefixes, params.format),
+    ))
+}
+
+#[utoipa::path(
+    get,
+    path = "/v1/asn/{number}",
+    params(
+        ("number" = String, Path, description = "AS number, `15169` or `AS15169`")
+    ),
+    responses(
+        (status = 200, body = AsnDetail),
+        (status = 400, description = "Invalid AS number", body = ErrorBody, content_type = "application/problem+json"),
+        (status = 404, description = "The AS is neither assigned nor announced", body = ErrorBody, content_type = "application/problem+json"),
+        (status = 502, description = "The datasets are not downloaded yet", body = ErrorBody, content_type = "application/problem+json"),
+        (status = 503, description = "The ASN dataset is not configured", body = ErrorBody, content_type = "application/problem+json")
+    )
+)]
+async fn asn_handler(
+    State(state): State<Arc<AppState>>,
+    Path(number): Path<String>,
+) -> Result<Json<AsnDetail>, Error> {
+    Ok(Json(state.service.asn(&number)?))
+}
+
+#[utoipa::path(
+    get,
+    path = "/v1/netblock/{ip}",
+    params(
+        ("ip" = String, Path, description = "IPv4 or IPv6 address")
+    ),
+    responses(
+        (status = 200, body = Netblock),
+        (status = 400, description = "Invalid IP address", body = ErrorBody, content_type = "application/problem+json"),
+        (status = 404, description = "The registry has no record of the address", body = ErrorBody, content_type = "application/problem+json"),
+        (status = 502, description = "The RDAP server failed", body = ErrorBody, content_type = "application/problem+json"),
+        (status = 503, description = "Netblock lookups are not configured", body = ErrorBody, content_type = "application/problem+json"),
+        (status = 504, description = "The RDAP server timed out", body = ErrorBody, content_type = "application/problem+json")
+    )
+)]
+async fn netblock_handler(
+    State(state): State<Arc<AppState>>,
+    Path(ip): Path<String>,
+) -> Result<Json<Netblock>, Error> {
+    Ok(Json(state.service.netblock(&ip).await?))
+}
+
+#[derive(Deserialize, IntoParams)]
+struct PingParams {
+    /// Echoes sent, the configured count by default.
+    count: Option<u32>,
+    /// Port of the TCP fallback, the configured one by default, else one of the
+    /// allowed ports.
+    port: Option<u16>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/v1/ping/{ip}",
+    params(
+        ("ip" = String, Path, description = "IPv4 or IPv6 address"),
+        PingParams
+    ),
+    responses(
+        (status = 200, body = Ping, description = "Round trip times and loss"),
+        (status = 400, description = "Invalid or private address, invalid count, or a port that is not allowed", body = ErrorBody, content_type = "application/problem+json"),
+        (status = 429, description = "Too many pings, in total or of this address", body = ErrorBody, content_type = "application/problem+json"),
+        (status = 503, description = "Ping is not configured", body = ErrorBody, content_type = "application/problem+json")
+    )
+)]
+async fn ping_handler(
+    State(state): State<Arc<AppState>>,
+    Path(ip): Path<String>,
+    Query(params): Query<PingParams>,
+) -> Result<Json<Ping>, Error> {
+    Ok(Json(
+        state.service.ping(&ip, params.count, params.port).await?,
+    ))
+}
+
+#[utoipa::path(
+    get,
+    path = "/v1/probe/{ip}/{port}",
+    params(
+        ("ip" = String, Path, description = "IPv4 or IPv6 address"),
+        ("port" = u16, Path, description = "TCP port, one of the configured ones")
+    ),
+    responses(
+        (status = 200, body = Probe, description = "State of the port and connect latency"),
+        (status = 400, description = "Invalid or private address, or a port that is not allowed", body = ErrorBody, content_type = "application/problem+json"),
+        (status = 429, description = "Too many probes, in total or of this address", body = ErrorBody, content_type = "application/problem+json"),
+        (status = 503, description = "The TCP probe is not configured", body = ErrorBody, content_type = "application/problem+json")
+    )
+)]
+async fn probe_handler(
+    State(state): State<Arc<AppState>>,
+    Path((ip, port)): Path<(String, String)>,
+) -> Result<Json<Probe>, Error> {
+    Ok(Json(state.service.probe(&ip, &port).await?))
+}
+
+#[derive(Deserialize, IntoParams)]
+struct TlsParams {
+    /// Server name sent in the handshake and verified, none by default.
+    sni: Option<String>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/v1/tls/{target}",
+    params(
+        ("target" = String, Path, description = "Address with an optional port, e.g. `192.0.2.1:8443` or `[2001:db8::1]:8443`"),
+        TlsParams
+    ),
+    responses(
+        (status = 200, body = TlsInspection, description = "Certificate chain and whether it is trusted"),
+        (status = 400, description = "Invalid or private address, or invalid server name", body = ErrorBody, content_type = "application/problem+json"),
+        (status = 502, description = "The connection or the handshake failed", body = ErrorBody, content_type = "application/problem+json"),
+        (status = 503, description = "TLS inspection is not configured", body = ErrorBody, content_type = "application/problem+json"),
+        (status = 504, description = "The handshake timed out", body = ErrorBody, content_type = "application/problem+json")
+    )
+)]
+async fn tls_handler(
+    State(state): State<Arc<AppState>>,
+    Path(target): Path<String>,
+    Query(params): Query<TlsParams>,
+) -> Result<Json<TlsInspection>, Error> {
+    Ok(Json(
+        state.service.tls(&target, params.sni.as_deref()).await?,
+    ))
+}
+
+#[derive(Deserialize, IntoParams)]
+struct TracerouteParams {
+    /// Hop limit, the configured one by default and at most.
+    max_hops: Option<u8>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/v1/traceroute/{ip}",
+    params(
+        ("ip" = String, Path, description = "IPv4 or IPv6 address"),
+        TracerouteParams
+    ),
+    responses(
+        (status = 200, body = Traceroute, description = "Hops to the address, public ones located"),
+        (status = 400, description = "Invalid or private address, or an invalid hop limit", body = ErrorBody, content_type = "application/problem+json"),
+        (status = 429, description = "Too many traceroutes, running, in total or to this address", body = ErrorBody, content_type = "application/problem+json"),
+        (status = 502, description = "Neither raw sockets nor the traceroute command are usable", body = ErrorBody, content_type = "application/problem+json"),
+        (status = 503, description = "Traceroute is not configured", body = ErrorBody, content_type = "application/problem+json")
+    )
+)]
+async fn traceroute_handler(
+    State(state): State<Arc<AppState>>,
+    Path(ip): Path<String>,
+    Query(params): Query<TracerouteParams>,
+) -> Result<Json<Traceroute>, Error> {
+    Ok(Json(state.service.traceroute(&ip, params.max_hops).await?))
+}
+
+#[utoipa::path(
+    get,
+    path = "/v1/stats/discrepancies",
+    responses(
+        (status = 200, body = DiscrepancyStats),
+        (status = 503, description = "Discrepancy detection is not configured", body = ErrorBody, content_type = "application/problem+json")
+    )
+)]
+async fn discrepancies_handler(
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<DiscrepancyStats>, Error> {
+    Ok(Json(state.service.discrepancies()?))
+}
+
+#[utoipa::path(
+    get,
+    path = "/v1/stats/shadows",
+    responses(
+        (status = 200, body = [ShadowStats], description = "Answers of every shadow provider compared with the ones returned"),
+        (status = 503, description = "No shadow provider is configured", body = ErrorBody, content_type = "application/problem+json")
+    )
+)]
+async fn shadows_handler(
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<Vec<ShadowStats>>, Error> {
+    Ok(Json(state.service.shadows()?))
+}
+
+#[utoipa::path(
+    get,
+    path = "/v1/stats/spend",
+    responses(
+        (status = 200, description = "Calls sent times the `cost_per_call` of each provider since the start", body = SpendStats)
+    )
+)]
+async fn spend_handler(State(state): State<Arc<AppState>>) -> Json<SpendStats> {
+    Json(state.service.spend())
+}
 
-    Ok(json)
+#[derive(Deserialize, IntoParams)]
+struct HeatmapParams {
+    /// Hours counted back from now, at most 24.
+    window_hours: Option<u64>,
+    /// Counts per one-degree grid cell of the coordinates too.
+    #[serde(default)]
+    grid: bool,
+}
+
+#[utoipa::path(
+    get,
+    path = "/v1/reports/heatmap",
+    params(HeatmapParams),
+    responses(
+        (status = 200, body = HeatmapData, description = "Successful API lookups by country and, with `grid`, by grid cell")
+    )
+)]
+async fn heatmap_handler(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<HeatmapParams>,
+) -> Json<HeatmapData> {
+    let window_hours = params.window_hours.unwrap_or(WINDOW_HOURS);
+    Json(state.heatmap.data(window_hours, params.grid))
+}
+
+#[utoipa::path(
+    get,
+    path = "/v1/slo",
+    responses(
+        (status = 200, body = [SloStatus], description = "Compliance and error budget of every `[[slos]]` objective over its window")
+    )
+)]
+async fn slo_handler(State(state): State<Arc<AppState>>) -> Json<Vec<SloStatus>> {
+    Json(state.slos.status())
+}
+
+#[derive(Deserialize, IntoParams)]
+struct SummaryParams {
+    /// `html` for the page of the report, JSON otherwise.
+    format: Option<String>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/v1/reports/summary",
+    params(SummaryParams),
+    responses(
+        (status = 200, body = Summary, description = "Most recent scheduled report, as JSON or an HTML page"),
+        (status = 404, description = "No report written yet", body = ErrorBody, content_type = "application/problem+json"),
+        (status = 503, description = "The reports are not configured", body = ErrorBody, content_type = "application/problem+json")
+    )
+)]
+async fn summary_handler(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<SummaryParams>,
+) -> Result<Response, Error> {
+    let summary = state.service.latest_report()?;
+    Ok(match params.format.as_deref() {
+        Some("html") => Html(reports::html(&summary)).into_response(),
+        _ => Json(summary).into_response(),
+    })
+}
+
+// --------- admin ---------
+
+/// Checks the bearer token, the admin endpoints are disabled without a configured one.
+fn authorize(state: &AppState, headers: &HeaderMap) -> Result<(), Error> {
+    let token = headers
+        .get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+    check_token(state, token)
+}
+
+fn check_token(state: &AppState, token: Option<&str>) -> Result<(), Error> {
+    let expected = state.admin_token.as_deref().ok_or(Error::Forbidden)?;
+    // compared in constant time, only a length mismatch returns early
+    match token {
+        Some(token) if bool::from(token.as_bytes().ct_eq(expected.as_bytes())) => Ok(()),
+        _ => Err(Error::Unauthorized),
+    }
+}
+
+#[derive(Deserialize, IntoParams)]
+struct TokenParams {
+    /// Admin token, for the clients unable to send an `Authorization` header.
+    token: Option<String>,
+}
+
+/// Route layer of the dashboard, the admin token comes as a bearer token or
+/// as the `token` parameter.
+async fn authorize_dashboard(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<TokenParams>,
+    request: Request,
+    next: middleware::Next,
+) -> Result<Response, Error> {
+    match params.token {
+        Some(token) => check_token(&state, Some(&token))?,
+        None => authorize(&state, request.headers())?,
+    }
+    Ok(next.run(request).await)
+}
+
+#[utoipa::path(
+    get,
+    path = "/v1/admin/flags",
+    security(("admin_token" = [])),
+    responses(
+        (status = 200, body = Vec<Flag>),
+        (status = 401, description = "Missing or wrong admin token", body = ErrorBody, content_type = "application/problem+json"),
+        (status = 403, description = "No admin token configured, or the caller is outside admin_sources", body = ErrorBody, content_type = "application/problem+json")
+    )
+)]
+async fn list_flags_handler(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<Flag>>, Error> {
+    authorize(&state, &headers)?;
+    Ok(Json(state.service.flags().list()))
+}
+
+#[utoipa::path(
+    put,
+    path = "/v1/admin/flags/{name}",
+    security(("admin_token" = [])),
+    params(
+        ("name" = String, Path, description = "Flag name, e.g. `risk_scoring` or `provider.ipinfo`")
+    ),
+    request_body = FlagUpdate,
+    responses(
+        (status = 200, body = Flag),
+        (status = 401, description = "Missing or wrong admin token", body = ErrorBody, content_type = "application/problem+json"),
+        (status = 403, description = "No admin token configured, or the caller is outside admin_sources", body = ErrorBody, content_type = "application/problem+json")
+    )
+)]
+async fn set_flag_handler(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Path(name): Path<String>,
+    Json(update): Json<FlagUpdate>,
+) -> Result<Json<Flag>, Error> {
+    authorize(&state, &headers)?;
+    let flag = state.service.flags().set(&name, update.rollout);
+    info!("flag {} set to {}%", flag.name, flag.rollout);
+    Ok(Json(flag))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/v1/admin/flags/{name}",
+    security(("admin_token" = [])),
+    params(
+        ("name" = String, Path, description = "Flag name")
+    ),
+    responses(
+        (status = 204, description = "Flag removed, the behavior is enabled everywhere"),
+        (status = 401, description = "Missing or wrong admin token", body = ErrorBody, content_type = "application/problem+json"),
+        (status = 403, description = "No admin token configured, or the caller is outside admin_sources", body = ErrorBody, content_type = "application/problem+json"),
+        (status = 404, description = "Flag not set", body = ErrorBody, content_type = "application/problem+json")
+    )
+)]
+async fn delete_flag_handler(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Path(name): Path<String>,
+) -> Result<StatusCode, Error> {
+    authorize(&state, &headers)?;
+    match state.service.flags().remove(&name) {
+        true => {
+            info!("flag {} removed", name);
+            Ok(StatusCode::NO_CONTENT)
+        }
+        false => Err(Error::NotFound(format!("flag {name}"))),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/v1/admin/config",
+    security(("admin_token" = [])),
+    responses(
+        (status = 200, body = Object, description = "Effective configuration of file and environment, secrets replaced by `***`"),
+        (status = 401, description = "Missing or wrong admin token", body = ErrorBody, content_type = "application/problem+json"),
+        (status = 403, description = "No admin token configured, or the caller is outside admin_sources", body = ErrorBody, content_type = "application/problem+json")
+    )
+)]
+async fn config_handler(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Result<Json<serde_json::Value>, Error> {
+    authorize(&state, &headers)?;
+    let config = serde_json::to_value(&state.config).map_err(|e| Error::Internal(e.to_string()))?;
+    Ok(Json(config))
+}
+
+#[utoipa::path(
+    get,
+    path = "/v1/admin/maintenance",
+    security(("admin_token" = [])),
+    responses(
+        (status = 200, body = MaintenanceMode),
+        (status = 401, description = "Missing or wrong admin token", body = ErrorBody, content_type = "application/problem+json"),
+        (status = 403, description = "No admin token configured, or the caller is outside admin_sources", body = ErrorBody, content_type = "application/problem+json")
+    )
+)]
+async fn get_maintenance_handler(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Result<Json<MaintenanceMode>, Error> {
+    authorize(&state, &headers)?;
+    Ok(Json(state.service.maintenance().get()))
+}
+
+#[utoipa::path(
+    put,
+    path = "/v1/admin/maintenance",
+    security(("admin_token" = [])),
+    request_body = MaintenanceMode,
+    responses(
+        (status = 200, body = MaintenanceMode, description = "The mode now in effect"),
+        (status = 401, description = "Missing or wrong admin token", body = ErrorBody, content_type = "application/problem+json"),
+        (status = 403, description = "No admin token configured, or the caller is outside admin_sources", body = ErrorBody, content_type = "application/problem+json")
+    )
+)]
+async fn set_maintenance_handler(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(mode): Json<MaintenanceMode>,
+) -> Result<Json<MaintenanceMode>, Error> {
+    authorize(&state, &headers)?;
+    match mode.enabled {
+        true => warn!(
+            "maintenance mode on, cache_only={} reason={:?}",
+            mode.cache_only, mode.reason
+        ),
+        false => info!("maintenance mode off"),
+    }
+    state.service.maintenance().set(mode.clone());
+    Ok(Json(mode))
+}
+
+#[utoipa::path(
+    get,
+    path = "/v1/admin/target-policy",
+    security(("admin_token" = [])),
+    responses(
+        (status = 200, body = TargetPolicyConfig),
+        (status = 401, description = "Missing or wrong admin token", body = ErrorBody, content_type = "application/problem+json"),
+        (status = 403, description = "No admin token configured, or the caller is outside admin_sources", body = ErrorBody, content_type = "application/problem+json")
+    )
+)]
+async fn get_target_policy_handler(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Result<Json<TargetPolicyConfig>, Error> {
+    authorize(&state, &headers)?;
+    Ok(Json(state.service.target_policy().get()))
+}
+
+#[utoipa::path(
+    put,
+    path = "/v1/admin/target-policy",
+    security(("admin_token" = [])),
+    request_body = TargetPolicyConfig,
+    responses(
+        (status = 200, body = TargetPolicyConfig, description = "The rules now in effect"),
+        (status = 400, description = "Invalid prefix or unnamed rule, the previous rules stay in effect", body = ErrorBody, content_type = "application/problem+json"),
+        (status = 401, description = "Missing or wrong admin token", body = ErrorBody, content_type = "application/problem+json"),
+        (status = 403, description = "No admin token configured, or the caller is outside admin_sources", body = ErrorBody, content_type = "application/problem+json")
+    )
+)]
+async fn set_target_policy_handler(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(policy): Json<TargetPolicyConfig>,
+) -> Result<Json<TargetPolicyConfig>, Error> {
+    authorize(&state, &headers)?;
+    state.service.target_policy().set(policy.clone())?;
+    Ok(Json(policy))
+}
+
+#[utoipa::path(
+    post,
+    path = "/v1/admin/target-policy/reload",
+    security(("admin_token" = [])),
+    responses(
+        (status = 200, body = TargetPolicyConfig, description = "The rules of the configuration file, now in effect"),
+        (status = 400, description = "The configuration does not load, the previous rules stay in effect", body = ErrorBody, content_type = "application/problem+json"),
+        (status = 401, description = "Missing or wrong admin token", body = ErrorBody, content_type = "application/problem+json"),
+        (status = 403, description = "No admin token configured, or the caller is outside admin_sources", body = ErrorBody, content_type = "application/problem+json")
+    )
+)]
+async fn reload_target_policy_handler(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Result<Json<TargetPolicyConfig>, Error> {
+    authorize(&state, &headers)?;
+    let config = config::Config::load()
+        .map_err(|e| Error::InvalidInput(format!("configuration not reloaded: {e}")))?;
+    state
+        .service
+        .target_policy()
+        .set(config.target_policy.clone())?;
+    Ok(Json(config.target_policy))
+}
+
+#[derive(Deserialize, IntoParams)]
+struct TopCallersParams {
+    /// Minutes counted back from now, at most 60.
+    window_mins: Option<u64>,
+    /// Callers listed, 10 by default.
+    limit: Option<usize>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/v1/reports/top-callers",
+    params(TopCallersParams),
+    security(("admin_token" = [])),
+    responses(
+        (status = 200, body = TopCallers, description = "Callers with the most API requests, counted per client address"),
+        (status = 401, description = "Missing or wrong admin token", body = ErrorBody, content_type = "application/problem+json"),
+        (status = 403, description = "No admin token configured, or the caller is outside admin_sources", body = ErrorBody, content_type = "application/problem+json")
+    )
+)]
+async fn top_callers_handler(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Query(params): Query<TopCallersParams>,
+) -> Result<Json<TopCallers>, Error> {
+    authorize(&state, &headers)?;
+    let window_mins = params.window_mins.unwrap_or(WINDOW_MINS);
+    Ok(Json(
+        state.callers.top(window_mins, params.limit.unwrap_or(10)),
+    ))
+}
+
+#[derive(Deserialize, IntoParams)]
+struct QueryParams {
+    #[serde(default)]
+    format: HistoryFormat,
+}
+
+#[utoipa::path(
+    post,
+    path = "/v1/query",
+    params(QueryParams),
+    request_body = HistoryQuery,
+    security(("admin_token" = [])),
+    responses(
+        (status = 200, body = HistoryResult, description = "Lookups of the history matching the filter, counted per group; with `format=csv` the rows only"),
+        (status = 400, description = "Invalid time, or a distinct column that is grouped by", body = ErrorBody, content_type = "application/problem+json"),
+        (status = 401, description = "Missing or wrong admin token", body = ErrorBody, content_type = "application/problem+json"),
+        (status = 403, description = "No admin token configured, or the caller is outside admin_sources", body = ErrorBody, content_type = "application/problem+json"),
+        (status = 503, description = "The lookup history is not configured", body = ErrorBody, content_type = "application/problem+json")
+    )
+)]
+async fn query_handler(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Query(params): Query<QueryParams>,
+    Json(query): Json<HistoryQuery>,
+) -> Result<Response, Error> {
+    authorize(&state, &headers)?;
+    let history = state
+        .history
+        .as_ref()
+        .ok_or(Error::NotConfigured("lookup history"))?;
+    let result = history.query(&query)?;
+    Ok(match params.format {
+        HistoryFormat::Json => Json(result).into_response(),
+        HistoryFormat::Csv => (
+            [(CONTENT_TYPE, "text/csv; charset=utf-8")],
+            history::to_csv(&query, &result),
+        )
+            .into_response(),
+    })
 }
 
 // --------- infra ---------
 
+#[utoipa::path(
+    get,
+    path = "/v1/providers",
+    responses(
+        (status = 200, body = Vec<ProviderStatus>)
+    )
+)]
+async fn providers_handler(State(state): State<Arc<AppState>>) -> Json<Vec<ProviderStatus>> {
+    Json(state.service.providers())
+}
+
 #[utoipa::path(
     get,
     path = "/health",
@@ -175,7 +1870,35 @@ async fn lookup_external_ip(ip: &str) -> Result<serde_json::Value, StatusCode> {
 )]
 async fn health_handler(State(state): State<Arc<AppState>>) -> Json<HealthResponse> {
     let uptime = state.started_at.elapsed().unwrap().as_secs();
-    Json(HealthResponse { status: "ok".into(), uptime_sec: uptime })
+    let maintenance = Some(state.service.maintenance().get()).filter(|mode| mode.enabled);
+    let canary = state.service.canary();
+    Json(HealthResponse {
+        status: match (&maintenance, &canary) {
+            (Some(_), _) => "maintenance".into(),
+            (None, Some(canary)) if !canary.ok => "failing".into(),
+            (None, _) => "ok".into(),
+        },
+        uptime_sec: uptime,
+        maintenance,
+        canary,
+    })
+}
+
+#[utoipa::path(
+    get,
+    path = "/ready",
+    responses(
+        (status = 200, body = Readiness),
+        (status = 503, description = "A dataset is not loaded or expired, or every provider is down", body = Readiness)
+    )
+)]
+async fn ready_handler(State(state): State<Arc<AppState>>) -> (StatusCode, Json<Readiness>) {
+    let readiness = state.service.readiness();
+    let status = match readiness.ready {
+        true => StatusCode::OK,
+        false => StatusCode::SERVICE_UNAVAILABLE,
+    };
+    (status, Json(readiness))
 }
 
 #[utoipa::path(
@@ -191,12 +1914,413 @@ async fn metrics_handler(State(state): State<Arc<AppState>>) -> Json<MetricsResp
         service: "adatari-ip-service".into(),
         version: env!("CARGO_PKG_VERSION").into(),
         uptime_sec: uptime,
+        panics: state.panics.count(),
+        slow_requests: state.slow.count(),
+        providers: state.service.usage(),
     })
 }
 
+/// Status page of the service, fed by `/v1/stats/live` with the `token` of
+/// the page.
+async fn dashboard_handler() -> Html<&'static str> {
+    Html(include_str!("dashboard.html"))
+}
+
+#[utoipa::path(
+    get,
+    path = "/v1/stats/live",
+    security(("admin_token" = [])),
+    params(TokenParams),
+    responses(
+        (status = 200, description = "Server-sent `stats` events every second", body = LiveStats, content_type = "text/event-stream"),
+        (status = 401, description = "Missing or wrong admin token", body = ErrorBody, content_type = "application/problem+json"),
+        (status = 403, description = "No admin token configured, or the caller is outside admin_sources", body = ErrorBody, content_type = "application/problem+json")
+    )
+)]
+async fn live_stats_handler(
+    State(state): State<Arc<AppState>>,
+) -> Sse<impl Stream<Item = Result<Event, axum::Error>>> {
+    let ticks = tokio::time::interval(Duration::from_secs(1));
+    let events = stream::unfold((state, ticks), |(state, mut ticks)| async move {
+        ticks.tick().await;
+        let stats = LiveStats {
+            uptime_sec: state.started_at.elapsed().unwrap_or_default().as_secs(),
+            traffic: state.traffic.snapshot(),
+            providers: state.service.providers(),
+        };
+        let event = Event::default().event("stats").json_data(stats);
+        Some((event, (state, ticks)))
+    });
+    Sse::new(events).keep_alive(KeepAlive::default())
+}
+
+#[utoipa::path(
+    get,
+    path = "/metrics/prometheus",
+    responses(
+        (status = 200, description = "Latency histograms, buckets from `metrics.buckets_ms`", body = String, content_type = "text/plain")
+    )
+)]
+async fn prometheus_handler(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    (
+        [(CONTENT_TYPE, prometheus::CONTENT_TYPE)],
+        state.service.latency_metrics().render() + &state.slos.render(),
+    )
+}
+
 // --------- shutdown ---------
 
 async fn shutdown_signal() {
     tokio::signal::ctrl_c().await.unwrap();
     warn!("shutdown");
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, extract::ConnectInfo};
+    use ip_service::config::{ChaosConfig, ProviderConfig, ProviderKind};
+    use tower::ServiceExt;
+
+    fn parse(args: &[&str]) -> Result<Option<Command>, clap::Error> {
+        let args = std::iter::once("ip-service").chain(args.iter().copied());
+        Cli::try_parse_from(args).map(|cli| cli.command)
+    }
+
+    fn service() -> LookupService {
+        LookupService::builder()
+            .provider(ProviderConfig::new(ProviderKind::Mock))
+            .build()
+    }
+
+    fn server(mut config: config::Config) -> Router {
+        config.providers = vec![ProviderConfig::new(ProviderKind::Mock)];
+        let service = LookupServiceBuilder::from_config(config.clone()).build();
+        app(config, service)
+    }
+
+    /// Response of `app` to a request from a public address.
+    async fn call(app: &Router, method: &str, uri: &str, body: &str) -> Response {
+        let peer: SocketAddr = "203.0.113.7:40000".parse().unwrap();
+        let request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(CONTENT_TYPE, "application/json")
+            .extension(ConnectInfo(peer))
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        app.clone().oneshot(request).await.unwrap()
+    }
+
+    async fn json(response: Response) -> serde_json::Value {
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    #[test]
+    fn test_parse_serve() {
+        assert_eq!(parse(&[]).unwrap(), None, "serve when omitted");
+        assert_eq!(
+            parse(&["serve"]).unwrap(),
+            Some(Command::Serve {
+                listen: "0.0.0.0:8080".parse().unwrap()
+            })
+        );
+        assert_eq!(
+            parse(&["serve", "--listen", "127.0.0.1:9000"]).unwrap(),
+            Some(Command::Serve {
+                listen: "127.0.0.1:9000".parse().unwrap()
+            })
+        );
+        assert!(parse(&["serve", "--listen", "localhost"]).is_err());
+        assert!(parse(&["listen"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_lookup() {
+        assert_eq!(
+            parse(&["lookup"]).unwrap(),
+            Some(Command::Lookup {
+                ip: None,
+                provider: None,
+                format: Format::Json,
+            })
+        );
+        assert_eq!(
+            parse(&[
+                "lookup",
+                "example.com",
+                "--provider",
+                "ipinfo",
+                "--format",
+                "table"
+            ])
+            .unwrap(),
+            Some(Command::Lookup {
+                ip: Some("example.com".into()),
+                provider: Some("ipinfo".into()),
+                format: Format::Table,
+            })
+        );
+        assert!(parse(&["lookup", "--format", "xml"]).is_err());
+        assert!(parse(&["lookup", "8.8.8.8", "1.1.1.1"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_batch() {
+        assert_eq!(
+            parse(&["batch"]).unwrap(),
+            Some(Command::Batch {
+                file: None,
+                column: None,
+                delimiter: ',',
+                concurrency: 8,
+                rate: None,
+                provider: None,
+                format: BatchFormat::Json,
+            })
+        );
+        let args = [
+            "batch",
+            "ips.csv",
+            "--column",
+            "address",
+            "--delimiter",
+            ";",
+            "--concurrency",
+            "2",
+            "--rate",
+            "10",
+            "--provider",
+            "mock",
+            "--format",
+            "csv",
+        ];
+        assert_eq!(
+            parse(&args).unwrap(),
+            Some(Command::Batch {
+                file: Some("ips.csv".into()),
+                column: Some(Column::Name("address".into())),
+                delimiter: ';',
+                concurrency: 2,
+                rate: Some(10),
+                provider: Some("mock".into()),
+                format: BatchFormat::Csv,
+            })
+        );
+        let Some(Command::Batch { column, .. }) = parse(&["batch", "--column", "2"]).unwrap()
+        else {
+            panic!("not a batch");
+        };
+        assert_eq!(column, Some(Column::Index(2)));
+        assert!(parse(&["batch", "--column", "0"]).is_err());
+        assert!(parse(&["batch", "--concurrency", "many"]).is_err());
+        assert!(parse(&["batch", "--delimiter", ";;"]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_lookup_output() {
+        let service = service();
+        let mut out = Vec::new();
+        lookup(
+            &service,
+            Some("8.8.8.8".into()),
+            None,
+            Format::Json,
+            &mut out,
+        )
+        .await
+        .unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(json["ip"], "8.8.8.8");
+        assert_eq!(json["geo"]["provider"], "mock");
+
+        let mut out = Vec::new();
+        lookup(
+            &service,
+            Some("8.8.8.8".into()),
+            None,
+            Format::Table,
+            &mut out,
+        )
+        .await
+        .unwrap();
+        let table = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = table.lines().collect();
+        assert_eq!(lines[0], "ip          8.8.8.8");
+        assert!(lines.contains(&"provider    mock"), "{table}");
+
+        let mut out = Vec::new();
+        let unknown = Some("unknown".to_string());
+        let e = lookup(
+            &service,
+            Some("8.8.8.8".into()),
+            unknown,
+            Format::Json,
+            &mut out,
+        )
+        .await;
+        assert!(e.is_err());
+        assert!(out.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_batch_output() {
+        let service = service();
+        let options = BulkOptions {
+            column: None,
+            delimiter: ',',
+            concurrency: 2,
+            rate: None,
+            lookup: BatchRequest::default(),
+        };
+        let input = b"8.8.8.8\nnot-an-ip\n1.1.1.1\n";
+        let output = |format| {
+            let (service, options) = (&service, &options);
+            async move {
+                let mut out = Vec::new();
+                write_batch(service, &input[..], options, format, &mut out)
+                    .await
+                    .unwrap();
+                String::from_utf8(out).unwrap()
+            }
+        };
+
+        let json = output(BatchFormat::Json).await;
+        let items: Vec<serde_json::Value> = json
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(items.len(), 3);
+        assert_eq!(items[0]["ip"], "8.8.8.8");
+        assert_eq!(items[1]["error"]["error"], "invalid_input", "{json}");
+        assert_eq!(items[2]["ip"], "1.1.1.1");
+
+        let csv = output(BatchFormat::Csv).await;
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines.len(), 4);
+        assert_eq!(lines[0], bulk::CSV_HEADER);
+        assert!(lines[1].starts_with("8.8.8.8,"), "{csv}");
+
+        let table = output(BatchFormat::Table).await;
+        let lines: Vec<&str> = table.lines().collect();
+        assert_eq!(lines.len(), 4);
+        assert!(lines[0].starts_with("IP         COUNTRY"), "{table}");
+        assert!(lines[2].starts_with("not-an-ip"));
+    }
+
+    #[tokio::test]
+    async fn test_slow_batch() {
+        let mut config = config::Config {
+            chaos: Some(ChaosConfig {
+                enabled: true,
+                latency_ms: 50,
+                latency_probability: 1.0,
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        config.slow_requests.request_ms = 20;
+        let app = server(config);
+        let response = call(&app, "POST", "/v1/batch", r#"{"ips": ["8.8.8.8"]}"#).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let metrics = json(call(&app, "GET", "/metrics", "").await).await;
+        assert_eq!(metrics["slow_requests"], 1, "{metrics}");
+    }
+
+    #[tokio::test]
+    async fn test_batch_observed() {
+        let app = server(config::Config::default());
+        let response = call(&app, "POST", "/v1/batch", r#"{"ips": ["8.8.8.8"]}"#).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = call(&app, "GET", "/metrics/prometheus", "").await;
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let metrics = String::from_utf8(body.to_vec()).unwrap();
+        assert!(
+            metrics
+                .lines()
+                .any(|line| line == "ip_service_request_duration_seconds_count 1"),
+            "{metrics}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_dashboard_access() {
+        let app = server(config::Config::default());
+        for uri in ["/dashboard", "/v1/stats/live"] {
+            let response = call(&app, "GET", uri, "").await;
+            assert_eq!(response.status(), StatusCode::FORBIDDEN, "{uri}");
+        }
+
+        let config = config::Config {
+            admin_token: Some("secret".into()),
+            admin_sources: vec!["203.0.113.0/24".into()],
+            ..Default::default()
+        };
+        let app = server(config);
+        let response = call(&app, "GET", "/v1/stats/live", "").await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let response = call(&app, "GET", "/v1/stats/live?token=wrong", "").await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let response = call(&app, "GET", "/v1/stats/live?token=secret", "").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = call(&app, "GET", "/dashboard?token=secret", "").await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let config = config::Config {
+            admin_token: Some("secret".into()),
+            admin_sources: vec!["10.0.0.0/8".into()],
+            ..Default::default()
+        };
+        let app = server(config);
+        let response = call(&app, "GET", "/v1/stats/live?token=secret", "").await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN, "outside the sources");
+    }
+
+    #[tokio::test]
+    async fn test_query() {
+        let config = config::Config {
+            admin_token: Some("secret".into()),
+            history: Some(Default::default()),
+            ..Default::default()
+        };
+        let app = server(config);
+        call(&app, "GET", "/v1/lookup/8.8.8.8", "").await;
+        call(&app, "GET", "/v1/lookup/1.1.1.1", "").await;
+        let query = r#"{"group_by": ["country_code"], "distinct": "ip"}"#;
+        let response = call(&app, "POST", "/v1/query", query).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let peer: SocketAddr = "203.0.113.7:40000".parse().unwrap();
+        let request = |uri: &str| {
+            Request::builder()
+                .method("POST")
+                .uri(uri)
+                .header(CONTENT_TYPE, "application/json")
+                .header(AUTHORIZATION, "Bearer secret")
+                .extension(ConnectInfo(peer))
+                .body(Body::from(query))
+                .unwrap()
+        };
+        let response = app.clone().oneshot(request("/v1/query")).await.unwrap();
+        let result = json(response).await;
+        assert_eq!(result["lookups"], 2, "{result}");
+        assert_eq!(result["rows"][0]["distinct_ip"], 1);
+
+        let response = app
+            .clone()
+            .oneshot(request("/v1/query?format=csv"))
+            .await
+            .unwrap();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let csv = String::from_utf8(body.to_vec()).unwrap();
+        assert!(csv.starts_with("country_code,lookups,distinct_ip\n"), "{csv}");
+        assert_eq!(csv.lines().count(), 3);
+    }
+}
@@ -0,0 +1,101 @@
+//! Maintenance mode
+//!
+//! While it is on, lookups are answered with a 503 and `Retry-After`, or only
+//! from the cache with `cache_only`, so provider credentials can be rotated
+//! without failing requests halfway. It is toggled at runtime through
+//! `/admin/maintenance` and reported by `/health`; it is not persisted.
+
+use crate::error::Error;
+use serde::{Deserialize, Serialize};
+use std::sync::RwLock;
+use utoipa::ToSchema;
+
+const DEFAULT_RETRY_AFTER_SECS: u64 = 60;
+
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone, PartialEq)]
+pub struct MaintenanceMode {
+    pub enabled: bool,
+    /// Still answer the lookups the cache holds.
+    #[serde(default)]
+    pub cache_only: bool,
+    /// `Retry-After` of the refused lookups.
+    #[serde(default = "default_retry_after")]
+    pub retry_after_secs: u64,
+    /// Shown to the refused callers.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+impl Default for MaintenanceMode {
+    fn default() -> Self {
+        MaintenanceMode {
+            enabled: false,
+            cache_only: false,
+            retry_after_secs: DEFAULT_RETRY_AFTER_SECS,
+            reason: None,
+        }
+    }
+}
+
+fn default_retry_after() -> u64 {
+    DEFAULT_RETRY_AFTER_SECS
+}
+
+#[derive(Default)]
+pub struct Maintenance {
+    mode: RwLock<MaintenanceMode>,
+}
+
+impl Maintenance {
+    pub fn get(&self) -> MaintenanceMode {
+        self.mode.read().unwrap().clone()
+    }
+
+    pub fn set(&self, mode: MaintenanceMode) {
+        *self.mode.write().unwrap() = mode;
+    }
+
+    /// Refuses a lookup, unless the mode is off or it is a cache hit in
+    /// `cache_only` mode.
+    pub fn check(&self, cached: bool) -> Result<(), Error> {
+        let mode = self.mode.read().unwrap();
+        if !mode.enabled || (cached && mode.cache_only) {
+            return Ok(());
+        }
+        Err(Error::Maintenance {
+            retry_after_secs: mode.retry_after_secs,
+            reason: mode.reason.clone().unwrap_or_else(|| "maintenance".into()),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check() {
+        let maintenance = Maintenance::default();
+        maintenance.check(false).unwrap();
+
+        maintenance.set(MaintenanceMode {
+            enabled: true,
+            ..Default::default()
+        });
+        assert!(matches!(
+            maintenance.check(true),
+            Err(Error::Maintenance {
+                retry_after_secs: 60,
+                ..
+            })
+        ));
+
+        maintenance.set(MaintenanceMode {
+            enabled: true,
+            cache_only: true,
+            ..Default::default()
+        });
+        maintenance.check(true).unwrap();
+        assert!(maintenance.check(false).is_err());
+    }
+}
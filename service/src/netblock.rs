@@ -0,0 +1,414 @@
+//! Netblock ownership over RDAP
+//!
+//! The IANA bootstrap files tell which regional registry serves a prefix,
+//! they are downloaded on a schedule like the other datasets. The RDAP
+//! server of that registry answers with the most specific allocation or
+//! assignment covering the address, its registrant and abuse contact. Until
+//! the bootstrap is loaded the queries go to the rdap.org redirector.
+
+use crate::{
+    asn::range_prefixes,
+    cache::TtlCache,
+    config::NetblockConfig,
+    dataset::{self, load_snapshot, save_snapshot, Dataset},
+    error::Error,
+};
+use ipnet::IpNet;
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    net::IpAddr,
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+use utoipa::ToSchema;
+
+pub const BOOTSTRAP_URLS: &[&str] = &[
+    "https://data.iana.org/rdap/ipv4.json",
+    "https://data.iana.org/rdap/ipv6.json",
+];
+pub const FALLBACK_URL: &str = "https://rdap.org/";
+
+/// Registries recognized in the RDAP server or whois host names.
+const REGISTRIES: &[(&str, &str)] = &[
+    ("afrinic", "AFRINIC"),
+    ("apnic", "APNIC"),
+    ("arin", "ARIN"),
+    ("lacnic", "LACNIC"),
+    ("ripe", "RIPE NCC"),
+];
+
+/// Registration of the network covering an address.
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone, PartialEq)]
+pub struct Netblock {
+    /// CIDR block of the registered range containing the address.
+    pub prefix: String,
+    /// First and last address of the registered range.
+    pub start: String,
+    pub end: String,
+    /// Registry handle, e.g. `NET-8-8-8-0-2`.
+    pub handle: Option<String>,
+    pub netname: Option<String>,
+    /// Allocation or assignment type as named by the registry.
+    #[serde(rename = "type")]
+    pub kind: Option<String>,
+    pub country: Option<String>,
+    /// Registrant organization.
+    pub org: Option<String>,
+    pub abuse: Option<AbuseContact>,
+    /// Regional registry of the record, e.g. `ARIN`.
+    pub registry: Option<String>,
+    /// RDAP URL of the record.
+    pub source: String,
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone, PartialEq, Default)]
+pub struct AbuseContact {
+    pub name: Option<String>,
+    pub email: Option<String>,
+    pub phone: Option<String>,
+}
+
+/// RDAP IP network object, RFC 9083 section 5.4.
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct IpNetwork {
+    handle: Option<String>,
+    start_address: IpAddr,
+    end_address: IpAddr,
+    name: Option<String>,
+    #[serde(rename = "type")]
+    kind: Option<String>,
+    country: Option<String>,
+    #[serde(default)]
+    entities: Vec<Entity>,
+    port43: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct Entity {
+    #[serde(default)]
+    roles: Vec<String>,
+    /// jCard, RFC 7095: `["vcard", [[name, params, type, value], ...]]`.
+    vcard_array: Option<Value>,
+    #[serde(default)]
+    entities: Vec<Entity>,
+}
+
+impl Entity {
+    /// First value of the vCard property `name`.
+    fn property(&self, name: &str) -> Option<String> {
+        let properties = self.vcard_array.as_ref()?.get(1)?.as_array()?;
+        properties.iter().find_map(|property| {
+            if property.get(0)?.as_str()? != name {
+                return None;
+            }
+            let value = match property.get(3)? {
+                // the `uri` type of `tel` values is `tel:+1-650-253-0000`
+                Value::String(value) => value.trim_start_matches("tel:").to_string(),
+                _ => return None,
+            };
+            Some(value).filter(|value| !value.is_empty())
+        })
+    }
+
+    /// This entity or a nested one with `role`.
+    fn with_role(&self, role: &str) -> Option<&Entity> {
+        if self.roles.iter().any(|r| r == role) {
+            return Some(self);
+        }
+        self.entities
+            .iter()
+            .find_map(|entity| entity.with_role(role))
+    }
+}
+
+/// `services` of an IANA bootstrap file.
+#[derive(Deserialize, Debug)]
+struct Bootstrap {
+    services: Vec<(Vec<String>, Vec<String>)>,
+}
+
+/// RDAP base URL of every delegated prefix.
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct Servers(Vec<(IpNet, String)>);
+
+impl Servers {
+    /// Base URL of the longest delegated prefix containing `ip`.
+    fn find(&self, ip: IpAddr) -> Option<&str> {
+        self.0
+            .iter()
+            .filter(|(net, _)| net.contains(&ip))
+            .max_by_key(|(net, _)| net.prefix_len())
+            .map(|(_, url)| url.as_str())
+    }
+}
+
+pub struct Rdap {
+    http: reqwest::Client,
+    config: NetblockConfig,
+    servers: RwLock<Arc<Servers>>,
+    updated: RwLock<Option<SystemTime>>,
+    cache: TtlCache<Netblock>,
+}
+
+impl Rdap {
+    pub fn new(http: reqwest::Client, config: NetblockConfig) -> Self {
+        let cache = TtlCache::new(
+            Duration::from_secs(config.cache_ttl_secs),
+            config.cache_capacity,
+        );
+        let rdap = Rdap {
+            http,
+            config,
+            servers: RwLock::default(),
+            updated: RwLock::default(),
+            cache,
+        };
+        if let Some((servers, modified)) = rdap.config.snapshot.as_deref().and_then(load_snapshot) {
+            rdap.replace(servers, modified);
+        }
+        rdap
+    }
+
+    /// Refreshes the bootstrap every `refresh_secs` until it is dropped.
+    pub fn spawn_refresh(self: &Arc<Self>) {
+        dataset::spawn_refresh(self);
+    }
+
+    /// Most specific registration covering `ip`.
+    pub async fn netblock(&self, ip: IpAddr) -> Result<Netblock, Error> {
+        if let Some(netblock) = self.cache.get(&ip) {
+            return Ok(netblock);
+        }
+        let base = self.servers.read().unwrap().find(ip).map(String::from);
+        let base = base.unwrap_or_else(|| self.config.fallback_url.clone());
+        let url = format!("{}/ip/{ip}", base.trim_end_matches('/'));
+        let unavailable = |e: reqwest::Error| match e.is_timeout() {
+            true => Error::Timeout,
+            false => Error::ProviderUnavailable(format!("RDAP request to {url} failed: {e}")),
+        };
+        let response = self
+            .http
+            .get(&url)
+            .header("Accept", "application/rdap+json")
+            .timeout(Duration::from_millis(self.config.timeout_ms))
+            .send()
+            .await
+            .map_err(unavailable)?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Err(Error::NotFound(format!("registration of {ip}")));
+        }
+        let network: IpNetwork = response
+            .error_for_status()
+            .map_err(unavailable)?
+            .json()
+            .await
+            .map_err(unavailable)?;
+        let netblock = netblock(ip, network, &url);
+        self.cache.insert(ip, netblock.clone());
+        Ok(netblock)
+    }
+
+    fn replace(&self, servers: Servers, updated: SystemTime) {
+        *self.servers.write().unwrap() = Arc::new(servers);
+        *self.updated.write().unwrap() = Some(updated);
+    }
+}
+
+impl Dataset for Rdap {
+    const NAME: &'static str = "rdap bootstrap";
+
+    fn updated(&self) -> Option<SystemTime> {
+        *self.updated.read().unwrap()
+    }
+
+    fn refresh_interval(&self) -> Duration {
+        Duration::from_secs(self.config.refresh_secs)
+    }
+
+    async fn refresh(&self) -> Result<usize, Error> {
+        let mut servers = Vec::new();
+        for url in &self.config.bootstrap_urls {
+            let unavailable = |e: reqwest::Error| {
+                Error::ProviderUnavailable(format!("{url} download failed: {e}"))
+            };
+            let bootstrap: Bootstrap = self
+                .http
+                .get(url)
+                .timeout(Duration::from_millis(self.config.timeout_ms))
+                .send()
+                .await
+                .and_then(|r| r.error_for_status())
+                .map_err(unavailable)?
+                .json()
+                .await
+                .map_err(unavailable)?;
+            servers.extend(delegations(bootstrap));
+        }
+        let servers = Servers(servers);
+        if let Some(path) = &self.config.snapshot {
+            save_snapshot(path, &servers).await?;
+        }
+        let count = servers.0.len();
+        self.replace(servers, SystemTime::now());
+        Ok(count)
+    }
+}
+
+/// Prefix and RDAP base URL pairs, the HTTPS URL of each service is preferred.
+fn delegations(bootstrap: Bootstrap) -> Vec<(IpNet, String)> {
+    let mut delegations = Vec::new();
+    for (prefixes, urls) in bootstrap.services {
+        let url = urls
+            .iter()
+            .find(|url| url.starts_with("https://"))
+            .or(urls.first());
+        let Some(url) = url else {
+            continue;
+        };
+        for prefix in prefixes {
+            if let Ok(net) = prefix.parse() {
+                delegations.push((net, url.clone()));
+            }
+        }
+    }
+    delegations
+}
+
+fn netblock(ip: IpAddr, network: IpNetwork, url: &str) -> Netblock {
+    let blocks = range_prefixes(network.start_address, network.end_address);
+    let prefix = blocks
+        .iter()
+        .find(|net| net.contains(&ip))
+        .or(blocks.first())
+        .map_or_else(|| IpNet::from(ip).to_string(), ToString::to_string);
+    let registrant = network
+        .entities
+        .iter()
+        .find_map(|entity| entity.with_role("registrant"));
+    let abuse = network
+        .entities
+        .iter()
+        .find_map(|entity| entity.with_role("abuse"))
+        .map(|entity| AbuseContact {
+            name: entity.property("fn"),
+            email: entity.property("email"),
+            phone: entity.property("tel"),
+        });
+    let host = network
+        .port43
+        .as_deref()
+        .unwrap_or(url)
+        .to_ascii_lowercase();
+    let registry = REGISTRIES
+        .iter()
+        .find(|(key, _)| host.contains(key))
+        .map(|(_, name)| name.to_string());
+    Netblock {
+        prefix,
+        start: network.start_address.to_string(),
+        end: network.end_address.to_string(),
+        handle: network.handle,
+        netname: network.name,
+        kind: network.kind,
+        country: network.country,
+        org: registrant.and_then(|entity| entity.property("fn")),
+        abuse,
+        registry,
+        source: url.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    /// Trimmed ARIN answer for 8.8.8.8.
+    fn arin() -> Value {
+        json!({
+            "objectClassName": "ip network",
+            "handle": "NET-8-8-8-0-2",
+            "startAddress": "8.8.8.0",
+            "endAddress": "8.8.8.255",
+            "ipVersion": "v4",
+            "name": "GOGL",
+            "type": "DIRECT ALLOCATION",
+            "port43": "whois.arin.net",
+            "entities": [{
+                "handle": "GOGL",
+                "roles": ["registrant"],
+                "vcardArray": ["vcard", [
+                    ["version", {}, "text", "4.0"],
+                    ["fn", {}, "text", "Google LLC"],
+                    ["kind", {}, "text", "org"]
+                ]],
+                "entities": [{
+                    "handle": "ABUSE5250-ARIN",
+                    "roles": ["abuse"],
+                    "vcardArray": ["vcard", [
+                        ["version", {}, "text", "4.0"],
+                        ["fn", {}, "text", "Abuse"],
+                        ["tel", { "type": ["work", "voice"] }, "text", "+1-650-253-0000"],
+                        ["email", {}, "text", "network-abuse@google.com"]
+                    ]]
+                }]
+            }]
+        })
+    }
+
+    #[test]
+    fn test_netblock() {
+        let network: IpNetwork = serde_json::from_value(arin()).unwrap();
+        let url = "https://rdap.arin.net/registry/ip/8.8.8.8";
+        let netblock = netblock("8.8.8.8".parse().unwrap(), network, url);
+        assert_eq!(netblock.prefix, "8.8.8.0/24");
+        assert_eq!(netblock.netname.as_deref(), Some("GOGL"));
+        assert_eq!(netblock.org.as_deref(), Some("Google LLC"));
+        assert_eq!(netblock.registry.as_deref(), Some("ARIN"));
+        let abuse = netblock.abuse.unwrap();
+        assert_eq!(abuse.email.as_deref(), Some("network-abuse@google.com"));
+        assert_eq!(abuse.phone.as_deref(), Some("+1-650-253-0000"));
+    }
+
+    #[test]
+    fn test_prefix_of_range() {
+        // RIPE ranges are not always a single CIDR block
+        let mut record = arin();
+        record["startAddress"] = json!("192.0.2.0");
+        record["endAddress"] = json!("192.0.2.191");
+        record["port43"] = json!("whois.ripe.net");
+        record["entities"] = json!([]);
+        let network: IpNetwork = serde_json::from_value(record).unwrap();
+        let netblock = netblock("192.0.2.130".parse().unwrap(), network, "https://x/ip");
+        assert_eq!(netblock.prefix, "192.0.2.128/26");
+        assert_eq!(netblock.registry.as_deref(), Some("RIPE NCC"));
+        assert_eq!((netblock.org, netblock.abuse), (None, None));
+    }
+
+    #[test]
+    fn test_bootstrap() {
+        let bootstrap: Bootstrap = serde_json::from_value(json!({
+            "version": "1.0",
+            "services": [
+                [["8.0.0.0/8"], ["https://rdap.arin.net/registry/", "http://rdap.arin.net/registry/"]],
+                [["8.8.0.0/16", "bogus"], ["http://rdap.example.net/"]]
+            ]
+        }))
+        .unwrap();
+        let servers = Servers(delegations(bootstrap));
+        assert_eq!(servers.0.len(), 2);
+        assert_eq!(
+            servers.find("8.8.8.8".parse().unwrap()),
+            Some("http://rdap.example.net/")
+        );
+        assert_eq!(
+            servers.find("8.1.0.1".parse().unwrap()),
+            Some("https://rdap.arin.net/registry/")
+        );
+        assert_eq!(servers.find("9.9.9.9".parse().unwrap()), None);
+    }
+}
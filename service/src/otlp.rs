@@ -0,0 +1,252 @@
+//! Push of the latency histograms to an OpenTelemetry collector
+//!
+//! For deployments without a scrape path to `/metrics/prometheus`: every
+//! `otlp.interval_secs` the histograms are posted to the collector's
+//! `/v1/metrics` as an OTLP/HTTP export in the JSON encoding, cumulative
+//! since the start like the Prometheus ones. A failed export is logged and
+//! the next one carries the counts on.
+
+use crate::{
+    config::OtlpConfig,
+    prometheus::{Counts, LatencyMetrics},
+    providers::USER_AGENT,
+};
+use reqwest::{header::HeaderMap, Client, Url};
+use serde_json::{json, Value};
+use std::{
+    sync::{Arc, Weak},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tracing::{debug, warn};
+
+pub struct Exporter {
+    http: Client,
+    url: Url,
+    headers: HeaderMap,
+    interval: Duration,
+    service_name: String,
+    started_at: SystemTime,
+}
+
+impl Exporter {
+    pub fn new(config: &OtlpConfig) -> Result<Self, String> {
+        let http = Client::builder()
+            .user_agent(USER_AGENT)
+            .timeout(Duration::from_millis(config.timeout_ms))
+            .build()
+            .map_err(|e| format!("otlp: {e}"))?;
+        Ok(Exporter {
+            http,
+            url: config.url()?,
+            headers: config.header_map()?,
+            interval: Duration::from_secs(config.interval_secs),
+            service_name: config.service_name.clone(),
+            started_at: SystemTime::now(),
+        })
+    }
+
+    /// Exports every interval until `metrics` is dropped.
+    pub fn spawn(self, metrics: &Arc<LatencyMetrics>) {
+        let metrics: Weak<LatencyMetrics> = Arc::downgrade(metrics);
+        tokio::spawn(async move {
+            let mut ticks = tokio::time::interval(self.interval);
+            // the first tick completes at once, nothing is observed yet
+            ticks.tick().await;
+            loop {
+                ticks.tick().await;
+                let Some(metrics) = metrics.upgrade() else {
+                    return;
+                };
+                match self.export(&metrics).await {
+                    Ok(()) => debug!("otlp: metrics exported to {}", self.url),
+                    Err(e) => warn!("otlp: export failed: {}", e),
+                }
+            }
+        });
+    }
+
+    pub async fn export(&self, metrics: &LatencyMetrics) -> Result<(), String> {
+        let body = self.request(metrics, SystemTime::now());
+        let response = self
+            .http
+            .post(self.url.clone())
+            .headers(self.headers.clone())
+            .json(&body)
+            .send()
+            .await
+            .map_err(|e| e.to_string())?;
+        match response.status() {
+            status if status.is_success() => Ok(()),
+            status => Err(format!("collector answered {status}")),
+        }
+    }
+
+    /// `ExportMetricsServiceRequest` of the histograms at `now`.
+    fn request(&self, metrics: &LatencyMetrics, now: SystemTime) -> Value {
+        let bounds: Vec<f64> = metrics.bounds_ms().iter().map(|ms| ms / 1_000.0).collect();
+        let point = |counts: Counts, attributes: Vec<Value>| {
+            json!({
+                "attributes": attributes,
+                "startTimeUnixNano": nanos(self.started_at),
+                "timeUnixNano": nanos(now),
+                // 64-bit integers are strings in the JSON encoding
+                "count": counts.count().to_string(),
+                "sum": counts.sum_secs,
+                "bucketCounts": counts.buckets.iter().map(u64::to_string).collect::<Vec<_>>(),
+                "explicitBounds": bounds,
+            })
+        };
+        let provider_points: Vec<Value> = metrics
+            .provider_call_counts()
+            .into_iter()
+            .map(|(provider, counts)| point(counts, vec![attribute("provider", &provider)]))
+            .collect();
+        let error_points: Vec<Value> = metrics
+            .provider_error_counts()
+            .into_iter()
+            .map(|(provider, class, count)| {
+                json!({
+                    "attributes": [attribute("provider", &provider), attribute("class", class)],
+                    "startTimeUnixNano": nanos(self.started_at),
+                    "timeUnixNano": nanos(now),
+                    "asInt": count.to_string(),
+                })
+            })
+            .collect();
+        let histogram = |name: &str, description: &str, points: Vec<Value>| {
+            json!({
+                "name": name,
+                "description": description,
+                "unit": "s",
+                // cumulative
+                "histogram": { "aggregationTemporality": 2, "dataPoints": points },
+            })
+        };
+        json!({
+            "resourceMetrics": [{
+                "resource": {
+                    "attributes": [
+                        attribute("service.name", &self.service_name),
+                        attribute("service.version", env!("CARGO_PKG_VERSION")),
+                    ],
+                },
+                "scopeMetrics": [{
+                    "scope": { "name": "ip-service", "version": env!("CARGO_PKG_VERSION") },
+                    "metrics": [
+                        histogram(
+                            "ip_service.request.duration",
+                            "Handling time of the API requests.",
+                            vec![point(metrics.request_counts(), Vec::new())],
+                        ),
+                        histogram(
+                            "ip_service.provider_call.duration",
+                            "Time of one call to a provider, by provider.",
+                            provider_points,
+                        ),
+                        {
+                            "name": "ip_service.provider_call.errors",
+                            "description": "Failed calls to a provider, by provider and error class.",
+                            "unit": "1",
+                            "sum": {
+                                "aggregationTemporality": 2,
+                                "isMonotonic": true,
+                                "dataPoints": error_points,
+                            },
+                        },
+                    ],
+                }],
+            }],
+        })
+    }
+}
+
+fn attribute(key: &str, value: &str) -> Value {
+    json!({ "key": key, "value": { "stringValue": value } })
+}
+
+fn nanos(time: SystemTime) -> String {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos()
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::MetricsConfig;
+    use axum::{http::HeaderMap, routing::post, Json, Router};
+    use tokio::sync::mpsc;
+
+    fn metrics() -> LatencyMetrics {
+        let metrics = LatencyMetrics::new(&MetricsConfig {
+            buckets_ms: vec![10.0, 100.0],
+            max_provider_labels: 20,
+        });
+        metrics.observe_request(Duration::from_millis(5));
+        metrics.observe_request(Duration::from_millis(50));
+        metrics.observe_provider_call("ipapi", Duration::from_millis(500), Some("timeout"));
+        metrics
+    }
+
+    #[test]
+    fn test_request() {
+        let exporter = Exporter::new(&OtlpConfig::default()).unwrap();
+        let body = exporter.request(&metrics(), SystemTime::now());
+        let scope = &body["resourceMetrics"][0]["scopeMetrics"][0];
+        let requests = &scope["metrics"][0]["histogram"]["dataPoints"][0];
+        assert_eq!(requests["count"], "2");
+        assert_eq!(requests["bucketCounts"], json!(["1", "1", "0"]));
+        assert_eq!(requests["explicitBounds"], json!([0.01, 0.1]));
+        let calls = &scope["metrics"][1]["histogram"]["dataPoints"][0];
+        assert_eq!(calls["attributes"][0]["value"]["stringValue"], "ipapi");
+        assert_eq!(calls["bucketCounts"], json!(["0", "0", "1"]));
+        let errors = &scope["metrics"][2]["sum"]["dataPoints"][0];
+        assert_eq!(errors["attributes"][1]["value"]["stringValue"], "timeout");
+        assert_eq!(errors["asInt"], "1");
+        assert_eq!(
+            body["resourceMetrics"][0]["resource"]["attributes"][0]["value"]["stringValue"],
+            "ip-service"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_export() {
+        let (sent, mut received) = mpsc::unbounded_channel();
+        let app = Router::new().route(
+            "/v1/metrics",
+            post(
+                move |headers: HeaderMap, Json(body): Json<Value>| async move {
+                    let key = headers["x-api-key"].to_str().unwrap().to_string();
+                    sent.send((key, body)).unwrap();
+                    "{}"
+                },
+            ),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let config = OtlpConfig {
+            endpoint: format!("http://127.0.0.1:{port}"),
+            headers: [("x-api-key".to_string(), "secret".to_string())].into(),
+            ..Default::default()
+        };
+        let exporter = Exporter::new(&config).unwrap();
+        exporter.export(&metrics()).await.unwrap();
+        let (key, body) = received.recv().await.unwrap();
+        assert_eq!(key, "secret");
+        assert!(body["resourceMetrics"][0]["scopeMetrics"][0]["metrics"].is_array());
+
+        let config = OtlpConfig {
+            endpoint: format!("http://127.0.0.1:{port}/missing"),
+            ..Default::default()
+        };
+        let e = Exporter::new(&config)
+            .unwrap()
+            .export(&metrics())
+            .await
+            .unwrap_err();
+        assert!(e.contains("404"), "{e}");
+    }
+}
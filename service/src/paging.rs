@@ -0,0 +1,453 @@
+//! Incidents paged through PagerDuty or Opsgenie, or emailed
+//!
+//! Every `paging.check_secs` the provider states are checked for two
+//! conditions: every provider down, and a provider failing more than
+//! `paging.error_rate` of its recent calls. A condition held for
+//! `paging.for_mins` triggers an incident, which is resolved once the
+//! condition has been clear for as long again. Each condition has a stable
+//! deduplication key, the PagerDuty `dedup_key` and the Opsgenie `alias`, so a
+//! flapping provider updates the one open incident instead of paging again.
+//! With `paging.smtp` every trigger and resolve is emailed as well, see
+//! [`smtp`](crate::smtp). A failed send is retried on the next check.
+
+use crate::{
+    config::PagingConfig,
+    providers::{health::HealthState, registry::ProviderStatus, USER_AGENT},
+    smtp::{self, Mailer},
+};
+use reqwest::{Client, Url};
+use serde_json::json;
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use tracing::{info, warn};
+
+#[derive(Debug)]
+struct Incident {
+    since: Instant,
+    /// When the condition last stopped holding, absent while it holds.
+    clear_since: Option<Instant>,
+    paged: bool,
+    summary: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Action {
+    Trigger { key: String, summary: String },
+    Resolve { key: String, summary: String },
+}
+
+pub struct Pager {
+    http: Client,
+    config: PagingConfig,
+    hold: Duration,
+    mailer: Option<Mailer>,
+    /// By deduplication key.
+    incidents: Mutex<HashMap<String, Incident>>,
+}
+
+impl Pager {
+    pub fn new(config: PagingConfig) -> Result<Self, String> {
+        let http = Client::builder()
+            .user_agent(USER_AGENT)
+            .timeout(Duration::from_millis(config.timeout_ms))
+            .build()
+            .map_err(|e| format!("paging: {e}"))?;
+        let timeout = Duration::from_millis(config.timeout_ms);
+        let mailer = match &config.smtp {
+            Some(smtp) => Some(Mailer::new(smtp.clone(), timeout)?),
+            None => None,
+        };
+        Ok(Pager {
+            http,
+            mailer,
+            hold: Duration::from_secs(config.for_mins * 60),
+            config,
+            incidents: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub fn interval(&self) -> Duration {
+        Duration::from_secs(self.config.check_secs)
+    }
+
+    /// Checks `providers` and sends the triggers and resolves due.
+    pub async fn check(&self, providers: &[ProviderStatus]) {
+        for action in self.update(Instant::now(), providers) {
+            match self.send(&action).await {
+                Ok(()) => {
+                    match &action {
+                        Action::Trigger { key, summary } => {
+                            info!("paging: {} paged: {}", key, summary)
+                        }
+                        Action::Resolve { key, .. } => info!("paging: {} resolved", key),
+                    }
+                    self.sent(&action);
+                }
+                Err(e) => warn!(
+                    "paging: {:?} not sent, retried on the next check: {}",
+                    action, e
+                ),
+            }
+        }
+    }
+
+    /// Conditions holding now as deduplication key and summary.
+    fn conditions(&self, providers: &[ProviderStatus]) -> Vec<(String, String)> {
+        let source = &self.config.source;
+        let mut conditions = Vec::new();
+        if !providers.is_empty() && providers.iter().all(|p| p.health == HealthState::Down) {
+            let names: Vec<&str> = providers.iter().map(|p| p.name.as_str()).collect();
+            conditions.push((
+                format!("{source}:all-providers-down"),
+                format!("{source}: all providers down ({})", names.join(", ")),
+            ));
+        }
+        for provider in providers {
+            let Some(success_rate) = provider.success_rate else {
+                continue;
+            };
+            let error_rate = 1.0 - success_rate;
+            if provider.samples >= self.config.min_samples && error_rate > self.config.error_rate {
+                conditions.push((
+                    format!("{source}:error-rate:{}", provider.name),
+                    format!(
+                        "{source}: provider {} failing {:.0} % of {} recent calls",
+                        provider.name,
+                        error_rate * 100.0,
+                        provider.samples
+                    ),
+                ));
+            }
+        }
+        conditions
+    }
+
+    /// Tracks the conditions at `now`, the actions are due until [`sent`](Self::sent).
+    fn update(&self, now: Instant, providers: &[ProviderStatus]) -> Vec<Action> {
+        let holding: HashMap<String, String> = self.conditions(providers).into_iter().collect();
+        let mut incidents = self.incidents.lock().unwrap();
+        for (key, summary) in &holding {
+            let incident = incidents.entry(key.clone()).or_insert_with(|| Incident {
+                since: now,
+                clear_since: None,
+                paged: false,
+                summary: summary.clone(),
+            });
+            incident.clear_since = None;
+            incident.summary.clone_from(summary);
+        }
+        // one that never paged starts over the next time
+        incidents.retain(|key, incident| incident.paged || holding.contains_key(key));
+        let mut actions = Vec::new();
+        for (key, incident) in incidents.iter_mut() {
+            if holding.contains_key(key) {
+                if !incident.paged && now.duration_since(incident.since) >= self.hold {
+                    actions.push(Action::Trigger {
+                        key: key.clone(),
+                        summary: incident.summary.clone(),
+                    });
+                }
+                continue;
+            }
+            let clear_since = *incident.clear_since.get_or_insert(now);
+            if now.duration_since(clear_since) >= self.hold {
+                actions.push(Action::Resolve {
+                    key: key.clone(),
+                    summary: incident.summary.clone(),
+                });
+            }
+        }
+        actions.sort_by(|a, b| key(a).cmp(key(b)));
+        actions
+    }
+
+    fn sent(&self, action: &Action) {
+        let mut incidents = self.incidents.lock().unwrap();
+        match action {
+            Action::Trigger { key, .. } => {
+                if let Some(incident) = incidents.get_mut(key) {
+                    incident.paged = true;
+                }
+            }
+            Action::Resolve { key, .. } => {
+                incidents.remove(key);
+            }
+        }
+    }
+
+    /// Sends `action` to every configured integration.
+    async fn send(&self, action: &Action) -> Result<(), String> {
+        if let Some(routing_key) = &self.config.pagerduty_routing_key {
+            self.pagerduty(routing_key, action).await?;
+        }
+        if let Some(api_key) = &self.config.opsgenie_api_key {
+            self.opsgenie(api_key, action).await?;
+        }
+        // last, a retry after a failed page would email again
+        if let Some(mailer) = &self.mailer {
+            let (status, key, summary) = match action {
+                Action::Trigger { key, summary } => ("triggered", key, summary),
+                Action::Resolve { key, summary } => ("resolved", key, summary),
+            };
+            let incident = smtp::Incident {
+                source: &self.config.source,
+                status,
+                key,
+                summary,
+            };
+            mailer.send(&incident).await?;
+        }
+        Ok(())
+    }
+
+    async fn pagerduty(&self, routing_key: &str, action: &Action) -> Result<(), String> {
+        let body = match action {
+            Action::Trigger { key, summary } => json!({
+                "routing_key": routing_key,
+                "event_action": "trigger",
+                "dedup_key": key,
+                "payload": {
+                    "summary": summary,
+                    "source": self.config.source,
+                    "severity": "critical",
+                },
+            }),
+            Action::Resolve { key, .. } => json!({
+                "routing_key": routing_key,
+                "event_action": "resolve",
+                "dedup_key": key,
+            }),
+        };
+        let response = self
+            .http
+            .post(&self.config.pagerduty_url)
+            .json(&body)
+            .send()
+            .await
+            .map_err(|e| format!("pagerduty: {e}"))?;
+        match response.status() {
+            status if status.is_success() => Ok(()),
+            status => Err(format!("pagerduty answered {status}")),
+        }
+    }
+
+    async fn opsgenie(&self, api_key: &str, action: &Action) -> Result<(), String> {
+        let mut url =
+            Url::parse(&self.config.opsgenie_url).map_err(|e| format!("opsgenie: {e}"))?;
+        let body = {
+            let mut segments = url
+                .path_segments_mut()
+                .map_err(|_| "opsgenie: invalid url".to_string())?;
+            segments.pop_if_empty().extend(["v2", "alerts"]);
+            match action {
+                Action::Trigger { key, summary } => json!({
+                    "message": summary,
+                    "alias": key,
+                    "source": self.config.source,
+                    "priority": "P1",
+                }),
+                Action::Resolve { key, .. } => {
+                    segments.extend([key.as_str(), "close"]);
+                    json!({ "source": self.config.source })
+                }
+            }
+        };
+        if matches!(action, Action::Resolve { .. }) {
+            url.set_query(Some("identifierType=alias"));
+        }
+        let response = self
+            .http
+            .post(url)
+            .header("authorization", format!("GenieKey {api_key}"))
+            .json(&body)
+            .send()
+            .await
+            .map_err(|e| format!("opsgenie: {e}"))?;
+        match response.status() {
+            status if status.is_success() => Ok(()),
+            status => Err(format!("opsgenie answered {status}")),
+        }
+    }
+}
+
+fn key(action: &Action) -> &str {
+    match action {
+        Action::Trigger { key, .. } | Action::Resolve { key, .. } => key,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::providers::health::CircuitState;
+    use axum::{extract::Path, http::HeaderMap, routing::post, Json, Router};
+    use serde_json::Value;
+    use tokio::sync::mpsc;
+
+    fn provider(name: &str, health: HealthState, success_rate: f64) -> ProviderStatus {
+        ProviderStatus {
+            name: name.into(),
+            kind: "mock".into(),
+            weight: 1,
+            cost: 0,
+            cost_per_call: 0.0,
+            capabilities: Vec::new(),
+            health,
+            circuit: CircuitState::Closed,
+            demoted: false,
+            samples: 50,
+            success_rate: Some(success_rate),
+            latency_p50_ms: None,
+            latency_p99_ms: None,
+            quota_remaining: None,
+        }
+    }
+
+    fn pager() -> Pager {
+        Pager::new(PagingConfig {
+            opsgenie_api_key: Some("key".into()),
+            ..Default::default()
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_update() {
+        let pager = pager();
+        let start = Instant::now();
+        let at = |mins: u64| start + Duration::from_secs(mins * 60);
+        let healthy = [provider("ipinfo", HealthState::Healthy, 0.99)];
+        let failing = [provider("ipinfo", HealthState::Degraded, 0.2)];
+        let down = [provider("ipinfo", HealthState::Down, 0.0)];
+
+        assert!(pager.update(at(0), &failing).is_empty());
+        assert!(pager.update(at(1), &healthy).is_empty());
+        assert!(pager.update(at(2), &failing).is_empty(), "Starts over");
+        assert!(pager.update(at(6), &failing).is_empty());
+        let actions = pager.update(at(7), &down);
+        assert_eq!(actions.len(), 1);
+        let Action::Trigger { key, summary } = &actions[0] else {
+            panic!("{actions:?}");
+        };
+        assert_eq!(key, "ip-service:error-rate:ipinfo");
+        assert!(summary.contains("100 % of 50"), "{summary}");
+        assert_eq!(pager.update(at(8), &down), actions, "Until sent");
+        pager.sent(&actions[0]);
+
+        // flapping keeps the incident open
+        assert!(pager.update(at(9), &healthy).is_empty());
+        assert!(pager.update(at(12), &failing).is_empty());
+        assert!(pager.update(at(13), &healthy).is_empty());
+        let actions = pager.update(at(18), &healthy);
+        assert_eq!(
+            actions,
+            vec![Action::Resolve {
+                key: "ip-service:error-rate:ipinfo".into(),
+                summary: "ip-service: provider ipinfo failing 80 % of 50 recent calls".into(),
+            }]
+        );
+        pager.sent(&actions[0]);
+        assert!(pager.update(at(19), &healthy).is_empty());
+        assert!(pager.incidents.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_conditions() {
+        let pager = pager();
+        let mut few = provider("ipapi", HealthState::Degraded, 0.1);
+        few.samples = 5;
+        let conditions = pager.conditions(&[few, provider("ipinfo", HealthState::Healthy, 0.6)]);
+        assert!(conditions.is_empty(), "Too few samples, error rate below");
+
+        let conditions = pager.conditions(&[
+            provider("ipapi", HealthState::Down, 0.6),
+            provider("ipinfo", HealthState::Down, 0.1),
+        ]);
+        let keys: Vec<&str> = conditions.iter().map(|(key, _)| key.as_str()).collect();
+        assert_eq!(
+            keys,
+            [
+                "ip-service:all-providers-down",
+                "ip-service:error-rate:ipinfo"
+            ]
+        );
+        assert!(conditions[0].1.contains("ipapi, ipinfo"));
+        assert!(pager.conditions(&[]).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_send() {
+        let (sent, mut received) = mpsc::unbounded_channel();
+        let pagerduty = sent.clone();
+        let app = Router::new()
+            .route(
+                "/v2/enqueue",
+                post(move |Json(body): Json<Value>| async move {
+                    pagerduty.send(("pagerduty".to_string(), body)).unwrap();
+                    "{}"
+                }),
+            )
+            .route(
+                "/v2/alerts",
+                post(
+                    move |headers: HeaderMap, Json(body): Json<Value>| async move {
+                        let key = headers["authorization"].to_str().unwrap().to_string();
+                        sent.send((key, body)).unwrap();
+                        "{}"
+                    },
+                ),
+            )
+            .route(
+                "/v2/alerts/:alias/close",
+                post(|Path(alias): Path<String>| async move {
+                    assert_eq!(alias, "host:all-providers-down");
+                    "{}"
+                }),
+            );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let pager = Pager::new(PagingConfig {
+            pagerduty_routing_key: Some("routing".into()),
+            opsgenie_api_key: Some("key".into()),
+            pagerduty_url: format!("http://127.0.0.1:{port}/v2/enqueue"),
+            opsgenie_url: format!("http://127.0.0.1:{port}/"),
+            source: "host".into(),
+            ..Default::default()
+        })
+        .unwrap();
+        let trigger = Action::Trigger {
+            key: "host:all-providers-down".into(),
+            summary: "host: all providers down (ipinfo)".into(),
+        };
+        pager.send(&trigger).await.unwrap();
+        let (_, body) = received.recv().await.unwrap();
+        assert_eq!(body["event_action"], "trigger");
+        assert_eq!(body["dedup_key"], "host:all-providers-down");
+        assert_eq!(body["payload"]["severity"], "critical");
+        let (key, body) = received.recv().await.unwrap();
+        assert_eq!(key, "GenieKey key");
+        assert_eq!(body["alias"], "host:all-providers-down");
+
+        let resolve = Action::Resolve {
+            key: "host:all-providers-down".into(),
+            summary: "host: all providers down (ipinfo)".into(),
+        };
+        pager.send(&resolve).await.unwrap();
+        let (_, body) = received.recv().await.unwrap();
+        assert_eq!(body["event_action"], "resolve");
+
+        let pager = Pager::new(PagingConfig {
+            pagerduty_routing_key: Some("routing".into()),
+            pagerduty_url: format!("http://127.0.0.1:{port}/missing"),
+            ..Default::default()
+        })
+        .unwrap();
+        let e = pager.send(&resolve).await.unwrap_err();
+        assert!(e.contains("404"), "{e}");
+    }
+}
@@ -0,0 +1,206 @@
+//! Passive DNS enrichment
+//!
+//! Domains historically resolved to an address, from a passive DNS source
+//! speaking the common output format of draft-dulaunoy-dnsop-passive-dns-cof:
+//! one JSON record per line with `rrname`, `rrtype`, `rdata`, `time_first`,
+//! `time_last` and `count`. CIRCL pDNS and most self-hosted collectors answer
+//! it. Queries only run when the caller asks for them and answers are cached.
+
+use crate::{cache::TtlCache, config::PassiveDnsConfig};
+use chrono::{DateTime, SecondsFormat};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, net::IpAddr, time::Duration};
+use tracing::warn;
+use utoipa::ToSchema;
+
+/// Domains seen on an address, most recently seen first.
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone, PartialEq, Default)]
+pub struct PassiveDns {
+    pub domains: Vec<PassiveDomain>,
+    /// Distinct domains known to the source, `domains` is cut to the configured bound.
+    pub total: usize,
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone, PartialEq)]
+pub struct PassiveDomain {
+    pub name: String,
+    /// RFC 3339 time of the first and last observation.
+    pub first_seen: Option<String>,
+    pub last_seen: Option<String>,
+    /// Observations, when the source counts them.
+    pub count: Option<u64>,
+}
+
+/// Record of the common output format.
+#[derive(Deserialize, Debug)]
+struct Record {
+    rrname: String,
+    rrtype: String,
+    rdata: serde_json::Value,
+    time_first: Option<i64>,
+    time_last: Option<i64>,
+    count: Option<u64>,
+}
+
+impl Record {
+    /// An A or AAAA record pointing at `ip`, `rdata` is a string or a list of them.
+    fn points_at(&self, ip: IpAddr) -> bool {
+        if !matches!(self.rrtype.as_str(), "A" | "AAAA") {
+            return false;
+        }
+        let matches = |value: &serde_json::Value| {
+            value
+                .as_str()
+                .and_then(|rdata| rdata.parse::<IpAddr>().ok())
+                == Some(ip)
+        };
+        match &self.rdata {
+            serde_json::Value::Array(values) => values.iter().any(matches),
+            value => matches(value),
+        }
+    }
+}
+
+pub struct PassiveDnsClient {
+    http: reqwest::Client,
+    config: PassiveDnsConfig,
+    cache: TtlCache<PassiveDns>,
+}
+
+impl PassiveDnsClient {
+    pub fn new(http: reqwest::Client, config: PassiveDnsConfig) -> Self {
+        let cache = TtlCache::new(
+            Duration::from_secs(config.cache_ttl_secs),
+            config.cache_capacity,
+        );
+        PassiveDnsClient {
+            http,
+            config,
+            cache,
+        }
+    }
+
+    /// Cached answer for `ip`, without a request.
+    pub fn cached(&self, ip: IpAddr) -> Option<PassiveDns> {
+        self.cache.get(&ip)
+    }
+
+    /// Domains seen on `ip`, `None` if the source could not be reached.
+    pub async fn domains(&self, ip: IpAddr) -> Option<PassiveDns> {
+        if let Some(domains) = self.cache.get(&ip) {
+            return Some(domains);
+        }
+        match self.request(ip).await {
+            Ok(domains) => {
+                self.cache.insert(ip, domains.clone());
+                Some(domains)
+            }
+            Err(e) => {
+                warn!("passive dns lookup failed ip={} error={}", ip, e);
+                None
+            }
+        }
+    }
+
+    async fn request(&self, ip: IpAddr) -> reqwest::Result<PassiveDns> {
+        let mut request = self
+            .http
+            .get(self.config.url.replace("{ip}", &ip.to_string()))
+            .timeout(Duration::from_millis(self.config.timeout_ms));
+        if let Some(username) = &self.config.username {
+            request = request.basic_auth(username, self.config.password.as_ref());
+        }
+        let response = request.send().await?;
+        // unknown addresses are answered with 404 by some sources
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(PassiveDns::default());
+        }
+        let body = response.error_for_status()?.text().await?;
+        Ok(summarize(ip, &body, self.config.max_domains))
+    }
+}
+
+/// One entry per domain of the records pointing at `ip`, keeping at most
+/// `max` of the most recently seen. The body is either one record per line
+/// or a JSON array of them.
+fn summarize(ip: IpAddr, body: &str, max: usize) -> PassiveDns {
+    let records: Vec<Record> = match serde_json::from_str(body) {
+        Ok(records) => records,
+        Err(_) => body
+            .lines()
+            .filter_map(|line| serde_json::from_str(line).ok())
+            .collect(),
+    };
+    let mut domains: HashMap<String, PassiveDomain> = HashMap::new();
+    for record in records.iter().filter(|record| record.points_at(ip)) {
+        let name = record.rrname.trim_end_matches('.').to_ascii_lowercase();
+        let first = record.time_first.and_then(rfc3339);
+        let last = record.time_last.and_then(rfc3339);
+        let domain = domains.entry(name.clone()).or_insert(PassiveDomain {
+            name,
+            first_seen: None,
+            last_seen: None,
+            count: None,
+        });
+        // RFC 3339 UTC timestamps compare as strings
+        domain.first_seen = match (domain.first_seen.take(), first) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+        domain.last_seen = match (domain.last_seen.take(), last) {
+            (Some(a), Some(b)) => Some(a.max(b)),
+            (a, b) => a.or(b),
+        };
+        if let Some(count) = record.count {
+            domain.count = Some(domain.count.unwrap_or(0) + count);
+        }
+    }
+    let total = domains.len();
+    let mut domains: Vec<PassiveDomain> = domains.into_values().collect();
+    domains.sort_by(|a, b| b.last_seen.cmp(&a.last_seen).then(a.name.cmp(&b.name)));
+    domains.truncate(max);
+    PassiveDns { domains, total }
+}
+
+fn rfc3339(timestamp: i64) -> Option<String> {
+    DateTime::from_timestamp(timestamp, 0)
+        .map(|time| time.to_rfc3339_opts(SecondsFormat::Secs, true))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEST_INPUT: &str = r#"{"count": 4, "time_first": 1500000000, "rrtype": "A", "rrname": "www.example.com.", "rdata": "192.0.2.10", "time_last": 1600000000}
+{"count": 2, "time_first": 1400000000, "rrtype": "A", "rrname": "WWW.example.com", "rdata": "192.0.2.10", "time_last": 1550000000}
+{"count": 1, "time_first": 1700000000, "rrtype": "A", "rrname": "mail.example.org", "rdata": ["192.0.2.99", "192.0.2.10"], "time_last": 1700000500}
+{"count": 9, "time_first": 1700000000, "rrtype": "NS", "rrname": "example.net", "rdata": "192.0.2.10", "time_last": 1700000500}
+{"count": 1, "time_first": 1700000000, "rrtype": "A", "rrname": "other.example", "rdata": "192.0.2.11", "time_last": 1700000500}
+not json
+"#;
+
+    #[test]
+    fn test_summarize() {
+        let ip = "192.0.2.10".parse().unwrap();
+        let pdns = summarize(ip, TEST_INPUT, 10);
+        assert_eq!(pdns.total, 2);
+        assert_eq!(pdns.domains[0].name, "mail.example.org");
+        let www = &pdns.domains[1];
+        assert_eq!(www.name, "www.example.com");
+        assert_eq!(www.count, Some(6));
+        assert_eq!(www.first_seen.as_deref(), Some("2014-05-13T16:53:20Z"));
+        assert_eq!(www.last_seen.as_deref(), Some("2020-09-13T12:26:40Z"));
+
+        let bounded = summarize(ip, TEST_INPUT, 1);
+        assert_eq!((bounded.domains.len(), bounded.total), (1, 2));
+    }
+
+    #[test]
+    fn test_array() {
+        let body = r#"[{"rrtype": "AAAA", "rrname": "v6.example", "rdata": "2001:db8::1"}]"#;
+        let pdns = summarize("2001:db8::1".parse().unwrap(), body, 10);
+        assert_eq!(pdns.domains[0].name, "v6.example");
+        assert_eq!(pdns.domains[0].last_seen, None);
+    }
+}
@@ -0,0 +1,349 @@
+//! Round trip time measurement
+//!
+//! ICMP echoes go over an unprivileged ping socket where
+//! `net.ipv4.ping_group_range` allows it, or else a raw socket with
+//! `CAP_NET_RAW`. When neither opens, the time of TCP connects to a port is
+//! measured instead, a refused connection answers as fast as an accepted one.
+//! Like the [`probe`](crate::probe) the fallback could scan ports, so it only
+//! connects to the configured ports, within a global rate and a rate per
+//! target, and private addresses are refused unless allowed.
+
+use crate::{
+    anycast::KM_PER_RTT_MS,
+    config::PingConfig,
+    error::Error,
+    ratelimit::{KeyedRateLimiter, RateLimiter},
+    traceroute::routable,
+};
+use serde::{Deserialize, Serialize};
+use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+use std::{
+    io::{self, ErrorKind},
+    net::{IpAddr, SocketAddr, UdpSocket},
+    time::{Duration, Instant},
+};
+use tracing::debug;
+use utoipa::ToSchema;
+
+/// Payload of an echo request.
+const PAYLOAD: &[u8; 16] = b"ip-service ping!";
+
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum PingMethod {
+    Icmp,
+    Tcp,
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone, PartialEq)]
+pub struct Ping {
+    #[schema(value_type = String)]
+    pub target: IpAddr,
+    pub method: PingMethod,
+    /// Port of the TCP connects.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub port: Option<u16>,
+    pub sent: u32,
+    pub received: u32,
+    pub loss_percent: f64,
+    pub min_ms: Option<f64>,
+    pub avg_ms: Option<f64>,
+    pub max_ms: Option<f64>,
+    /// Farthest the target can be for the fastest round trip, light in fiber
+    /// covers about 100 km per millisecond of it.
+    pub max_distance_km: Option<f64>,
+}
+
+impl Ping {
+    fn new(
+        target: IpAddr,
+        method: PingMethod,
+        port: Option<u16>,
+        rtts: &[Option<Duration>],
+    ) -> Self {
+        let ms: Vec<f64> = rtts
+            .iter()
+            .flatten()
+            .map(|rtt| rtt.as_secs_f64() * 1000.0)
+            .collect();
+        let sent = rtts.len() as u32;
+        let received = ms.len() as u32;
+        let min_ms = ms.iter().copied().reduce(f64::min);
+        Ping {
+            target,
+            method,
+            port,
+            sent,
+            received,
+            loss_percent: match sent {
+                0 => 0.0,
+                _ => f64::from(sent - received) * 100.0 / f64::from(sent),
+            },
+            min_ms,
+            avg_ms: (received > 0).then(|| ms.iter().sum::<f64>() / f64::from(received)),
+            max_ms: ms.iter().copied().reduce(f64::max),
+            max_distance_km: min_ms.map(|rtt| rtt * KM_PER_RTT_MS),
+        }
+    }
+}
+
+pub struct Pinger {
+    config: PingConfig,
+    limiter: RateLimiter,
+    targets: KeyedRateLimiter<IpAddr>,
+}
+
+impl Pinger {
+    pub fn new(config: PingConfig) -> Self {
+        let period = Duration::from_secs(config.period_secs);
+        Pinger {
+            limiter: RateLimiter::new(config.requests, period),
+            targets: KeyedRateLimiter::new(config.requests_per_target, period),
+            config,
+        }
+    }
+
+    pub fn config(&self) -> &PingConfig {
+        &self.config
+    }
+
+    /// `count` echoes to `target`, TCP connects to `port` when no ICMP socket opens.
+    pub async fn ping(&self, target: IpAddr, count: u32, port: u16) -> Result<Ping, Error> {
+        if port != self.config.tcp_port && !self.config.ports.contains(&port) {
+            return Err(Error::InvalidInput(format!(
+                "port {port} is not allowed, allowed ports: {:?}",
+                self.allowed_ports()
+            )));
+        }
+        if !self.config.allow_private && !routable(target) {
+            return Err(Error::InvalidInput(format!(
+                "{target} is not a public address"
+            )));
+        }
+        if !self.limiter.try_acquire() {
+            return Err(Error::RateLimited("too many pings".into()));
+        }
+        if !self.targets.try_acquire(target) {
+            return Err(Error::RateLimited(format!("too many pings of {target}")));
+        }
+        let timeout = Duration::from_millis(self.config.timeout_ms);
+        let interval = Duration::from_millis(self.config.interval_ms);
+        let icmp =
+            tokio::task::spawn_blocking(move || echo(target, count, timeout, interval)).await;
+        match icmp.map_err(|e| Error::Internal(e.to_string()))? {
+            Ok(rtts) => Ok(Ping::new(target, PingMethod::Icmp, None, &rtts)),
+            Err(e) => {
+                debug!("ping: no ICMP socket ({}), connecting to port {}", e, port);
+                let rtts = self.connect(target, count, port).await;
+                Ok(Ping::new(target, PingMethod::Tcp, Some(port), &rtts))
+            }
+        }
+    }
+
+    fn allowed_ports(&self) -> Vec<u16> {
+        let mut ports = self.config.ports.clone();
+        ports.push(self.config.tcp_port);
+        ports.sort_unstable();
+        ports.dedup();
+        ports
+    }
+
+    async fn connect(&self, target: IpAddr, count: u32, port: u16) -> Vec<Option<Duration>> {
+        let timeout = Duration::from_millis(self.config.timeout_ms);
+        let mut rtts = Vec::new();
+        for i in 0..count {
+            if i > 0 {
+                tokio::time::sleep(Duration::from_millis(self.config.interval_ms)).await;
+            }
+            let started = Instant::now();
+            let connect = tokio::net::TcpStream::connect((target, port));
+            let rtt = match tokio::time::timeout(timeout, connect).await {
+                Ok(Ok(_)) => Some(started.elapsed()),
+                Ok(Err(e)) if e.kind() == ErrorKind::ConnectionRefused => Some(started.elapsed()),
+                _ => None,
+            };
+            rtts.push(rtt);
+        }
+        rtts
+    }
+}
+
+/// Ping socket, or raw socket whose IPv4 reads start with the IP header.
+fn icmp_socket(target: IpAddr) -> io::Result<(UdpSocket, bool)> {
+    let (domain, protocol) = match target {
+        IpAddr::V4(_) => (Domain::IPV4, Protocol::ICMPV4),
+        IpAddr::V6(_) => (Domain::IPV6, Protocol::ICMPV6),
+    };
+    let (socket, raw) = match Socket::new(domain, Type::DGRAM, Some(protocol)) {
+        Ok(socket) => (socket, false),
+        Err(_) => (Socket::new(domain, Type::RAW, Some(protocol))?, true),
+    };
+    socket.connect(&SockAddr::from(SocketAddr::new(target, 0)))?;
+    // std only needs a datagram descriptor for `send` and `recv`
+    Ok((socket.into(), raw))
+}
+
+fn echo(
+    target: IpAddr,
+    count: u32,
+    timeout: Duration,
+    interval: Duration,
+) -> io::Result<Vec<Option<Duration>>> {
+    let (socket, raw) = icmp_socket(target)?;
+    // ping sockets replace the identifier with their own
+    let identifier = std::process::id() as u16;
+    let mut buf = [0u8; 1500];
+    let mut rtts = Vec::new();
+    for sequence in 0..count as u16 {
+        if sequence > 0 {
+            std::thread::sleep(interval);
+        }
+        let sent = Instant::now();
+        socket.send(&request(target, identifier, sequence))?;
+        let rtt = loop {
+            let left = timeout.saturating_sub(sent.elapsed());
+            if left.is_zero() {
+                break None;
+            }
+            socket.set_read_timeout(Some(left))?;
+            let len = match socket.recv(&mut buf) {
+                Ok(len) => len,
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                    break None;
+                }
+                Err(e) => return Err(e),
+            };
+            let mut reply = &buf[..len];
+            if raw && target.is_ipv4() {
+                let header = usize::from(reply.first().map_or(0, |b| b & 0x0f)) * 4;
+                reply = reply.get(header..).unwrap_or_default();
+            }
+            // a raw socket also reads the echoes of other processes
+            if is_reply(target, reply, raw.then_some(identifier), sequence) {
+                break Some(sent.elapsed());
+            }
+        };
+        rtts.push(rtt);
+    }
+    Ok(rtts)
+}
+
+/// Echo request, the kernel computes the ICMPv6 checksum.
+fn request(target: IpAddr, identifier: u16, sequence: u16) -> Vec<u8> {
+    let kind = match target {
+        IpAddr::V4(_) => 8,
+        IpAddr::V6(_) => 128,
+    };
+    let mut packet = vec![kind, 0, 0, 0];
+    packet.extend(identifier.to_be_bytes());
+    packet.extend(sequence.to_be_bytes());
+    packet.extend(PAYLOAD);
+    if target.is_ipv4() {
+        let sum = checksum(&packet);
+        packet[2..4].copy_from_slice(&sum.to_be_bytes());
+    }
+    packet
+}
+
+fn is_reply(target: IpAddr, packet: &[u8], identifier: Option<u16>, sequence: u16) -> bool {
+    let kind = match target {
+        IpAddr::V4(_) => 0,
+        IpAddr::V6(_) => 129,
+    };
+    let field = |at: usize| {
+        packet
+            .get(at..at + 2)
+            .map(|b| u16::from_be_bytes([b[0], b[1]]))
+    };
+    packet.first() == Some(&kind)
+        && field(6) == Some(sequence)
+        && identifier.is_none_or(|identifier| field(4) == Some(identifier))
+}
+
+/// RFC 1071 internet checksum.
+fn checksum(packet: &[u8]) -> u16 {
+    let mut sum: u32 = packet
+        .chunks(2)
+        .map(|pair| u32::from(u16::from_be_bytes([pair[0], *pair.get(1).unwrap_or(&0)])))
+        .sum();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_request() {
+        let target = "192.0.2.1".parse().unwrap();
+        let packet = request(target, 0x1234, 7);
+        assert_eq!(packet[..2], [8, 0]);
+        assert_eq!(packet[4..8], [0x12, 0x34, 0, 7]);
+        assert_eq!(checksum(&packet), 0, "a valid packet sums to zero");
+
+        let mut reply = packet.clone();
+        reply[0] = 0;
+        assert!(is_reply(target, &reply, Some(0x1234), 7));
+        assert!(is_reply(target, &reply, None, 7));
+        assert!(!is_reply(target, &reply, Some(0x4321), 7));
+        assert!(!is_reply(target, &reply, None, 8));
+        assert!(!is_reply(target, &packet, None, 7), "our own request");
+    }
+
+    #[tokio::test]
+    async fn test_limits() {
+        let localhost = "127.0.0.1".parse().unwrap();
+        let strict = Pinger::new(PingConfig::default());
+        assert!(matches!(
+            strict.ping(localhost, 1, 443).await,
+            Err(Error::InvalidInput(_))
+        ));
+
+        let pinger = Pinger::new(PingConfig {
+            ports: vec![9],
+            allow_private: true,
+            requests_per_target: 2,
+            interval_ms: 0,
+            ..Default::default()
+        });
+        assert!(matches!(
+            pinger.ping(localhost, 1, 22).await,
+            Err(Error::InvalidInput(_))
+        ));
+        pinger.ping(localhost, 1, 9).await.unwrap();
+        pinger.ping(localhost, 1, 443).await.unwrap();
+        assert!(matches!(
+            pinger.ping(localhost, 1, 9).await,
+            Err(Error::RateLimited(_))
+        ));
+    }
+
+    #[test]
+    fn test_summary() {
+        let rtts = [
+            Some(Duration::from_millis(10)),
+            None,
+            Some(Duration::from_millis(30)),
+            Some(Duration::from_millis(20)),
+        ];
+        let ping = Ping::new("192.0.2.1".parse().unwrap(), PingMethod::Icmp, None, &rtts);
+        assert_eq!((ping.sent, ping.received), (4, 3));
+        assert_eq!(ping.loss_percent, 25.0);
+        assert_eq!(ping.min_ms, Some(10.0));
+        assert_eq!(ping.avg_ms, Some(20.0));
+        assert_eq!(ping.max_ms, Some(30.0));
+        assert_eq!(ping.max_distance_km, Some(1000.0));
+
+        let lost = Ping::new(
+            "192.0.2.1".parse().unwrap(),
+            PingMethod::Tcp,
+            Some(443),
+            &[None],
+        );
+        assert_eq!(lost.loss_percent, 100.0);
+        assert_eq!((lost.min_ms, lost.avg_ms), (None, None));
+    }
+}
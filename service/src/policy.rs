@@ -0,0 +1,188 @@
+//! Target policy
+//!
+//! Named rules allow or deny lookups of the addresses in their prefixes, e.g.
+//! to keep the service from probing our own infrastructure. The first rule
+//! containing the target decides, targets no rule contains are allowed unless
+//! `deny_by_default` turns the rules into an allowlist. The rules start from
+//! the `[target_policy]` configuration and are replaced at runtime through
+//! `/admin/target-policy`, or reloaded from the configuration file.
+
+use crate::{
+    config::{PolicyAction, TargetPolicyConfig},
+    error::Error,
+};
+use ipnet::IpNet;
+use std::{
+    net::IpAddr,
+    sync::{Arc, RwLock},
+};
+use tracing::info;
+
+/// Policy name of the refusals no rule decided.
+pub const DEFAULT_POLICY: &str = "default";
+
+/// Rules with their prefixes parsed.
+pub(crate) struct Rules {
+    config: TargetPolicyConfig,
+    nets: Vec<Vec<IpNet>>,
+}
+
+impl Rules {
+    pub(crate) fn new(config: TargetPolicyConfig) -> Result<Self, String> {
+        let nets = config
+            .rules
+            .iter()
+            .map(|rule| {
+                if rule.name.is_empty() {
+                    return Err("target_policy: every rule needs a name".to_string());
+                }
+                rule.cidrs
+                    .iter()
+                    .map(|cidr| {
+                        cidr.parse::<IpNet>()
+                            .or_else(|_| cidr.parse::<IpAddr>().map(IpNet::from))
+                            .map_err(|_| {
+                                format!("target_policy: invalid prefix {cidr} in {}", rule.name)
+                            })
+                    })
+                    .collect()
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Rules { config, nets })
+    }
+
+    /// Name of the policy refusing `ip`, `None` when it is allowed.
+    fn refusal(&self, ip: IpAddr) -> Option<&str> {
+        let ip = ip.to_canonical();
+        let rule = self
+            .config
+            .rules
+            .iter()
+            .zip(&self.nets)
+            .find(|(_, nets)| nets.iter().any(|net| net.contains(&ip)))
+            .map(|(rule, _)| rule);
+        match rule {
+            Some(rule) if rule.action == PolicyAction::Deny => Some(&rule.name),
+            Some(_) => None,
+            None if self.config.deny_by_default => Some(DEFAULT_POLICY),
+            None => None,
+        }
+    }
+}
+
+pub struct TargetPolicy {
+    rules: RwLock<Arc<Rules>>,
+}
+
+impl TargetPolicy {
+    /// Policy of a configuration [`Config::load`](crate::config::Config::load)
+    /// validated, invalid prefixes are dropped otherwise.
+    pub fn new(config: TargetPolicyConfig) -> Self {
+        let rules = Rules::new(config).unwrap_or_else(|_| Rules {
+            config: TargetPolicyConfig::default(),
+            nets: Vec::new(),
+        });
+        TargetPolicy {
+            rules: RwLock::new(Arc::new(rules)),
+        }
+    }
+
+    pub fn get(&self) -> TargetPolicyConfig {
+        self.rules.read().unwrap().config.clone()
+    }
+
+    /// Replaces the rules, keeping the current ones when a prefix is invalid.
+    pub fn set(&self, config: TargetPolicyConfig) -> Result<(), Error> {
+        let rules = Rules::new(config).map_err(Error::InvalidInput)?;
+        info!("target policy: {} rules", rules.config.rules.len());
+        *self.rules.write().unwrap() = Arc::new(rules);
+        Ok(())
+    }
+
+    /// Refuses a lookup of `ip` the policy denies.
+    pub fn check(&self, ip: IpAddr) -> Result<(), Error> {
+        let rules = self.rules.read().unwrap().clone();
+        match rules.refusal(ip) {
+            Some(policy) => Err(Error::TargetRefused {
+                target: ip,
+                policy: policy.into(),
+            }),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::PolicyRule;
+
+    fn rule(name: &str, action: PolicyAction, cidrs: &[&str]) -> PolicyRule {
+        PolicyRule {
+            name: name.into(),
+            action,
+            cidrs: cidrs.iter().map(|cidr| cidr.to_string()).collect(),
+        }
+    }
+
+    fn refused(policy: &TargetPolicy, ip: &str) -> Option<String> {
+        match policy.check(ip.parse().unwrap()) {
+            Err(Error::TargetRefused { policy, .. }) => Some(policy),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_check() {
+        let policy = TargetPolicy::new(TargetPolicyConfig {
+            rules: vec![
+                rule("monitoring", PolicyAction::Allow, &["203.0.113.10"]),
+                rule(
+                    "infrastructure",
+                    PolicyAction::Deny,
+                    &["203.0.113.0/24", "2001:db8::/32"],
+                ),
+            ],
+            deny_by_default: false,
+        });
+        assert_eq!(
+            refused(&policy, "203.0.113.20").as_deref(),
+            Some("infrastructure")
+        );
+        assert_eq!(
+            refused(&policy, "::ffff:203.0.113.20").as_deref(),
+            Some("infrastructure")
+        );
+        assert_eq!(
+            refused(&policy, "2001:db8::1").as_deref(),
+            Some("infrastructure")
+        );
+        assert_eq!(
+            refused(&policy, "203.0.113.10"),
+            None,
+            "the first rule decides"
+        );
+        assert_eq!(refused(&policy, "8.8.8.8"), None);
+    }
+
+    #[test]
+    fn test_allowlist() {
+        let policy = TargetPolicy::new(TargetPolicyConfig::default());
+        assert_eq!(refused(&policy, "8.8.8.8"), None);
+        policy
+            .set(TargetPolicyConfig {
+                rules: vec![rule("customers", PolicyAction::Allow, &["198.51.100.0/24"])],
+                deny_by_default: true,
+            })
+            .unwrap();
+        assert_eq!(refused(&policy, "198.51.100.7"), None);
+        assert_eq!(refused(&policy, "8.8.8.8").as_deref(), Some(DEFAULT_POLICY));
+
+        let invalid = TargetPolicyConfig {
+            rules: vec![rule("broken", PolicyAction::Deny, &["300.0.0.0/8"])],
+            deny_by_default: false,
+        };
+        assert!(matches!(policy.set(invalid), Err(Error::InvalidInput(_))));
+        assert!(policy.get().deny_by_default, "the previous rules are kept");
+    }
+}
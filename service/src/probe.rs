@@ -0,0 +1,154 @@
+//! TCP reachability probe
+//!
+//! A single connect to an allowed port, bounded by a timeout. An accepted
+//! connection is closed right away, a reset means closed, no answer in time
+//! means a firewall dropped the SYN. The endpoint could be turned into a port
+//! scanner, it is limited to the configured ports, a global rate and a rate
+//! per target, and refuses private addresses unless allowed.
+
+use crate::{
+    config::ProbeConfig,
+    error::Error,
+    ratelimit::{KeyedRateLimiter, RateLimiter},
+    traceroute::routable,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    io::ErrorKind,
+    net::IpAddr,
+    time::{Duration, Instant},
+};
+use utoipa::ToSchema;
+
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum PortState {
+    /// The connection was accepted.
+    Open,
+    /// The host answered with a reset.
+    Closed,
+    /// No answer before the timeout, or the network is unreachable.
+    Filtered,
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone, PartialEq)]
+pub struct Probe {
+    #[schema(value_type = String)]
+    pub target: IpAddr,
+    pub port: u16,
+    pub state: PortState,
+    /// Time to the accept or the reset.
+    pub latency_ms: Option<f64>,
+}
+
+pub struct Prober {
+    config: ProbeConfig,
+    limiter: RateLimiter,
+    targets: KeyedRateLimiter<IpAddr>,
+}
+
+impl Prober {
+    pub fn new(config: ProbeConfig) -> Self {
+        let period = Duration::from_secs(config.period_secs);
+        Prober {
+            limiter: RateLimiter::new(config.requests, period),
+            targets: KeyedRateLimiter::new(config.requests_per_target, period),
+            config,
+        }
+    }
+
+    /// Connects to `port` of `target` once the request passes the limits.
+    pub async fn probe(&self, target: IpAddr, port: u16) -> Result<Probe, Error> {
+        if !self.config.ports.contains(&port) {
+            return Err(Error::InvalidInput(format!(
+                "port {port} is not allowed, allowed ports: {:?}",
+                self.config.ports
+            )));
+        }
+        if !self.config.allow_private && !routable(target) {
+            return Err(Error::InvalidInput(format!(
+                "{target} is not a public address"
+            )));
+        }
+        if !self.limiter.try_acquire() {
+            return Err(Error::RateLimited("too many probes".into()));
+        }
+        if !self.targets.try_acquire(target) {
+            return Err(Error::RateLimited(format!("too many probes of {target}")));
+        }
+        let timeout = Duration::from_millis(self.config.timeout_ms);
+        let started = Instant::now();
+        let connect = tokio::net::TcpStream::connect((target, port));
+        let (state, latency) = match tokio::time::timeout(timeout, connect).await {
+            Ok(Ok(_)) => (PortState::Open, Some(started.elapsed())),
+            Ok(Err(e)) if e.kind() == ErrorKind::ConnectionRefused => {
+                (PortState::Closed, Some(started.elapsed()))
+            }
+            Ok(Err(_)) | Err(_) => (PortState::Filtered, None),
+        };
+        Ok(Probe {
+            target,
+            port,
+            state,
+            latency_ms: latency.map(|latency| latency.as_secs_f64() * 1000.0),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn prober(ports: Vec<u16>) -> Prober {
+        Prober::new(ProbeConfig {
+            ports,
+            allow_private: true,
+            requests_per_target: 2,
+            ..Default::default()
+        })
+    }
+
+    #[tokio::test]
+    async fn test_states() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let open = listener.local_addr().unwrap().port();
+        // the port of a dropped listener refuses
+        let closed = {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            listener.local_addr().unwrap().port()
+        };
+        let prober = prober(vec![open, closed]);
+        let localhost = "127.0.0.1".parse().unwrap();
+
+        let probe = prober.probe(localhost, open).await.unwrap();
+        assert_eq!(probe.state, PortState::Open);
+        assert!(probe.latency_ms.is_some());
+        let probe = prober.probe(localhost, closed).await.unwrap();
+        assert_eq!(probe.state, PortState::Closed);
+    }
+
+    #[tokio::test]
+    async fn test_limits() {
+        let prober = prober(vec![9]);
+        let localhost = "127.0.0.1".parse().unwrap();
+        assert!(matches!(
+            prober.probe(localhost, 22).await,
+            Err(Error::InvalidInput(_))
+        ));
+        prober.probe(localhost, 9).await.unwrap();
+        prober.probe(localhost, 9).await.unwrap();
+        assert!(matches!(
+            prober.probe(localhost, 9).await,
+            Err(Error::RateLimited(_))
+        ));
+
+        let strict = Prober::new(ProbeConfig {
+            ports: vec![9],
+            ..Default::default()
+        });
+        assert!(matches!(
+            strict.probe(localhost, 9).await,
+            Err(Error::InvalidInput(_))
+        ));
+    }
+}
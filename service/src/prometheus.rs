@@ -0,0 +1,341 @@
+//! Latency histograms in the Prometheus text format
+//!
+//! `/metrics` answers JSON counters, `/metrics/prometheus` the histograms of
+//! the API requests and of the provider calls, bucketed at the
+//! `metrics.buckets_ms` bounds so they meet the latency objectives, next to
+//! the failed provider calls counted by error class. The provider calls are
+//! labelled by provider up to `max_provider_labels` distinct names, the
+//! further ones share `provider="other"`. With a
+//! `[statsd]` agent the observations are sent there too, see [`Statsd`].
+
+use crate::{config::MetricsConfig, statsd::Statsd};
+use axum::{
+    extract::{Request, State},
+    middleware::Next,
+    response::Response,
+};
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+/// Media type of the exposition.
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// Label of the providers past the limit.
+const OTHER: &str = "other";
+
+/// Point in time counts of a histogram, e.g. for the [OTLP export](crate::otlp).
+#[derive(Debug, Clone, PartialEq)]
+pub struct Counts {
+    /// Observations per bucket, not cumulative, the last one is `+Inf`.
+    pub buckets: Vec<u64>,
+    pub sum_secs: f64,
+}
+
+impl Counts {
+    pub fn count(&self) -> u64 {
+        self.buckets.iter().sum()
+    }
+}
+
+#[derive(Debug)]
+struct Histogram {
+    /// Observations per bucket, the last one is `+Inf`.
+    counts: Vec<AtomicU64>,
+    sum_us: AtomicU64,
+}
+
+impl Histogram {
+    fn new(buckets: usize) -> Self {
+        Histogram {
+            counts: (0..=buckets).map(|_| AtomicU64::new(0)).collect(),
+            sum_us: AtomicU64::new(0),
+        }
+    }
+
+    fn observe(&self, bounds_ms: &[f64], elapsed: Duration) {
+        let ms = elapsed.as_secs_f64() * 1_000.0;
+        let bucket = bounds_ms.partition_point(|bound| *bound < ms);
+        self.counts[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum_us
+            .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    fn counts(&self) -> Counts {
+        Counts {
+            buckets: self
+                .counts
+                .iter()
+                .map(|count| count.load(Ordering::Relaxed))
+                .collect(),
+            sum_secs: self.sum_us.load(Ordering::Relaxed) as f64 / 1_000_000.0,
+        }
+    }
+
+    /// Cumulative buckets, sum and count of the series `name{label}`.
+    fn render(&self, out: &mut String, name: &str, label: Option<(&str, &str)>, bounds_ms: &[f64]) {
+        let (series, prefix) = match label {
+            Some((label, value)) => {
+                let pair = format!("{label}=\"{}\"", escape(value));
+                (format!("{{{pair}}}"), format!("{pair},"))
+            }
+            None => (String::new(), String::new()),
+        };
+        let mut cumulative = 0;
+        let bounds = bounds_ms
+            .iter()
+            .map(|ms| (ms / 1_000.0).to_string())
+            .chain(["+Inf".to_string()]);
+        for (count, le) in self.counts.iter().zip(bounds) {
+            cumulative += count.load(Ordering::Relaxed);
+            let _ = writeln!(out, "{name}_bucket{{{prefix}le=\"{le}\"}} {cumulative}");
+        }
+        let sum = self.sum_us.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+        let _ = writeln!(out, "{name}_sum{series} {sum}");
+        let _ = writeln!(out, "{name}_count{series} {cumulative}");
+    }
+}
+
+#[derive(Debug)]
+pub struct LatencyMetrics {
+    bounds_ms: Vec<f64>,
+    max_provider_labels: usize,
+    requests: Histogram,
+    provider_calls: Mutex<BTreeMap<String, Arc<Histogram>>>,
+    /// Failed calls by provider label and error class.
+    provider_errors: Mutex<BTreeMap<(String, &'static str), u64>>,
+    statsd: Option<Statsd>,
+}
+
+impl LatencyMetrics {
+    /// Histograms of the configured buckets, which [`Config::load`](crate::config::Config::load) validated.
+    pub fn new(config: &MetricsConfig) -> Self {
+        LatencyMetrics {
+            bounds_ms: config.buckets_ms.clone(),
+            max_provider_labels: config.max_provider_labels,
+            requests: Histogram::new(config.buckets_ms.len()),
+            provider_calls: Mutex::new(BTreeMap::new()),
+            provider_errors: Mutex::new(BTreeMap::new()),
+            statsd: None,
+        }
+    }
+
+    /// Also sends the observations to `statsd`.
+    pub fn with_statsd(mut self, statsd: Statsd) -> Self {
+        self.statsd = Some(statsd);
+        self
+    }
+
+    pub fn observe_request(&self, elapsed: Duration) {
+        self.requests.observe(&self.bounds_ms, elapsed);
+        if let Some(statsd) = &self.statsd {
+            statsd.timing("request.duration", elapsed, &[]);
+        }
+    }
+
+    /// Observes one call, failed with an error of `error_class` if any, see
+    /// [`ProviderError::class`](crate::providers::ProviderError::class).
+    pub fn observe_provider_call(
+        &self,
+        provider: &str,
+        elapsed: Duration,
+        error_class: Option<&'static str>,
+    ) {
+        let (label, histogram) = {
+            let mut calls = self.provider_calls.lock().unwrap();
+            let labelled = calls.keys().filter(|name| *name != OTHER).count();
+            let label = match calls.contains_key(provider) || labelled < self.max_provider_labels {
+                true => provider,
+                false => OTHER,
+            };
+            let histogram = calls
+                .entry(label.to_string())
+                .or_insert_with(|| Arc::new(Histogram::new(self.bounds_ms.len())))
+                .clone();
+            (label, histogram)
+        };
+        histogram.observe(&self.bounds_ms, elapsed);
+        if let Some(class) = error_class {
+            let mut errors = self.provider_errors.lock().unwrap();
+            *errors.entry((label.to_string(), class)).or_default() += 1;
+        }
+        if let Some(statsd) = &self.statsd {
+            statsd.timing("provider_call.duration", elapsed, &[("provider", label)]);
+            if let Some(class) = error_class {
+                let tags = [("provider", label), ("class", class)];
+                statsd.count("provider_call.errors", 1, &tags);
+            }
+        }
+    }
+
+    /// Upper bounds of the buckets but `+Inf`.
+    pub fn bounds_ms(&self) -> &[f64] {
+        &self.bounds_ms
+    }
+
+    pub fn request_counts(&self) -> Counts {
+        self.requests.counts()
+    }
+
+    /// Counts by provider label.
+    pub fn provider_call_counts(&self) -> Vec<(String, Counts)> {
+        let calls = self.provider_calls.lock().unwrap();
+        calls
+            .iter()
+            .map(|(provider, histogram)| (provider.clone(), histogram.counts()))
+            .collect()
+    }
+
+    /// Failed calls by provider label and error class.
+    pub fn provider_error_counts(&self) -> Vec<(String, &'static str, u64)> {
+        let errors = self.provider_errors.lock().unwrap();
+        errors
+            .iter()
+            .map(|((provider, class), count)| (provider.clone(), *class, *count))
+            .collect()
+    }
+
+    /// The exposition of every metric.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let name = "ip_service_request_duration_seconds";
+        let _ = writeln!(out, "# HELP {name} Handling time of the API requests.");
+        let _ = writeln!(out, "# TYPE {name} histogram");
+        self.requests.render(&mut out, name, None, &self.bounds_ms);
+
+        let name = "ip_service_provider_call_duration_seconds";
+        let _ = writeln!(
+            out,
+            "# HELP {name} Time of one call to a provider, by provider."
+        );
+        let _ = writeln!(out, "# TYPE {name} histogram");
+        for (provider, histogram) in self.provider_calls.lock().unwrap().iter() {
+            let label = Some(("provider", provider.as_str()));
+            histogram.render(&mut out, name, label, &self.bounds_ms);
+        }
+
+        let name = "ip_service_provider_call_errors_total";
+        let _ = writeln!(
+            out,
+            "# HELP {name} Failed calls to a provider, by provider and error class."
+        );
+        let _ = writeln!(out, "# TYPE {name} counter");
+        for (provider, class, count) in self.provider_error_counts() {
+            let provider = escape(&provider);
+            let _ = writeln!(
+                out,
+                "{name}{{provider=\"{provider}\",class=\"{class}\"}} {count}"
+            );
+        }
+        out
+    }
+}
+
+impl Default for LatencyMetrics {
+    fn default() -> Self {
+        LatencyMetrics::new(&MetricsConfig::default())
+    }
+}
+
+/// Label value with the backslashes, quotes and newlines escaped.
+pub(crate) fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Middleware observing the handling time of the rest of the stack.
+pub async fn observe(
+    State(metrics): State<Arc<LatencyMetrics>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let start = Instant::now();
+    let response = next.run(request).await;
+    metrics.observe_request(start.elapsed());
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn metrics(buckets_ms: Vec<f64>, max_provider_labels: usize) -> LatencyMetrics {
+        LatencyMetrics::new(&MetricsConfig {
+            buckets_ms,
+            max_provider_labels,
+        })
+    }
+
+    #[test]
+    fn test_buckets() {
+        let metrics = metrics(vec![10.0, 25.0, 250.0], 20);
+        for ms in [3, 10, 20, 100, 900] {
+            metrics.observe_request(Duration::from_millis(ms));
+        }
+        let out = metrics.render();
+        let lines = [
+            "ip_service_request_duration_seconds_bucket{le=\"0.01\"} 2",
+            "ip_service_request_duration_seconds_bucket{le=\"0.025\"} 3",
+            "ip_service_request_duration_seconds_bucket{le=\"0.25\"} 4",
+            "ip_service_request_duration_seconds_bucket{le=\"+Inf\"} 5",
+            "ip_service_request_duration_seconds_sum 1.033",
+            "ip_service_request_duration_seconds_count 5",
+        ];
+        for line in lines {
+            assert!(out.lines().any(|l| l == line), "{line} missing in\n{out}");
+        }
+    }
+
+    #[test]
+    fn test_provider_labels() {
+        let metrics = metrics(vec![100.0], 2);
+        for provider in ["ipapi", "ipinfo", "ipdata", "mock", "ipapi"] {
+            metrics.observe_provider_call(provider, Duration::from_millis(5), None);
+        }
+        let out = metrics.render();
+        let count = |provider: &str| {
+            let prefix = format!(
+                "ip_service_provider_call_duration_seconds_count{{provider=\"{provider}\"}} "
+            );
+            out.lines()
+                .find_map(|line| line.strip_prefix(&prefix))
+                .map(|count| count.to_string())
+        };
+        assert_eq!(count("ipapi").as_deref(), Some("2"));
+        assert_eq!(count("ipinfo").as_deref(), Some("1"));
+        assert_eq!(count("other").as_deref(), Some("2"), "ipdata and mock");
+        assert_eq!(count("mock"), None);
+        assert_eq!(escape("a\"b\\"), "a\\\"b\\\\");
+    }
+
+    #[test]
+    fn test_errors() {
+        let metrics = metrics(vec![100.0], 1);
+        let calls = [
+            ("ipapi", Some("timeout")),
+            ("ipapi", Some("timeout")),
+            ("ipapi", None),
+            ("ipinfo", Some("server_error")),
+        ];
+        for (provider, class) in calls {
+            metrics.observe_provider_call(provider, Duration::from_millis(5), class);
+        }
+        let out = metrics.render();
+        let lines = [
+            "# TYPE ip_service_provider_call_errors_total counter",
+            "ip_service_provider_call_errors_total{provider=\"ipapi\",class=\"timeout\"} 2",
+            "ip_service_provider_call_errors_total{provider=\"other\",class=\"server_error\"} 1",
+        ];
+        for line in lines {
+            assert!(out.lines().any(|l| l == line), "{line} missing in\n{out}");
+        }
+    }
+}
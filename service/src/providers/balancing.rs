@@ -0,0 +1,100 @@
+//! Weighted distribution of the lookups of a field profile
+//!
+//! By default every lookup starts with the heaviest healthy provider. A
+//! `[[balancing]]` profile spreads the lookups asking for its `fields`
+//! across several providers instead, e.g. 70% to an offline database and 30%
+//! to ip-api. The drawn provider goes first, the others stay in their usual
+//! order as fallbacks.
+
+use crate::{config::BalancingProfile, geo::Field};
+use uuid::Uuid;
+
+#[derive(Debug)]
+pub struct Balancing {
+    /// Source fields of the profile, every lookup when empty.
+    fields: Vec<Field>,
+    weights: Vec<(String, u32)>,
+}
+
+impl Balancing {
+    pub fn new(profile: BalancingProfile) -> Self {
+        Balancing {
+            fields: Field::sources(&profile.fields),
+            weights: profile.weights.into_iter().collect(),
+        }
+    }
+
+    /// Whether the profile covers a lookup of the source `fields`.
+    pub fn applies(&self, fields: &[Field]) -> bool {
+        match self.fields.is_empty() {
+            true => fields.is_empty(),
+            false => !fields.is_empty() && fields.iter().all(|f| self.fields.contains(f)),
+        }
+    }
+
+    /// Draws one of the providers `available` accepts by weight.
+    pub fn pick(&self, available: impl Fn(&str) -> bool) -> Option<&str> {
+        // v4 UUIDs come from the OS generator, as for the sampling
+        let (roll, _) = Uuid::new_v4().as_u64_pair();
+        self.pick_with(available, roll)
+    }
+
+    fn pick_with(&self, available: impl Fn(&str) -> bool, roll: u64) -> Option<&str> {
+        let weights: Vec<(&str, u64)> = self
+            .weights
+            .iter()
+            .filter(|(name, weight)| *weight > 0 && available(name))
+            .map(|(name, weight)| (name.as_str(), u64::from(*weight)))
+            .collect();
+        let total: u64 = weights.iter().map(|(_, weight)| weight).sum();
+        if total == 0 {
+            return None;
+        }
+        let mut roll = roll % total;
+        for (name, weight) in weights {
+            if roll < weight {
+                return Some(name);
+            }
+            roll -= weight;
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn balancing(fields: Vec<Field>) -> Balancing {
+        Balancing::new(BalancingProfile {
+            fields,
+            weights: [("mmdb".to_string(), 7), ("ipapi".to_string(), 3)].into(),
+        })
+    }
+
+    #[test]
+    fn test_applies() {
+        let balancing = self::balancing(vec![Field::Country, Field::Isp]);
+        assert!(balancing.applies(&[Field::Country]));
+        assert!(balancing.applies(&Field::sources(&[Field::Currency, Field::Isp])));
+        assert!(!balancing.applies(&[Field::Country, Field::City]));
+        assert!(!balancing.applies(&[]), "Every field");
+        assert!(self::balancing(Vec::new()).applies(&[]));
+    }
+
+    #[test]
+    fn test_pick() {
+        let balancing = balancing(Vec::new());
+        let picks: Vec<_> = (0..10)
+            .map(|roll| balancing.pick_with(|_| true, roll).unwrap())
+            .collect();
+        // in name order
+        assert_eq!(picks.iter().filter(|name| **name == "ipapi").count(), 3);
+        assert_eq!(picks[0], "ipapi");
+        assert_eq!(picks[9], "mmdb");
+
+        let picked = balancing.pick_with(|name| name != "mmdb", 9);
+        assert_eq!(picked, Some("ipapi"), "mmdb unavailable");
+        assert_eq!(balancing.pick_with(|_| false, 0), None);
+    }
+}
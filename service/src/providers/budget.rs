@@ -0,0 +1,181 @@
+//! Daily and monthly call budgets
+//!
+//! Counters reset at UTC midnight and on the first day of the month. They are
+//! kept in memory, a restart starts the period from zero.
+
+use crate::config::BudgetConfig;
+use chrono::{DateTime, Datelike, NaiveDate, Utc};
+use std::{sync::Mutex, time::Duration};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BudgetState {
+    Available,
+    /// Past the soft limit, the provider is only tried after the others.
+    Low,
+    /// No call left in the current day or month.
+    Exhausted,
+}
+
+#[derive(Default)]
+struct Usage {
+    day: Option<NaiveDate>,
+    daily: u64,
+    monthly: u64,
+}
+
+pub struct Budget {
+    daily: Option<u64>,
+    monthly: Option<u64>,
+    soft_limit: f64,
+    usage: Mutex<Usage>,
+}
+
+impl Budget {
+    pub fn new(config: BudgetConfig) -> Self {
+        Budget {
+            daily: config.daily,
+            monthly: config.monthly,
+            soft_limit: f64::from(config.soft_limit_pct.min(100)) / 100.0,
+            usage: Mutex::new(Usage::default()),
+        }
+    }
+
+    pub fn state(&self) -> BudgetState {
+        self.state_at(Utc::now())
+    }
+
+    /// Counts one call against the budget.
+    pub fn record(&self) {
+        self.record_at(Utc::now())
+    }
+
+    /// Calls left before the tighter of the two limits, `None` without limits.
+    pub fn remaining(&self) -> Option<u64> {
+        let (daily, monthly) = self.usage_at(Utc::now());
+        let left = |limit: Option<u64>, used: u64| limit.map(|l| l.saturating_sub(used));
+        match (left(self.daily, daily), left(self.monthly, monthly)) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        }
+    }
+
+    /// Time until the periods used up reset, `None` while calls are left.
+    pub fn resets_in(&self) -> Option<Duration> {
+        self.resets_in_at(Utc::now())
+    }
+
+    fn resets_in_at(&self, now: DateTime<Utc>) -> Option<Duration> {
+        let (daily, monthly) = self.usage_at(now);
+        let today = now.date_naive();
+        let tomorrow = today.succ_opt()?;
+        let next_month = match today.month() {
+            12 => NaiveDate::from_ymd_opt(today.year() + 1, 1, 1)?,
+            month => NaiveDate::from_ymd_opt(today.year(), month + 1, 1)?,
+        };
+        let spent = |limit: Option<u64>, used: u64| limit.is_some_and(|limit| used >= limit);
+        let reset = match (spent(self.monthly, monthly), spent(self.daily, daily)) {
+            (true, _) => next_month,
+            (false, true) => tomorrow,
+            (false, false) => return None,
+        };
+        let reset = reset.and_hms_opt(0, 0, 0)?.and_utc();
+        (reset - now).to_std().ok()
+    }
+
+    fn state_at(&self, now: DateTime<Utc>) -> BudgetState {
+        let (daily, monthly) = self.usage_at(now);
+        let ratio = |limit: Option<u64>, used: u64| match limit {
+            Some(0) => 1.0,
+            Some(limit) => used as f64 / limit as f64,
+            None => 0.0,
+        };
+        let used = ratio(self.daily, daily).max(ratio(self.monthly, monthly));
+        if used >= 1.0 {
+            BudgetState::Exhausted
+        } else if used >= self.soft_limit {
+            BudgetState::Low
+        } else {
+            BudgetState::Available
+        }
+    }
+
+    fn record_at(&self, now: DateTime<Utc>) {
+        let mut usage = self.usage.lock().unwrap();
+        roll(&mut usage, now.date_naive());
+        usage.daily += 1;
+        usage.monthly += 1;
+    }
+
+    fn usage_at(&self, now: DateTime<Utc>) -> (u64, u64) {
+        let mut usage = self.usage.lock().unwrap();
+        roll(&mut usage, now.date_naive());
+        (usage.daily, usage.monthly)
+    }
+}
+
+/// Resets the counters whose period ended before `today`.
+fn roll(usage: &mut Usage, today: NaiveDate) {
+    if let Some(day) = usage.day {
+        if (day.year(), day.month()) != (today.year(), today.month()) {
+            usage.monthly = 0;
+        }
+        if day != today {
+            usage.daily = 0;
+        }
+    }
+    usage.day = Some(today);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn budget(daily: Option<u64>, monthly: Option<u64>) -> Budget {
+        Budget::new(BudgetConfig {
+            daily,
+            monthly,
+            soft_limit_pct: 50,
+        })
+    }
+
+    fn at(month: u32, day: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, month, day, 12, 0, 0).unwrap()
+    }
+
+    #[test]
+    fn test_states() {
+        let budget = budget(Some(4), None);
+        assert_eq!(budget.state_at(at(1, 1)), BudgetState::Available);
+        budget.record_at(at(1, 1));
+        budget.record_at(at(1, 1));
+        assert_eq!(budget.state_at(at(1, 1)), BudgetState::Low);
+        budget.record_at(at(1, 1));
+        budget.record_at(at(1, 1));
+        assert_eq!(budget.state_at(at(1, 1)), BudgetState::Exhausted);
+        assert_eq!(budget.state_at(at(1, 2)), BudgetState::Available);
+    }
+
+    #[test]
+    fn test_monthly_reset() {
+        let budget = budget(None, Some(2));
+        budget.record_at(at(1, 30));
+        budget.record_at(at(1, 31));
+        assert_eq!(budget.state_at(at(1, 31)), BudgetState::Exhausted);
+        assert_eq!(budget.state_at(at(2, 1)), BudgetState::Available);
+    }
+
+    #[test]
+    fn test_resets_in() {
+        let budget = budget(Some(1), Some(2));
+        assert_eq!(budget.resets_in_at(at(1, 31)), None);
+        budget.record_at(at(1, 31));
+        let hours = |h: u64| Some(Duration::from_secs(h * 3600));
+        assert_eq!(budget.resets_in_at(at(1, 31)), hours(12), "At midnight");
+        budget.record_at(at(1, 31));
+        assert_eq!(budget.resets_in_at(at(1, 31)), hours(12), "Next month");
+        let budget = self::budget(None, Some(1));
+        budget.record_at(at(1, 15));
+        assert_eq!(budget.resets_in_at(at(1, 15)), hours(16 * 24 + 12));
+    }
+}
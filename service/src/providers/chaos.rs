@@ -0,0 +1,76 @@
+//! Fault injection for resilience testing
+//!
+//! When enabled, provider calls are delayed, failed with a 503 or answered
+//! with a truncated payload at the configured probabilities, exercising the
+//! fallback chain, the circuit breaker and the caches without a real outage.
+
+use crate::{config::ChaosConfig, sampling::chance};
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    /// Fail as if the provider answered 503.
+    Error,
+    /// Replace the payload with invalid JSON.
+    Malformed,
+}
+
+pub struct Chaos {
+    config: ChaosConfig,
+}
+
+impl Chaos {
+    /// `None` unless the configuration explicitly enables it.
+    pub fn new(config: ChaosConfig) -> Option<Self> {
+        config.enabled.then_some(Chaos { config })
+    }
+
+    fn applies(&self, provider: &str) -> bool {
+        self.config.providers.is_empty() || self.config.providers.iter().any(|p| p == provider)
+    }
+
+    /// Sleeps for the injected latency, then returns the fault to inject, if any.
+    pub async fn inject(&self, provider: &str) -> Option<Fault> {
+        if !self.applies(provider) {
+            return None;
+        }
+        if chance(self.config.latency_probability) {
+            tokio::time::sleep(Duration::from_millis(self.config.latency_ms)).await;
+        }
+        if chance(self.config.error_probability) {
+            return Some(Fault::Error);
+        }
+        if chance(self.config.malformed_probability) {
+            return Some(Fault::Malformed);
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(enabled: bool) -> ChaosConfig {
+        ChaosConfig {
+            enabled,
+            providers: vec!["ipinfo".into()],
+            latency_ms: 0,
+            latency_probability: 1.0,
+            error_probability: 1.0,
+            malformed_probability: 0.0,
+        }
+    }
+
+    #[test]
+    fn test_disabled() {
+        assert!(Chaos::new(config(false)).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_inject() {
+        let chaos = Chaos::new(config(true)).unwrap();
+        assert_eq!(chaos.inject("ipinfo").await, Some(Fault::Error));
+        assert_eq!(chaos.inject("ipapi").await, None);
+    }
+}
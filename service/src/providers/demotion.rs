@@ -0,0 +1,165 @@
+//! Demotion of a provider on its rolling error rate
+//!
+//! The circuit breaker reacts to consecutive outages; a provider failing one
+//! call in three never trips it but still slows every lookup down with its
+//! failures. Once the share of outages in its [`Health`] window reaches
+//! `error_rate`, the provider is demoted: tried after every other one, or not
+//! at all with `action = "disable"`. Every `probe_secs` it gets one call in
+//! its usual place; a successful probe restores it with a fresh window.
+
+use super::health::Health;
+use crate::config::{DemotionAction, DemotionConfig};
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use tracing::{info, warn};
+
+/// Where a provider goes in the order of a lookup.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Placement {
+    Normal,
+    /// Demoted, in its usual place for one call.
+    Probe,
+    /// Demoted, after the other providers.
+    Last,
+    /// Disabled until a probe succeeds.
+    Skipped,
+}
+
+#[derive(Default)]
+struct State {
+    /// Time of the demotion or of the last probe.
+    demoted_at: Option<Instant>,
+}
+
+pub struct Demotion {
+    error_rate: f64,
+    min_samples: usize,
+    action: DemotionAction,
+    probe_every: Duration,
+    state: Mutex<State>,
+}
+
+impl Demotion {
+    pub fn new(config: DemotionConfig) -> Self {
+        Demotion {
+            error_rate: config.error_rate,
+            min_samples: config.min_samples.max(1),
+            action: config.action,
+            probe_every: Duration::from_secs(config.probe_secs),
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    pub fn is_demoted(&self) -> bool {
+        self.state.lock().unwrap().demoted_at.is_some()
+    }
+
+    /// Demotes the provider once the error rate of `health` reaches the threshold.
+    pub fn check(&self, provider: &str, health: &Health) {
+        let snapshot = health.snapshot();
+        let Some(success_rate) = snapshot.success_rate else {
+            return;
+        };
+        let mut state = self.state.lock().unwrap();
+        let error_rate = 1.0 - success_rate;
+        if state.demoted_at.is_none()
+            && snapshot.samples >= self.min_samples
+            && error_rate >= self.error_rate
+        {
+            warn!(
+                "provider {} demoted, {:.0}% of the last {} calls failed",
+                provider,
+                error_rate * 100.0,
+                snapshot.samples
+            );
+            state.demoted_at = Some(Instant::now());
+        }
+    }
+
+    /// Placement for the next lookup, takes the probe slot when one is due.
+    pub fn place(&self) -> Placement {
+        let mut state = self.state.lock().unwrap();
+        match state.demoted_at {
+            None => Placement::Normal,
+            Some(at) if at.elapsed() >= self.probe_every => {
+                state.demoted_at = Some(Instant::now());
+                Placement::Probe
+            }
+            Some(_) if self.action == DemotionAction::Disable => Placement::Skipped,
+            Some(_) => Placement::Last,
+        }
+    }
+
+    /// Outcome of a probe call, a success restores the provider.
+    pub fn probed(&self, provider: &str, ok: bool, health: &Health) {
+        if !ok {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        if state.demoted_at.take().is_some() {
+            // the failures that demoted it would demote it again at once
+            health.clear();
+            info!("provider {} restored after a successful probe", provider);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::CircuitConfig;
+
+    fn demotion(action: DemotionAction, probe_secs: u64) -> Demotion {
+        Demotion::new(DemotionConfig {
+            error_rate: 0.3,
+            min_samples: 10,
+            action,
+            probe_secs,
+        })
+    }
+
+    fn health(failures: usize, calls: usize) -> Health {
+        let health = Health::new(CircuitConfig {
+            failure_threshold: 100,
+            open_secs: 30,
+        });
+        for call in 0..calls {
+            health.record(call % (calls / failures.max(1)) != 0, Duration::ZERO);
+        }
+        health
+    }
+
+    #[test]
+    fn test_demote() {
+        let demotion = demotion(DemotionAction::Demote, 60);
+        demotion.check("ipapi", &health(2, 8));
+        assert_eq!(demotion.place(), Placement::Normal, "Too few samples");
+        demotion.check("ipapi", &health(2, 20));
+        assert_eq!(demotion.place(), Placement::Normal, "10% of errors");
+        demotion.check("ipapi", &health(10, 20));
+        assert!(demotion.is_demoted());
+        assert_eq!(demotion.place(), Placement::Last);
+
+        let demotion = self::demotion(DemotionAction::Disable, 60);
+        demotion.check("ipapi", &health(10, 20));
+        assert_eq!(demotion.place(), Placement::Skipped);
+    }
+
+    #[test]
+    fn test_probe() {
+        let demotion = demotion(DemotionAction::Disable, 0);
+        let health = health(10, 20);
+        demotion.check("ipapi", &health);
+        assert_eq!(demotion.place(), Placement::Probe);
+        demotion.probed("ipapi", false, &health);
+        assert!(demotion.is_demoted(), "Failed probe");
+
+        demotion.probed("ipapi", true, &health);
+        assert!(!demotion.is_demoted());
+        assert_eq!(health.snapshot().samples, 0, "Fresh window");
+        demotion.check("ipapi", &health);
+        assert_eq!(demotion.place(), Placement::Normal);
+    }
+}
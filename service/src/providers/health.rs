@@ -0,0 +1,205 @@
+//! Rolling health and circuit breaker of a provider
+//!
+//! The last `WINDOW` calls give the success rate and latency percentiles.
+//! After `failure_threshold` consecutive outages the circuit opens and the
+//! provider is skipped for `open_secs`, then a single probe request decides
+//! whether it closes again.
+
+use crate::config::CircuitConfig;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::VecDeque,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use utoipa::ToSchema;
+
+const WINDOW: usize = 100;
+
+#[derive(Serialize, ToSchema, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CircuitState {
+    Closed,
+    /// Skipped until the cooldown ends.
+    Open,
+    /// Cooldown ended, the next request is a probe.
+    HalfOpen,
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum HealthState {
+    Healthy,
+    /// Closed circuit but less than 90% of the recent calls succeeded.
+    Degraded,
+    Down,
+}
+
+/// Point in time view of the rolling window.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HealthSnapshot {
+    pub state: HealthState,
+    pub circuit: CircuitState,
+    pub samples: usize,
+    pub success_rate: Option<f64>,
+    pub latency_p50_ms: Option<u64>,
+    pub latency_p99_ms: Option<u64>,
+}
+
+struct Sample {
+    ok: bool,
+    latency_ms: u64,
+}
+
+#[derive(Default)]
+struct Inner {
+    samples: VecDeque<Sample>,
+    consecutive_failures: u32,
+    opened_at: Option<Instant>,
+    probing: bool,
+}
+
+pub struct Health {
+    failure_threshold: u32,
+    open_for: Duration,
+    inner: Mutex<Inner>,
+}
+
+impl Health {
+    pub fn new(config: CircuitConfig) -> Self {
+        Health {
+            failure_threshold: config.failure_threshold.max(1),
+            open_for: Duration::from_secs(config.open_secs),
+            inner: Mutex::new(Inner::default()),
+        }
+    }
+
+    /// Whether a request may be sent, takes the probe slot of a half-open circuit.
+    pub fn allow(&self) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        match circuit(&inner, self.open_for) {
+            CircuitState::Closed => true,
+            CircuitState::Open => false,
+            CircuitState::HalfOpen if inner.probing => false,
+            CircuitState::HalfOpen => {
+                inner.probing = true;
+                true
+            }
+        }
+    }
+
+    /// Records a call, `ok` is false for outages (network errors, 5xx, garbage).
+    pub fn record(&self, ok: bool, latency: Duration) {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.samples.len() == WINDOW {
+            inner.samples.pop_front();
+        }
+        inner.samples.push_back(Sample {
+            ok,
+            latency_ms: latency.as_millis() as u64,
+        });
+        if ok {
+            inner.consecutive_failures = 0;
+            inner.opened_at = None;
+        } else {
+            inner.consecutive_failures += 1;
+            if inner.probing || inner.consecutive_failures >= self.failure_threshold {
+                inner.opened_at = Some(Instant::now());
+            }
+        }
+        inner.probing = false;
+    }
+
+    /// Forgets the recent calls, the circuit state is kept.
+    pub fn clear(&self) {
+        self.inner.lock().unwrap().samples.clear();
+    }
+
+    pub fn snapshot(&self) -> HealthSnapshot {
+        let inner = self.inner.lock().unwrap();
+        let circuit = circuit(&inner, self.open_for);
+        let samples = inner.samples.len();
+        let success_rate = (samples > 0)
+            .then(|| inner.samples.iter().filter(|s| s.ok).count() as f64 / samples as f64);
+        let mut latencies: Vec<u64> = inner.samples.iter().map(|s| s.latency_ms).collect();
+        latencies.sort_unstable();
+
+        let state = match (circuit, success_rate) {
+            (CircuitState::Open | CircuitState::HalfOpen, _) => HealthState::Down,
+            (CircuitState::Closed, Some(rate)) if rate < 0.9 => HealthState::Degraded,
+            (CircuitState::Closed, _) => HealthState::Healthy,
+        };
+        HealthSnapshot {
+            state,
+            circuit,
+            samples,
+            success_rate,
+            latency_p50_ms: percentile(&latencies, 50),
+            latency_p99_ms: percentile(&latencies, 99),
+        }
+    }
+}
+
+fn circuit(inner: &Inner, open_for: Duration) -> CircuitState {
+    match inner.opened_at {
+        None => CircuitState::Closed,
+        Some(at) if at.elapsed() < open_for => CircuitState::Open,
+        Some(_) => CircuitState::HalfOpen,
+    }
+}
+
+/// Nearest-rank percentile of sorted values.
+fn percentile(sorted: &[u64], p: usize) -> Option<u64> {
+    if sorted.is_empty() {
+        return None;
+    }
+    let rank = (p * sorted.len()).div_ceil(100).max(1);
+    Some(sorted[rank - 1])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn health(open_secs: u64) -> Health {
+        Health::new(CircuitConfig {
+            failure_threshold: 2,
+            open_secs,
+        })
+    }
+
+    #[test]
+    fn test_circuit_opens() {
+        let health = health(60);
+        health.record(false, Duration::from_millis(10));
+        assert!(health.allow());
+        health.record(false, Duration::from_millis(10));
+        assert!(!health.allow());
+        assert_eq!(health.snapshot().state, HealthState::Down);
+    }
+
+    #[test]
+    fn test_half_open_probe() {
+        let health = health(0);
+        health.record(false, Duration::ZERO);
+        health.record(false, Duration::ZERO);
+        assert_eq!(health.snapshot().circuit, CircuitState::HalfOpen);
+        assert!(health.allow());
+        assert!(!health.allow(), "only one probe at a time");
+        health.record(true, Duration::ZERO);
+        assert_eq!(health.snapshot().circuit, CircuitState::Closed);
+    }
+
+    #[test]
+    fn test_percentiles() {
+        let health = health(60);
+        for ms in 1..=100 {
+            health.record(ms != 1, Duration::from_millis(ms));
+        }
+        let snapshot = health.snapshot();
+        assert_eq!(snapshot.latency_p50_ms, Some(50));
+        assert_eq!(snapshot.latency_p99_ms, Some(99));
+        assert_eq!(snapshot.success_rate, Some(0.99));
+        assert_eq!(snapshot.state, HealthState::Healthy);
+    }
+}
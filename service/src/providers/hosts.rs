@@ -0,0 +1,167 @@
+//! Resolution of the provider hostnames
+//!
+//! reqwest asks the system resolver on every new connection, so a flaky one
+//! adds its latency to the upstream calls. [`Hosts`] resolves the hosts of the
+//! providers at startup through the `[dns]` resolver and caches the addresses
+//! for their TTL. While resolving a host again fails, its last addresses are
+//! used for `dns.stale_secs` more, past that the calls fail with
+//! [`ResolveFailed`], reported apart from the other request errors.
+
+use crate::dns::Resolver;
+use futures::future::join_all;
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use thiserror::Error;
+use tracing::{debug, warn};
+
+/// Error the client of the providers fails a request with, found back by
+/// [`ResolveFailed::find`].
+#[derive(Error, Debug, Clone, PartialEq)]
+#[error("{0}")]
+pub struct ResolveFailed(pub String);
+
+impl ResolveFailed {
+    /// The resolution failure behind an error of reqwest, if any.
+    pub fn find<'a>(e: &'a (dyn std::error::Error + 'static)) -> Option<&'a ResolveFailed> {
+        let mut source = Some(e);
+        while let Some(e) = source {
+            if let Some(failed) = e.downcast_ref::<ResolveFailed>() {
+                return Some(failed);
+            }
+            source = e.source();
+        }
+        None
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Cached {
+    addresses: Vec<IpAddr>,
+    valid_until: Instant,
+}
+
+pub struct Hosts {
+    resolver: Resolver,
+    cache: Mutex<HashMap<String, Cached>>,
+    stale: Duration,
+}
+
+impl Hosts {
+    pub fn new(resolver: Resolver, stale: Duration) -> Self {
+        Hosts {
+            resolver,
+            cache: Mutex::new(HashMap::new()),
+            stale,
+        }
+    }
+
+    /// The system resolver, without stale addresses.
+    pub fn system() -> Self {
+        Hosts::new(Resolver::system(), Duration::ZERO)
+    }
+
+    /// Addresses of `host`, IPv6 first, from the cache until their TTL ends.
+    pub async fn resolve(&self, host: &str) -> Result<Vec<IpAddr>, ResolveFailed> {
+        let now = Instant::now();
+        let cached = self.cache.lock().unwrap().get(host).cloned();
+        if let Some(cached) = cached.as_ref().filter(|cached| now < cached.valid_until) {
+            return Ok(cached.addresses.clone());
+        }
+        match self.resolver.addresses(host).await {
+            Ok((mut addresses, valid_until)) => {
+                // the connector tries the family of the first address first
+                addresses.sort_by_key(IpAddr::is_ipv4);
+                let fresh = Cached {
+                    addresses: addresses.clone(),
+                    valid_until,
+                };
+                self.cache.lock().unwrap().insert(host.to_string(), fresh);
+                Ok(addresses)
+            }
+            Err(e) => match cached.filter(|cached| now < cached.valid_until + self.stale) {
+                Some(cached) => {
+                    warn!("{}, using the addresses resolved before", e);
+                    Ok(cached.addresses)
+                }
+                None => Err(ResolveFailed(e.to_string())),
+            },
+        }
+    }
+
+    /// Resolves `hosts` ahead of the first calls.
+    pub async fn warm(&self, hosts: Vec<String>) {
+        let resolutions = hosts.iter().map(|host| async move {
+            match self.resolve(host).await {
+                Ok(addresses) => debug!("provider host {} resolved: {:?}", host, addresses),
+                Err(e) => warn!("provider host not resolved at startup: {}", e),
+            }
+        });
+        join_all(resolutions).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::providers::timing;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_resolve() {
+        let hosts = Hosts::system();
+        let addresses = hosts.resolve("localhost").await.unwrap();
+        assert!(addresses.iter().any(IpAddr::is_loopback));
+        assert!(hosts.cache.lock().unwrap().contains_key("localhost"));
+
+        let e = hosts.resolve("nonexistent.invalid").await.unwrap_err();
+        assert!(e.0.contains("nonexistent.invalid"));
+    }
+
+    #[tokio::test]
+    async fn test_stale() {
+        let hosts = Hosts::new(Resolver::system(), Duration::from_secs(60));
+        let expired = Cached {
+            addresses: vec![IpAddr::from([192, 0, 2, 1])],
+            valid_until: Instant::now() - Duration::from_secs(1),
+        };
+        let stale = Cached {
+            valid_until: Instant::now() - Duration::from_secs(120),
+            ..expired.clone()
+        };
+        {
+            let mut cache = hosts.cache.lock().unwrap();
+            cache.insert("expired.invalid".into(), expired);
+            cache.insert("stale.invalid".into(), stale);
+        }
+
+        let addresses = hosts.resolve("expired.invalid").await.unwrap();
+        assert_eq!(
+            addresses,
+            [IpAddr::from([192, 0, 2, 1])],
+            "Last addresses kept"
+        );
+        assert!(hosts.resolve("stale.invalid").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_find() {
+        let hosts = Arc::new(Hosts::system());
+        let http = timing::instrument(reqwest::Client::builder(), &hosts)
+            .build()
+            .unwrap();
+        let e = http
+            .get("http://nonexistent.invalid/")
+            .send()
+            .await
+            .unwrap_err();
+        let failed = ResolveFailed::find(&e).unwrap();
+        assert!(failed.0.contains("nonexistent.invalid"));
+
+        let e = http.get("http://127.0.0.1:9/").send().await.unwrap_err();
+        assert_eq!(ResolveFailed::find(&e), None, "Refused, not unresolved");
+    }
+}
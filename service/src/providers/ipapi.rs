@@ -0,0 +1,276 @@
+//! <https://ip-api.com> lookup provider
+
+use super::{Endpoint, Provider, ProviderError, Reply};
+use crate::{
+    anonymity::Signals,
+    carrier::Carrier,
+    geo::{parse_asn, Field, Geo},
+};
+use serde::Deserialize;
+use std::net::IpAddr;
+
+/// Free endpoint, HTTP only
+pub const BASE_URL: &str = "http://ip-api.com";
+/// Endpoint for keys of the pro plan
+pub const PRO_BASE_URL: &str = "https://pro.ip-api.com";
+
+/// Bits of the `fields` query parameter, see <https://ip-api.com/docs/api:json>
+mod fields {
+    pub const COUNTRY: u32 = 1 << 0;
+    pub const COUNTRY_CODE: u32 = 1 << 1;
+    pub const REGION: u32 = 1 << 2;
+    pub const REGION_NAME: u32 = 1 << 3;
+    pub const CITY: u32 = 1 << 4;
+    pub const ZIP: u32 = 1 << 5;
+    pub const LAT: u32 = 1 << 6;
+    pub const LON: u32 = 1 << 7;
+    pub const TIMEZONE: u32 = 1 << 8;
+    pub const ISP: u32 = 1 << 9;
+    pub const ORG: u32 = 1 << 10;
+    pub const AS: u32 = 1 << 11;
+    pub const REVERSE: u32 = 1 << 12;
+    pub const QUERY: u32 = 1 << 13;
+    pub const STATUS: u32 = 1 << 14;
+    pub const MESSAGE: u32 = 1 << 15;
+    pub const MOBILE: u32 = 1 << 16;
+    pub const PROXY: u32 = 1 << 17;
+    pub const DISTRICT: u32 = 1 << 19;
+    pub const CONTINENT: u32 = 1 << 20;
+    pub const CONTINENT_CODE: u32 = 1 << 21;
+    pub const ASNAME: u32 = 1 << 22;
+    pub const CURRENCY: u32 = 1 << 23;
+    pub const HOSTING: u32 = 1 << 24;
+    pub const OFFSET: u32 = 1 << 25;
+}
+
+/// Every field, the raw payload is part of the lookup response.
+const FIELDS: u32 = fields::STATUS
+    | fields::MESSAGE
+    | fields::QUERY
+    | fields::CONTINENT
+    | fields::CONTINENT_CODE
+    | fields::COUNTRY
+    | fields::COUNTRY_CODE
+    | fields::REGION
+    | fields::REGION_NAME
+    | fields::CITY
+    | fields::DISTRICT
+    | fields::ZIP
+    | fields::LAT
+    | fields::LON
+    | fields::TIMEZONE
+    | fields::OFFSET
+    | fields::CURRENCY
+    | fields::ISP
+    | fields::ORG
+    | fields::AS
+    | fields::ASNAME
+    | fields::REVERSE
+    | fields::MOBILE
+    | fields::PROXY
+    | fields::HOSTING;
+
+/// ip-api reply, `status` tells a lookup from an error.
+#[derive(Deserialize, Debug)]
+#[serde(tag = "status", rename_all = "lowercase")]
+enum IpApiResponse {
+    Success(Box<IpApiRecord>),
+    Fail { message: Option<String> },
+}
+
+/// Successful ip-api lookup, unknown values are empty strings.
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct IpApiRecord {
+    query: IpAddr,
+    continent: Option<String>,
+    continent_code: Option<String>,
+    country: Option<String>,
+    country_code: Option<String>,
+    /// Region code
+    region: Option<String>,
+    region_name: Option<String>,
+    city: Option<String>,
+    zip: Option<String>,
+    lat: Option<f64>,
+    lon: Option<f64>,
+    timezone: Option<String>,
+    offset: Option<i32>,
+    currency: Option<String>,
+    isp: Option<String>,
+    org: Option<String>,
+    /// `AS15169 Google LLC`
+    #[serde(rename = "as")]
+    as_number: Option<String>,
+    asname: Option<String>,
+    reverse: Option<String>,
+    mobile: Option<bool>,
+    proxy: Option<bool>,
+    hosting: Option<bool>,
+}
+
+/// IpApi lookup provider
+pub struct IpApi {
+    endpoint: Endpoint,
+}
+
+impl IpApi {
+    pub fn new(endpoint: Endpoint) -> Self {
+        IpApi { endpoint }
+    }
+}
+
+impl Provider for IpApi {
+    fn name(&self) -> &str {
+        &self.endpoint.name
+    }
+
+    fn endpoint(&self, ip: IpAddr) -> String {
+        let key = match &self.endpoint.api_key {
+            Some(key) => format!("&key={key}"),
+            None => "".to_string(),
+        };
+        format!("{}/json/{ip}?fields={FIELDS}{key}", self.endpoint.base_url)
+    }
+
+    fn capabilities(&self) -> &'static [Field] {
+        &[
+            Field::Continent,
+            Field::Country,
+            Field::Region,
+            Field::City,
+            Field::PostalCode,
+            Field::Location,
+            Field::Timezone,
+            Field::UtcOffset,
+            Field::Currency,
+            Field::Asn,
+            Field::Isp,
+            Field::Org,
+            Field::Hostname,
+            Field::Carrier,
+            Field::Anonymity,
+        ]
+    }
+
+    fn parse_reply(&self, raw: &serde_json::Value) -> Result<Reply, ProviderError> {
+        let record = match IpApiResponse::deserialize(raw).map_err(ProviderError::Parse)? {
+            IpApiResponse::Success(record) => record,
+            IpApiResponse::Fail { message } => {
+                let message = message.unwrap_or_else(|| "unknown".into());
+                return Err(ProviderError::Rejected(message));
+            }
+        };
+        let text = |value: Option<String>| value.filter(|v| !v.is_empty());
+
+        let mut geo = Geo::new(record.query, self.name());
+        geo.continent = text(record.continent);
+        geo.continent_code = text(record.continent_code);
+        geo.country = text(record.country);
+        geo.country_code = text(record.country_code);
+        geo.region = text(record.region_name);
+        geo.region_code = text(record.region);
+        geo.city = text(record.city);
+        geo.postal_code = text(record.zip);
+        geo.latitude = record.lat;
+        geo.longitude = record.lon;
+        geo.timezone = text(record.timezone);
+        geo.utc_offset = record.offset;
+        geo.currency = text(record.currency);
+        geo.asn = record.as_number.as_deref().and_then(parse_asn);
+        geo.as_name = text(record.asname);
+        geo.isp = text(record.isp);
+        geo.org = text(record.org);
+        geo.hostname = text(record.reverse);
+        if record.mobile == Some(true) {
+            // no carrier block, the mobile ISP is the carrier
+            geo.carrier = Some(Carrier {
+                name: geo.isp.clone().or(geo.org.clone()),
+                ..Default::default()
+            });
+        }
+
+        let signals = Signals {
+            proxy: record.proxy,
+            hosting: record.hosting,
+            ..Default::default()
+        };
+        Ok(Reply::new(geo, signals))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn provider() -> IpApi {
+        IpApi::new(Endpoint::new("ipapi", BASE_URL, None))
+    }
+
+    #[test]
+    fn test_endpoint() {
+        let ip = "8.8.8.8".parse().unwrap();
+        assert_eq!(
+            provider().endpoint(ip),
+            "http://ip-api.com/json/8.8.8.8?fields=66846719"
+        );
+        let pro = IpApi::new(Endpoint::new("pro", PRO_BASE_URL, Some("k".into())));
+        assert_eq!(
+            pro.endpoint(ip),
+            "https://pro.ip-api.com/json/8.8.8.8?fields=66846719&key=k"
+        );
+    }
+
+    #[test]
+    fn test_parse() {
+        let raw = json!({
+            "status": "success",
+            "query": "8.8.8.8",
+            "country": "United States",
+            "countryCode": "US",
+            "regionName": "Virginia",
+            "city": "Ashburn",
+            "lat": 39.03,
+            "lon": -77.5,
+            "as": "AS15169 Google LLC",
+            "asname": "GOOGLE",
+            "reverse": "",
+            "mobile": false,
+            "proxy": false,
+            "hosting": true
+        });
+        let Reply { geo, signals, .. } = provider().parse_reply(&raw).unwrap();
+        assert_eq!(geo.ip, "8.8.8.8".parse::<IpAddr>().unwrap());
+        assert_eq!(geo.asn, Some(15169));
+        assert_eq!(geo.country_code.as_deref(), Some("US"));
+        assert_eq!(signals.hosting, Some(true));
+        assert_eq!(geo.hostname, None);
+        assert_eq!(geo.carrier, None);
+    }
+
+    #[test]
+    fn test_fields() {
+        // the documented "all fields" value
+        assert_eq!(FIELDS, 66846719);
+    }
+
+    #[test]
+    fn test_parse_fail() {
+        let raw = json!({ "status": "fail", "message": "private range", "query": "10.0.0.1" });
+        let error = provider().parse_reply(&raw).unwrap_err();
+        assert_eq!(error.to_string(), "lookup rejected: private range");
+    }
+
+    #[test]
+    fn test_parse_schema_error() {
+        let raw = json!({ "status": "success", "query": "not an address" });
+        let error = provider().parse_reply(&raw).unwrap_err();
+        assert!(matches!(error, ProviderError::Parse(_)));
+        let raw = json!({ "status": "pending", "query": "8.8.8.8" });
+        assert!(matches!(
+            provider().parse_reply(&raw),
+            Err(ProviderError::Parse(_))
+        ));
+    }
+}
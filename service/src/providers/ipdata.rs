@@ -0,0 +1,266 @@
+//! <https://ipdata.co> lookup provider
+
+use super::{Endpoint, Provider, ProviderError, Reply};
+use crate::{
+    anonymity::Signals,
+    carrier::Carrier,
+    geo::{parse_asn, Field, Geo, Threat},
+};
+use serde::Deserialize;
+use std::net::IpAddr;
+
+/// <https://docs.ipdata.co/docs>
+#[derive(Deserialize, Debug)]
+struct IpDataResponse {
+    ip: String,
+    city: Option<String>,
+    region: Option<String>,
+    region_code: Option<String>,
+    country_name: Option<String>,
+    country_code: Option<String>,
+    continent_name: Option<String>,
+    continent_code: Option<String>,
+    latitude: Option<f64>,
+    longitude: Option<f64>,
+    postal: Option<String>,
+    asn: Option<Asn>,
+    /// Only set for mobile networks
+    carrier: Option<CarrierBlock>,
+    time_zone: Option<Timezone>,
+    threat: Option<ThreatBlock>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Asn {
+    asn: Option<String>,
+    name: Option<String>,
+    domain: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct CarrierBlock {
+    name: Option<String>,
+    mcc: Option<String>,
+    mnc: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Timezone {
+    name: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ThreatBlock {
+    #[serde(default)]
+    is_tor: bool,
+    #[serde(default)]
+    is_icloud_relay: bool,
+    #[serde(default)]
+    is_proxy: bool,
+    #[serde(default)]
+    is_datacenter: bool,
+    #[serde(default)]
+    is_anonymous: bool,
+    #[serde(default)]
+    is_known_attacker: bool,
+    #[serde(default)]
+    is_known_abuser: bool,
+    #[serde(default)]
+    is_threat: bool,
+    #[serde(default)]
+    is_bogon: bool,
+    #[serde(default)]
+    blocklists: Vec<Blocklist>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Blocklist {
+    name: Option<String>,
+}
+
+pub const BASE_URL: &str = "https://api.ipdata.co";
+
+/// IpData lookup provider
+pub struct IpData {
+    endpoint: Endpoint,
+}
+
+impl IpData {
+    pub fn new(endpoint: Endpoint) -> Self {
+        IpData { endpoint }
+    }
+}
+
+impl Provider for IpData {
+    fn name(&self) -> &str {
+        &self.endpoint.name
+    }
+
+    fn endpoint(&self, ip: IpAddr) -> String {
+        format!(
+            "{}/{ip}?api-key={}",
+            self.endpoint.base_url,
+            self.endpoint.key()
+        )
+    }
+
+    fn capabilities(&self) -> &'static [Field] {
+        &[
+            Field::Continent,
+            Field::Country,
+            Field::Region,
+            Field::City,
+            Field::PostalCode,
+            Field::Location,
+            Field::Timezone,
+            Field::Asn,
+            Field::Org,
+            Field::Carrier,
+            Field::Anonymity,
+            Field::Threat,
+        ]
+    }
+
+    fn parse_reply(&self, raw: &serde_json::Value) -> Result<Reply, ProviderError> {
+        let response = IpDataResponse::deserialize(raw).map_err(ProviderError::Parse)?;
+        let ip = response
+            .ip
+            .parse()
+            .map_err(|_| ProviderError::Rejected("reply without address".into()))?;
+
+        let mut geo = Geo::new(ip, self.name());
+        geo.continent = response.continent_name;
+        geo.continent_code = response.continent_code;
+        geo.country = response.country_name;
+        geo.country_code = response.country_code;
+        geo.region = response.region;
+        geo.region_code = response.region_code;
+        geo.city = response.city;
+        geo.postal_code = response.postal;
+        geo.latitude = response.latitude;
+        geo.longitude = response.longitude;
+        geo.timezone = response.time_zone.and_then(|tz| tz.name);
+        if let Some(asn) = response.asn {
+            geo.asn = asn.asn.as_deref().and_then(parse_asn);
+            geo.as_name = asn.name;
+            geo.org = asn.domain;
+        }
+        geo.carrier = response.carrier.map(|carrier| Carrier {
+            name: carrier.name,
+            mcc: carrier.mcc,
+            mnc: carrier.mnc,
+        });
+
+        let Some(block) = response.threat else {
+            return Ok(Reply::new(geo, Signals::default()));
+        };
+        let signals = Signals {
+            // ipdata reports VPNs as anonymous proxies
+            vpn: None,
+            proxy: Some(block.is_proxy || block.is_anonymous),
+            hosting: Some(block.is_datacenter),
+            relay: Some(block.is_tor || block.is_icloud_relay),
+        };
+        let threat = Threat {
+            is_tor: block.is_tor,
+            is_datacenter: block.is_datacenter,
+            is_anonymous: block.is_anonymous,
+            is_known_attacker: block.is_known_attacker,
+            is_known_abuser: block.is_known_abuser,
+            is_threat: block.is_threat,
+            is_bogon: block.is_bogon,
+            blocklists: block
+                .blocklists
+                .into_iter()
+                .filter_map(|list| list.name)
+                .collect(),
+        };
+        Ok(Reply {
+            geo,
+            signals,
+            threat: Some(threat),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn provider() -> IpData {
+        IpData::new(Endpoint::new("ipdata", BASE_URL, Some("key".into())))
+    }
+
+    const TEST_INPUT: &str = r#"
+{
+  "ip": "185.220.101.1",
+  "is_eu": true,
+  "city": "Berlin",
+  "region": "Land Berlin",
+  "region_code": "BE",
+  "country_name": "Germany",
+  "country_code": "DE",
+  "continent_name": "Europe",
+  "continent_code": "EU",
+  "latitude": 52.52,
+  "longitude": 13.4,
+  "postal": "10117",
+  "asn": {
+    "asn": "AS60729",
+    "name": "Stiftung Erneuerbare Freiheit",
+    "domain": "torproject.org",
+    "route": "185.220.101.0/24",
+    "type": "hosting"
+  },
+  "time_zone": { "name": "Europe/Berlin" },
+  "threat": {
+    "is_tor": true,
+    "is_icloud_relay": false,
+    "is_proxy": false,
+    "is_datacenter": true,
+    "is_anonymous": true,
+    "is_known_attacker": true,
+    "is_known_abuser": true,
+    "is_threat": true,
+    "is_bogon": false,
+    "blocklists": [
+      { "name": "Blocklist.de", "site": "https://www.blocklist.de", "type": "abuse" }
+    ]
+  }
+}
+"#;
+
+    #[test]
+    fn test_parse() {
+        let raw = serde_json::from_str(TEST_INPUT).unwrap();
+        let reply = provider().parse_reply(&raw).unwrap();
+        assert_eq!(reply.geo.asn, Some(60729));
+        assert_eq!(reply.geo.timezone.as_deref(), Some("Europe/Berlin"));
+        assert_eq!(reply.signals.relay, Some(true));
+        assert_eq!(reply.signals.hosting, Some(true));
+
+        let threat = reply.threat.unwrap();
+        assert!(threat.is_known_attacker);
+        assert_eq!(threat.blocklists, vec!["Blocklist.de".to_string()]);
+    }
+
+    #[test]
+    fn test_parse_without_threat() {
+        let raw = serde_json::json!({ "ip": "8.8.8.8", "country_code": "US" });
+        let reply = provider().parse_reply(&raw).unwrap();
+        assert!(reply.threat.is_none());
+        assert!(reply.geo.carrier.is_none());
+    }
+
+    #[test]
+    fn test_parse_carrier() {
+        let raw = serde_json::json!({
+            "ip": "172.56.0.1",
+            "country_code": "US",
+            "carrier": { "name": "T-Mobile", "mcc": "310", "mnc": "260" }
+        });
+        let carrier = provider().parse_reply(&raw).unwrap().geo.carrier.unwrap();
+        assert_eq!(carrier.mcc.as_deref(), Some("310"));
+        assert_eq!(carrier.mnc.as_deref(), Some("260"));
+    }
+}
@@ -0,0 +1,224 @@
+//! <https://ipgeolocation.io> lookup provider
+
+use super::{Endpoint, Provider, ProviderError, Reply};
+use crate::{
+    anonymity::Signals,
+    geo::{parse_asn, Field, Geo},
+};
+use serde::Deserialize;
+use std::net::IpAddr;
+
+/// <https://ipgeolocation.io/documentation/ip-geolocation-api.html>
+#[derive(Deserialize, Debug)]
+struct IpGeolocationResponse {
+    ip: String,
+    hostname: Option<String>,
+    continent_code: Option<String>,
+    continent_name: Option<String>,
+    country_code2: Option<String>,
+    country_name: Option<String>,
+    state_prov: Option<String>,
+    state_code: Option<String>,
+    city: Option<String>,
+    zipcode: Option<String>,
+    /// Coordinates are sent as strings
+    latitude: Option<String>,
+    longitude: Option<String>,
+    isp: Option<String>,
+    organization: Option<String>,
+    asn: Option<String>,
+    currency: Option<Currency>,
+    time_zone: Option<Timezone>,
+    message: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Currency {
+    code: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Timezone {
+    name: Option<String>,
+    /// Standard offset in hours, may be fractional (e.g. 5.5)
+    offset: Option<f64>,
+    /// Current offset in hours including daylight saving, newer API versions only
+    offset_with_dst: Option<f64>,
+    is_dst: Option<bool>,
+    /// Daylight saving shift in hours
+    dst_savings: Option<f64>,
+}
+
+impl Timezone {
+    /// Current offset from UTC in seconds.
+    fn current_offset(&self) -> Option<i32> {
+        let hours = match (self.offset_with_dst, self.offset) {
+            (Some(with_dst), _) => with_dst,
+            (None, Some(offset)) if self.is_dst == Some(true) => {
+                offset + self.dst_savings.unwrap_or(0.0)
+            }
+            (None, Some(offset)) => offset,
+            (None, None) => return None,
+        };
+        Some((hours * 3600.0).round() as i32)
+    }
+}
+
+pub const BASE_URL: &str = "https://api.ipgeolocation.io";
+
+/// IpGeolocation lookup provider
+pub struct IpGeolocation {
+    endpoint: Endpoint,
+}
+
+impl IpGeolocation {
+    pub fn new(endpoint: Endpoint) -> Self {
+        IpGeolocation { endpoint }
+    }
+}
+
+impl Provider for IpGeolocation {
+    fn name(&self) -> &str {
+        &self.endpoint.name
+    }
+
+    fn endpoint(&self, ip: IpAddr) -> String {
+        format!(
+            "{}/ipgeo?apiKey={}&ip={ip}",
+            self.endpoint.base_url,
+            self.endpoint.key()
+        )
+    }
+
+    fn capabilities(&self) -> &'static [Field] {
+        &[
+            Field::Continent,
+            Field::Country,
+            Field::Region,
+            Field::City,
+            Field::PostalCode,
+            Field::Location,
+            Field::Timezone,
+            Field::UtcOffset,
+            Field::Currency,
+            Field::Asn,
+            Field::Isp,
+            Field::Org,
+            Field::Hostname,
+        ]
+    }
+
+    fn parse_reply(&self, raw: &serde_json::Value) -> Result<Reply, ProviderError> {
+        let response = IpGeolocationResponse::deserialize(raw).map_err(ProviderError::Parse)?;
+        if let Some(message) = response.message {
+            return Err(ProviderError::Rejected(message));
+        }
+        let ip = response
+            .ip
+            .parse()
+            .map_err(|_| ProviderError::Rejected("reply without address".into()))?;
+
+        let mut geo = Geo::new(ip, self.name());
+        geo.continent = response.continent_name;
+        geo.continent_code = response.continent_code;
+        geo.country = response.country_name;
+        geo.country_code = response.country_code2;
+        geo.region = response.state_prov;
+        geo.region_code = response.state_code;
+        geo.city = response.city;
+        geo.postal_code = response.zipcode;
+        geo.latitude = response.latitude.and_then(|lat| lat.parse().ok());
+        geo.longitude = response.longitude.and_then(|lon| lon.parse().ok());
+        geo.asn = response.asn.as_deref().and_then(parse_asn);
+        geo.isp = response.isp;
+        geo.org = response.organization;
+        geo.hostname = response.hostname;
+        geo.currency = response.currency.and_then(|c| c.code);
+        if let Some(time_zone) = response.time_zone {
+            geo.utc_offset = time_zone.current_offset();
+            geo.timezone = time_zone.name;
+        }
+        Ok(Reply::new(geo, Signals::default()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn provider() -> IpGeolocation {
+        IpGeolocation::new(Endpoint::new("ipgeolocation", BASE_URL, Some("key".into())))
+    }
+
+    const TEST_INPUT: &str = r#"
+{
+  "ip": "8.8.8.8",
+  "hostname": "dns.google",
+  "continent_code": "NA",
+  "continent_name": "North America",
+  "country_code2": "US",
+  "country_code3": "USA",
+  "country_name": "United States",
+  "state_prov": "California",
+  "state_code": "US-CA",
+  "city": "Mountain View",
+  "zipcode": "94043-1351",
+  "latitude": "37.42240",
+  "longitude": "-122.08421",
+  "is_eu": false,
+  "calling_code": "+1",
+  "isp": "Google LLC",
+  "connection_type": "",
+  "organization": "Google LLC",
+  "asn": "AS15169",
+  "currency": { "code": "USD", "name": "US Dollar", "symbol": "$" },
+  "time_zone": {
+    "name": "America/Los_Angeles",
+    "offset": -8,
+    "offset_with_dst": -7,
+    "current_time": "2024-06-01 10:00:00.000-0700",
+    "is_dst": true,
+    "dst_savings": 1
+  }
+}
+"#;
+
+    #[test]
+    fn test_parse() {
+        let raw = serde_json::from_str(TEST_INPUT).unwrap();
+        let reply = provider().parse_reply(&raw).unwrap();
+        let geo = reply.geo;
+        assert_eq!(geo.latitude, Some(37.4224));
+        assert_eq!(geo.asn, Some(15169));
+        assert_eq!(geo.currency.as_deref(), Some("USD"));
+        assert_eq!(geo.timezone.as_deref(), Some("America/Los_Angeles"));
+        assert_eq!(geo.utc_offset, Some(-7 * 3600));
+    }
+
+    #[test]
+    fn test_offset_without_dst_field() {
+        let time_zone = Timezone {
+            name: Some("Asia/Kolkata".into()),
+            offset: Some(5.5),
+            offset_with_dst: None,
+            is_dst: Some(false),
+            dst_savings: Some(0.0),
+        };
+        assert_eq!(time_zone.current_offset(), Some(19800));
+
+        let time_zone = Timezone {
+            name: Some("Europe/Berlin".into()),
+            offset: Some(1.0),
+            offset_with_dst: None,
+            is_dst: Some(true),
+            dst_savings: Some(1.0),
+        };
+        assert_eq!(time_zone.current_offset(), Some(7200));
+    }
+
+    #[test]
+    fn test_parse_error() {
+        let raw = serde_json::json!({ "ip": "", "message": "Provided API key is not valid." });
+        assert!(provider().parse_reply(&raw).is_err());
+    }
+}
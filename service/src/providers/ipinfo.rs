@@ -0,0 +1,229 @@
+//! <https://ipinfo.io> lookup provider
+
+use super::{Endpoint, Provider, ProviderError, Reply};
+use crate::{
+    anonymity::Signals,
+    carrier::Carrier,
+    geo::{parse_asn, Field, Geo},
+};
+use reqwest::RequestBuilder;
+use serde::Deserialize;
+use std::net::IpAddr;
+
+/// <https://ipinfo.io/developers/responses>
+#[derive(Deserialize, Debug)]
+struct IpInfoResponse {
+    ip: String,
+    #[serde(default)]
+    bogon: bool,
+    hostname: Option<String>,
+    city: Option<String>,
+    region: Option<String>,
+    country: Option<String>,
+    loc: Option<String>,
+    /// `AS15169 Google LLC`, the free tier has no separate `asn` block
+    org: Option<String>,
+    postal: Option<String>,
+    timezone: Option<String>,
+    asn: Option<Asn>,
+    company: Option<Company>,
+    privacy: Option<Privacy>,
+    carrier: Option<CarrierBlock>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Asn {
+    asn: Option<String>,
+    name: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct CarrierBlock {
+    name: Option<String>,
+    mcc: Option<String>,
+    mnc: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Company {
+    name: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Privacy {
+    vpn: Option<bool>,
+    proxy: Option<bool>,
+    tor: Option<bool>,
+    relay: Option<bool>,
+    hosting: Option<bool>,
+}
+
+pub const BASE_URL: &str = "https://ipinfo.io";
+
+/// IpInfo lookup provider
+pub struct IpInfo {
+    endpoint: Endpoint,
+}
+
+impl IpInfo {
+    pub fn new(endpoint: Endpoint) -> Self {
+        IpInfo { endpoint }
+    }
+}
+
+impl Provider for IpInfo {
+    fn name(&self) -> &str {
+        &self.endpoint.name
+    }
+
+    fn endpoint(&self, ip: IpAddr) -> String {
+        format!("{}/{ip}/json", self.endpoint.base_url)
+    }
+
+    /// The token is optional, the free tier works without one
+    fn add_auth(&self, request: RequestBuilder) -> RequestBuilder {
+        match &self.endpoint.api_key {
+            Some(token) => request.bearer_auth(token),
+            None => request,
+        }
+    }
+
+    /// The privacy and carrier blocks need a paid plan.
+    fn capabilities(&self) -> &'static [Field] {
+        &[
+            Field::Country,
+            Field::Region,
+            Field::City,
+            Field::PostalCode,
+            Field::Location,
+            Field::Timezone,
+            Field::Asn,
+            Field::Org,
+            Field::Hostname,
+            Field::Carrier,
+            Field::Anonymity,
+        ]
+    }
+
+    fn parse_reply(&self, raw: &serde_json::Value) -> Result<Reply, ProviderError> {
+        let response = IpInfoResponse::deserialize(raw).map_err(ProviderError::Parse)?;
+        if response.bogon {
+            return Err(ProviderError::Rejected("bogon address".into()));
+        }
+        let ip = response
+            .ip
+            .parse()
+            .map_err(|_| ProviderError::Rejected("reply without address".into()))?;
+
+        let mut geo = Geo::new(ip, self.name());
+        geo.country_code = response.country;
+        geo.region = response.region;
+        geo.city = response.city;
+        geo.postal_code = response.postal;
+        geo.timezone = response.timezone;
+        geo.hostname = response.hostname;
+        if let Some((lat, lon)) = response.loc.as_deref().and_then(|loc| loc.split_once(',')) {
+            geo.latitude = lat.parse().ok();
+            geo.longitude = lon.parse().ok();
+        }
+        match response.asn {
+            Some(asn) => {
+                geo.asn = asn.asn.as_deref().and_then(parse_asn);
+                geo.as_name = asn.name;
+            }
+            None => {
+                geo.asn = response.org.as_deref().and_then(parse_asn);
+                // strip the `AS15169 ` prefix
+                geo.as_name = response
+                    .org
+                    .as_deref()
+                    .and_then(|org| org.split_once(' '))
+                    .map(|(_, name)| name.to_string());
+            }
+        }
+        geo.org = response
+            .company
+            .and_then(|c| c.name)
+            .or(geo.as_name.clone());
+        geo.carrier = response.carrier.map(|carrier| Carrier {
+            name: carrier.name,
+            mcc: carrier.mcc,
+            mnc: carrier.mnc,
+        });
+
+        let signals = match response.privacy {
+            Some(privacy) => Signals {
+                vpn: privacy.vpn,
+                proxy: privacy.proxy,
+                hosting: privacy.hosting,
+                relay: match (privacy.tor, privacy.relay) {
+                    (None, None) => None,
+                    (tor, relay) => Some(tor.unwrap_or(false) || relay.unwrap_or(false)),
+                },
+            },
+            None => Signals::default(),
+        };
+        Ok(Reply::new(geo, signals))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn provider() -> IpInfo {
+        IpInfo::new(Endpoint::new("ipinfo", BASE_URL, Some("token".into())))
+    }
+
+    const TEST_INPUT: &str = r#"
+{
+  "ip": "66.87.125.72",
+  "hostname": "ip-66-87-125-72.spfdma.spcsdns.net",
+  "city": "Springfield",
+  "region": "Massachusetts",
+  "country": "US",
+  "loc": "42.1015,-72.5898",
+  "org": "AS10507 Sprint Personal Communications Systems",
+  "postal": "01101",
+  "timezone": "America/New_York"
+}
+"#;
+
+    const PRIVACY_INPUT: &str = r#"
+{
+  "ip": "185.220.101.1",
+  "country": "DE",
+  "loc": "52.52,13.40",
+  "asn": { "asn": "AS60729", "name": "Stiftung Erneuerbare Freiheit", "type": "hosting" },
+  "privacy": { "vpn": false, "proxy": false, "tor": true, "relay": false, "hosting": true }
+}
+"#;
+
+    #[test]
+    fn test_parse() {
+        let raw = serde_json::from_str(TEST_INPUT).unwrap();
+        let Reply { geo, signals, .. } = provider().parse_reply(&raw).unwrap();
+        assert_eq!(geo.asn, Some(10507));
+        assert_eq!(
+            geo.as_name.as_deref(),
+            Some("Sprint Personal Communications Systems")
+        );
+        assert_eq!(geo.latitude, Some(42.1015));
+        assert_eq!(signals, Signals::default());
+    }
+
+    #[test]
+    fn test_parse_privacy() {
+        let raw = serde_json::from_str(PRIVACY_INPUT).unwrap();
+        let Reply { geo, signals, .. } = provider().parse_reply(&raw).unwrap();
+        assert_eq!(geo.asn, Some(60729));
+        assert_eq!(signals.relay, Some(true));
+        assert_eq!(signals.hosting, Some(true));
+    }
+
+    #[test]
+    fn test_parse_bogon() {
+        let raw = serde_json::json!({ "ip": "10.0.0.1", "bogon": true });
+        assert!(provider().parse_reply(&raw).is_err());
+    }
+}
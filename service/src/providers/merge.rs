@@ -0,0 +1,201 @@
+//! Merge of the answers of several providers
+//!
+//! With a `[merge]` section a lookup asks the first `sources` providers of
+//! its order at once and builds the answer field by field: for every group
+//! of [`MERGED`] the [`MergeStrategy`] picks the answer it is taken from.
+//! The first answer in lookup order gives the rest, the signals, the threat
+//! block and the raw payload, and names the provider. The [`Support`] of
+//! every field kept goes to the [confidence](crate::confidence).
+
+use super::ProviderLookup;
+use crate::{
+    config::MergeStrategyKind,
+    geo::{Field, MERGED},
+};
+use std::time::{Duration, SystemTime};
+
+/// One answer to merge.
+pub struct Source<'a> {
+    pub lookup: &'a ProviderLookup,
+    /// When the data was current, absent for offline providers whose data
+    /// age is unknown.
+    pub as_of: Option<SystemTime>,
+}
+
+/// How the merged answers backed the value of a field.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Support {
+    /// Answers with the value kept, its own included.
+    pub agreed: usize,
+    /// Answers supplying the field.
+    pub supplied: usize,
+    /// Age of the data of the value kept, absent when unknown.
+    pub age: Option<Duration>,
+}
+
+/// How a conflict between providers is resolved.
+pub trait MergeStrategy: Send + Sync {
+    /// Index of the source `field` is taken from. `candidates` are the
+    /// sources supplying the field, in lookup order, never empty.
+    fn pick(&self, field: Field, candidates: &[&Source]) -> usize;
+}
+
+/// The first provider in lookup order supplying the field.
+pub struct Priority;
+
+impl MergeStrategy for Priority {
+    fn pick(&self, _field: Field, _candidates: &[&Source]) -> usize {
+        0
+    }
+}
+
+/// The value most providers agree on, the first in lookup order on a tie.
+pub struct MajorityVote;
+
+impl MergeStrategy for MajorityVote {
+    fn pick(&self, field: Field, candidates: &[&Source]) -> usize {
+        let keys: Vec<Option<String>> = candidates
+            .iter()
+            .map(|source| source.lookup.geo.key(field))
+            .collect();
+        let votes = |key: &Option<String>| keys.iter().filter(|other| *other == key).count();
+        // max_by_key keeps the last maximum, ties go to the first in order
+        (0..keys.len())
+            .rev()
+            .max_by_key(|index| votes(&keys[*index]))
+            .unwrap_or(0)
+    }
+}
+
+/// The most recent answer supplying the field, live answers before offline ones.
+pub struct FreshestWins;
+
+impl MergeStrategy for FreshestWins {
+    fn pick(&self, _field: Field, candidates: &[&Source]) -> usize {
+        (0..candidates.len())
+            .rev()
+            .max_by_key(|index| candidates[*index].as_of)
+            .unwrap_or(0)
+    }
+}
+
+impl MergeStrategyKind {
+    pub fn strategy(&self) -> Box<dyn MergeStrategy> {
+        match self {
+            MergeStrategyKind::Majority => Box::new(MajorityVote),
+            MergeStrategyKind::Priority => Box::new(Priority),
+            MergeStrategyKind::Freshest => Box::new(FreshestWins),
+        }
+    }
+}
+
+/// Answer of `sources`, in lookup order and never empty, merged by `strategy`.
+pub fn merge(strategy: &dyn MergeStrategy, sources: &[Source]) -> ProviderLookup {
+    let now = SystemTime::now();
+    let mut merged = sources[0].lookup.clone();
+    merged.support.clear();
+    for field in MERGED {
+        let candidates: Vec<&Source> = sources
+            .iter()
+            .filter(|source| source.lookup.geo.key(*field).is_some())
+            .collect();
+        if candidates.is_empty() {
+            continue;
+        }
+        let picked = candidates[strategy.pick(*field, &candidates)];
+        merged.geo.copy_field(&picked.lookup.geo, *field);
+        let key = picked.lookup.geo.key(*field);
+        let support = Support {
+            agreed: candidates
+                .iter()
+                .filter(|source| source.lookup.geo.key(*field) == key)
+                .count(),
+            supplied: candidates.len(),
+            age: picked
+                .as_of
+                .map(|as_of| now.duration_since(as_of).unwrap_or_default()),
+        };
+        merged.support.push((*field, support));
+    }
+    merged.data_age = merged
+        .support
+        .iter()
+        .filter_map(|(_, support)| support.age)
+        .max();
+    merged
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geo::Geo;
+
+    fn lookup(provider: &str, country: &str, city: Option<&str>) -> ProviderLookup {
+        let mut geo = Geo::new("1.2.3.4".parse().unwrap(), provider);
+        geo.country_code = Some(country.into());
+        geo.city = city.map(str::to_string);
+        ProviderLookup {
+            geo,
+            ..ProviderLookup::unknown("1.2.3.4".parse().unwrap())
+        }
+    }
+
+    #[test]
+    fn test_strategies() {
+        let now = SystemTime::now();
+        let lookups = [
+            lookup("a", "DE", None),
+            lookup("b", "FR", Some("Paris")),
+            lookup("c", "FR", Some("Lyon")),
+        ];
+        let sources: Vec<Source> = lookups
+            .iter()
+            .zip([Some(now - Duration::from_secs(60)), None, Some(now)])
+            .map(|(lookup, as_of)| Source { lookup, as_of })
+            .collect();
+
+        let merged = merge(&MajorityVote, &sources);
+        assert_eq!(merged.geo.provider, "a");
+        assert_eq!(merged.geo.country_code.as_deref(), Some("FR"));
+        assert_eq!(merged.geo.city.as_deref(), Some("Paris"), "Tie");
+
+        let merged = merge(&Priority, &sources);
+        assert_eq!(merged.geo.country_code.as_deref(), Some("DE"));
+        assert_eq!(merged.geo.city.as_deref(), Some("Paris"), "a has no city");
+
+        let merged = merge(&FreshestWins, &sources);
+        assert_eq!(merged.geo.country_code.as_deref(), Some("FR"));
+        assert_eq!(merged.geo.city.as_deref(), Some("Lyon"));
+    }
+
+    #[test]
+    fn test_support() {
+        let now = SystemTime::now();
+        let lookups = [
+            lookup("a", "FR", Some("Paris")),
+            lookup("b", "FR", Some("Lyon")),
+            lookup("c", "FR", None),
+        ];
+        let sources: Vec<Source> = lookups
+            .iter()
+            .zip([Some(now - Duration::from_secs(3600)), Some(now), None])
+            .map(|(lookup, as_of)| Source { lookup, as_of })
+            .collect();
+        let merged = merge(&Priority, &sources);
+        let support = |field| {
+            merged
+                .support
+                .iter()
+                .find(|(f, _)| *f == field)
+                .map(|(_, support)| *support)
+                .unwrap()
+        };
+        let country = support(Field::Country);
+        assert_eq!((country.agreed, country.supplied), (3, 3));
+        let city = support(Field::City);
+        assert_eq!((city.agreed, city.supplied), (1, 2));
+        assert!(city.age.unwrap() >= Duration::from_secs(3600));
+        assert_eq!(merged.data_age, city.age, "the oldest value kept");
+        assert!(!merged.support.iter().any(|(f, _)| *f == Field::Asn));
+    }
+}
@@ -0,0 +1,370 @@
+//! Deterministic offline provider for tests and demos
+//!
+//! Known addresses are answered from built-in fixtures, any other address gets
+//! a synthetic answer derived from its bytes, so the same address always maps
+//! to the same location. No request ever leaves the process.
+
+use super::{Endpoint, Provider, ProviderError, Reply};
+use crate::{
+    anonymity::Signals,
+    carrier::Carrier,
+    geo::{Field, Geo},
+    sampling::stable_hash,
+};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::net::IpAddr;
+
+pub const BASE_URL: &str = "mock://fixtures";
+
+struct Location {
+    country: &'static str,
+    country_code: &'static str,
+    city: &'static str,
+    continent: &'static str,
+    latitude: f64,
+    longitude: f64,
+    timezone: &'static str,
+    utc_offset: i32,
+    currency: &'static str,
+}
+
+/// Locations used for addresses without a fixture.
+const SYNTHETIC: &[Location] = &[
+    Location {
+        country: "Germany",
+        country_code: "DE",
+        city: "Berlin",
+        continent: "Europe",
+        latitude: 52.52,
+        longitude: 13.405,
+        timezone: "Europe/Berlin",
+        utc_offset: 3600,
+        currency: "EUR",
+    },
+    Location {
+        country: "France",
+        country_code: "FR",
+        city: "Paris",
+        continent: "Europe",
+        latitude: 48.8566,
+        longitude: 2.3522,
+        timezone: "Europe/Paris",
+        utc_offset: 3600,
+        currency: "EUR",
+    },
+    Location {
+        country: "United States",
+        country_code: "US",
+        city: "New York",
+        continent: "North America",
+        latitude: 40.7128,
+        longitude: -74.006,
+        timezone: "America/New_York",
+        utc_offset: -18000,
+        currency: "USD",
+    },
+    Location {
+        country: "Japan",
+        country_code: "JP",
+        city: "Tokyo",
+        continent: "Asia",
+        latitude: 35.6762,
+        longitude: 139.6503,
+        timezone: "Asia/Tokyo",
+        utc_offset: 32400,
+        currency: "JPY",
+    },
+    Location {
+        country: "Brazil",
+        country_code: "BR",
+        city: "Sao Paulo",
+        continent: "South America",
+        latitude: -23.5505,
+        longitude: -46.6333,
+        timezone: "America/Sao_Paulo",
+        utc_offset: -10800,
+        currency: "BRL",
+    },
+    Location {
+        country: "Australia",
+        country_code: "AU",
+        city: "Sydney",
+        continent: "Oceania",
+        latitude: -33.8688,
+        longitude: 151.2093,
+        timezone: "Australia/Sydney",
+        utc_offset: 36000,
+        currency: "AUD",
+    },
+];
+
+/// Mock lookup provider
+pub struct Mock {
+    endpoint: Endpoint,
+}
+
+impl Mock {
+    pub fn new(endpoint: Endpoint) -> Self {
+        Mock { endpoint }
+    }
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct MockReply {
+    ip: String,
+    continent: Option<String>,
+    continent_code: Option<String>,
+    country: Option<String>,
+    country_code: Option<String>,
+    region: Option<String>,
+    region_code: Option<String>,
+    city: Option<String>,
+    postal_code: Option<String>,
+    latitude: Option<f64>,
+    longitude: Option<f64>,
+    timezone: Option<String>,
+    utc_offset: Option<i32>,
+    currency: Option<String>,
+    asn: Option<u32>,
+    as_name: Option<String>,
+    isp: Option<String>,
+    org: Option<String>,
+    hostname: Option<String>,
+    carrier: Option<String>,
+    vpn: bool,
+    proxy: bool,
+    hosting: bool,
+    relay: bool,
+}
+
+impl Provider for Mock {
+    fn name(&self) -> &str {
+        &self.endpoint.name
+    }
+
+    fn endpoint(&self, ip: IpAddr) -> String {
+        format!("{}/{ip}", self.endpoint.base_url)
+    }
+
+    fn capabilities(&self) -> &'static [Field] {
+        &[
+            Field::Continent,
+            Field::Country,
+            Field::Region,
+            Field::City,
+            Field::PostalCode,
+            Field::Location,
+            Field::Timezone,
+            Field::UtcOffset,
+            Field::Currency,
+            Field::Asn,
+            Field::Isp,
+            Field::Org,
+            Field::Hostname,
+            Field::Carrier,
+            Field::Anonymity,
+        ]
+    }
+
+    fn offline(&self, ip: IpAddr) -> Option<Value> {
+        Some(fixture(ip).unwrap_or_else(|| synthetic(ip)))
+    }
+
+    fn parse_reply(&self, raw: &Value) -> Result<Reply, ProviderError> {
+        let reply = MockReply::deserialize(raw).map_err(ProviderError::Parse)?;
+        let ip = reply
+            .ip
+            .parse()
+            .map_err(|_| ProviderError::Rejected("reply without address".into()))?;
+
+        let mut geo = Geo::new(ip, self.name());
+        geo.continent = reply.continent;
+        geo.continent_code = reply.continent_code;
+        geo.country = reply.country;
+        geo.country_code = reply.country_code;
+        geo.region = reply.region;
+        geo.region_code = reply.region_code;
+        geo.city = reply.city;
+        geo.postal_code = reply.postal_code;
+        geo.latitude = reply.latitude;
+        geo.longitude = reply.longitude;
+        geo.timezone = reply.timezone;
+        geo.utc_offset = reply.utc_offset;
+        geo.currency = reply.currency;
+        geo.asn = reply.asn;
+        geo.as_name = reply.as_name;
+        geo.isp = reply.isp;
+        geo.org = reply.org;
+        geo.hostname = reply.hostname;
+        geo.carrier = reply.carrier.map(|name| Carrier {
+            name: Some(name),
+            ..Default::default()
+        });
+
+        let signals = Signals {
+            vpn: Some(reply.vpn),
+            proxy: Some(reply.proxy),
+            hosting: Some(reply.hosting),
+            relay: Some(reply.relay),
+        };
+        Ok(Reply::new(geo, signals))
+    }
+}
+
+/// Built-in fixtures for well known addresses.
+fn fixture(ip: IpAddr) -> Option<Value> {
+    let google = |ip: &str| {
+        json!({
+            "ip": ip,
+            "continent": "North America",
+            "continent_code": "NA",
+            "country": "United States",
+            "country_code": "US",
+            "region": "California",
+            "region_code": "CA",
+            "city": "Mountain View",
+            "postal_code": "94043",
+            "latitude": 37.422,
+            "longitude": -122.085,
+            "timezone": "America/Los_Angeles",
+            "utc_offset": -28800,
+            "currency": "USD",
+            "asn": 15169,
+            "as_name": "GOOGLE",
+            "isp": "Google LLC",
+            "org": "Google Public DNS",
+            "hostname": "dns.google",
+            "hosting": true
+        })
+    };
+    let fixture = match ip.to_string().as_str() {
+        "8.8.8.8" => google("8.8.8.8"),
+        "2001:4860:4860::8888" => google("2001:4860:4860::8888"),
+        "1.1.1.1" => json!({
+            "ip": "1.1.1.1",
+            "continent": "Oceania",
+            "continent_code": "OC",
+            "country": "Australia",
+            "country_code": "AU",
+            "region": "Queensland",
+            "region_code": "QLD",
+            "city": "South Brisbane",
+            "postal_code": "4101",
+            "latitude": -27.4766,
+            "longitude": 153.0166,
+            "timezone": "Australia/Brisbane",
+            "utc_offset": 36000,
+            "currency": "AUD",
+            "asn": 13335,
+            "as_name": "CLOUDFLARENET",
+            "isp": "Cloudflare, Inc",
+            "org": "APNIC and Cloudflare DNS Resolver project",
+            "hostname": "one.one.one.one",
+            "hosting": true
+        }),
+        // documentation range, stands for an anonymizing proxy
+        "198.51.100.1" => json!({
+            "ip": "198.51.100.1",
+            "continent": "Europe",
+            "continent_code": "EU",
+            "country": "Netherlands",
+            "country_code": "NL",
+            "city": "Amsterdam",
+            "latitude": 52.3676,
+            "longitude": 4.9041,
+            "timezone": "Europe/Amsterdam",
+            "utc_offset": 3600,
+            "currency": "EUR",
+            "asn": 64496,
+            "as_name": "MOCK-VPN",
+            "isp": "Mock VPN Services",
+            "org": "Mock VPN Services",
+            "vpn": true,
+            "proxy": true,
+            "hosting": true
+        }),
+        // documentation range, stands for a mobile subscriber
+        "198.51.100.2" => json!({
+            "ip": "198.51.100.2",
+            "continent": "Europe",
+            "continent_code": "EU",
+            "country": "Germany",
+            "country_code": "DE",
+            "city": "Hamburg",
+            "latitude": 53.5511,
+            "longitude": 9.9937,
+            "timezone": "Europe/Berlin",
+            "utc_offset": 3600,
+            "currency": "EUR",
+            "asn": 64497,
+            "as_name": "MOCK-MOBILE",
+            "isp": "Telekom Deutschland GmbH",
+            "org": "Telekom Deutschland GmbH",
+            "carrier": "Telekom Deutschland GmbH"
+        }),
+        _ => return None,
+    };
+    Some(fixture)
+}
+
+/// Answer derived from the address bytes, stable across runs.
+fn synthetic(ip: IpAddr) -> Value {
+    let bytes = match ip {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => ip.octets().to_vec(),
+    };
+    let hash = stable_hash(&bytes);
+    let location = &SYNTHETIC[(hash % SYNTHETIC.len() as u64) as usize];
+    // private use AS numbers
+    let asn = 64512 + (hash % 1000) as u32;
+    json!({
+        "ip": ip.to_string(),
+        "continent": location.continent,
+        "country": location.country,
+        "country_code": location.country_code,
+        "city": location.city,
+        "latitude": location.latitude,
+        "longitude": location.longitude,
+        "timezone": location.timezone,
+        "utc_offset": location.utc_offset,
+        "currency": location.currency,
+        "asn": asn,
+        "as_name": format!("MOCK-AS{asn}"),
+        "isp": "Mock Networks",
+        "org": "Mock Networks"
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn provider() -> Mock {
+        Mock::new(Endpoint::new("mock", BASE_URL, None))
+    }
+
+    #[test]
+    fn test_fixture() {
+        let provider = provider();
+        let ip = "8.8.8.8".parse().unwrap();
+        let raw = provider.offline(ip).unwrap();
+        let Reply { geo, signals, .. } = provider.parse_reply(&raw).unwrap();
+        assert_eq!(geo.country_code.as_deref(), Some("US"));
+        assert_eq!(geo.asn, Some(15169));
+        assert_eq!(geo.provider, "mock");
+        assert_eq!(signals.hosting, Some(true));
+    }
+
+    #[test]
+    fn test_synthetic_is_stable() {
+        let provider = provider();
+        let ip = "203.0.113.7".parse().unwrap();
+        let first = provider.offline(ip).unwrap();
+        assert_eq!(first, provider.offline(ip).unwrap());
+        let Reply { geo, .. } = provider.parse_reply(&first).unwrap();
+        assert_eq!(geo.ip, ip);
+        assert!(geo.country_code.is_some());
+    }
+}
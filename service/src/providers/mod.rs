@@ -0,0 +1,469 @@
+//! Geolocation lookup providers
+//!
+//! Each provider only knows how to build its request and how to map its reply
+//! into the normalized `Geo` schema, sending the request and the fallback chain
+//! are shared.
+
+use crate::{
+    anonymity::Signals,
+    config::{Priority, UpstreamConfig},
+    error::Resource,
+    geo::{Field, Geo, Threat},
+    prometheus::LatencyMetrics,
+};
+use chaos::{Chaos, Fault};
+use hosts::ResolveFailed;
+use merge::Support;
+use public_ip_address::response::LookupResponse as CoreResponse;
+use recording::Recorder;
+use reqwest::{header::HeaderMap, Client, RequestBuilder, StatusCode};
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use thiserror::Error;
+use throttle::Throttle;
+use tokio::sync::{Semaphore, SemaphorePermit};
+use tracing::warn;
+
+pub mod balancing;
+pub mod budget;
+pub mod chaos;
+pub mod demotion;
+pub mod health;
+pub mod hosts;
+pub mod ipapi;
+pub mod ipdata;
+pub mod ipgeolocation;
+pub mod ipinfo;
+pub mod merge;
+pub mod mock;
+pub mod recording;
+pub mod registry;
+pub mod throttle;
+pub mod timing;
+
+/// `User-Agent` of the outbound requests, reqwest sends none and some
+/// providers refuse requests without one.
+pub const USER_AGENT: &str = concat!("ip-service/", env!("CARGO_PKG_VERSION"));
+
+/// Where and how to reach a provider, built from its registry entry.
+pub struct Endpoint {
+    /// Unique name of the registry entry.
+    pub name: String,
+    /// Base URL without a trailing slash.
+    pub base_url: String,
+    pub api_key: Option<String>,
+}
+
+impl Endpoint {
+    pub fn new(name: &str, base_url: &str, api_key: Option<String>) -> Self {
+        Endpoint {
+            name: name.to_string(),
+            base_url: base_url.trim_end_matches('/').to_string(),
+            api_key,
+        }
+    }
+
+    pub fn key(&self) -> &str {
+        self.api_key.as_deref().unwrap_or_default()
+    }
+}
+
+/// Provider trait to define the methods that a provider must implement
+pub trait Provider: Send + Sync {
+    /// Name used to select the provider in requests and responses.
+    fn name(&self) -> &str;
+    /// Returns the API endpoint for a target lookup.
+    fn endpoint(&self, ip: IpAddr) -> String;
+    /// Fields the provider can fill in.
+    fn capabilities(&self) -> &'static [Field];
+    /// Parses the reply into the normalized schema.
+    fn parse_reply(&self, raw: &serde_json::Value) -> Result<Reply, ProviderError>;
+
+    /// Add authentication to the request
+    fn add_auth(&self, request: RequestBuilder) -> RequestBuilder {
+        request
+    }
+
+    /// Payload answered without a request, only offline providers have one.
+    fn offline(&self, _ip: IpAddr) -> Option<serde_json::Value> {
+        None
+    }
+}
+
+/// Provider reply mapped into the normalized schema.
+#[derive(Debug)]
+pub struct Reply {
+    pub geo: Geo,
+    /// Anonymity flags reported by the provider.
+    pub signals: Signals,
+    /// Threat block, only some providers have one.
+    pub threat: Option<Threat>,
+}
+
+impl Reply {
+    pub fn new(geo: Geo, signals: Signals) -> Self {
+        Reply {
+            geo,
+            signals,
+            threat: None,
+        }
+    }
+}
+
+/// Result of a successful provider lookup.
+#[derive(Clone)]
+pub struct ProviderLookup {
+    pub geo: Geo,
+    pub signals: Signals,
+    pub threat: Option<Threat>,
+    /// Unmodified provider payload.
+    pub raw: serde_json::Value,
+    /// Answered by a fallback, or by a provider missing some requested fields.
+    pub degraded: bool,
+    /// How old the data can be, the `data_age_secs` of the provider.
+    pub data_age: Option<Duration>,
+    /// How the answers of a merge backed each field kept, empty unless merged.
+    pub support: Vec<(Field, Support)>,
+}
+
+impl ProviderLookup {
+    /// Stands in for the answer no provider gave in time, every field unknown.
+    pub fn unknown(ip: IpAddr) -> Self {
+        ProviderLookup {
+            geo: Geo::new(ip, "none"),
+            signals: Signals::default(),
+            threat: None,
+            raw: serde_json::Value::Null,
+            degraded: true,
+            data_age: None,
+            support: Vec::new(),
+        }
+    }
+
+    /// Wraps a lookup performed by the core library.
+    pub fn from_core(response: CoreResponse) -> Self {
+        let raw = serde_json::to_value(&response).unwrap_or_default();
+        let signals = Signals {
+            proxy: response.is_proxy,
+            ..Default::default()
+        };
+        ProviderLookup {
+            geo: response.into(),
+            signals,
+            threat: None,
+            raw,
+            degraded: false,
+            data_age: None,
+            support: Vec::new(),
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum ProviderError {
+    /// The request could not be sent or the body not read.
+    #[error("request failed: {0}")]
+    Request(reqwest::Error),
+    /// The host of the provider could not be resolved, the request was not sent.
+    #[error("{0}")]
+    Unresolved(ResolveFailed),
+    /// The provider is rate limiting us.
+    #[error("too many requests")]
+    TooManyRequests,
+    /// Any other unexpected HTTP status.
+    #[error("unexpected status: {0}")]
+    Status(StatusCode),
+    /// The configured rate limit is reached, the request was not sent.
+    #[error("local rate limit reached")]
+    RateLimited,
+    /// The daily or monthly budget is used up, the request was not sent.
+    #[error("budget exhausted")]
+    BudgetExhausted,
+    /// The circuit is open after repeated outages, the request was not sent.
+    #[error("circuit open")]
+    CircuitOpen,
+    /// The provider asked to wait for its window to reset, the request was not sent.
+    #[error("backing off after throttling")]
+    BackingOff,
+    /// No concurrency slot freed up within the queue timeout, the request was not sent.
+    #[error("too many requests in flight")]
+    Overloaded,
+    /// Replay mode without a usable recording.
+    #[error("replay failed: {0}")]
+    Replay(String),
+    /// The provider answered but refused the lookup (private range, invalid query...).
+    #[error("lookup rejected: {0}")]
+    Rejected(String),
+    /// The reply does not match the expected schema.
+    #[error("invalid reply: {0}")]
+    Parse(serde_json::Error),
+}
+
+impl ProviderError {
+    /// Class of the error in the metrics, bounded unlike the messages.
+    pub fn class(&self) -> &'static str {
+        match self {
+            ProviderError::Request(e) if e.is_timeout() => "timeout",
+            ProviderError::Request(e) if e.is_connect() => "connect",
+            ProviderError::Request(_) => "request",
+            ProviderError::Unresolved(_) => "dns",
+            ProviderError::TooManyRequests => "rate_limited",
+            ProviderError::Status(_) if self.is_quota() => "quota",
+            ProviderError::Status(status) if status.is_server_error() => "server_error",
+            ProviderError::Status(_) => "client_error",
+            ProviderError::Rejected(_) => "rejected",
+            ProviderError::Parse(_) => "parse",
+            ProviderError::Replay(_) => "replay",
+            ProviderError::RateLimited
+            | ProviderError::BudgetExhausted
+            | ProviderError::CircuitOpen
+            | ProviderError::BackingOff
+            | ProviderError::Overloaded => "not_sent",
+        }
+    }
+
+    /// The key is throttled or out of quota, another key may still work.
+    pub fn is_quota(&self) -> bool {
+        matches!(
+            self,
+            ProviderError::TooManyRequests
+                | ProviderError::Status(
+                    StatusCode::PAYMENT_REQUIRED | StatusCode::FORBIDDEN | StatusCode::LOCKED
+                )
+        )
+    }
+
+    /// Resource the call was refused or not sent for want of, `None` when it failed otherwise.
+    pub fn saturated(&self) -> Option<Resource> {
+        match self {
+            ProviderError::TooManyRequests | ProviderError::BackingOff => {
+                Some(Resource::ProviderRateLimit)
+            }
+            ProviderError::RateLimited => Some(Resource::RateLimit),
+            ProviderError::BudgetExhausted => Some(Resource::Budget),
+            ProviderError::Overloaded => Some(Resource::UpstreamConcurrency),
+            _ => None,
+        }
+    }
+
+    /// The provider is unreachable or broken, as opposed to answering with an error.
+    pub fn is_outage(&self) -> bool {
+        match self {
+            ProviderError::Request(_) | ProviderError::Unresolved(_) | ProviderError::Parse(_) => {
+                true
+            }
+            ProviderError::Status(status) => status.is_server_error(),
+            _ => false,
+        }
+    }
+}
+
+/// HTTP access shared by every provider.
+pub struct Transport {
+    http: Client,
+    /// Clients of the providers with their own route, by provider name.
+    routes: HashMap<String, Client>,
+    /// Headers configured per provider, by provider name.
+    headers: HashMap<String, HeaderMap>,
+    recorder: Option<Recorder>,
+    chaos: Option<Chaos>,
+    /// Calls taking longer are logged and counted, see [`timing`] for their phases.
+    slow_call: Option<Duration>,
+    /// Latency histograms of the calls, not observed when absent.
+    metrics: Option<Arc<LatencyMetrics>>,
+    /// Requests in flight over every provider, unlimited when absent.
+    permits: Option<Slots>,
+    queue_timeout: Duration,
+    bulk_share: f64,
+}
+
+/// Concurrency slots of an upstream limit, the bulk lookups hold at most a
+/// share of them so the interactive ones always find some free.
+pub struct Slots {
+    all: Semaphore,
+    bulk: Semaphore,
+}
+
+impl Slots {
+    fn new(max: usize, bulk_share: f64) -> Self {
+        let bulk = ((max as f64 * bulk_share) as usize).clamp(1, max);
+        Slots {
+            all: Semaphore::new(max),
+            bulk: Semaphore::new(bulk),
+        }
+    }
+
+    async fn acquire(&self, priority: Priority) -> [Option<SemaphorePermit<'_>>; 2] {
+        // the semaphores are never closed
+        let bulk = match priority {
+            Priority::Bulk => self.bulk.acquire().await.ok(),
+            Priority::Interactive => None,
+        };
+        [bulk, self.all.acquire().await.ok()]
+    }
+}
+
+impl Transport {
+    pub fn new(
+        http: Client,
+        recorder: Option<Recorder>,
+        chaos: Option<Chaos>,
+        slow_call: Option<Duration>,
+        upstream: UpstreamConfig,
+    ) -> Self {
+        Transport {
+            http,
+            routes: HashMap::new(),
+            headers: HashMap::new(),
+            recorder,
+            chaos,
+            slow_call,
+            metrics: None,
+            permits: (upstream.max_concurrency > 0)
+                .then(|| Slots::new(upstream.max_concurrency, upstream.bulk_share)),
+            queue_timeout: Duration::from_millis(upstream.queue_timeout_ms),
+            bulk_share: upstream.bulk_share,
+        }
+    }
+
+    /// Slots of a provider's own limit of `max` requests in flight.
+    fn slots(&self, max: usize) -> Slots {
+        Slots::new(max, self.bulk_share)
+    }
+
+    /// Sends the calls of `provider` with `http`, e.g. through its SOCKS5 proxy.
+    pub fn route(&mut self, provider: impl Into<String>, http: Client) {
+        self.routes.insert(provider.into(), http);
+    }
+
+    /// Sends `headers` with the calls of `provider`, over the ones it sets.
+    pub fn headers(&mut self, provider: impl Into<String>, headers: HeaderMap) {
+        self.headers.insert(provider.into(), headers);
+    }
+
+    /// Observes the latency of the calls in `metrics`.
+    pub fn metrics(&mut self, metrics: Arc<LatencyMetrics>) {
+        self.metrics = Some(metrics);
+    }
+
+    /// Waits for a slot of `own`, the limit of a provider, then for one of the
+    /// global limit, `Overloaded` once the queue timeout passes.
+    async fn permits<'a>(
+        &'a self,
+        own: Option<&'a Slots>,
+        priority: Priority,
+    ) -> Result<[[Option<SemaphorePermit<'a>>; 2]; 2], ProviderError> {
+        let acquire = |slots: Option<&'a Slots>| async move {
+            match slots {
+                Some(slots) => slots.acquire(priority).await,
+                None => [None, None],
+            }
+        };
+        let permits = async { [acquire(own).await, acquire(self.permits.as_ref()).await] };
+        tokio::time::timeout(self.queue_timeout, permits)
+            .await
+            .map_err(|_| ProviderError::Overloaded)
+    }
+
+    fn is_slow(&self, elapsed: Duration) -> bool {
+        self.slow_call.is_some_and(|threshold| elapsed > threshold)
+    }
+
+    fn observe(&self, provider: &str, elapsed: Duration, error: Option<&ProviderError>) {
+        if let Some(metrics) = &self.metrics {
+            metrics.observe_provider_call(provider, elapsed, error.map(ProviderError::class));
+        }
+    }
+
+    /// Fetches the raw payload of a lookup, from the recording in replay mode.
+    /// The answers sent by the provider adapt `throttle`.
+    async fn fetch(
+        &self,
+        provider: &dyn Provider,
+        ip: IpAddr,
+        timeout: Duration,
+        throttle: &Throttle,
+    ) -> Result<serde_json::Value, ProviderError> {
+        let fault = match &self.chaos {
+            Some(chaos) => chaos.inject(provider.name()).await,
+            None => None,
+        };
+        match fault {
+            Some(Fault::Error) => {
+                return Err(ProviderError::Status(StatusCode::SERVICE_UNAVAILABLE))
+            }
+            Some(Fault::Malformed) => {
+                return serde_json::from_str(r#"{"truncated":"#).map_err(ProviderError::Parse)
+            }
+            None => {}
+        }
+        if let Some(raw) = provider.offline(ip) {
+            return Ok(raw);
+        }
+        let url = provider.endpoint(ip);
+        let (status, body) = match &self.recorder {
+            Some(recorder) if recorder.replaying() => recorder
+                .replay(provider.name(), ip)
+                .await
+                .map_err(|e| ProviderError::Replay(e.to_string()))?,
+            _ => {
+                let http = self.routes.get(provider.name()).unwrap_or(&self.http);
+                let mut request = provider.add_auth(http.get(&url).timeout(timeout));
+                if let Some(headers) = self.headers.get(provider.name()) {
+                    request = request.headers(headers.clone());
+                }
+                let sent = Instant::now();
+                let response = request
+                    .send()
+                    .await
+                    .map_err(|e| match ResolveFailed::find(&e) {
+                        Some(failed) => ProviderError::Unresolved(failed.clone()),
+                        None => ProviderError::Request(e),
+                    })?;
+                timing::headers(sent.elapsed());
+                let status = response.status();
+                throttle.observe(status, response.headers());
+                let body = response.text().await.map_err(ProviderError::Request)?;
+                if let Some(recorder) = &self.recorder {
+                    if let Err(e) = recorder
+                        .record(provider.name(), ip, &url, status, &body)
+                        .await
+                    {
+                        warn!("recording of {} failed: {}", provider.name(), e);
+                    }
+                }
+                (status, body)
+            }
+        };
+        match status {
+            StatusCode::OK => serde_json::from_str(&body).map_err(ProviderError::Parse),
+            StatusCode::TOO_MANY_REQUESTS => Err(ProviderError::TooManyRequests),
+            status => Err(ProviderError::Status(status)),
+        }
+    }
+}
+
+/// Sends the lookup request to `provider` and parses the reply.
+pub async fn lookup(
+    provider: &dyn Provider,
+    transport: &Transport,
+    ip: IpAddr,
+    timeout: Duration,
+    throttle: &Throttle,
+) -> Result<ProviderLookup, ProviderError> {
+    let raw = transport.fetch(provider, ip, timeout, throttle).await?;
+    let reply = provider.parse_reply(&raw)?;
+    Ok(ProviderLookup {
+        geo: reply.geo,
+        signals: reply.signals,
+        threat: reply.threat,
+        raw,
+        degraded: false,
+        data_age: None,
+        support: Vec::new(),
+    })
+}
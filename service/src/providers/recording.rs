@@ -0,0 +1,158 @@
+//! Record and replay of provider HTTP exchanges
+//!
+//! In record mode every exchange is written to
+//! `<dir>/<provider>/<ip>.json`, overwriting the previous one so re-recording
+//! shows provider changes as a plain diff. In replay mode the files are served
+//! back instead of sending requests. Query parameters carrying keys or tokens
+//! are redacted before writing.
+
+use crate::config::{RecordingConfig, RecordingMode};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{io, net::IpAddr, path::PathBuf};
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct Exchange {
+    provider: String,
+    /// Request URL with secrets redacted.
+    url: String,
+    status: u16,
+    recorded_at: String,
+    /// JSON bodies are stored as is, anything else as a string.
+    body: Value,
+}
+
+pub struct Recorder {
+    mode: RecordingMode,
+    dir: PathBuf,
+}
+
+impl Recorder {
+    pub fn new(config: RecordingConfig) -> Self {
+        Recorder {
+            mode: config.mode,
+            dir: config.dir,
+        }
+    }
+
+    pub fn replaying(&self) -> bool {
+        self.mode == RecordingMode::Replay
+    }
+
+    fn path(&self, provider: &str, ip: IpAddr) -> PathBuf {
+        // `:` is not allowed in file names everywhere
+        let file = ip.to_string().replace(':', "_");
+        self.dir.join(provider).join(format!("{file}.json"))
+    }
+
+    /// Saves an exchange, a no-op in replay mode.
+    pub async fn record(
+        &self,
+        provider: &str,
+        ip: IpAddr,
+        url: &str,
+        status: StatusCode,
+        body: &str,
+    ) -> io::Result<()> {
+        if self.replaying() {
+            return Ok(());
+        }
+        let exchange = Exchange {
+            provider: provider.to_string(),
+            url: redact(url),
+            status: status.as_u16(),
+            recorded_at: chrono::Utc::now().to_rfc3339(),
+            body: serde_json::from_str(body).unwrap_or_else(|_| Value::String(body.into())),
+        };
+        let path = self.path(provider, ip);
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        let content = serde_json::to_string_pretty(&exchange)?;
+        tokio::fs::write(path, content).await
+    }
+
+    /// Status and body of the recorded exchange.
+    pub async fn replay(&self, provider: &str, ip: IpAddr) -> io::Result<(StatusCode, String)> {
+        let content = tokio::fs::read_to_string(self.path(provider, ip)).await?;
+        let exchange: Exchange = serde_json::from_str(&content)?;
+        let status = StatusCode::from_u16(exchange.status)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let body = match exchange.body {
+            Value::String(body) => body,
+            body => body.to_string(),
+        };
+        Ok((status, body))
+    }
+}
+
+/// Replaces the value of query parameters that look like credentials.
+fn redact(url: &str) -> String {
+    let Some((base, query)) = url.split_once('?') else {
+        return url.to_string();
+    };
+    let query: Vec<String> = query
+        .split('&')
+        .map(|pair| match pair.split_once('=') {
+            Some((name, _)) if is_secret(name) => format!("{name}=REDACTED"),
+            _ => pair.to_string(),
+        })
+        .collect();
+    format!("{base}?{}", query.join("&"))
+}
+
+fn is_secret(name: &str) -> bool {
+    let name = name.to_ascii_lowercase();
+    name.contains("key") || name.contains("token")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_redact() {
+        assert_eq!(
+            redact("https://api.ipdata.co/1.1.1.1?api-key=secret"),
+            "https://api.ipdata.co/1.1.1.1?api-key=REDACTED"
+        );
+        assert_eq!(
+            redact("https://x/ipgeo?apiKey=secret&ip=1.1.1.1"),
+            "https://x/ipgeo?apiKey=REDACTED&ip=1.1.1.1"
+        );
+        assert_eq!(
+            redact("http://ip-api.com/json/1.1.1.1"),
+            "http://ip-api.com/json/1.1.1.1"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_record_replay() {
+        let dir = std::env::temp_dir().join(format!("ip-service-{}", uuid::Uuid::new_v4()));
+        let config = |mode| RecordingConfig {
+            mode,
+            dir: dir.clone(),
+        };
+        let ip = "2001:db8::1".parse().unwrap();
+        let recorder = Recorder::new(config(RecordingMode::Record));
+        recorder
+            .record(
+                "ipinfo",
+                ip,
+                "https://ipinfo.io/x?token=t",
+                StatusCode::OK,
+                r#"{"ip":"2001:db8::1"}"#,
+            )
+            .await
+            .unwrap();
+        assert!(dir.join("ipinfo").join("2001_db8__1.json").exists());
+
+        let replay = Recorder::new(config(RecordingMode::Replay));
+        let (status, body) = replay.replay("ipinfo", ip).await.unwrap();
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, r#"{"ip":"2001:db8::1"}"#);
+        assert!(replay.replay("ipdata", ip).await.is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,1362 @@
+//! Provider registry built from the `[[providers]]` configuration
+
+use super::{
+    balancing::Balancing,
+    budget::{Budget, BudgetState},
+    demotion::{Demotion, Placement},
+    health::{CircuitState, Health, HealthState},
+    ipapi::{self, IpApi},
+    ipdata::{self, IpData},
+    ipgeolocation::{self, IpGeolocation},
+    ipinfo::{self, IpInfo},
+    lookup,
+    merge::{self, MergeStrategy, Source},
+    mock::{self, Mock},
+    throttle::Throttle,
+    timing, Endpoint, Provider, ProviderError, ProviderLookup, Slots, Transport,
+};
+use crate::{
+    config::{
+        BalancingProfile, FailoverPolicy, KeyRotation, MergeConfig, Priority, ProviderConfig,
+        ProviderKind, Routing,
+    },
+    error::Resource,
+    geo::Field,
+    ratelimit::RateLimiter,
+};
+use reqwest::Url;
+use serde::Serialize;
+use std::{
+    cmp::Reverse,
+    net::IpAddr,
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+    time::{Duration, Instant, SystemTime},
+};
+use tracing::warn;
+use utoipa::ToSchema;
+
+/// Shortest wait advised after a lookup refused for want of quota or capacity.
+const MIN_RETRY: Duration = Duration::from_secs(1);
+
+/// One API key of a provider, the provider is built once per key.
+struct Key {
+    provider: Box<dyn Provider>,
+    label: Option<String>,
+    requests: AtomicU64,
+    throttled: AtomicU64,
+    slow: AtomicU64,
+    throttle: Throttle,
+}
+
+struct Entry {
+    name: String,
+    kind: ProviderKind,
+    weight: u32,
+    cost: u32,
+    cost_per_call: f64,
+    /// Age the answers can have, live when absent.
+    data_age: Option<Duration>,
+    keys: Vec<Key>,
+    rotation: KeyRotation,
+    /// Next key for round robin, current key for failover.
+    next: AtomicUsize,
+    timeout: Duration,
+    limiter: Option<RateLimiter>,
+    budget: Option<Budget>,
+    health: Health,
+    demotion: Option<Demotion>,
+    /// Requests in flight of the provider, unlimited when absent.
+    permits: Option<Slots>,
+    /// Requests shed by the concurrency limits.
+    shed: AtomicU64,
+    /// Host name resolved ahead of the calls, absent when nothing resolves
+    /// it locally.
+    host: Option<String>,
+    /// Calls failed as the host could not be resolved.
+    unresolved: AtomicU64,
+}
+
+/// Request counters of a provider, per key.
+#[derive(Serialize, ToSchema, Debug, Clone, PartialEq)]
+pub struct ProviderUsage {
+    pub name: String,
+    pub keys: Vec<KeyUsage>,
+    /// Calls left in the current day or month, absent without a budget.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub budget_remaining: Option<u64>,
+    /// Lookups not sent because no concurrency slot freed up in time.
+    pub shed: u64,
+    /// Calls failed as the host of the provider could not be resolved.
+    pub unresolved: u64,
+}
+
+/// Operational state of a provider, served at `/providers`.
+#[derive(Serialize, ToSchema, Debug, Clone, PartialEq)]
+pub struct ProviderStatus {
+    pub name: String,
+    /// Provider type, e.g. `ipinfo`.
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub weight: u32,
+    pub cost: u32,
+    pub cost_per_call: f64,
+    /// Fields the provider can supply.
+    pub capabilities: Vec<Field>,
+    pub health: HealthState,
+    pub circuit: CircuitState,
+    /// Tried after the others or not at all, see `demotion` of the provider.
+    pub demoted: bool,
+    /// Calls in the rolling window the rates are computed over.
+    pub samples: usize,
+    /// Share of recent calls that were not outages, absent before the first call.
+    pub success_rate: Option<f64>,
+    pub latency_p50_ms: Option<u64>,
+    pub latency_p99_ms: Option<u64>,
+    /// Calls left in the current budget period, absent without a budget.
+    pub quota_remaining: Option<u64>,
+}
+
+/// Estimated spend on the providers since the start, served at `/v1/stats/spend`.
+#[derive(Serialize, ToSchema, Debug, Clone, PartialEq)]
+pub struct SpendStats {
+    /// In the currency of the `cost_per_call` prices.
+    pub total: f64,
+    pub providers: Vec<ProviderSpend>,
+}
+
+#[derive(Serialize, ToSchema, Debug, Clone, PartialEq)]
+pub struct ProviderSpend {
+    pub name: String,
+    /// Calls sent, with every key.
+    pub calls: u64,
+    pub cost_per_call: f64,
+    pub spend: f64,
+}
+
+#[derive(Serialize, ToSchema, Debug, Clone, PartialEq)]
+pub struct KeyUsage {
+    /// Last characters of the key, absent for keyless providers.
+    pub key: Option<String>,
+    pub requests: u64,
+    /// Requests answered with a rate limit or quota error.
+    pub throttled: u64,
+    /// Requests over the `slow_requests.provider_call_ms` threshold.
+    pub slow: u64,
+    /// Share of the lookups sent with the key, lowered after 429 answers.
+    pub share: f64,
+    /// Nothing is sent until the rate limit window of the provider resets.
+    pub backing_off: bool,
+    /// Calls left in the rate limit window, as the provider last reported it.
+    pub remaining: Option<u64>,
+}
+
+impl Key {
+    fn new(kind: ProviderKind, name: &str, base_url: &str, api_key: Option<String>) -> Self {
+        Key {
+            label: api_key.as_deref().map(mask),
+            provider: kind.build(Endpoint::new(name, base_url, api_key)),
+            requests: AtomicU64::new(0),
+            throttled: AtomicU64::new(0),
+            slow: AtomicU64::new(0),
+            throttle: Throttle::default(),
+        }
+    }
+}
+
+/// Keeps the error of the lookup, a provider that failed outright explains it
+/// better than the ones refused for want of quota or capacity.
+fn keep(last: &mut Option<ProviderError>, e: ProviderError) {
+    let failed = last.as_ref().is_some_and(|last| last.saturated().is_none());
+    if !failed || e.saturated().is_none() {
+        *last = Some(e);
+    }
+}
+
+/// `abcdef123456` -> `****3456`
+fn mask(key: &str) -> String {
+    let tail: String = match key.chars().count() {
+        n if n >= 8 => key.chars().skip(n - 4).collect(),
+        _ => String::new(),
+    };
+    format!("****{tail}")
+}
+
+impl Entry {
+    /// When the data of an answer given at `now` was current: `data_age`
+    /// before it when configured, else `now` for a live provider and
+    /// unknown for an offline one.
+    fn as_of(&self, now: SystemTime) -> Option<SystemTime> {
+        match self.data_age {
+            Some(age) => now.checked_sub(age),
+            None => (!self.kind.is_offline()).then_some(now),
+        }
+    }
+
+    /// Key indices in the order they are tried for one request.
+    fn key_order(&self) -> impl Iterator<Item = usize> {
+        let len = self.keys.len();
+        let start = match self.rotation {
+            KeyRotation::RoundRobin => self.next.fetch_add(1, Ordering::Relaxed),
+            KeyRotation::Failover => self.next.load(Ordering::Relaxed),
+        } % len;
+        (0..len).map(move |i| (start + i) % len)
+    }
+
+    /// Checks the budget, the rate limit and the circuit before sending the request.
+    async fn try_lookup(
+        &self,
+        transport: &Transport,
+        ip: IpAddr,
+        state: BudgetState,
+        priority: Priority,
+    ) -> Result<ProviderLookup, ProviderError> {
+        if state == BudgetState::Exhausted {
+            return Err(ProviderError::BudgetExhausted);
+        }
+        // held until every key was tried
+        let _permits = transport
+            .permits(self.permits.as_ref(), priority)
+            .await
+            .inspect_err(|_| {
+                self.shed.fetch_add(1, Ordering::Relaxed);
+            })?;
+        if self.limiter.as_ref().is_some_and(|l| !l.try_acquire()) {
+            return Err(ProviderError::RateLimited);
+        }
+        if !self.health.allow() {
+            return Err(ProviderError::CircuitOpen);
+        }
+        self.lookup(transport, ip).await
+    }
+
+    /// [`try_lookup`](Self::try_lookup) reporting the outcome of a probe call.
+    async fn attempt(
+        &self,
+        transport: &Transport,
+        ip: IpAddr,
+        state: BudgetState,
+        placement: Placement,
+        priority: Priority,
+    ) -> Result<ProviderLookup, ProviderError> {
+        let result = self.try_lookup(transport, ip, state, priority).await;
+        if let (Placement::Probe, Some(demotion)) = (placement, &self.demotion) {
+            demotion.probed(&self.name, result.is_ok(), &self.health);
+        }
+        if let Err(e) = &result {
+            warn!("provider {} failed: {}", self.name, e);
+        }
+        result
+    }
+
+    /// Tries the keys in rotation order, moving on to the next key only on
+    /// rate limit and quota errors.
+    async fn lookup(
+        &self,
+        transport: &Transport,
+        ip: IpAddr,
+    ) -> Result<ProviderLookup, ProviderError> {
+        let mut last_error = ProviderError::TooManyRequests;
+        for index in self.key_order() {
+            let key = &self.keys[index];
+            if !key.throttle.admit() {
+                last_error = ProviderError::BackingOff;
+                continue;
+            }
+            key.requests.fetch_add(1, Ordering::Relaxed);
+            if let Some(budget) = &self.budget {
+                budget.record();
+            }
+            let start = Instant::now();
+            let call = lookup(
+                key.provider.as_ref(),
+                transport,
+                ip,
+                self.timeout,
+                &key.throttle,
+            );
+            let (result, phases) = timing::measure(call).await;
+            let elapsed = start.elapsed();
+            if let Err(ProviderError::Unresolved(_)) = &result {
+                self.unresolved.fetch_add(1, Ordering::Relaxed);
+            }
+            let outage = result.as_ref().is_err_and(ProviderError::is_outage);
+            self.health.record(!outage, elapsed);
+            if let Some(demotion) = &self.demotion {
+                demotion.check(&self.name, &self.health);
+            }
+            transport.observe(&self.name, elapsed, result.as_ref().err());
+            if transport.is_slow(elapsed) {
+                key.slow.fetch_add(1, Ordering::Relaxed);
+                warn!(
+                    target: "slow",
+                    "slow provider call provider={} key={} ip={} latency={}ms {}",
+                    self.name,
+                    index,
+                    ip,
+                    elapsed.as_millis(),
+                    phases
+                );
+            }
+            match result {
+                Err(e) if e.is_quota() => {
+                    key.throttled.fetch_add(1, Ordering::Relaxed);
+                    if self.rotation == KeyRotation::Failover {
+                        let next = (index + 1) % self.keys.len();
+                        let _ = self.next.compare_exchange(
+                            index,
+                            next,
+                            Ordering::Relaxed,
+                            Ordering::Relaxed,
+                        );
+                    }
+                    if self.keys.len() > 1 {
+                        warn!("provider {} key {} throttled: {}", self.name, index, e);
+                    }
+                    last_error = e;
+                }
+                result => return result,
+            }
+        }
+        Err(last_error)
+    }
+
+    fn usage(&self) -> ProviderUsage {
+        ProviderUsage {
+            name: self.name.clone(),
+            keys: self
+                .keys
+                .iter()
+                .map(|key| KeyUsage {
+                    key: key.label.clone(),
+                    requests: key.requests.load(Ordering::Relaxed),
+                    throttled: key.throttled.load(Ordering::Relaxed),
+                    slow: key.slow.load(Ordering::Relaxed),
+                    share: key.throttle.share(),
+                    backing_off: key.throttle.backing_off(),
+                    remaining: key.throttle.remaining(),
+                })
+                .collect(),
+            budget_remaining: self.budget.as_ref().and_then(Budget::remaining),
+            shed: self.shed.load(Ordering::Relaxed),
+            unresolved: self.unresolved.load(Ordering::Relaxed),
+        }
+    }
+
+    fn status(&self) -> ProviderStatus {
+        let health = self.health.snapshot();
+        ProviderStatus {
+            name: self.name.clone(),
+            kind: self.kind.as_str().to_string(),
+            weight: self.weight,
+            cost: self.cost,
+            cost_per_call: self.cost_per_call,
+            capabilities: self.capabilities().to_vec(),
+            health: health.state,
+            circuit: health.circuit,
+            demoted: self.demotion.as_ref().is_some_and(Demotion::is_demoted),
+            samples: health.samples,
+            success_rate: health.success_rate,
+            latency_p50_ms: health.latency_p50_ms,
+            latency_p99_ms: health.latency_p99_ms,
+            quota_remaining: self.budget.as_ref().and_then(Budget::remaining),
+        }
+    }
+
+    fn capabilities(&self) -> &'static [Field] {
+        self.keys[0].provider.capabilities()
+    }
+
+    fn supplies(&self, fields: &[Field]) -> bool {
+        let capabilities = self.capabilities();
+        fields.iter().all(|field| capabilities.contains(field))
+    }
+
+    /// Every key waits for the rate limit window to reset.
+    fn backing_off(&self) -> bool {
+        self.keys.iter().all(|key| key.throttle.backing_off())
+    }
+
+    /// Time until the first key is sent again, while every key is backing off.
+    fn reset_in(&self) -> Option<Duration> {
+        let resets: Option<Vec<_>> = self
+            .keys
+            .iter()
+            .map(|key| key.throttle.reset_in())
+            .collect();
+        resets?.into_iter().min()
+    }
+
+    fn budget_state(&self) -> BudgetState {
+        self.budget
+            .as_ref()
+            .map_or(BudgetState::Available, Budget::state)
+    }
+
+    fn spend(&self) -> ProviderSpend {
+        let calls = self
+            .keys
+            .iter()
+            .map(|key| key.requests.load(Ordering::Relaxed))
+            .sum();
+        ProviderSpend {
+            name: self.name.clone(),
+            calls,
+            cost_per_call: self.cost_per_call,
+            spend: calls as f64 * self.cost_per_call,
+        }
+    }
+
+    /// Price rank, `cost` first and then `cost_per_call`.
+    fn price(&self) -> (u32, u64) {
+        // in millionths, prices are not negative
+        (self.cost, (self.cost_per_call * 1_000_000.0).round() as u64)
+    }
+
+    /// Placement in the next lookup, takes the probe slot when one is due.
+    fn placement(&self) -> Placement {
+        match &self.demotion {
+            Some(demotion) => demotion.place(),
+            None => Placement::Normal,
+        }
+    }
+}
+
+/// How a lookup picks its providers.
+#[derive(Default)]
+pub struct LookupOptions<'a> {
+    /// Provider to try first.
+    pub selected: Option<&'a str>,
+    /// Requested fields, all of them when empty.
+    pub fields: &'a [Field],
+    pub policy: FailoverPolicy,
+    /// Providers left out, e.g. by a feature flag.
+    pub excluded: Vec<&'a str>,
+    pub priority: Priority,
+    /// Oldest answer accepted, providers with older data are tried last.
+    pub max_age: Option<Duration>,
+}
+
+/// Configured providers, tried in order of descending weight.
+pub struct ProviderRegistry {
+    transport: Transport,
+    entries: Vec<Entry>,
+    /// Only ever called by name, see [`shadows`](Self::shadows).
+    shadows: Vec<Entry>,
+    balancing: Vec<Balancing>,
+    routing: Routing,
+    /// Providers asked at once and how their answers are merged.
+    merge: Option<(usize, Box<dyn MergeStrategy>)>,
+}
+
+impl ProviderKind {
+    /// Builds the provider for a registry entry.
+    pub fn build(&self, endpoint: Endpoint) -> Box<dyn Provider> {
+        match self {
+            ProviderKind::IpApi => Box::new(IpApi::new(endpoint)),
+            ProviderKind::IpInfo => Box::new(IpInfo::new(endpoint)),
+            ProviderKind::IpData => Box::new(IpData::new(endpoint)),
+            ProviderKind::IpGeolocation => Box::new(IpGeolocation::new(endpoint)),
+            ProviderKind::Mock => Box::new(Mock::new(endpoint)),
+        }
+    }
+
+    fn default_base_url(&self, api_key: Option<&str>) -> &'static str {
+        match self {
+            ProviderKind::IpApi if api_key.is_some() => ipapi::PRO_BASE_URL,
+            ProviderKind::IpApi => ipapi::BASE_URL,
+            ProviderKind::IpInfo => ipinfo::BASE_URL,
+            ProviderKind::IpData => ipdata::BASE_URL,
+            ProviderKind::IpGeolocation => ipgeolocation::BASE_URL,
+            ProviderKind::Mock => mock::BASE_URL,
+        }
+    }
+}
+
+impl ProviderRegistry {
+    /// Builds the registry, entries of a type that needs a key but has none are skipped.
+    pub fn new(transport: Transport, configs: Vec<ProviderConfig>) -> Self {
+        let mut configs: Vec<_> = configs
+            .into_iter()
+            .filter(|config| {
+                let missing = config.kind.requires_key() && config.keys().is_empty();
+                if missing {
+                    warn!("provider {} requires an api_key, skipped", config.name());
+                }
+                !missing
+            })
+            .collect();
+        // stable, entries with the same weight keep the configured order
+        configs.sort_by_key(|config| Reverse(config.weight));
+
+        let (shadows, entries): (Vec<_>, Vec<_>) = configs
+            .into_iter()
+            .map(|config| {
+                let name = config.name();
+                let keys = config.keys();
+                let base_url = config.base_url.clone().unwrap_or_else(|| {
+                    let key = keys.first().map(String::as_str);
+                    config.kind.default_base_url(key).to_string()
+                });
+                // the SOCKS5 proxy resolves the host
+                let host = Url::parse(&base_url)
+                    .ok()
+                    .and_then(|url| url.domain().map(str::to_string))
+                    .filter(|_| !config.kind.is_offline() && config.socks_proxy.is_none());
+                let keys = match keys.is_empty() {
+                    true => vec![Key::new(config.kind, &name, &base_url, None)],
+                    false => keys
+                        .into_iter()
+                        .map(|key| Key::new(config.kind, &name, &base_url, Some(key)))
+                        .collect(),
+                };
+                let entry = Entry {
+                    name,
+                    kind: config.kind,
+                    weight: config.weight,
+                    cost: config.cost,
+                    cost_per_call: config.cost_per_call,
+                    data_age: config.data_age_secs.map(Duration::from_secs),
+                    keys,
+                    rotation: config.key_rotation,
+                    next: AtomicUsize::new(0),
+                    timeout: Duration::from_millis(config.timeout_ms),
+                    budget: config.budget.map(Budget::new),
+                    health: Health::new(config.circuit),
+                    demotion: config.demotion.map(Demotion::new),
+                    permits: (config.max_concurrency > 0)
+                        .then(|| transport.slots(config.max_concurrency)),
+                    shed: AtomicU64::new(0),
+                    host,
+                    unresolved: AtomicU64::new(0),
+                    limiter: config.rate_limit.map(|limit| {
+                        RateLimiter::new(limit.requests, Duration::from_secs(limit.period_secs))
+                    }),
+                };
+                (config.shadow, entry)
+            })
+            .partition(|(shadow, _)| *shadow);
+        ProviderRegistry {
+            transport,
+            entries: entries.into_iter().map(|(_, entry)| entry).collect(),
+            shadows: shadows.into_iter().map(|(_, entry)| entry).collect(),
+            balancing: Vec::new(),
+            routing: Routing::default(),
+            merge: None,
+        }
+    }
+
+    /// Merges the answers of the first `merge.sources` providers, see [`merge`].
+    pub fn with_merge(mut self, merge: MergeConfig) -> Self {
+        self.merge = Some((merge.sources, merge.strategy.strategy()));
+        self
+    }
+
+    pub fn with_routing(mut self, routing: Routing) -> Self {
+        self.routing = routing;
+        self
+    }
+
+    /// Spreads the lookups of the field profiles across their providers.
+    pub fn with_balancing(mut self, profiles: Vec<BalancingProfile>) -> Self {
+        self.balancing = profiles.into_iter().map(Balancing::new).collect();
+        self
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.entries.iter().any(|e| e.name == name)
+    }
+
+    /// Names in the order they are tried.
+    pub fn names(&self) -> Vec<&str> {
+        self.entries.iter().map(|e| e.name.as_str()).collect()
+    }
+
+    /// Names of the shadow providers, which never answer a lookup: they are
+    /// only called through [`lookup_with`](Self::lookup_with), to compare
+    /// their answers with the ones of the others.
+    pub fn shadows(&self) -> Vec<&str> {
+        self.shadows.iter().map(|e| e.name.as_str()).collect()
+    }
+
+    /// Host names of the providers, shadows included, each once.
+    pub fn hosts(&self) -> Vec<String> {
+        let mut hosts: Vec<String> = self
+            .entries
+            .iter()
+            .chain(&self.shadows)
+            .filter_map(|e| e.host.clone())
+            .collect();
+        hosts.sort();
+        hosts.dedup();
+        hosts
+    }
+
+    /// Names of the providers answering without network access.
+    pub fn offline(&self) -> Vec<&str> {
+        self.entries
+            .iter()
+            .filter(|e| e.kind.is_offline())
+            .map(|e| e.name.as_str())
+            .collect()
+    }
+
+    /// Request counters of every provider, shadows last.
+    pub fn usage(&self) -> Vec<ProviderUsage> {
+        self.entries
+            .iter()
+            .chain(&self.shadows)
+            .map(Entry::usage)
+            .collect()
+    }
+
+    /// Time until a provider is called again while every one waits for its
+    /// rate limit window to reset, `None` while one can be called.
+    pub fn retry_after(&self) -> Option<Duration> {
+        let resets: Option<Vec<_>> = self.entries.iter().map(Entry::reset_in).collect();
+        resets?.into_iter().min()
+    }
+
+    /// Wait before a lookup refused for want of `resource` is worth another try.
+    pub fn retry_in(&self, resource: Resource) -> Duration {
+        let reset = match resource {
+            Resource::ProviderRateLimit => self.retry_after(),
+            Resource::Budget => self
+                .entries
+                .iter()
+                .filter_map(|e| e.budget.as_ref()?.resets_in())
+                .min(),
+            Resource::RateLimit | Resource::UpstreamConcurrency => None,
+        };
+        reset.unwrap_or_default().max(MIN_RETRY)
+    }
+
+    /// Status of every provider in the order they are tried.
+    pub fn status(&self) -> Vec<ProviderStatus> {
+        self.entries.iter().map(Entry::status).collect()
+    }
+
+    /// Calls sent times `cost_per_call`, per provider, shadows included.
+    pub fn spend(&self) -> SpendStats {
+        let providers: Vec<ProviderSpend> = self
+            .entries
+            .iter()
+            .chain(&self.shadows)
+            .map(Entry::spend)
+            .collect();
+        SpendStats {
+            total: providers.iter().map(|p| p.spend).sum(),
+            providers,
+        }
+    }
+
+    /// Looks `ip` up with the selected provider first. Under the best-effort
+    /// policy the others are tried in weight order until one succeeds, the
+    /// strict policy fails with the first provider.
+    ///
+    /// When `fields` are requested, or for every lookup under the cost
+    /// routing, the providers supplying all of them go first, cheapest first.
+    /// Providers with data older than `max_age` are skipped unless no other
+    /// provider is left, so a cheap but stale one never wins on its price.
+    /// Providers backing off after 429 answers or close to their budget are
+    /// tried after the others, exhausted ones are skipped. Demoted providers
+    /// go last, disabled ones are skipped unless no other provider is left.
+    ///
+    /// The first `[[balancing]]` profile covering `fields` draws the provider
+    /// tried first among its healthy ones, the others follow as above. With
+    /// a merge the first providers are asked at once and their answers
+    /// merged, the rest are tried one by one if none of them answers.
+    pub async fn lookup(
+        &self,
+        ip: IpAddr,
+        options: &LookupOptions<'_>,
+    ) -> Result<ProviderLookup, ProviderError> {
+        let LookupOptions {
+            selected,
+            fields,
+            policy,
+            excluded,
+            priority,
+            max_age,
+        } = options;
+        let mut order: Vec<(&Entry, BudgetState, Placement)> = self
+            .entries
+            .iter()
+            .filter(|e| !excluded.contains(&e.name.as_str()))
+            .map(|e| (e, e.budget_state(), e.placement()))
+            .collect();
+        let is_selected = |e: &Entry| selected.is_some_and(|selected| e.name == selected);
+        if order
+            .iter()
+            .any(|(e, _, placement)| *placement != Placement::Skipped || is_selected(e))
+        {
+            order.retain(|(e, _, placement)| *placement != Placement::Skipped || is_selected(e));
+        }
+        let stale =
+            |e: &Entry| max_age.is_some_and(|max_age| e.data_age.is_some_and(|age| age > max_age));
+        if order.iter().any(|(e, _, _)| !stale(e) || is_selected(e)) {
+            order.retain(|(e, _, _)| !stale(e) || is_selected(e));
+        }
+        let picked = self
+            .balancing
+            .iter()
+            .find(|balancing| balancing.applies(fields))
+            .and_then(|balancing| {
+                balancing.pick(|name| {
+                    order.iter().any(|(e, state, placement)| {
+                        e.name == name
+                            && *placement == Placement::Normal
+                            && *state == BudgetState::Available
+                            && !e.backing_off()
+                            && e.health.snapshot().circuit == CircuitState::Closed
+                    })
+                })
+            });
+        order.sort_by_key(|(e, state, placement)| {
+            let cost = match fields.is_empty() && self.routing == Routing::Weight {
+                true => (0, 0),
+                false => e.price(),
+            };
+            (
+                selected.is_some_and(|selected| e.name != selected),
+                picked.is_some_and(|picked| e.name != picked),
+                matches!(placement, Placement::Last | Placement::Skipped),
+                !e.supplies(fields),
+                stale(e),
+                e.backing_off(),
+                *state == BudgetState::Low,
+                cost,
+            )
+        });
+        if *policy == FailoverPolicy::Strict {
+            order.truncate(1);
+        }
+
+        let mut last_error = None;
+        let mut order = order.into_iter();
+        let mut attempted = 0;
+        if let Some((sources, strategy)) = self.merge.as_ref().filter(|_| order.len() > 1) {
+            let head: Vec<_> = order.by_ref().take(*sources).collect();
+            let results =
+                futures::future::join_all(head.iter().map(|(entry, state, placement)| {
+                    entry.attempt(&self.transport, ip, *state, *placement, *priority)
+                }))
+                .await;
+            let now = SystemTime::now();
+            let mut answered = Vec::new();
+            for ((entry, _, _), result) in head.iter().zip(results) {
+                match result {
+                    Ok(lookup) => answered.push((*entry, lookup)),
+                    Err(e) => keep(&mut last_error, e),
+                }
+            }
+            if let Some((first, _)) = answered.first() {
+                let sources: Vec<Source> = answered
+                    .iter()
+                    .map(|(entry, lookup)| Source {
+                        lookup,
+                        as_of: entry.as_of(now),
+                    })
+                    .collect();
+                let mut result = merge::merge(strategy.as_ref(), &sources);
+                result.degraded = first.name != head[0].0.name || !first.supplies(fields);
+                return Ok(result);
+            }
+            attempted = head.len();
+        }
+        for (attempt, (entry, state, placement)) in order.enumerate() {
+            match entry
+                .attempt(&self.transport, ip, state, placement, *priority)
+                .await
+            {
+                Ok(mut result) => {
+                    result.degraded = attempted + attempt > 0 || !entry.supplies(fields);
+                    result.data_age = entry.data_age;
+                    return Ok(result);
+                }
+                Err(e) => keep(&mut last_error, e),
+            }
+        }
+        Err(last_error.unwrap_or_else(|| ProviderError::Rejected("no provider configured".into())))
+    }
+
+    /// Whether the named provider supplies every one of `fields`.
+    pub fn supplies(&self, name: &str, fields: &[Field]) -> bool {
+        self.entries
+            .iter()
+            .any(|e| e.name == name && e.supplies(fields))
+    }
+
+    /// First provider in weight order other than `name`.
+    pub fn alternative(&self, name: &str) -> Option<&str> {
+        self.names().into_iter().find(|other| *other != name)
+    }
+
+    /// Looks `ip` up with the named provider only, a shadow too, without
+    /// fallback, as a bulk lookup.
+    pub async fn lookup_with(
+        &self,
+        ip: IpAddr,
+        name: &str,
+    ) -> Result<ProviderLookup, ProviderError> {
+        let entry = self
+            .entries
+            .iter()
+            .chain(&self.shadows)
+            .find(|e| e.name == name)
+            .ok_or_else(|| ProviderError::Rejected(format!("unknown provider {name}")))?;
+        entry
+            .try_lookup(&self.transport, ip, entry.budget_state(), Priority::Bulk)
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::{
+            BudgetConfig, DemotionConfig, MergeStrategyKind, RateLimitConfig, UpstreamConfig,
+        },
+        providers::hosts::Hosts,
+    };
+    use reqwest::Client;
+    use std::sync::Arc;
+
+    fn transport() -> Transport {
+        Transport::new(Client::new(), None, None, None, UpstreamConfig::default())
+    }
+
+    fn config(kind: ProviderKind, name: &str, weight: u32) -> ProviderConfig {
+        ProviderConfig {
+            name: Some(name.to_string()),
+            weight,
+            ..ProviderConfig::new(kind)
+        }
+    }
+
+    #[test]
+    fn test_weight_order() {
+        let registry = ProviderRegistry::new(
+            transport(),
+            vec![
+                config(ProviderKind::IpApi, "first", 1),
+                config(ProviderKind::IpInfo, "heavy", 10),
+                config(ProviderKind::IpApi, "second", 1),
+            ],
+        );
+        assert_eq!(registry.names(), vec!["heavy", "first", "second"]);
+    }
+
+    #[test]
+    fn test_missing_key_skipped() {
+        let registry = ProviderRegistry::new(
+            transport(),
+            vec![
+                config(ProviderKind::IpData, "ipdata", 1),
+                config(ProviderKind::IpApi, "ipapi", 1),
+            ],
+        );
+        assert!(!registry.contains("ipdata"));
+        assert!(registry.contains("ipapi"));
+    }
+
+    fn rotating(rotation: KeyRotation) -> Entry {
+        let mut config = config(ProviderKind::IpInfo, "ipinfo", 1);
+        config.api_keys = vec!["team-a-key-0001".into(), "team-b-key-0002".into()];
+        config.key_rotation = rotation;
+        let registry = ProviderRegistry::new(transport(), vec![config]);
+        registry.entries.into_iter().next().unwrap()
+    }
+
+    #[test]
+    fn test_round_robin() {
+        let entry = rotating(KeyRotation::RoundRobin);
+        assert_eq!(entry.key_order().collect::<Vec<_>>(), vec![0, 1]);
+        assert_eq!(entry.key_order().collect::<Vec<_>>(), vec![1, 0]);
+        assert_eq!(entry.key_order().collect::<Vec<_>>(), vec![0, 1]);
+    }
+
+    #[test]
+    fn test_failover() {
+        let entry = rotating(KeyRotation::Failover);
+        assert_eq!(entry.key_order().collect::<Vec<_>>(), vec![0, 1]);
+        assert_eq!(entry.key_order().collect::<Vec<_>>(), vec![0, 1]);
+    }
+
+    #[test]
+    fn test_usage() {
+        let usage = rotating(KeyRotation::RoundRobin).usage();
+        assert_eq!(usage.keys.len(), 2);
+        assert_eq!(usage.keys[0].key.as_deref(), Some("****0001"));
+        assert_eq!(mask("short"), "****");
+    }
+
+    #[test]
+    fn test_capabilities() {
+        let registry = ProviderRegistry::new(
+            transport(),
+            vec![
+                config(ProviderKind::IpApi, "ipapi", 1),
+                config(ProviderKind::IpInfo, "ipinfo", 1),
+            ],
+        );
+        let [ipapi, ipinfo] = &registry.entries[..] else {
+            panic!("two entries expected");
+        };
+        assert!(ipapi.supplies(&[Field::Country, Field::Currency]));
+        assert!(!ipinfo.supplies(&[Field::Country, Field::Currency]));
+        assert!(ipinfo.supplies(&[]));
+    }
+
+    #[tokio::test]
+    async fn test_budget_exhausted() {
+        let mut spent = config(ProviderKind::IpApi, "spent", 1);
+        spent.budget = Some(BudgetConfig {
+            daily: Some(0),
+            monthly: None,
+            soft_limit_pct: 90,
+        });
+        let registry = ProviderRegistry::new(transport(), vec![spent]);
+        let result = registry
+            .lookup("8.8.8.8".parse().unwrap(), &LookupOptions::default())
+            .await;
+        assert!(matches!(result, Err(ProviderError::BudgetExhausted)));
+        assert_eq!(registry.usage()[0].budget_remaining, Some(0));
+        let status = &registry.status()[0];
+        assert_eq!(status.quota_remaining, Some(0));
+        assert_eq!(status.samples, 0, "skipped providers are not sampled");
+    }
+
+    #[tokio::test]
+    async fn test_fallback_degraded() {
+        let mut limited = config(ProviderKind::IpApi, "limited", 2);
+        limited.rate_limit = Some(RateLimitConfig {
+            requests: 0,
+            period_secs: 60,
+        });
+        let mock = config(ProviderKind::Mock, "mock", 1);
+        let registry = ProviderRegistry::new(transport(), vec![limited, mock]);
+        let ip = "1.1.1.1".parse().unwrap();
+
+        let result = registry
+            .lookup(ip, &LookupOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(result.geo.provider, "mock");
+        assert!(result.degraded);
+
+        let result = registry
+            .lookup(
+                ip,
+                &LookupOptions {
+                    selected: Some("mock"),
+                    policy: FailoverPolicy::Strict,
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+        assert!(!result.degraded);
+        assert_eq!(registry.status()[1].samples, 2);
+    }
+
+    #[tokio::test]
+    async fn test_excluded() {
+        let mock = config(ProviderKind::Mock, "mock", 1);
+        let registry = ProviderRegistry::new(transport(), vec![mock]);
+        let options = LookupOptions {
+            excluded: vec!["mock"],
+            ..Default::default()
+        };
+        let result = registry.lookup("1.1.1.1".parse().unwrap(), &options).await;
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_strict() {
+        let mut limited = config(ProviderKind::IpApi, "limited", 2);
+        limited.rate_limit = Some(RateLimitConfig {
+            requests: 0,
+            period_secs: 60,
+        });
+        let mut spent = config(ProviderKind::IpApi, "spent", 1);
+        spent.budget = Some(BudgetConfig {
+            daily: Some(0),
+            monthly: None,
+            soft_limit_pct: 90,
+        });
+        let registry = ProviderRegistry::new(transport(), vec![limited, spent]);
+        let ip = "8.8.8.8".parse().unwrap();
+
+        let strict = LookupOptions {
+            policy: FailoverPolicy::Strict,
+            ..Default::default()
+        };
+        let result = registry.lookup(ip, &strict).await;
+        assert!(matches!(result, Err(ProviderError::RateLimited)));
+        let result = registry.lookup(ip, &LookupOptions::default()).await;
+        assert!(matches!(result, Err(ProviderError::BudgetExhausted)));
+    }
+
+    #[tokio::test]
+    async fn test_rate_limited() {
+        let mut limited = config(ProviderKind::IpApi, "limited", 1);
+        limited.rate_limit = Some(RateLimitConfig {
+            requests: 0,
+            period_secs: 60,
+        });
+        let registry = ProviderRegistry::new(transport(), vec![limited]);
+        let result = registry
+            .lookup("8.8.8.8".parse().unwrap(), &LookupOptions::default())
+            .await;
+        assert!(matches!(result, Err(ProviderError::RateLimited)));
+    }
+
+    #[tokio::test]
+    async fn test_backing_off() {
+        let registry = ProviderRegistry::new(
+            transport(),
+            vec![
+                config(ProviderKind::Mock, "first", 2),
+                config(ProviderKind::Mock, "second", 1),
+            ],
+        );
+        let window: reqwest::header::HeaderMap = [
+            ("x-rl".parse().unwrap(), "0".parse().unwrap()),
+            ("x-ttl".parse().unwrap(), "60".parse().unwrap()),
+        ]
+        .into_iter()
+        .collect();
+        registry.entries[0].keys[0]
+            .throttle
+            .observe(reqwest::StatusCode::OK, &window);
+
+        let ip = "8.8.8.8".parse().unwrap();
+        let lookup = registry
+            .lookup(ip, &LookupOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(lookup.geo.provider, "second");
+        let usage = registry.usage();
+        assert!(usage[0].keys[0].backing_off);
+        assert_eq!(usage[0].keys[0].requests, 0);
+
+        let strict = LookupOptions {
+            selected: Some("first"),
+            policy: FailoverPolicy::Strict,
+            ..Default::default()
+        };
+        let result = registry.lookup(ip, &strict).await;
+        assert!(matches!(result, Err(ProviderError::BackingOff)));
+
+        assert_eq!(registry.retry_after(), None, "second can be called");
+        registry.entries[1].keys[0]
+            .throttle
+            .observe(reqwest::StatusCode::OK, &window);
+        let retry_after = registry.retry_after().unwrap();
+        assert!(retry_after > Duration::from_secs(55) && retry_after <= Duration::from_secs(60));
+    }
+
+    #[tokio::test]
+    async fn test_overloaded() {
+        let upstream = UpstreamConfig {
+            max_concurrency: 8,
+            queue_timeout_ms: 0,
+            ..Default::default()
+        };
+        let transport = Transport::new(Client::new(), None, None, None, upstream);
+        let mut mock = config(ProviderKind::Mock, "mock", 1);
+        mock.max_concurrency = 1;
+        let registry = ProviderRegistry::new(transport, vec![mock]);
+        let ip = "8.8.8.8".parse().unwrap();
+
+        let in_flight = registry.entries[0].permits.as_ref().unwrap();
+        let permit = in_flight.all.acquire().await.unwrap();
+        let result = registry.lookup(ip, &LookupOptions::default()).await;
+        assert!(matches!(result, Err(ProviderError::Overloaded)));
+        assert_eq!(registry.usage()[0].shed, 1);
+
+        drop(permit);
+        assert!(registry.lookup(ip, &LookupOptions::default()).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_saturated() {
+        let mut spent = config(ProviderKind::Mock, "spent", 2);
+        spent.budget = Some(BudgetConfig {
+            daily: Some(0),
+            monthly: None,
+            soft_limit_pct: 90,
+        });
+        let mut failing = config(ProviderKind::IpApi, "failing", 1);
+        failing.base_url = Some("http://127.0.0.1:9".into());
+        let registry = ProviderRegistry::new(transport(), vec![spent, failing]);
+        let ip = "8.8.8.8".parse().unwrap();
+
+        let result = registry.lookup(ip, &LookupOptions::default()).await;
+        let Err(error) = result else {
+            panic!("Neither provider answers");
+        };
+        assert_eq!(error.saturated(), None, "The outage explains it: {error}");
+        let strict = LookupOptions {
+            policy: FailoverPolicy::Strict,
+            ..Default::default()
+        };
+        let result = registry.lookup(ip, &strict).await;
+        assert!(matches!(result, Err(ProviderError::BudgetExhausted)));
+        assert!(
+            registry.retry_in(Resource::Budget) > MIN_RETRY,
+            "Until midnight"
+        );
+        assert_eq!(registry.retry_in(Resource::UpstreamConcurrency), MIN_RETRY);
+    }
+
+    #[tokio::test]
+    async fn test_priority() {
+        let upstream = UpstreamConfig {
+            max_concurrency: 4,
+            queue_timeout_ms: 0,
+            bulk_share: 0.5,
+        };
+        let transport = Transport::new(Client::new(), None, None, None, upstream);
+        let registry =
+            ProviderRegistry::new(transport, vec![config(ProviderKind::Mock, "mock", 1)]);
+        let ip = "8.8.8.8".parse().unwrap();
+        let bulk = LookupOptions {
+            priority: Priority::Bulk,
+            ..Default::default()
+        };
+
+        let transport = &registry.transport;
+        let held = [
+            transport.permits(None, Priority::Bulk).await.unwrap(),
+            transport.permits(None, Priority::Bulk).await.unwrap(),
+        ];
+        let result = registry.lookup(ip, &bulk).await;
+        assert!(
+            matches!(result, Err(ProviderError::Overloaded)),
+            "Bulk share in use"
+        );
+        let interactive = LookupOptions::default();
+        assert!(registry.lookup(ip, &interactive).await.is_ok());
+
+        drop(held);
+        assert!(registry.lookup(ip, &bulk).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_demoted() {
+        let mut failing = config(ProviderKind::IpApi, "failing", 1);
+        failing.base_url = Some("http://127.0.0.1:9".into());
+        failing.demotion = Some(DemotionConfig {
+            min_samples: 2,
+            ..Default::default()
+        });
+        let registry = ProviderRegistry::new(
+            transport(),
+            vec![failing, config(ProviderKind::Mock, "mock", 0)],
+        );
+        let ip = "8.8.8.8".parse().unwrap();
+        let options = LookupOptions::default();
+        for _ in 0..2 {
+            let result = registry.lookup(ip, &options).await.unwrap();
+            assert!(result.degraded, "Answered by the fallback");
+        }
+        assert!(registry.status()[0].demoted);
+        let result = registry.lookup(ip, &options).await.unwrap();
+        assert!(!result.degraded, "Mock tried first");
+        assert_eq!(registry.usage()[0].keys[0].requests, 2);
+    }
+
+    #[tokio::test]
+    async fn test_shadow() {
+        let mut shadow = config(ProviderKind::Mock, "shadow", 10);
+        shadow.shadow = true;
+        let registry = ProviderRegistry::new(
+            transport(),
+            vec![shadow, config(ProviderKind::Mock, "mock", 0)],
+        );
+        assert_eq!(registry.names(), ["mock"]);
+        assert_eq!(registry.shadows(), ["shadow"]);
+        assert!(!registry.contains("shadow"));
+        assert_eq!(registry.alternative("mock"), None);
+        let ip = "8.8.8.8".parse().unwrap();
+        let options = LookupOptions {
+            selected: Some("shadow"),
+            ..Default::default()
+        };
+        let result = registry.lookup(ip, &options).await.unwrap();
+        assert_eq!(result.geo.provider, "mock", "Never answers");
+        let result = registry.lookup_with(ip, "shadow").await.unwrap();
+        assert_eq!(result.geo.provider, "shadow");
+        assert_eq!(registry.spend().providers.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_balancing() {
+        let registry = ProviderRegistry::new(
+            transport(),
+            vec![
+                config(ProviderKind::Mock, "heavy", 10),
+                config(ProviderKind::Mock, "light", 1),
+            ],
+        )
+        .with_balancing(vec![BalancingProfile {
+            fields: vec![Field::Country],
+            weights: [("light".to_string(), 1)].into(),
+        }]);
+        let ip = "8.8.8.8".parse().unwrap();
+        let country = LookupOptions {
+            fields: &[Field::Country],
+            ..Default::default()
+        };
+        let result = registry.lookup(ip, &country).await.unwrap();
+        assert!(!result.degraded);
+        registry
+            .lookup(ip, &LookupOptions::default())
+            .await
+            .unwrap();
+        let usage = registry.usage();
+        assert_eq!(usage[0].keys[0].requests, 1, "Outside of the profile");
+        assert_eq!(usage[1].keys[0].requests, 1);
+    }
+
+    #[tokio::test]
+    async fn test_cost_routing() {
+        let mut paid = config(ProviderKind::Mock, "paid", 10);
+        paid.cost_per_call = 0.25;
+        let configs = vec![paid, config(ProviderKind::Mock, "free", 1)];
+        let ip = "8.8.8.8".parse().unwrap();
+
+        let registry = ProviderRegistry::new(transport(), configs.clone());
+        let result = registry
+            .lookup(ip, &LookupOptions::default())
+            .await
+            .unwrap();
+        registry
+            .lookup(ip, &LookupOptions::default())
+            .await
+            .unwrap();
+        assert!(!result.degraded);
+        let spend = registry.spend();
+        assert_eq!(spend.providers[0].calls, 2, "Heaviest first");
+        assert_eq!(spend.total, 0.5);
+
+        let registry = ProviderRegistry::new(transport(), configs).with_routing(Routing::Cost);
+        registry
+            .lookup(ip, &LookupOptions::default())
+            .await
+            .unwrap();
+        let spend = registry.spend();
+        assert_eq!(spend.providers[1].calls, 1, "Cheapest first");
+        assert_eq!(spend.total, 0.0);
+
+        // the free one answers from a daily database
+        let mut paid = config(ProviderKind::Mock, "paid", 10);
+        paid.cost_per_call = 0.25;
+        let mut daily = config(ProviderKind::Mock, "free", 1);
+        daily.data_age_secs = Some(86_400);
+        let registry = ProviderRegistry::new(transport(), vec![paid, daily.clone()])
+            .with_routing(Routing::Cost);
+        let max_age = |secs| LookupOptions {
+            max_age: Some(Duration::from_secs(secs)),
+            ..Default::default()
+        };
+        let provider = |result: ProviderLookup| result.geo.provider;
+        let result = registry.lookup(ip, &max_age(3600)).await.unwrap();
+        assert_eq!(provider(result), "paid", "Too old for max_age");
+        let result = registry.lookup(ip, &max_age(86_400)).await.unwrap();
+        assert_eq!(provider(result), "free");
+        let result = registry.lookup(ip, &LookupOptions::default()).await;
+        assert_eq!(provider(result.unwrap()), "free");
+        let registry = ProviderRegistry::new(transport(), vec![daily]);
+        let result = registry.lookup(ip, &max_age(3600)).await.unwrap();
+        assert_eq!(provider(result), "free", "No fresher one left");
+    }
+
+    #[tokio::test]
+    async fn test_merge() {
+        let mut failing = config(ProviderKind::IpApi, "failing", 10);
+        failing.base_url = Some("http://127.0.0.1:9".into());
+        let configs = vec![
+            failing,
+            config(ProviderKind::Mock, "a", 5),
+            config(ProviderKind::Mock, "b", 1),
+            config(ProviderKind::Mock, "c", 0),
+        ];
+        let merge = MergeConfig {
+            sources: 3,
+            ..Default::default()
+        };
+        let registry = ProviderRegistry::new(transport(), configs).with_merge(merge);
+        let ip = "8.8.8.8".parse().unwrap();
+        let result = registry
+            .lookup(ip, &LookupOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(result.geo.provider, "a");
+        assert!(result.degraded, "The first provider failed");
+        let calls: Vec<u64> = registry
+            .usage()
+            .iter()
+            .map(|u| u.keys[0].requests)
+            .collect();
+        assert_eq!(calls, [1, 1, 1, 0]);
+    }
+
+    #[tokio::test]
+    async fn test_merge_freshest() {
+        use axum::{routing::get, Json, Router};
+        use serde_json::json;
+
+        let app = Router::new().route(
+            "/json/:ip",
+            get(|| async {
+                Json(json!({ "status": "success", "query": "8.8.8.8", "city": "Lyon" }))
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        // live, but from a database rebuilt daily
+        let mut daily = config(ProviderKind::IpApi, "daily", 10);
+        daily.base_url = Some(format!("http://127.0.0.1:{port}"));
+        daily.data_age_secs = Some(86_400);
+        let mut fresh = config(ProviderKind::Mock, "fresh", 1);
+        fresh.data_age_secs = Some(60);
+        let merge = MergeConfig {
+            sources: 2,
+            strategy: MergeStrategyKind::Freshest,
+        };
+        let registry = ProviderRegistry::new(transport(), vec![daily, fresh]).with_merge(merge);
+        let ip = "8.8.8.8".parse().unwrap();
+        let result = registry
+            .lookup(ip, &LookupOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(result.geo.provider, "daily", "first in order");
+        assert_eq!(result.geo.city.as_deref(), Some("Mountain View"), "the fresher city");
+    }
+
+    #[tokio::test]
+    async fn test_unresolved() {
+        let hosts = Arc::new(Hosts::system());
+        let http = timing::instrument(Client::builder(), &hosts)
+            .build()
+            .unwrap();
+        let transport = Transport::new(http, None, None, None, UpstreamConfig::default());
+        let mut ipapi = config(ProviderKind::IpApi, "ipapi", 1);
+        ipapi.base_url = Some("http://nonexistent.invalid".into());
+        let registry = ProviderRegistry::new(
+            transport,
+            vec![ipapi, config(ProviderKind::Mock, "mock", 0)],
+        );
+        assert_eq!(registry.hosts(), ["nonexistent.invalid"], "Not the mock");
+
+        let ip = "8.8.8.8".parse().unwrap();
+        let result = registry.lookup_with(ip, "ipapi").await;
+        assert!(matches!(result, Err(ProviderError::Unresolved(_))));
+        assert_eq!(registry.usage()[0].unresolved, 1);
+    }
+}
@@ -0,0 +1,242 @@
+//! Adaptive throttling of the keys a provider answers with 429
+//!
+//! Every 429 halves the share of the calls a key is sent, every other answer
+//! grows it back by a step. Once a provider tells when its window resets,
+//! through `Retry-After` or the `X-Ttl` of ip-api, with a 429 or with
+//! `X-Rl: 0`, nothing is sent with the key until then and the registry tries
+//! the other providers first, instead of spending the rest of the quota on
+//! rejected calls. `Retry-After` is either seconds or an HTTP date.
+//!
+//! The calls left that `X-Rl` reports are counted down by every call sent
+//! after it, so the key backs off once they are used up rather than on the
+//! 429 of the next one.
+
+use chrono::{DateTime, Utc};
+use reqwest::{
+    header::{HeaderMap, RETRY_AFTER},
+    StatusCode,
+};
+use std::{
+    str::FromStr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// The share never drops below one call in 64, so the key keeps probing.
+const MIN_SHARE: f64 = 1.0 / 64.0;
+/// Added to the share by every answered call.
+const SHARE_STEP: f64 = 0.05;
+
+/// Calls left in the window, ip-api.
+const X_RL: &str = "x-rl";
+/// Seconds until the window resets, ip-api.
+const X_TTL: &str = "x-ttl";
+
+#[derive(Debug)]
+struct State {
+    share: f64,
+    /// Accumulates the share of every call, one is sent per whole unit.
+    credit: f64,
+    until: Option<Instant>,
+    /// Calls left in the window of the provider, as last reported.
+    remaining: Option<u64>,
+    /// End of that window, when the provider told.
+    resets: Option<Instant>,
+}
+
+#[derive(Debug)]
+pub struct Throttle {
+    state: Mutex<State>,
+}
+
+impl Default for Throttle {
+    fn default() -> Self {
+        Throttle {
+            state: Mutex::new(State {
+                share: 1.0,
+                credit: 0.0,
+                until: None,
+                remaining: None,
+                resets: None,
+            }),
+        }
+    }
+}
+
+impl Throttle {
+    /// Whether a call may be sent now, spreading the admitted share evenly.
+    pub fn admit(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if let Some(until) = state.until {
+            if Instant::now() < until {
+                return false;
+            }
+            state.until = None;
+        }
+        if state.resets.is_some_and(|resets| Instant::now() >= resets) {
+            state.remaining = None;
+            state.resets = None;
+        }
+        if state.remaining == Some(0) && state.resets.is_some() {
+            state.until = state.resets;
+            return false;
+        }
+        state.credit += state.share;
+        if state.credit < 1.0 {
+            return false;
+        }
+        state.credit -= 1.0;
+        if let Some(remaining) = &mut state.remaining {
+            *remaining = remaining.saturating_sub(1);
+        }
+        true
+    }
+
+    /// Waiting for the window of the provider to reset.
+    pub fn backing_off(&self) -> bool {
+        let state = self.state.lock().unwrap();
+        state.until.is_some_and(|until| Instant::now() < until)
+    }
+
+    /// Time left until the window of the provider resets, while backing off.
+    pub fn reset_in(&self) -> Option<Duration> {
+        let state = self.state.lock().unwrap();
+        state
+            .until
+            .and_then(|until| until.checked_duration_since(Instant::now()))
+    }
+
+    /// Calls left in the window of the provider, unknown unless it reports them.
+    pub fn remaining(&self) -> Option<u64> {
+        let state = self.state.lock().unwrap();
+        match state.resets {
+            Some(resets) if Instant::now() >= resets => None,
+            _ => state.remaining,
+        }
+    }
+
+    /// Share of the calls sent, 1 unless the key was answered 429.
+    pub fn share(&self) -> f64 {
+        self.state.lock().unwrap().share
+    }
+
+    /// Adapts to the answer of a call.
+    pub fn observe(&self, status: StatusCode, headers: &HeaderMap) {
+        let reset = retry_after(headers)
+            .or_else(|| header(headers, X_TTL).map(Duration::from_secs))
+            .map(|reset| Instant::now() + reset);
+        let remaining = header::<u64>(headers, X_RL);
+        let mut state = self.state.lock().unwrap();
+        let exhausted = if status == StatusCode::TOO_MANY_REQUESTS {
+            state.share = (state.share / 2.0).max(MIN_SHARE);
+            true
+        } else {
+            state.share = (state.share + SHARE_STEP).min(1.0);
+            remaining == Some(0)
+        };
+        if remaining.is_some() || exhausted {
+            state.remaining = match exhausted {
+                true => reset.map(|_| 0),
+                false => remaining,
+            };
+            state.resets = reset;
+        }
+        if let Some(reset) = reset.filter(|_| exhausted) {
+            state.until = Some(reset);
+        }
+    }
+}
+
+fn header<T: FromStr>(headers: &HeaderMap, name: &str) -> Option<T> {
+    headers.get(name)?.to_str().ok()?.trim().parse().ok()
+}
+
+/// Delay of `Retry-After`, in seconds or until an HTTP date.
+fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    if let Some(secs) = header(headers, RETRY_AFTER.as_str()) {
+        return Some(Duration::from_secs(secs));
+    }
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?;
+    let at = DateTime::parse_from_rfc2822(value.trim()).ok()?;
+    Some(
+        (at.with_timezone(&Utc) - Utc::now())
+            .to_std()
+            .unwrap_or_default(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+        pairs
+            .iter()
+            .map(|(name, value)| (name.parse().unwrap(), value.parse().unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn test_share() {
+        let throttle = Throttle::default();
+        assert!(throttle.admit());
+        throttle.observe(StatusCode::TOO_MANY_REQUESTS, &HeaderMap::new());
+        throttle.observe(StatusCode::TOO_MANY_REQUESTS, &HeaderMap::new());
+        assert_eq!(throttle.share(), 0.25);
+        let admitted = (0..8).filter(|_| throttle.admit()).count();
+        assert_eq!(admitted, 2, "A quarter of the calls");
+
+        for _ in 0..20 {
+            throttle.observe(StatusCode::OK, &HeaderMap::new());
+        }
+        assert_eq!(throttle.share(), 1.0);
+        assert!(!throttle.backing_off());
+    }
+
+    #[test]
+    fn test_window() {
+        let throttle = Throttle::default();
+        throttle.observe(StatusCode::OK, &headers(&[(X_RL, "3"), (X_TTL, "60")]));
+        assert!(!throttle.backing_off());
+        throttle.observe(StatusCode::OK, &headers(&[(X_RL, "0"), (X_TTL, "60")]));
+        assert!(throttle.backing_off());
+        assert!(!throttle.admit(), "Nothing sent before the reset");
+
+        let throttle = Throttle::default();
+        throttle.observe(
+            StatusCode::TOO_MANY_REQUESTS,
+            &headers(&[("retry-after", "0")]),
+        );
+        assert!(!throttle.backing_off(), "The window reset already");
+        assert_eq!(throttle.share(), 0.5);
+
+        let throttle = Throttle::default();
+        let at = (Utc::now() + chrono::Duration::seconds(30)).to_rfc2822();
+        throttle.observe(
+            StatusCode::TOO_MANY_REQUESTS,
+            &headers(&[("retry-after", &at)]),
+        );
+        assert!(throttle.backing_off(), "HTTP date");
+        let reset_in = throttle.reset_in().unwrap();
+        assert!(reset_in > Duration::from_secs(25) && reset_in <= Duration::from_secs(30));
+    }
+
+    #[test]
+    fn test_remaining() {
+        let throttle = Throttle::default();
+        assert_eq!(throttle.remaining(), None);
+        throttle.observe(StatusCode::OK, &headers(&[(X_RL, "2"), (X_TTL, "60")]));
+        assert_eq!(throttle.remaining(), Some(2));
+        assert!(throttle.admit());
+        assert!(throttle.admit());
+        assert_eq!(throttle.remaining(), Some(0));
+        assert!(!throttle.admit(), "The reported calls are used up");
+        assert!(throttle.backing_off());
+
+        let throttle = Throttle::default();
+        throttle.observe(StatusCode::OK, &headers(&[(X_RL, "1"), (X_TTL, "0")]));
+        assert!(throttle.admit());
+        assert!(throttle.admit(), "The window reset");
+        assert_eq!(throttle.remaining(), None);
+    }
+}
@@ -0,0 +1,190 @@
+//! Phase timings of the provider calls
+//!
+//! reqwest only tells how long a whole request took. The clients
+//! [`instrument`] builds time the DNS resolution and the connection setup of the requests sent
+//! within [`measure`], and the transport adds the wait for the response
+//! headers, so a slow call shows which phase was slow. A request over a pooled
+//! connection has neither a DNS nor a connect phase.
+
+use super::hosts::Hosts;
+use reqwest::{
+    dns::{Addrs, Name, Resolve, Resolving},
+    ClientBuilder,
+};
+use std::{
+    cell::RefCell,
+    fmt,
+    future::Future,
+    net::SocketAddr,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+use tower::{Layer, Service};
+
+tokio::task_local! {
+    static PHASES: RefCell<Phases>;
+}
+
+/// Phases of one call, absent when the call did not go through them.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Phases {
+    pub dns: Option<Duration>,
+    /// TCP and TLS setup, the resolution excluded.
+    pub connect: Option<Duration>,
+    /// From sending the request to the response headers, the connection
+    /// setup excluded.
+    pub ttfb: Option<Duration>,
+}
+
+impl Phases {
+    fn setup(&self) -> Duration {
+        self.dns.unwrap_or_default() + self.connect.unwrap_or_default()
+    }
+}
+
+/// `dns=3ms connect=41ms ttfb=1200ms`, `-` for the phases not gone through.
+impl fmt::Display for Phases {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let phases = [
+            ("dns", self.dns),
+            ("connect", self.connect),
+            ("ttfb", self.ttfb),
+        ];
+        for (i, (name, phase)) in phases.into_iter().enumerate() {
+            if i > 0 {
+                f.write_str(" ")?;
+            }
+            match phase {
+                Some(phase) => write!(f, "{name}={}ms", phase.as_millis())?,
+                None => write!(f, "{name}=-")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Records into the phases of the current [`measure`], if any. Connections
+/// hyper finishes in the background after a pooled one won are not recorded.
+fn record(update: impl FnOnce(&mut Phases)) {
+    let _ = PHASES.try_with(|phases| update(&mut phases.borrow_mut()));
+}
+
+/// Records the response headers arriving `elapsed` after the request was sent.
+pub(super) fn headers(elapsed: Duration) {
+    record(|phases| phases.ttfb = Some(elapsed.saturating_sub(phases.setup())));
+}
+
+/// Runs `call`, returning the phases its requests went through.
+pub async fn measure<F: Future>(call: F) -> (F::Output, Phases) {
+    PHASES
+        .scope(RefCell::new(Phases::default()), async {
+            let output = call.await;
+            (output, PHASES.with(|phases| *phases.borrow()))
+        })
+        .await
+}
+
+/// Times the phases of the requests of the client built, which resolves the
+/// hosts through `hosts`.
+pub fn instrument(builder: ClientBuilder, hosts: &Arc<Hosts>) -> ClientBuilder {
+    builder
+        .dns_resolver(Arc::new(TimedResolver(hosts.clone())))
+        .connector_layer(TimedConnectLayer)
+}
+
+struct TimedResolver(Arc<Hosts>);
+
+impl Resolve for TimedResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let hosts = self.0.clone();
+        Box::pin(async move {
+            let start = Instant::now();
+            let addresses = hosts.resolve(name.as_str()).await?;
+            record(|phases| phases.dns = Some(start.elapsed()));
+            // the connector sets the port of the URL
+            let addrs = addresses.into_iter().map(|ip| SocketAddr::new(ip, 0));
+            Ok(Box::new(addrs) as Addrs)
+        })
+    }
+}
+
+#[derive(Clone)]
+struct TimedConnectLayer;
+
+impl<S> Layer<S> for TimedConnectLayer {
+    type Service = TimedConnect<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        TimedConnect(inner)
+    }
+}
+
+/// Connector of reqwest, which resolves the host before connecting.
+#[derive(Clone)]
+struct TimedConnect<S>(S);
+
+impl<S, R> Service<R> for TimedConnect<S>
+where
+    S: Service<R>,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<S::Response, S::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.0.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: R) -> Self::Future {
+        let connecting = self.0.call(request);
+        Box::pin(async move {
+            let start = Instant::now();
+            let connection = connecting.await;
+            record(|phases| {
+                phases.connect = Some(
+                    start
+                        .elapsed()
+                        .saturating_sub(phases.dns.unwrap_or_default()),
+                )
+            });
+            connection
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{routing::get, Router};
+
+    #[tokio::test]
+    async fn test_measure() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let app = Router::new().route("/", get(|| async { "ok" }));
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let hosts = Arc::new(Hosts::system());
+        let http = instrument(reqwest::Client::builder(), &hosts)
+            .build()
+            .unwrap();
+        let url = format!("http://localhost:{port}/");
+        let call = || async {
+            let sent = Instant::now();
+            let response = http.get(&url).send().await.unwrap();
+            headers(sent.elapsed());
+            response.text().await.unwrap()
+        };
+        let (body, phases) = measure(call()).await;
+        assert_eq!(body, "ok");
+        assert!(phases.dns.is_some() && phases.connect.is_some() && phases.ttfb.is_some());
+
+        let (_, pooled) = measure(call()).await;
+        assert_eq!((pooled.dns, pooled.connect), (None, None));
+        assert!(pooled.ttfb.is_some());
+        assert!(pooled.to_string().starts_with("dns=- connect=- ttfb="));
+    }
+}
@@ -0,0 +1,105 @@
+//! Fixed window rate limiter
+
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Windows kept before the expired ones are dropped.
+const KEYED_WINDOWS: usize = 10_000;
+
+pub struct RateLimiter {
+    limit: u32,
+    period: Duration,
+    window: Mutex<(Instant, u32)>,
+}
+
+impl RateLimiter {
+    pub fn new(limit: u32, period: Duration) -> Self {
+        RateLimiter {
+            limit,
+            period,
+            window: Mutex::new((Instant::now(), 0)),
+        }
+    }
+
+    /// Takes a slot in the current window, `false` if the limit is reached.
+    pub fn try_acquire(&self) -> bool {
+        let mut window = self.window.lock().unwrap();
+        if window.0.elapsed() >= self.period {
+            *window = (Instant::now(), 0);
+        }
+        if window.1 >= self.limit {
+            return false;
+        }
+        window.1 += 1;
+        true
+    }
+}
+
+/// One fixed window per key.
+pub struct KeyedRateLimiter<K> {
+    limit: u32,
+    period: Duration,
+    windows: Mutex<HashMap<K, (Instant, u32)>>,
+}
+
+impl<K: Eq + Hash> KeyedRateLimiter<K> {
+    pub fn new(limit: u32, period: Duration) -> Self {
+        KeyedRateLimiter {
+            limit,
+            period,
+            windows: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Takes a slot in the current window of `key`, `false` if the limit is reached.
+    pub fn try_acquire(&self, key: K) -> bool {
+        let mut windows = self.windows.lock().unwrap();
+        if windows.len() >= KEYED_WINDOWS {
+            windows.retain(|_, window| window.0.elapsed() < self.period);
+        }
+        let window = windows.entry(key).or_insert((Instant::now(), 0));
+        if window.0.elapsed() >= self.period {
+            *window = (Instant::now(), 0);
+        }
+        if window.1 >= self.limit {
+            return false;
+        }
+        window.1 += 1;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_limit() {
+        let limiter = RateLimiter::new(2, Duration::from_secs(60));
+        assert!(limiter.try_acquire());
+        assert!(limiter.try_acquire());
+        assert!(!limiter.try_acquire(), "Third request should be limited");
+    }
+
+    #[test]
+    fn test_window_reset() {
+        let limiter = RateLimiter::new(1, Duration::ZERO);
+        assert!(limiter.try_acquire());
+        assert!(limiter.try_acquire(), "Window should have been reset");
+    }
+
+    #[test]
+    fn test_keyed() {
+        let limiter = KeyedRateLimiter::new(1, Duration::from_secs(60));
+        assert!(limiter.try_acquire("a"));
+        assert!(!limiter.try_acquire("a"));
+        assert!(
+            limiter.try_acquire("b"),
+            "Keys should have separate windows"
+        );
+    }
+}
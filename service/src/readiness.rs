@@ -0,0 +1,161 @@
+//! Readiness of the service dependencies
+//!
+//! `/health` only tells that the process answers, readiness tells whether it
+//! can answer lookups well: every configured dataset is downloaded and fresh,
+//! and at least one provider circuit accepts requests. Each dependency is
+//! reported on its own so an orchestrator log shows which one holds the
+//! instance back.
+
+use crate::{
+    dataset::Dataset,
+    providers::{health::CircuitState, registry::ProviderStatus},
+};
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::Serialize;
+use std::time::SystemTime;
+use utoipa::ToSchema;
+
+/// A dataset older than this many refresh intervals is expired, one failed
+/// download is tolerated while the retries run.
+const EXPIRED_AFTER_INTERVALS: u32 = 2;
+
+#[derive(Serialize, ToSchema, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DependencyState {
+    Ready,
+    /// Not downloaded yet and no snapshot to start from.
+    NotLoaded,
+    /// Downloads kept failing, the data is older than two refresh intervals.
+    Expired,
+    /// The circuit of the provider is open.
+    Down,
+}
+
+#[derive(Serialize, ToSchema, Debug, Clone, PartialEq)]
+pub struct Dependency {
+    pub name: String,
+    /// `dataset` or `provider`.
+    pub kind: String,
+    pub state: DependencyState,
+    /// RFC 3339 time of the data of a dataset.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub updated: Option<String>,
+}
+
+/// Served at `/ready`, with a 503 unless `ready`.
+#[derive(Serialize, ToSchema, Debug, Clone, PartialEq)]
+pub struct Readiness {
+    pub ready: bool,
+    pub dependencies: Vec<Dependency>,
+}
+
+impl Readiness {
+    /// Ready when every dataset is and at least one provider accepts requests,
+    /// a single provider with an open circuit is covered by the failover.
+    pub fn new(dependencies: Vec<Dependency>) -> Self {
+        let of_kind = |kind: &'static str| {
+            dependencies
+                .iter()
+                .filter(move |dependency| dependency.kind == kind)
+        };
+        let datasets = of_kind("dataset").all(|d| d.state == DependencyState::Ready);
+        let providers = of_kind("provider").any(|d| d.state == DependencyState::Ready);
+        Readiness {
+            ready: datasets && providers,
+            dependencies,
+        }
+    }
+}
+
+/// State of `dataset` at `now`.
+pub fn dataset<D: Dataset>(dataset: &D, now: SystemTime) -> Dependency {
+    let updated = dataset.updated();
+    let max_age = dataset.refresh_interval() * EXPIRED_AFTER_INTERVALS;
+    let state = match updated {
+        None => DependencyState::NotLoaded,
+        // a clock going backwards counts as fresh data
+        Some(updated) => match now.duration_since(updated) {
+            Ok(age) if age > max_age => DependencyState::Expired,
+            _ => DependencyState::Ready,
+        },
+    };
+    Dependency {
+        name: D::NAME.into(),
+        kind: "dataset".into(),
+        state,
+        updated: updated.map(|updated| {
+            DateTime::<Utc>::from(updated).to_rfc3339_opts(SecondsFormat::Secs, true)
+        }),
+    }
+}
+
+/// State of a provider from its circuit, a half-open circuit lets the probe through.
+pub fn provider(status: &ProviderStatus) -> Dependency {
+    Dependency {
+        name: status.name.clone(),
+        kind: "provider".into(),
+        state: match status.circuit {
+            CircuitState::Open => DependencyState::Down,
+            CircuitState::Closed | CircuitState::HalfOpen => DependencyState::Ready,
+        },
+        updated: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Error;
+    use std::time::Duration;
+
+    const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+    struct Stub(Option<SystemTime>);
+
+    impl Dataset for Stub {
+        const NAME: &'static str = "stub";
+
+        fn updated(&self) -> Option<SystemTime> {
+            self.0
+        }
+
+        fn refresh_interval(&self) -> Duration {
+            DAY
+        }
+
+        async fn refresh(&self) -> Result<usize, Error> {
+            Ok(0)
+        }
+    }
+
+    #[test]
+    fn test_dataset() {
+        let now = SystemTime::UNIX_EPOCH + 10 * DAY;
+        let state = |updated| dataset(&Stub(updated), now).state;
+        assert_eq!(state(None), DependencyState::NotLoaded);
+        assert_eq!(state(Some(now - DAY)), DependencyState::Ready);
+        assert_eq!(state(Some(now - 3 * DAY)), DependencyState::Expired);
+        assert_eq!(state(Some(now + DAY)), DependencyState::Ready);
+
+        let loaded = dataset(&Stub(Some(SystemTime::UNIX_EPOCH)), now);
+        assert_eq!(loaded.updated.as_deref(), Some("1970-01-01T00:00:00Z"));
+    }
+
+    #[test]
+    fn test_readiness() {
+        let dependency = |kind: &str, state| Dependency {
+            name: kind.into(),
+            kind: kind.into(),
+            state,
+            updated: None,
+        };
+        let provider_down = dependency("provider", DependencyState::Down);
+        let provider_up = dependency("provider", DependencyState::Ready);
+        let expired = dependency("dataset", DependencyState::Expired);
+
+        let readiness = Readiness::new(vec![provider_down.clone(), provider_up.clone()]);
+        assert!(readiness.ready);
+        assert!(!Readiness::new(vec![provider_down]).ready);
+        assert!(!Readiness::new(vec![provider_up, expired]).ready);
+    }
+}
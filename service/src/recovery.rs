@@ -0,0 +1,132 @@
+//! Panic recovery of the HTTP handlers
+//!
+//! A panicking handler would otherwise drop the connection without an answer.
+//! [`catch_panic`] turns the panic into a problem+json 500 carrying the request
+//! id, so the failure can be found in the logs, and counts it for the metrics.
+
+use crate::error::{Error, PROBLEM_JSON};
+use axum::{
+    extract::{Request, State},
+    http::{header::CONTENT_TYPE, HeaderName, HeaderValue, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use futures::FutureExt;
+use std::{
+    any::Any,
+    panic::AssertUnwindSafe,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+use tracing::error;
+use uuid::Uuid;
+
+pub const REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+/// Panics caught since the start.
+#[derive(Debug, Default)]
+pub struct PanicCounter(AtomicU64);
+
+impl PanicCounter {
+    pub fn count(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Middleware answering the panics of the rest of the stack. Requests without
+/// an `x-request-id` get one, the handlers see the same id.
+pub async fn catch_panic(
+    State(panics): State<Arc<PanicCounter>>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let request_id = match request.headers().get(&REQUEST_ID) {
+        Some(id) => id.clone(),
+        None => {
+            let id = HeaderValue::from_str(&Uuid::new_v4().to_string()).expect("uuid");
+            request.headers_mut().insert(REQUEST_ID, id.clone());
+            id
+        }
+    };
+    let path = request.uri().path().to_string();
+    match AssertUnwindSafe(next.run(request)).catch_unwind().await {
+        Ok(response) => response,
+        Err(panic) => {
+            panics.0.fetch_add(1, Ordering::Relaxed);
+            let id = request_id.to_str().unwrap_or_default();
+            error!(
+                "handler of {} panicked request_id={}: {}",
+                path,
+                id,
+                message(&*panic)
+            );
+            let mut body = Error::Internal("the handler panicked".into()).body();
+            body.request_id = Some(id.to_string());
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                [
+                    (CONTENT_TYPE, HeaderValue::from_static(PROBLEM_JSON)),
+                    (REQUEST_ID, request_id),
+                ],
+                Json(body),
+            )
+                .into_response()
+        }
+    }
+}
+
+/// The payload of `panic!` and `unwrap` is a string.
+fn message(panic: &(dyn Any + Send)) -> &str {
+    panic
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("unknown payload")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::ErrorBody;
+    use axum::{body::Body, middleware, routing::get, Router};
+    use tower::ServiceExt;
+
+    fn app(panics: Arc<PanicCounter>) -> Router {
+        Router::new()
+            .route(
+                "/panic",
+                get(|| async { serde_json::from_str::<u32>("x").unwrap().to_string() }),
+            )
+            .route("/ok", get(|| async { "ok" }))
+            .layer(middleware::from_fn_with_state(panics, catch_panic))
+    }
+
+    #[tokio::test]
+    async fn test_catch_panic() {
+        let panics = Arc::new(PanicCounter::default());
+        let request = Request::builder()
+            .uri("/panic")
+            .header(REQUEST_ID, "abc")
+            .body(Body::empty())
+            .unwrap();
+        let response = app(panics.clone()).oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(response.headers()[CONTENT_TYPE], PROBLEM_JSON);
+        assert_eq!(response.headers()[REQUEST_ID], "abc");
+        let body = axum::body::to_bytes(response.into_body(), 4096)
+            .await
+            .unwrap();
+        let body: ErrorBody = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body.error, "internal");
+        assert_eq!(body.request_id.as_deref(), Some("abc"));
+        assert_eq!(panics.count(), 1);
+
+        let request = Request::builder().uri("/ok").body(Body::empty()).unwrap();
+        let response = app(panics.clone()).oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(panics.count(), 1);
+    }
+}
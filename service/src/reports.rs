@@ -0,0 +1,410 @@
+//! Scheduled summary reports
+//!
+//! At the end of every [`ReportPeriod`] the API requests, the provider calls
+//! and their failures and the spend since the previous report are summed up
+//! with the provider health at the time, and written to `reports.dir` as
+//! `<period>-<first day>.json` and `.html`. The counters start over with the
+//! process, so the first report after a restart covers the time since the
+//! start.
+
+use crate::{
+    config::{ReportPeriod, ReportsConfig},
+    dataset::{load_snapshot, save_snapshot},
+    error::Error,
+    prometheus::LatencyMetrics,
+    providers::{
+        health::HealthState,
+        registry::{ProviderStatus, SpendStats},
+    },
+};
+use chrono::{DateTime, Datelike, Duration, SecondsFormat, Utc};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fmt::Write,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+use tracing::warn;
+use utoipa::ToSchema;
+
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone, PartialEq)]
+pub struct Summary {
+    pub period: ReportPeriod,
+    /// RFC 3339 start, the previous report or the start of the service.
+    pub from: String,
+    /// RFC 3339 end.
+    pub to: String,
+    /// API requests answered.
+    pub requests: u64,
+    pub mean_latency_ms: Option<f64>,
+    pub provider_calls: u64,
+    /// Failed provider calls, rate limits and timeouts included.
+    pub provider_errors: u64,
+    /// In the currency of the `cost_per_call` prices.
+    pub spend: f64,
+    pub providers: Vec<ProviderSummary>,
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone, PartialEq)]
+pub struct ProviderSummary {
+    pub name: String,
+    pub calls: u64,
+    pub errors: u64,
+    pub spend: f64,
+    /// At the end of the period.
+    pub health: HealthState,
+    /// Of the recent calls at the end of the period.
+    pub success_rate: Option<f64>,
+    pub quota_remaining: Option<u64>,
+}
+
+/// Counters since the start of the process.
+#[derive(Debug, Default, Clone)]
+pub struct Totals {
+    requests: u64,
+    request_secs: f64,
+    /// Calls and spend by provider name.
+    calls: HashMap<String, (u64, f64)>,
+    /// Failed calls by provider label.
+    errors: HashMap<String, u64>,
+}
+
+impl Totals {
+    pub fn new(metrics: &LatencyMetrics, spend: &SpendStats) -> Self {
+        let requests = metrics.request_counts();
+        let mut errors: HashMap<String, u64> = HashMap::new();
+        for (provider, _, count) in metrics.provider_error_counts() {
+            *errors.entry(provider).or_default() += count;
+        }
+        Totals {
+            requests: requests.count(),
+            request_secs: requests.sum_secs,
+            calls: spend
+                .providers
+                .iter()
+                .map(|p| (p.name.clone(), (p.calls, p.spend)))
+                .collect(),
+            errors,
+        }
+    }
+}
+
+pub struct Reports {
+    config: ReportsConfig,
+    /// End of the previous report and the counters then.
+    last: Mutex<(DateTime<Utc>, Totals)>,
+}
+
+impl Reports {
+    pub fn new(config: ReportsConfig) -> Self {
+        Reports {
+            config,
+            last: Mutex::new((Utc::now(), Totals::default())),
+        }
+    }
+
+    /// End of the period running at `now`.
+    pub fn next_run(&self, now: DateTime<Utc>) -> DateTime<Utc> {
+        let days = match self.config.period {
+            ReportPeriod::Daily => 1,
+            ReportPeriod::Weekly => 7 - now.weekday().num_days_from_monday() as i64,
+        };
+        let midnight = now.date_naive().and_hms_opt(0, 0, 0).expect("valid time");
+        (midnight + Duration::days(days)).and_utc()
+    }
+
+    /// Sums up the period ending at `now`, the next one starts from there.
+    pub fn summarize(
+        &self,
+        now: DateTime<Utc>,
+        totals: Totals,
+        status: Vec<ProviderStatus>,
+    ) -> Summary {
+        let mut last = self.last.lock().unwrap();
+        let (from, before) = std::mem::replace(&mut *last, (now, totals.clone()));
+        let requests = totals.requests.saturating_sub(before.requests);
+        let request_secs = totals.request_secs - before.request_secs;
+        let providers: Vec<ProviderSummary> = status
+            .into_iter()
+            .map(|status| {
+                let (calls, spend) = totals.calls.get(&status.name).copied().unwrap_or_default();
+                let (calls_before, spend_before) =
+                    before.calls.get(&status.name).copied().unwrap_or_default();
+                let errors = totals.errors.get(&status.name).copied().unwrap_or_default();
+                let errors_before = before.errors.get(&status.name).copied().unwrap_or_default();
+                ProviderSummary {
+                    calls: calls.saturating_sub(calls_before),
+                    errors: errors.saturating_sub(errors_before),
+                    spend: spend - spend_before,
+                    health: status.health,
+                    success_rate: status.success_rate,
+                    quota_remaining: status.quota_remaining,
+                    name: status.name,
+                }
+            })
+            .collect();
+        let errors: u64 = totals.errors.values().sum();
+        let errors_before: u64 = before.errors.values().sum();
+        Summary {
+            period: self.config.period,
+            from: from.to_rfc3339_opts(SecondsFormat::Secs, true),
+            to: now.to_rfc3339_opts(SecondsFormat::Secs, true),
+            requests,
+            mean_latency_ms: (requests > 0).then(|| request_secs * 1_000.0 / requests as f64),
+            provider_calls: providers.iter().map(|p| p.calls).sum(),
+            provider_errors: errors.saturating_sub(errors_before),
+            spend: providers.iter().map(|p| p.spend).sum(),
+            providers,
+        }
+    }
+
+    /// Writes the JSON and the HTML of `summary`, removes the reports past `keep`.
+    pub async fn save(&self, summary: &Summary) -> Result<PathBuf, Error> {
+        let dir = &self.config.dir;
+        tokio::fs::create_dir_all(dir)
+            .await
+            .map_err(|e| Error::Internal(format!("reports: {}: {e}", dir.display())))?;
+        let path = dir.join(format!("{}.json", name(summary)));
+        save_snapshot(&path, summary).await?;
+        tokio::fs::write(path.with_extension("html"), html(summary))
+            .await
+            .map_err(|e| Error::Internal(format!("reports: {}: {e}", path.display())))?;
+        for old in self.files().into_iter().rev().skip(self.config.keep) {
+            for old in [old.clone(), old.with_extension("html")] {
+                if let Err(e) = std::fs::remove_file(&old) {
+                    warn!("reports: {} not removed: {}", old.display(), e);
+                }
+            }
+        }
+        Ok(path)
+    }
+
+    /// The most recent report written.
+    pub fn latest(&self) -> Option<Summary> {
+        let path = self.files().pop()?;
+        load_snapshot(&path).map(|(summary, _)| summary)
+    }
+
+    /// JSON reports of the period, oldest first.
+    fn files(&self) -> Vec<PathBuf> {
+        let prefix = format!("{}-", period_name(self.config.period));
+        let Ok(entries) = std::fs::read_dir(&self.config.dir) else {
+            return Vec::new();
+        };
+        let mut files: Vec<PathBuf> = entries
+            .flatten()
+            .map(|entry| entry.path())
+            .filter(|path| is_report(path, &prefix))
+            .collect();
+        // the dates in the names sort chronologically
+        files.sort();
+        files
+    }
+}
+
+fn is_report(path: &Path, prefix: &str) -> bool {
+    path.extension().is_some_and(|ext| ext == "json")
+        && path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| name.starts_with(prefix))
+}
+
+fn period_name(period: ReportPeriod) -> &'static str {
+    match period {
+        ReportPeriod::Daily => "daily",
+        ReportPeriod::Weekly => "weekly",
+    }
+}
+
+/// `<period>-<first day>`, the day the period started on.
+fn name(summary: &Summary) -> String {
+    let length = match summary.period {
+        ReportPeriod::Daily => Duration::days(1),
+        ReportPeriod::Weekly => Duration::days(7),
+    };
+    let first = DateTime::parse_from_rfc3339(&summary.to)
+        .map(|to| (to - length).date_naive().to_string())
+        .unwrap_or_else(|_| summary.to.clone());
+    format!("{}-{first}", period_name(summary.period))
+}
+
+/// Standalone page of `summary`.
+pub fn html(summary: &Summary) -> String {
+    let mut out = String::new();
+    let title = format!(
+        "{} report {} to {}",
+        period_name(summary.period),
+        summary.from,
+        summary.to
+    );
+    let _ = writeln!(
+        out,
+        "<!doctype html>\n<html lang=\"en\">\n<head>\n<meta charset=\"utf-8\">"
+    );
+    let _ = writeln!(out, "<title>{}</title>", escape(&title));
+    let _ = writeln!(
+        out,
+        "<style>body {{ font: 14px/1.4 system-ui, sans-serif; margin: 1.5rem; }} \
+         table {{ border-collapse: collapse; }} \
+         th, td {{ border-bottom: 1px solid #eee; padding: .3rem .6rem; text-align: left; }}</style>"
+    );
+    let _ = writeln!(out, "</head>\n<body>\n<h1>{}</h1>", escape(&title));
+    let latency = summary
+        .mean_latency_ms
+        .map_or("–".to_string(), |ms| format!("{ms:.1} ms"));
+    let _ = writeln!(out, "<table>");
+    for (label, value) in [
+        ("API requests", summary.requests.to_string()),
+        ("Mean latency", latency),
+        ("Provider calls", summary.provider_calls.to_string()),
+        ("Failed provider calls", summary.provider_errors.to_string()),
+        ("Spend", format!("{:.2}", summary.spend)),
+    ] {
+        let _ = writeln!(out, "<tr><th>{label}</th><td>{value}</td></tr>");
+    }
+    let _ = writeln!(out, "</table>\n<h2>Providers</h2>\n<table>");
+    let _ = writeln!(
+        out,
+        "<tr><th>Name</th><th>Calls</th><th>Failed</th><th>Spend</th><th>Health</th><th>Success</th><th>Quota left</th></tr>"
+    );
+    for p in &summary.providers {
+        let success = p
+            .success_rate
+            .map_or("–".to_string(), |rate| format!("{:.1} %", rate * 100.0));
+        let quota = p.quota_remaining.map_or("–".to_string(), |q| q.to_string());
+        let health = serde_json::to_value(p.health).unwrap_or_default();
+        let _ = writeln!(
+            out,
+            "<tr><td>{}</td><td>{}</td><td>{}</td><td>{:.2}</td><td>{}</td><td>{success}</td><td>{quota}</td></tr>",
+            escape(&p.name),
+            p.calls,
+            p.errors,
+            p.spend,
+            health.as_str().unwrap_or_default(),
+        );
+    }
+    let _ = writeln!(out, "</table>\n</body>\n</html>");
+    out
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::providers::health::CircuitState;
+
+    fn at(time: &str) -> DateTime<Utc> {
+        time.parse().unwrap()
+    }
+
+    fn totals(requests: u64, calls: u64, errors: u64) -> Totals {
+        Totals {
+            requests,
+            request_secs: requests as f64 * 0.01,
+            calls: [("ipinfo".to_string(), (calls, calls as f64 * 0.5))].into(),
+            errors: [("ipinfo".to_string(), errors)].into(),
+        }
+    }
+
+    fn status() -> Vec<ProviderStatus> {
+        vec![ProviderStatus {
+            name: "ipinfo".into(),
+            kind: "ipinfo".into(),
+            weight: 1,
+            cost: 0,
+            cost_per_call: 0.5,
+            capabilities: Vec::new(),
+            health: HealthState::Healthy,
+            circuit: CircuitState::Closed,
+            demoted: false,
+            samples: 10,
+            success_rate: Some(0.9),
+            latency_p50_ms: None,
+            latency_p99_ms: None,
+            quota_remaining: Some(1_000),
+        }]
+    }
+
+    #[test]
+    fn test_next_run() {
+        let daily = Reports::new(ReportsConfig::default());
+        assert_eq!(
+            daily.next_run(at("2026-10-14T15:30:00Z")),
+            at("2026-10-15T00:00:00Z")
+        );
+        let weekly = Reports::new(ReportsConfig {
+            period: ReportPeriod::Weekly,
+            ..Default::default()
+        });
+        // a Wednesday, then a Monday
+        assert_eq!(
+            weekly.next_run(at("2026-10-14T15:30:00Z")),
+            at("2026-10-19T00:00:00Z")
+        );
+        assert_eq!(
+            weekly.next_run(at("2026-10-19T00:00:00Z")),
+            at("2026-10-26T00:00:00Z")
+        );
+    }
+
+    #[test]
+    fn test_summarize() {
+        let reports = Reports::new(ReportsConfig::default());
+        let first = reports.summarize(at("2026-10-14T00:00:00Z"), totals(100, 10, 1), status());
+        assert_eq!((first.requests, first.provider_calls), (100, 10));
+        assert_eq!(first.mean_latency_ms, Some(10.0));
+        assert_eq!(first.spend, 5.0);
+        let second = reports.summarize(at("2026-10-15T00:00:00Z"), totals(150, 14, 3), status());
+        assert_eq!(
+            second.from, "2026-10-14T00:00:00Z",
+            "From the previous report"
+        );
+        assert_eq!((second.requests, second.provider_calls), (50, 4));
+        assert_eq!((second.provider_errors, second.providers[0].errors), (2, 2));
+        assert_eq!(second.providers[0].quota_remaining, Some(1_000));
+        let html = html(&second);
+        assert!(html.contains("<tr><th>API requests</th><td>50</td></tr>"));
+        assert!(html.contains("<td>healthy</td>"));
+    }
+
+    #[tokio::test]
+    async fn test_save() {
+        let dir = std::env::temp_dir().join(format!("ip-service-reports-{}", uuid::Uuid::new_v4()));
+        let reports = Reports::new(ReportsConfig {
+            dir: dir.clone(),
+            keep: 2,
+            ..Default::default()
+        });
+        assert!(reports.latest().is_none());
+        for day in 14..=16 {
+            let now = at(&format!("2026-10-{day}T00:00:00Z"));
+            let summary = reports.summarize(now, totals(day, 0, 0), status());
+            reports.save(&summary).await.unwrap();
+        }
+        let mut names: Vec<String> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        assert_eq!(
+            names,
+            [
+                "daily-2026-10-14.html",
+                "daily-2026-10-14.json",
+                "daily-2026-10-15.html",
+                "daily-2026-10-15.json"
+            ],
+            "Named by the day covered, the oldest removed"
+        );
+        assert_eq!(reports.latest().unwrap().to, "2026-10-16T00:00:00Z");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
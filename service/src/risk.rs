@@ -0,0 +1,256 @@
+//! Composite risk score
+//!
+//! Folds geo anomalies, anonymity flags, DNSBL hits and reputation feeds into a
+//! single 0-100 score. Every signal has a configurable weight, the score is the
+//! sum of `weight * value` (value in `0.0..=1.0`) capped at 100, and the explain
+//! block lists each contributing signal.
+
+use crate::{
+    abuseipdb::AbuseReport,
+    anonymity::Anonymity,
+    config::RiskWeights,
+    dnsbl::DnsblReport,
+    geo::{Geo, Threat},
+    greynoise::Noise,
+};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+/// Risk score with the signals that contributed to it.
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone, PartialEq)]
+pub struct RiskScore {
+    /// 0 (no known risk) to 100.
+    pub score: u8,
+    /// Contributing signals, highest contribution first.
+    pub signals: Vec<RiskSignal>,
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone, PartialEq)]
+pub struct RiskSignal {
+    pub name: String,
+    /// Configured weight of the signal.
+    pub weight: f64,
+    /// Strength of the signal, 0.0 to 1.0.
+    pub value: f64,
+    /// `weight * value`, the points added to the score.
+    pub points: f64,
+}
+
+/// Everything the score is computed from, missing sources are skipped.
+pub struct RiskInputs<'a> {
+    pub geo: &'a Geo,
+    pub anonymity: &'a Anonymity,
+    pub abuse: Option<&'a AbuseReport>,
+    pub noise: Option<&'a Noise>,
+    pub dnsbl: Option<&'a DnsblReport>,
+    pub threat: Option<&'a Threat>,
+    pub threat_lists: Option<&'a [String]>,
+}
+
+impl RiskScore {
+    pub fn compute(weights: &RiskWeights, inputs: &RiskInputs) -> Self {
+        let mut signals = Vec::new();
+        let mut add = |name: &str, weight: f64, value: f64| {
+            if value > 0.0 && weight > 0.0 {
+                signals.push(RiskSignal {
+                    name: name.to_string(),
+                    weight,
+                    value,
+                    points: weight * value,
+                });
+            }
+        };
+        let flag = |set: bool| if set { 1.0 } else { 0.0 };
+
+        let anonymity = inputs.anonymity;
+        add("vpn", weights.vpn, flag(anonymity.vpn));
+        add("proxy", weights.proxy, flag(anonymity.proxy));
+        add("relay", weights.relay, flag(anonymity.relay));
+        add("hosting", weights.hosting, flag(anonymity.hosting));
+
+        if let Some(abuse) = inputs.abuse {
+            add(
+                "abuse_confidence",
+                weights.abuse_confidence,
+                f64::from(abuse.confidence_score) / 100.0,
+            );
+            let mismatch = matches!(
+                (inputs.geo.country_code.as_deref(), abuse.country_code.as_deref()),
+                (Some(a), Some(b)) if !a.eq_ignore_ascii_case(b)
+            );
+            add("geo_mismatch", weights.geo_mismatch, flag(mismatch));
+        }
+        if let Some(noise) = inputs.noise {
+            let malicious = noise.classification.as_deref() == Some("malicious");
+            add("noise_malicious", weights.noise_malicious, flag(malicious));
+        }
+        if let Some(dnsbl) = inputs.dnsbl {
+            // one listing is already a strong signal, two or more saturate it
+            let listings = (dnsbl.listed.len() as f64 / 2.0).min(1.0);
+            add("dnsbl", weights.dnsbl, listings);
+        }
+        if let Some(threat) = inputs.threat {
+            let known = threat.is_known_attacker || threat.is_known_abuser || threat.is_threat;
+            add("known_threat", weights.known_threat, flag(known));
+            let listings = (threat.blocklists.len() as f64 / 2.0).min(1.0);
+            add("provider_blocklists", weights.provider_blocklists, listings);
+        }
+        if let Some(lists) = inputs.threat_lists {
+            let listings = (lists.len() as f64 / 2.0).min(1.0);
+            add("threat_lists", weights.threat_lists, listings);
+        }
+        add(
+            "null_island",
+            weights.null_island,
+            flag(null_island(inputs.geo)),
+        );
+
+        signals.sort_by(|a, b| b.points.total_cmp(&a.points));
+        let total: f64 = signals.iter().map(|s| s.points).sum();
+        RiskScore {
+            score: total.round().clamp(0.0, 100.0) as u8,
+            signals,
+        }
+    }
+}
+
+/// Coordinates of exactly 0,0 are a placeholder for an unknown location.
+fn null_island(geo: &Geo) -> bool {
+    matches!(
+        (geo.latitude, geo.longitude),
+        (Some(lat), Some(lon)) if lat == 0.0 && lon == 0.0
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dnsbl::DnsblMatch;
+
+    fn geo(country: Option<&str>, lat: f64, lon: f64) -> Geo {
+        let mut geo = Geo::new("1.2.3.4".parse().unwrap(), "test");
+        geo.country_code = country.map(str::to_string);
+        geo.latitude = Some(lat);
+        geo.longitude = Some(lon);
+        geo
+    }
+
+    fn abuse(score: u8, country: &str) -> AbuseReport {
+        AbuseReport {
+            confidence_score: score,
+            total_reports: 10,
+            distinct_reporters: 3,
+            last_reported_at: None,
+            country_code: Some(country.to_string()),
+            usage_type: None,
+            is_tor: false,
+            is_whitelisted: false,
+        }
+    }
+
+    #[test]
+    fn test_clean_address() {
+        let geo = geo(Some("DE"), 52.5, 13.4);
+        let inputs = RiskInputs {
+            geo: &geo,
+            anonymity: &Anonymity::default(),
+            abuse: None,
+            noise: None,
+            dnsbl: None,
+            threat: None,
+            threat_lists: None,
+        };
+        let risk = RiskScore::compute(&RiskWeights::default(), &inputs);
+        assert_eq!(risk.score, 0);
+        assert!(risk.signals.is_empty());
+    }
+
+    #[test]
+    fn test_explain() {
+        let geo = geo(Some("DE"), 52.5, 13.4);
+        let anonymity = Anonymity {
+            vpn: true,
+            ..Default::default()
+        };
+        let abuse = abuse(50, "NL");
+        let dnsbl = DnsblReport {
+            listed: vec![DnsblMatch {
+                zone: "zen.spamhaus.org".into(),
+                codes: vec!["127.0.0.2".into()],
+            }],
+            checked: 3,
+            failed: Vec::new(),
+        };
+        let inputs = RiskInputs {
+            geo: &geo,
+            anonymity: &anonymity,
+            abuse: Some(&abuse),
+            noise: None,
+            dnsbl: Some(&dnsbl),
+            threat: None,
+            threat_lists: None,
+        };
+        let weights = RiskWeights::default();
+        let risk = RiskScore::compute(&weights, &inputs);
+
+        let names: Vec<&str> = risk.signals.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(
+            names,
+            vec!["abuse_confidence", "vpn", "dnsbl", "geo_mismatch"]
+        );
+        let expected = weights.abuse_confidence * 0.5
+            + weights.vpn
+            + weights.geo_mismatch
+            + weights.dnsbl * 0.5;
+        assert_eq!(risk.score, expected.round() as u8);
+    }
+
+    #[test]
+    fn test_provider_threat() {
+        let geo = geo(Some("DE"), 52.5, 13.4);
+        let threat = Threat {
+            is_known_attacker: true,
+            blocklists: vec!["Blocklist.de".into()],
+            ..Default::default()
+        };
+        let lists = ["feodotracker".to_string()];
+        let inputs = RiskInputs {
+            geo: &geo,
+            anonymity: &Anonymity::default(),
+            abuse: None,
+            noise: None,
+            dnsbl: None,
+            threat: Some(&threat),
+            threat_lists: Some(&lists),
+        };
+        let weights = RiskWeights::default();
+        let risk = RiskScore::compute(&weights, &inputs);
+        let expected =
+            weights.known_threat + weights.provider_blocklists * 0.5 + weights.threat_lists * 0.5;
+        assert_eq!(risk.score, expected.round() as u8);
+    }
+
+    #[test]
+    fn test_capped() {
+        let geo = geo(None, 0.0, 0.0);
+        let anonymity = Anonymity {
+            vpn: true,
+            proxy: true,
+            hosting: true,
+            relay: true,
+        };
+        let abuse = abuse(100, "US");
+        let inputs = RiskInputs {
+            geo: &geo,
+            anonymity: &anonymity,
+            abuse: Some(&abuse),
+            noise: None,
+            dnsbl: None,
+            threat: None,
+            threat_lists: None,
+        };
+        let risk = RiskScore::compute(&RiskWeights::default(), &inputs);
+        assert_eq!(risk.score, 100);
+        assert!(risk.signals.iter().any(|s| s.name == "null_island"));
+    }
+}
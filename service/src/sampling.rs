@@ -0,0 +1,72 @@
+//! Random sampling helpers
+
+use crate::config::LogSamplingConfig;
+use uuid::Uuid;
+
+/// `true` with the given probability, `0.0` never and `1.0` always.
+pub fn chance(probability: f64) -> bool {
+    // v4 UUIDs come from the OS generator, good enough for sampling
+    let (roll, _) = Uuid::new_v4().as_u64_pair();
+    (roll as f64 / u64::MAX as f64) < probability
+}
+
+/// FNV-1a, unlike `DefaultHasher` it is stable across releases and runs.
+pub fn stable_hash(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325_u64, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// What a request log line reports, each outcome has its own rate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    CacheHit,
+    CacheMiss,
+    Error,
+}
+
+/// Which per-request info logs are written, a rate of 1 skips the roll.
+#[derive(Debug, Clone)]
+pub struct LogSampler {
+    config: LogSamplingConfig,
+}
+
+impl LogSampler {
+    pub fn new(config: LogSamplingConfig) -> Self {
+        LogSampler { config }
+    }
+
+    /// Share of the requests with `outcome` that are logged.
+    pub fn rate(&self, outcome: Outcome) -> f64 {
+        match outcome {
+            Outcome::CacheHit => self.config.cache_hits,
+            Outcome::CacheMiss => self.config.cache_misses,
+            Outcome::Error => self.config.errors,
+        }
+    }
+
+    pub fn sample(&self, outcome: Outcome) -> bool {
+        let rate = self.rate(outcome);
+        rate >= 1.0 || (rate > 0.0 && chance(rate))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_log_sampler() {
+        let sampler = LogSampler::new(LogSamplingConfig {
+            cache_hits: 0.0,
+            cache_misses: 0.5,
+            errors: 1.0,
+        });
+        assert!(!(0..1000).any(|_| sampler.sample(Outcome::CacheHit)));
+        assert!((0..1000).all(|_| sampler.sample(Outcome::Error)));
+        let misses = (0..10_000)
+            .filter(|_| sampler.sample(Outcome::CacheMiss))
+            .count();
+        assert!((4_000..6_000).contains(&misses), "{misses} of 10000");
+    }
+}
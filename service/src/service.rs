@@ -0,0 +1,2829 @@
+//! Lookup pipeline shared by the HTTP server and embedding applications
+//!
+//! A [`LookupService`] resolves an address with the configured providers and
+//! enriches the answer with the optional reputation feeds, the anonymity flags
+//! and the risk score. It is cheap to clone and can be shared between tasks.
+//!
+//! ```no_run
+//! use ip_service::{
+//!     config::{CacheConfig, ProviderConfig, ProviderKind},
+//!     LookupRequest, LookupService,
+//! };
+//!
+//! # async fn run() -> Result<(), ip_service::Error> {
+//! let service = LookupService::builder()
+//!     .provider(ProviderConfig::new(ProviderKind::IpInfo))
+//!     .cache(CacheConfig::default())
+//!     .build();
+//! let lookup = service.lookup(&LookupRequest::ip("8.8.8.8")).await?;
+//! println!("{:?}", lookup.geo.country);
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::{
+    abuseipdb::{AbuseIpDb, AbuseReport},
+    anonymity::Anonymity,
+    anycast::{self, Anycast},
+    asn::{AsnDb, AsnDetail},
+    cache::TtlCache,
+    canary::{Canary, CanaryReport},
+    carrier::{self, ConnectionType},
+    cidr::{self, CidrSummary},
+    confidence::{Confidence, Evidence},
+    config::{
+        AbuseIpDbConfig, AnycastConfig, AsnConfig, BalancingProfile, BatchConfig, CacheConfig,
+        CanaryConfig, ChaosConfig, Config, DiscrepancyConfig, DnsConfig, DnsblConfig,
+        FailoverPolicy, GreyNoiseConfig, IxpConfig, JobsConfig, MergeConfig, MetricsConfig,
+        NetblockConfig, OtlpConfig, OutboundConfig, PagingConfig, PassiveDnsConfig, PingConfig,
+        Priority, ProbeConfig, ProviderConfig, ProxyConfig, RecordingConfig, ReportsConfig,
+        RiskConfig, Rollout, Routing, ShodanConfig, StatsdConfig, TargetPolicyConfig,
+        ThreatListsConfig, TlsConfig, TracerouteConfig, UpstreamConfig,
+    },
+    country::{self, CountryFlag},
+    discrepancy::{Comparator, DiscrepancyStats},
+    dns::{Resolution, Resolver},
+    dnsbl::{Dnsbl, DnsblReport},
+    error::{Error, ErrorBody},
+    flags::{self, Flags},
+    geo::{parse_asn, Field, Geo, Threat},
+    greynoise::{GreyNoise, Noise},
+    ixp::{Exchange, PeeringDb},
+    jobs::{DeadLetter, Job, Jobs},
+    maintenance::Maintenance,
+    netblock::{Netblock, Rdap},
+    otlp::Exporter,
+    paging::Pager,
+    pdns::{PassiveDns, PassiveDnsClient},
+    ping::{Ping, Pinger},
+    policy::TargetPolicy,
+    probe::{Probe, Prober},
+    prometheus::LatencyMetrics,
+    providers::{
+        chaos::Chaos,
+        hosts::Hosts,
+        recording::Recorder,
+        registry::{LookupOptions, ProviderRegistry, ProviderStatus, ProviderUsage, SpendStats},
+        timing, ProviderLookup, Transport,
+    },
+    readiness::{self, Readiness},
+    reports::{Reports, Summary, Totals},
+    risk::{RiskInputs, RiskScore},
+    shadow::{ShadowStats, Shadows},
+    shodan::{Shodan, ShodanHost},
+    statsd::Statsd,
+    threatlist::ThreatLists,
+    tls::{self, Inspector, TlsInspection},
+    traceroute::{self, Tracer, Traceroute},
+};
+use chrono::Utc;
+use futures::{stream, Stream, StreamExt};
+use ipnet::IpNet;
+use public_ip_address::perform_lookup;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
+use tracing::{debug, info, warn};
+use utoipa::ToSchema;
+
+#[derive(Deserialize, Serialize, ToSchema, Debug, Clone, Default)]
+pub struct LookupRequest {
+    /// Address to resolve, the public address of the service when absent.
+    #[schema(example = "8.8.8.8")]
+    pub ip: Option<String>,
+    /// Hostname to resolve instead of `ip`, every address it resolves to is looked up.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub host: Option<String>,
+    /// Name of the configured provider to try first, e.g. `ipapi`.
+    pub provider: Option<String>,
+    /// Attach Shodan host context, slow and quota-bound.
+    #[serde(default)]
+    pub shodan: bool,
+    /// Attach the domains passive DNS has seen on the address.
+    #[serde(default)]
+    pub passive_dns: bool,
+    /// Check the address against the configured DNS blocklists.
+    #[serde(default)]
+    pub dnsbl: bool,
+    /// Attach the flag emoji and image of the country.
+    #[serde(default)]
+    pub country_flag: bool,
+    /// Confirm anycast with the latency probes, slow. Listed prefixes are
+    /// always flagged.
+    #[serde(default)]
+    pub anycast_probe: bool,
+    /// Only these fields are needed, all of them when empty.
+    #[serde(default)]
+    pub fields: Vec<Field>,
+    /// Overrides the configured failover policy.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub failover: Option<FailoverPolicy>,
+    /// Answer from the caches and offline providers only, never calling a
+    /// paid API. Addresses they do not know are answered with a 404.
+    #[serde(default)]
+    pub cache_only: bool,
+    /// A cached answer older than this is looked up again, 0 always calls
+    /// a provider.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(example = 3600)]
+    pub max_age_secs: Option<u64>,
+    /// Wait at most this long for the providers, capped by the server. The
+    /// location is then unknown and the lookup `degraded` instead of failing.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(example = 300)]
+    pub timeout_ms: Option<u64>,
+    /// `bulk` leaves the upstream slots to the interactive lookups first.
+    #[serde(default)]
+    pub priority: Priority,
+}
+
+impl LookupRequest {
+    /// Request for a single address with the default options.
+    pub fn ip(ip: impl Into<String>) -> Self {
+        LookupRequest {
+            ip: Some(ip.into()),
+            ..Default::default()
+        }
+    }
+}
+
+/// Enriched answer of a lookup.
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone)]
+pub struct Lookup {
+    pub ip: String,
+    pub raw: serde_json::Value,
+    /// The selected provider failed or lacks some requested fields, the answer
+    /// came from a fallback and may be partial.
+    pub degraded: bool,
+    /// The provider answer came from the cache. Served as `X-Cache` instead,
+    /// the body and its `ETag` are the same on a miss and on a hit.
+    #[serde(skip)]
+    pub cached: bool,
+    pub geo: Geo,
+    pub anonymity: Anonymity,
+    pub connection_type: ConnectionType,
+    /// Announced from several sites, the location is one of them at best.
+    pub is_anycast: bool,
+    /// Internet exchange of the peering LAN, `geo` is then its location.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ixp: Option<Exchange>,
+    /// Caveats about the answer, e.g. for anycast addresses.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub warnings: Vec<String>,
+    /// Absent while the `risk_scoring` flag is off for the address.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub risk: Option<RiskScore>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub threat: Option<Threat>,
+    /// Configured threat lists the address is on, absent until they are downloaded.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub threat_lists: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub abuse: Option<AbuseReport>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub noise: Option<Noise>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub shodan: Option<ShodanHost>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub passive_dns: Option<PassiveDns>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dnsbl: Option<DnsblReport>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub country_flag: Option<CountryFlag>,
+    /// Served in the v2 schema only.
+    #[serde(skip)]
+    pub confidence: Confidence,
+    /// Set for hostname lookups, the top level describes the first address.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub host: Option<HostLookup>,
+}
+
+/// Resolution of a hostname and the lookup of every address.
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone)]
+pub struct HostLookup {
+    #[serde(flatten)]
+    pub resolution: Resolution,
+    /// One item per address, in resolution order.
+    pub results: Vec<BatchItem>,
+}
+
+/// [`Lookup`] answered over HTTP.
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone)]
+pub struct LookupResponse {
+    pub request_id: String,
+    pub latency_ms: u128,
+    #[serde(flatten)]
+    pub lookup: Lookup,
+}
+
+/// Several addresses looked up with the same options.
+#[derive(Deserialize, Serialize, ToSchema, Debug, Clone, Default)]
+pub struct BatchRequest {
+    #[schema(example = json!(["8.8.8.8", "1.1.1.1"]))]
+    pub ips: Vec<String>,
+    /// Name of the configured provider to try first.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub provider: Option<String>,
+    /// Only these fields are needed, all of them when empty.
+    #[serde(default)]
+    pub fields: Vec<Field>,
+    /// Overrides the configured failover policy.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub failover: Option<FailoverPolicy>,
+    /// Answer from the caches and offline providers only, see [`LookupRequest::cache_only`].
+    #[serde(default)]
+    pub cache_only: bool,
+    /// Oldest cached answer accepted, see [`LookupRequest::max_age_secs`].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_age_secs: Option<u64>,
+    /// `bulk` unless set, see [`LookupRequest::priority`].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub priority: Option<Priority>,
+}
+
+impl BatchRequest {
+    pub fn new<I: IntoIterator<Item = S>, S: Into<String>>(ips: I) -> Self {
+        BatchRequest {
+            ips: ips.into_iter().map(Into::into).collect(),
+            ..Default::default()
+        }
+    }
+
+    fn request(&self, ip: &str) -> LookupRequest {
+        LookupRequest {
+            ip: Some(ip.to_string()),
+            provider: self.provider.clone(),
+            fields: self.fields.clone(),
+            failover: self.failover,
+            cache_only: self.cache_only,
+            max_age_secs: self.max_age_secs,
+            priority: self.priority.unwrap_or(Priority::Bulk),
+            ..Default::default()
+        }
+    }
+}
+
+/// Outcome of one address of a batch, either `lookup` or `error` is set.
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone)]
+pub struct BatchItem {
+    /// Address as submitted.
+    pub ip: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lookup: Option<Lookup>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<ErrorBody>,
+}
+
+impl BatchItem {
+    fn new(ip: String, lookup: Result<Lookup, Error>) -> Self {
+        let (lookup, error) = match lookup {
+            Ok(lookup) => (Some(lookup), None),
+            Err(e) => (None, Some(e.body())),
+        };
+        BatchItem { ip, lookup, error }
+    }
+}
+
+/// Addresses written differently but equal, e.g. `::1` and `0::1`, share a key.
+pub(crate) fn dedup_key(ip: &str) -> String {
+    let ip = ip.trim();
+    match ip.parse::<IpAddr>() {
+        Ok(addr) => addr.to_string(),
+        Err(_) => ip.to_ascii_lowercase(),
+    }
+}
+
+/// How far apart two addresses are located.
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone)]
+pub struct Distance {
+    /// Great-circle distance, absent unless both sides have coordinates.
+    pub distance_km: Option<f64>,
+    /// Absent unless both countries are known.
+    pub same_country: Option<bool>,
+    /// Absent unless both ASNs are known.
+    pub same_asn: Option<bool>,
+    pub from: Lookup,
+    pub to: Lookup,
+}
+
+impl Distance {
+    fn new(from: Lookup, to: Lookup) -> Self {
+        let (a, b) = (&from.geo, &to.geo);
+        let same_country = match (&a.country_code, &b.country_code) {
+            (Some(a), Some(b)) => Some(a.eq_ignore_ascii_case(b)),
+            _ => None,
+        };
+        let same_asn = a.asn.zip(b.asn).map(|(a, b)| a == b);
+        Distance {
+            distance_km: a.distance_km(b),
+            same_country,
+            same_asn,
+            from,
+            to,
+        }
+    }
+}
+
+/// Most addresses of a synchronous batch, larger ones go through a job.
+pub const MAX_BATCH: usize = 1_000;
+/// Most addresses of a job.
+pub const MAX_JOB: usize = 100_000;
+/// Lookups in flight at once of the CIDR summaries, traceroutes and cache refreshes.
+const BATCH_CONCURRENCY: usize = 8;
+/// Most addresses of a hostname looked up.
+const MAX_HOST_ADDRESSES: usize = 16;
+/// Wait of the first canary run after the start.
+const CANARY_START_DELAY: Duration = Duration::from_secs(10);
+
+struct Inner {
+    providers: ProviderRegistry,
+    /// Provider answers of lookups without an explicit provider.
+    cache: Option<TtlCache<ProviderLookup>>,
+    abuseipdb: Option<AbuseIpDb>,
+    greynoise: Option<GreyNoise>,
+    shodan: Option<Shodan>,
+    passive_dns: Option<PassiveDnsClient>,
+    dnsbl: Option<Dnsbl>,
+    anycast: Anycast,
+    ixp: Option<Arc<PeeringDb>>,
+    asn: Option<Arc<AsnDb>>,
+    rdap: Option<Arc<Rdap>>,
+    threat_lists: Option<Arc<ThreatLists>>,
+    tracer: Option<Tracer>,
+    pinger: Option<Pinger>,
+    prober: Option<Prober>,
+    inspector: Option<Inspector>,
+    discrepancy: Option<Comparator>,
+    shadows: Shadows,
+    risk: RiskConfig,
+    failover: FailoverPolicy,
+    flags: Flags,
+    maintenance: Maintenance,
+    policy: TargetPolicy,
+    /// Cap of the `timeout_ms` of the requests.
+    max_timeout: Option<Duration>,
+    batch: BatchConfig,
+    jobs: Jobs,
+    reports: Option<Reports>,
+    pager: Option<Pager>,
+    canary: Option<Canary>,
+    resolver: Resolver,
+    /// Shared with the transport, which observes the provider calls.
+    metrics: Arc<LatencyMetrics>,
+    /// Fill missing hostnames with a PTR lookup.
+    ptr: bool,
+    /// Template of the country flag images.
+    flag_image_url: String,
+}
+
+/// Provider registry, caches and enrichment feeds behind one lookup call.
+#[derive(Clone)]
+pub struct LookupService {
+    inner: Arc<Inner>,
+}
+
+impl LookupService {
+    pub fn builder() -> LookupServiceBuilder {
+        LookupServiceBuilder::default()
+    }
+
+    /// Service configured from the file and environment, see [`Config::load`].
+    pub fn from_config(config: Config) -> Self {
+        LookupServiceBuilder::from_config(config).build()
+    }
+
+    /// Resolves and enriches the requested address or hostname.
+    pub async fn lookup(&self, req: &LookupRequest) -> Result<Lookup, Error> {
+        match &req.host {
+            Some(_) if req.ip.is_some() => {
+                Err(Error::InvalidInput("either ip or host, not both".into()))
+            }
+            Some(host) => self.lookup_host(host, req).await,
+            None => self.lookup_address(req).await,
+        }
+    }
+
+    /// How long `lookup` stays valid: the time left of the cached provider
+    /// answer behind it. `None` when it was not cached, e.g. a degraded
+    /// lookup or one with an explicit provider.
+    pub fn cache_ttl(&self, req: &LookupRequest, lookup: &Lookup) -> Option<Duration> {
+        let cache = self
+            .inner
+            .cache
+            .as_ref()
+            .filter(|_| req.provider.is_none())?;
+        if lookup.degraded || lookup.host.is_some() {
+            return None;
+        }
+        cache.remaining(&lookup.ip.parse().ok()?)
+    }
+
+    async fn lookup_host(&self, host: &str, req: &LookupRequest) -> Result<Lookup, Error> {
+        let resolution = self.inner.resolver.resolve(host).await?;
+        let addresses = &resolution.addresses[..resolution.addresses.len().min(MAX_HOST_ADDRESSES)];
+        if addresses.is_empty() {
+            return Err(Error::NotFound(format!("address of {host}")));
+        }
+        let lookups = addresses.iter().map(|addr| async move {
+            let req = LookupRequest {
+                ip: Some(addr.to_string()),
+                host: None,
+                ..req.clone()
+            };
+            self.lookup_address(&req).await
+        });
+        let mut lookups = futures::future::join_all(lookups).await;
+        // the top level is the first address that resolved, the error of the first otherwise
+        let Some(first) = lookups.iter().position(Result::is_ok) else {
+            return Err(lookups.swap_remove(0).unwrap_err());
+        };
+        let results: Vec<BatchItem> = addresses
+            .iter()
+            .zip(lookups)
+            .map(|(addr, lookup)| BatchItem::new(addr.to_string(), lookup))
+            .collect();
+        let mut lookup = results[first].lookup.clone().expect("successful lookup");
+        lookup.host = Some(HostLookup {
+            resolution,
+            results,
+        });
+        Ok(lookup)
+    }
+
+    async fn lookup_address(&self, req: &LookupRequest) -> Result<Lookup, Error> {
+        let state = &self.inner;
+        if let Some(provider) = &req.provider {
+            if !state.providers.contains(provider) {
+                return Err(Error::InvalidInput(format!("unknown provider {provider}")));
+            }
+            if req.cache_only && !state.providers.offline().contains(&provider.as_str()) {
+                return Err(Error::InvalidInput(format!(
+                    "cache_only can not use the online provider {provider}"
+                )));
+            }
+        }
+
+        let timeout = self.timeout(req)?;
+        let mut warnings = Vec::new();
+        let (mut lookup, cached) = match &req.ip {
+            Some(ip) => {
+                let ip = self.target(ip)?;
+                match timeout {
+                    Some(timeout) => {
+                        match tokio::time::timeout(timeout, self.resolve(ip, req)).await {
+                            Ok(resolved) => resolved?,
+                            Err(_) => {
+                                warnings.push(format!(
+                                    "no provider answered within {}ms, the location is unknown",
+                                    timeout.as_millis()
+                                ));
+                                (ProviderLookup::unknown(ip), false)
+                            }
+                        }
+                    }
+                    None => self.resolve(ip, req).await?,
+                }
+            }
+            // fallback: мой public IP
+            None if req.cache_only => {
+                return Err(Error::InvalidInput("cache_only needs an ip or host".into()));
+            }
+            None => {
+                state.maintenance.check(false)?;
+                (
+                    ProviderLookup::from_core(perform_lookup(None).await?),
+                    false,
+                )
+            }
+        };
+        let wants_hostname = req.fields.is_empty() || req.fields.contains(&Field::Hostname);
+        if state.ptr && wants_hostname && lookup.geo.hostname.is_none() {
+            match state.resolver.reverse(lookup.geo.ip).await {
+                Ok(names) => lookup.geo.hostname = names.into_iter().next(),
+                Err(e) => debug!("no PTR for {}: {}", lookup.geo.ip, e),
+            }
+        }
+        let ixp = state.ixp.as_ref().and_then(|ixp| ixp.find(lookup.geo.ip));
+        if let Some(exchange) = &ixp {
+            exchange.locate(&mut lookup.geo);
+        }
+        country::complete(&mut lookup.geo);
+        carrier::normalize(&mut lookup.geo);
+
+        let addr = lookup.geo.ip;
+        let ip = addr.to_string();
+        let mut feeds = vec![lookup.signals.clone()];
+        // the enrichment APIs are paid too
+        let abuse = match &state.abuseipdb {
+            Some(abuseipdb) if req.cache_only => abuseipdb.cached(addr),
+            Some(abuseipdb) => abuseipdb.check(addr).await,
+            None => None,
+        };
+        if let Some(abuse) = &abuse {
+            feeds.push(abuse.signals());
+        }
+        let noise = match &state.greynoise {
+            Some(greynoise) if req.cache_only => greynoise.cached(addr),
+            Some(greynoise) => greynoise.check(addr).await,
+            None => None,
+        };
+        if let Some(noise) = &noise {
+            feeds.push(noise.signals());
+        }
+        let shodan = match &state.shodan {
+            Some(shodan) if req.shodan && req.cache_only => shodan.cached(addr),
+            Some(shodan) if req.shodan => shodan.host(addr).await,
+            _ => None,
+        };
+        let passive_dns = match &state.passive_dns {
+            Some(passive_dns) if req.passive_dns && req.cache_only => passive_dns.cached(addr),
+            Some(passive_dns) if req.passive_dns => passive_dns.domains(addr).await,
+            _ => None,
+        };
+        let dnsbl = match &state.dnsbl {
+            Some(dnsbl) if req.dnsbl => Some(dnsbl.check(addr).await),
+            _ => None,
+        };
+        let threat_lists = state
+            .threat_lists
+            .as_ref()
+            .and_then(|threat_lists| threat_lists.lists(addr));
+
+        let is_anycast = state.anycast.detect(addr, req.anycast_probe).await;
+        if is_anycast {
+            warnings.push(anycast::WARNING.to_string());
+        }
+
+        let anonymity = Anonymity::detect(&lookup.geo, &feeds);
+        let connection_type = carrier::classify(&lookup.geo, &anonymity);
+        let risk = state.flags.enabled(flags::RISK_SCORING, &ip).then(|| {
+            RiskScore::compute(
+                &state.risk.weights,
+                &RiskInputs {
+                    geo: &lookup.geo,
+                    anonymity: &anonymity,
+                    abuse: abuse.as_ref(),
+                    noise: noise.as_ref(),
+                    dnsbl: dnsbl.as_ref(),
+                    threat: lookup.threat.as_ref(),
+                    threat_lists: threat_lists.as_deref(),
+                },
+            )
+        });
+        let country_flag = match &lookup.geo.country_code {
+            Some(code) if req.country_flag => CountryFlag::new(code, &state.flag_image_url),
+            _ => None,
+        };
+        let mut geo = lookup.geo;
+        let mut threat = lookup.threat;
+        if !req.fields.is_empty() {
+            geo.retain(&req.fields);
+            if !req.fields.contains(&Field::Threat) {
+                threat = None;
+            }
+        }
+        let evidence = Evidence {
+            agreement: state
+                .discrepancy
+                .as_ref()
+                .map(|comparator| comparator.agreement(&geo.provider)),
+            age: state
+                .cache
+                .as_ref()
+                .filter(|_| cached)
+                .and_then(|cache| cache.age(&addr))
+                .unwrap_or_default(),
+            data_age: lookup.data_age,
+            support: lookup.support,
+            is_anycast,
+            ixp: ixp.is_some(),
+        };
+        let confidence = Confidence::compute(&geo, &evidence);
+
+        Ok(Lookup {
+            ip,
+            raw: lookup.raw,
+            degraded: lookup.degraded,
+            cached,
+            geo,
+            anonymity,
+            connection_type,
+            is_anycast,
+            ixp,
+            warnings,
+            risk,
+            threat,
+            threat_lists,
+            abuse,
+            noise,
+            shodan,
+            passive_dns,
+            dnsbl,
+            country_flag,
+            confidence,
+            host: None,
+        })
+    }
+
+    /// Deadline of the provider answer the request asks for, within the cap.
+    fn timeout(&self, req: &LookupRequest) -> Result<Option<Duration>, Error> {
+        match req.timeout_ms {
+            None => Ok(None),
+            Some(0) => Err(Error::InvalidInput("timeout_ms must be positive".into())),
+            Some(ms) => {
+                let timeout = Duration::from_millis(ms);
+                Ok(Some(
+                    self.inner
+                        .max_timeout
+                        .map_or(timeout, |max| timeout.min(max)),
+                ))
+            }
+        }
+    }
+
+    /// Provider answer for `ip`, from the cache when possible, and whether it was.
+    async fn resolve(
+        &self,
+        ip: IpAddr,
+        req: &LookupRequest,
+    ) -> Result<(ProviderLookup, bool), Error> {
+        let state = &self.inner;
+        // an explicitly selected provider bypasses the cache
+        let cache = state.cache.as_ref().filter(|_| req.provider.is_none());
+        let fields = Field::sources(&req.fields);
+        let max_age = req.max_age_secs.map_or(Duration::MAX, Duration::from_secs);
+        if let Some(cached) = cache.and_then(|cache| cache.get_within(&ip, max_age)) {
+            if state.providers.supplies(&cached.geo.provider, &fields) {
+                state.maintenance.check(true)?;
+                return Ok((cached, true));
+            }
+        }
+        state.maintenance.check(false)?;
+        let lookup = self.query(ip, req).await?;
+        self.spawn_comparison(&lookup.geo);
+        self.spawn_shadows(&lookup.geo);
+        Ok((lookup, false))
+    }
+
+    /// Provider answer for `ip` bypassing the cache read, cached unless degraded.
+    async fn query(&self, ip: IpAddr, req: &LookupRequest) -> Result<ProviderLookup, Error> {
+        let state = &self.inner;
+        let offline = state.providers.offline();
+        if req.cache_only && offline.is_empty() {
+            return Err(Error::NotFound(format!("cached answer for {ip}")));
+        }
+        // an explicitly selected provider bypasses its rollout flag
+        let subject = ip.to_string();
+        let excluded = state
+            .providers
+            .names()
+            .into_iter()
+            .filter(|name| {
+                let paid = req.cache_only && !offline.contains(name);
+                let gated = req.provider.as_deref() != Some(*name)
+                    && !state.flags.enabled(&flags::provider_flag(name), &subject);
+                paid || gated
+            })
+            .collect();
+        let options = LookupOptions {
+            selected: req.provider.as_deref(),
+            fields: &Field::sources(&req.fields),
+            policy: req.failover.unwrap_or(state.failover),
+            excluded,
+            priority: req.priority,
+            max_age: req.max_age_secs.map(Duration::from_secs),
+        };
+        let lookup =
+            state
+                .providers
+                .lookup(ip, &options)
+                .await
+                .map_err(|e| match e.saturated() {
+                    // the wait until the quota or capacity is back, for the caller to back off
+                    Some(resource) => Error::Saturated {
+                        resource,
+                        retry_after_secs: state.providers.retry_in(resource).as_secs_f64().ceil()
+                            as u64,
+                    },
+                    None => e.into(),
+                })?;
+        let cache = state.cache.as_ref().filter(|_| req.provider.is_none());
+        if let Some(cache) = cache.filter(|_| !lookup.degraded) {
+            cache.insert(ip, lookup.clone());
+        }
+        Ok(lookup)
+    }
+
+    /// Re-resolves the `top` most read cache entries expiring within `before`
+    /// every half of `before`, until the service is dropped. Popular addresses
+    /// then stay cached instead of missing once per TTL.
+    fn spawn_cache_refresh(&self, top: usize, before: Duration) {
+        let inner = Arc::downgrade(&self.inner);
+        let every = (before / 2).max(Duration::from_secs(1));
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(every).await;
+                let Some(inner) = inner.upgrade() else {
+                    return;
+                };
+                let service = LookupService { inner };
+                service.refresh_hot(top, before).await;
+            }
+        });
+    }
+
+    /// Writes a report at the end of every period until the service is dropped.
+    fn spawn_reports(&self) {
+        let inner = Arc::downgrade(&self.inner);
+        tokio::spawn(async move {
+            loop {
+                let next = inner
+                    .upgrade()
+                    .and_then(|inner| inner.reports.as_ref().map(|r| r.next_run(Utc::now())));
+                let Some(next) = next else {
+                    return;
+                };
+                tokio::time::sleep((next - Utc::now()).to_std().unwrap_or_default()).await;
+                let Some(inner) = inner.upgrade() else {
+                    return;
+                };
+                match (LookupService { inner }).report().await {
+                    Ok(summary) => info!("reports: {} to {} written", summary.from, summary.to),
+                    Err(e) => warn!("reports: not written: {}", e),
+                }
+            }
+        });
+    }
+
+    /// Checks the providers for incidents every interval until the service is dropped.
+    fn spawn_paging(&self) {
+        let inner = Arc::downgrade(&self.inner);
+        tokio::spawn(async move {
+            loop {
+                let interval = inner
+                    .upgrade()
+                    .and_then(|inner| inner.pager.as_ref().map(Pager::interval));
+                let Some(interval) = interval else {
+                    return;
+                };
+                tokio::time::sleep(interval).await;
+                let Some(inner) = inner.upgrade() else {
+                    return;
+                };
+                if let Some(pager) = &inner.pager {
+                    pager.check(&inner.providers.status()).await;
+                }
+            }
+        });
+    }
+
+    /// Runs the canary every interval until the service is dropped, the first
+    /// run once the server had time to listen.
+    fn spawn_canary(&self) {
+        let inner = Arc::downgrade(&self.inner);
+        tokio::spawn(async move {
+            let mut delay = CANARY_START_DELAY;
+            loop {
+                tokio::time::sleep(delay).await;
+                let Some(inner) = inner.upgrade() else {
+                    return;
+                };
+                let service = LookupService { inner };
+                let Some(canary) = &service.inner.canary else {
+                    return;
+                };
+                delay = canary.interval();
+                let report = canary.run(&service).await;
+                if !report.ok {
+                    let failed: Vec<&str> = report
+                        .checks
+                        .iter()
+                        .filter(|check| !check.ok)
+                        .map(|check| check.name.as_str())
+                        .collect();
+                    warn!(
+                        "canary: lookup of {} failed: {}",
+                        report.ip,
+                        failed.join(", ")
+                    );
+                }
+            }
+        });
+    }
+
+    async fn refresh_hot(&self, top: usize, before: Duration) {
+        let state = &self.inner;
+        let Some(cache) = &state.cache else {
+            return;
+        };
+        if state.maintenance.check(false).is_err() {
+            return;
+        }
+        let hot = cache.hot(top, before);
+        if hot.is_empty() {
+            return;
+        }
+        debug!("refreshing {} hot cache entries", hot.len());
+        let req = LookupRequest {
+            priority: Priority::Bulk,
+            ..Default::default()
+        };
+        stream::iter(hot)
+            .for_each_concurrent(BATCH_CONCURRENCY, |ip| {
+                let req = &req;
+                async move {
+                    if let Err(e) = self.query(ip, req).await {
+                        warn!("cache refresh failed ip={} error={}", ip, e);
+                    }
+                }
+            })
+            .await;
+    }
+
+    /// Re-resolves a sample of the lookups with another provider in the background.
+    fn spawn_comparison(&self, primary: &Geo) {
+        let Some(comparator) = &self.inner.discrepancy else {
+            return;
+        };
+        let Some(secondary) = self.inner.providers.alternative(&primary.provider) else {
+            return;
+        };
+        if !comparator.sample() {
+            return;
+        }
+        let state = self.inner.clone();
+        let primary = primary.clone();
+        let secondary = secondary.to_string();
+        tokio::spawn(async move {
+            match state.providers.lookup_with(primary.ip, &secondary).await {
+                Ok(lookup) => {
+                    if let Some(comparator) = &state.discrepancy {
+                        comparator.record(&primary, &lookup.geo);
+                    }
+                }
+                Err(e) => warn!("comparison with {} failed: {}", secondary, e),
+            }
+        });
+    }
+
+    /// Mirrors a sample of the lookups to the shadow providers in the background.
+    fn spawn_shadows(&self, primary: &Geo) {
+        for shadow in self.inner.shadows.sample() {
+            let state = self.inner.clone();
+            let primary = primary.clone();
+            tokio::spawn(async move {
+                let start = Instant::now();
+                let result = state.providers.lookup_with(primary.ip, &shadow).await;
+                let latency = start.elapsed();
+                if let Err(e) = &result {
+                    debug!("shadow lookup with {} failed: {}", shadow, e);
+                }
+                let answer = result.as_ref().ok().map(|lookup| &lookup.geo);
+                state.shadows.record(&primary, &shadow, answer, latency);
+            });
+        }
+    }
+
+    /// Looks every address of the batch up, in submission order.
+    pub async fn batch(&self, req: &BatchRequest) -> Result<Vec<BatchItem>, Error> {
+        if req.ips.len() > MAX_BATCH {
+            return Err(Error::InvalidInput(format!(
+                "{} addresses in the batch, at most {MAX_BATCH}, submit a job instead",
+                req.ips.len()
+            )));
+        }
+        Ok(self.batch_items(req, false).collect().await)
+    }
+
+    /// Items in submission order, chunk after chunk with the configured pause.
+    ///
+    /// Every distinct address is looked up once, its item is repeated at
+    /// each position it was submitted at, as soon as the ones before are out.
+    /// With `retry` a transient failure is retried before it is repeated.
+    fn batch_items<'a>(
+        &'a self,
+        req: &'a BatchRequest,
+        retry: bool,
+    ) -> impl Stream<Item = BatchItem> + 'a {
+        let config = self.inner.batch;
+        let delay = Duration::from_millis(config.chunk_delay_ms);
+        let mut distinct: Vec<&str> = Vec::new();
+        let mut seen = HashMap::new();
+        // index in `distinct` of every submitted address
+        let slots: Vec<usize> = req
+            .ips
+            .iter()
+            .map(|ip| {
+                *seen.entry(dedup_key(ip)).or_insert_with(|| {
+                    distinct.push(ip);
+                    distinct.len() - 1
+                })
+            })
+            .collect();
+        let mut done: Vec<BatchItem> = Vec::with_capacity(distinct.len());
+        let mut next = 0;
+        let chunks: Vec<Vec<&str>> = distinct
+            .chunks(config.chunk_size.max(1))
+            .map(<[&str]>::to_vec)
+            .collect();
+        stream::iter(chunks.into_iter().enumerate())
+            .then(move |(index, chunk)| async move {
+                if index > 0 && !delay.is_zero() {
+                    tokio::time::sleep(delay).await;
+                }
+                stream::iter(chunk)
+                    .map(move |ip| async move {
+                        let item = self.batch_item(req, ip).await;
+                        match retry {
+                            true => self.retry(req, item).await,
+                            false => item,
+                        }
+                    })
+                    .buffered(config.concurrency.max(1))
+            })
+            .flatten()
+            .flat_map(move |item| {
+                done.push(item);
+                let mut ready = Vec::new();
+                while slots.get(next).is_some_and(|slot| *slot < done.len()) {
+                    ready.push(BatchItem {
+                        ip: req.ips[next].clone(),
+                        ..done[slots[next]].clone()
+                    });
+                    next += 1;
+                }
+                stream::iter(ready)
+            })
+    }
+
+    /// Looks `ip` up with the options of `req`, a failure becomes the error of the item.
+    pub(crate) async fn batch_item(&self, req: &BatchRequest, ip: &str) -> BatchItem {
+        BatchItem::new(ip.to_string(), self.lookup(&req.request(ip)).await)
+    }
+
+    /// Locates both addresses, only the location, country and ASN are looked up.
+    pub async fn distance(&self, from: &str, to: &str) -> Result<Distance, Error> {
+        let request = |ip: &str| LookupRequest {
+            fields: vec![Field::Country, Field::Location, Field::Asn],
+            ..LookupRequest::ip(ip)
+        };
+        let (from, to) = (request(from), request(to));
+        let (from, to) = futures::future::try_join(self.lookup(&from), self.lookup(&to)).await?;
+        Ok(Distance::new(from, to))
+    }
+
+    /// Countries and ASNs of a prefix, from up to `samples` of its sub-blocks.
+    pub async fn cidr(&self, prefix: &str, samples: usize) -> Result<CidrSummary, Error> {
+        let net = cidr::parse(prefix)?;
+        let blocks = cidr::blocks(net, samples);
+        let req = BatchRequest::default();
+        let items = stream::iter(blocks)
+            .map(|(block, ip)| {
+                let req = &req;
+                async move { (block, self.batch_item(req, &ip.to_string()).await) }
+            })
+            .buffered(BATCH_CONCURRENCY)
+            .collect()
+            .await;
+        Ok(cidr::summarize(net, items))
+    }
+
+    /// Queues the batch, it runs in the background and is polled with [`Self::job`].
+    pub async fn submit(&self, req: BatchRequest) -> Result<Job, Error> {
+        self.inner.maintenance.check(false)?;
+        if req.ips.len() > MAX_JOB {
+            return Err(Error::InvalidInput(format!(
+                "{} addresses in the job, at most {MAX_JOB}",
+                req.ips.len()
+            )));
+        }
+        let job = self.inner.jobs.create(req.ips.len());
+        self.inner.jobs.save(&job.id, &req).await;
+        self.spawn_job(job.id.clone(), req);
+        Ok(job)
+    }
+
+    /// Runs the job in the background, after the addresses it already processed.
+    fn spawn_job(&self, id: String, req: BatchRequest) {
+        let service = self.clone();
+        tokio::spawn(async move {
+            let jobs = &service.inner.jobs;
+            let done = jobs.start(&id).min(req.ips.len());
+            let rest = BatchRequest {
+                ips: req.ips[done..].to_vec(),
+                ..req.clone()
+            };
+            let mut items = std::pin::pin!(service.batch_items(&rest, true));
+            while let Some(item) = items.next().await {
+                if jobs.record(&id, item) {
+                    jobs.save(&id, &req).await;
+                }
+            }
+            jobs.finish(&id);
+            jobs.save(&id, &req).await;
+            info!("job {} done, {} addresses", id, req.ips.len());
+        });
+    }
+
+    /// Looks the address of a transiently failed job item up again, until it
+    /// succeeds or fails for good or the retries are used up. While every
+    /// provider is rate limited the retry waits for the first window to reset.
+    async fn retry(&self, req: &BatchRequest, mut item: BatchItem) -> BatchItem {
+        let mut attempt = 1;
+        while item.error.as_ref().is_some_and(ErrorBody::is_transient) {
+            let Some(delay) = self.inner.jobs.retry_delay(attempt) else {
+                break;
+            };
+            let reset = self.inner.providers.retry_after().unwrap_or_default();
+            tokio::time::sleep(delay.max(reset)).await;
+            item = self.batch_item(req, &item.ip).await;
+            attempt += 1;
+        }
+        item
+    }
+
+    /// Progress of a submitted job, with the results so far.
+    pub fn job(&self, id: &str) -> Result<Job, Error> {
+        self.inner
+            .jobs
+            .get(id)
+            .ok_or_else(|| Error::NotFound(format!("job {id}")))
+    }
+
+    /// Addresses of a job that failed for good, with the reason.
+    pub fn job_failures(&self, id: &str) -> Result<Vec<DeadLetter>, Error> {
+        self.inner
+            .jobs
+            .failed(id)
+            .ok_or_else(|| Error::NotFound(format!("job {id}")))
+    }
+
+    /// GreyNoise classification of `ip`.
+    pub async fn noise(&self, ip: &str) -> Result<Noise, Error> {
+        let greynoise = self
+            .inner
+            .greynoise
+            .as_ref()
+            .ok_or(Error::NotConfigured("GreyNoise"))?;
+        let ip = self.target(ip)?;
+        greynoise
+            .check(ip)
+            .await
+            .ok_or_else(|| Error::ProviderUnavailable("GreyNoise request failed".into()))
+    }
+
+    /// DNS blocklist listings of `ip`.
+    pub async fn dnsbl(&self, ip: &str) -> Result<DnsblReport, Error> {
+        let dnsbl = self
+            .inner
+            .dnsbl
+            .as_ref()
+            .ok_or(Error::NotConfigured("DNSBL"))?;
+        Ok(dnsbl.check(self.target(ip)?).await)
+    }
+
+    /// Name, registry and announced prefixes of an AS, `AS15169` or `15169`.
+    pub fn asn(&self, number: &str) -> Result<AsnDetail, Error> {
+        let asn = self
+            .inner
+            .asn
+            .as_ref()
+            .ok_or(Error::NotConfigured("ASN dataset"))?;
+        let number = parse_asn(number)
+            .filter(|_| !number.trim().contains(' '))
+            .ok_or_else(|| Error::InvalidInput(format!("invalid AS number {number}")))?;
+        asn.detail(number)
+    }
+
+    /// Prefixes of the ASes registered in `country`, an ISO 3166-1 alpha-2 code.
+    pub fn blocklist(&self, country: &str) -> Result<Vec<IpNet>, Error> {
+        let asn = self
+            .inner
+            .asn
+            .as_ref()
+            .ok_or(Error::NotConfigured("ASN dataset"))?;
+        let country = country::info(country)
+            .ok_or_else(|| Error::InvalidInput(format!("unknown country {country}")))?;
+        asn.country_prefixes(country.code)
+    }
+
+    /// Most specific allocation or assignment covering `ip`, from its registry.
+    pub async fn netblock(&self, ip: &str) -> Result<Netblock, Error> {
+        let rdap = self
+            .inner
+            .rdap
+            .as_ref()
+            .ok_or(Error::NotConfigured("netblock lookup"))?;
+        rdap.netblock(self.target(ip)?).await
+    }
+
+    /// Round trip times to `ip`, `port` is the one of the TCP fallback.
+    pub async fn ping(
+        &self,
+        ip: &str,
+        count: Option<u32>,
+        port: Option<u16>,
+    ) -> Result<Ping, Error> {
+        let pinger = self
+            .inner
+            .pinger
+            .as_ref()
+            .ok_or(Error::NotConfigured("ping"))?;
+        let target = self.target(ip)?;
+        let config = pinger.config();
+        let count = count.unwrap_or(config.count);
+        if count == 0 || count > config.max_count {
+            return Err(Error::InvalidInput(format!(
+                "count must be between 1 and {}",
+                config.max_count
+            )));
+        }
+        let port = match port.unwrap_or(config.tcp_port) {
+            0 => return Err(Error::InvalidInput("port must not be 0".into())),
+            port => port,
+        };
+        pinger.ping(target, count, port).await
+    }
+
+    /// Whether `port` of `ip` accepts TCP connections.
+    pub async fn probe(&self, ip: &str, port: &str) -> Result<Probe, Error> {
+        let prober = self
+            .inner
+            .prober
+            .as_ref()
+            .ok_or(Error::NotConfigured("TCP probe"))?;
+        let port = port
+            .parse()
+            .map_err(|_| Error::InvalidInput(format!("invalid port {port}")))?;
+        prober.probe(self.target(ip)?, port).await
+    }
+
+    /// Certificate chain presented at `target`, an address with an optional port.
+    pub async fn tls(&self, target: &str, sni: Option<&str>) -> Result<TlsInspection, Error> {
+        let inspector = self
+            .inner
+            .inspector
+            .as_ref()
+            .ok_or(Error::NotConfigured("TLS inspection"))?;
+        let target = tls::parse_target(target, inspector.default_port())?;
+        self.inner.policy.check(target.ip())?;
+        inspector.inspect(target, sni).await
+    }
+
+    /// Path to `ip` with every public hop located, up to the configured hop
+    /// limit when `max_hops` is absent.
+    pub async fn traceroute(&self, ip: &str, max_hops: Option<u8>) -> Result<Traceroute, Error> {
+        let tracer = self
+            .inner
+            .tracer
+            .as_ref()
+            .ok_or(Error::NotConfigured("traceroute"))?;
+        let target = self.target(ip)?;
+        let max_hops = max_hops.unwrap_or(tracer.max_hops());
+        if max_hops == 0 || max_hops > tracer.max_hops() {
+            return Err(Error::InvalidInput(format!(
+                "max_hops must be between 1 and {}",
+                tracer.max_hops()
+            )));
+        }
+        let mut trace = tracer.trace(target, max_hops).await?;
+        let geos = stream::iter(trace.hops.iter().map(|hop| hop.address).collect::<Vec<_>>())
+            .map(|address| async move {
+                let address = address.filter(|ip| traceroute::routable(*ip))?;
+                let req = LookupRequest {
+                    fields: vec![Field::Country, Field::Location, Field::Asn],
+                    ..LookupRequest::ip(address.to_string())
+                };
+                match self.lookup(&req).await {
+                    Ok(lookup) => Some(lookup.geo),
+                    Err(e) => {
+                        debug!("traceroute hop {} not located: {}", address, e);
+                        None
+                    }
+                }
+            })
+            .buffered(BATCH_CONCURRENCY)
+            .collect::<Vec<_>>()
+            .await;
+        for (hop, geo) in trace.hops.iter_mut().zip(geos) {
+            hop.geo = geo;
+        }
+        Ok(trace)
+    }
+
+    /// Agreement between the providers, from the sampled comparisons.
+    pub fn discrepancies(&self) -> Result<DiscrepancyStats, Error> {
+        let comparator = self
+            .inner
+            .discrepancy
+            .as_ref()
+            .ok_or(Error::NotConfigured("discrepancy detection"))?;
+        Ok(comparator.stats())
+    }
+
+    /// Latest canary run, absent before the first one or without a canary.
+    pub fn canary(&self) -> Option<CanaryReport> {
+        self.inner.canary.as_ref()?.latest()
+    }
+
+    pub fn shadows(&self) -> Result<Vec<ShadowStats>, Error> {
+        if self.inner.shadows.is_empty() {
+            return Err(Error::NotConfigured("shadow providers"));
+        }
+        Ok(self.inner.shadows.stats())
+    }
+
+    pub fn providers(&self) -> Vec<ProviderStatus> {
+        self.inner.providers.status()
+    }
+
+    /// Dataset and provider states, see [`readiness`](crate::readiness).
+    pub fn readiness(&self) -> Readiness {
+        let state = &self.inner;
+        let now = SystemTime::now();
+        let mut dependencies = Vec::new();
+        if let Some(asn) = &state.asn {
+            dependencies.push(readiness::dataset(asn.as_ref(), now));
+        }
+        if let Some(ixp) = &state.ixp {
+            dependencies.push(readiness::dataset(ixp.as_ref(), now));
+        }
+        if let Some(rdap) = &state.rdap {
+            dependencies.push(readiness::dataset(rdap.as_ref(), now));
+        }
+        if let Some(threat_lists) = &state.threat_lists {
+            dependencies.push(readiness::dataset(threat_lists.as_ref(), now));
+        }
+        dependencies.extend(state.providers.status().iter().map(readiness::provider));
+        Readiness::new(dependencies)
+    }
+
+    pub fn usage(&self) -> Vec<ProviderUsage> {
+        self.inner.providers.usage()
+    }
+
+    /// Estimated spend on the paid providers since the start.
+    pub fn spend(&self) -> SpendStats {
+        self.inner.providers.spend()
+    }
+
+    /// Sums up the period ending now into a report and writes it.
+    pub async fn report(&self) -> Result<Summary, Error> {
+        let reports = self.reports()?;
+        let totals = Totals::new(&self.inner.metrics, &self.spend());
+        let summary = reports.summarize(Utc::now(), totals, self.providers());
+        reports.save(&summary).await?;
+        Ok(summary)
+    }
+
+    /// The most recent report written.
+    pub fn latest_report(&self) -> Result<Summary, Error> {
+        self.reports()?
+            .latest()
+            .ok_or_else(|| Error::NotFound("report".into()))
+    }
+
+    fn reports(&self) -> Result<&Reports, Error> {
+        self.inner
+            .reports
+            .as_ref()
+            .ok_or(Error::NotConfigured("reports"))
+    }
+
+    /// Latency histograms of `/metrics/prometheus`, the HTTP layer observes the requests.
+    pub fn latency_metrics(&self) -> &Arc<LatencyMetrics> {
+        &self.inner.metrics
+    }
+
+    pub fn flags(&self) -> &Flags {
+        &self.inner.flags
+    }
+
+    pub fn maintenance(&self) -> &Maintenance {
+        &self.inner.maintenance
+    }
+
+    pub fn target_policy(&self) -> &TargetPolicy {
+        &self.inner.policy
+    }
+
+    /// Address of a request, refused when the target policy denies it.
+    fn target(&self, ip: &str) -> Result<IpAddr, Error> {
+        let ip = parse_ip(ip)?;
+        self.inner.policy.check(ip)?;
+        Ok(ip)
+    }
+}
+
+fn parse_ip(ip: &str) -> Result<IpAddr, Error> {
+    ip.parse()
+        .map_err(|_| Error::InvalidInput(format!("invalid IP address {ip}")))
+}
+
+/// Builder of a [`LookupService`], nothing is enabled by default.
+///
+/// Without any provider the free ip-api endpoint is used.
+#[derive(Default)]
+pub struct LookupServiceBuilder {
+    http: Option<reqwest::Client>,
+    providers: Vec<ProviderConfig>,
+    failover: FailoverPolicy,
+    balancing: Vec<BalancingProfile>,
+    routing: Routing,
+    merge: Option<MergeConfig>,
+    cache: Option<CacheConfig>,
+    abuseipdb: Option<AbuseIpDbConfig>,
+    greynoise: Option<GreyNoiseConfig>,
+    shodan: Option<ShodanConfig>,
+    passive_dns: Option<PassiveDnsConfig>,
+    dnsbl: Option<DnsblConfig>,
+    discrepancy: Option<DiscrepancyConfig>,
+    recording: Option<RecordingConfig>,
+    chaos: Option<ChaosConfig>,
+    risk: RiskConfig,
+    dns: DnsConfig,
+    anycast: AnycastConfig,
+    ixp: Option<IxpConfig>,
+    asn: Option<AsnConfig>,
+    netblock: Option<NetblockConfig>,
+    threat_lists: Option<ThreatListsConfig>,
+    traceroute: Option<TracerouteConfig>,
+    ping: Option<PingConfig>,
+    probe: Option<ProbeConfig>,
+    tls: Option<TlsConfig>,
+    flags: HashMap<String, Rollout>,
+    target_policy: TargetPolicyConfig,
+    slow_provider_call: Option<Duration>,
+    max_timeout: Option<Duration>,
+    batch: BatchConfig,
+    jobs: JobsConfig,
+    resume_jobs: bool,
+    reports: Option<ReportsConfig>,
+    paging: Option<PagingConfig>,
+    canary: Option<CanaryConfig>,
+    upstream: UpstreamConfig,
+    proxy: Option<ProxyConfig>,
+    outbound: OutboundConfig,
+    metrics: MetricsConfig,
+    statsd: Option<StatsdConfig>,
+    otlp: Option<OtlpConfig>,
+    flag_image_url: Option<String>,
+}
+
+impl LookupServiceBuilder {
+    /// Builder with every section of the configuration applied.
+    pub fn from_config(config: Config) -> Self {
+        LookupServiceBuilder {
+            http: None,
+            providers: config.providers,
+            failover: config.failover,
+            balancing: config.balancing,
+            routing: config.routing,
+            merge: config.merge,
+            cache: config.cache,
+            abuseipdb: config.abuseipdb,
+            greynoise: config.greynoise,
+            shodan: config.shodan,
+            passive_dns: config.passive_dns,
+            dnsbl: config.dnsbl,
+            discrepancy: config.discrepancy,
+            recording: config.recording,
+            chaos: config.chaos,
+            risk: config.risk,
+            dns: config.dns,
+            anycast: config.anycast,
+            ixp: config.ixp,
+            asn: config.asn,
+            netblock: config.netblock,
+            threat_lists: config.threat_lists,
+            traceroute: config.traceroute,
+            ping: config.ping,
+            probe: config.probe,
+            tls: config.tls,
+            flags: config.flags,
+            target_policy: config.target_policy,
+            slow_provider_call: match config.slow_requests.provider_call_ms {
+                0 => None,
+                ms => Some(Duration::from_millis(ms)),
+            },
+            max_timeout: Some(Duration::from_millis(config.timeouts.max_lookup_ms)),
+            batch: config.batch,
+            jobs: config.jobs,
+            resume_jobs: false,
+            reports: config.reports,
+            paging: config.paging,
+            canary: config.canary,
+            upstream: config.upstream,
+            proxy: config.proxy,
+            outbound: config.outbound,
+            metrics: config.metrics,
+            statsd: config.statsd,
+            otlp: config.otlp,
+            flag_image_url: config.flag_image_url,
+        }
+    }
+
+    /// HTTP client shared by the providers and feeds, the `proxy` is then not applied.
+    pub fn http(mut self, http: reqwest::Client) -> Self {
+        self.http = Some(http);
+        self
+    }
+
+    /// Proxy of the HTTP requests, instead of the one of the environment.
+    pub fn proxy(mut self, config: ProxyConfig) -> Self {
+        self.proxy = Some(config);
+        self
+    }
+
+    /// Address family and local address of the connections of the HTTP client.
+    pub fn outbound(mut self, config: OutboundConfig) -> Self {
+        self.outbound = config;
+        self
+    }
+
+    /// Buckets and label limit of the latency histograms.
+    pub fn metrics(mut self, config: MetricsConfig) -> Self {
+        self.metrics = config;
+        self
+    }
+
+    /// Sends the latencies to a StatsD agent.
+    pub fn statsd(mut self, config: StatsdConfig) -> Self {
+        self.statsd = Some(config);
+        self
+    }
+
+    /// Pushes the latencies to an OTLP collector.
+    pub fn otlp(mut self, config: OtlpConfig) -> Self {
+        self.otlp = Some(config);
+        self
+    }
+
+    /// Adds a provider, see [`ProviderConfig`] for the ordering.
+    pub fn provider(mut self, provider: ProviderConfig) -> Self {
+        self.providers.push(provider);
+        self
+    }
+
+    pub fn failover(mut self, failover: FailoverPolicy) -> Self {
+        self.failover = failover;
+        self
+    }
+
+    /// Order the providers of a lookup are tried in.
+    pub fn routing(mut self, routing: Routing) -> Self {
+        self.routing = routing;
+        self
+    }
+
+    /// Merges the answers of several providers field by field.
+    pub fn merge(mut self, merge: MergeConfig) -> Self {
+        self.merge = Some(merge);
+        self
+    }
+
+    /// Spreads the lookups of a field profile across its providers by weight.
+    pub fn balancing(mut self, profile: BalancingProfile) -> Self {
+        self.balancing.push(profile);
+        self
+    }
+
+    /// Caches the provider answers in memory.
+    pub fn cache(mut self, cache: CacheConfig) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    pub fn abuseipdb(mut self, config: AbuseIpDbConfig) -> Self {
+        self.abuseipdb = Some(config);
+        self
+    }
+
+    pub fn greynoise(mut self, config: GreyNoiseConfig) -> Self {
+        self.greynoise = Some(config);
+        self
+    }
+
+    pub fn shodan(mut self, config: ShodanConfig) -> Self {
+        self.shodan = Some(config);
+        self
+    }
+
+    /// Passive DNS source of the `passive_dns` add-on.
+    pub fn passive_dns(mut self, config: PassiveDnsConfig) -> Self {
+        self.passive_dns = Some(config);
+        self
+    }
+
+    pub fn dnsbl(mut self, config: DnsblConfig) -> Self {
+        self.dnsbl = Some(config);
+        self
+    }
+
+    pub fn discrepancy(mut self, config: DiscrepancyConfig) -> Self {
+        self.discrepancy = Some(config);
+        self
+    }
+
+    pub fn recording(mut self, config: RecordingConfig) -> Self {
+        self.recording = Some(config);
+        self
+    }
+
+    pub fn chaos(mut self, config: ChaosConfig) -> Self {
+        self.chaos = Some(config);
+        self
+    }
+
+    pub fn risk(mut self, config: RiskConfig) -> Self {
+        self.risk = config;
+        self
+    }
+
+    /// Resolver of hostname lookups, the system one by default.
+    pub fn dns(mut self, config: DnsConfig) -> Self {
+        self.dns = config;
+        self
+    }
+
+    /// Anycast prefixes on top of the built-in ones and latency probes.
+    pub fn anycast(mut self, config: AnycastConfig) -> Self {
+        self.anycast = config;
+        self
+    }
+
+    /// Locates peering LAN addresses at their exchange, the table is refreshed
+    /// in the background when built inside a Tokio runtime.
+    pub fn ixp(mut self, config: IxpConfig) -> Self {
+        self.ixp = Some(config);
+        self
+    }
+
+    /// Serves AS details, the datasets are refreshed in the background when
+    /// built inside a Tokio runtime.
+    pub fn asn(mut self, config: AsnConfig) -> Self {
+        self.asn = Some(config);
+        self
+    }
+
+    /// Serves netblock registrations over RDAP, the bootstrap is refreshed in
+    /// the background when built inside a Tokio runtime.
+    pub fn netblock(mut self, config: NetblockConfig) -> Self {
+        self.netblock = Some(config);
+        self
+    }
+
+    /// Reports the threat lists of every lookup, they are refreshed in the
+    /// background when built inside a Tokio runtime.
+    pub fn threat_lists(mut self, config: ThreatListsConfig) -> Self {
+        self.threat_lists = Some(config);
+        self
+    }
+
+    /// Serves traceroutes, over raw sockets with `CAP_NET_RAW` or else the
+    /// configured command.
+    pub fn traceroute(mut self, config: TracerouteConfig) -> Self {
+        self.traceroute = Some(config);
+        self
+    }
+
+    /// Serves round trip times, over ICMP where a socket opens or else TCP connects.
+    pub fn ping(mut self, config: PingConfig) -> Self {
+        self.ping = Some(config);
+        self
+    }
+
+    /// Serves TCP connect checks of the allowed ports.
+    pub fn probe(mut self, config: ProbeConfig) -> Self {
+        self.probe = Some(config);
+        self
+    }
+
+    /// Serves the certificate chains of TLS servers.
+    pub fn tls(mut self, config: TlsConfig) -> Self {
+        self.tls = Some(config);
+        self
+    }
+
+    /// Country flag image URL, `{code}` is replaced by the lowercase country code.
+    pub fn flag_image_url(mut self, url: impl Into<String>) -> Self {
+        self.flag_image_url = Some(url.into());
+        self
+    }
+
+    /// Refuses the lookups of the targets the rules deny.
+    pub fn target_policy(mut self, config: TargetPolicyConfig) -> Self {
+        self.target_policy = config;
+        self
+    }
+
+    /// Chunking and concurrency of the batches and jobs.
+    pub fn batch(mut self, config: BatchConfig) -> Self {
+        self.batch = config;
+        self
+    }
+
+    /// Keeps the jobs in a store, to resume them after a restart.
+    pub fn jobs(mut self, config: JobsConfig) -> Self {
+        self.jobs = config;
+        self
+    }
+
+    /// Resumes the jobs of the store a restart interrupted, only for the
+    /// server: another instance on the same store has its running jobs
+    /// there too.
+    pub fn resume_jobs(mut self, resume: bool) -> Self {
+        self.resume_jobs = resume;
+        self
+    }
+
+    /// Writes a summary report at the end of every period.
+    pub fn reports(mut self, config: ReportsConfig) -> Self {
+        self.reports = Some(config);
+        self
+    }
+
+    /// Pages through PagerDuty or Opsgenie when the providers fail.
+    pub fn paging(mut self, config: PagingConfig) -> Self {
+        self.paging = Some(config);
+        self
+    }
+
+    /// Looks up a known address through every provider and the API every interval.
+    pub fn canary(mut self, config: CanaryConfig) -> Self {
+        self.canary = Some(config);
+        self
+    }
+
+    /// Limit of the provider requests in flight over every provider.
+    pub fn upstream(mut self, config: UpstreamConfig) -> Self {
+        self.upstream = config;
+        self
+    }
+
+    /// Caps the `timeout_ms` the requests ask for.
+    pub fn max_timeout(mut self, max: Duration) -> Self {
+        self.max_timeout = Some(max);
+        self
+    }
+
+    /// Logs and counts the provider calls taking longer than `threshold`.
+    pub fn slow_provider_call(mut self, threshold: Duration) -> Self {
+        self.slow_provider_call = Some(threshold);
+        self
+    }
+
+    /// Sets the rollout of a feature flag, see `flags.rs` for the known flags.
+    pub fn flag(mut self, name: impl Into<String>, rollout: Rollout) -> Self {
+        self.flags.insert(name.into(), rollout);
+        self
+    }
+
+    pub fn build(self) -> LookupService {
+        let proxy = self.proxy;
+        let hosts = Arc::new(Hosts::new(
+            Resolver::outbound(&self.dns, self.outbound.ip_family),
+            Duration::from_secs(self.dns.stale_secs),
+        ));
+        // a client of the caller resolves the hosts itself
+        let warm = self.http.is_none();
+        let http = self.http.unwrap_or_else(|| {
+            let mut builder = timing::instrument(reqwest::Client::builder(), &hosts)
+                .user_agent(crate::providers::USER_AGENT)
+                .local_address(self.outbound.local_address);
+            match proxy.as_ref().map(ProxyConfig::proxy) {
+                Some(Ok(proxy)) => builder = builder.proxy(proxy),
+                Some(Err(e)) => warn!("{}, connecting directly", e),
+                None => {}
+            }
+            builder.build().expect("http client")
+        });
+        let mut providers = self.providers;
+        if providers.is_empty() {
+            providers.push(ProviderConfig::new(crate::config::ProviderKind::IpApi));
+        }
+
+        let recorder = self.recording.map(Recorder::new);
+        let chaos = self.chaos.and_then(Chaos::new);
+        if chaos.is_some() {
+            warn!("fault injection is enabled");
+        }
+        let mut transport = Transport::new(
+            http.clone(),
+            recorder,
+            chaos,
+            self.slow_provider_call,
+            self.upstream,
+        );
+        let mut metrics = LatencyMetrics::new(&self.metrics);
+        match self.statsd.as_ref().map(Statsd::new) {
+            Some(Ok(statsd)) => metrics = metrics.with_statsd(statsd),
+            Some(Err(e)) => warn!("statsd: {}, metrics not sent", e),
+            None => {}
+        }
+        let metrics = Arc::new(metrics);
+        match self.otlp.as_ref().map(Exporter::new) {
+            Some(Ok(exporter)) => match tokio::runtime::Handle::try_current() {
+                Ok(_) => exporter.spawn(&metrics),
+                Err(_) => warn!("otlp: no runtime, the metrics are never exported"),
+            },
+            Some(Err(e)) => warn!("{}, metrics not exported", e),
+            None => {}
+        }
+        transport.metrics(metrics.clone());
+        for config in &providers {
+            match config.header_map() {
+                Ok(headers) if headers.is_empty() => {}
+                Ok(headers) => transport.headers(config.name(), headers),
+                Err(e) => warn!("{}, headers not sent", e),
+            }
+        }
+        // never sent directly when the SOCKS5 route cannot be set up
+        providers.retain(|config| match &config.socks_proxy {
+            Some(url) => match crate::socks::client(url, &hosts) {
+                Ok(client) => {
+                    transport.route(config.name(), client);
+                    true
+                }
+                Err(e) => {
+                    warn!("provider {} skipped: {}", config.name(), e);
+                    false
+                }
+            },
+            None => true,
+        });
+        let shadow_rates: Vec<(String, f64)> = providers
+            .iter()
+            .filter(|config| config.shadow)
+            .map(|config| (config.name(), config.shadow_rate))
+            .collect();
+        let providers = ProviderRegistry::new(transport, providers)
+            .with_balancing(self.balancing)
+            .with_routing(self.routing);
+        let providers = match self.merge {
+            Some(merge) => providers.with_merge(merge),
+            None => providers,
+        };
+        info!("providers: {}", providers.names().join(", "));
+        let shadows = providers.shadows();
+        if !shadows.is_empty() {
+            info!("shadow providers: {}", shadows.join(", "));
+        }
+        // the ones skipped by the registry are left out
+        let shadow_rates: Vec<(String, f64)> = shadow_rates
+            .into_iter()
+            .filter(|(name, _)| shadows.contains(&name.as_str()))
+            .collect();
+        match tokio::runtime::Handle::try_current() {
+            _ if !warm => {}
+            Ok(runtime) => {
+                let provider_hosts = providers.hosts();
+                runtime.spawn(async move { hosts.warm(provider_hosts).await });
+            }
+            Err(_) => warn!("dns: no runtime, the provider hosts are resolved on first use"),
+        }
+
+        let inner = Inner {
+            providers,
+            cache: self
+                .cache
+                .map(|c| TtlCache::new(Duration::from_secs(c.ttl_secs), c.capacity)),
+            abuseipdb: self
+                .abuseipdb
+                .map(|config| AbuseIpDb::new(http.clone(), config)),
+            greynoise: self
+                .greynoise
+                .map(|config| GreyNoise::new(http.clone(), config)),
+            shodan: self.shodan.map(|config| Shodan::new(http.clone(), config)),
+            passive_dns: self
+                .passive_dns
+                .map(|config| PassiveDnsClient::new(http.clone(), config)),
+            dnsbl: self.dnsbl.map(Dnsbl::new),
+            anycast: Anycast::new(http.clone(), self.anycast),
+            ixp: self.ixp.map(|config| {
+                let ixp = Arc::new(PeeringDb::new(http.clone(), config));
+                match tokio::runtime::Handle::try_current() {
+                    Ok(_) => ixp.spawn_refresh(),
+                    Err(_) => warn!("ixp: no runtime, the table is never refreshed"),
+                }
+                ixp
+            }),
+            asn: self.asn.map(|config| {
+                let asn = Arc::new(AsnDb::new(http.clone(), config));
+                match tokio::runtime::Handle::try_current() {
+                    Ok(_) => asn.spawn_refresh(),
+                    Err(_) => warn!("asn: no runtime, the datasets are never refreshed"),
+                }
+                asn
+            }),
+            rdap: self.netblock.map(|config| {
+                let rdap = Arc::new(Rdap::new(http.clone(), config));
+                match tokio::runtime::Handle::try_current() {
+                    Ok(_) => rdap.spawn_refresh(),
+                    Err(_) => warn!("netblock: no runtime, the bootstrap is never refreshed"),
+                }
+                rdap
+            }),
+            threat_lists: self.threat_lists.map(|config| {
+                let threat_lists = Arc::new(ThreatLists::new(http.clone(), config));
+                match tokio::runtime::Handle::try_current() {
+                    Ok(_) => threat_lists.spawn_refresh(),
+                    Err(_) => warn!("threat_lists: no runtime, the lists are never refreshed"),
+                }
+                threat_lists
+            }),
+            tracer: self.traceroute.map(Tracer::new),
+            pinger: self.ping.map(Pinger::new),
+            prober: self.probe.map(Prober::new),
+            inspector: self.tls.map(Inspector::new),
+            shadows: Shadows::new(
+                shadow_rates,
+                self.discrepancy
+                    .as_ref()
+                    .unwrap_or(&DiscrepancyConfig::default()),
+            ),
+            discrepancy: self.discrepancy.map(Comparator::new),
+            risk: self.risk,
+            failover: self.failover,
+            flags: Flags::new(self.flags),
+            maintenance: Maintenance::default(),
+            policy: TargetPolicy::new(self.target_policy),
+            max_timeout: self.max_timeout,
+            batch: self.batch,
+            jobs: Jobs::new(&self.jobs),
+            reports: self.reports.map(Reports::new),
+            pager: self.paging.and_then(|config| {
+                Pager::new(config)
+                    .map_err(|e| warn!("{}, nothing paged", e))
+                    .ok()
+            }),
+            canary: self.canary.and_then(|config| {
+                Canary::new(config)
+                    .map_err(|e| warn!("{}, no canary lookups", e))
+                    .ok()
+            }),
+            resolver: Resolver::new(&self.dns),
+            metrics,
+            ptr: self.dns.ptr,
+            flag_image_url: self
+                .flag_image_url
+                .unwrap_or_else(|| country::DEFAULT_FLAG_IMAGE_URL.into()),
+        };
+        let service = LookupService {
+            inner: Arc::new(inner),
+        };
+        if let Some(cache) = self.cache.filter(|cache| cache.refresh_top > 0) {
+            match tokio::runtime::Handle::try_current() {
+                Ok(_) => service.spawn_cache_refresh(
+                    cache.refresh_top,
+                    Duration::from_secs(cache.refresh_before_secs),
+                ),
+                Err(_) => warn!("cache: no runtime, hot entries are never refreshed"),
+            }
+        }
+        if service.inner.reports.is_some() {
+            match tokio::runtime::Handle::try_current() {
+                Ok(_) => service.spawn_reports(),
+                Err(_) => warn!("reports: no runtime, the reports are never written"),
+            }
+        }
+        if service.inner.pager.is_some() {
+            match tokio::runtime::Handle::try_current() {
+                Ok(_) => service.spawn_paging(),
+                Err(_) => warn!("paging: no runtime, nothing is paged"),
+            }
+        }
+        if service.inner.canary.is_some() {
+            match tokio::runtime::Handle::try_current() {
+                Ok(_) => service.spawn_canary(),
+                Err(_) => warn!("canary: no runtime, nothing is looked up"),
+            }
+        }
+        let interrupted = match self.resume_jobs {
+            true => service.inner.jobs.interrupted(),
+            false => Vec::new(),
+        };
+        if !interrupted.is_empty() {
+            match tokio::runtime::Handle::try_current() {
+                Ok(_) => {
+                    for (id, req) in interrupted {
+                        info!("job {} resumed", id);
+                        service.spawn_job(id, req);
+                    }
+                }
+                Err(_) => warn!("jobs: no runtime, the interrupted jobs are never resumed"),
+            }
+        }
+        service
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::{PolicyAction, PolicyRule, ProviderKind},
+        maintenance::MaintenanceMode,
+    };
+
+    fn service() -> LookupService {
+        LookupService::builder()
+            .provider(ProviderConfig::new(ProviderKind::Mock))
+            .cache(CacheConfig::default())
+            .build()
+    }
+
+    #[tokio::test]
+    async fn test_lookup() {
+        let lookup = service()
+            .lookup(&LookupRequest::ip("8.8.8.8"))
+            .await
+            .unwrap();
+        assert_eq!(lookup.ip, "8.8.8.8");
+        assert_eq!(lookup.geo.provider, "mock");
+        assert_eq!(lookup.geo.country_code.as_deref(), Some("US"));
+        assert!(!lookup.degraded);
+    }
+
+    #[tokio::test]
+    async fn test_cache() {
+        let service = service();
+        let req = LookupRequest::ip("1.1.1.1");
+        service.lookup(&req).await.unwrap();
+        service.lookup(&req).await.unwrap();
+        let requests: u64 = service.usage()[0].keys.iter().map(|k| k.requests).sum();
+        assert_eq!(requests, 1, "Second lookup should be served from the cache");
+        let fresh = LookupRequest {
+            max_age_secs: Some(0),
+            ..req.clone()
+        };
+        let (_, cached) = service
+            .resolve("1.1.1.1".parse().unwrap(), &fresh)
+            .await
+            .unwrap();
+        assert!(!cached, "Older than max_age_secs");
+
+        let lookup = service.lookup(&req).await.unwrap();
+        let ttl = service.cache_ttl(&req, &lookup).unwrap();
+        assert!(ttl <= Duration::from_secs(CacheConfig::default().ttl_secs));
+        let explicit = LookupRequest {
+            provider: Some("mock".into()),
+            ..req.clone()
+        };
+        assert_eq!(service.cache_ttl(&explicit, &lookup), None);
+    }
+
+    #[tokio::test]
+    async fn test_refresh_hot() {
+        let service = service();
+        let requests = || -> u64 { service.usage()[0].keys.iter().map(|k| k.requests).sum() };
+        let hot = LookupRequest::ip("1.1.1.1");
+        service.lookup(&hot).await.unwrap();
+        service.lookup(&hot).await.unwrap();
+        service.lookup(&LookupRequest::ip("2.2.2.2")).await.unwrap();
+        assert_eq!(requests(), 2);
+
+        let ttl = Duration::from_secs(CacheConfig::default().ttl_secs);
+        service.refresh_hot(10, ttl).await;
+        assert_eq!(
+            requests(),
+            3,
+            "Only the entry read from the cache is refreshed"
+        );
+        let left = service
+            .inner
+            .cache
+            .as_ref()
+            .unwrap()
+            .remaining(&"1.1.1.1".parse().unwrap());
+        assert!(left.unwrap() > ttl - Duration::from_secs(1));
+
+        service.refresh_hot(10, ttl).await;
+        assert_eq!(requests(), 3, "Not read since the refresh");
+    }
+
+    #[tokio::test]
+    async fn test_target_policy() {
+        let service = LookupService::builder()
+            .provider(ProviderConfig::new(ProviderKind::Mock))
+            .target_policy(TargetPolicyConfig {
+                rules: vec![PolicyRule {
+                    name: "infrastructure".into(),
+                    action: PolicyAction::Deny,
+                    cidrs: vec!["203.0.113.0/24".into()],
+                }],
+                deny_by_default: false,
+            })
+            .build();
+        let error = service
+            .lookup(&LookupRequest::ip("203.0.113.5"))
+            .await
+            .unwrap_err();
+        assert_eq!(error.code(), "target_refused");
+        assert_eq!(error.body().policy.as_deref(), Some("infrastructure"));
+        assert!(service.lookup(&LookupRequest::ip("8.8.8.8")).await.is_ok());
+
+        service
+            .target_policy()
+            .set(TargetPolicyConfig::default())
+            .unwrap();
+        assert!(service
+            .lookup(&LookupRequest::ip("203.0.113.5"))
+            .await
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_batch_chunks() {
+        let service = LookupService::builder()
+            .provider(ProviderConfig::new(ProviderKind::Mock))
+            .batch(BatchConfig {
+                chunk_size: 2,
+                concurrency: 1,
+                chunk_delay_ms: 30,
+            })
+            .build();
+        let ips = [
+            "1.1.1.1",
+            "1.0.0.1",
+            "9.9.9.9",
+            "149.112.112.112",
+            "8.8.4.4",
+        ];
+        let start = Instant::now();
+        let items = service.batch(&BatchRequest::new(ips)).await.unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(60), "Two pauses");
+        let order: Vec<_> = items.iter().map(|item| item.ip.as_str()).collect();
+        assert_eq!(order, ips);
+    }
+
+    #[tokio::test]
+    async fn test_batch_dedup() {
+        let service = LookupService::builder()
+            .provider(ProviderConfig::new(ProviderKind::Mock))
+            .batch(BatchConfig {
+                chunk_size: 2,
+                concurrency: 4,
+                chunk_delay_ms: 0,
+            })
+            .build();
+        let ips = [
+            "1.1.1.1", "8.8.8.8", "1.1.1.1", " 8.8.8.8", "9.9.9.9", "1.1.1.1",
+        ];
+        let items = service.batch(&BatchRequest::new(ips)).await.unwrap();
+        let order: Vec<_> = items.iter().map(|item| item.ip.as_str()).collect();
+        assert_eq!(order, ips);
+        let country = |item: &BatchItem| item.lookup.as_ref().unwrap().geo.country_code.clone();
+        assert_eq!(country(&items[3]), country(&items[1]));
+        assert_eq!(service.usage()[0].keys[0].requests, 3);
+    }
+
+    #[tokio::test]
+    async fn test_job_resume() {
+        use crate::jobs::JobStatus;
+
+        let dir = std::env::temp_dir().join(format!("ip-service-jobs-{}", uuid::Uuid::new_v4()));
+        let config = JobsConfig {
+            store: Some(dir.clone()),
+            checkpoint_every: 1,
+            ..Default::default()
+        };
+        // interrupted after the first address
+        let req = BatchRequest::new(["192.0.2.1", "8.8.8.8", "1.1.1.1"]);
+        let jobs = Jobs::new(&config);
+        let job = jobs.create(req.ips.len());
+        jobs.start(&job.id);
+        let item = BatchItem::new(req.ips[0].clone(), Err(Error::Timeout));
+        jobs.record(&job.id, item);
+        jobs.save(&job.id, &req).await;
+
+        // a one-shot service on the same store leaves it to the server
+        let service = LookupService::builder()
+            .provider(ProviderConfig::new(ProviderKind::Mock))
+            .jobs(config.clone())
+            .build();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(service.job(&job.id).unwrap().status, JobStatus::Queued);
+        assert_eq!(service.usage()[0].keys[0].requests, 0);
+
+        let service = LookupService::builder()
+            .provider(ProviderConfig::new(ProviderKind::Mock))
+            .jobs(config)
+            .resume_jobs(true)
+            .build();
+        let mut resumed = service.job(&job.id).unwrap();
+        for _ in 0..100 {
+            if resumed.status == JobStatus::Done {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            resumed = service.job(&job.id).unwrap();
+        }
+        assert_eq!(resumed.status, JobStatus::Done);
+        let ips: Vec<_> = resumed
+            .results
+            .iter()
+            .map(|item| item.ip.as_str())
+            .collect();
+        assert_eq!(ips, req.ips);
+        assert_eq!(resumed.failed, 1, "Kept from the checkpoint");
+        assert_eq!(service.usage()[0].keys[0].requests, 2);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_job_failures() {
+        use crate::jobs::JobStatus;
+        use axum::{http::StatusCode, routing::get, Json, Router};
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        // unavailable on the first call
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+        let app = Router::new().route(
+            "/json/:ip",
+            get(move || async move {
+                match counter.fetch_add(1, Ordering::Relaxed) {
+                    0 => Err(StatusCode::SERVICE_UNAVAILABLE),
+                    _ => Ok(Json(serde_json::json!({
+                        "status": "success",
+                        "query": "8.8.8.8",
+                        "countryCode": "US",
+                    }))),
+                }
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let service = LookupService::builder()
+            .provider(ProviderConfig {
+                base_url: Some(url),
+                ..ProviderConfig::new(ProviderKind::IpApi)
+            })
+            .jobs(JobsConfig {
+                retry_delay_ms: 0,
+                ..Default::default()
+            })
+            .build();
+        let job = service
+            .submit(BatchRequest::new(["8.8.8.8", "8.8.8"]))
+            .await
+            .unwrap();
+        let mut done = service.job(&job.id).unwrap();
+        for _ in 0..100 {
+            if done.status == JobStatus::Done {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            done = service.job(&job.id).unwrap();
+        }
+        assert_eq!(done.status, JobStatus::Done);
+        assert_eq!(calls.load(Ordering::Relaxed), 2, "One retry expected");
+        let failures = service.job_failures(&job.id).unwrap();
+        assert_eq!(failures.len(), 1, "{failures:?}");
+        assert_eq!(failures[0].ip, "8.8.8");
+        assert_eq!(failures[0].reason, "invalid_input");
+        assert!(service.job_failures("unknown").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_job_retry_dedup() {
+        use crate::jobs::JobStatus;
+        use axum::{http::StatusCode, routing::get, Router};
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+        let app = Router::new().route(
+            "/json/:ip",
+            get(move || async move {
+                counter.fetch_add(1, Ordering::Relaxed);
+                StatusCode::SERVICE_UNAVAILABLE
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let service = LookupService::builder()
+            .provider(ProviderConfig {
+                base_url: Some(url),
+                ..ProviderConfig::new(ProviderKind::IpApi)
+            })
+            .jobs(JobsConfig {
+                retries: 2,
+                retry_delay_ms: 0,
+                ..Default::default()
+            })
+            .build();
+        let job = service
+            .submit(BatchRequest::new(["8.8.8.8", "8.8.8.8", " 8.8.8.8"]))
+            .await
+            .unwrap();
+        let mut done = service.job(&job.id).unwrap();
+        for _ in 0..100 {
+            if done.status == JobStatus::Done {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            done = service.job(&job.id).unwrap();
+        }
+        assert_eq!(done.status, JobStatus::Done);
+        assert_eq!(
+            calls.load(Ordering::Relaxed),
+            3,
+            "Retried once per distinct address"
+        );
+        assert_eq!(service.job_failures(&job.id).unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_proxy() {
+        use axum::{http::HeaderMap, routing::get, Json, Router};
+
+        // requests through a proxy carry the absolute URL, the router sees its path
+        let app = Router::new().route(
+            "/json/:ip",
+            get(|headers: HeaderMap| async move {
+                let auth = headers.get("proxy-authorization").cloned();
+                let country = match auth {
+                    Some(auth) if auth == "Basic dXNlcjpzZWNyZXQ=" => "DE",
+                    _ => "unauthenticated",
+                };
+                Json(serde_json::json!({
+                    "status": "success",
+                    "query": "93.184.216.34",
+                    "countryCode": country,
+                }))
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let service = LookupService::builder()
+            .provider(ProviderConfig::new(ProviderKind::IpApi))
+            .proxy(ProxyConfig {
+                url,
+                username: Some("user".into()),
+                password: Some("secret".into()),
+                no_proxy: Vec::new(),
+            })
+            .build();
+        let lookup = service
+            .lookup(&LookupRequest::ip("93.184.216.34"))
+            .await
+            .unwrap();
+        assert_eq!(lookup.geo.country_code.as_deref(), Some("DE"));
+    }
+
+    #[tokio::test]
+    async fn test_headers() {
+        use axum::{http::HeaderMap, routing::get, Json, Router};
+
+        let app = Router::new().route(
+            "/json/:ip",
+            get(|headers: HeaderMap| async move {
+                let header = |name| {
+                    headers
+                        .get(name)
+                        .and_then(|v| v.to_str().ok())
+                        .unwrap_or("-")
+                };
+                Json(serde_json::json!({
+                    "status": "success",
+                    "query": "93.184.216.34",
+                    "city": header("user-agent"),
+                    "regionName": header("x-token"),
+                }))
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let lookup = |config: ProviderConfig| async move {
+            let service = LookupService::builder().provider(config).build();
+            let req = LookupRequest::ip("93.184.216.34");
+            service.lookup(&req).await.unwrap().geo
+        };
+        let config = ProviderConfig {
+            base_url: Some(url.clone()),
+            ..ProviderConfig::new(ProviderKind::IpApi)
+        };
+        let geo = lookup(config.clone()).await;
+        assert_eq!(geo.city.as_deref(), Some(crate::providers::USER_AGENT));
+        assert_eq!(geo.region.as_deref(), Some("-"));
+
+        let config = ProviderConfig {
+            user_agent: Some("research-bot/1.0".into()),
+            headers: [("x-token".to_string(), "secret".to_string())].into(),
+            ..config
+        };
+        let geo = lookup(config).await;
+        assert_eq!(geo.city.as_deref(), Some("research-bot/1.0"));
+        assert_eq!(geo.region.as_deref(), Some("secret"));
+    }
+
+    #[tokio::test]
+    async fn test_timeout() {
+        let service = LookupService::builder()
+            .provider(ProviderConfig::new(ProviderKind::Mock))
+            .chaos(ChaosConfig {
+                enabled: true,
+                latency_ms: 60_000,
+                latency_probability: 1.0,
+                ..Default::default()
+            })
+            .max_timeout(Duration::from_millis(50))
+            .build();
+        let req = LookupRequest {
+            timeout_ms: Some(60_000),
+            ..LookupRequest::ip("93.184.216.34")
+        };
+        let lookup = tokio::time::timeout(Duration::from_secs(5), service.lookup(&req))
+            .await
+            .expect("capped by max_timeout")
+            .unwrap();
+        assert!(lookup.degraded);
+        assert_eq!(lookup.geo.country_code, None);
+        assert_eq!(
+            lookup.warnings,
+            ["no provider answered within 50ms, the location is unknown"]
+        );
+
+        let req = LookupRequest {
+            timeout_ms: Some(0),
+            ..LookupRequest::ip("93.184.216.34")
+        };
+        let error = service.lookup(&req).await.unwrap_err();
+        assert!(matches!(error, Error::InvalidInput(_)));
+    }
+
+    #[tokio::test]
+    async fn test_maintenance() {
+        let service = service();
+        let cached = LookupRequest::ip("1.1.1.1");
+        service.lookup(&cached).await.unwrap();
+        service.maintenance().set(MaintenanceMode {
+            enabled: true,
+            cache_only: true,
+            ..Default::default()
+        });
+        service.lookup(&cached).await.unwrap();
+        let error = service
+            .lookup(&LookupRequest::ip("8.8.8.8"))
+            .await
+            .unwrap_err();
+        assert!(matches!(error, Error::Maintenance { .. }));
+        assert!(service
+            .submit(BatchRequest::new(["8.8.8.8"]))
+            .await
+            .is_err());
+
+        service.maintenance().set(MaintenanceMode {
+            enabled: true,
+            ..Default::default()
+        });
+        assert!(service.lookup(&cached).await.is_err());
+        service.maintenance().set(MaintenanceMode::default());
+        service.lookup(&LookupRequest::ip("8.8.8.8")).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_cache_only() {
+        // no request may leave the test, an ip-api lookup would fail
+        let online = LookupService::builder()
+            .provider(ProviderConfig {
+                base_url: Some("http://127.0.0.1:9".into()),
+                ..ProviderConfig::new(ProviderKind::IpApi)
+            })
+            .cache(CacheConfig::default())
+            .build();
+        let req = LookupRequest {
+            cache_only: true,
+            ..LookupRequest::ip("8.8.8.8")
+        };
+        let error = online.lookup(&req).await.unwrap_err();
+        assert!(matches!(error, Error::NotFound(_)), "{error}");
+        let explicit = LookupRequest {
+            provider: Some("ipapi".into()),
+            ..req.clone()
+        };
+        let error = online.lookup(&explicit).await.unwrap_err();
+        assert!(matches!(error, Error::InvalidInput(_)));
+
+        // offline providers still answer
+        let lookup = service().lookup(&req).await.unwrap();
+        assert_eq!(lookup.geo.provider, "mock");
+    }
+
+    #[tokio::test]
+    async fn test_connection_type() {
+        let service = service();
+        let mobile = service
+            .lookup(&LookupRequest::ip("198.51.100.2"))
+            .await
+            .unwrap();
+        assert_eq!(mobile.connection_type, ConnectionType::Mobile);
+        let carrier = mobile.geo.carrier.unwrap();
+        assert_eq!(carrier.name.as_deref(), Some("Telekom"));
+        assert_eq!(carrier.mcc.as_deref(), Some("262"));
+        assert_eq!(carrier.mnc.as_deref(), Some("01"));
+
+        let hosting = service.lookup(&LookupRequest::ip("8.8.8.8")).await.unwrap();
+        assert_eq!(hosting.connection_type, ConnectionType::Hosting);
+    }
+
+    #[tokio::test]
+    async fn test_anycast() {
+        let service = service();
+        let lookup = service.lookup(&LookupRequest::ip("1.1.1.1")).await.unwrap();
+        assert!(lookup.is_anycast);
+        assert_eq!(lookup.warnings, [anycast::WARNING]);
+
+        let lookup = service
+            .lookup(&LookupRequest::ip("198.51.100.2"))
+            .await
+            .unwrap();
+        assert!(!lookup.is_anycast);
+        assert!(lookup.warnings.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_ixp() {
+        let path = std::env::temp_dir().join(format!("ip-service-{}.json", uuid::Uuid::new_v4()));
+        let snapshot = serde_json::json!([{
+            "id": 31,
+            "name": "DE-CIX Frankfurt",
+            "city": "Frankfurt",
+            "country": "DE",
+            "prefix": "80.81.192.0/21"
+        }]);
+        std::fs::write(&path, snapshot.to_string()).unwrap();
+        let service = LookupService::builder()
+            .provider(ProviderConfig::new(ProviderKind::Mock))
+            .ixp(IxpConfig {
+                snapshot: Some(path.clone()),
+                ..Default::default()
+            })
+            .build();
+        let lookup = service
+            .lookup(&LookupRequest::ip("80.81.193.17"))
+            .await
+            .unwrap();
+        assert_eq!(lookup.ixp.unwrap().name, "DE-CIX Frankfurt");
+        assert_eq!(lookup.geo.city.as_deref(), Some("Frankfurt"));
+        assert_eq!(lookup.geo.country_code.as_deref(), Some("DE"));
+        assert_eq!(lookup.geo.currency.as_deref(), Some("EUR"));
+
+        let lookup = service.lookup(&LookupRequest::ip("8.8.8.8")).await.unwrap();
+        assert!(lookup.ixp.is_none());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_threat_lists() {
+        let path = std::env::temp_dir().join(format!("ip-service-{}.json", uuid::Uuid::new_v4()));
+        let snapshot = serde_json::json!([{ "name": "feodotracker", "addresses": ["192.0.2.10"] }]);
+        std::fs::write(&path, snapshot.to_string()).unwrap();
+        let service = LookupService::builder()
+            .provider(ProviderConfig::new(ProviderKind::Mock))
+            .threat_lists(ThreatListsConfig {
+                snapshot: Some(path.clone()),
+                ..Default::default()
+            })
+            .build();
+        let lookup = service
+            .lookup(&LookupRequest::ip("192.0.2.10"))
+            .await
+            .unwrap();
+        assert_eq!(lookup.threat_lists.unwrap(), ["feodotracker"]);
+        let risk = lookup.risk.unwrap();
+        assert!(risk.signals.iter().any(|s| s.name == "threat_lists"));
+
+        let lookup = service.lookup(&LookupRequest::ip("8.8.8.8")).await.unwrap();
+        assert_eq!(lookup.threat_lists, Some(Vec::new()));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_report() {
+        let error = service().latest_report().unwrap_err();
+        assert!(matches!(error, Error::NotConfigured(_)));
+
+        let dir = std::env::temp_dir().join(format!("ip-service-reports-{}", uuid::Uuid::new_v4()));
+        let service = LookupService::builder()
+            .provider(ProviderConfig::new(ProviderKind::Mock))
+            .reports(ReportsConfig {
+                dir: dir.clone(),
+                ..Default::default()
+            })
+            .build();
+        assert!(matches!(service.latest_report(), Err(Error::NotFound(_))));
+        service.lookup(&LookupRequest::ip("8.8.8.8")).await.unwrap();
+        service
+            .latency_metrics()
+            .observe_request(Duration::from_millis(20));
+        let summary = service.report().await.unwrap();
+        assert_eq!((summary.requests, summary.provider_calls), (1, 1));
+        assert_eq!(summary.providers[0].name, "mock");
+        assert_eq!(service.latest_report().unwrap(), summary);
+        let next = service.report().await.unwrap();
+        assert_eq!(
+            (next.requests, next.provider_calls),
+            (0, 0),
+            "Since the last one"
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_shadows() {
+        assert!(matches!(service().shadows(), Err(Error::NotConfigured(_))));
+        let service = LookupService::builder()
+            .provider(ProviderConfig::new(ProviderKind::Mock))
+            .provider(ProviderConfig {
+                name: Some("candidate".into()),
+                weight: 10,
+                shadow: true,
+                shadow_rate: 1.0,
+                ..ProviderConfig::new(ProviderKind::Mock)
+            })
+            .build();
+        let lookup = service.lookup(&LookupRequest::ip("8.8.8.8")).await.unwrap();
+        assert_eq!(lookup.geo.provider, "mock", "The shadow never answers");
+        let mut stats = service.shadows().unwrap();
+        for _ in 0..100 {
+            if stats[0].mirrored > 0 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            stats = service.shadows().unwrap();
+        }
+        assert_eq!(stats[0].provider, "candidate");
+        assert_eq!((stats[0].mirrored, stats[0].compared), (1, 1));
+        assert_eq!(stats[0].country_mismatches, 0);
+        let error = service
+            .lookup(&LookupRequest {
+                provider: Some("candidate".into()),
+                ..LookupRequest::ip("8.8.8.8")
+            })
+            .await
+            .unwrap_err();
+        assert!(matches!(error, Error::InvalidInput(_)));
+    }
+
+    #[tokio::test]
+    async fn test_asn() {
+        let error = service().asn("15169").unwrap_err();
+        assert!(matches!(error, Error::NotConfigured(_)));
+
+        let path = std::env::temp_dir().join(format!("ip-service-{}.json", uuid::Uuid::new_v4()));
+        let snapshot = serde_json::json!({
+            "networks": {
+                "15169": { "name": "GOOGLE", "country": "US", "prefixes": ["8.8.8.0/24"] }
+            },
+            "registries": [{ "first": 13312, "last": 15359, "registry": "ARIN" }]
+        });
+        std::fs::write(&path, snapshot.to_string()).unwrap();
+        let service = LookupService::builder()
+            .provider(ProviderConfig::new(ProviderKind::Mock))
+            .asn(AsnConfig {
+                snapshot: Some(path.clone()),
+                ..Default::default()
+            })
+            .build();
+        let detail = service.asn("AS15169").unwrap();
+        assert_eq!(detail.registry.as_deref(), Some("ARIN"));
+        assert_eq!(detail.prefixes, ["8.8.8.0/24"]);
+        assert!(matches!(service.asn("AS1 x"), Err(Error::InvalidInput(_))));
+        assert!(matches!(service.asn("64496"), Err(Error::NotFound(_))));
+        let prefixes = service.blocklist("us").unwrap();
+        assert_eq!(prefixes, ["8.8.8.0/24".parse::<IpNet>().unwrap()]);
+        assert!(matches!(
+            service.blocklist("XX"),
+            Err(Error::InvalidInput(_))
+        ));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_netblock() {
+        let error = service().netblock("8.8.8.8").await.unwrap_err();
+        assert!(matches!(error, Error::NotConfigured(_)));
+
+        let service = LookupService::builder()
+            .provider(ProviderConfig::new(ProviderKind::Mock))
+            .netblock(NetblockConfig::default())
+            .build();
+        let error = service.netblock("8.8.8").await.unwrap_err();
+        assert!(matches!(error, Error::InvalidInput(_)));
+    }
+
+    #[tokio::test]
+    async fn test_passive_dns() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!(
+            "http://{}/pdns/query/{{ip}}",
+            listener.local_addr().unwrap()
+        );
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = [0u8; 1024];
+            let _ = stream.read(&mut buf).await.unwrap();
+            let body = r#"{"rrname": "dns.google", "rrtype": "A", "rdata": "8.8.8.8", "time_first": 1500000000, "time_last": 1700000000}"#;
+            let response = format!(
+                "HTTP/1.1 200 OK\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{body}",
+                body.len()
+            );
+            stream.write_all(response.as_bytes()).await.unwrap();
+        });
+        let service = LookupService::builder()
+            .provider(ProviderConfig::new(ProviderKind::Mock))
+            .passive_dns(PassiveDnsConfig {
+                url,
+                ..Default::default()
+            })
+            .build();
+        let lookup = service.lookup(&LookupRequest::ip("8.8.8.8")).await.unwrap();
+        assert!(lookup.passive_dns.is_none(), "only on request");
+
+        let req = LookupRequest {
+            passive_dns: true,
+            ..LookupRequest::ip("8.8.8.8")
+        };
+        let passive_dns = service.lookup(&req).await.unwrap().passive_dns.unwrap();
+        assert_eq!(passive_dns.total, 1);
+        assert_eq!(passive_dns.domains[0].name, "dns.google");
+        // the second lookup is served from the cache, the server is gone
+        let cached = service.lookup(&req).await.unwrap().passive_dns.unwrap();
+        assert_eq!(cached, passive_dns);
+    }
+
+    #[tokio::test]
+    async fn test_ping() {
+        let error = service().ping("127.0.0.1", None, None).await.unwrap_err();
+        assert!(matches!(error, Error::NotConfigured(_)));
+
+        let service = LookupService::builder()
+            .provider(ProviderConfig::new(ProviderKind::Mock))
+            .ping(PingConfig {
+                interval_ms: 0,
+                ..Default::default()
+            })
+            .build();
+        let error = service.ping("127.0.0.1", Some(11), None).await;
+        assert!(matches!(error, Err(Error::InvalidInput(_))));
+        let error = service.ping("127.0.0.1", None, Some(0)).await;
+        assert!(matches!(error, Err(Error::InvalidInput(_))));
+        // neither a private address nor a port off the list
+        let error = service.ping("127.0.0.1", None, Some(22)).await;
+        assert!(matches!(error, Err(Error::InvalidInput(_))));
+        let error = service.ping("192.0.2.1", None, Some(22)).await;
+        assert!(matches!(error, Err(Error::InvalidInput(_))));
+
+        let service = LookupService::builder()
+            .provider(ProviderConfig::new(ProviderKind::Mock))
+            .ping(PingConfig {
+                interval_ms: 0,
+                ports: vec![9],
+                allow_private: true,
+                ..Default::default()
+            })
+            .build();
+
+        // loopback answers both the echoes and the refused connects
+        let ping = service.ping("127.0.0.1", Some(2), Some(9)).await.unwrap();
+        assert_eq!((ping.sent, ping.received), (2, 2));
+        assert!(ping.max_distance_km.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_probe() {
+        let error = service().probe("8.8.8.8", "443").await.unwrap_err();
+        assert!(matches!(error, Error::NotConfigured(_)));
+
+        let service = LookupService::builder()
+            .provider(ProviderConfig::new(ProviderKind::Mock))
+            .probe(ProbeConfig::default())
+            .build();
+        for port in ["http", "70000"] {
+            let error = service.probe("8.8.8.8", port).await.unwrap_err();
+            assert!(matches!(error, Error::InvalidInput(_)));
+        }
+        let error = service.probe("10.0.0.1", "443").await.unwrap_err();
+        assert!(matches!(error, Error::InvalidInput(_)));
+    }
+
+    #[tokio::test]
+    async fn test_tls() {
+        let error = service().tls("192.0.2.1", None).await.unwrap_err();
+        assert!(matches!(error, Error::NotConfigured(_)));
+
+        let service = LookupService::builder()
+            .provider(ProviderConfig::new(ProviderKind::Mock))
+            .tls(TlsConfig::default())
+            .build();
+        let error = service.tls("example.com:443", None).await.unwrap_err();
+        assert!(matches!(error, Error::InvalidInput(_)));
+        let error = service.tls("[::1]:8443", None).await.unwrap_err();
+        assert!(
+            matches!(error, Error::InvalidInput(_)),
+            "loopback is refused"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_traceroute_limits() {
+        let error = service().traceroute("8.8.8.8", None).await.unwrap_err();
+        assert!(matches!(error, Error::NotConfigured(_)));
+
+        let service = LookupService::builder()
+            .provider(ProviderConfig::new(ProviderKind::Mock))
+            .traceroute(TracerouteConfig::default())
+            .build();
+        for max_hops in [0, 31] {
+            let error = service.traceroute("8.8.8.8", Some(max_hops)).await;
+            assert!(matches!(error, Err(Error::InvalidInput(_))));
+        }
+        let error = service.traceroute("8.8.8", None).await.unwrap_err();
+        assert!(matches!(error, Error::InvalidInput(_)));
+        let error = service.traceroute("10.0.0.1", None).await.unwrap_err();
+        assert!(matches!(error, Error::InvalidInput(_)), "a private address");
+    }
+
+    #[tokio::test]
+    async fn test_invalid_input() {
+        let service = service();
+        let error = service
+            .lookup(&LookupRequest::ip("nope"))
+            .await
+            .unwrap_err();
+        assert!(matches!(error, Error::InvalidInput(_)));
+        let req = LookupRequest {
+            provider: Some("ipinfo".into()),
+            ..LookupRequest::ip("8.8.8.8")
+        };
+        let error = service.lookup(&req).await.unwrap_err();
+        assert_eq!(error.to_string(), "invalid input: unknown provider ipinfo");
+        let req = LookupRequest {
+            host: Some("example.com".into()),
+            ..LookupRequest::ip("8.8.8.8")
+        };
+        let error = service.lookup(&req).await.unwrap_err();
+        assert!(matches!(error, Error::InvalidInput(_)));
+    }
+
+    #[tokio::test]
+    async fn test_distance() {
+        let distance = service().distance("8.8.8.8", "1.1.1.1").await.unwrap();
+        assert_eq!(distance.same_country, Some(false));
+        assert_eq!(distance.same_asn, Some(false));
+        assert!(distance.distance_km.unwrap() > 10_000.0);
+        assert_eq!(distance.from.geo.city, None, "Only the needed fields");
+
+        let distance = service().distance("8.8.8.8", "8.8.8.8").await.unwrap();
+        assert_eq!(distance.distance_km, Some(0.0));
+        assert_eq!(distance.same_asn, Some(true));
+        assert!(service().distance("8.8.8.8", "nope").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_cidr() {
+        let summary = service().cidr("8.8.8.8/30", 64).await.unwrap();
+        assert_eq!(summary.prefix, "8.8.8.8/30");
+        assert!(summary.enumerated);
+        assert_eq!(summary.blocks.len(), 4);
+        assert_eq!(summary.blocks[0].result.ip, "8.8.8.8");
+        let counted: usize = summary.countries.iter().map(|c| c.count).sum();
+        assert_eq!(counted, 4);
+        assert!(summary.asns.iter().any(|a| a.asn == 15169 && a.count == 1));
+
+        let summary = service().cidr("100.64.0.0/10", 16).await.unwrap();
+        assert!(!summary.enumerated);
+        assert_eq!(summary.addresses, 1 << 22);
+        assert_eq!(summary.blocks.len(), 16);
+    }
+
+    #[tokio::test]
+    async fn test_host() {
+        let req = LookupRequest {
+            host: Some("localhost".into()),
+            ..Default::default()
+        };
+        let lookup = service().lookup(&req).await.unwrap();
+        let host = lookup.host.unwrap();
+        assert_eq!(host.resolution.host, "localhost");
+        assert_eq!(host.results.len(), host.resolution.addresses.len());
+        assert_eq!(lookup.ip, host.resolution.addresses[0].to_string());
+    }
+}
@@ -0,0 +1,200 @@
+//! Shadow testing of new providers
+//!
+//! A provider with `shadow = true` is never tried for a lookup. A
+//! `shadow_rate` share of the lookups the other providers answer is resolved
+//! with it again in the background, as a bulk lookup, and its answer compared
+//! with the one returned: failures, latency, country mismatches and locations
+//! further apart than `discrepancy.distance_km`. Cache hits are not mirrored.
+//! Served at `/stats/shadows`, to validate a provider before it joins the
+//! chain.
+
+use crate::{config::DiscrepancyConfig, discrepancy::Discrepancy, geo::Geo, sampling::chance};
+use serde::Serialize;
+use std::{sync::Mutex, time::Duration};
+use utoipa::ToSchema;
+
+#[derive(Serialize, ToSchema, Debug, Clone, PartialEq)]
+pub struct ShadowStats {
+    pub provider: String,
+    pub sample_rate: f64,
+    /// Lookups mirrored to the provider.
+    pub mirrored: u64,
+    pub failed: u64,
+    /// Answers compared with the one returned.
+    pub compared: u64,
+    pub country_mismatches: u64,
+    /// Comparisons with locations further apart than the threshold.
+    pub distant: u64,
+    /// Mean distance of the comparisons with coordinates on both sides.
+    pub mean_distance_km: Option<f64>,
+    /// Mean time of the answered calls.
+    pub mean_latency_ms: Option<f64>,
+    /// Latest disagreements, newest first, the provider is the `secondary`.
+    pub recent: Vec<Discrepancy>,
+    #[serde(skip)]
+    distance_sum: f64,
+    #[serde(skip)]
+    distance_count: u64,
+    #[serde(skip)]
+    latency_sum_ms: f64,
+}
+
+pub struct Shadows {
+    distance_km: f64,
+    recent: usize,
+    providers: Vec<Mutex<ShadowStats>>,
+}
+
+impl Shadows {
+    /// Shadows of the named providers and their sample rates, compared with
+    /// the thresholds of `discrepancy`.
+    pub fn new(providers: Vec<(String, f64)>, discrepancy: &DiscrepancyConfig) -> Self {
+        Shadows {
+            distance_km: discrepancy.distance_km,
+            recent: discrepancy.recent,
+            providers: providers
+                .into_iter()
+                .map(|(provider, sample_rate)| {
+                    Mutex::new(ShadowStats {
+                        provider,
+                        sample_rate,
+                        mirrored: 0,
+                        failed: 0,
+                        compared: 0,
+                        country_mismatches: 0,
+                        distant: 0,
+                        mean_distance_km: None,
+                        mean_latency_ms: None,
+                        recent: Vec::new(),
+                        distance_sum: 0.0,
+                        distance_count: 0,
+                        latency_sum_ms: 0.0,
+                    })
+                })
+                .collect(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.providers.is_empty()
+    }
+
+    /// Shadow providers a lookup is mirrored to, each drawn on its own.
+    pub fn sample(&self) -> Vec<String> {
+        self.providers
+            .iter()
+            .filter_map(|stats| {
+                let stats = stats.lock().unwrap();
+                chance(stats.sample_rate).then(|| stats.provider.clone())
+            })
+            .collect()
+    }
+
+    /// Compares the answer of `provider` after `latency` with the `primary` one.
+    pub fn record(&self, primary: &Geo, provider: &str, answer: Option<&Geo>, latency: Duration) {
+        let Some(stats) = self
+            .providers
+            .iter()
+            .find(|stats| stats.lock().unwrap().provider == provider)
+        else {
+            return;
+        };
+        let mut stats = stats.lock().unwrap();
+        stats.mirrored += 1;
+        let Some(shadow) = answer else {
+            stats.failed += 1;
+            return;
+        };
+        stats.compared += 1;
+        stats.latency_sum_ms += latency.as_secs_f64() * 1_000.0;
+        stats.mean_latency_ms = Some(stats.latency_sum_ms / stats.compared as f64);
+        let country_mismatch = match (&primary.country_code, &shadow.country_code) {
+            (Some(a), Some(b)) => !a.eq_ignore_ascii_case(b),
+            _ => false,
+        };
+        let distance = primary.distance_km(shadow);
+        let distant = distance.is_some_and(|d| d > self.distance_km);
+        stats.country_mismatches += u64::from(country_mismatch);
+        stats.distant += u64::from(distant);
+        if let Some(distance) = distance {
+            stats.distance_sum += distance;
+            stats.distance_count += 1;
+            stats.mean_distance_km = Some(stats.distance_sum / stats.distance_count as f64);
+        }
+        if country_mismatch || distant {
+            if stats.recent.len() >= self.recent {
+                stats.recent.pop();
+            }
+            stats.recent.insert(
+                0,
+                Discrepancy {
+                    ip: primary.ip,
+                    primary: primary.provider.clone(),
+                    secondary: provider.to_string(),
+                    primary_country: primary.country_code.clone(),
+                    secondary_country: shadow.country_code.clone(),
+                    distance_km: distance,
+                },
+            );
+        }
+    }
+
+    pub fn stats(&self) -> Vec<ShadowStats> {
+        self.providers
+            .iter()
+            .map(|stats| stats.lock().unwrap().clone())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn geo(provider: &str, country: &str, lat: f64, lon: f64) -> Geo {
+        let mut geo = Geo::new("1.2.3.4".parse().unwrap(), provider);
+        geo.country_code = Some(country.to_string());
+        geo.latitude = Some(lat);
+        geo.longitude = Some(lon);
+        geo
+    }
+
+    #[test]
+    fn test_record() {
+        let shadows = Shadows::new(
+            vec![("new".into(), 1.0), ("off".into(), 0.0)],
+            &DiscrepancyConfig {
+                distance_km: 100.0,
+                recent: 1,
+                ..Default::default()
+            },
+        );
+        assert_eq!(shadows.sample(), ["new"]);
+        let primary = geo("ipinfo", "DE", 52.52, 13.405);
+        let ms = Duration::from_millis;
+        shadows.record(&primary, "new", Some(&geo("new", "DE", 52.4, 13.3)), ms(10));
+        shadows.record(
+            &primary,
+            "new",
+            Some(&geo("new", "FR", 48.85, 2.35)),
+            ms(30),
+        );
+        shadows.record(
+            &primary,
+            "new",
+            Some(&geo("new", "PL", 52.23, 21.01)),
+            ms(20),
+        );
+        shadows.record(&primary, "new", None, ms(5000));
+        shadows.record(&primary, "unknown", None, ms(5));
+
+        let stats = shadows.stats();
+        let new = &stats[0];
+        assert_eq!((new.mirrored, new.failed, new.compared), (4, 1, 3));
+        assert_eq!((new.country_mismatches, new.distant), (2, 2));
+        assert_eq!(new.mean_latency_ms, Some(20.0), "Failed calls left out");
+        assert_eq!(new.recent.len(), 1);
+        assert_eq!(new.recent[0].secondary_country.as_deref(), Some("PL"));
+        assert_eq!(stats[1].mirrored, 0);
+    }
+}
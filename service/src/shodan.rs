@@ -0,0 +1,137 @@
+//! <https://www.shodan.io> host context enrichment
+//!
+//! Host lookups are slow and consume query credits, so they only run when the
+//! caller asks for them and answers are cached.
+
+use crate::{cache::TtlCache, config::ShodanConfig};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use std::{net::IpAddr, time::Duration};
+use tracing::warn;
+use utoipa::ToSchema;
+
+const ENDPOINT: &str = "https://api.shodan.io/shodan/host";
+
+/// What Shodan has seen running on an address.
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone, PartialEq, Default)]
+pub struct ShodanHost {
+    /// Open ports seen by the crawlers, empty if Shodan has no data.
+    pub ports: Vec<u16>,
+    /// Shodan tags like `cloud`, `vpn`, `self-signed`.
+    pub tags: Vec<String>,
+    pub hostnames: Vec<String>,
+    pub os: Option<String>,
+    /// Time of the last crawl.
+    pub last_seen: Option<String>,
+}
+
+/// <https://developer.shodan.io/api>
+#[derive(Deserialize, Debug)]
+struct HostResponse {
+    #[serde(default)]
+    ports: Vec<u16>,
+    #[serde(default)]
+    tags: Vec<String>,
+    #[serde(default)]
+    hostnames: Vec<String>,
+    os: Option<String>,
+    last_update: Option<String>,
+}
+
+impl From<HostResponse> for ShodanHost {
+    fn from(response: HostResponse) -> Self {
+        let mut ports = response.ports;
+        ports.sort_unstable();
+        ShodanHost {
+            ports,
+            tags: response.tags,
+            hostnames: response.hostnames,
+            os: response.os,
+            last_seen: response.last_update,
+        }
+    }
+}
+
+pub struct Shodan {
+    http: reqwest::Client,
+    config: ShodanConfig,
+    cache: TtlCache<ShodanHost>,
+}
+
+impl Shodan {
+    pub fn new(http: reqwest::Client, config: ShodanConfig) -> Self {
+        let cache = TtlCache::new(
+            Duration::from_secs(config.cache_ttl_secs),
+            config.cache_capacity,
+        );
+        Shodan {
+            http,
+            config,
+            cache,
+        }
+    }
+
+    /// Cached answer for `ip`, without a request.
+    pub fn cached(&self, ip: IpAddr) -> Option<ShodanHost> {
+        self.cache.get(&ip)
+    }
+
+    /// Returns the host context of `ip`, `None` if the API could not be reached.
+    pub async fn host(&self, ip: IpAddr) -> Option<ShodanHost> {
+        if let Some(host) = self.cache.get(&ip) {
+            return Some(host);
+        }
+        match self.request(ip).await {
+            Ok(host) => {
+                self.cache.insert(ip, host.clone());
+                Some(host)
+            }
+            Err(e) => {
+                warn!("shodan host lookup failed ip={} error={}", ip, e);
+                None
+            }
+        }
+    }
+
+    async fn request(&self, ip: IpAddr) -> reqwest::Result<ShodanHost> {
+        let response = self
+            .http
+            .get(format!("{ENDPOINT}/{ip}"))
+            .query(&[("key", self.config.api_key.as_str()), ("minify", "true")])
+            .timeout(Duration::from_millis(self.config.timeout_ms))
+            .send()
+            .await?;
+        // unknown hosts are answered with 404
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(ShodanHost::default());
+        }
+        let response: HostResponse = response.error_for_status()?.json().await?;
+        Ok(response.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEST_INPUT: &str = r#"
+{
+  "ip_str": "8.8.8.8",
+  "ports": [443, 53],
+  "tags": [],
+  "hostnames": ["dns.google"],
+  "os": null,
+  "org": "Google LLC",
+  "last_update": "2024-01-01T00:00:00.000000"
+}
+"#;
+
+    #[test]
+    fn test_parse() {
+        let response: HostResponse = serde_json::from_str(TEST_INPUT).unwrap();
+        let host = ShodanHost::from(response);
+        assert_eq!(host.ports, vec![53, 443]);
+        assert_eq!(host.hostnames, vec!["dns.google".to_string()]);
+        assert!(host.last_seen.is_some());
+    }
+}
@@ -0,0 +1,275 @@
+//! Service level objectives of the API lookups
+//!
+//! Every `[[slos]]` counts the API lookups over its rolling window in
+//! one-hour buckets: good ones succeeded, within `latency_ms` if set, bad ones
+//! failed on the service's side (a 5xx) or took longer. Rejected requests, a
+//! 4xx, are not counted. The compliance and the error budget left are served
+//! at `/v1/slo` and as gauges in `/metrics/prometheus`. Held in memory only,
+//! a restart starts the windows over.
+
+use crate::{config::SloConfig, error::Error, prometheus::escape, service::Lookup};
+use serde::Serialize;
+use std::{
+    collections::VecDeque,
+    fmt::Write,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use utoipa::ToSchema;
+
+#[derive(Debug, Default)]
+struct Hour {
+    hour: u64,
+    good: u64,
+    bad: u64,
+}
+
+#[derive(Debug)]
+struct Slo {
+    config: SloConfig,
+    hours: Mutex<VecDeque<Hour>>,
+}
+
+#[derive(Serialize, ToSchema, Debug, Clone, PartialEq)]
+pub struct SloStatus {
+    pub name: String,
+    pub objective: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub latency_ms: Option<u64>,
+    pub window_days: u64,
+    /// Lookups counted over the window.
+    pub total: u64,
+    pub good: u64,
+    /// Share of good lookups, absent before the first one.
+    pub compliance: Option<f64>,
+    /// Share of the bad lookups the objective allows still left, negative once
+    /// overspent.
+    pub error_budget_remaining: f64,
+    /// Bad lookups of the current hour to the ones the objective allows,
+    /// above 1 the budget runs out before the window ends.
+    pub burn_rate_1h: Option<f64>,
+    /// The compliance meets the objective, also before the first lookup.
+    pub met: bool,
+}
+
+/// Value of a gauge, left out when absent.
+type Gauge = fn(&SloStatus) -> Option<f64>;
+
+#[derive(Debug, Default)]
+pub struct Slos {
+    slos: Vec<Slo>,
+}
+
+impl Slos {
+    pub fn new(configs: &[SloConfig]) -> Self {
+        Slos {
+            slos: configs
+                .iter()
+                .map(|config| Slo {
+                    config: config.clone(),
+                    hours: Mutex::new(VecDeque::new()),
+                })
+                .collect(),
+        }
+    }
+
+    /// Counts an API lookup answered after `latency_ms`.
+    pub fn lookup(&self, result: &Result<Lookup, Error>, latency_ms: u128) {
+        let failed = match result {
+            Ok(_) => false,
+            Err(e) if e.status().is_server_error() => true,
+            Err(_) => return,
+        };
+        self.lookup_at(now(), failed, latency_ms);
+    }
+
+    pub fn status(&self) -> Vec<SloStatus> {
+        self.status_at(now())
+    }
+
+    /// The gauges of every objective in the Prometheus text format.
+    pub fn render(&self) -> String {
+        let status = self.status();
+        let mut out = String::new();
+        if status.is_empty() {
+            return out;
+        }
+        let gauges: [(&str, &str, Gauge); 4] = [
+            (
+                "ip_service_slo_objective",
+                "Share of the lookups meeting the objective, by objective.",
+                |slo| Some(slo.objective),
+            ),
+            (
+                "ip_service_slo_compliance",
+                "Share of good lookups over the window, by objective.",
+                |slo| slo.compliance,
+            ),
+            (
+                "ip_service_slo_error_budget_remaining",
+                "Share of the error budget left over the window, by objective.",
+                |slo| Some(slo.error_budget_remaining),
+            ),
+            (
+                "ip_service_slo_burn_rate_1h",
+                "Error budget burn rate of the current hour, by objective.",
+                |slo| slo.burn_rate_1h,
+            ),
+        ];
+        for (name, help, value) in gauges {
+            let _ = writeln!(out, "# HELP {name} {help}");
+            let _ = writeln!(out, "# TYPE {name} gauge");
+            for slo in &status {
+                if let Some(value) = value(slo) {
+                    let _ = writeln!(out, "{name}{{slo=\"{}\"}} {value}", escape(&slo.name));
+                }
+            }
+        }
+        out
+    }
+
+    fn lookup_at(&self, now: u64, failed: bool, latency_ms: u128) {
+        let hour = now / 3600;
+        for slo in &self.slos {
+            let good = !failed
+                && slo
+                    .config
+                    .latency_ms
+                    .is_none_or(|limit| latency_ms <= limit as u128);
+            let mut hours = slo.hours.lock().unwrap();
+            expire(&mut hours, hour, slo.config.window_days * 24);
+            if hours.back().is_none_or(|h| h.hour < hour) {
+                hours.push_back(Hour {
+                    hour,
+                    ..Default::default()
+                });
+            }
+            // a clock stepping back counts into the latest hour
+            let bucket = hours.back_mut().expect("pushed");
+            match good {
+                true => bucket.good += 1,
+                false => bucket.bad += 1,
+            }
+        }
+    }
+
+    fn status_at(&self, now: u64) -> Vec<SloStatus> {
+        let hour = now / 3600;
+        self.slos
+            .iter()
+            .map(|slo| {
+                let config = &slo.config;
+                let mut hours = slo.hours.lock().unwrap();
+                expire(&mut hours, hour, config.window_days * 24);
+                let good: u64 = hours.iter().map(|h| h.good).sum();
+                let bad: u64 = hours.iter().map(|h| h.bad).sum();
+                let total = good + bad;
+                let budget = 1.0 - config.objective;
+                let compliance = (total > 0).then(|| good as f64 / total as f64);
+                let error_budget_remaining = match total {
+                    0 => 1.0,
+                    total => 1.0 - bad as f64 / (budget * total as f64),
+                };
+                let burn_rate_1h = hours
+                    .back()
+                    .filter(|h| h.hour == hour && h.good + h.bad > 0)
+                    .map(|h| h.bad as f64 / (h.good + h.bad) as f64 / budget);
+                SloStatus {
+                    name: config.name.clone(),
+                    objective: config.objective,
+                    latency_ms: config.latency_ms,
+                    window_days: config.window_days,
+                    total,
+                    good,
+                    compliance,
+                    error_budget_remaining,
+                    burn_rate_1h,
+                    met: compliance.is_none_or(|compliance| compliance >= config.objective),
+                }
+            })
+            .collect()
+    }
+}
+
+fn expire(hours: &mut VecDeque<Hour>, hour: u64, window_hours: u64) {
+    while hours.front().is_some_and(|h| h.hour + window_hours <= hour) {
+        hours.pop_front();
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn slos() -> Slos {
+        Slos::new(&[
+            SloConfig {
+                name: "available".into(),
+                objective: 0.9,
+                latency_ms: None,
+                window_days: 1,
+            },
+            SloConfig {
+                name: "fast".into(),
+                objective: 0.5,
+                latency_ms: Some(300),
+                window_days: 2,
+            },
+        ])
+    }
+
+    #[test]
+    fn test_status() {
+        let slos = slos();
+        assert!(slos.status_at(0).iter().all(|slo| slo.met));
+        for _ in 0..18 {
+            slos.lookup_at(3600, false, 100);
+        }
+        slos.lookup_at(3600, false, 900);
+        slos.lookup_at(7200, true, 10);
+
+        let status = slos.status_at(7300);
+        let available = &status[0];
+        assert_eq!((available.total, available.good), (20, 19));
+        assert_eq!(available.compliance, Some(0.95));
+        assert!((available.error_budget_remaining - 0.5).abs() < 1e-9);
+        assert!((available.burn_rate_1h.unwrap() - 10.0).abs() < 1e-9);
+        assert!(available.met);
+        let fast = &status[1];
+        assert_eq!(fast.good, 18, "Too slow and failed ones");
+        assert!(fast.met);
+
+        let later = slos.status_at(3600 * 25);
+        assert_eq!(later[0].total, 1, "The first hour out of the window");
+        assert_eq!(later[0].compliance, Some(0.0));
+        assert!(!later[0].met);
+        assert!((later[0].error_budget_remaining + 9.0).abs() < 1e-9);
+        assert_eq!(later[0].burn_rate_1h, None);
+        assert_eq!(later[1].total, 20);
+    }
+
+    #[test]
+    fn test_render() {
+        let slos = slos();
+        assert!(Slos::default().render().is_empty());
+        slos.lookup_at(now(), false, 500);
+        let out = slos.render();
+        let lines = [
+            "ip_service_slo_objective{slo=\"available\"} 0.9",
+            "ip_service_slo_compliance{slo=\"available\"} 1",
+            "ip_service_slo_compliance{slo=\"fast\"} 0",
+            "ip_service_slo_error_budget_remaining{slo=\"fast\"} -1",
+            "ip_service_slo_burn_rate_1h{slo=\"fast\"} 2",
+        ];
+        for line in lines {
+            assert!(out.lines().any(|l| l == line), "{line} missing in\n{out}");
+        }
+    }
+}
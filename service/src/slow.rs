@@ -0,0 +1,102 @@
+//! Slow request detection
+//!
+//! Requests taking longer than `slow_requests.request_ms` are logged under the
+//! `slow` target and counted for the metrics, next to the provider calls over
+//! `provider_call_ms` the registry reports the same way. A degrading upstream
+//! then shows up in the logs before the deadlines start answering 504.
+
+use crate::recovery::REQUEST_ID;
+use axum::{
+    extract::{Request, State},
+    middleware::Next,
+    response::Response,
+};
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+use tracing::warn;
+
+/// Requests over the threshold since the start, none are when it is 0.
+#[derive(Debug)]
+pub struct SlowRequests {
+    threshold: Duration,
+    count: AtomicU64,
+}
+
+impl SlowRequests {
+    pub fn new(threshold: Duration) -> Self {
+        SlowRequests {
+            threshold,
+            count: AtomicU64::new(0),
+        }
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    fn is_slow(&self, elapsed: Duration) -> bool {
+        !self.threshold.is_zero() && elapsed > self.threshold
+    }
+}
+
+/// Middleware timing the rest of the stack.
+pub async fn detect(
+    State(slow): State<Arc<SlowRequests>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let method = request.method().clone();
+    let path = request.uri().path().to_string();
+    let request_id = request.headers().get(REQUEST_ID).cloned();
+    let start = Instant::now();
+    let response = next.run(request).await;
+    let elapsed = start.elapsed();
+    if slow.is_slow(elapsed) {
+        slow.count.fetch_add(1, Ordering::Relaxed);
+        warn!(
+            target: "slow",
+            "slow request method={} path={} status={} latency={}ms request_id={}",
+            method,
+            path,
+            response.status().as_u16(),
+            elapsed.as_millis(),
+            request_id
+                .as_ref()
+                .and_then(|id| id.to_str().ok())
+                .unwrap_or_default()
+        );
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, middleware, routing::get, Router};
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn test_detect() {
+        let slow = Arc::new(SlowRequests::new(Duration::from_millis(20)));
+        let app = Router::new()
+            .route(
+                "/slow",
+                get(|| async {
+                    tokio::time::sleep(Duration::from_millis(50)).await;
+                    "done"
+                }),
+            )
+            .route("/fast", get(|| async { "done" }))
+            .layer(middleware::from_fn_with_state(slow.clone(), detect));
+        for path in ["/fast", "/slow", "/fast"] {
+            let request = Request::builder().uri(path).body(Body::empty()).unwrap();
+            app.clone().oneshot(request).await.unwrap();
+        }
+        assert_eq!(slow.count(), 1);
+    }
+}
@@ -0,0 +1,348 @@
+//! Incident emails over SMTP
+//!
+//! A minimal client for the [`paging`](crate::paging) incidents, for the
+//! environments where mail is allowed out but no paging service is. The
+//! connection is secured with `STARTTLS` or TLS from the start, the server
+//! certificate verified against the Mozilla roots, and the credentials sent
+//! with `AUTH PLAIN`. One message per trigger and resolve goes to every
+//! recipient, its subject and body rendered from the templates of
+//! `paging.smtp`.
+
+use crate::config::{SmtpConfig, SmtpTls};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chrono::{SecondsFormat, Utc};
+use std::{sync::Arc, time::Duration};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader},
+    net::TcpStream,
+};
+use tokio_rustls::{
+    rustls::{crypto::ring::default_provider, pki_types::ServerName, ClientConfig, RootCertStore},
+    TlsConnector,
+};
+
+/// Name sent in `EHLO`, an address literal would need the local address.
+const CLIENT_NAME: &str = "localhost";
+
+trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
+/// Fields of an incident the templates are rendered with.
+pub struct Incident<'a> {
+    pub source: &'a str,
+    /// `triggered` or `resolved`.
+    pub status: &'a str,
+    pub key: &'a str,
+    pub summary: &'a str,
+}
+
+pub struct Mailer {
+    config: SmtpConfig,
+    tls: TlsConnector,
+    timeout: Duration,
+}
+
+impl Mailer {
+    pub fn new(config: SmtpConfig, timeout: Duration) -> Result<Self, String> {
+        let roots = RootCertStore {
+            roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+        };
+        let tls = ClientConfig::builder_with_provider(Arc::new(default_provider()))
+            .with_safe_default_protocol_versions()
+            .map_err(|e| format!("smtp: {e}"))?
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        Ok(Mailer {
+            config,
+            tls: TlsConnector::from(Arc::new(tls)),
+            timeout,
+        })
+    }
+
+    /// Emails `incident` to every recipient.
+    pub async fn send(&self, incident: &Incident<'_>) -> Result<(), String> {
+        let message = self.message(incident, &Utc::now().to_rfc2822());
+        tokio::time::timeout(self.timeout, self.deliver(&message))
+            .await
+            .map_err(|_| "smtp: timed out".to_string())?
+    }
+
+    /// Message with its headers, the lines ending in CRLF.
+    fn message(&self, incident: &Incident<'_>, date: &str) -> String {
+        let render = |template: &str| {
+            template
+                .replace("{source}", incident.source)
+                .replace("{status}", incident.status)
+                .replace("{key}", incident.key)
+                .replace("{summary}", incident.summary)
+                .replace(
+                    "{time}",
+                    &Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true),
+                )
+        };
+        // a header ends at the first line break
+        let subject: String = render(&self.config.subject)
+            .chars()
+            .map(|c| if c == '\r' || c == '\n' { ' ' } else { c })
+            .collect();
+        let subject = match subject.is_ascii() {
+            true => subject,
+            false => format!("=?utf-8?B?{}?=", STANDARD.encode(subject)),
+        };
+        let mut message = format!(
+            "From: <{}>\r\nTo: {}\r\nSubject: {subject}\r\nDate: {date}\r\n\
+             MIME-Version: 1.0\r\nContent-Type: text/plain; charset=utf-8\r\n\
+             Content-Transfer-Encoding: 8bit\r\n\r\n",
+            self.config.from,
+            self.config
+                .to
+                .iter()
+                .map(|to| format!("<{to}>"))
+                .collect::<Vec<_>>()
+                .join(", "),
+        );
+        for line in render(&self.config.body).lines() {
+            // a line of a single dot would end the data
+            if line.starts_with('.') {
+                message.push('.');
+            }
+            message.push_str(line);
+            message.push_str("\r\n");
+        }
+        message
+    }
+
+    async fn deliver(&self, message: &str) -> Result<(), String> {
+        let config = &self.config;
+        let tcp = TcpStream::connect((config.host.as_str(), config.port))
+            .await
+            .map_err(|e| format!("smtp: {}:{}: {e}", config.host, config.port))?;
+        let stream: Box<dyn Stream> = match config.tls {
+            SmtpTls::Tls => Box::new(self.handshake(tcp).await?),
+            SmtpTls::Starttls | SmtpTls::None => Box::new(tcp),
+        };
+        let mut session = Session::new(stream);
+        session.expect(220).await?;
+        session.command(&format!("EHLO {CLIENT_NAME}"), 250).await?;
+        if config.tls == SmtpTls::Starttls {
+            session.command("STARTTLS", 220).await?;
+            let stream = session.stream.into_inner();
+            session = Session::new(Box::new(self.handshake(stream).await?));
+            session.command(&format!("EHLO {CLIENT_NAME}"), 250).await?;
+        }
+        if let Some(username) = &config.username {
+            let password = config.password.as_deref().unwrap_or_default();
+            let credentials = STANDARD.encode(format!("\0{username}\0{password}"));
+            session
+                .command(&format!("AUTH PLAIN {credentials}"), 235)
+                .await?;
+        }
+        session
+            .command(&format!("MAIL FROM:<{}>", config.from), 250)
+            .await?;
+        for to in &config.to {
+            session.command(&format!("RCPT TO:<{to}>"), 250).await?;
+        }
+        session.command("DATA", 354).await?;
+        session.command(&format!("{message}."), 250).await?;
+        // the message is accepted, a failed goodbye changes nothing
+        let _ = session.command("QUIT", 221).await;
+        Ok(())
+    }
+
+    async fn handshake<S: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        stream: S,
+    ) -> Result<tokio_rustls::client::TlsStream<S>, String> {
+        let name = ServerName::try_from(self.config.host.clone())
+            .map_err(|_| format!("smtp: invalid server name {}", self.config.host))?;
+        self.tls
+            .connect(name, stream)
+            .await
+            .map_err(|e| format!("smtp: tls: {e}"))
+    }
+}
+
+struct Session {
+    stream: BufReader<Box<dyn Stream>>,
+}
+
+impl Session {
+    fn new(stream: Box<dyn Stream>) -> Self {
+        Session {
+            stream: BufReader::new(stream),
+        }
+    }
+
+    /// Sends `line` and reads the reply, which must have the `code`. A `250`
+    /// also accepts the other success codes, e.g. `251` for a forwarded
+    /// recipient.
+    async fn command(&mut self, line: &str, code: u16) -> Result<(), String> {
+        let stream = self.stream.get_mut();
+        stream
+            .write_all(format!("{line}\r\n").as_bytes())
+            .await
+            .map_err(|e| format!("smtp: {e}"))?;
+        stream.flush().await.map_err(|e| format!("smtp: {e}"))?;
+        self.expect(code).await.map_err(|e| {
+            // neither the credentials nor the whole message in the logs
+            let verb = line.split(' ').next().unwrap_or_default();
+            format!("{e} after {verb}")
+        })
+    }
+
+    /// Reads a reply, its last line carries the code followed by a space.
+    async fn expect(&mut self, code: u16) -> Result<(), String> {
+        let mut reply = String::new();
+        loop {
+            let mut line = String::new();
+            let read = self
+                .stream
+                .read_line(&mut line)
+                .await
+                .map_err(|e| format!("smtp: {e}"))?;
+            if read == 0 {
+                return Err("smtp: connection closed".into());
+            }
+            reply.push_str(line.trim_end());
+            if line.as_bytes().get(3) != Some(&b'-') {
+                break;
+            }
+            reply.push(' ');
+        }
+        let got: u16 = reply
+            .get(..3)
+            .and_then(|code| code.parse().ok())
+            .ok_or_else(|| format!("smtp: unexpected reply {reply}"))?;
+        let accepted = match code {
+            250 => (200..300).contains(&got),
+            code => got == code,
+        };
+        match accepted {
+            true => Ok(()),
+            false => Err(format!("smtp: server answered {reply}")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::{net::TcpListener, sync::oneshot};
+
+    fn config(port: u16) -> SmtpConfig {
+        SmtpConfig {
+            host: "127.0.0.1".into(),
+            port,
+            tls: SmtpTls::None,
+            username: Some("ip".into()),
+            password: Some("secret".into()),
+            from: "ip@example.com".into(),
+            to: vec!["ops@example.com".into(), "oncall@example.com".into()],
+            ..Default::default()
+        }
+    }
+
+    fn incident(summary: &str) -> Incident<'_> {
+        Incident {
+            source: "host",
+            status: "triggered",
+            key: "host:all-providers-down",
+            summary,
+        }
+    }
+
+    /// Server answering one session, sends the lines it read once done.
+    async fn server(replies: &'static [&'static str]) -> (u16, oneshot::Receiver<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (sent, received) = oneshot::channel();
+        tokio::spawn(async move {
+            let (tcp, _) = listener.accept().await.unwrap();
+            let mut stream = BufReader::new(tcp);
+            stream.get_mut().write_all(b"220 ready\r\n").await.unwrap();
+            let mut lines = Vec::new();
+            let mut replies = replies.iter();
+            let mut data = false;
+            loop {
+                let mut line = String::new();
+                if stream.read_line(&mut line).await.unwrap() == 0 {
+                    break;
+                }
+                let line = line.trim_end().to_string();
+                let reply = match (data, line.as_str()) {
+                    (true, ".") => {
+                        data = false;
+                        Some("250 queued")
+                    }
+                    (true, _) => None,
+                    (false, "DATA") => {
+                        data = true;
+                        replies.next().copied()
+                    }
+                    (false, _) => replies.next().copied(),
+                };
+                lines.push(line);
+                if let Some(reply) = reply {
+                    let reply = format!("{reply}\r\n");
+                    stream.get_mut().write_all(reply.as_bytes()).await.unwrap();
+                }
+            }
+            let _ = sent.send(lines);
+        });
+        (port, received)
+    }
+
+    #[test]
+    fn test_message() {
+        let mailer = Mailer::new(config(25), Duration::from_secs(1)).unwrap();
+        let message = mailer.message(&incident("all down\n.\nipinfo"), "date");
+        assert!(message.starts_with("From: <ip@example.com>\r\n"));
+        assert!(message.contains("To: <ops@example.com>, <oncall@example.com>\r\n"));
+        assert!(
+            message.contains("Subject: [host] triggered: all down . ipinfo\r\n"),
+            "{message}"
+        );
+        assert!(message.contains("\r\n\r\nall down\r\n..\r\nipinfo\r\n"));
+        assert!(message.contains("Incident host:all-providers-down triggered at "));
+
+        let message = mailer.message(&incident("Zürich"), "date");
+        assert!(message.contains("Subject: =?utf-8?B?"), "{message}");
+    }
+
+    #[tokio::test]
+    async fn test_send() {
+        let (port, lines) = server(&[
+            "250-localhost\r\n250 AUTH PLAIN",
+            "235 ok",
+            "250 ok",
+            "250 ok",
+            "251 forwarded",
+            "354 go ahead",
+            "221 bye",
+        ])
+        .await;
+        let mailer = Mailer::new(config(port), Duration::from_secs(5)).unwrap();
+        mailer.send(&incident("all providers down")).await.unwrap();
+        let lines = lines.await.unwrap();
+        assert_eq!(lines[0], "EHLO localhost");
+        assert_eq!(
+            lines[1],
+            format!("AUTH PLAIN {}", STANDARD.encode("\0ip\0secret"))
+        );
+        assert_eq!(lines[2], "MAIL FROM:<ip@example.com>");
+        assert_eq!(lines[4], "RCPT TO:<oncall@example.com>");
+        assert_eq!(lines[5], "DATA");
+        assert!(lines.contains(&"Subject: [host] triggered: all providers down".into()));
+        assert_eq!(lines.last().unwrap(), "QUIT");
+
+        let (port, _) = server(&["250 localhost", "535 invalid credentials"]).await;
+        let mailer = Mailer::new(config(port), Duration::from_secs(5)).unwrap();
+        let e = mailer.send(&incident("down")).await.unwrap_err();
+        assert_eq!(
+            e,
+            "smtp: server answered 535 invalid credentials after AUTH"
+        );
+    }
+}
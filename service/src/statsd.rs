@@ -0,0 +1,155 @@
+//! StatsD and DogStatsD exporter
+//!
+//! For deployments standardized on a Datadog or StatsD agent rather than
+//! scraping `/metrics/prometheus`: the latencies the histograms observe are
+//! also sent as timings and the failed provider calls as counters, one UDP
+//! datagram each. Sending never waits, a
+//! datagram the socket can not take right away is dropped.
+
+use crate::config::StatsdConfig;
+use std::{io, net::UdpSocket, time::Duration};
+use tracing::debug;
+
+#[derive(Debug)]
+pub struct Statsd {
+    socket: UdpSocket,
+    prefix: String,
+    dogstatsd: bool,
+    /// Tags of every metric, `,` separated.
+    tags: String,
+}
+
+impl Statsd {
+    /// Socket connected to the agent of `config`.
+    pub fn new(config: &StatsdConfig) -> io::Result<Self> {
+        let local = match config.address.starts_with('[') {
+            true => "[::]:0",
+            false => "0.0.0.0:0",
+        };
+        let socket = UdpSocket::bind(local)?;
+        socket.connect(&config.address)?;
+        socket.set_nonblocking(true)?;
+        Ok(Statsd {
+            socket,
+            prefix: config.prefix.trim_end_matches('.').to_string(),
+            dogstatsd: config.dogstatsd,
+            tags: config.tags.join(","),
+        })
+    }
+
+    /// Sends `elapsed` in milliseconds as the timing `name`.
+    pub fn timing(&self, name: &str, elapsed: Duration, tags: &[(&str, &str)]) {
+        let ms = elapsed.as_secs_f64() * 1_000.0;
+        self.send(&self.line(name, &format!("{ms:.3}|ms"), tags));
+    }
+
+    /// Adds `value` to the counter `name`.
+    pub fn count(&self, name: &str, value: u64, tags: &[(&str, &str)]) {
+        self.send(&self.line(name, &format!("{value}|c"), tags));
+    }
+
+    fn line(&self, name: &str, value: &str, tags: &[(&str, &str)]) -> String {
+        let mut metric = match self.prefix.is_empty() {
+            true => name.to_string(),
+            false => format!("{}.{name}", self.prefix),
+        };
+        if !self.dogstatsd {
+            for (_, tag) in tags {
+                metric.push('.');
+                metric.push_str(&sanitize(tag));
+            }
+            return format!("{metric}:{value}");
+        }
+        let tags = tags
+            .iter()
+            .map(|(name, tag)| format!("{name}:{}", sanitize(tag)))
+            .chain((!self.tags.is_empty()).then(|| self.tags.clone()))
+            .collect::<Vec<_>>()
+            .join(",");
+        match tags.is_empty() {
+            true => format!("{metric}:{value}"),
+            false => format!("{metric}:{value}|#{tags}"),
+        }
+    }
+
+    fn send(&self, line: &str) {
+        if let Err(e) = self.socket.send(line.as_bytes()) {
+            debug!("statsd: {} not sent: {}", line, e);
+        }
+    }
+}
+
+/// Tag value without the characters of the line format.
+fn sanitize(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| match c {
+            ':' | '|' | ',' | '#' | '@' => '_',
+            c if c.is_whitespace() => '_',
+            c => c,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn agent() -> (UdpSocket, StatsdConfig) {
+        let agent = UdpSocket::bind("127.0.0.1:0").unwrap();
+        agent
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let config = StatsdConfig {
+            address: agent.local_addr().unwrap().to_string(),
+            tags: vec!["env:test".into()],
+            ..Default::default()
+        };
+        (agent, config)
+    }
+
+    fn receive(agent: &UdpSocket) -> String {
+        let mut buf = [0; 512];
+        let len = agent.recv(&mut buf).unwrap();
+        String::from_utf8_lossy(&buf[..len]).into_owned()
+    }
+
+    #[test]
+    fn test_dogstatsd() {
+        let (agent, config) = agent();
+        let statsd = Statsd::new(&config).unwrap();
+        let elapsed = Duration::from_micros(12_500);
+        statsd.timing("provider_call.duration", elapsed, &[("provider", "ip api")]);
+        assert_eq!(
+            receive(&agent),
+            "ip_service.provider_call.duration:12.500|ms|#provider:ip_api,env:test"
+        );
+        statsd.timing("request.duration", elapsed, &[]);
+        assert_eq!(
+            receive(&agent),
+            "ip_service.request.duration:12.500|ms|#env:test"
+        );
+        let tags = [("provider", "ipapi"), ("class", "timeout")];
+        statsd.count("provider_call.errors", 1, &tags);
+        assert_eq!(
+            receive(&agent),
+            "ip_service.provider_call.errors:1|c|#provider:ipapi,class:timeout,env:test"
+        );
+    }
+
+    #[test]
+    fn test_plain() {
+        let (agent, config) = agent();
+        let config = StatsdConfig {
+            dogstatsd: false,
+            ..config
+        };
+        let statsd = Statsd::new(&config).unwrap();
+        let elapsed = Duration::from_millis(3);
+        statsd.timing("provider_call.duration", elapsed, &[("provider", "ipapi")]);
+        assert_eq!(
+            receive(&agent),
+            "ip_service.provider_call.duration.ipapi:3.000|ms"
+        );
+    }
+}
@@ -0,0 +1,373 @@
+//! Threat intelligence address lists
+//!
+//! Plain text lists of known bad addresses, one address or prefix per line,
+//! downloaded on a schedule. They hold millions of entries together, so a
+//! lookup checks a bloom filter first and only the rare positive is confirmed
+//! with a binary search of the sorted entries: the answer stays exact and a
+//! clean address costs a few hashes.
+//!
+//! The lists are saved to the optional snapshot file, a restart serves them
+//! right away and only downloads new ones once they are stale.
+
+use crate::{
+    config::ThreatListsConfig,
+    dataset::{self, load_snapshot, save_snapshot, Dataset},
+    error::Error,
+};
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+use std::{
+    f64::consts::LN_2,
+    net::IpAddr,
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+use tracing::{debug, info};
+
+/// Lists of the default configuration, both publish single addresses.
+pub const DEFAULT_LISTS: &[(&str, &str)] = &[
+    ("blocklist.de", "https://lists.blocklist.de/lists/all.txt"),
+    (
+        "feodotracker",
+        "https://feodotracker.abuse.ch/downloads/ipblocklist.txt",
+    ),
+];
+
+/// Prefixes with more addresses are skipped, their expansion would outgrow
+/// the filter.
+const MAX_EXPANDED: u32 = 256;
+
+/// Lists an entry can be on, one bit each.
+pub const MAX_LISTS: usize = 64;
+
+/// Parsed list, the snapshot is a vector of them.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct List {
+    name: String,
+    addresses: Vec<IpAddr>,
+}
+
+/// Bit array with `hashes` positions per key, from the double hashing of
+/// one 64 bit digest.
+struct Bloom {
+    bits: Vec<u64>,
+    hashes: u32,
+}
+
+impl Bloom {
+    /// Filter sized for `items` keys at the given false positive rate.
+    fn new(items: usize, false_positive_rate: f64) -> Self {
+        let items = items.max(1) as f64;
+        let bits = (-items * false_positive_rate.ln() / (LN_2 * LN_2)).ceil();
+        let hashes = (bits / items * LN_2).round().clamp(1.0, 16.0) as u32;
+        Bloom {
+            bits: vec![0; (bits as usize).div_ceil(64).max(1)],
+            hashes,
+        }
+    }
+
+    fn insert(&mut self, key: u128) {
+        for position in positions(key, self.hashes, self.bits.len()) {
+            self.bits[position / 64] |= 1 << (position % 64);
+        }
+    }
+
+    fn contains(&self, key: u128) -> bool {
+        positions(key, self.hashes, self.bits.len())
+            .all(|position| self.bits[position / 64] & (1 << (position % 64)) != 0)
+    }
+}
+
+/// Bit positions of `key` in a filter of `words` 64 bit words.
+fn positions(key: u128, hashes: u32, words: usize) -> impl Iterator<Item = usize> {
+    let h1 = mix(key as u64 ^ mix((key >> 64) as u64));
+    let h2 = mix(h1 ^ 0x9e37_79b9_7f4a_7c15) | 1;
+    let len = words as u64 * 64;
+    (0..u64::from(hashes)).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % len) as usize)
+}
+
+/// Finalizer of splitmix64.
+fn mix(mut x: u64) -> u64 {
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^ (x >> 31)
+}
+
+/// IPv4 addresses are keyed by their mapped IPv6 form.
+fn key(ip: IpAddr) -> u128 {
+    match ip {
+        IpAddr::V4(ip) => u128::from(ip.to_ipv6_mapped()),
+        IpAddr::V6(ip) => u128::from(ip),
+    }
+}
+
+/// Filter in front of the sorted entries, each with the bits of its lists.
+struct Index {
+    names: Vec<String>,
+    bloom: Bloom,
+    entries: Vec<(u128, u64)>,
+}
+
+impl Index {
+    fn new(lists: &[List], false_positive_rate: f64) -> Self {
+        let mut entries: Vec<(u128, u64)> = lists
+            .iter()
+            .take(MAX_LISTS)
+            .enumerate()
+            .flat_map(|(i, list)| list.addresses.iter().map(move |ip| (key(*ip), 1 << i)))
+            .collect();
+        entries.sort_unstable();
+        // one entry per address, with the bits of every list it is on
+        entries.dedup_by(|next, kept| {
+            let same = next.0 == kept.0;
+            if same {
+                kept.1 |= next.1;
+            }
+            same
+        });
+        let mut bloom = Bloom::new(entries.len(), false_positive_rate);
+        for (key, _) in &entries {
+            bloom.insert(*key);
+        }
+        Index {
+            names: lists.iter().map(|list| list.name.clone()).collect(),
+            bloom,
+            entries,
+        }
+    }
+
+    fn lists(&self, ip: IpAddr) -> Vec<String> {
+        let key = key(ip);
+        if !self.bloom.contains(key) {
+            return Vec::new();
+        }
+        let Ok(index) = self.entries.binary_search_by_key(&key, |(key, _)| *key) else {
+            return Vec::new();
+        };
+        let bits = self.entries[index].1;
+        self.names
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| bits & (1 << i) != 0)
+            .map(|(_, name)| name.clone())
+            .collect()
+    }
+}
+
+pub struct ThreatLists {
+    http: reqwest::Client,
+    config: ThreatListsConfig,
+    index: RwLock<Option<Arc<Index>>>,
+    /// Time of the data in `index`, `None` before the first download.
+    updated: RwLock<Option<SystemTime>>,
+}
+
+impl ThreatLists {
+    /// Lists with the snapshot loaded, empty until the first download without one.
+    pub fn new(http: reqwest::Client, config: ThreatListsConfig) -> Self {
+        let lists = ThreatLists {
+            http,
+            config,
+            index: RwLock::default(),
+            updated: RwLock::default(),
+        };
+        let snapshot = lists.config.snapshot.as_deref().and_then(load_snapshot);
+        if let Some((snapshot, modified)) = snapshot {
+            let snapshot: Vec<List> = snapshot;
+            lists.replace(
+                Index::new(&snapshot, lists.config.false_positive_rate),
+                modified,
+            );
+        }
+        lists
+    }
+
+    /// Refreshes the lists every `refresh_secs` until they are dropped.
+    pub fn spawn_refresh(self: &Arc<Self>) {
+        dataset::spawn_refresh(self);
+    }
+
+    /// Names of the lists `ip` is on, `None` before the first download.
+    pub fn lists(&self, ip: IpAddr) -> Option<Vec<String>> {
+        let index = self.index.read().unwrap().clone()?;
+        Some(index.lists(ip))
+    }
+
+    /// Whether `ip` is on any of the lists.
+    pub fn is_listed(&self, ip: IpAddr) -> bool {
+        self.lists(ip).is_some_and(|lists| !lists.is_empty())
+    }
+
+    fn replace(&self, index: Index, updated: SystemTime) {
+        *self.index.write().unwrap() = Some(Arc::new(index));
+        *self.updated.write().unwrap() = Some(updated);
+    }
+
+    async fn download(&self, url: &str) -> Result<String, Error> {
+        let unavailable = |e: reqwest::Error| Error::ProviderUnavailable(format!("{url}: {e}"));
+        self.http
+            .get(url)
+            .timeout(Duration::from_millis(self.config.timeout_ms))
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+            .map_err(unavailable)?
+            .text()
+            .await
+            .map_err(unavailable)
+    }
+}
+
+impl Dataset for ThreatLists {
+    const NAME: &'static str = "threat_lists";
+
+    fn updated(&self) -> Option<SystemTime> {
+        *self.updated.read().unwrap()
+    }
+
+    fn refresh_interval(&self) -> Duration {
+        Duration::from_secs(self.config.refresh_secs)
+    }
+
+    /// Downloads every list and saves the snapshot.
+    async fn refresh(&self) -> Result<usize, Error> {
+        let mut lists = Vec::new();
+        for source in &self.config.lists {
+            let text = self.download(&source.url).await?;
+            lists.push(parse(&source.name, &text));
+        }
+        if let Some(path) = &self.config.snapshot {
+            save_snapshot(path, &lists).await?;
+        }
+        let rate = self.config.false_positive_rate;
+        let index = tokio::task::spawn_blocking(move || Index::new(&lists, rate))
+            .await
+            .map_err(|e| Error::Internal(e.to_string()))?;
+        let count = index.entries.len();
+        self.replace(index, SystemTime::now());
+        Ok(count)
+    }
+}
+
+/// First token of every line, `#` and `;` start comments. Prefixes of up to
+/// [`MAX_EXPANDED`] addresses are expanded, larger ones are skipped.
+fn parse(name: &str, text: &str) -> List {
+    let mut addresses = Vec::new();
+    let mut skipped = 0;
+    for line in text.lines() {
+        let line = line.split(['#', ';']).next().unwrap_or_default();
+        let Some(token) = line.split_whitespace().next() else {
+            continue;
+        };
+        if let Ok(ip) = token.parse::<IpAddr>() {
+            addresses.push(ip);
+            continue;
+        }
+        match token.parse::<IpNet>() {
+            Ok(net) if net.max_prefix_len() - net.prefix_len() <= MAX_EXPANDED.ilog2() as u8 => {
+                addresses.extend(expand(net));
+            }
+            Ok(_) => skipped += 1,
+            Err(_) => debug!("{}: ignoring line {}", name, token),
+        }
+    }
+    if skipped > 0 {
+        info!(
+            "{}: skipped {} prefixes larger than {} addresses",
+            name, skipped, MAX_EXPANDED
+        );
+    }
+    List {
+        name: name.into(),
+        addresses,
+    }
+}
+
+/// Every address of a small prefix, network and broadcast included.
+fn expand(net: IpNet) -> Vec<IpAddr> {
+    match net {
+        IpNet::V4(net) => (u32::from(net.network())..=u32::from(net.broadcast()))
+            .map(|ip| IpAddr::from(std::net::Ipv4Addr::from(ip)))
+            .collect(),
+        IpNet::V6(net) => (u128::from(net.network())..=u128::from(net.broadcast()))
+            .map(|ip| IpAddr::from(std::net::Ipv6Addr::from(ip)))
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FEODO: &str = "# Feodo Tracker\n# Last updated\n192.0.2.10\n192.0.2.11 # comment\n\n";
+    const DROP: &str =
+        "; Spamhaus DROP\n198.51.100.0/30 ; SBL1\n10.0.0.0/8 ; SBL2\n2001:db8::1\nbogus\n";
+
+    fn index() -> Index {
+        let lists = [parse("feodo", FEODO), parse("drop", DROP)];
+        Index::new(&lists, 0.01)
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse("feodo", FEODO).addresses.len(), 2);
+        let drop = parse("drop", DROP);
+        assert_eq!(drop.addresses.len(), 5, "the /8 is skipped");
+        assert!(drop.addresses.contains(&"198.51.100.3".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_lists() {
+        let index = index();
+        let listed = |ip: &str| index.lists(ip.parse().unwrap());
+        assert_eq!(listed("192.0.2.10"), vec!["feodo"]);
+        assert_eq!(listed("198.51.100.2"), vec!["drop"]);
+        assert_eq!(
+            listed("::ffff:192.0.2.11"),
+            vec!["feodo"],
+            "mapped addresses match"
+        );
+        assert_eq!(listed("2001:db8::1"), vec!["drop"]);
+        assert!(listed("198.51.100.4").is_empty());
+        assert!(listed("10.1.2.3").is_empty());
+
+        let both = [parse("a", "192.0.2.1"), parse("b", "192.0.2.1\n192.0.2.2")];
+        let index = Index::new(&both, 0.01);
+        assert_eq!(index.lists("192.0.2.1".parse().unwrap()), vec!["a", "b"]);
+        assert_eq!(index.entries.len(), 2);
+    }
+
+    #[test]
+    fn test_bloom() {
+        let mut bloom = Bloom::new(10_000, 0.01);
+        for key in 0..10_000u128 {
+            bloom.insert(key * 7919);
+        }
+        assert!(
+            (0..10_000u128).all(|key| bloom.contains(key * 7919)),
+            "no false negatives"
+        );
+        let false_positives = (0..10_000u128)
+            .filter(|key| bloom.contains((key + 1_000_000) * 7919 + 1))
+            .count();
+        assert!(false_positives < 300, "{false_positives} false positives");
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let path = std::env::temp_dir().join(format!("ip-service-{}.json", uuid::Uuid::new_v4()));
+        let lists = vec![parse("feodo", FEODO)];
+        std::fs::write(&path, serde_json::to_vec(&lists).unwrap()).unwrap();
+        let config = ThreatListsConfig {
+            snapshot: Some(path.clone()),
+            ..Default::default()
+        };
+        let threat_lists = ThreatLists::new(reqwest::Client::new(), config);
+        assert!(threat_lists.is_listed("192.0.2.10".parse().unwrap()));
+        assert!(!threat_lists.is_listed("8.8.8.8".parse().unwrap()));
+        std::fs::remove_file(path).unwrap();
+
+        let empty = ThreatLists::new(reqwest::Client::new(), ThreatListsConfig::default());
+        assert_eq!(empty.lists("192.0.2.10".parse().unwrap()), None);
+    }
+}
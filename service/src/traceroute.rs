@@ -0,0 +1,424 @@
+//! Traceroute
+//!
+//! UDP probes are sent with an increasing TTL, every router on the path
+//! answers the expiring one with an ICMP time exceeded and the target with a
+//! port unreachable. Reading the ICMP answers needs a raw socket, that is
+//! `CAP_NET_RAW`. Without it the system `traceroute` command is run instead,
+//! it relies on its own setuid bit or the unprivileged error queue. Each trace
+//! holds a blocking thread or a process for seconds, so only a few run at
+//! once, within a global rate and a rate per target, and private addresses
+//! are refused unless allowed.
+
+use crate::{
+    config::TracerouteConfig,
+    error::Error,
+    geo::Geo,
+    ratelimit::{KeyedRateLimiter, RateLimiter},
+};
+use serde::{Deserialize, Serialize};
+use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+use std::{
+    io::{self, ErrorKind},
+    net::{IpAddr, SocketAddr, UdpSocket},
+    time::{Duration, Instant},
+};
+use tokio::sync::Semaphore;
+use tracing::debug;
+use utoipa::ToSchema;
+
+/// UDP payload of a probe.
+const PROBE: [u8; 32] = [0; 32];
+
+/// How the path was traced.
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum TraceMethod {
+    /// Raw ICMP socket of the service.
+    Raw,
+    /// The system `traceroute` command.
+    Command,
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone)]
+pub struct Traceroute {
+    #[schema(value_type = String)]
+    pub target: IpAddr,
+    pub method: TraceMethod,
+    /// The target answered before the hop limit.
+    pub reached: bool,
+    pub hops: Vec<Hop>,
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone, PartialEq)]
+pub struct Hop {
+    pub ttl: u8,
+    /// Router that answered, `None` when the probe timed out.
+    #[schema(value_type = Option<String>)]
+    pub address: Option<IpAddr>,
+    pub rtt_ms: Option<f64>,
+    /// Country, location and ASN of public hop addresses.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub geo: Option<Geo>,
+}
+
+impl Hop {
+    fn timeout(ttl: u8) -> Self {
+        Hop {
+            ttl,
+            address: None,
+            rtt_ms: None,
+            geo: None,
+        }
+    }
+}
+
+/// ICMP answer to a probe.
+#[derive(Debug, PartialEq)]
+enum Reply {
+    TimeExceeded,
+    Unreachable,
+}
+
+pub struct Tracer {
+    config: TracerouteConfig,
+    running: Semaphore,
+    limiter: RateLimiter,
+    targets: KeyedRateLimiter<IpAddr>,
+}
+
+impl Tracer {
+    pub fn new(config: TracerouteConfig) -> Self {
+        let period = Duration::from_secs(config.period_secs);
+        Tracer {
+            running: Semaphore::new(config.concurrency),
+            limiter: RateLimiter::new(config.requests, period),
+            targets: KeyedRateLimiter::new(config.requests_per_target, period),
+            config,
+        }
+    }
+
+    pub fn max_hops(&self) -> u8 {
+        self.config.max_hops
+    }
+
+    /// Path to `target`, over a raw socket when permitted or else the command.
+    pub async fn trace(&self, target: IpAddr, max_hops: u8) -> Result<Traceroute, Error> {
+        if !self.config.allow_private && !routable(target) {
+            return Err(Error::InvalidInput(format!(
+                "{target} is not a public address"
+            )));
+        }
+        let _permit = self
+            .running
+            .try_acquire()
+            .map_err(|_| Error::RateLimited("too many traceroutes running".into()))?;
+        if !self.limiter.try_acquire() {
+            return Err(Error::RateLimited("too many traceroutes".into()));
+        }
+        if !self.targets.try_acquire(target) {
+            return Err(Error::RateLimited(format!(
+                "too many traceroutes to {target}"
+            )));
+        }
+        let timeout = Duration::from_millis(self.config.hop_timeout_ms);
+        let port = self.config.port;
+        let raw =
+            tokio::task::spawn_blocking(move || trace_raw(target, max_hops, timeout, port)).await;
+        let (method, hops) = match raw.map_err(|e| Error::Internal(e.to_string()))? {
+            Ok(hops) => (TraceMethod::Raw, hops),
+            Err(e) if e.kind() == ErrorKind::PermissionDenied => {
+                debug!("traceroute: no raw socket, running {}", self.config.command);
+                (
+                    TraceMethod::Command,
+                    self.trace_command(target, max_hops).await?,
+                )
+            }
+            Err(e) => {
+                return Err(Error::Internal(format!(
+                    "traceroute to {target} failed: {e}"
+                )));
+            }
+        };
+        let reached = hops.iter().any(|hop| hop.address == Some(target));
+        Ok(Traceroute {
+            target,
+            method,
+            reached,
+            hops,
+        })
+    }
+
+    async fn trace_command(&self, target: IpAddr, max_hops: u8) -> Result<Vec<Hop>, Error> {
+        let wait = self.config.hop_timeout_ms.div_ceil(1000).max(1);
+        let output = tokio::process::Command::new(&self.config.command)
+            .args(["-n", "-q", "1"])
+            .args(["-m", &max_hops.to_string()])
+            .args(["-w", &wait.to_string()])
+            .args(["-p", &self.config.port.to_string()])
+            .arg(target.to_string())
+            .kill_on_drop(true)
+            .output()
+            .await
+            .map_err(|e| {
+                Error::ProviderUnavailable(format!(
+                    "traceroute needs CAP_NET_RAW or the {} command: {e}",
+                    self.config.command
+                ))
+            })?;
+        if !output.status.success() {
+            return Err(Error::ProviderUnavailable(format!(
+                "{} failed: {}",
+                self.config.command,
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+        Ok(parse_output(&String::from_utf8_lossy(&output.stdout)))
+    }
+}
+
+/// Whether the geo providers can know `ip`, private and link-local hops are left alone.
+pub fn routable(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let shared = ip.octets()[0] == 100 && (ip.octets()[1] & 0xc0) == 64;
+            !(ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || shared)
+        }
+        IpAddr::V6(ip) => {
+            let first = ip.segments()[0];
+            let unique_local = (first & 0xfe00) == 0xfc00;
+            let link_local = (first & 0xffc0) == 0xfe80;
+            !(ip.is_loopback() || ip.is_unspecified() || unique_local || link_local)
+        }
+    }
+}
+
+fn trace_raw(target: IpAddr, max_hops: u8, timeout: Duration, port: u16) -> io::Result<Vec<Hop>> {
+    let (domain, protocol) = match target {
+        IpAddr::V4(_) => (Domain::IPV4, Protocol::ICMPV4),
+        IpAddr::V6(_) => (Domain::IPV6, Protocol::ICMPV6),
+    };
+    // std only needs a datagram descriptor for `recv_from`
+    let icmp: UdpSocket = Socket::new(domain, Type::RAW, Some(protocol))?.into();
+    let probe = Socket::new(domain, Type::DGRAM, Some(Protocol::UDP))?;
+    let unspecified = match target {
+        IpAddr::V4(_) => IpAddr::from([0u8; 4]),
+        IpAddr::V6(_) => IpAddr::from([0u8; 16]),
+    };
+    probe.bind(&SocketAddr::new(unspecified, 0).into())?;
+    let source_port = probe
+        .local_addr()?
+        .as_socket()
+        .map_or(0, |addr| addr.port());
+
+    let mut hops = Vec::new();
+    let mut buf = [0u8; 1500];
+    for ttl in 1..=max_hops {
+        match target {
+            IpAddr::V4(_) => probe.set_ttl_v4(ttl.into())?,
+            IpAddr::V6(_) => probe.set_unicast_hops_v6(ttl.into())?,
+        }
+        let destination_port = port.wrapping_add(ttl.into());
+        let sent = Instant::now();
+        probe.send_to(
+            &PROBE,
+            &SockAddr::from(SocketAddr::new(target, destination_port)),
+        )?;
+        let answer = loop {
+            let left = timeout.saturating_sub(sent.elapsed());
+            if left.is_zero() {
+                break None;
+            }
+            icmp.set_read_timeout(Some(left))?;
+            let (len, from) = match icmp.recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                    break None;
+                }
+                Err(e) => return Err(e),
+            };
+            // every ICMP message of the host arrives here, only ours count
+            let reply = parse_reply(target, &buf[..len], source_port, destination_port);
+            if let Some(reply) = reply {
+                break Some((from.ip(), sent.elapsed(), reply));
+            }
+        };
+        match answer {
+            Some((address, rtt, reply)) => {
+                hops.push(Hop {
+                    ttl,
+                    address: Some(address),
+                    rtt_ms: Some(rtt.as_secs_f64() * 1000.0),
+                    geo: None,
+                });
+                if reply == Reply::Unreachable {
+                    break;
+                }
+            }
+            None => hops.push(Hop::timeout(ttl)),
+        }
+    }
+    Ok(hops)
+}
+
+/// ICMP answer quoting our probe to `target`. IPv4 raw sockets receive the
+/// IP header, IPv6 ones start at the ICMP header.
+fn parse_reply(
+    target: IpAddr,
+    packet: &[u8],
+    source_port: u16,
+    destination_port: u16,
+) -> Option<Reply> {
+    let (reply, quoted_destination, udp) = match target {
+        IpAddr::V4(_) => {
+            let icmp = packet.get(usize::from(packet.first()? & 0x0f) * 4..)?;
+            let reply = match icmp.first()? {
+                11 => Reply::TimeExceeded,
+                3 => Reply::Unreachable,
+                _ => return None,
+            };
+            let inner = icmp.get(8..)?;
+            if *inner.get(9)? != 17 {
+                return None;
+            }
+            let destination: [u8; 4] = inner.get(16..20)?.try_into().ok()?;
+            let udp = inner.get(usize::from(inner.first()? & 0x0f) * 4..)?;
+            (reply, IpAddr::from(destination), udp)
+        }
+        IpAddr::V6(_) => {
+            let reply = match packet.first()? {
+                3 => Reply::TimeExceeded,
+                1 => Reply::Unreachable,
+                _ => return None,
+            };
+            let inner = packet.get(8..)?;
+            // extension headers are not followed
+            if *inner.get(6)? != 17 {
+                return None;
+            }
+            let destination: [u8; 16] = inner.get(24..40)?.try_into().ok()?;
+            (reply, IpAddr::from(destination), inner.get(40..)?)
+        }
+    };
+    let port = |at: usize| Some(u16::from_be_bytes(udp.get(at..at + 2)?.try_into().ok()?));
+    let ours =
+        quoted_destination == target && port(0)? == source_port && port(2)? == destination_port;
+    ours.then_some(reply)
+}
+
+/// Hops of `traceroute -n -q 1` output, the header line is skipped.
+fn parse_output(output: &str) -> Vec<Hop> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let mut words = line.split_whitespace();
+            let ttl = words.next()?.parse().ok()?;
+            let address = words.next().and_then(|word| word.parse().ok());
+            let rtt_ms = address.and(words.next()).and_then(|word| word.parse().ok());
+            Some(Hop {
+                ttl,
+                address,
+                rtt_ms,
+                geo: None,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// ICMP time exceeded from 192.0.2.1 quoting a probe from port 40000 to 8.8.8.8:33435.
+    const TIME_EXCEEDED: &[u8] = &[
+        // outer IPv4 header
+        0x45, 0, 0, 56, 0, 0, 0, 0, 64, 1, 0, 0, 192, 0, 2, 1, 198, 51, 100, 7,
+        // ICMP time exceeded
+        11, 0, 0, 0, 0, 0, 0, 0, // quoted IPv4 header
+        0x45, 0, 0, 60, 0, 0, 0, 0, 1, 17, 0, 0, 198, 51, 100, 7, 8, 8, 8, 8,
+        // quoted UDP header
+        0x9c, 0x40, 0x82, 0x9b, 0, 40, 0, 0,
+    ];
+
+    #[tokio::test]
+    async fn test_limits() {
+        let localhost = "127.0.0.1".parse().unwrap();
+        let strict = Tracer::new(TracerouteConfig::default());
+        assert!(matches!(
+            strict.trace(localhost, 1).await,
+            Err(Error::InvalidInput(_))
+        ));
+
+        let tracer = Tracer::new(TracerouteConfig {
+            hop_timeout_ms: 100,
+            command: "nonexistent-traceroute".into(),
+            requests_per_target: 1,
+            allow_private: true,
+            ..Default::default()
+        });
+        let running = tracer.running.acquire_many(4).await.unwrap();
+        assert!(matches!(
+            tracer.trace(localhost, 1).await,
+            Err(Error::RateLimited(_))
+        ));
+        drop(running);
+        // traced or not, the attempt counts
+        let _ = tracer.trace(localhost, 1).await;
+        assert!(matches!(
+            tracer.trace(localhost, 1).await,
+            Err(Error::RateLimited(_))
+        ));
+    }
+
+    #[test]
+    fn test_parse_reply() {
+        let target = "8.8.8.8".parse().unwrap();
+        assert_eq!(
+            parse_reply(target, TIME_EXCEEDED, 40000, 33435),
+            Some(Reply::TimeExceeded)
+        );
+        assert_eq!(parse_reply(target, TIME_EXCEEDED, 40000, 33436), None);
+        assert_eq!(
+            parse_reply("8.8.4.4".parse().unwrap(), TIME_EXCEEDED, 40000, 33435),
+            None
+        );
+        assert_eq!(
+            parse_reply(target, &TIME_EXCEEDED[..30], 40000, 33435),
+            None
+        );
+
+        let mut unreachable = TIME_EXCEEDED.to_vec();
+        unreachable[20] = 3;
+        assert_eq!(
+            parse_reply(target, &unreachable, 40000, 33435),
+            Some(Reply::Unreachable)
+        );
+    }
+
+    #[test]
+    fn test_parse_output() {
+        let output = "traceroute to 8.8.8.8 (8.8.8.8), 30 hops max, 60 byte packets\n \
+            1  192.168.1.1  0.512 ms\n \
+            2  *\n \
+            3  8.8.8.8  9.870 ms\n";
+        let hops = parse_output(output);
+        assert_eq!(hops.len(), 3);
+        assert_eq!(hops[0].rtt_ms, Some(0.512));
+        assert_eq!(hops[1], Hop::timeout(2));
+        assert_eq!(hops[2].address, Some("8.8.8.8".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_routable() {
+        assert!(routable("8.8.8.8".parse().unwrap()));
+        assert!(!routable("192.168.1.1".parse().unwrap()));
+        assert!(!routable("100.64.0.1".parse().unwrap()));
+        assert!(routable("100.128.0.1".parse().unwrap()));
+        assert!(!routable("fe80::1".parse().unwrap()));
+        assert!(!routable("fd00::1".parse().unwrap()));
+        assert!(routable("2001:4860:4860::8888".parse().unwrap()));
+    }
+}
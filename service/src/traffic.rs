@@ -0,0 +1,281 @@
+//! Live traffic statistics of the dashboard
+//!
+//! The last minute of API requests in one-second buckets: the request rate,
+//! the latency percentiles and the cache hit rate of the lookups, next to the
+//! most recent lookups. Held in memory only and pushed to `/dashboard` by the
+//! `/v1/stats/live` event stream.
+
+use crate::{
+    error::Error,
+    service::{Lookup, LookupRequest},
+};
+use axum::{
+    extract::{Request, State},
+    middleware::Next,
+    response::Response,
+};
+use chrono::{SecondsFormat, Utc};
+use serde::Serialize;
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use utoipa::ToSchema;
+
+/// Seconds of traffic kept.
+const WINDOW_SECS: u64 = 60;
+/// Seconds the request rate is averaged over.
+const RATE_SECS: u64 = 10;
+/// Latency samples kept per second, the further requests are only counted.
+const SAMPLES_PER_SEC: usize = 1_000;
+/// Recent lookups listed.
+const RECENT: usize = 20;
+
+#[derive(Debug, Default)]
+struct Bucket {
+    second: u64,
+    requests: u64,
+    latencies_ms: Vec<u64>,
+    hits: u64,
+    misses: u64,
+    errors: u64,
+}
+
+/// One lookup of the recent list.
+#[derive(Serialize, ToSchema, Debug, Clone, PartialEq)]
+pub struct RecentLookup {
+    /// RFC 3339 time the lookup completed.
+    pub at: String,
+    /// Address, hostname or `self` for the caller's own address.
+    pub target: String,
+    /// Absent for failed lookups.
+    pub provider: Option<String>,
+    pub cached: bool,
+    /// Code of the error of a failed lookup.
+    pub error: Option<String>,
+    pub latency_ms: u64,
+}
+
+/// Traffic of the last minute.
+#[derive(Serialize, ToSchema, Debug, Clone, PartialEq)]
+pub struct TrafficSnapshot {
+    /// Requests per second over the last 10 seconds.
+    pub rps: f64,
+    /// Requests of the last minute.
+    pub requests: u64,
+    pub latency_p50_ms: Option<u64>,
+    pub latency_p95_ms: Option<u64>,
+    pub latency_p99_ms: Option<u64>,
+    /// Share of the lookups of the last minute answered from the cache,
+    /// absent without lookups.
+    pub cache_hit_rate: Option<f64>,
+    /// Failed lookups of the last minute.
+    pub lookup_errors: u64,
+    /// Most recent first.
+    pub recent: Vec<RecentLookup>,
+}
+
+#[derive(Debug, Default)]
+pub struct Traffic {
+    buckets: Mutex<VecDeque<Bucket>>,
+    recent: Mutex<VecDeque<RecentLookup>>,
+}
+
+impl Traffic {
+    /// Counts a request answered after `latency`.
+    pub fn request(&self, latency: Duration) {
+        self.request_at(now(), latency);
+    }
+
+    /// Counts the outcome of a lookup and lists it with the recent ones.
+    pub fn lookup(&self, req: &LookupRequest, result: &Result<Lookup, Error>, latency_ms: u128) {
+        self.lookup_at(now(), req, result, latency_ms);
+    }
+
+    pub fn snapshot(&self) -> TrafficSnapshot {
+        self.snapshot_at(now())
+    }
+
+    fn request_at(&self, now: u64, latency: Duration) {
+        self.with_bucket(now, |bucket| {
+            bucket.requests += 1;
+            if bucket.latencies_ms.len() < SAMPLES_PER_SEC {
+                bucket.latencies_ms.push(latency.as_millis() as u64);
+            }
+        });
+    }
+
+    fn lookup_at(
+        &self,
+        now: u64,
+        req: &LookupRequest,
+        result: &Result<Lookup, Error>,
+        latency_ms: u128,
+    ) {
+        self.with_bucket(now, |bucket| match result {
+            Ok(lookup) if lookup.cached => bucket.hits += 1,
+            Ok(_) => bucket.misses += 1,
+            Err(_) => bucket.errors += 1,
+        });
+        let (provider, cached, error) = match result {
+            Ok(lookup) => (Some(lookup.geo.provider.clone()), lookup.cached, None),
+            Err(e) => (None, false, Some(e.code().to_string())),
+        };
+        let mut recent = self.recent.lock().unwrap();
+        if recent.len() >= RECENT {
+            recent.pop_back();
+        }
+        recent.push_front(RecentLookup {
+            at: Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true),
+            target: req
+                .ip
+                .as_deref()
+                .or(req.host.as_deref())
+                .unwrap_or("self")
+                .to_string(),
+            provider,
+            cached,
+            error,
+            latency_ms: latency_ms as u64,
+        });
+    }
+
+    /// Runs `update` on the bucket of `now`, dropping the buckets out of the window.
+    fn with_bucket(&self, now: u64, update: impl FnOnce(&mut Bucket)) {
+        let mut buckets = self.buckets.lock().unwrap();
+        expire(&mut buckets, now);
+        if buckets.back().is_none_or(|bucket| bucket.second < now) {
+            buckets.push_back(Bucket {
+                second: now,
+                ..Default::default()
+            });
+        }
+        // a clock stepping back counts into the latest bucket
+        update(buckets.back_mut().expect("pushed"));
+    }
+
+    fn snapshot_at(&self, now: u64) -> TrafficSnapshot {
+        let mut buckets = self.buckets.lock().unwrap();
+        expire(&mut buckets, now);
+        // the current second is still filling up
+        let rated: u64 = buckets
+            .iter()
+            .filter(|bucket| bucket.second < now && bucket.second + RATE_SECS >= now)
+            .map(|bucket| bucket.requests)
+            .sum();
+        let mut latencies: Vec<u64> = buckets
+            .iter()
+            .flat_map(|bucket| bucket.latencies_ms.iter().copied())
+            .collect();
+        latencies.sort_unstable();
+        let percentile = |p: usize| match latencies.len() {
+            0 => None,
+            len => Some(latencies[(len * p / 100).min(len - 1)]),
+        };
+        let (hits, misses) = buckets.iter().fold((0, 0), |(hits, misses), bucket| {
+            (hits + bucket.hits, misses + bucket.misses)
+        });
+        TrafficSnapshot {
+            rps: rated as f64 / RATE_SECS as f64,
+            requests: buckets.iter().map(|bucket| bucket.requests).sum(),
+            latency_p50_ms: percentile(50),
+            latency_p95_ms: percentile(95),
+            latency_p99_ms: percentile(99),
+            cache_hit_rate: (hits + misses > 0).then(|| hits as f64 / (hits + misses) as f64),
+            lookup_errors: buckets.iter().map(|bucket| bucket.errors).sum(),
+            recent: self.recent.lock().unwrap().iter().cloned().collect(),
+        }
+    }
+}
+
+fn expire(buckets: &mut VecDeque<Bucket>, now: u64) {
+    while buckets
+        .front()
+        .is_some_and(|bucket| bucket.second + WINDOW_SECS <= now)
+    {
+        buckets.pop_front();
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Middleware counting the requests of the rest of the stack.
+pub async fn track(State(traffic): State<Arc<Traffic>>, request: Request, next: Next) -> Response {
+    let start = Instant::now();
+    let response = next.run(request).await;
+    traffic.request(start.elapsed());
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::{ProviderConfig, ProviderKind},
+        LookupService,
+    };
+
+    #[test]
+    fn test_snapshot() {
+        let traffic = Traffic::default();
+        for second in 100..110 {
+            for ms in 1..=10 {
+                traffic.request_at(second, Duration::from_millis(ms * 10));
+            }
+        }
+        let snapshot = traffic.snapshot_at(110);
+        assert_eq!(snapshot.rps, 10.0);
+        assert_eq!(snapshot.requests, 100);
+        assert_eq!(snapshot.latency_p50_ms, Some(60));
+        assert_eq!(snapshot.latency_p99_ms, Some(100));
+        assert_eq!(snapshot.cache_hit_rate, None);
+
+        let snapshot = traffic.snapshot_at(165);
+        assert_eq!(snapshot.requests, 40, "Older seconds out of the window");
+        assert_eq!(snapshot.rps, 0.0);
+        assert_eq!(traffic.snapshot_at(500).latency_p50_ms, None);
+    }
+
+    #[tokio::test]
+    async fn test_lookups() {
+        let traffic = Traffic::default();
+        let error = || Err(Error::Timeout);
+        for _ in 0..RECENT + 5 {
+            traffic.lookup_at(100, &LookupRequest::ip("198.51.100.1"), &error(), 5);
+        }
+        let snapshot = traffic.snapshot_at(100);
+        assert_eq!(snapshot.lookup_errors, RECENT as u64 + 5);
+        assert_eq!(
+            snapshot.cache_hit_rate, None,
+            "Errors are neither hits nor misses"
+        );
+        assert_eq!(snapshot.recent.len(), RECENT);
+        assert_eq!(snapshot.recent[0].error.as_deref(), Some("timeout"));
+        assert_eq!(snapshot.recent[0].target, "198.51.100.1");
+
+        let service = LookupService::builder()
+            .provider(ProviderConfig::new(ProviderKind::Mock))
+            .build();
+        let request = LookupRequest::ip("8.8.8.8");
+        let fresh = service.lookup(&request).await.unwrap();
+        let cached = Lookup {
+            cached: true,
+            ..fresh.clone()
+        };
+        let request = LookupRequest::default();
+        traffic.lookup_at(100, &request, &Ok(cached), 1);
+        traffic.lookup_at(100, &request, &Ok(fresh.clone()), 1);
+        traffic.lookup_at(100, &request, &Ok(fresh), 1);
+        let snapshot = traffic.snapshot_at(101);
+        let hit_rate = snapshot.cache_hit_rate.unwrap();
+        assert!((hit_rate - 1.0 / 3.0).abs() < 1e-9);
+        assert_eq!(snapshot.recent[0].target, "self");
+        assert_eq!(snapshot.recent[0].provider.as_deref(), Some("mock"));
+    }
+}
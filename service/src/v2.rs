@@ -0,0 +1,266 @@
+//! Normalized lookup schema, `application/vnd.adatari.v2+json`
+//!
+//! The v1 [`Lookup`] grew field by field: the provider payload, the location,
+//! the network and the reputation blocks sit side by side at the top level.
+//! The v2 schema groups them into `location`, `network`, `security` and
+//! `meta`, and leaves the provider payload out. The lookup endpoints answer it
+//! when the `Accept` header asks for it, see [`Schema`](crate::versioning::Schema),
+//! the v1 schema stays the default.
+
+use crate::{
+    abuseipdb::AbuseReport,
+    anonymity::Anonymity,
+    carrier::{Carrier, ConnectionType},
+    confidence::Confidence,
+    country::CountryFlag,
+    dns::Resolution,
+    dnsbl::DnsblReport,
+    error::ErrorBody,
+    geo::Threat,
+    greynoise::Noise,
+    ixp::Exchange,
+    pdns::PassiveDns,
+    risk::RiskScore,
+    service::{BatchItem, HostLookup, Lookup, LookupResponse},
+    shodan::ShodanHost,
+};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+/// [`Lookup`] in the v2 schema.
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone)]
+pub struct LookupV2 {
+    pub ip: String,
+    pub location: Location,
+    pub network: Network,
+    pub security: Security,
+    /// How reliable the fields of `location` and `network` are.
+    pub confidence: Confidence,
+    /// Set for hostname lookups, the top level describes the first address.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub host: Option<HostLookupV2>,
+    pub meta: Meta,
+}
+
+/// Where the address is located.
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone)]
+pub struct Location {
+    pub continent: Option<String>,
+    pub continent_code: Option<String>,
+    pub country: Option<String>,
+    /// ISO 3166-1 alpha-2 country code.
+    pub country_code: Option<String>,
+    pub is_eu: Option<bool>,
+    pub is_eea: Option<bool>,
+    pub is_uk: Option<bool>,
+    pub gdpr: Option<bool>,
+    pub region: Option<String>,
+    pub region_code: Option<String>,
+    pub city: Option<String>,
+    pub postal_code: Option<String>,
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+    pub timezone: Option<String>,
+    pub utc_offset: Option<i32>,
+    pub currency: Option<String>,
+    pub calling_code: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub flag: Option<CountryFlag>,
+}
+
+/// Who operates the address and how it is connected.
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone)]
+pub struct Network {
+    pub asn: Option<u32>,
+    pub as_name: Option<String>,
+    pub isp: Option<String>,
+    pub org: Option<String>,
+    pub hostname: Option<String>,
+    pub carrier: Option<Carrier>,
+    pub connection_type: ConnectionType,
+    pub is_anycast: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ixp: Option<Exchange>,
+}
+
+/// Anonymity, reputation and the optional enrichments.
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone)]
+pub struct Security {
+    pub anonymity: Anonymity,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub risk: Option<RiskScore>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub threat: Option<Threat>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub threat_lists: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub abuse: Option<AbuseReport>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub noise: Option<Noise>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub shodan: Option<ShodanHost>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub passive_dns: Option<PassiveDns>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dnsbl: Option<DnsblReport>,
+}
+
+/// How the answer was obtained.
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone)]
+pub struct Meta {
+    /// Provider that answered the lookup.
+    pub provider: String,
+    /// Served as `X-Cache`, see [`Lookup::cached`].
+    #[serde(skip)]
+    pub cached: bool,
+    /// The selected provider failed or lacks some requested fields.
+    pub degraded: bool,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub warnings: Vec<String>,
+    /// Set on the answers of `POST /lookup`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub latency_ms: Option<u128>,
+}
+
+/// [`HostLookup`] in the v2 schema.
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone)]
+pub struct HostLookupV2 {
+    #[serde(flatten)]
+    pub resolution: Resolution,
+    pub results: Vec<BatchItemV2>,
+}
+
+/// [`BatchItem`] in the v2 schema.
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone)]
+pub struct BatchItemV2 {
+    pub ip: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lookup: Option<LookupV2>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<ErrorBody>,
+}
+
+impl From<Lookup> for LookupV2 {
+    fn from(lookup: Lookup) -> Self {
+        let geo = lookup.geo;
+        LookupV2 {
+            ip: lookup.ip,
+            location: Location {
+                continent: geo.continent,
+                continent_code: geo.continent_code,
+                country: geo.country,
+                country_code: geo.country_code,
+                is_eu: geo.is_eu,
+                is_eea: geo.is_eea,
+                is_uk: geo.is_uk,
+                gdpr: geo.gdpr,
+                region: geo.region,
+                region_code: geo.region_code,
+                city: geo.city,
+                postal_code: geo.postal_code,
+                latitude: geo.latitude,
+                longitude: geo.longitude,
+                timezone: geo.timezone,
+                utc_offset: geo.utc_offset,
+                currency: geo.currency,
+                calling_code: geo.calling_code,
+                flag: lookup.country_flag,
+            },
+            network: Network {
+                asn: geo.asn,
+                as_name: geo.as_name,
+                isp: geo.isp,
+                org: geo.org,
+                hostname: geo.hostname,
+                carrier: geo.carrier,
+                connection_type: lookup.connection_type,
+                is_anycast: lookup.is_anycast,
+                ixp: lookup.ixp,
+            },
+            security: Security {
+                anonymity: lookup.anonymity,
+                risk: lookup.risk,
+                threat: lookup.threat,
+                threat_lists: lookup.threat_lists,
+                abuse: lookup.abuse,
+                noise: lookup.noise,
+                shodan: lookup.shodan,
+                passive_dns: lookup.passive_dns,
+                dnsbl: lookup.dnsbl,
+            },
+            confidence: lookup.confidence,
+            host: lookup.host.map(HostLookupV2::from),
+            meta: Meta {
+                provider: geo.provider,
+                cached: lookup.cached,
+                degraded: lookup.degraded,
+                warnings: lookup.warnings,
+                request_id: None,
+                latency_ms: None,
+            },
+        }
+    }
+}
+
+impl From<LookupResponse> for LookupV2 {
+    fn from(response: LookupResponse) -> Self {
+        let mut lookup = LookupV2::from(response.lookup);
+        lookup.meta.request_id = Some(response.request_id);
+        lookup.meta.latency_ms = Some(response.latency_ms);
+        lookup
+    }
+}
+
+impl From<HostLookup> for HostLookupV2 {
+    fn from(host: HostLookup) -> Self {
+        HostLookupV2 {
+            resolution: host.resolution,
+            results: host.results.into_iter().map(BatchItemV2::from).collect(),
+        }
+    }
+}
+
+impl From<BatchItem> for BatchItemV2 {
+    fn from(item: BatchItem) -> Self {
+        BatchItemV2 {
+            ip: item.ip,
+            lookup: item.lookup.map(LookupV2::from),
+            error: item.error,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::{ProviderConfig, ProviderKind},
+        LookupRequest, LookupService,
+    };
+
+    #[tokio::test]
+    async fn test_from_lookup() {
+        let service = LookupService::builder()
+            .provider(ProviderConfig::new(ProviderKind::Mock))
+            .build();
+        let lookup = service.lookup(&LookupRequest::ip("8.8.8.8")).await.unwrap();
+        let response = LookupResponse {
+            request_id: "req-1".into(),
+            latency_ms: 3,
+            lookup: lookup.clone(),
+        };
+        let value = serde_json::to_value(LookupV2::from(response)).unwrap();
+        assert_eq!(value["ip"], "8.8.8.8");
+        assert_eq!(value["location"]["country_code"], "US");
+        assert_eq!(value["network"]["asn"], lookup.geo.asn.unwrap());
+        assert_eq!(
+            value["security"]["anonymity"]["hosting"],
+            lookup.anonymity.hosting
+        );
+        assert_eq!(value["meta"]["provider"], "mock");
+        assert_eq!(value["meta"]["request_id"], "req-1");
+        assert!(value.get("raw").is_none() && value.get("geo").is_none());
+    }
+}
@@ -0,0 +1,184 @@
+//! Deprecation of the unversioned routes and response schema negotiation
+//!
+//! The API lives under [`V1`]. Until their sunset the same routes answer at
+//! their old unversioned paths, with the headers telling clients to move:
+//! `Deprecation` (RFC 9745), `Sunset` (RFC 8594) and a `Link` to the
+//! successor under `/v1`. A later version nests next to `/v1` with its own
+//! router, the unversioned paths stay aliases of `/v1`.
+//!
+//! The shape of a lookup evolves without a new path: `Accept: `[`V2_JSON`]
+//! selects the normalized [`LookupV2`](crate::v2::LookupV2), any other
+//! `Accept` keeps the v1 schema.
+
+use crate::config::ApiConfig;
+use axum::{
+    extract::{Request, State},
+    http::{
+        header::{HeaderName, ACCEPT, CONTENT_TYPE, LINK, VARY},
+        HeaderMap, HeaderValue,
+    },
+    middleware::Next,
+    response::Response,
+};
+use chrono::{DateTime, Utc};
+use std::sync::Arc;
+use tracing::debug;
+
+/// Prefix of the current API version.
+pub const V1: &str = "/v1";
+
+/// Media type of the v2 lookup schema.
+pub const V2_JSON: &str = "application/vnd.adatari.v2+json";
+
+const DEPRECATION: HeaderName = HeaderName::from_static("deprecation");
+const SUNSET: HeaderName = HeaderName::from_static("sunset");
+
+/// Lookup schema a request asks for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Schema {
+    #[default]
+    V1,
+    V2,
+}
+
+impl Schema {
+    /// V2 when `Accept` lists its media type without `q=0`.
+    pub fn negotiate(request: &HeaderMap) -> Self {
+        let v2 = request
+            .get_all(ACCEPT)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .any(|range| {
+                let mut parts = range.split(';').map(str::trim);
+                let media_type = parts.next().unwrap_or_default();
+                let refused = parts.any(|param| {
+                    param.split_once('=').is_some_and(|(name, q)| {
+                        name.trim() == "q" && q.trim().parse::<f32>() == Ok(0.0)
+                    })
+                });
+                media_type.eq_ignore_ascii_case(V2_JSON) && !refused
+            });
+        match v2 {
+            true => Schema::V2,
+            false => Schema::V1,
+        }
+    }
+
+    /// Labels `response` with the media type of the schema, and as varying
+    /// with `Accept` for the caches.
+    pub fn label(self, mut response: Response) -> Response {
+        let headers = response.headers_mut();
+        headers.append(VARY, HeaderValue::from_static("accept"));
+        if self == Schema::V2 && headers.contains_key(CONTENT_TYPE) {
+            headers.insert(CONTENT_TYPE, HeaderValue::from_static(V2_JSON));
+        }
+        response
+    }
+}
+
+/// Headers of the responses at deprecated paths.
+#[derive(Debug, Clone)]
+pub struct Deprecation {
+    deprecation: HeaderValue,
+    sunset: Option<HeaderValue>,
+}
+
+impl Deprecation {
+    /// Headers of the configured times, which [`Config::load`](crate::config::Config::load) validated.
+    pub fn new(config: &ApiConfig) -> Self {
+        let parse = |time: &str| {
+            DateTime::parse_from_rfc3339(time)
+                .map(|time| time.with_timezone(&Utc))
+                .unwrap_or_default()
+        };
+        let deprecated_at = parse(&config.deprecated_at);
+        Deprecation {
+            // structured field date, seconds since the epoch
+            deprecation: HeaderValue::from_str(&format!("@{}", deprecated_at.timestamp()))
+                .expect("digits"),
+            sunset: config.sunset.as_deref().map(|time| {
+                let http_date = parse(time).format("%a, %d %b %Y %H:%M:%S GMT");
+                HeaderValue::from_str(&http_date.to_string()).expect("ascii date")
+            }),
+        }
+    }
+}
+
+/// Middleware of the unversioned routes, adds the deprecation headers and
+/// links the `/v1` path of the request.
+pub async fn deprecated(
+    State(deprecation): State<Arc<Deprecation>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let path = request.uri().path().to_string();
+    debug!("deprecated path {}", path);
+    let mut response = next.run(request).await;
+    let headers = response.headers_mut();
+    headers.insert(DEPRECATION, deprecation.deprecation.clone());
+    if let Some(sunset) = &deprecation.sunset {
+        headers.insert(SUNSET, sunset.clone());
+    }
+    if let Ok(link) = HeaderValue::from_str(&format!("<{V1}{path}>; rel=\"successor-version\"")) {
+        headers.append(LINK, link);
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, middleware, routing::get, Router};
+    use tower::ServiceExt;
+
+    fn app() -> Router {
+        let api = Router::new().route("/whoami", get(|| async { "ok" }));
+        let deprecation = Arc::new(Deprecation::new(&ApiConfig::default()));
+        Router::new()
+            .nest(V1, api.clone())
+            .merge(api.layer(middleware::from_fn_with_state(deprecation, deprecated)))
+    }
+
+    async fn get_path(path: &str) -> Response {
+        let request = Request::builder().uri(path).body(Body::empty()).unwrap();
+        app().oneshot(request).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_legacy_headers() {
+        let response = get_path("/whoami").await;
+        assert!(response.status().is_success());
+        let headers = response.headers();
+        assert_eq!(headers[DEPRECATION], "@1791936000");
+        assert_eq!(headers[SUNSET], "Wed, 14 Apr 2027 00:00:00 GMT");
+        assert_eq!(headers[LINK], "</v1/whoami>; rel=\"successor-version\"");
+    }
+
+    #[test]
+    fn test_negotiate() {
+        let accept = |value: &'static str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(ACCEPT, HeaderValue::from_static(value));
+            Schema::negotiate(&headers)
+        };
+        assert_eq!(Schema::negotiate(&HeaderMap::new()), Schema::V1);
+        assert_eq!(accept("application/json"), Schema::V1);
+        assert_eq!(accept("*/*"), Schema::V1);
+        assert_eq!(accept("application/vnd.adatari.v2+json"), Schema::V2);
+        assert_eq!(
+            accept("application/json;q=0.5, application/vnd.adatari.v2+json"),
+            Schema::V2
+        );
+        assert_eq!(accept("application/vnd.adatari.v2+json; q=0"), Schema::V1);
+        assert_eq!(accept("application/vnd.adatari.v3+json"), Schema::V1);
+    }
+
+    #[tokio::test]
+    async fn test_versioned() {
+        let response = get_path("/v1/whoami").await;
+        assert!(response.status().is_success());
+        assert!(!response.headers().contains_key(DEPRECATION));
+        assert!(!response.headers().contains_key(SUNSET));
+    }
+}